@@ -253,6 +253,18 @@ pub unsafe extern "C" fn switch_to_user_arm_v7m(
     user_stack
 }
 
+/// Stacked PC and LR of the last kernel hard fault.
+static mut KERNEL_FAULT: Option<(u32, u32)> = None;
+
+/// The program counter and link register of the kernel code that caused a
+/// hard fault, if one occurred.
+///
+/// The fault handler records these before it panics, so boards can keep them
+/// for post-mortem debugging from their panic handler.
+pub fn kernel_fault() -> Option<(u32, u32)> {
+    unsafe { KERNEL_FAULT }
+}
+
 #[cfg(all(target_arch = "arm", target_os = "none"))]
 #[inline(never)]
 unsafe fn kernel_hardfault_arm_v7m(faulting_stack: *mut u32) -> ! {
@@ -264,6 +276,7 @@ unsafe fn kernel_hardfault_arm_v7m(faulting_stack: *mut u32) -> ! {
     let stacked_lr: u32 = *faulting_stack.offset(5);
     let stacked_pc: u32 = *faulting_stack.offset(6);
     let stacked_xpsr: u32 = *faulting_stack.offset(7);
+    KERNEL_FAULT = Some((stacked_pc, stacked_lr));
 
     let mode_str = "Kernel";
 
@@ -176,4 +176,11 @@ impl Nvic {
 
         NVIC.icpr[idx / 32].set(1 << (self.0 & 31));
     }
+
+    /// Check if the interrupt is pending
+    pub fn is_pending(&self) -> bool {
+        let idx = self.0 as usize;
+
+        NVIC.ispr[idx / 32].get() & (1 << (self.0 & 31)) != 0
+    }
 }
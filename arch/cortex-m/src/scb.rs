@@ -289,6 +289,27 @@ pub unsafe fn reset() {
     );
 }
 
+/// Fault status and fault address registers.
+#[derive(Clone, Copy, Debug)]
+pub struct FaultStatus {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+/// Read the fault status and fault address registers. The status bits stay
+/// set until they are cleared, so they still describe the last fault after
+/// its handler has returned.
+pub unsafe fn fault_status() -> FaultStatus {
+    FaultStatus {
+        cfsr: SCB.cfsr.get(),
+        hfsr: SCB.hfsr.get(),
+        mmfar: SCB.mmfar.get(),
+        bfar: SCB.bfar.get(),
+    }
+}
+
 /// relocate interrupt vector table
 pub unsafe fn set_vector_table_offset(offset: *const ()) {
     SCB.vtor.set(offset as u32);
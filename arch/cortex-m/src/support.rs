@@ -16,6 +16,24 @@ pub unsafe fn wfi() {
     asm!("wfi", options(nomem, preserves_flags));
 }
 
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+#[inline(always)]
+/// DMB instruction
+pub fn dmb() {
+    unsafe {
+        asm!("dmb", options(nostack, preserves_flags));
+    }
+}
+
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+#[inline(always)]
+/// DSB instruction
+pub fn dsb() {
+    unsafe {
+        asm!("dsb", options(nostack, preserves_flags));
+    }
+}
+
 #[cfg(all(target_arch = "arm", target_os = "none"))]
 pub unsafe fn atomic<F, R>(f: F) -> R
 where
@@ -44,6 +62,16 @@ pub unsafe fn wfi() {
     unimplemented!()
 }
 
+#[cfg(not(any(target_arch = "arm", target_os = "none")))]
+/// DMB instruction (mock). Tests run on one core with nothing to order
+/// against, so this does nothing.
+pub fn dmb() {}
+
+#[cfg(not(any(target_arch = "arm", target_os = "none")))]
+/// DSB instruction (mock). Tests run on one core with nothing to order
+/// against, so this does nothing.
+pub fn dsb() {}
+
 #[cfg(not(any(target_arch = "arm", target_os = "none")))]
 pub unsafe fn atomic<F, R>(_f: F) -> R
 where
@@ -51,3 +79,16 @@ where
 {
     unimplemented!()
 }
+
+/// The Cortex-M memory barrier instructions.
+pub struct MemoryBarriers;
+
+impl kernel::hil::memory_barrier::MemoryBarrier for MemoryBarriers {
+    fn data_memory_barrier(&self) {
+        dmb();
+    }
+
+    fn data_synchronization_barrier(&self) {
+        dsb();
+    }
+}
@@ -37,6 +37,9 @@ extern "C" {
 // Space for 8 u32s: r0-r3, r12, lr, pc, and xPSR
 const SVC_FRAME_SIZE: usize = 32;
 
+/// Number of words in `CortexMStoredState`, as serialized by `store_context()`.
+const STORED_STATE_WORDS: usize = 11;
+
 /// This holds all of the state that the kernel must keep for the process when
 /// the process is not executing.
 #[derive(Default)]
@@ -247,6 +250,22 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         (switch_reason, Some(new_stack_pointer as *const u8))
     }
 
+    unsafe fn get_fault_pc(
+        &self,
+        accessible_memory_start: *const u8,
+        app_brk: *const u8,
+        state: &CortexMStoredState,
+    ) -> Option<usize> {
+        // The hardware stacked the PC in the exception frame on the process
+        // stack, so only read it if that frame is within process memory.
+        if state.psp < accessible_memory_start as usize
+            || (state.psp + SVC_FRAME_SIZE) > app_brk as usize
+        {
+            return None;
+        }
+        Some(read_volatile((state.psp as *const usize).offset(6)))
+    }
+
     unsafe fn print_context(
         &self,
         accessible_memory_start: *const u8,
@@ -332,4 +351,37 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
             },
         ));
     }
+
+    fn store_context(&self, state: &CortexMStoredState, out: &mut [u8]) -> Result<usize, ()> {
+        let special = [state.yield_pc, state.psr, state.psp];
+        let words = state.regs.iter().chain(special.iter());
+        let mut len = 0;
+        for (word, chunk) in words.zip(out.chunks_exact_mut(mem::size_of::<usize>())) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+            len += chunk.len();
+        }
+        if len == STORED_STATE_WORDS * mem::size_of::<usize>() {
+            Ok(len)
+        } else {
+            Err(())
+        }
+    }
+
+    fn restore_context(&self, state: &mut CortexMStoredState, data: &[u8]) -> Result<(), ()> {
+        if data.len() != STORED_STATE_WORDS * mem::size_of::<usize>() {
+            return Err(());
+        }
+        let mut words = data.chunks_exact(mem::size_of::<usize>()).map(|chunk| {
+            let mut bytes = [0; mem::size_of::<usize>()];
+            bytes.copy_from_slice(chunk);
+            usize::from_le_bytes(bytes)
+        });
+        for reg in state.regs.iter_mut() {
+            *reg = words.next().ok_or(())?;
+        }
+        state.yield_pc = words.next().ok_or(())?;
+        state.psr = words.next().ok_or(())?;
+        state.psp = words.next().ok_or(())?;
+        Ok(())
+    }
 }
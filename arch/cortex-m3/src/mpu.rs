@@ -5,6 +5,7 @@ use core::cell::Cell;
 use core::cmp;
 use core::fmt;
 use kernel;
+use kernel::capabilities::MpuInfoCapability;
 use kernel::common::cells::OptionalCell;
 use kernel::common::math;
 use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite};
@@ -387,6 +388,16 @@ impl kernel::mpu::MPU for MPU {
         self.registers.mpu_type.read(Type::DREGION) as usize
     }
 
+    fn limits(&self, _capability: &dyn MpuInfoCapability) -> Option<mpu::MpuLimits> {
+        Some(mpu::MpuLimits {
+            total_regions: self.number_total_regions(),
+            min_region_size: 32,
+            min_alignment: 32,
+            power_of_two_sizes: true,
+            subregions: 8,
+        })
+    }
+
     fn allocate_region(
         &self,
         unallocated_memory_start: *const u8,
@@ -514,6 +525,22 @@ impl kernel::mpu::MPU for MPU {
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn remove_region(&self, region: mpu::Region, config: &mut Self::MpuConfig) -> Result<(), ()> {
+        let location = Some((region.start_address(), region.size()));
+        let region_num = config
+            .regions
+            .iter()
+            .enumerate()
+            .find(|(number, r)| *number != APP_MEMORY_REGION_NUM && r.location() == location)
+            .map(|(number, _)| number)
+            .ok_or(())?;
+
+        config.regions[region_num] = CortexMRegion::empty(region_num);
+        config.is_dirty.set(true);
+
+        Ok(())
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
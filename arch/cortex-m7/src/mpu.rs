@@ -520,6 +520,22 @@ impl kernel::mpu::MPU for MPU {
         Some(mpu::Region::new(start as *const u8, size))
     }
 
+    fn remove_region(&self, region: mpu::Region, config: &mut Self::MpuConfig) -> Result<(), ()> {
+        let location = Some((region.start_address(), region.size()));
+        let region_num = config
+            .regions
+            .iter()
+            .enumerate()
+            .find(|(number, r)| *number != APP_MEMORY_REGION_NUM && r.location() == location)
+            .map(|(number, _)| number)
+            .ok_or(())?;
+
+        config.regions[region_num] = CortexMRegion::empty(region_num);
+        config.is_dirty.set(true);
+
+        Ok(())
+    }
+
     fn allocate_app_memory_region(
         &self,
         unallocated_memory_start: *const u8,
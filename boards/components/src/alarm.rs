@@ -11,6 +11,9 @@
 //! let mux_alarm = components::alarm::AlarmMuxComponent::new(ast)
 //!     .finalize(components::alarm_mux_component_helper!(sam4l::ast::Ast));
 //! ast.configure(mux_alarm);
+//! // Or, to allow at most 8 virtual alarms to be armed at once:
+//! let mux_alarm = components::alarm::AlarmMuxComponent::new_with_limit(ast, 8)
+//!     .finalize(components::alarm_mux_component_helper!(sam4l::ast::Ast));
 //! let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
 //!     .finalize(components::alarm_component_helper!(sam4l::ast::Ast));
 //! ```
@@ -55,11 +58,25 @@ macro_rules! alarm_component_helper {
 
 pub struct AlarmMuxComponent<A: 'static + time::Alarm<'static>> {
     alarm: &'static A,
+    max_armed: Option<usize>,
 }
 
 impl<A: 'static + time::Alarm<'static>> AlarmMuxComponent<A> {
     pub fn new(alarm: &'static A) -> AlarmMuxComponent<A> {
-        AlarmMuxComponent { alarm }
+        AlarmMuxComponent {
+            alarm,
+            max_armed: None,
+        }
+    }
+
+    /// Limit the mux to `max_armed` concurrently armed virtual alarms. Every
+    /// client of the mux must arm its alarm with `try_set_alarm()`, as
+    /// `AlarmDriver` does, see `MuxAlarm::new_with_limit()`.
+    pub fn new_with_limit(alarm: &'static A, max_armed: usize) -> AlarmMuxComponent<A> {
+        AlarmMuxComponent {
+            alarm,
+            max_armed: Some(max_armed),
+        }
     }
 }
 
@@ -71,7 +88,10 @@ impl<A: 'static + time::Alarm<'static>> Component for AlarmMuxComponent<A> {
         let mux_alarm = static_init_half!(
             static_buffer,
             MuxAlarm<'static, A>,
-            MuxAlarm::new(self.alarm)
+            match self.max_armed {
+                Some(max_armed) => MuxAlarm::new_with_limit(self.alarm, max_armed),
+                None => MuxAlarm::new(self.alarm),
+            }
         );
 
         self.alarm.set_alarm_client(mux_alarm);
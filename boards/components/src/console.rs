@@ -110,6 +110,7 @@ impl Component for ConsoleComponent {
                 console_uart,
                 &mut console::WRITE_BUF,
                 &mut console::READ_BUF,
+                self.board_kernel.create_grant(&grant_cap),
                 self.board_kernel.create_grant(&grant_cap)
             )
         );
@@ -3,6 +3,8 @@
 //! This provides components for attaching the kernel debug output (for panic!,
 //! print!, debug!, etc.) to the output. `DebugWriterComponent` uses a UART mux,
 //! and `DebugWriterNoMuxComponent` just uses a UART interface directly.
+//! `DebugWriterRamComponent` keeps the output in RAM, for boards without a
+//! working UART.
 //!
 //! Usage
 //! -----
@@ -13,13 +15,18 @@
 //!     &nrf52::uart::UARTE0,
 //! )
 //! .finalize(());
+//!
+//! components::debug_writer::DebugWriterRamComponent::new(dynamic_deferred_caller)
+//!     .finalize(());
 //! ```
 
 // Author: Brad Campbell <bradjc@virginia.edu>
 // Last modified: 11/07/2019
 
+use capsules::ram_uart::RamUart;
 use capsules::virtual_uart::{MuxUart, UartDevice};
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::common::ring_buffer::RingBuffer;
 use kernel::component::Component;
 use kernel::hil;
@@ -125,3 +132,59 @@ impl<U: uart::Uart<'static> + uart::Transmit<'static> + 'static> Component
         });
     }
 }
+
+/// Size of the buffer `DebugWriterRamComponent` keeps the most recent output
+/// in.
+const RAM_DEBUG_BUFFER_LEN: usize = 1024;
+
+pub struct DebugWriterRamComponent {
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl DebugWriterRamComponent {
+    pub fn new(deferred_caller: &'static DynamicDeferredCall) -> DebugWriterRamComponent {
+        DebugWriterRamComponent {
+            deferred_caller: deferred_caller,
+        }
+    }
+}
+
+impl Component for DebugWriterRamComponent {
+    type StaticInput = ();
+    type Output = &'static RamUart<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let ram_buf = static_init!([u8; RAM_DEBUG_BUFFER_LEN], [0; RAM_DEBUG_BUFFER_LEN]);
+        let ram_ring = static_init!(RingBuffer<'static, u8>, RingBuffer::new(ram_buf));
+        let ram_uart = static_init!(
+            RamUart<'static>,
+            RamUart::new(ram_ring, self.deferred_caller)
+        );
+        ram_uart.initialize_callback_handle(
+            self.deferred_caller
+                .register(ram_uart)
+                .expect("no deferred call slot available for ram uart"),
+        );
+
+        let buf = static_init!(
+            [u8; 1024 * DEBUG_BUFFER_KBYTE],
+            [0; 1024 * DEBUG_BUFFER_KBYTE]
+        );
+        let (output_buf, internal_buf) = buf.split_at_mut(DEBUG_BUFFER_SPLIT);
+
+        let ring_buffer = static_init!(RingBuffer<'static, u8>, RingBuffer::new(internal_buf));
+        let debugger = static_init!(
+            kernel::debug::DebugWriter,
+            kernel::debug::DebugWriter::new(ram_uart, output_buf, ring_buffer)
+        );
+        hil::uart::Transmit::set_transmit_client(ram_uart, debugger);
+
+        let debug_wrapper = static_init!(
+            kernel::debug::DebugWriterWrapper,
+            kernel::debug::DebugWriterWrapper::new(debugger)
+        );
+        kernel::debug::set_debug_writer_wrapper(debug_wrapper);
+
+        ram_uart
+    }
+}
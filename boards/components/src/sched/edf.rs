@@ -0,0 +1,83 @@
+//! Component for an earliest-deadline-first scheduler.
+//!
+//! This provides one Component, EDFComponent.
+//!
+//! Processes whose jobs have the same deadline run in the order of the
+//! `PROCESSES` array.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let scheduler = components::sched::edf::EDFComponent::new(
+//!     board_kernel,
+//!     &PROCESSES,
+//!     wait_time_clock,
+//! )
+//! .finalize(components::edf_component_helper!(NUM_PROCS));
+//! ```
+
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::procs::ProcessType;
+use kernel::WaitTimeClock;
+use kernel::{static_init, static_init_half};
+use kernel::{EDFProcessNode, EDFSched};
+
+#[macro_export]
+macro_rules! edf_component_helper {
+    ($N:expr $(,)?) => {{
+        use core::mem::MaybeUninit;
+        use kernel::static_buf;
+        use kernel::EDFProcessNode;
+        const UNINIT: MaybeUninit<EDFProcessNode<'static>> = MaybeUninit::uninit();
+        static mut BUF: [MaybeUninit<EDFProcessNode<'static>>; $N] = [UNINIT; $N];
+        &mut BUF
+    };};
+}
+
+pub struct EDFComponent {
+    board_kernel: &'static kernel::Kernel,
+    processes: &'static [Option<&'static dyn ProcessType>],
+    clock: &'static dyn WaitTimeClock,
+}
+
+impl EDFComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        processes: &'static [Option<&'static dyn ProcessType>],
+        clock: &'static dyn WaitTimeClock,
+    ) -> EDFComponent {
+        EDFComponent {
+            board_kernel,
+            processes,
+            clock,
+        }
+    }
+}
+
+impl Component for EDFComponent {
+    type StaticInput = &'static mut [MaybeUninit<EDFProcessNode<'static>>];
+    type Output = &'static mut EDFSched<'static>;
+
+    unsafe fn finalize(self, buf: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let scheduler = static_init!(
+            EDFSched<'static>,
+            EDFSched::new(self.board_kernel.create_grant(&grant_cap), self.clock)
+        );
+
+        for (i, node) in buf.iter_mut().enumerate() {
+            let init_node = static_init_half!(
+                node,
+                EDFProcessNode<'static>,
+                EDFProcessNode::new(&self.processes[i])
+            );
+            // Keep the order of `processes`, which breaks ties.
+            scheduler.processes.push_tail(init_node);
+        }
+        scheduler
+    }
+}
@@ -1,4 +1,7 @@
 pub mod cooperative;
+pub mod edf;
 pub mod mlfq;
 pub mod priority;
+pub mod proportional;
 pub mod round_robin;
+pub mod two_tier;
@@ -0,0 +1,74 @@
+//! Component for a proportional-share scheduler.
+//!
+//! This provides one Component, ProportionalComponent.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let scheduler = components::proportional::ProportionalComponent::new(
+//!     board_kernel,
+//!     &PROCESSES,
+//! )
+//! .finalize(components::proportional_component_helper!(NUM_PROCS));
+//! ```
+
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::procs::ProcessType;
+use kernel::{static_init, static_init_half};
+use kernel::{ProportionalProcessNode, ProportionalSched};
+
+#[macro_export]
+macro_rules! proportional_component_helper {
+    ($N:expr $(,)?) => {{
+        use core::mem::MaybeUninit;
+        use kernel::static_buf;
+        use kernel::ProportionalProcessNode;
+        const UNINIT: MaybeUninit<ProportionalProcessNode<'static>> = MaybeUninit::uninit();
+        static mut BUF: [MaybeUninit<ProportionalProcessNode<'static>>; $N] = [UNINIT; $N];
+        &mut BUF
+    };};
+}
+
+pub struct ProportionalComponent {
+    board_kernel: &'static kernel::Kernel,
+    processes: &'static [Option<&'static dyn ProcessType>],
+}
+
+impl ProportionalComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        processes: &'static [Option<&'static dyn ProcessType>],
+    ) -> ProportionalComponent {
+        ProportionalComponent {
+            board_kernel,
+            processes,
+        }
+    }
+}
+
+impl Component for ProportionalComponent {
+    type StaticInput = &'static mut [MaybeUninit<ProportionalProcessNode<'static>>];
+    type Output = &'static mut ProportionalSched<'static>;
+
+    unsafe fn finalize(self, buf: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let scheduler = static_init!(
+            ProportionalSched<'static>,
+            ProportionalSched::new(self.board_kernel.create_grant(&grant_cap))
+        );
+
+        for (i, node) in buf.iter_mut().enumerate() {
+            let init_node = static_init_half!(
+                node,
+                ProportionalProcessNode<'static>,
+                ProportionalProcessNode::new(&self.processes[i])
+            );
+            scheduler.processes.push_head(init_node);
+        }
+        scheduler
+    }
+}
@@ -8,6 +8,15 @@
 //! let scheduler = components::round_robin::RoundRobinComponent::new(&PROCESSES)
 //!     .finalize(components::rr_component_helper!(NUM_PROCS));
 //! ```
+//!
+//! To resume processes interrupted by the kernel first, at most 4 times in a
+//! row:
+//!
+//! ```rust
+//! let scheduler =
+//!     components::round_robin::RoundRobinComponent::new_resume_preempted(&PROCESSES, 4)
+//!         .finalize(components::rr_component_helper!(NUM_PROCS));
+//! ```
 
 // Author: Hudson Ayers <hayers@stanford.edu>
 // Last modified: 03/31/2020
@@ -32,11 +41,25 @@ macro_rules! rr_component_helper {
 
 pub struct RoundRobinComponent {
     processes: &'static [Option<&'static dyn ProcessType>],
+    max_preempted_resumes: Option<usize>,
 }
 
 impl RoundRobinComponent {
     pub fn new(processes: &'static [Option<&'static dyn ProcessType>]) -> RoundRobinComponent {
-        RoundRobinComponent { processes }
+        RoundRobinComponent {
+            processes,
+            max_preempted_resumes: None,
+        }
+    }
+
+    pub fn new_resume_preempted(
+        processes: &'static [Option<&'static dyn ProcessType>],
+        max_resumes: usize,
+    ) -> RoundRobinComponent {
+        RoundRobinComponent {
+            processes,
+            max_preempted_resumes: Some(max_resumes),
+        }
     }
 }
 
@@ -45,7 +68,13 @@ impl Component for RoundRobinComponent {
     type Output = &'static mut RoundRobinSched<'static>;
 
     unsafe fn finalize(self, buf: Self::StaticInput) -> Self::Output {
-        let scheduler = static_init!(RoundRobinSched<'static>, RoundRobinSched::new());
+        let scheduler = static_init!(
+            RoundRobinSched<'static>,
+            match self.max_preempted_resumes {
+                Some(max_resumes) => RoundRobinSched::new_resume_preempted(max_resumes),
+                None => RoundRobinSched::new(),
+            }
+        );
 
         for (i, node) in buf.iter_mut().enumerate() {
             let init_node = static_init_half!(
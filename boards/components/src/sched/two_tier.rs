@@ -0,0 +1,58 @@
+//! Component for a two-tier scheduler.
+//!
+//! This provides one Component, TwoTierComponent.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let scheduler = components::two_tier::TwoTierComponent::new(&PROCESSES)
+//!     .finalize(components::two_tier_component_helper!(NUM_PROCS));
+//! scheduler.set_background_processes(&["logger"]);
+//! ```
+
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::procs::ProcessType;
+use kernel::{static_init, static_init_half};
+use kernel::{TwoTierProcessNode, TwoTierSched};
+
+#[macro_export]
+macro_rules! two_tier_component_helper {
+    ($N:expr $(,)?) => {{
+        use core::mem::MaybeUninit;
+        use kernel::static_buf;
+        use kernel::TwoTierProcessNode;
+        const UNINIT: MaybeUninit<TwoTierProcessNode<'static>> = MaybeUninit::uninit();
+        static mut BUF: [MaybeUninit<TwoTierProcessNode<'static>>; $N] = [UNINIT; $N];
+        &mut BUF
+    };};
+}
+
+pub struct TwoTierComponent {
+    processes: &'static [Option<&'static dyn ProcessType>],
+}
+
+impl TwoTierComponent {
+    pub fn new(processes: &'static [Option<&'static dyn ProcessType>]) -> TwoTierComponent {
+        TwoTierComponent { processes }
+    }
+}
+
+impl Component for TwoTierComponent {
+    type StaticInput = &'static mut [MaybeUninit<TwoTierProcessNode<'static>>];
+    type Output = &'static mut TwoTierSched<'static>;
+
+    unsafe fn finalize(self, buf: Self::StaticInput) -> Self::Output {
+        let scheduler = static_init!(TwoTierSched<'static>, TwoTierSched::new());
+
+        for (i, node) in buf.iter_mut().enumerate() {
+            let init_node = static_init_half!(
+                node,
+                TwoTierProcessNode<'static>,
+                TwoTierProcessNode::new(&self.processes[i])
+            );
+            scheduler.processes.push_head(init_node);
+        }
+        scheduler
+    }
+}
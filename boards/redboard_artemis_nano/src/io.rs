@@ -1,3 +1,24 @@
+//! Panic output over UART0.
+//!
+//! Before the usual human-readable dump, the panic handler writes a single
+//! line record that host tooling can find and parse:
+//!
+//! ```text
+//! TOCKPANIC 1 <pc> <lr> <cfsr> <hfsr> <mmfar> <bfar> <process>
+//! ```
+//!
+//! Fields are separated by single spaces. After the `TOCKPANIC` magic comes
+//! the format version, `PANIC_RECORD_VERSION`, which is increased whenever
+//! fields change. The next six fields are 8 digit hexadecimal numbers:
+//!
+//! - `pc`, `lr`: the program counter and link register of the kernel code
+//!   that caused a hard fault, or 0 if the panic does not come from one.
+//! - `cfsr`, `hfsr`, `mmfar`, `bfar`: the fault status and fault address
+//!   registers, which report MPU violations as well as bus and usage faults.
+//!
+//! The last field is the name of the process that faulted, or `-` if none
+//! did. Characters other than printable ASCII are replaced with `?`.
+
 use core::fmt::Write;
 use core::panic::PanicInfo;
 
@@ -7,6 +28,13 @@ use apollo3;
 use kernel::debug;
 use kernel::debug::IoWrite;
 use kernel::hil::led;
+use kernel::procs::State;
+
+/// Marks the start of a panic record.
+const PANIC_RECORD_MAGIC: &str = "TOCKPANIC";
+
+/// Version of the panic record format.
+const PANIC_RECORD_VERSION: u32 = 1;
 
 /// Writer is used by kernel::debug to panic message to the serial port.
 pub struct Writer {
@@ -32,11 +60,50 @@ impl Write for Writer {
 
 impl IoWrite for Writer {
     fn write(&mut self, buf: &[u8]) {
+        // The board did not bring up the UART, so it may not be usable.
+        if !self.initialized {
+            return;
+        }
         let uart = apollo3::uart::Uart::new_uart_0(); // Aliases memory for uart0. Okay bc we are panicking.
         uart.transmit_sync(buf);
     }
 }
 
+/// Write the machine-readable panic record. Like the rest of the panic
+/// output it is written synchronously, without interrupts or allocation.
+unsafe fn write_panic_record(writer: &mut Writer) {
+    let (pc, lr) = cortexm4::kernel_fault().unwrap_or((0, 0));
+    let fault = cortexm4::scb::fault_status();
+    let _ = write!(
+        writer,
+        "\r\n{} {} {:08x} {:08x} {:08x} {:08x} {:08x} {:08x} ",
+        PANIC_RECORD_MAGIC,
+        PANIC_RECORD_VERSION,
+        pc,
+        lr,
+        fault.cfsr,
+        fault.hfsr,
+        fault.mmfar,
+        fault.bfar
+    );
+
+    let faulted = PROCESSES
+        .iter()
+        .filter_map(|process| *process)
+        .find(|process| process.get_state() == State::Fault);
+    match faulted {
+        Some(process) => {
+            for c in process.get_process_name().chars() {
+                let _ = writer.write_char(if c.is_ascii_graphic() { c } else { '?' });
+            }
+        }
+        None => {
+            let _ = writer.write_str("-");
+        }
+    }
+    let _ = writer.write_str("\r\n");
+}
+
 /// Panic handler.
 #[no_mangle]
 #[panic_handler]
@@ -51,12 +118,12 @@ pub unsafe extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
     let led = &mut led::LedLow::new(led_pin);
     let writer = &mut WRITER;
 
-    debug::panic(
-        &mut [led],
-        writer,
-        info,
-        &cortexm4::support::nop,
-        &PROCESSES,
-        &CHIP,
-    )
+    debug::panic_hook(info);
+    debug::panic_begin(&cortexm4::support::nop);
+    write_panic_record(writer);
+    debug::panic_banner(writer, info);
+    debug::flush(writer);
+    debug::panic_cpu_state(&CHIP, writer);
+    debug::panic_process_info(&PROCESSES, writer);
+    debug::panic_blink_forever(&mut [led])
 }
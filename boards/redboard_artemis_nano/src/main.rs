@@ -9,15 +9,20 @@
 #![deny(missing_docs)]
 
 use apollo3::chip::Apollo3DefaultPeripherals;
+use capsules::oneshot_timer::OneshotTimer;
 use capsules::virtual_alarm::VirtualMuxAlarm;
 use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
 use kernel::component::Component;
+use kernel::hil;
 use kernel::hil::i2c::I2CMaster;
 use kernel::hil::led::LedHigh;
-use kernel::hil::time::Counter;
+use kernel::hil::time::{Alarm, Counter, Ticks, Time};
+use kernel::mpu::MPU;
+use kernel::Chip;
 use kernel::Platform;
+use kernel::ReturnCode;
 use kernel::{create_capability, debug, static_init};
 
 pub mod ble;
@@ -26,24 +31,110 @@ pub mod io;
 
 #[allow(dead_code)]
 mod multi_alarm_test;
+mod panic_record;
 
 // Number of concurrent processes this platform supports.
-const NUM_PROCS: usize = 4;
+const NUM_PROCS: usize = 6;
+
+// Number of the last entries of the processes array that are swap slots, see
+// kernel::swap. The others hold the apps loaded at boot.
+const NUM_SWAP_SLOTS: usize = 2;
 
 // Actual memory for holding the active process structures.
-static mut PROCESSES: [Option<&'static dyn kernel::procs::ProcessType>; NUM_PROCS] = [None; 4];
+static mut PROCESSES: [Option<&'static dyn kernel::procs::ProcessType>; NUM_PROCS] =
+    [None; NUM_PROCS];
+
+// RAM of each swap slot, for the app swapped into it.
+static mut SWAP_MEMORY: [[u8; 16384]; NUM_SWAP_SLOTS] = [[0; 16384]; NUM_SWAP_SLOTS];
 
 // Static reference to chip for panic dumps.
 static mut CHIP: Option<&'static apollo3::chip::Apollo3<Apollo3DefaultPeripherals>> = None;
 
-// How should the kernel respond when a process faults.
+// Interrupts whose bottom halves are serviced ahead of all others, most
+// important first. GPIO is first so that edges on the exposed pins are handled
+// with the lowest latency.
+static INTERRUPT_PRIORITY: [u32; 1] = [apollo3::nvic::GPIO];
+
+// How should the kernel respond when a process faults. Faulting processes are
+// not restarted, so the restart throttle (`capsules::restart_throttle`) is not
+// set up.
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
 
+// How long to wait after initialization before starting processes, so that
+// peripherals on the Qwiic bus have powered up before apps first use them.
+// Slow sensors fail their first transaction without it. The delay comes after
+// all peripherals are set up and processes are loaded, right before the kernel
+// loop starts running processes.
+const STARTUP_DELAY_MS: u32 = 100;
+
+/// Version of the hibernation snapshots this build saves and restores. Bump it
+/// when a change to the kernel or board makes older snapshots unusable.
+const HIBERNATION_VERSION: u32 = 1;
+
 /// Dummy buffer that causes the linker to reserve enough space for the stack.
 #[no_mangle]
 #[link_section = ".stack_buffer"]
 pub static mut STACK_MEMORY: [u8; 0x1000] = [0; 0x1000];
 
+/// Lets the wait time driver look up processes.
+struct WaitTimeCapability;
+unsafe impl capabilities::ProcessManagementCapability for WaitTimeCapability {}
+
+/// Lets the process stats driver read the state and memory use of processes.
+struct ProcessStatsCapability;
+unsafe impl capabilities::ProcessManagementCapability for ProcessStatsCapability {}
+
+/// Lets the system reset driver reset the chip, and look up the apps allowed
+/// to request it.
+struct ResetCapability;
+unsafe impl capabilities::SystemResetCapability for ResetCapability {}
+unsafe impl capabilities::ProcessManagementCapability for ResetCapability {}
+
+/// Lets the watchdog driver look up the apps allowed to tune it.
+struct WatchdogCapability;
+unsafe impl capabilities::ProcessManagementCapability for WatchdogCapability {}
+
+/// Lets the board set the power budget and create the consumers counted
+/// against it.
+struct PowerBudgetCap;
+unsafe impl capabilities::PowerBudgetCapability for PowerBudgetCap {}
+
+/// Lets the board read the MPU limits it reports to apps.
+struct MpuInfoCap;
+unsafe impl capabilities::MpuInfoCapability for MpuInfoCap {}
+
+/// Lets hibernation freeze and save the processes, and look up the apps
+/// allowed to request it.
+struct HibernationCapability;
+unsafe impl capabilities::ProcessManagementCapability for HibernationCapability {}
+
+/// Powers the board off once the processes are saved for hibernation. Every
+/// interrupt is disabled, so only a reset wakes the board again.
+struct PowerOff;
+
+impl capsules::hibernate_storage::HibernateClient for PowerOff {
+    fn snapshot_saved(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            debug!("Hibernation failed: {:?}", result);
+            return;
+        }
+        unsafe {
+            cortexm4::nvic::disable_all();
+            cortexm4::nvic::clear_all_pending();
+            cortexm4::scb::set_sleepdeep();
+            loop {
+                cortexm4::support::wfi();
+            }
+        }
+    }
+
+    fn snapshot_invalidated(&self) {}
+}
+
+/// Rough estimate of the extra power the I2C bus draws while in use, in
+/// microwatts.
+const I2C_POWER_UW: u32 = 1_000;
+
 /// A structure representing this platform that holds references to all
 /// capsules for this platform.
 struct RedboardArtemisNano {
@@ -56,13 +147,46 @@ struct RedboardArtemisNano {
         LedHigh<'static, apollo3::gpio::GpioPin<'static>>,
     >,
     gpio: &'static capsules::gpio::GPIO<'static, apollo3::gpio::GpioPin<'static>>,
-    console: &'static capsules::console::Console<'static>,
-    i2c_master: &'static capsules::i2c_master::I2CMasterDriver<apollo3::iom::Iom<'static>>,
+    gpio_ports: &'static capsules::gpio_ports::GpioPorts<'static>,
+    console: Option<&'static capsules::console::Console<'static>>,
+    i2c_master: Option<&'static capsules::i2c_master::I2CMasterDriver<apollo3::iom::Iom<'static>>>,
+    temperature: Option<&'static capsules::temperature::TemperatureSensor<'static>>,
+    humidity: Option<&'static capsules::humidity::HumiditySensor<'static>>,
     ble_radio: &'static capsules::ble_advertising_driver::BLE<
         'static,
         apollo3::ble::Ble<'static>,
         VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
     >,
+    uptime: &'static capsules::uptime::Uptime,
+    sleep_veto: &'static capsules::sleep_veto::SleepVeto,
+    pre_sleep: &'static capsules::pre_sleep::PreSleep<'static>,
+    alarm_stats:
+        &'static capsules::alarm_stats::AlarmStats<'static, apollo3::stimer::STimer<'static>>,
+    wake_reason: &'static capsules::wake_reason::WakeReason,
+    syscall_benchmark: &'static capsules::syscall_benchmark::SyscallBenchmark<
+        'static,
+        apollo3::stimer::STimer<'static>,
+    >,
+    process_wait_time: &'static capsules::process_wait_time::ProcessWaitTime<WaitTimeCapability>,
+    system_reset: &'static capsules::system_reset::SystemResetDriver<
+        'static,
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
+        ResetCapability,
+    >,
+    device_id: &'static capsules::device_id::DeviceIdDriver,
+    timeslice_yield: &'static capsules::timeslice_yield::TimesliceYield,
+    watchdog: &'static capsules::watchdog::WatchdogDriver<'static, WatchdogCapability>,
+    memory_barrier: &'static capsules::memory_barrier::MemoryBarrierDriver<'static>,
+    power_budget: &'static capsules::power_budget::PowerBudgetDriver,
+    mpu_limits: &'static capsules::mpu_limits::MpuLimitsDriver,
+    process_stats: &'static capsules::process_stats::ProcessStats<ProcessStatsCapability>,
+    bit_bang: &'static capsules::bit_bang::BitBang<
+        'static,
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer<'static>>,
+    >,
+    key_value: &'static capsules::key_value::KeyValue<'static, apollo3::flashctrl::FlashCtrl>,
+    hibernate:
+        &'static capsules::hibernate_storage::HibernateDriver<'static, HibernationCapability>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -75,14 +199,57 @@ impl Platform for RedboardArtemisNano {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::led::DRIVER_NUM => f(Some(self.led)),
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
-            capsules::console::DRIVER_NUM => f(Some(self.console)),
-            capsules::i2c_master::DRIVER_NUM => f(Some(self.i2c_master)),
+            capsules::gpio_ports::DRIVER_NUM => f(Some(self.gpio_ports)),
+            capsules::console::DRIVER_NUM => f(self.console.map(|c| c as &dyn kernel::Driver)),
+            capsules::i2c_master::DRIVER_NUM => {
+                f(self.i2c_master.map(|d| d as &dyn kernel::Driver))
+            }
+            capsules::temperature::DRIVER_NUM => {
+                f(self.temperature.map(|d| d as &dyn kernel::Driver))
+            }
+            capsules::humidity::DRIVER_NUM => f(self.humidity.map(|d| d as &dyn kernel::Driver)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
+            capsules::uptime::DRIVER_NUM => f(Some(self.uptime)),
+            capsules::sleep_veto::DRIVER_NUM => f(Some(self.sleep_veto)),
+            capsules::pre_sleep::DRIVER_NUM => f(Some(self.pre_sleep)),
+            capsules::alarm_stats::DRIVER_NUM => f(Some(self.alarm_stats)),
+            capsules::wake_reason::DRIVER_NUM => f(Some(self.wake_reason)),
+            capsules::syscall_benchmark::DRIVER_NUM => f(Some(self.syscall_benchmark)),
+            capsules::process_wait_time::DRIVER_NUM => f(Some(self.process_wait_time)),
+            capsules::system_reset::DRIVER_NUM => f(Some(self.system_reset)),
+            capsules::device_id::DRIVER_NUM => f(Some(self.device_id)),
+            capsules::timeslice_yield::DRIVER_NUM => f(Some(self.timeslice_yield)),
+            capsules::watchdog::DRIVER_NUM => f(Some(self.watchdog)),
+            capsules::memory_barrier::DRIVER_NUM => f(Some(self.memory_barrier)),
+            capsules::power_budget::DRIVER_NUM => f(Some(self.power_budget)),
+            capsules::mpu_limits::DRIVER_NUM => f(Some(self.mpu_limits)),
+            capsules::process_stats::DRIVER_NUM => f(Some(self.process_stats)),
+            capsules::bit_bang::DRIVER_NUM => f(Some(self.bit_bang)),
+            capsules::key_value::DRIVER_NUM => f(Some(self.key_value)),
+            capsules::hibernate_storage::DRIVER_NUM => f(Some(self.hibernate)),
             _ => f(None),
         }
     }
 }
 
+/// Core clock frequency, in cycles per millisecond.
+const CYCLES_PER_MS: u32 = 48_000;
+
+/// Wait for `ms` milliseconds by polling the STimer, which must be started.
+///
+/// The STimer runs from the 32.768 kHz crystal, so it never ticks if the
+/// crystal failed to start. The wait is also bounded by a count of loop
+/// iterations: each takes at least a core clock cycle, so the bound is never
+/// reached before `ms` milliseconds while the STimer runs.
+fn busy_wait_ms(stimer: &apollo3::stimer::STimer, ms: u32) {
+    let start = stimer.now();
+    let ticks = apollo3::stimer::STimer::ticks_from_ms(ms).into_u32();
+    let mut iterations = ms.saturating_mul(CYCLES_PER_MS);
+    while stimer.now().wrapping_sub(start).into_u32() < ticks && iterations > 0 {
+        iterations -= 1;
+    }
+}
+
 /// Reset Handler.
 ///
 /// This symbol is loaded into vector table by the Apollo3 chip crate.
@@ -92,15 +259,20 @@ impl Platform for RedboardArtemisNano {
 #[no_mangle]
 pub unsafe fn reset_handler() {
     apollo3::init();
+    kernel::debug::set_panic_hook(panic_record::save);
 
     let peripherals = static_init!(Apollo3DefaultPeripherals, Apollo3DefaultPeripherals::new());
 
-    // No need to statically allocate mcu/pwr/clk_ctrl because they are only used in main!
-    let mcu_ctrl = apollo3::mcuctrl::McuCtrl::new();
+    // No need to statically allocate pwr/clk_ctrl because they are only used in main!
+    // The system reset and device ID drivers keep a reference to mcu_ctrl.
+    let mcu_ctrl = static_init!(apollo3::mcuctrl::McuCtrl, apollo3::mcuctrl::McuCtrl::new());
     let pwr_ctrl = apollo3::pwrctrl::PwrCtrl::new();
     let clkgen = apollo3::clkgen::ClkGen::new();
 
     clkgen.set_clock_frequency(apollo3::clkgen::ClockFrequency::Freq48MHz);
+    // The Artemis module has a 32.768 kHz crystal; keep the HFRC adjusted
+    // against it for accurate UART and BLE timing.
+    clkgen.set_clock_source(apollo3::clkgen::ClockSource::Crystal);
 
     // initialize capabilities
     let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
@@ -108,7 +280,7 @@ pub unsafe fn reset_handler() {
     let memory_allocation_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 1], Default::default());
+        static_init!([DynamicDeferredCallClientState; 5], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -118,7 +290,7 @@ pub unsafe fn reset_handler() {
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     // Power up components
-    pwr_ctrl.enable_uart0();
+    let uart_powered = pwr_ctrl.enable_uart0();
     pwr_ctrl.enable_iom2();
 
     // Enable PinCfg
@@ -137,18 +309,45 @@ pub unsafe fn reset_handler() {
         None,
     );
 
-    // Create a shared UART channel for the console and for kernel debug.
-    let uart_mux = components::console::UartMuxComponent::new(
-        &peripherals.uart0,
-        115200,
-        dynamic_deferred_caller,
-    )
-    .finalize(());
-
-    // Setup the console.
-    let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
-    // Create the debugger object that handles calls to `debug!()`.
-    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+    // Check that the console UART works before relying on it. If it does
+    // not, for example because of a hardware fault, boot without a console:
+    // `debug!()` output is kept in RAM, where it can be read with a debugger,
+    // and pad 42 is driven high to show that the console is missing.
+    let uart_ok = uart_powered
+        && hil::uart::Configure::configure(
+            &peripherals.uart0,
+            hil::uart::Parameters {
+                baud_rate: 115200,
+                width: hil::uart::Width::Eight,
+                stop_bits: hil::uart::StopBits::One,
+                parity: hil::uart::Parity::None,
+                hw_flow_control: false,
+            },
+        ) == ReturnCode::SUCCESS;
+
+    let console = if uart_ok {
+        // Create a shared UART channel for the console and for kernel debug.
+        let uart_mux = components::console::UartMuxComponent::new(
+            &peripherals.uart0,
+            115200,
+            dynamic_deferred_caller,
+        )
+        .finalize(());
+
+        // Setup the console.
+        let console =
+            components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
+        // Allow apps to temporarily raise the baud rate for bulk transfers.
+        console.set_baud_rate_control(uart_mux);
+        // Create the debugger object that handles calls to `debug!()`.
+        components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+        io::WRITER.set_initialized();
+        Some(console)
+    } else {
+        components::debug_writer::DebugWriterRamComponent::new(dynamic_deferred_caller)
+            .finalize(());
+        None
+    };
 
     // LEDs
     let led = components::led::LedsComponent::new(components::led_component_helper!(
@@ -158,6 +357,13 @@ pub unsafe fn reset_handler() {
     .finalize(components::led_component_buf!(
         LedHigh<'static, apollo3::gpio::GpioPin>
     ));
+    // The blue LED on pad 19 belongs to apps and to kernel debug output, so
+    // the missing console is shown on pad 42 instead, which nothing else uses.
+    if !uart_ok {
+        let no_console = &peripherals.gpio_port[42];
+        hil::gpio::Configure::make_output(no_console);
+        hil::gpio::Output::set(no_console);
+    }
 
     // GPIOs
     // These are also ADC channels, but let's expose them as GPIOs
@@ -174,6 +380,20 @@ pub unsafe fn reset_handler() {
     )
     .finalize(components::gpio_component_buf!(apollo3::gpio::GpioPin));
 
+    // Let apps read the same header pins together, for example as a parallel
+    // bus. They start out as inputs.
+    for &pin in &[11, 13, 29, 31, 33] {
+        hil::gpio::Configure::make_input(&peripherals.gpio_port[pin]);
+    }
+    let gpio_ports = static_init!(
+        capsules::gpio_ports::GpioPorts<'static>,
+        capsules::gpio_ports::GpioPorts::new(
+            &peripherals.gpio_port,
+            &[1 << 11 | 1 << 13 | 1 << 29 | 1 << 31, 1 << (33 - 32)],
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
     // Create a shared virtualisation mux layer on top of a single hardware
     // alarm.
     peripherals.stimer.start();
@@ -182,19 +402,202 @@ pub unsafe fn reset_handler() {
     );
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(apollo3::stimer::STimer));
+    let alarm_stats = static_init!(
+        capsules::alarm_stats::AlarmStats<'static, apollo3::stimer::STimer<'static>>,
+        capsules::alarm_stats::AlarmStats::new(mux_alarm)
+    );
+
+    // Timer for sampled GPIO reads and for coalescing GPIO bank changes.
+    let gpio_alarm = static_init!(
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let gpio_timer = static_init!(
+        capsules::oneshot_timer::AlarmOneshotTimer<
+            'static,
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        >,
+        capsules::oneshot_timer::AlarmOneshotTimer::new(gpio_alarm)
+    );
+    gpio_alarm.set_alarm_client(gpio_timer);
+    gpio_timer.set_client(gpio);
+    gpio.set_sample_timer(gpio_timer);
+    let gpio_edge_counters = static_init!(
+        [Option<&'static dyn hil::gpio::EdgeCounter>; 6],
+        [
+            Some(&peripherals.gpio_port[13]),
+            Some(&peripherals.gpio_port[33]),
+            Some(&peripherals.gpio_port[11]),
+            Some(&peripherals.gpio_port[29]),
+            None,
+            Some(&peripherals.gpio_port[31]),
+        ]
+    );
+    gpio.set_edge_counters(gpio_edge_counters);
 
-    // Init the I2C device attached via Qwiic
-    let i2c_master = static_init!(
-        capsules::i2c_master::I2CMasterDriver<apollo3::iom::Iom<'static>>,
-        capsules::i2c_master::I2CMasterDriver::new(
-            &peripherals.iom2,
-            &mut capsules::i2c_master::BUF,
+    // Time since boot, extending the 32-bit STimer to 64 bits.
+    let uptime_clock = static_init!(
+        kernel::uptime::Uptime<'static, apollo3::stimer::STimer<'static>>,
+        kernel::uptime::Uptime::new(&peripherals.stimer)
+    );
+    peripherals.stimer.set_overflow_client(uptime_clock);
+    board_kernel.set_uptime_clock(uptime_clock, &main_loop_cap);
+    let uptime = static_init!(
+        capsules::uptime::Uptime,
+        capsules::uptime::Uptime::new(
+            board_kernel,
             board_kernel.create_grant(&memory_allocation_cap)
         )
     );
 
-    &peripherals.iom2.set_master_client(i2c_master);
+    // Measurement of system call overhead.
+    let syscall_benchmark = static_init!(
+        capsules::syscall_benchmark::SyscallBenchmark<'static, apollo3::stimer::STimer<'static>>,
+        capsules::syscall_benchmark::SyscallBenchmark::new(
+            &peripherals.stimer,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
+    // How long ready processes wait to run, to spot starvation, timed with
+    // the uptime clock.
+    board_kernel.set_wait_time_clock(uptime_clock, &process_mgmt_cap);
+    let process_wait_time = static_init!(
+        capsules::process_wait_time::ProcessWaitTime<WaitTimeCapability>,
+        capsules::process_wait_time::ProcessWaitTime::new(board_kernel, WaitTimeCapability)
+    );
+
+    // Time the holds of processes that yield for a minimum time.
+    let yield_for_alarm = static_init!(
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let yield_for_wakeup = static_init!(
+        capsules::wakeup_timer::AlarmWakeupTimer<
+            'static,
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        >,
+        capsules::wakeup_timer::AlarmWakeupTimer::new(yield_for_alarm)
+    );
+    yield_for_alarm.set_alarm_client(yield_for_wakeup);
+    board_kernel.set_yield_for_timer(uptime_clock, yield_for_wakeup, &main_loop_cap);
+
+    // The manager app, trusted with resetting the system and tuning the
+    // watchdog, is the first app in flash. Apps are identified by where they
+    // are flashed rather than by their package name, which any app can
+    // claim.
+    let trusted_apps = static_init!([usize; 1], [&_sapps as *const u8 as usize]);
+
+    // Let the manager app reboot the system, for remote recovery.
+    let reset_alarm = static_init!(
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let system_reset = static_init!(
+        capsules::system_reset::SystemResetDriver<
+            'static,
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+            ResetCapability,
+        >,
+        capsules::system_reset::SystemResetDriver::new(
+            mcu_ctrl,
+            reset_alarm,
+            board_kernel,
+            trusted_apps,
+            ResetCapability
+        )
+    );
+    reset_alarm.set_alarm_client(system_reset);
+
+    // Unique chip identifier, for per-device provisioning.
+    let device_id = static_init!(
+        capsules::device_id::DeviceIdDriver,
+        capsules::device_id::DeviceIdDriver::new(
+            mcu_ctrl,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
+    // Let compute-bound apps give up their timeslice at convenient points.
+    let timeslice_yield = static_init!(
+        capsules::timeslice_yield::TimesliceYield,
+        capsules::timeslice_yield::TimesliceYield::new(board_kernel)
+    );
+
+    // Let apps order their writes to buffers shared with other bus masters.
+    let memory_barrier = static_init!(
+        capsules::memory_barrier::MemoryBarrierDriver<'static>,
+        capsules::memory_barrier::MemoryBarrierDriver::new(&cortexm4::support::MemoryBarriers)
+    );
+
+    // Estimate the power peripherals draw, so that solar powered deployments
+    // can set a budget to stay within their harvest. No budget is set by
+    // default.
+    let power_budget = static_init!(
+        kernel::power_budget::PowerBudget,
+        kernel::power_budget::PowerBudget::new()
+    );
+    let power_budget_driver = static_init!(
+        capsules::power_budget::PowerBudgetDriver,
+        capsules::power_budget::PowerBudgetDriver::new(power_budget)
+    );
+
+    // The Qwiic connector may have an SHT3x temperature and humidity sensor
+    // attached. If it answers a probe the sensor gets the bus, otherwise the
+    // bus is given to apps as a raw I2C master, so that one image works on
+    // boards with and without the sensor.
     &peripherals.iom2.enable();
+    let sht3x_present = peripherals.iom2.probe(capsules::sht3x::BASE_ADDR);
+
+    let (i2c_master, temperature, humidity) = if sht3x_present {
+        let i2c_mux =
+            components::i2c::I2CMuxComponent::new(&peripherals.iom2, None, dynamic_deferred_caller)
+                .finalize(components::i2c_mux_component_helper!());
+        let sht3x = components::sht3x::SHT3xComponent::new(i2c_mux, mux_alarm).finalize(
+            components::sht3x_component_helper!(apollo3::stimer::STimer<'static>),
+        );
+        let temperature =
+            components::temperature::TemperatureComponent::new(board_kernel, sht3x).finalize(());
+        let humidity =
+            components::humidity::HumidityComponent::new(board_kernel, sht3x).finalize(());
+
+        (None, Some(temperature), Some(humidity))
+    } else {
+        let i2c_master = static_init!(
+            capsules::i2c_master::I2CMasterDriver<apollo3::iom::Iom<'static>>,
+            capsules::i2c_master::I2CMasterDriver::new(
+                &peripherals.iom2,
+                &mut capsules::i2c_master::BUF,
+                board_kernel.create_grant(&memory_allocation_cap)
+            )
+        );
+
+        // Timer for delays in I2C scripts and clock stretching timeouts.
+        let i2c_alarm = static_init!(
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+            VirtualMuxAlarm::new(mux_alarm)
+        );
+        let i2c_timer = static_init!(
+            capsules::oneshot_timer::AlarmOneshotTimer<
+                'static,
+                VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+            >,
+            capsules::oneshot_timer::AlarmOneshotTimer::new(i2c_alarm)
+        );
+        i2c_alarm.set_alarm_client(i2c_timer);
+        i2c_timer.set_client(i2c_master);
+        i2c_master.set_timer(i2c_timer);
+
+        let i2c_power = static_init!(
+            kernel::power_budget::PowerConsumer,
+            kernel::power_budget::PowerConsumer::new(power_budget, I2C_POWER_UW, &PowerBudgetCap)
+        );
+        i2c_master.set_power_consumer(i2c_power);
+
+        &peripherals.iom2.set_master_client(i2c_master);
+
+        (Some(&*i2c_master), None, None)
+    };
 
     // Setup BLE
     mcu_ctrl.enable_ble();
@@ -207,52 +610,294 @@ pub unsafe fn reset_handler() {
 
     let ble_radio = ble::BLEComponent::new(board_kernel, &peripherals.ble, mux_alarm).finalize(());
 
+    // Let apps keep the chip out of deep sleep. The grant is eager, so an app
+    // in the middle of an operation deep sleep would break can always take a
+    // veto.
+    let sleep_veto = static_init!(
+        capsules::sleep_veto::SleepVeto,
+        capsules::sleep_veto::SleepVeto::new(
+            board_kernel.create_eager_grant(&memory_allocation_cap)
+        )
+    );
+
+    // Warn apps before deep sleep, giving them up to 10 ms to get ready.
+    let pre_sleep_alarm = static_init!(
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let pre_sleep_timer = static_init!(
+        capsules::oneshot_timer::AlarmOneshotTimer<
+            'static,
+            VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        >,
+        capsules::oneshot_timer::AlarmOneshotTimer::new(pre_sleep_alarm)
+    );
+    pre_sleep_alarm.set_alarm_client(pre_sleep_timer);
+    let pre_sleep = static_init!(
+        capsules::pre_sleep::PreSleep<'static>,
+        capsules::pre_sleep::PreSleep::new(
+            sleep_veto,
+            pre_sleep_timer,
+            10_000,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    pre_sleep_timer.set_client(pre_sleep);
+    board_kernel.set_sleep_notifier(pre_sleep, &main_loop_cap);
+
     mcu_ctrl.print_chip_revision();
+    panic_record::report();
+    if let Some(reason) = mcu_ctrl.take_reset_reason() {
+        debug!("Reset requested by an app, reason {:#x}", reason);
+    }
+
+    if sht3x_present {
+        debug!("SHT3x sensor found on Qwiic, raw I2C access is not available");
+    }
 
     debug!("Initialization complete. Entering main loop");
 
     /// These symbols are defined in the linker script.
     extern "C" {
+        /// Beginning of the kernel's zero-initialized RAM.
+        static _szero: u8;
+        /// End of the kernel's zero-initialized RAM.
+        static _ezero: u8;
         /// Beginning of the ROM region containing app images.
         static _sapps: u8;
-        /// End of the ROM region containing app images.
-        static _eapps: u8;
+        /// Beginning of the ROM region containing swappable app images, which
+        /// is the end of the apps loaded at boot.
+        static _sswapapps: u8;
+        /// End of the ROM region containing swappable app images.
+        static _eswapapps: u8;
+        /// Beginning of the ROM region holding the key-value store.
+        static _skeyvalue: u8;
+        /// End of the ROM region holding the key-value store.
+        static _ekeyvalue: u8;
+        /// Beginning of the ROM region holding the hibernation snapshot.
+        static _shibernation: u8;
+        /// End of the ROM region holding the hibernation snapshot.
+        static _ehibernation: u8;
         /// Beginning of the RAM region for app memory.
         static mut _sappmem: u8;
         /// End of the RAM region for app memory.
         static _eappmem: u8;
     }
 
+    let chip = static_init!(
+        apollo3::chip::Apollo3<Apollo3DefaultPeripherals>,
+        apollo3::chip::Apollo3::new(peripherals)
+    );
+    CHIP = Some(chip);
+    chip.set_interrupt_priority(&INTERRUPT_PRIORITY);
+    chip.set_deep_sleep_veto(pre_sleep);
+    chip.set_clock_users(peripherals);
+
+    // Record what wakes the chip, for power debugging.
+    let wake_reason = static_init!(
+        capsules::wake_reason::WakeReason,
+        capsules::wake_reason::WakeReason::new(chip)
+    );
+
+    // Reset the board if the kernel hangs, with a timeout the manager app can
+    // tune for the deployment.
+    chip.enable_watchdog();
+    let watchdog = static_init!(
+        capsules::watchdog::WatchdogDriver<'static, WatchdogCapability>,
+        capsules::watchdog::WatchdogDriver::new(
+            chip.watchdog(),
+            board_kernel,
+            trusted_apps,
+            WatchdogCapability
+        )
+    );
+
+    // Let deployment tools check that apps fit the MPU before loading them.
+    let mpu_limits = static_init!(
+        capsules::mpu_limits::MpuLimitsDriver,
+        capsules::mpu_limits::MpuLimitsDriver::new(chip.mpu().limits(&MpuInfoCap))
+    );
+
+    // Let monitoring apps read process and memory statistics in one snapshot.
+    let process_stats = static_init!(
+        capsules::process_stats::ProcessStats<ProcessStatsCapability>,
+        capsules::process_stats::ProcessStats::new(
+            board_kernel,
+            ProcessStatsCapability,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+
+    // Let apps bit-bang simple protocols such as one-wire on pad 18.
+    let bit_bang_alarm = static_init!(
+        VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let bit_bang = static_init!(
+        capsules::bit_bang::BitBang<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+        capsules::bit_bang::BitBang::new(
+            &peripherals.gpio_port[18],
+            bit_bang_alarm,
+            board_kernel.create_grant(&memory_allocation_cap)
+        )
+    );
+    bit_bang_alarm.set_alarm_client(bit_bang);
+
+    // Key-value store for apps, in the flash region reserved for it by the
+    // linker script.
+    let key_value_flash = static_init!(
+        apollo3::flashctrl::FlashCtrl,
+        apollo3::flashctrl::FlashCtrl::new(
+            &_skeyvalue as *const u8 as usize,
+            &_ekeyvalue as *const u8 as usize - &_skeyvalue as *const u8 as usize,
+            dynamic_deferred_caller,
+        )
+    );
+    key_value_flash.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(key_value_flash)
+            .expect("no deferred call slot available for the key-value flash"),
+    );
+    let key_value_page = static_init!(
+        apollo3::flashctrl::Apollo3Page,
+        apollo3::flashctrl::Apollo3Page::default()
+    );
+    let key_value = static_init!(
+        capsules::key_value::KeyValue<'static, apollo3::flashctrl::FlashCtrl>,
+        capsules::key_value::KeyValue::new(
+            key_value_flash,
+            board_kernel.create_grant(&memory_allocation_cap),
+            key_value_page,
+            &_skeyvalue as *const u8 as usize / apollo3::flashctrl::PAGE_SIZE,
+            (&_ekeyvalue as *const u8 as usize - &_skeyvalue as *const u8 as usize)
+                / apollo3::flashctrl::PAGE_SIZE,
+        )
+    );
+    hil::flash::HasClient::set_client(key_value_flash, key_value);
+    key_value.mount();
+
+    // Let the manager app hibernate the board, saving the processes to the
+    // flash region reserved for them by the linker script.
+    let hibernation_start = &_shibernation as *const u8 as usize;
+    let hibernation_len = &_ehibernation as *const u8 as usize - hibernation_start;
+    let hibernation_flash = static_init!(
+        apollo3::flashctrl::FlashCtrl,
+        apollo3::flashctrl::FlashCtrl::new(
+            hibernation_start,
+            hibernation_len,
+            dynamic_deferred_caller,
+        )
+    );
+    hibernation_flash.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(hibernation_flash)
+            .expect("no deferred call slot available for the hibernation flash"),
+    );
+    let hibernation_page = static_init!(
+        apollo3::flashctrl::Apollo3Page,
+        apollo3::flashctrl::Apollo3Page::default()
+    );
+    let hibernation_storage = static_init!(
+        capsules::nonvolatile_to_pages::NonvolatileToPages<'static, apollo3::flashctrl::FlashCtrl>,
+        capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+            hibernation_flash,
+            hibernation_page
+        )
+    );
+    hil::flash::HasClient::set_client(hibernation_flash, hibernation_storage);
+    let hibernation = static_init!(
+        kernel::hibernate::Hibernation,
+        kernel::hibernate::Hibernation::new(board_kernel, HIBERNATION_VERSION)
+    );
+    // Write the snapshot a whole page at a time, so that each page is only
+    // erased once.
+    let hibernation_buffer = static_init!(
+        [u8; apollo3::flashctrl::PAGE_SIZE],
+        [0; apollo3::flashctrl::PAGE_SIZE]
+    );
+    let hibernate_storage = static_init!(
+        capsules::hibernate_storage::HibernateStorage<'static, HibernationCapability>,
+        capsules::hibernate_storage::HibernateStorage::new(
+            hibernation,
+            hibernation_storage,
+            hibernation_start,
+            hibernation_len,
+            hibernation_buffer,
+            HibernationCapability,
+        )
+    );
+    hil::nonvolatile_storage::NonvolatileStorage::set_client(
+        hibernation_storage,
+        hibernate_storage,
+    );
+    hibernate_storage.set_client(static_init!(PowerOff, PowerOff));
+    let hibernate = static_init!(
+        capsules::hibernate_storage::HibernateDriver<'static, HibernationCapability>,
+        capsules::hibernate_storage::HibernateDriver::new(
+            hibernate_storage,
+            board_kernel,
+            trusted_apps,
+            dynamic_deferred_caller,
+        )
+    );
+    hibernate.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(hibernate)
+            .expect("no deferred call slot available for hibernation"),
+    );
+
     let artemis_nano = static_init!(
         RedboardArtemisNano,
         RedboardArtemisNano {
             alarm,
             console,
             gpio,
+            gpio_ports,
             led,
             i2c_master,
+            temperature,
+            humidity,
             ble_radio,
+            uptime,
+            sleep_veto,
+            pre_sleep,
+            alarm_stats,
+            wake_reason,
+            syscall_benchmark,
+            process_wait_time,
+            system_reset,
+            device_id,
+            timeslice_yield,
+            watchdog,
+            memory_barrier,
+            power_budget: power_budget_driver,
+            mpu_limits,
+            process_stats,
+            bit_bang,
+            key_value,
+            hibernate,
         }
     );
 
-    let chip = static_init!(
-        apollo3::chip::Apollo3<Apollo3DefaultPeripherals>,
-        apollo3::chip::Apollo3::new(peripherals)
-    );
-    CHIP = Some(chip);
+    // Check the hardware behind the drivers before apps rely on it. A
+    // failure is only reported, as the board is still useful without some
+    // of its peripherals.
+    kernel::run_self_tests(artemis_nano, &[capsules::alarm::DRIVER_NUM]);
 
-    kernel::procs::load_processes(
+    let (boot_processes, swap_processes) = PROCESSES.split_at_mut(NUM_PROCS - NUM_SWAP_SLOTS);
+    kernel::procs::load_processes_checking_drivers(
         board_kernel,
         chip,
+        artemis_nano,
         core::slice::from_raw_parts(
             &_sapps as *const u8,
-            &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+            &_sswapapps as *const u8 as usize - &_sapps as *const u8 as usize,
         ),
         core::slice::from_raw_parts_mut(
             &mut _sappmem as *mut u8,
             &_eappmem as *const u8 as usize - &_sappmem as *const u8 as usize,
         ),
-        &mut PROCESSES,
+        boot_processes,
         FAULT_RESPONSE,
         &process_mgmt_cap,
     )
@@ -261,9 +906,61 @@ pub unsafe fn reset_handler() {
         debug!("{:?}", err);
     });
 
+    // Run more apps than fit in RAM by swapping them through the swap slots.
+    // The first ones start at boot, others can be swapped in for them later.
+    let (swap_entry0, swap_entry1) = swap_processes.split_at_mut(1);
+    let [swap_memory0, swap_memory1] = &mut SWAP_MEMORY;
+    let swap_slots = static_init!(
+        [kernel::swap::SwapSlot; NUM_SWAP_SLOTS],
+        [
+            kernel::swap::SwapSlot::new(&mut swap_entry0[0], swap_memory0),
+            kernel::swap::SwapSlot::new(&mut swap_entry1[0], swap_memory1),
+        ]
+    );
+    let swap = static_init!(
+        kernel::swap::SwapManager<
+            apollo3::chip::Apollo3<Apollo3DefaultPeripherals>,
+            RedboardArtemisNano,
+        >,
+        kernel::swap::SwapManager::new(
+            board_kernel,
+            chip,
+            artemis_nano,
+            core::slice::from_raw_parts(
+                &_sswapapps as *const u8,
+                &_eswapapps as *const u8 as usize - &_sswapapps as *const u8 as usize,
+            ),
+            swap_slots,
+            FAULT_RESPONSE,
+        )
+    );
+    for app in 0..core::cmp::min(swap.app_count(), NUM_SWAP_SLOTS) {
+        if let Err(err) = swap.swap_in(app, &process_mgmt_cap) {
+            debug!("Could not swap in app {}: {:?}", app, err);
+        }
+    }
+
+    // Resume the processes where they were when the board last hibernated.
+    // Without a valid snapshot they simply cold start.
+    let hibernation_snapshot =
+        core::slice::from_raw_parts(hibernation_start as *const u8, hibernation_len);
+    if hibernation.restore(hibernation_snapshot, &process_mgmt_cap) == ReturnCode::SUCCESS {
+        hibernate_storage.invalidate();
+    }
+
     let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
         .finalize(components::rr_component_helper!(NUM_PROCS));
 
+    let (static_count, static_bytes) = kernel::common::utils::static_allocations();
+    debug!(
+        "Static allocations: {} objects, {} bytes of {} bytes kernel BSS",
+        static_count,
+        static_bytes,
+        &_ezero as *const u8 as usize - &_szero as *const u8 as usize,
+    );
+
+    busy_wait_ms(&peripherals.stimer, STARTUP_DELAY_MS);
+
     board_kernel.kernel_loop(
         artemis_nano,
         chip,
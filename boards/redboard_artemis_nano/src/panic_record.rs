@@ -0,0 +1,121 @@
+//! Keeps the last panic in flash so it can be read after the reboot.
+//!
+//! The board runs headless, so a panic message printed to the UART is usually
+//! lost. `save()` runs as the panic hook and writes a bounded record to the
+//! last flash page, which is reserved for it in `layout.ld`. At the next boot
+//! `report()` prints a stored record with `debug!()` and erases it.
+//!
+//! The record is a sequence of little-endian words:
+//!
+//! | Offset | Value                                                     |
+//! |--------|-----------------------------------------------------------|
+//! | 0      | `MAGIC`                                                   |
+//! | 4      | PC of the kernel instruction that faulted, or 0           |
+//! | 8      | LR at that fault, or 0                                    |
+//! | 12     | Index of the faulted process, or `NO_PROCESS`             |
+//! | 16     | Length of the message in bytes                            |
+//! | 20     | Panic message, truncated to `MESSAGE_LEN` bytes           |
+//!
+//! The PC and LR are only known when the panic comes from a kernel hard
+//! fault. A process fault panics with the process still in the `Fault` state,
+//! which identifies the process.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use crate::PROCESSES;
+use apollo3::flashctrl;
+use kernel::procs::State;
+use kernel::{debug, ReturnCode};
+
+/// Start of the flash page holding the record.
+const RECORD_ADDRESS: usize = flashctrl::FLASH_END - flashctrl::PAGE_SIZE;
+
+/// Marks a valid record. Erased flash reads as all ones.
+const MAGIC: u32 = 0x5041_4e43;
+
+/// Process index stored when no process faulted.
+const NO_PROCESS: u32 = u32::MAX;
+
+const HEADER_WORDS: usize = 5;
+const RECORD_WORDS: usize = 64;
+
+/// Bytes of the panic message that are kept.
+const MESSAGE_LEN: usize = (RECORD_WORDS - HEADER_WORDS) * 4;
+
+/// Collects formatted output into a fixed buffer, dropping what does not fit.
+struct Message {
+    bytes: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = core::cmp::min(s.len(), MESSAGE_LEN - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Write a record of the panic to flash. Registered as the panic hook.
+///
+/// Failures are ignored: the panic goes on to print to the UART as usual.
+pub fn save(info: &PanicInfo) {
+    let mut message = Message {
+        bytes: [0; MESSAGE_LEN],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+
+    let (pc, lr) = cortexm4::kernel_fault().unwrap_or((0, 0));
+    let process = unsafe { PROCESSES.iter() }
+        .position(|process| process.map_or(false, |p| p.get_state() == State::Fault))
+        .map_or(NO_PROCESS, |index| index as u32);
+
+    let mut record = [0xffff_ffff; RECORD_WORDS];
+    record[..HEADER_WORDS].copy_from_slice(&[MAGIC, pc, lr, process, message.len as u32]);
+    for (word, chunk) in record[HEADER_WORDS..]
+        .iter_mut()
+        .zip(message.bytes.chunks_exact(4))
+    {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+
+    unsafe {
+        if flashctrl::erase_page(RECORD_ADDRESS) == ReturnCode::SUCCESS {
+            flashctrl::write_words(RECORD_ADDRESS, &record);
+        }
+    }
+}
+
+/// Print the record of a panic before the last reset, if there is one, and
+/// erase it so it is only reported once.
+pub unsafe fn report() {
+    let record = core::slice::from_raw_parts(RECORD_ADDRESS as *const u32, RECORD_WORDS);
+    if record[0] != MAGIC {
+        return;
+    }
+
+    let len = core::cmp::min(record[4] as usize, MESSAGE_LEN);
+    let bytes = core::slice::from_raw_parts((RECORD_ADDRESS + HEADER_WORDS * 4) as *const u8, len);
+    // Truncation may have split a character, keep what precedes it.
+    let message = match core::str::from_utf8(bytes) {
+        Ok(message) => message,
+        Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+    };
+    debug!("Previous boot ended in a panic: {}", message);
+    if record[1] != 0 {
+        debug!(
+            "\tKernel fault at PC {:#010x}, LR {:#010x}",
+            record[1], record[2]
+        );
+    }
+    if record[3] != NO_PROCESS {
+        debug!("\tFaulted process index {}", record[3]);
+    }
+
+    if flashctrl::erase_page(RECORD_ADDRESS) != ReturnCode::SUCCESS {
+        debug!("Could not erase the panic record, it will be reported again");
+    }
+}
@@ -1,5 +1,18 @@
 //! Tock syscall driver capsule for Alarms, which issue callbacks when
 //! a point in time has been reached.
+//!
+//! An alarm can also repeat with a fixed period, in which case the capsule
+//! re-arms it each time it fires. Each period is measured from the end of
+//! the previous one rather than from when the callback is delivered, so a
+//! repeating alarm does not drift. The callback's second argument is the end
+//! of the period that just ended, so it moves forward by one period with each
+//! callback.
+//!
+//! An app that is slow can miss periods. Periods that end while the callback
+//! of an earlier one has not been delivered yet, or that pass entirely before
+//! the alarm is serviced, get no callback of their own. The next callback
+//! reports how many periods were missed since the previous one in its third
+//! argument.
 
 use core::cell::Cell;
 use kernel::hil::time::{self, Alarm, Frequency, Ticks, Ticks32};
@@ -9,6 +22,10 @@ use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Alarm as usize;
 
+/// Number of times the self-test reads the clock waiting for it to advance.
+/// This is enough for a tick of a 1 kHz clock on a CPU running at 100 MHz.
+const SELF_TEST_POLLS: usize = 1_000_000;
+
 #[derive(Copy, Clone, Debug)]
 enum Expiration {
     Disabled,
@@ -18,6 +35,11 @@ enum Expiration {
 #[derive(Copy, Clone)]
 pub struct AlarmData {
     expiration: Expiration,
+    /// Period of a repeating alarm in ticks, or 0 for a single alarm.
+    period: u32,
+    /// Periods of a repeating alarm that ended without a callback, since the
+    /// last callback was scheduled.
+    missed: u32,
     callback: Option<Callback>,
 }
 
@@ -25,11 +47,22 @@ impl Default for AlarmData {
     fn default() -> AlarmData {
         AlarmData {
             expiration: Expiration::Disabled,
+            period: 0,
+            missed: 0,
             callback: None,
         }
     }
 }
 
+/// Where a repeating alarm whose period ended at `end` starts its next
+/// period, given that it is serviced at `now`. Periods that ended in the
+/// meantime are skipped: returns the start of the next period and how many
+/// periods were missed.
+fn next_period(end: u32, period: u32, now: u32) -> (u32, u32) {
+    let missed = now.wrapping_sub(end) / period;
+    (end.wrapping_add(missed.wrapping_mul(period)), missed)
+}
+
 pub struct AlarmDriver<'a, A: Alarm<'a>> {
     alarm: &'a A,
     num_armed: Cell<usize>,
@@ -49,7 +82,10 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
 
     // This logic is tricky because it needs to handle the case when the
     // underlying alarm is wider than 32 bits.
-    fn reset_active_alarm(&self) {
+    //
+    // Returns `EBUSY` if the underlying alarm refused to be armed because
+    // too many alarms share its hardware timer.
+    fn reset_active_alarm(&self) -> ReturnCode {
         let mut earliest_alarm = Expiration::Disabled;
         let mut earliest_end: A::Ticks = A::Ticks::from(0);
         // Scale now down to a u32 since that is the width of the alarm;
@@ -122,6 +158,7 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
         match earliest_alarm {
             Expiration::Disabled => {
                 self.alarm.disarm();
+                ReturnCode::SUCCESS
             }
             Expiration::Enabled { reference, dt } => {
                 // This logic handles when the underlying Alarm is wider than
@@ -137,7 +174,7 @@ impl<'a, A: Alarm<'a>> AlarmDriver<'a, A> {
                     high_bits = high_bits.wrapping_sub(bit33);
                 }
                 let real_reference = high_bits.wrapping_add(A::Ticks::from(reference));
-                self.alarm.set_alarm(real_reference, A::Ticks::from(dt));
+                self.alarm.try_set_alarm(real_reference, A::Ticks::from(dt))
             }
         }
     }
@@ -173,6 +210,13 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
     /// - `3`: Stop the alarm if it is outstanding
     /// - `4`: Set an alarm to fire at a given clock value `time`.
     /// - `5`: Set an alarm to fire at a given clock value `time` relative to `now` (EXPERIMENTAL).
+    /// - `6`: Set an alarm to fire at a given clock value `time` relative to a
+    ///   given `reference`.
+    /// - `7`: Set an alarm to fire every `period` ticks, starting `period`
+    ///   ticks from now. Stopped with command `3`.
+    ///
+    /// Setting an alarm returns `EBUSY` if the underlying alarm cannot be
+    /// armed because too many alarms are already armed on its hardware timer.
     fn command(&self, cmd_type: usize, data: usize, data2: usize, caller_id: AppId) -> ReturnCode {
         // Returns the error code to return to the user and whether we need to
         // reset which is the next active alarm. We _don't_ reset if
@@ -181,8 +225,11 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
         //   - on an error (i.e. no change to the alarms).
         self.app_alarms
             .enter(caller_id, |td, _alloc| {
+                let previous = td.expiration;
+                let previous_period = td.period;
+                let previous_missed = td.missed;
                 // helper function to rearm alarm
-                let mut rearm = |reference: usize, dt: usize| {
+                let mut rearm = |reference: usize, dt: usize, period: u32| {
                     if let Expiration::Disabled = td.expiration {
                         self.num_armed.set(self.num_armed.get() + 1);
                     }
@@ -190,6 +237,8 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         reference: reference as u32,
                         dt: dt as u32,
                     };
+                    td.period = period;
+                    td.missed = 0;
                     (
                         ReturnCode::SuccessWithValue {
                             value: reference.wrapping_add(dt),
@@ -227,13 +276,13 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         let future_time = data;
                         let dt = future_time.wrapping_sub(reference);
                         // if previously unarmed, but now will become armed
-                        rearm(reference, dt)
+                        rearm(reference, dt, 0)
                     },
                     5 /* Set relative expiration */ => {
                         let reference = now.into_u32() as usize;
                         let dt = data;
                         // if previously unarmed, but now will become armed
-                        rearm(reference, dt)
+                        rearm(reference, dt, 0)
                     },
                     6 /* Set absolute expiration with reference point */ => {
                         // Taking a reference timestamp from userspace
@@ -243,17 +292,49 @@ impl<'a, A: Alarm<'a>> Driver for AlarmDriver<'a, A> {
                         // comamnd for backwards compatibility. -pal
                         let reference = data;
                         let dt = data2;
-                        rearm(reference, dt)
+                        rearm(reference, dt, 0)
+                    }
+                    7 /* Set repeating expiration */ => {
+                        let reference = now.into_u32() as usize;
+                        let period = data as u32;
+                        if period == 0 {
+                            (ReturnCode::EINVAL, false)
+                        } else {
+                            rearm(reference, period as usize, period)
+                        }
                     }
                     _ => (ReturnCode::ENOSUPPORT, false)
                 };
                 if reset {
-                    self.reset_active_alarm();
+                    let result = self.reset_active_alarm();
+                    if result != ReturnCode::SUCCESS {
+                        // The underlying alarm is only refused when it was
+                        // not armed, so no other alarm depends on it: undo
+                        // this request and report the failure.
+                        if let Expiration::Disabled = previous {
+                            self.num_armed.set(self.num_armed.get() - 1);
+                        }
+                        td.expiration = previous;
+                        td.period = previous_period;
+                        td.missed = previous_missed;
+                        return result;
+                    }
                 }
                 return_code
             })
             .unwrap_or_else(|err| err.into())
     }
+
+    /// Check that the clock counts, by reading it until it advances.
+    fn self_test(&self) -> ReturnCode {
+        let start = self.alarm.now().into_u32();
+        let counts = (0..SELF_TEST_POLLS).any(|_| self.alarm.now().into_u32() != start);
+        if counts {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        }
+    }
 }
 
 impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
@@ -267,14 +348,28 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
                     Ticks32::from(reference),
                     Ticks32::from(reference.wrapping_add(dt)),
                 ) {
-                    alarm.expiration = Expiration::Disabled;
-                    self.num_armed.set(self.num_armed.get() - 1);
+                    let end = reference.wrapping_add(dt);
+                    if alarm.period == 0 {
+                        alarm.expiration = Expiration::Disabled;
+                        self.num_armed.set(self.num_armed.get() - 1);
+                    } else {
+                        // Stays armed for the next period.
+                        let (next, missed) = next_period(end, alarm.period, now.into_u32());
+                        alarm.expiration = Expiration::Enabled {
+                            reference: next,
+                            dt: alarm.period,
+                        };
+                        alarm.missed = alarm.missed.saturating_add(missed);
+                        // The app has not received the callback of an earlier
+                        // period yet, so this one is missed as well.
+                        if alarm.callback.map_or(false, |cb| cb.is_pending()) {
+                            alarm.missed = alarm.missed.saturating_add(1);
+                            return;
+                        }
+                    }
+                    let missed = core::mem::replace(&mut alarm.missed, 0);
                     alarm.callback.map(|mut cb| {
-                        cb.schedule(
-                            now.into_u32() as usize,
-                            reference.wrapping_add(dt) as usize,
-                            0,
-                        )
+                        cb.schedule(now.into_u32() as usize, end as usize, missed as usize)
                     });
                 }
             }
@@ -286,7 +381,89 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmDriver<'a, A> {
         if self.num_armed.get() == 0 {
             self.alarm.disarm();
         } else {
+            // The underlying alarm was disarmed just before this callback,
+            // so there is room to arm it again.
             self.reset_active_alarm();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{next_period, AlarmDriver, DRIVER_NUM};
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::hil::time::Alarm;
+    use kernel::procs::ProcessType;
+    use kernel::testing::{MockAlarm, MockProcess};
+    use kernel::{create_capability, Kernel, ReturnCode};
+    use std::boxed::Box;
+
+    #[test]
+    fn repeats_every_period() {
+        // A 100 ms period on a 1 kHz clock over one second, with each
+        // callback serviced a few ticks late.
+        let (period, latency) = (100, 7);
+        let mut end = period;
+        let mut callbacks = 0;
+        while end <= 1000 {
+            let (next, missed) = next_period(end, period, end + latency);
+            assert_eq!(missed, 0);
+            callbacks += 1;
+            end = next + period;
+        }
+        assert_eq!(callbacks, 10);
+    }
+
+    #[test]
+    fn missed_periods_are_coalesced() {
+        // Serviced 250 ms after the period ending at 100 ms: the periods
+        // ending at 200 and 300 ms were missed, and the next ends at 400 ms.
+        assert_eq!(next_period(100, 100, 350), (300, 2));
+    }
+
+    #[test]
+    fn repeats_across_counter_wrap() {
+        // The period ending 50 ticks before the counter wraps is serviced
+        // 200 ticks later, so the periods ending at 50 and 150 were missed.
+        assert_eq!(next_period(u32::MAX - 49, 100, 150), (150, 2));
+    }
+
+    #[test]
+    fn undelivered_periods_are_counted_as_missed() {
+        let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+        let kernel = Box::leak(Box::new(Kernel::new(processes)));
+        process.attach(kernel, 0);
+        let alarm: &'static MockAlarm = Box::leak(Box::new(MockAlarm::new()));
+        let driver: &'static AlarmDriver<MockAlarm> = Box::leak(Box::new(AlarmDriver::new(
+            alarm,
+            kernel.create_grant(&memory_allocation_cap),
+        )));
+        alarm.set_alarm_client(driver);
+
+        assert_eq!(
+            process.subscribe(driver, DRIVER_NUM, 0),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            process.command(driver, 7, 100, 0),
+            ReturnCode::SuccessWithValue { value: 100 }
+        );
+
+        // A 100 ms period over one second, each callback delivered in time.
+        for end in (100..=1000).step_by(100) {
+            assert!(alarm.fire());
+            assert_eq!(process.take_callback(), Some((0, end, end, 0)));
+        }
+
+        // The app is busy while the next three periods end.
+        for _ in 0..3 {
+            assert!(alarm.fire());
+        }
+        assert_eq!(process.take_callback(), Some((0, 1100, 1100, 0)));
+        assert_eq!(process.take_callback(), None);
+        assert!(alarm.fire());
+        assert_eq!(process.take_callback(), Some((0, 1400, 1400, 2)));
+    }
+}
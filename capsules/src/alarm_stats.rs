@@ -0,0 +1,82 @@
+//! Provides userspace with the usage counters of an alarm mux.
+//!
+//! All virtual alarms on a board usually share one hardware alarm through a
+//! `MuxAlarm`. When many alarms are in use the mux re-arms the hardware alarm
+//! often and alarms can fire late. This capsule exposes the mux's counters so
+//! that such overload can be diagnosed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let alarm_stats = static_init!(
+//!     capsules::alarm_stats::AlarmStats<'static, apollo3::stimer::STimer<'static>>,
+//!     capsules::alarm_stats::AlarmStats::new(mux_alarm)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the number of virtual alarms that are currently armed.
+//! - `2`: Return the number of times the hardware alarm has been set.
+//! - `3`: Return the number of alarms that fired late.
+//! - `4`: Reset the counters returned by commands `2` and `3`.
+//!
+//! The counters saturate instead of wrapping.
+
+use kernel::hil::time::Alarm;
+use kernel::{AppId, Driver, ReturnCode};
+
+use crate::virtual_alarm::MuxAlarm;
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::AlarmStats as usize;
+
+pub struct AlarmStats<'a, A: Alarm<'a>> {
+    mux: &'a MuxAlarm<'a, A>,
+}
+
+impl<'a, A: Alarm<'a>> AlarmStats<'a, A> {
+    pub fn new(mux: &'a MuxAlarm<'a, A>) -> AlarmStats<'a, A> {
+        AlarmStats { mux: mux }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for AlarmStats<'a, A> {
+    /// Read and reset the alarm mux counters.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Number of armed virtual alarms.
+    /// - `2`: Number of hardware alarm re-arms.
+    /// - `3`: Number of missed deadlines.
+    /// - `4`: Reset the re-arm and missed deadline counters.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        let stats = self.mux.stats();
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue {
+                value: stats.active,
+            },
+            2 => ReturnCode::SuccessWithValue {
+                value: stats.rearms as usize,
+            },
+            3 => ReturnCode::SuccessWithValue {
+                value: stats.missed_deadlines as usize,
+            },
+            4 => {
+                self.mux.reset_stats();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
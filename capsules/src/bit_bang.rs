@@ -0,0 +1,562 @@
+//! Timed drive and sample of a GPIO pin, for bit-banging simple protocols.
+//!
+//! Sensors such as the DS18B20 use protocols like one-wire that are neither
+//! I2C nor SPI, and are implemented by driving and sampling a single pin at
+//! precise times. Apps cannot do that themselves, as they are only scheduled
+//! from time to time and every system call costs time. This capsule instead
+//! runs short scripts of pin operations from the alarm interrupt.
+//!
+//! A script is a sequence of operations, each of which drives the pin low or
+//! high, releases it (makes it an input with the pull-up enabled), samples it
+//! or does nothing, and then waits a number of microseconds before the next
+//! one. Waits are timed from the start of the script rather than from the
+//! previous operation, so rounding to alarm ticks does not add up. Samples
+//! are stored as bits in a buffer the app allows, first sample in the least
+//! significant bit of the first byte.
+//!
+//! Timing accuracy
+//! ---------------
+//!
+//! Operations without a wait between them run back to back, within a few
+//! processor cycles of each other. An operation that follows a wait runs from
+//! the alarm interrupt, so it starts up to one alarm tick late plus the
+//! interrupt latency, which is longer while the kernel handles other
+//! interrupts. Waits shorter than the alarm can time, `minimum_dt()` ticks,
+//! are rejected with `EINVAL` rather than silently lengthened. With the 16 kHz
+//! Apollo3 STimer a tick is 61 microseconds and the shortest wait is 123
+//! microseconds, which is enough for the reset pulse of one-wire (480 us) but
+//! not for its 1 to 15 microsecond read and write slots. Those need a faster
+//! alarm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let bit_bang_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let bit_bang = static_init!(
+//!     capsules::bit_bang::BitBang<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::bit_bang::BitBang::new(
+//!         &peripherals.gpio_port[18],
+//!         bit_bang_alarm,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! bit_bang_alarm.set_alarm_client(bit_bang);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: The script, four bytes per operation: the operation (`0` drive low,
+//!   `1` drive high, `2` release, `3` sample, `4` none), then the wait after
+//!   it as a little-endian 24-bit number of microseconds.
+//! - `1`: The buffer samples are stored in.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Called with the number of operations run, the number of samples
+//!   taken and the level of the last sample once a script or timed operation
+//!   completes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Drive the pin now: low if `data1` is `0`, high if `1`, release if
+//!   `2`.
+//! - `2`: Sample the pin now. Returns its level.
+//! - `3`: Drive the pin low if `data1` is `0` or high otherwise for `data2`
+//!   microseconds, then release it.
+//! - `4`: Wait `data1` microseconds, then sample the pin.
+//! - `5`: Run the script in the allowed buffer.
+//! - `6`: Abort the running script or timed operation of the app. Returns the
+//!   number of operations run, or `EINVAL` if none is running.
+//! - `7`: The shortest wait supported, in microseconds.
+//!
+//! Commands that drive the pin or start operations return `EBUSY` while a
+//! script or timed operation is running. Commands that start operations
+//! return `EINVAL` for a wait shorter than the shortest supported, other than
+//! zero, or longer than 2^24 - 1 microseconds, for an empty or malformed
+//! script, and `ESIZE` if the script takes more samples than fit in the
+//! sample buffer.
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::gpio;
+use kernel::hil::time::{Alarm, AlarmClient, Frequency, Ticks};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BitBang as usize;
+
+/// Longest wait after an operation, in microseconds.
+const MAX_WAIT_US: u32 = (1 << 24) - 1;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    script: Option<AppSlice<Shared, u8>>,
+    samples: Option<AppSlice<Shared, u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Low,
+    High,
+    Release,
+    Sample,
+    None,
+}
+
+/// An operation on the pin and the wait that follows it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Operation {
+    action: Action,
+    wait_us: u32,
+}
+
+/// The operation encoded in the four bytes at `index` of a script.
+fn operation(script: &[u8], index: usize) -> Option<Operation> {
+    let bytes = script.chunks_exact(4).nth(index)?;
+    let action = match bytes[0] {
+        0 => Action::Low,
+        1 => Action::High,
+        2 => Action::Release,
+        3 => Action::Sample,
+        4 => Action::None,
+        _ => return None,
+    };
+    let wait_us = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], 0]);
+    Some(Operation { action, wait_us })
+}
+
+/// Alarm ticks from the start of a script to `us` microseconds into it.
+fn ticks_at(us: u64, frequency: u32) -> u32 {
+    (us * frequency as u64 / 1_000_000) as u32
+}
+
+/// The shortest wait in microseconds that spans at least `minimum_dt` ticks,
+/// and at least one.
+fn min_wait_us(minimum_dt: u32, frequency: u32) -> u32 {
+    let ticks = core::cmp::max(minimum_dt, 1) as u64;
+    ((ticks * 1_000_000 + frequency as u64 - 1) / frequency as u64) as u32
+}
+
+/// Whether the alarm can time a wait of `wait_us`.
+fn valid_wait(wait_us: u32, min_wait_us: u32) -> bool {
+    wait_us == 0 || (wait_us >= min_wait_us && wait_us <= MAX_WAIT_US)
+}
+
+/// The number of samples `script` takes, or `None` if it is empty or not a
+/// valid script.
+fn script_samples(script: &[u8], min_wait_us: u32) -> Option<usize> {
+    if script.is_empty() || script.len() % 4 != 0 {
+        return None;
+    }
+    let mut samples = 0;
+    for index in 0..script.len() / 4 {
+        let op = operation(script, index)?;
+        if !valid_wait(op.wait_us, min_wait_us) {
+            return None;
+        }
+        if op.action == Action::Sample {
+            samples += 1;
+        }
+    }
+    Some(samples)
+}
+
+/// Where the running operations come from.
+#[derive(Clone, Copy)]
+enum Source {
+    /// The script the app allowed.
+    Script,
+    /// A timed operation started with a command.
+    Timed([Operation; 2]),
+}
+
+pub struct BitBang<'a, A: Alarm<'a>> {
+    pin: &'a dyn gpio::Pin,
+    alarm: &'a A,
+    apps: Grant<App>,
+    /// The app whose operations are running.
+    active_app: OptionalCell<AppId>,
+    source: Cell<Source>,
+    /// Operations run so far.
+    completed: Cell<usize>,
+    /// Samples taken so far, and the level of the last one.
+    samples: Cell<usize>,
+    last_sample: Cell<bool>,
+    /// Alarm ticks at the start of the running operations.
+    start: Cell<u32>,
+    /// Microseconds from the start to the next operation.
+    next_us: Cell<u64>,
+}
+
+impl<'a, A: Alarm<'a>> BitBang<'a, A> {
+    pub fn new(pin: &'a dyn gpio::Pin, alarm: &'a A, grant: Grant<App>) -> BitBang<'a, A> {
+        BitBang {
+            pin: pin,
+            alarm: alarm,
+            apps: grant,
+            active_app: OptionalCell::empty(),
+            source: Cell::new(Source::Script),
+            completed: Cell::new(0),
+            samples: Cell::new(0),
+            last_sample: Cell::new(false),
+            start: Cell::new(0),
+            next_us: Cell::new(0),
+        }
+    }
+
+    fn min_wait_us(&self) -> u32 {
+        min_wait_us(
+            self.alarm.minimum_dt().into_u32(),
+            <A::Frequency>::frequency(),
+        )
+    }
+
+    fn drive(&self, action: Action) {
+        match action {
+            Action::Low => {
+                self.pin.make_output();
+                self.pin.clear();
+            }
+            Action::High => {
+                self.pin.make_output();
+                self.pin.set();
+            }
+            Action::Release => {
+                self.pin.make_input();
+                self.pin.set_floating_state(gpio::FloatingState::PullUp);
+            }
+            Action::Sample | Action::None => {}
+        }
+    }
+
+    /// Begin running operations from `source` for `appid`.
+    fn begin(&self, appid: AppId, source: Source) {
+        self.active_app.set(appid);
+        self.source.set(source);
+        self.completed.set(0);
+        self.samples.set(0);
+        self.last_sample.set(false);
+        self.start.set(self.alarm.now().into_u32());
+        self.next_us.set(0);
+        self.run(appid);
+    }
+
+    fn start_script(&self, appid: AppId) -> ReturnCode {
+        if self.active_app.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let min_wait_us = self.min_wait_us();
+        let res = self.apps.enter(appid, |app, _| {
+            let script = match app.script {
+                Some(ref slice) => slice.as_ref(),
+                None => return ReturnCode::EINVAL,
+            };
+            let samples = match script_samples(script, min_wait_us) {
+                Some(samples) => samples,
+                None => return ReturnCode::EINVAL,
+            };
+            let sample_bits = app.samples.as_ref().map_or(0, |slice| slice.len() * 8);
+            if samples > sample_bits {
+                return ReturnCode::ESIZE;
+            }
+            ReturnCode::SUCCESS
+        });
+        match res {
+            Ok(ReturnCode::SUCCESS) => {
+                self.begin(appid, Source::Script);
+                ReturnCode::SUCCESS
+            }
+            Ok(err) => err,
+            Err(err) => err.into(),
+        }
+    }
+
+    fn start_timed(&self, appid: AppId, operations: [Operation; 2]) -> ReturnCode {
+        if self.active_app.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let min_wait_us = self.min_wait_us();
+        if !operations
+            .iter()
+            .all(|op| valid_wait(op.wait_us, min_wait_us))
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.begin(appid, Source::Timed(operations));
+        ReturnCode::SUCCESS
+    }
+
+    /// Run operations of `appid` until one is followed by a wait, or all
+    /// have run.
+    fn run(&self, appid: AppId) {
+        let res = self.apps.enter(appid, |app, _| loop {
+            let index = self.completed.get();
+            let next = match self.source.get() {
+                Source::Script => app
+                    .script
+                    .as_ref()
+                    .and_then(|slice| operation(slice.as_ref(), index)),
+                Source::Timed(operations) => operations.get(index).copied(),
+            };
+            let op = match next {
+                Some(op) => op,
+                None => {
+                    self.active_app.clear();
+                    app.callback.map(|mut cb| {
+                        cb.schedule(
+                            self.completed.get(),
+                            self.samples.get(),
+                            self.last_sample.get() as usize,
+                        )
+                    });
+                    break;
+                }
+            };
+
+            if op.action == Action::Sample {
+                let level = self.pin.read();
+                let sample = self.samples.get();
+                if let Source::Script = self.source.get() {
+                    app.samples.as_mut().map(|slice| {
+                        slice.as_mut().get_mut(sample / 8).map(|byte| {
+                            if level {
+                                *byte |= 1 << (sample % 8);
+                            } else {
+                                *byte &= !(1 << (sample % 8));
+                            }
+                        });
+                    });
+                }
+                self.samples.set(sample + 1);
+                self.last_sample.set(level);
+            } else {
+                self.drive(op.action);
+            }
+            self.completed.set(index + 1);
+
+            if op.wait_us > 0 {
+                let frequency = <A::Frequency>::frequency();
+                let from_us = self.next_us.get();
+                let to_us = from_us + op.wait_us as u64;
+                let from = self.start.get().wrapping_add(ticks_at(from_us, frequency));
+                let dt = ticks_at(to_us, frequency).wrapping_sub(ticks_at(from_us, frequency));
+                self.alarm
+                    .set_alarm(A::Ticks::from(from), A::Ticks::from(dt));
+                self.next_us.set(to_us);
+                break;
+            }
+        });
+        if res.is_err() {
+            // The app is gone.
+            self.active_app.clear();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for BitBang<'a, A> {
+    fn alarm(&self) {
+        self.active_app.map(|appid| self.run(*appid));
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for BitBang<'a, A> {
+    /// Setup the script and the sample buffer.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The script. Cannot change while operations of the app run.
+    /// - `1`: The buffer samples are stored in. Cannot change while
+    ///   operations of the app run.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        if allow_num > 1 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.active_app.contains(&appid) {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                if allow_num == 0 {
+                    app.script = slice;
+                } else {
+                    app.samples = slice;
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup the completion callback.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when a script or timed operation completes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Drive and sample the pin.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Drive the pin low, high or release it now.
+    /// - `2`: Sample the pin now.
+    /// - `3`: Drive the pin for `data2` microseconds, then release it.
+    /// - `4`: Sample the pin after `data1` microseconds.
+    /// - `5`: Run the allowed script.
+    /// - `6`: Abort and return the number of operations run.
+    /// - `7`: Shortest wait in microseconds.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                if self.active_app.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                match data1 {
+                    0 => self.drive(Action::Low),
+                    1 => self.drive(Action::High),
+                    2 => self.drive(Action::Release),
+                    _ => return ReturnCode::EINVAL,
+                }
+                ReturnCode::SUCCESS
+            }
+            2 => ReturnCode::SuccessWithValue {
+                value: self.pin.read() as usize,
+            },
+            3 => {
+                let action = if data1 == 0 {
+                    Action::Low
+                } else {
+                    Action::High
+                };
+                if data2 == 0 || data2 > MAX_WAIT_US as usize {
+                    return ReturnCode::EINVAL;
+                }
+                self.start_timed(
+                    appid,
+                    [
+                        Operation {
+                            action,
+                            wait_us: data2 as u32,
+                        },
+                        Operation {
+                            action: Action::Release,
+                            wait_us: 0,
+                        },
+                    ],
+                )
+            }
+            4 => {
+                if data1 == 0 || data1 > MAX_WAIT_US as usize {
+                    return ReturnCode::EINVAL;
+                }
+                self.start_timed(
+                    appid,
+                    [
+                        Operation {
+                            action: Action::None,
+                            wait_us: data1 as u32,
+                        },
+                        Operation {
+                            action: Action::Sample,
+                            wait_us: 0,
+                        },
+                    ],
+                )
+            }
+            5 => self.start_script(appid),
+            6 => {
+                if !self.active_app.contains(&appid) {
+                    return ReturnCode::EINVAL;
+                }
+                self.alarm.disarm();
+                self.active_app.clear();
+                ReturnCode::SuccessWithValue {
+                    value: self.completed.get(),
+                }
+            }
+            7 => ReturnCode::SuccessWithValue {
+                value: self.min_wait_us() as usize,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{min_wait_us, operation, script_samples, valid_wait, Action, Operation};
+
+    #[test]
+    fn decodes_operations() {
+        let script = [1, 0xe0, 0x01, 0, 3, 0, 0, 0, 9, 0, 0, 0];
+        assert_eq!(
+            operation(&script, 0),
+            Some(Operation {
+                action: Action::High,
+                wait_us: 480
+            })
+        );
+        assert_eq!(
+            operation(&script, 1),
+            Some(Operation {
+                action: Action::Sample,
+                wait_us: 0
+            })
+        );
+        assert_eq!(operation(&script, 2), None);
+        assert_eq!(operation(&script, 3), None);
+    }
+
+    #[test]
+    fn rejects_waits_finer_than_the_timer() {
+        let min = min_wait_us(2, 16384);
+        assert_eq!(min, 123);
+        assert!(valid_wait(0, min));
+        assert!(!valid_wait(15, min));
+        assert!(valid_wait(480, min));
+    }
+
+    #[test]
+    fn counts_samples() {
+        let script = [0, 0xe0, 0x01, 0, 2, 0, 0, 0, 3, 0xe0, 0x01, 0, 3, 0, 0, 0];
+        assert_eq!(script_samples(&script, 123), Some(2));
+        assert_eq!(script_samples(&script[..3], 123), None);
+        assert_eq!(script_samples(&[], 123), None);
+        assert_eq!(script_samples(&[0, 15, 0, 0], 123), None);
+    }
+}
@@ -18,6 +18,21 @@
 //! * 0: Advertising data
 //! * 1: Passive scanning buffer
 //!
+//! While scanning, each received advertising channel PDU is written to the
+//! passive scanning buffer: the 2-byte header, then the 6-byte address of the
+//! advertiser (AdvA), then its advertising data. The buffer holds one packet
+//! at a time. Once the process has read a packet it releases the buffer with
+//! command 9. Packets received before then are dropped and counted, and the
+//! count is read with command 10.
+//!
+//! A process can also provide up to `MAX_ADV_PAYLOADS` advertising payloads,
+//! using allow number `ADV_PAYLOAD_ALLOW_BASE + index` for the payload at
+//! `index` (allow number 0 is the payload at index 0). The driver rotates
+//! through the provided payloads, moving to the next one after the current
+//! one has been sent for its dwell, a number of advertising events set with
+//! command 6. Allowing an empty buffer for an index removes that payload from
+//! the rotation. With a single payload it is used for every event.
+//!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
 //! * SUCCESS: The buffer has successfully been filled
@@ -33,7 +48,13 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. It is called
+//!      once per packet written to the scanning buffer, with the result, the
+//!      length of the packet, and its RSSI in dBm as a signed integer. The
+//!      RSSI is `RSSI_UNAVAILABLE` (127) when the radio did not measure it.
+//! * 1: callback confirming that advertising stopped after command 7 or 8. The
+//!      first argument is `SUCCESS` if the last advertising event completed, or
+//!      `ECANCEL` if it was aborted.
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -49,6 +70,19 @@
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
 //! * 5: start scanning
+//! * 6: set the dwell of the payload at index `data` to `interval` advertising
+//!      events
+//! * 7: stop advertising once the current advertising event, if any, has been
+//!      sent on all three channels, then power the radio down
+//! * 8: stop advertising immediately, aborting the current advertising event
+//!      mid-packet if needed, and power the radio down
+//! * 9: release the scanning buffer for the next packet, returns `EALREADY` if
+//!      it holds no packet
+//! * 10: number of packets dropped since scanning started because the
+//!      scanning buffer had not been released
+//!
+//! Commands 7 and 8 return `EALREADY` if the process is not advertising, and
+//! signal the stop with the subscribe 1 callback.
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -116,6 +150,14 @@ pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 /// Advertisement Buffer
 pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 
+/// Number of advertising payloads a process can rotate through.
+pub const MAX_ADV_PAYLOADS: usize = 4;
+
+/// Allow number of the advertising payload at index 0. The payload at index
+/// `i` uses allow number `ADV_PAYLOAD_ALLOW_BASE + i`.
+pub const ADV_PAYLOAD_ALLOW_BASE: usize = 0x10;
+const ADV_PAYLOAD_ALLOW_MAX: usize = ADV_PAYLOAD_ALLOW_BASE + MAX_ADV_PAYLOADS - 1;
+
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
@@ -170,7 +212,14 @@ pub struct App {
     alarm_data: AlarmData,
 
     // Advertising meta-data
-    adv_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    adv_data: [Option<kernel::AppSlice<kernel::Shared, u8>>; MAX_ADV_PAYLOADS],
+    /// Number of advertising events each payload is sent for before moving to
+    /// the next one.
+    adv_dwell: [u32; MAX_ADV_PAYLOADS],
+    /// Index of the payload currently being advertised.
+    adv_index: usize,
+    /// Number of advertising events the current payload has been sent for.
+    adv_events: u32,
     address: [u8; PACKET_ADDR_LEN],
     pdu_type: AdvPduType,
     advertisement_interval_ms: u32,
@@ -182,20 +231,35 @@ pub struct App {
     /// well.
     random_nonce: u32,
 
+    /// Stop advertising at the end of the current advertising event.
+    stop_pending: bool,
+    stop_callback: Option<kernel::Callback>,
+
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    /// The scanning buffer holds a packet the process has not released yet.
+    scan_pending: bool,
+    /// Packets dropped since scanning started.
+    scan_dropped: u32,
 }
 
 impl Default for App {
     fn default() -> App {
         App {
             alarm_data: AlarmData::new(),
-            adv_data: None,
+            adv_data: [None, None, None, None],
+            adv_dwell: [1; MAX_ADV_PAYLOADS],
+            adv_index: 0,
+            adv_events: 0,
+            stop_pending: false,
+            stop_callback: None,
             scan_buffer: None,
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: None,
+            scan_pending: false,
+            scan_dropped: 0,
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
@@ -234,12 +298,41 @@ impl App {
         ReturnCode::SUCCESS
     }
 
+    // Index of the payload to advertise: the current one if it is set,
+    // otherwise the next one that is.
+    fn current_adv_index(&self) -> Option<usize> {
+        (0..MAX_ADV_PAYLOADS)
+            .map(|i| (self.adv_index + i) % MAX_ADV_PAYLOADS)
+            .find(|&i| self.adv_data[i].is_some())
+    }
+
+    // Called at the end of each advertising event. Moves on to the next
+    // payload once the current one has been sent for its dwell.
+    fn rotate_adv_data(&mut self) {
+        if let Some(index) = self.current_adv_index() {
+            if index != self.adv_index {
+                // The payload being rotated to was removed, so the event just
+                // sent was the first for this payload.
+                self.adv_index = index;
+                self.adv_events = 0;
+            }
+            self.adv_events += 1;
+            if self.adv_events >= self.adv_dwell[index] {
+                self.adv_index = (index + 1) % MAX_ADV_PAYLOADS;
+                self.adv_events = 0;
+            }
+        }
+    }
+
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
         B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm<'a>,
     {
-        self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
+        let adv_data = self
+            .current_adv_index()
+            .and_then(|index| self.adv_data[index].as_ref());
+        adv_data.map_or(ReturnCode::FAIL, |adv_data| {
             ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
                 let adv_data_len = cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
                 let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
@@ -284,6 +377,28 @@ impl App {
         self.random_nonce
     }
 
+    // Claims the scanning buffer for a received packet. If the process has
+    // not released the previous packet yet the new one is dropped.
+    fn claim_scan_buffer(&mut self) -> bool {
+        if self.scan_pending {
+            self.scan_dropped = self.scan_dropped.saturating_add(1);
+            false
+        } else {
+            self.scan_pending = true;
+            true
+        }
+    }
+
+    // Leave the advertising state and let the process know.
+    fn advertising_stopped(&mut self, result: ReturnCode) {
+        self.stop_pending = false;
+        self.alarm_data.expiration = Expiration::Disabled;
+        self.process_status = Some(BLEState::Initialized);
+        self.stop_callback.map(|mut cb| {
+            cb.schedule(usize::from(result), 0, 0);
+        });
+    }
+
     // Set the next alarm for this app using the period and provided start time.
     fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
         let nonce = self.random_nonce() % 10;
@@ -293,6 +408,12 @@ impl App {
     }
 }
 
+// Callback arguments for a scanned packet. The RSSI is sign extended so
+// that the process reads it back as a negative number.
+fn scanned_packet_args(result: ReturnCode, len: u8, rssi: i8) -> (usize, usize, usize) {
+    (usize::from(result), len as usize, rssi as isize as usize)
+}
+
 pub struct BLE<'a, B, A>
 where
     B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
@@ -438,7 +559,7 @@ where
     B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, rssi: i8, result: ReturnCode) {
         self.receiving_app.map(|appid| {
             let _ = self.app.enter(*appid, |app, _| {
                 // Validate the received data, because ordinary BLE packets can be bigger than 39
@@ -450,23 +571,22 @@ where
                 // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
                 // only be sent on the other 37 RadioChannel channels.
 
-                if len <= PACKET_LENGTH as u8 && result == ReturnCode::SUCCESS {
+                if len <= PACKET_LENGTH as u8
+                    && result == ReturnCode::SUCCESS
+                    && app.scan_buffer.is_some()
+                    && app.claim_scan_buffer()
+                {
                     // write to buffer in userland
-                    let success = app
-                        .scan_buffer
-                        .as_mut()
-                        .map(|userland| {
-                            for (dst, src) in userland.iter_mut().zip(buf[0..len as usize].iter()) {
-                                *dst = *src;
-                            }
-                        })
-                        .is_some();
+                    app.scan_buffer.as_mut().map(|userland| {
+                        for (dst, src) in userland.iter_mut().zip(buf[0..len as usize].iter()) {
+                            *dst = *src;
+                        }
+                    });
 
-                    if success {
-                        app.scan_callback.map(|mut cb| {
-                            cb.schedule(usize::from(result), len as usize, 0);
-                        });
-                    }
+                    app.scan_callback.map(|mut cb| {
+                        let (result, len, rssi) = scanned_packet_args(result, len, rssi);
+                        cb.schedule(result, len, rssi);
+                    });
                 }
 
                 match app.process_status {
@@ -529,8 +649,16 @@ where
 
                     Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
                         self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                        app.rotate_adv_data();
+                        if app.stop_pending {
+                            // The event is complete, so this is the gap the
+                            // process asked to stop in.
+                            self.radio.power_down();
+                            app.advertising_stopped(ReturnCode::SUCCESS);
+                        } else {
+                            app.process_status = Some(BLEState::AdvertisingIdle);
+                            app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                        }
                     }
                     // Invalid state => don't care
                     _ => (),
@@ -585,6 +713,7 @@ where
                 .enter(appid, |app, _| match app.process_status {
                     Some(BLEState::AdvertisingIdle) | Some(BLEState::ScanningIdle) => {
                         app.process_status = Some(BLEState::Initialized);
+                        app.scan_pending = false;
                         ReturnCode::SUCCESS
                     }
                     _ => ReturnCode::EBUSY,
@@ -628,6 +757,8 @@ where
                 .enter(appid, |app, _| {
                     if let Some(BLEState::Initialized) = app.process_status {
                         app.process_status = Some(BLEState::ScanningIdle);
+                        app.scan_pending = false;
+                        app.scan_dropped = 0;
                         app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                         self.reset_active_alarm();
                         ReturnCode::SUCCESS
@@ -637,6 +768,86 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Set the dwell of an advertising payload
+            //
+            // data - Index of the payload
+            // interval - Number of advertising events to send it for
+            6 => self
+                .app
+                .enter(appid, |app, _| {
+                    if data >= MAX_ADV_PAYLOADS || interval == 0 {
+                        ReturnCode::EINVAL
+                    } else {
+                        app.adv_dwell[data] = interval as u32;
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Stop advertising after the current advertising event
+            7 => self
+                .app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::AdvertisingIdle) => {
+                        if !self.busy.get() {
+                            self.radio.power_down();
+                        }
+                        app.advertising_stopped(ReturnCode::SUCCESS);
+                        ReturnCode::SUCCESS
+                    }
+                    Some(BLEState::Advertising(_)) => {
+                        app.stop_pending = true;
+                        ReturnCode::SUCCESS
+                    }
+                    _ => ReturnCode::EALREADY,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Stop advertising immediately
+            8 => self
+                .app
+                .enter(appid, |app, _| match app.process_status {
+                    Some(BLEState::AdvertisingIdle) => {
+                        if !self.busy.get() {
+                            self.radio.power_down();
+                        }
+                        app.advertising_stopped(ReturnCode::SUCCESS);
+                        ReturnCode::SUCCESS
+                    }
+                    Some(BLEState::Advertising(_)) => {
+                        // Take the packet buffer back from the radio, as no
+                        // transmit event will return it.
+                        self.radio.abort().map(|buf| self.kernel_tx.replace(buf));
+                        self.sending_app.clear();
+                        self.busy.set(false);
+                        app.advertising_stopped(ReturnCode::ECANCEL);
+                        ReturnCode::SUCCESS
+                    }
+                    _ => ReturnCode::EALREADY,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Release the scanning buffer
+            9 => self
+                .app
+                .enter(appid, |app, _| {
+                    if app.scan_pending {
+                        app.scan_pending = false;
+                        ReturnCode::SUCCESS
+                    } else {
+                        ReturnCode::EALREADY
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Number of dropped scanned packets
+            10 => self
+                .app
+                .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                    value: app.scan_dropped as usize,
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -648,16 +859,26 @@ where
         slice: Option<kernel::AppSlice<kernel::Shared, u8>>,
     ) -> ReturnCode {
         match allow_num {
-            // Advertisement buffer
-            0 => self
+            // Advertisement buffers
+            0 | ADV_PAYLOAD_ALLOW_BASE..=ADV_PAYLOAD_ALLOW_MAX => self
                 .app
                 .enter(appid, |app, _| {
-                    app.adv_data = slice;
-                    if let ReturnCode::SUCCESS = app.generate_random_address(appid) {
-                        app.process_status = Some(BLEState::Initialized);
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::FAIL
+                    let index = allow_num.saturating_sub(ADV_PAYLOAD_ALLOW_BASE);
+                    app.adv_data[index] = slice;
+                    match app.process_status {
+                        // Payloads can be changed while advertising, taking
+                        // effect from the next advertising event.
+                        Some(BLEState::AdvertisingIdle) | Some(BLEState::Advertising(_)) => {
+                            ReturnCode::SUCCESS
+                        }
+                        _ => {
+                            if let ReturnCode::SUCCESS = app.generate_random_address(appid) {
+                                app.process_status = Some(BLEState::Initialized);
+                                ReturnCode::SUCCESS
+                            } else {
+                                ReturnCode::FAIL
+                            }
+                        }
                     }
                 })
                 .unwrap_or_else(|err| err.into()),
@@ -698,7 +919,147 @@ where
                     _ => ReturnCode::EINVAL,
                 })
                 .unwrap_or_else(|err| err.into()),
+
+            // Callback for stopping advertising
+            1 => self
+                .app
+                .enter(app_id, |app, _| {
+                    app.stop_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{scanned_packet_args, App, BLE, DRIVER_NUM, PACKET_LENGTH};
+    use core::cell::Cell;
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::hil::ble_advertising::{self, RadioChannel, RxClient, RSSI_UNAVAILABLE};
+    use kernel::hil::time::Alarm;
+    use kernel::procs::ProcessType;
+    use kernel::testing::{MockAlarm, MockProcess};
+    use kernel::{create_capability, Kernel, ReturnCode};
+    use std::boxed::Box;
+
+    /// A radio that only remembers the channel it was last asked to receive
+    /// on.
+    struct MockRadio {
+        receiving: Cell<Option<RadioChannel>>,
+    }
+
+    impl<'a> ble_advertising::BleAdvertisementDriver<'a> for MockRadio {
+        fn transmit_advertisement(&self, _buf: &'static mut [u8], _len: usize, _: RadioChannel) {}
+        fn receive_advertisement(&self, channel: RadioChannel) {
+            self.receiving.set(Some(channel));
+        }
+        fn set_receive_client(&self, _client: &'a dyn ble_advertising::RxClient) {}
+        fn set_transmit_client(&self, _client: &'a dyn ble_advertising::TxClient) {}
+        fn power_down(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn abort(&self) -> Option<&'static mut [u8]> {
+            None
+        }
+    }
+
+    impl ble_advertising::BleConfig for MockRadio {
+        fn set_tx_power(&self, _power: u8) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    #[test]
+    fn received_packets_reach_the_process() {
+        let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+        let kernel = Box::leak(Box::new(Kernel::new(processes)));
+        process.attach(kernel, 0);
+        let radio: &'static MockRadio = Box::leak(Box::new(MockRadio {
+            receiving: Cell::new(None),
+        }));
+        let alarm: &'static MockAlarm = Box::leak(Box::new(MockAlarm::new()));
+        let ble: &'static BLE<MockRadio, MockAlarm> = Box::leak(Box::new(BLE::new(
+            radio,
+            kernel.create_grant(&memory_allocation_cap),
+            Box::leak(Box::new([0; PACKET_LENGTH])),
+            alarm,
+        )));
+        alarm.set_alarm_client(ble);
+
+        // Scan into a buffer in the process's memory.
+        let scan_buffer = process.app_memory(0, PACKET_LENGTH);
+        assert_eq!(
+            process.allow_driver(ble, 1, Some(scan_buffer)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(process.subscribe(ble, DRIVER_NUM, 0), ReturnCode::SUCCESS);
+        assert_eq!(process.command(ble, 5, 0, 0), ReturnCode::SUCCESS);
+        assert!(alarm.fire());
+        assert_eq!(
+            radio.receiving.get(),
+            Some(RadioChannel::AdvertisingChannel37)
+        );
+
+        // ADV_NONCONN_IND with 3 bytes of data, received at -67 dBm.
+        let pdu = Box::leak(Box::new([0; PACKET_LENGTH]));
+        let adv = [0x42, 9, 1, 2, 3, 4, 5, 6, 2, 1, 6];
+        pdu[..11].copy_from_slice(&adv);
+        ble.receive_event(pdu, 11, -67, ReturnCode::SUCCESS);
+        assert_eq!(process.take_callback(), Some((0, 0, 11, -67isize as usize)));
+        assert_eq!(process.app_memory(0, 11), &adv);
+        assert_eq!(
+            radio.receiving.get(),
+            Some(RadioChannel::AdvertisingChannel38)
+        );
+
+        // Dropped until the process releases the buffer.
+        let pdu = Box::leak(Box::new([0; PACKET_LENGTH]));
+        ble.receive_event(pdu, 11, -40, ReturnCode::SUCCESS);
+        assert_eq!(process.take_callback(), None);
+        assert_eq!(
+            process.command(ble, 10, 0, 0),
+            ReturnCode::SuccessWithValue { value: 1 }
+        );
+        assert_eq!(process.command(ble, 9, 0, 0), ReturnCode::SUCCESS);
+
+        let pdu = Box::leak(Box::new([0; PACKET_LENGTH]));
+        ble.receive_event(pdu, 8, RSSI_UNAVAILABLE, ReturnCode::SUCCESS);
+        assert_eq!(
+            process.take_callback(),
+            Some((0, 0, 8, RSSI_UNAVAILABLE as usize))
+        );
+        // After the last advertising channel the next scan is scheduled.
+        assert!(alarm.armed().is_some());
+    }
+
+    #[test]
+    fn packets_dropped_until_buffer_released() {
+        let mut app = App::default();
+        assert!(app.claim_scan_buffer());
+        assert!(!app.claim_scan_buffer());
+        assert!(!app.claim_scan_buffer());
+        assert_eq!(app.scan_dropped, 2);
+
+        // Released with command 9.
+        app.scan_pending = false;
+        assert!(app.claim_scan_buffer());
+        assert_eq!(app.scan_dropped, 2);
+    }
+
+    #[test]
+    fn rssi_is_passed_signed() {
+        for &rssi in &[-67i8, -127, 0, 20, RSSI_UNAVAILABLE] {
+            let (result, len, arg) = scanned_packet_args(ReturnCode::SUCCESS, 17, rssi);
+            assert_eq!(result, 0);
+            assert_eq!(len, 17);
+            // Processes receive callback arguments as `int`.
+            assert_eq!(arg as i32, rssi as i32);
+        }
+    }
+}
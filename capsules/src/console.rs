@@ -15,6 +15,7 @@
 //!                  115200,
 //!                  &mut console::WRITE_BUF,
 //!                  &mut console::READ_BUF,
+//!                  board_kernel.create_grant(&grant_cap),
 //!                  board_kernel.create_grant(&grant_cap)));
 //! hil::uart::UART::set_client(&usart::USART0, console);
 //! ```
@@ -36,10 +37,67 @@
 //! When the buffer has been written successfully, the buffer is released from
 //! the driver. Successive writes must call `allow` each time a buffer is to be
 //! written.
+//!
+//! Allowing a null or zero-length buffer revokes the buffer previously shared
+//! with the same allow number. Revoking the write buffer cancels the rest of
+//! an ongoing write, and revoking the read buffer aborts an ongoing receive.
+//!
+//! Command `8` returns how many bytes of a write would be copied into the
+//! console's transmit buffer right away. Longer writes are sent in several
+//! chunks, so an app can use this to size its writes. It returns 0 while the
+//! buffer is in use, which includes the time a chunk waits for kernel
+//! `debug!` output sharing the UART to be sent first. Writes of other apps
+//! waiting for the buffer are started as soon as it is free, so any space
+//! reported is not claimed by them.
+//!
+//! Changing the baud rate
+//! ----------------------
+//!
+//! If the board passes the UART mux to `set_baud_rate_control()`, apps can
+//! temporarily switch the baud rate, for example to speed up a bulk transfer,
+//! and switch back afterwards. The rate applies to every user of the mux, so
+//! the host must follow the switch.
+//!
+//! ```c
+//! command(CONSOLE_DRIVER_NUM, 4, 921600);
+//! // ... bulk transfer ...
+//! command(CONSOLE_DRIVER_NUM, 5, 0);
+//! ```
+//!
+//! Line editing
+//! ------------
+//!
+//! An app implementing an interactive shell can let the console edit input
+//! lines for it. Once an app switches to line mode with command `6`, passing
+//! how many lines of history to keep (at most `MAX_HISTORY_DEPTH`), each
+//! receive with command `2` edits one line and returns it once the user
+//! presses enter. The console echoes the line as it is edited and supports
+//! backspace, delete, the left and right arrows, home and end (also `Ctrl-A`
+//! and `Ctrl-E`), and recalling earlier lines with the up and down arrows.
+//! Other escape sequences and control characters are ignored. The returned
+//! line does not include the line ending, and is at most `MAX_LINE_LEN`
+//! bytes long. Command `7` switches back to receiving raw bytes.
+//!
+//! The echo assumes the terminal cursor stays where the line is being
+//! edited, so the app should print its prompt before starting the receive
+//! and not write to the console until the line is returned.
+//!
+//! ```c
+//! command(CONSOLE_DRIVER_NUM, 6, 4);
+//! while (1) {
+//!     printf("> ");
+//!     // The callback reports the length of the line.
+//!     command(CONSOLE_DRIVER_NUM, 2, sizeof(line));
+//!     yield_for(&line_done);
+//! }
+//! ```
 
+use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::uart;
+
+use crate::virtual_uart::MuxUart;
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 /// Syscall driver number.
@@ -57,11 +115,406 @@ pub struct App {
     read_callback: Option<Callback>,
     read_buffer: Option<AppSlice<Shared, u8>>,
     read_len: usize,
+    line_mode: bool,
+}
+
+/// Longest line, in bytes, that an app in line mode can receive.
+pub const MAX_LINE_LEN: usize = 64;
+
+/// Most lines an app in line mode can recall from its history.
+pub const MAX_HISTORY_DEPTH: usize = 4;
+
+const ESC: u8 = 0x1b;
+
+/// Progress through a terminal escape sequence.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// Received `ESC`.
+    Esc,
+    /// Received `ESC [` and the first numeric parameter so far. `more` is set
+    /// once further parameters follow, which are ignored.
+    Csi {
+        param: u8,
+        more: bool,
+    },
+    /// Received `ESC O`.
+    Ss3,
+}
+
+/// The line an app in line mode is editing, its history, and what the
+/// terminal currently shows of the line.
+pub struct LineEditor {
+    line: [u8; MAX_LINE_LEN],
+    len: usize,
+    cursor: usize,
+    max_len: usize,
+    escape: Escape,
+    /// The last byte was a carriage return, so a line feed right after it
+    /// does not end another line.
+    after_cr: bool,
+
+    history: [[u8; MAX_LINE_LEN]; MAX_HISTORY_DEPTH],
+    history_lens: [usize; MAX_HISTORY_DEPTH],
+    history_depth: usize,
+    history_count: usize,
+    /// Entry the next line is stored in.
+    history_next: usize,
+    /// History entry shown in the line, counting back from the newest.
+    recalled: Option<usize>,
+
+    /// Cursor position on the terminal.
+    shown_cursor: usize,
+    /// The terminal shows the line correctly only up to this position.
+    dirty_from: Option<usize>,
+    /// Enter was pressed, the line is complete once the newline is echoed.
+    enter_pending: bool,
+    newline_echoed: bool,
+}
+
+impl Default for LineEditor {
+    fn default() -> LineEditor {
+        LineEditor {
+            line: [0; MAX_LINE_LEN],
+            len: 0,
+            cursor: 0,
+            max_len: MAX_LINE_LEN,
+            escape: Escape::None,
+            after_cr: false,
+            history: [[0; MAX_LINE_LEN]; MAX_HISTORY_DEPTH],
+            history_lens: [0; MAX_HISTORY_DEPTH],
+            history_depth: 0,
+            history_count: 0,
+            history_next: 0,
+            recalled: None,
+            shown_cursor: 0,
+            dirty_from: None,
+            enter_pending: false,
+            newline_echoed: false,
+        }
+    }
+}
+
+impl LineEditor {
+    /// Start over with an empty history of `history_depth` lines.
+    fn enable(&mut self, history_depth: usize) {
+        *self = LineEditor::default();
+        self.history_depth = cmp::min(history_depth, MAX_HISTORY_DEPTH);
+    }
+
+    /// Start editing a new line of at most `max_len` bytes.
+    fn start_line(&mut self, max_len: usize) {
+        self.clear_line();
+        self.max_len = cmp::min(max_len, MAX_LINE_LEN);
+    }
+
+    fn clear_line(&mut self) {
+        self.len = 0;
+        self.cursor = 0;
+        self.escape = Escape::None;
+        self.recalled = None;
+        self.shown_cursor = 0;
+        self.dirty_from = None;
+        self.enter_pending = false;
+        self.newline_echoed = false;
+    }
+
+    /// Copy the line into `dest` and clear it, returning its length. A line
+    /// that was completed with enter is added to the history.
+    fn take_line(&mut self, dest: &mut [u8], completed: bool) -> usize {
+        let len = cmp::min(self.len, dest.len());
+        dest[..len].copy_from_slice(&self.line[..len]);
+        if completed {
+            self.add_history();
+        }
+        self.clear_line();
+        len
+    }
+
+    /// The line is complete and its newline has been echoed.
+    fn line_ready(&self) -> bool {
+        self.enter_pending && self.newline_echoed
+    }
+
+    /// Process one received byte. Returns `true` once enter is pressed.
+    fn input(&mut self, byte: u8) -> bool {
+        if self.enter_pending {
+            return true;
+        }
+        let after_cr = self.after_cr;
+        self.after_cr = false;
+
+        match self.escape {
+            Escape::None => {}
+            Escape::Esc => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi {
+                        param: 0,
+                        more: false,
+                    },
+                    b'O' => Escape::Ss3,
+                    ESC => Escape::Esc,
+                    _ => Escape::None,
+                };
+                return false;
+            }
+            Escape::Ss3 => {
+                self.escape = Escape::None;
+                self.key(byte);
+                return false;
+            }
+            Escape::Csi { param, more } => {
+                match byte {
+                    b'0'..=b'9' if !more => {
+                        self.escape = Escape::Csi {
+                            param: param.saturating_mul(10).saturating_add(byte - b'0'),
+                            more,
+                        };
+                        return false;
+                    }
+                    // Further parameters and intermediate bytes.
+                    0x20..=0x3f => {
+                        self.escape = Escape::Csi { param, more: true };
+                        return false;
+                    }
+                    // Final byte.
+                    0x40..=0x7e => {
+                        self.escape = Escape::None;
+                        if byte == b'~' {
+                            match param {
+                                1 | 7 => self.key(b'H'),
+                                3 => self.delete(),
+                                4 | 8 => self.key(b'F'),
+                                _ => {}
+                            }
+                        } else {
+                            self.key(byte);
+                        }
+                        return false;
+                    }
+                    // Not a valid sequence, drop it and handle the byte as
+                    // usual.
+                    _ => self.escape = Escape::None,
+                }
+            }
+        }
+
+        match byte {
+            ESC => self.escape = Escape::Esc,
+            b'\r' => {
+                self.after_cr = true;
+                return self.enter();
+            }
+            b'\n' if !after_cr => return self.enter(),
+            0x08 | 0x7f => self.backspace(),
+            0x01 => self.key(b'H'),
+            0x05 => self.key(b'F'),
+            0x20..=0x7e => self.insert(byte),
+            _ => {}
+        }
+        false
+    }
+
+    /// Handle the final byte of an arrow, home or end key sequence.
+    fn key(&mut self, key: u8) {
+        match key {
+            b'A' => self.recall_older(),
+            b'B' => self.recall_newer(),
+            b'C' => self.cursor = cmp::min(self.cursor + 1, self.len),
+            b'D' => self.cursor = self.cursor.saturating_sub(1),
+            b'H' => self.cursor = 0,
+            b'F' => self.cursor = self.len,
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self) -> bool {
+        self.enter_pending = true;
+        // Echo the newline after the end of the line.
+        self.cursor = self.len;
+        true
+    }
+
+    fn mark_dirty(&mut self, from: usize) {
+        self.dirty_from = Some(self.dirty_from.map_or(from, |dirty| cmp::min(dirty, from)));
+    }
+
+    fn insert(&mut self, byte: u8) {
+        if self.len >= self.max_len {
+            return;
+        }
+        self.line
+            .copy_within(self.cursor..self.len, self.cursor + 1);
+        self.line[self.cursor] = byte;
+        self.len += 1;
+        self.mark_dirty(self.cursor);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.line
+            .copy_within(self.cursor..self.len, self.cursor - 1);
+        self.len -= 1;
+        self.cursor -= 1;
+        self.mark_dirty(self.cursor);
+    }
+
+    fn delete(&mut self) {
+        if self.cursor == self.len {
+            return;
+        }
+        self.line
+            .copy_within(self.cursor + 1..self.len, self.cursor);
+        self.len -= 1;
+        self.mark_dirty(self.cursor);
+    }
+
+    fn add_history(&mut self) {
+        if self.history_depth == 0 || self.len == 0 {
+            return;
+        }
+        // Don't store the same line twice in a row.
+        if self.history_count > 0 {
+            let newest = self.history_index(0);
+            if self.history[newest][..self.history_lens[newest]] == self.line[..self.len] {
+                return;
+            }
+        }
+        let next = self.history_next;
+        self.history[next][..self.len].copy_from_slice(&self.line[..self.len]);
+        self.history_lens[next] = self.len;
+        self.history_next = (next + 1) % self.history_depth;
+        self.history_count = cmp::min(self.history_count + 1, self.history_depth);
+    }
+
+    /// Index into `history` of the entry `age` lines back from the newest.
+    fn history_index(&self, age: usize) -> usize {
+        (self.history_next + self.history_depth - 1 - age) % self.history_depth
+    }
+
+    fn recall_older(&mut self) {
+        let age = self.recalled.map_or(0, |age| age + 1);
+        if age < self.history_count {
+            self.recall(Some(age));
+        }
+    }
+
+    fn recall_newer(&mut self) {
+        match self.recalled {
+            Some(0) => self.recall(None),
+            Some(age) => self.recall(Some(age - 1)),
+            None => {}
+        }
+    }
+
+    /// Replace the line with a history entry, or with an empty line.
+    fn recall(&mut self, age: Option<usize>) {
+        self.len = age.map_or(0, |age| {
+            let index = self.history_index(age);
+            let len = cmp::min(self.history_lens[index], self.max_len);
+            self.line[..len].copy_from_slice(&self.history[index][..len]);
+            len
+        });
+        self.cursor = self.len;
+        self.recalled = age;
+        self.mark_dirty(0);
+    }
+
+    /// Write the bytes that bring the terminal up to date with the line into
+    /// `buf`, returning how many were written. If they don't all fit, the
+    /// next call continues where this one stopped.
+    fn echo(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        if let Some(from) = self.dirty_from {
+            match write_cursor_move(&mut buf[written..], self.shown_cursor, from) {
+                Some(n) => written += n,
+                None => return written,
+            }
+            self.shown_cursor = from;
+
+            let count = cmp::min(self.len - from, buf.len() - written);
+            buf[written..written + count].copy_from_slice(&self.line[from..from + count]);
+            written += count;
+            self.shown_cursor += count;
+            if self.shown_cursor < self.len {
+                self.dirty_from = Some(self.shown_cursor);
+                return written;
+            }
+
+            // Erase whatever is left of a longer line shown before.
+            let erase = [ESC, b'[', b'K'];
+            if buf.len() - written < erase.len() {
+                self.dirty_from = Some(self.shown_cursor);
+                return written;
+            }
+            buf[written..written + erase.len()].copy_from_slice(&erase);
+            written += erase.len();
+            self.dirty_from = None;
+        }
+
+        match write_cursor_move(&mut buf[written..], self.shown_cursor, self.cursor) {
+            Some(n) => written += n,
+            None => return written,
+        }
+        self.shown_cursor = self.cursor;
+
+        if self.enter_pending && !self.newline_echoed && buf.len() - written >= 2 {
+            buf[written..written + 2].copy_from_slice(b"\r\n");
+            written += 2;
+            self.newline_echoed = true;
+        }
+        written
+    }
+}
+
+/// Write the escape sequence that moves the terminal cursor from column
+/// `from` to column `to` into `buf`. Returns the number of bytes written, or
+/// `None` if the sequence does not fit.
+fn write_cursor_move(buf: &mut [u8], from: usize, to: usize) -> Option<usize> {
+    let (distance, direction) = if to > from {
+        (to - from, b'C')
+    } else if to < from {
+        (from - to, b'D')
+    } else {
+        return Some(0);
+    };
+
+    let mut digits = [0; 3];
+    let mut count = 0;
+    let mut rest = cmp::min(distance, 999);
+    while rest > 0 {
+        digits[count] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        count += 1;
+    }
+
+    let len = count + 3;
+    if buf.len() < len {
+        return None;
+    }
+    buf[0] = ESC;
+    buf[1] = b'[';
+    for (dest, digit) in buf[2..2 + count]
+        .iter_mut()
+        .zip(digits[..count].iter().rev())
+    {
+        *dest = *digit;
+    }
+    buf[len - 1] = direction;
+    Some(len)
 }
 
 pub static mut WRITE_BUF: [u8; 64] = [0; 64];
 pub static mut READ_BUF: [u8; 64] = [0; 64];
 
+/// Largest buffer an app may allow for either reading or writing. Writes are
+/// sent in `WRITE_BUF` sized chunks, so this bounds how long a single write
+/// can hold the UART from other apps.
+pub const MAX_ALLOW_SIZE: usize = 1024;
+
 pub struct Console<'a> {
     uart: &'a dyn uart::UartData<'a>,
     apps: Grant<App>,
@@ -69,6 +522,11 @@ pub struct Console<'a> {
     tx_buffer: TakeCell<'static, [u8]>,
     rx_in_progress: OptionalCell<AppId>,
     rx_buffer: TakeCell<'static, [u8]>,
+    uart_mux: OptionalCell<&'a MuxUart<'a>>,
+    line_editors: Grant<LineEditor>,
+    /// App in line mode whose line is being edited or echoed.
+    echo_app: OptionalCell<AppId>,
+    echo_in_progress: Cell<bool>,
 }
 
 impl<'a> Console<'a> {
@@ -77,6 +535,7 @@ impl<'a> Console<'a> {
         tx_buffer: &'static mut [u8],
         rx_buffer: &'static mut [u8],
         grant: Grant<App>,
+        line_editors: Grant<LineEditor>,
     ) -> Console<'a> {
         Console {
             uart: uart,
@@ -85,9 +544,18 @@ impl<'a> Console<'a> {
             tx_buffer: TakeCell::new(tx_buffer),
             rx_in_progress: OptionalCell::empty(),
             rx_buffer: TakeCell::new(rx_buffer),
+            uart_mux: OptionalCell::empty(),
+            line_editors: line_editors,
+            echo_app: OptionalCell::empty(),
+            echo_in_progress: Cell::new(false),
         }
     }
 
+    /// Let apps change the baud rate of the UART mux the console is on.
+    pub fn set_baud_rate_control(&self, uart_mux: &'a MuxUart<'a>) {
+        self.uart_mux.set(uart_mux);
+    }
+
     /// Internal helper function for setting up a new send transaction
     fn send_new(&self, app_id: AppId, app: &mut App, len: usize) -> ReturnCode {
         match app.write_buffer.take() {
@@ -119,7 +587,7 @@ impl<'a> Console<'a> {
     /// Internal helper function for sending data for an existing transaction.
     /// Cannot fail. If can't send now, it will schedule for sending later.
     fn send(&self, app_id: AppId, app: &mut App, slice: AppSlice<Shared, u8>) {
-        if self.tx_in_progress.is_none() {
+        if self.tx_in_progress.is_none() && !self.echo_in_progress.get() {
             self.tx_in_progress.set(app_id);
             self.tx_buffer.take().map(|buffer| {
                 let mut transaction_len = app.write_remaining;
@@ -151,8 +619,32 @@ impl<'a> Console<'a> {
         }
     }
 
+    /// Internal helper function for the bytes of a new write that would be
+    /// copied into the transmit buffer right away. The buffer stays in use
+    /// while it is queued in the UART mux behind other output.
+    fn tx_space(&self) -> usize {
+        self.tx_buffer.map_or(0, |buffer| buffer.len())
+    }
+
+    /// Internal helper function for cancelling a write whose buffer is being
+    /// revoked. A chunk already copied to the UART is still sent, and the
+    /// write callback then reports how many bytes were written. A write still
+    /// waiting for the UART is dropped without a callback.
+    fn revoke_write(&self, app: &mut App) {
+        if app.pending_write {
+            app.pending_write = false;
+            app.write_len = 0;
+        } else {
+            app.write_len -= app.write_remaining;
+        }
+        app.write_remaining = 0;
+    }
+
     /// Internal helper function for starting a receive operation
     fn receive_new(&self, app_id: AppId, app: &mut App, len: usize) -> ReturnCode {
+        if app.line_mode {
+            return self.receive_line(app_id, app, len);
+        }
         if self.rx_buffer.is_none() {
             // For now, we tolerate only one concurrent receive operation on this console.
             // Competing apps will have to retry until success.
@@ -182,6 +674,158 @@ impl<'a> Console<'a> {
             }
         }
     }
+
+    /// Internal helper function for starting to edit a line in line mode
+    fn receive_line(&self, app_id: AppId, app: &mut App, len: usize) -> ReturnCode {
+        if self.rx_buffer.is_none() || self.echo_app.is_some() {
+            // The console edits only one line at a time.
+            return ReturnCode::EBUSY;
+        }
+
+        let max_len = match app.read_buffer {
+            Some(ref slice) => cmp::min(len, slice.len()),
+            None => return ReturnCode::EINVAL,
+        };
+        if max_len == 0 {
+            return ReturnCode::EINVAL;
+        }
+
+        self.line_editors
+            .enter(app_id, |editor, _| {
+                editor.start_line(max_len);
+                self.echo_app.set(app_id);
+                self.receive_key(app_id);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Internal helper function for receiving the next key of a line
+    fn receive_key(&self, app_id: AppId) {
+        self.rx_buffer.take().map(|buffer| {
+            self.rx_in_progress.set(app_id);
+            let (_err, _opt) = self.uart.receive_buffer(buffer, 1);
+        });
+    }
+
+    /// Internal helper function for handling bytes received in line mode.
+    /// Returns true if the line is not complete yet and more keys should be
+    /// received.
+    fn receive_line_input(
+        &self,
+        app_id: AppId,
+        input: &[u8],
+        rcode: ReturnCode,
+        error: uart::Error,
+    ) -> bool {
+        match error {
+            uart::Error::None => {
+                let entered = self.line_editors.enter(app_id, |editor, _| {
+                    input.iter().any(|byte| editor.input(*byte))
+                });
+                match entered {
+                    Ok(entered) => {
+                        // Once the line is entered, it is returned after the
+                        // newline is echoed.
+                        self.echo();
+                        !entered
+                    }
+                    Err(_) => {
+                        self.echo_app.clear();
+                        false
+                    }
+                }
+            }
+            uart::Error::Aborted => {
+                // Return what has been edited so far.
+                self.finish_line(app_id, rcode, false);
+                false
+            }
+            _ => {
+                // Some UART error occurred
+                self.finish_line(app_id, ReturnCode::FAIL, false);
+                false
+            }
+        }
+    }
+
+    /// Internal helper function for returning the edited line to the app.
+    /// Lines that were `completed` with enter are added to the history.
+    fn finish_line(&self, app_id: AppId, result: ReturnCode, completed: bool) {
+        self.echo_app.clear();
+        let mut line = [0; MAX_LINE_LEN];
+        let len = self
+            .line_editors
+            .enter(app_id, |editor, _| editor.take_line(&mut line, completed))
+            .unwrap_or(0);
+
+        self.apps
+            .enter(app_id, |app, _| {
+                app.read_callback.map(|mut cb| {
+                    if result == ReturnCode::FAIL {
+                        cb.schedule(From::from(result), 0, 0);
+                    } else if let Some(mut app_buffer) = app.read_buffer.take() {
+                        let copied = cmp::min(len, app_buffer.len());
+                        for (a, b) in app_buffer.iter_mut().zip(line[..copied].iter()) {
+                            *a = *b;
+                        }
+                        // The app may have allowed a smaller buffer while the
+                        // line was edited.
+                        let ret = if copied < len {
+                            ReturnCode::ESIZE
+                        } else {
+                            result
+                        };
+                        cb.schedule(From::from(ret), copied, 0);
+                    } else {
+                        // Oops, no app buffer
+                        cb.schedule(From::from(ReturnCode::EINVAL), 0, 0);
+                    }
+                });
+            })
+            .unwrap_or_default();
+    }
+
+    /// Internal helper function for echoing the line being edited, if the
+    /// UART is free.
+    fn echo(&self) {
+        if self.tx_in_progress.is_some() || self.echo_in_progress.get() {
+            return;
+        }
+        self.echo_app.map(|app_id| {
+            self.tx_buffer.take().map(|buffer| {
+                let len = self
+                    .line_editors
+                    .enter(*app_id, |editor, _| editor.echo(buffer))
+                    .unwrap_or_else(|_| {
+                        // The app is gone, stop echoing its line.
+                        self.echo_app.clear();
+                        0
+                    });
+                if len > 0 {
+                    self.echo_in_progress.set(true);
+                    let (_err, _opt) = self.uart.transmit_buffer(buffer, len);
+                } else {
+                    self.tx_buffer.replace(buffer);
+                }
+            });
+        });
+    }
+
+    /// Internal helper function for handling a finished echo. Returns the
+    /// line once its newline has been echoed.
+    fn echo_done(&self) {
+        self.echo_app.map(|app_id| *app_id).map(|app_id| {
+            match self
+                .line_editors
+                .enter(app_id, |editor, _| editor.line_ready())
+            {
+                Ok(true) => self.finish_line(app_id, ReturnCode::SUCCESS, true),
+                Ok(false) => {}
+                Err(_) => self.echo_app.clear(),
+            }
+        });
+    }
 }
 
 impl Driver for Console<'_> {
@@ -201,6 +845,9 @@ impl Driver for Console<'_> {
             1 => self
                 .apps
                 .enter(appid, |app, _| {
+                    if slice.is_none() {
+                        self.revoke_write(app);
+                    }
                     app.write_buffer = slice;
                     ReturnCode::SUCCESS
                 })
@@ -208,6 +855,12 @@ impl Driver for Console<'_> {
             2 => self
                 .apps
                 .enter(appid, |app, _| {
+                    if slice.is_none() && self.rx_in_progress.contains(&appid) {
+                        // Stop receiving into the revoked buffer. The receive
+                        // callback reports `EINVAL` as there is no buffer left
+                        // to copy into.
+                        self.uart.receive_abort();
+                    }
                     app.read_buffer = slice;
                     ReturnCode::SUCCESS
                 })
@@ -216,6 +869,13 @@ impl Driver for Console<'_> {
         }
     }
 
+    fn allow_max_size(&self, allow_num: usize) -> Option<usize> {
+        match allow_num {
+            1 | 2 => Some(MAX_ALLOW_SIZE),
+            _ => None,
+        }
+    }
+
     /// Setup callbacks.
     ///
     /// ### `subscribe_num`
@@ -255,6 +915,11 @@ impl Driver for Console<'_> {
     ///        passed in `arg1`
     /// - `3`: Cancel any in progress receives and return (via callback)
     ///        what has been received so far.
+    /// - `4`: Switch the UART to the baud rate passed in `arg1`.
+    /// - `5`: Restore the UART to its default baud rate.
+    /// - `6`: Switch to line mode, keeping a history of `arg1` lines.
+    /// - `7`: Switch back from line mode to receiving raw bytes.
+    /// - `8`: Bytes of a write that would be sent without waiting.
     fn command(&self, cmd_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         match cmd_num {
             0 /* check if present */ => ReturnCode::SUCCESS,
@@ -274,6 +939,42 @@ impl Driver for Console<'_> {
                 self.uart.receive_abort();
                 ReturnCode::SUCCESS
             }
+            4 /* set baud rate */ => {
+                self.uart_mux.map_or(ReturnCode::ENOSUPPORT, |mux| {
+                    mux.set_baud_rate(arg1 as u32)
+                })
+            }
+            5 /* restore baud rate */ => {
+                self.uart_mux.map_or(ReturnCode::ENOSUPPORT, |mux| mux.restore_baud_rate())
+            }
+            6 /* enable line mode */ => {
+                let history_depth = arg1;
+                if history_depth > MAX_HISTORY_DEPTH {
+                    ReturnCode::EINVAL
+                } else if self.rx_in_progress.contains(&appid) || self.echo_app.contains(&appid) {
+                    ReturnCode::EBUSY
+                } else {
+                    self.line_editors.enter(appid, |editor, _| {
+                        editor.enable(history_depth);
+                    }).and_then(|()| self.apps.enter(appid, |app, _| {
+                        app.line_mode = true;
+                        ReturnCode::SUCCESS
+                    })).unwrap_or_else(|err| err.into())
+                }
+            }
+            7 /* disable line mode */ => {
+                if self.rx_in_progress.contains(&appid) || self.echo_app.contains(&appid) {
+                    ReturnCode::EBUSY
+                } else {
+                    self.apps.enter(appid, |app, _| {
+                        app.line_mode = false;
+                        ReturnCode::SUCCESS
+                    }).unwrap_or_else(|err| err.into())
+                }
+            }
+            8 /* tx space */ => ReturnCode::SuccessWithValue {
+                value: self.tx_space(),
+            },
             _ => ReturnCode::ENOSUPPORT
         }
     }
@@ -284,6 +985,9 @@ impl uart::TransmitClient for Console<'_> {
         // Either print more from the AppSlice or send a callback to the
         // application.
         self.tx_buffer.replace(buffer);
+        if self.echo_in_progress.replace(false) {
+            self.echo_done();
+        }
         self.tx_in_progress.take().map(|appid| {
             self.apps.enter(appid, |app, _| {
                 match self.send_continue(appid, app) {
@@ -311,9 +1015,12 @@ impl uart::TransmitClient for Console<'_> {
             })
         });
 
+        // Keep the line being edited up to date before printing anything else.
+        self.echo();
+
         // If we are not printing more from the current AppSlice,
         // see if any other applications have pending messages.
-        if self.tx_in_progress.is_none() {
+        if self.tx_in_progress.is_none() && !self.echo_in_progress.get() {
             for cntr in self.apps.iter() {
                 let started_tx = cntr.enter(|app, _| {
                     if app.pending_write {
@@ -352,6 +1059,30 @@ impl uart::ReceiveClient for Console<'_> {
         rcode: ReturnCode,
         error: uart::Error,
     ) {
+        // Apps in line mode receive one key at a time, which is edited into
+        // their line.
+        let line_app = self.rx_in_progress.and_then(|appid| {
+            if self
+                .apps
+                .enter(appid, |app, _| app.line_mode)
+                .unwrap_or(false)
+            {
+                Some(appid)
+            } else {
+                None
+            }
+        });
+        if let Some(appid) = line_app {
+            self.rx_in_progress.clear();
+            let input = &buffer[..cmp::min(rx_len, buffer.len())];
+            let more = self.receive_line_input(appid, input, rcode, error);
+            self.rx_buffer.replace(buffer);
+            if more {
+                self.receive_key(appid);
+            }
+            return;
+        }
+
         self.rx_in_progress
             .take()
             .map(|appid| {
@@ -418,3 +1149,298 @@ impl uart::ReceiveClient for Console<'_> {
         self.rx_buffer.replace(buffer);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{write_cursor_move, Console, LineEditor, DRIVER_NUM};
+    use core::cell::Cell;
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::uart::{self, ReceiveClient, TransmitClient};
+    use kernel::procs::ProcessType;
+    use kernel::testing::MockProcess;
+    use kernel::{create_capability, Kernel, ReturnCode};
+    use std::boxed::Box;
+
+    fn type_keys(editor: &mut LineEditor, keys: &[u8]) -> bool {
+        keys.iter().any(|key| editor.input(*key))
+    }
+
+    fn line(editor: &LineEditor) -> &[u8] {
+        &editor.line[..editor.len]
+    }
+
+    #[test]
+    fn edits_line() {
+        let mut editor = LineEditor::default();
+        editor.enable(0);
+        editor.start_line(16);
+        assert!(!type_keys(&mut editor, b"helo\x1b[D\x1b[Dl"));
+        assert_eq!(line(&editor), b"hello");
+        assert!(!type_keys(&mut editor, b"\x01\x1b[3~J\x7f\x7fj\x05!"));
+        assert_eq!(line(&editor), b"jello!");
+        assert!(type_keys(&mut editor, b"\r"));
+    }
+
+    #[test]
+    fn ignores_unknown_sequences() {
+        let mut editor = LineEditor::default();
+        editor.enable(0);
+        editor.start_line(16);
+        type_keys(&mut editor, b"a\x1b[1;5Pb\x1b[15~c\x1bxd\x07e\x1b[\x7f");
+        assert_eq!(line(&editor), b"abcd");
+    }
+
+    #[test]
+    fn recalls_history() {
+        let mut editor = LineEditor::default();
+        let mut dest = [0; 16];
+        editor.enable(2);
+        for entry in [&b"one"[..], b"two", b"three"].iter() {
+            editor.start_line(16);
+            type_keys(&mut editor, entry);
+            type_keys(&mut editor, b"\r");
+            editor.take_line(&mut dest, true);
+        }
+
+        editor.start_line(16);
+        type_keys(&mut editor, b"\x1b[A");
+        assert_eq!(line(&editor), b"three");
+        type_keys(&mut editor, b"\x1b[A\x1b[A");
+        assert_eq!(line(&editor), b"two");
+        type_keys(&mut editor, b"\x1bOB");
+        assert_eq!(line(&editor), b"three");
+        type_keys(&mut editor, b"\x1b[B");
+        assert_eq!(line(&editor), b"");
+    }
+
+    #[test]
+    fn ignores_line_feed_after_carriage_return() {
+        let mut editor = LineEditor::default();
+        let mut dest = [0; 16];
+        editor.enable(0);
+        editor.start_line(16);
+        assert!(type_keys(&mut editor, b"ls\r"));
+        assert_eq!(editor.take_line(&mut dest, true), 2);
+        editor.start_line(16);
+        assert!(!type_keys(&mut editor, b"\n"));
+        assert!(type_keys(&mut editor, b"\n"));
+    }
+
+    #[test]
+    fn echoes_edits() {
+        let mut editor = LineEditor::default();
+        let mut buf = [0; 64];
+        editor.enable(0);
+        editor.start_line(16);
+
+        type_keys(&mut editor, b"abc");
+        let len = editor.echo(&mut buf);
+        assert_eq!(&buf[..len], b"abc\x1b[K");
+
+        type_keys(&mut editor, b"\x1b[D\x1b[D\x7f");
+        let len = editor.echo(&mut buf);
+        assert_eq!(&buf[..len], b"\x1b[3Dbc\x1b[K\x1b[2D");
+
+        type_keys(&mut editor, b"\r");
+        assert!(!editor.line_ready());
+        let len = editor.echo(&mut buf);
+        assert_eq!(&buf[..len], b"\x1b[2C\r\n");
+        assert!(editor.line_ready());
+    }
+
+    #[test]
+    fn echoes_in_chunks() {
+        let mut editor = LineEditor::default();
+        let mut buf = [0; 4];
+        editor.enable(0);
+        editor.start_line(16);
+
+        type_keys(&mut editor, b"abcdef");
+        assert_eq!(editor.echo(&mut buf), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(editor.echo(&mut buf), 2);
+        assert_eq!(&buf[..2], b"ef");
+        assert_eq!(editor.echo(&mut buf), 3);
+        assert_eq!(&buf[..3], b"\x1b[K");
+        assert_eq!(editor.echo(&mut buf), 0);
+    }
+
+    #[test]
+    fn cursor_moves() {
+        let mut buf = [0; 8];
+        assert_eq!(write_cursor_move(&mut buf, 3, 3), Some(0));
+        assert_eq!(write_cursor_move(&mut buf, 12, 0), Some(5));
+        assert_eq!(&buf[..5], b"\x1b[12D");
+        assert_eq!(write_cursor_move(&mut buf, 0, 1), Some(4));
+        assert_eq!(&buf[..4], b"\x1b[1C");
+        assert_eq!(write_cursor_move(&mut buf[..3], 0, 1), None);
+    }
+
+    /// Stands in for the UART. It holds on to the buffers of the transfers in
+    /// progress until the test completes them.
+    struct MockUart {
+        tx_buffer: TakeCell<'static, [u8]>,
+        tx_len: Cell<usize>,
+        transmits: Cell<usize>,
+        rx_buffer: TakeCell<'static, [u8]>,
+        rx_aborted: Cell<bool>,
+    }
+
+    impl<'a> uart::Transmit<'a> for MockUart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            self.tx_buffer.replace(tx_buffer);
+            self.tx_len.set(tx_len);
+            self.transmits.set(self.transmits.get() + 1);
+            (ReturnCode::SUCCESS, None)
+        }
+        fn transmit_word(&self, _word: u32) -> ReturnCode {
+            ReturnCode::FAIL
+        }
+        fn transmit_abort(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    impl<'a> uart::Receive<'a> for MockUart {
+        fn set_receive_client(&self, _client: &'a dyn uart::ReceiveClient) {}
+        fn receive_buffer(
+            &self,
+            rx_buffer: &'static mut [u8],
+            _rx_len: usize,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            self.rx_buffer.replace(rx_buffer);
+            (ReturnCode::SUCCESS, None)
+        }
+        fn receive_word(&self) -> ReturnCode {
+            ReturnCode::FAIL
+        }
+        fn receive_abort(&self) -> ReturnCode {
+            self.rx_aborted.set(true);
+            ReturnCode::EBUSY
+        }
+    }
+
+    impl<'a> uart::UartData<'a> for MockUart {}
+
+    /// A process with write and read callbacks, and the console it uses.
+    fn setup() -> (
+        &'static MockProcess,
+        &'static MockUart,
+        &'static Console<'static>,
+    ) {
+        let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+        let kernel = Box::leak(Box::new(Kernel::new(processes)));
+        process.attach(kernel, 0);
+        let uart: &'static MockUart = Box::leak(Box::new(MockUart {
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            transmits: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_aborted: Cell::new(false),
+        }));
+        let console: &'static Console = Box::leak(Box::new(Console::new(
+            uart,
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 64])),
+            kernel.create_grant(&memory_allocation_cap),
+            kernel.create_grant(&memory_allocation_cap),
+        )));
+        for subscribe_num in 1..=2 {
+            assert_eq!(
+                process.subscribe(console, DRIVER_NUM, subscribe_num),
+                ReturnCode::SUCCESS
+            );
+        }
+        (process, uart, console)
+    }
+
+    #[test]
+    fn revoking_write_buffer_cancels_rest_of_write() {
+        let (process, uart, console) = setup();
+        let message = process.app_memory(0, 100);
+        assert_eq!(
+            process.allow_driver(console, 1, Some(message)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(process.command(console, 1, 100, 0), ReturnCode::SUCCESS);
+        assert_eq!(uart.tx_len.get(), 64);
+
+        // A null buffer revokes the one being written.
+        assert_eq!(process.allow_driver(console, 1, None), ReturnCode::SUCCESS);
+        console.transmitted_buffer(uart.tx_buffer.take().unwrap(), 64, ReturnCode::SUCCESS);
+
+        // The chunk already sent is reported, the rest is dropped.
+        assert_eq!(process.take_callback(), Some((1, 64, 0, 0)));
+        assert_eq!(uart.transmits.get(), 1);
+        assert!(uart.tx_buffer.is_none());
+    }
+
+    #[test]
+    fn revoking_read_buffer_aborts_receive() {
+        let (process, uart, console) = setup();
+        let line = process.app_memory(0, 10);
+        assert_eq!(
+            process.allow_driver(console, 2, Some(line)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(process.command(console, 2, 10, 0), ReturnCode::SUCCESS);
+
+        // A zero-length buffer revokes the one being received into.
+        let empty = process.app_memory(0, 0);
+        assert_eq!(
+            process.allow_driver(console, 2, Some(empty)),
+            ReturnCode::SUCCESS
+        );
+        assert!(uart.rx_aborted.get());
+        let buffer = uart.rx_buffer.take().unwrap();
+        buffer[..3].copy_from_slice(b"abc");
+        console.received_buffer(buffer, 3, ReturnCode::ECANCEL, uart::Error::Aborted);
+
+        assert_eq!(
+            process.take_callback(),
+            Some((2, usize::from(ReturnCode::EINVAL), 0, 0))
+        );
+        assert_eq!(process.app_memory(0, 10), &[0; 10]);
+
+        // The console can receive again.
+        let line = process.app_memory(0, 10);
+        assert_eq!(
+            process.allow_driver(console, 2, Some(line)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(process.command(console, 2, 10, 0), ReturnCode::SUCCESS);
+        assert!(uart.rx_buffer.is_some());
+    }
+
+    #[test]
+    fn tx_space_is_zero_while_buffer_is_in_use() {
+        let (process, uart, console) = setup();
+        let tx_space = || process.command(console, 8, 0, 0);
+        assert_eq!(tx_space(), ReturnCode::SuccessWithValue { value: 64 });
+
+        let message = process.app_memory(0, 100);
+        assert_eq!(
+            process.allow_driver(console, 1, Some(message)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(process.command(console, 1, 100, 0), ReturnCode::SUCCESS);
+        assert_eq!(tx_space(), ReturnCode::SuccessWithValue { value: 0 });
+
+        // The second chunk claims the buffer as soon as the first is sent.
+        console.transmitted_buffer(uart.tx_buffer.take().unwrap(), 64, ReturnCode::SUCCESS);
+        assert_eq!(uart.tx_len.get(), 36);
+        assert_eq!(tx_space(), ReturnCode::SuccessWithValue { value: 0 });
+
+        console.transmitted_buffer(uart.tx_buffer.take().unwrap(), 36, ReturnCode::SUCCESS);
+        assert_eq!(process.take_callback(), Some((1, 100, 0, 0)));
+        assert_eq!(tx_space(), ReturnCode::SuccessWithValue { value: 64 });
+    }
+}
@@ -0,0 +1,69 @@
+//! Ends the `debug!()` rate limit windows with an alarm.
+//!
+//! The kernel can cap how many bytes of `debug!()` output are printed per
+//! window, so that a capsule stuck in a loop cannot flood the console. This
+//! capsule sets that budget and ends a window every `window_ms`, which prints
+//! how many messages the window dropped. The alarm fires at the end of every
+//! window, so longer windows wake the chip less often.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let debug_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let debug_rate_limit = static_init!(
+//!     capsules::debug_rate_limit::DebugRateLimit<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::debug_rate_limit::DebugRateLimit::new(debug_alarm, 1000)
+//! );
+//! debug_alarm.set_alarm_client(debug_rate_limit);
+//! // At most 2 KiB of debug output per second.
+//! debug_rate_limit.start(2048);
+//! ```
+
+use kernel::debug;
+use kernel::hil::time::{Alarm, AlarmClient};
+
+pub struct DebugRateLimit<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    window_ms: u32,
+}
+
+impl<'a, A: Alarm<'a>> DebugRateLimit<'a, A> {
+    pub fn new(alarm: &'a A, window_ms: u32) -> DebugRateLimit<'a, A> {
+        DebugRateLimit {
+            alarm: alarm,
+            window_ms: window_ms,
+        }
+    }
+
+    /// Limit debug output to `bytes_per_window` bytes per window, starting
+    /// with a new window now.
+    pub fn start(&self, bytes_per_window: usize) {
+        debug::set_rate_limit(Some(bytes_per_window));
+        debug::end_rate_limit_window();
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(self.window_ms));
+    }
+
+    /// Remove the limit.
+    pub fn stop(&self) {
+        self.alarm.disarm();
+        debug::set_rate_limit(None);
+        debug::end_rate_limit_window();
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for DebugRateLimit<'a, A> {
+    fn alarm(&self) {
+        debug::end_rate_limit_window();
+        // Count from when this window was due to end, so that windows do not
+        // drift.
+        self.alarm
+            .set_alarm(self.alarm.get_alarm(), A::ticks_from_ms(self.window_ms));
+    }
+}
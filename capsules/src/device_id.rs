@@ -0,0 +1,117 @@
+//! Gives userspace the unique identifier of the chip.
+//!
+//! Apps that need a per-device identity, for provisioning or licensing, can
+//! read the identifier the factory programmed into the chip. Its length and
+//! format depend on the chip: on the Apollo3 it is 8 bytes, the
+//! little-endian `CHIPID0` register followed by `CHIPID1`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let device_id = static_init!(
+//!     capsules::device_id::DeviceIdDriver,
+//!     capsules::device_id::DeviceIdDriver::new(
+//!         mcu_ctrl,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer to copy the identifier into.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Copy the identifier into the start of the allowed buffer and return
+//!   its length. Returns `EINVAL` if no buffer was allowed, `ESIZE` if the
+//!   buffer is too short, and `ENODEVICE` if the chip was never programmed
+//!   with an identifier.
+//! - `2`: Return the length of the identifier in bytes.
+
+use kernel::hil::device_id::DeviceId;
+use kernel::{AppId, AppSlice, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::DeviceId as usize;
+
+#[derive(Default)]
+pub struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct DeviceIdDriver {
+    device: &'static dyn DeviceId,
+    apps: Grant<App>,
+}
+
+impl DeviceIdDriver {
+    pub fn new(device: &'static dyn DeviceId, grant: Grant<App>) -> DeviceIdDriver {
+        DeviceIdDriver {
+            device: device,
+            apps: grant,
+        }
+    }
+}
+
+impl Driver for DeviceIdDriver {
+    /// Setup the buffer for the identifier.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer to copy the identifier into.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Read the identifier.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Copy the identifier into the allowed buffer.
+    /// - `2`: Length of the identifier.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self
+                .apps
+                .enter(appid, |app, _| match app.buffer {
+                    Some(ref mut buffer) => match self.device.read_id(buffer.as_mut()) {
+                        ReturnCode::SUCCESS => ReturnCode::SuccessWithValue {
+                            value: self.device.id_len(),
+                        },
+                        err => err,
+                    },
+                    None => ReturnCode::EINVAL,
+                })
+                .unwrap_or_else(|err| err.into()),
+            2 => ReturnCode::SuccessWithValue {
+                value: self.device.id_len(),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -42,6 +42,7 @@ pub enum NUM {
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    KeyValue              = 0x50003,
 
     // Sensors
     Temperature           = 0x60000,
@@ -71,5 +72,26 @@ pub enum NUM {
     Screen                = 0x90001,
     Touch                 = 0x90002,
     TextScreen            = 0x90003,
+    Uptime                = 0x90004,
+    SleepVeto             = 0x90005,
+    AlarmStats            = 0x90006,
+    WakeReason            = 0x90007,
+    SyscallBenchmark      = 0x90008,
+    SleepInhibit          = 0x90009,
+    PinMux                = 0x9000A,
+    ProcessStats          = 0x9000B,
+    ProcessWaitTime       = 0x9000C,
+    SystemReset           = 0x9000D,
+    PulseTrain            = 0x9000E,
+    DeviceId              = 0x9000F,
+    TimesliceYield        = 0x90010,
+    Watchdog              = 0x90011,
+    MemoryBarrier         = 0x90012,
+    BitBang               = 0x90013,
+    PowerBudget           = 0x90014,
+    MpuLimits             = 0x90015,
+    GpioPorts             = 0x90016,
+    PreSleep              = 0x90017,
+    Hibernate             = 0x90018,
 }
 }
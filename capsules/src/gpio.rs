@@ -30,6 +30,17 @@
 //! }
 //! ```
 //!
+//! Sampled reads (command `11`) need a timer to space out the samples, and
+//! bank monitoring (command `12`) one to time its coalescing window. Boards
+//! that want them pass a `OneshotTimer` with `set_sample_timer`, see
+//! `oneshot_timer`. The timer serves one of them at a time: a bank change
+//! during a sampled read starts its window once the read completes, and
+//! sampled reads return `EBUSY` while a window is open.
+//!
+//! Edge counting (commands `13` and `14`) needs pins that can count edges
+//! themselves, which boards pass with `set_edge_counters`, in the same order
+//! as the pins.
+//!
 //! Syscall Interface
 //! -----------------
 //!
@@ -37,33 +48,124 @@
 //!
 //! ### Commands
 //!
-//! All GPIO operations are synchronous.
+//! All GPIO operations are synchronous, except for sampled reads, which read a
+//! pin several times and report the majority value through a callback.
 //!
 //! Commands control and query GPIO information, namely how many GPIOs are
 //! present, the GPIO direction and state, and whether they should interrupt.
 //!
 //! ### Subscribes
 //!
-//! The GPIO interface provides only one callback, which is used for pins that
-//! have had interrupts enabled.
+//! The GPIO interface provides one callback for pins that have had interrupts
+//! enabled, one for the result of sampled reads, and one for changes to a
+//! monitored bank of pins.
+//!
+//! ### Bank Monitoring
+//!
+//! To watch a bank of inputs, such as a keypad or DIP switches, an app can
+//! monitor a set of pins and get a single callback for changes that happen
+//! close together. The first change to a monitored pin opens a window, and
+//! when it ends the app is sent the state of all its monitored pins as read
+//! then, with the pins that changed. A pin that changed several times within
+//! the window is only reported with its final state. The monitored pins must
+//! have interrupts enabled with command `7`.
+//!
+//! ### Edge Counting
+//!
+//! To measure pulses, such as from a tachometer or an encoder, an app can
+//! have the edges on a pin counted as they interrupt, rather than getting a
+//! callback for each one, and read the count when it needs it. Counts
+//! saturate at `u32::MAX`. Reading a count can reset it in the same step, so
+//! no edge is missed between two readings. A pin that counts edges does not
+//! trigger the subscribe `0` callback, and configuring or disabling its
+//! interrupt with command `7` or `8` stops counting.
 
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Gpio as usize;
 
+/// Commands and the arguments they accept, checked before dispatch.
+const COMMANDS: CommandTable = CommandTable::new(&[
+    CommandSpec::new(0, CommandArg::Any, CommandArg::Any),
+    CommandSpec::new(1, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(2, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(3, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(4, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(5, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(6, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(7, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(8, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(9, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(10, CommandArg::Index, CommandArg::Below(2)),
+    CommandSpec::new(11, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(12, CommandArg::Any, CommandArg::Any),
+    CommandSpec::new(13, CommandArg::Index, CommandArg::Below(4)),
+    CommandSpec::new(14, CommandArg::Index, CommandArg::Below(2)),
+]);
+
+/// Number of pins that can be monitored as a bank, one per bit of the mask.
+const MAX_BANK_PINS: usize = 32;
+
+use crate::oneshot_timer::{OneshotTimer, OneshotTimerClient};
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
 use kernel::hil::gpio;
 use kernel::hil::gpio::{Configure, Input, InterruptWithValue, Output};
-use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+use kernel::{AppId, Callback, CommandArg, CommandSpec, CommandTable, Driver, Grant, ReturnCode};
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    sample_callback: Option<Callback>,
+    bank_callback: Option<Callback>,
+    /// Pins monitored as a bank, one bit per pin.
+    bank_mask: u32,
+    /// How long changes to the bank are coalesced for.
+    bank_window_us: u32,
+}
+
+/// A sampled read in progress.
+#[derive(Clone, Copy)]
+struct Sampling {
+    app: AppId,
+    pin: usize,
+    /// Configuration of the pin when sampling started, used to detect the pin
+    /// being reconfigured before sampling completes.
+    config: (gpio::Configuration, gpio::FloatingState),
+    spacing_us: u32,
+    remaining: usize,
+    highs: usize,
+    total: usize,
+}
+
+/// Value reported by a sampled read: high only if a strict majority of the
+/// samples were high, so ties read as low.
+fn majority(highs: usize, total: usize) -> bool {
+    highs * 2 > total
+}
+
+/// Whether `mask` only names pins below `num_pins`.
+fn bank_mask_valid(mask: usize, num_pins: usize) -> bool {
+    num_pins >= MAX_BANK_PINS || mask >> num_pins == 0
+}
 
 pub struct GPIO<'a, IP: gpio::InterruptPin<'a>> {
     pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-    apps: Grant<Option<Callback>>,
+    apps: Grant<App>,
+    sample_timer: OptionalCell<&'a dyn OneshotTimer<'a>>,
+    sampling: Cell<Option<Sampling>>,
+    /// Monitored pins that changed since the bank window opened.
+    bank_changed: Cell<u32>,
+    /// Whether the timer is timing a bank window.
+    bank_window: Cell<bool>,
+    /// The pins as edge counters, in the same order as `pins`.
+    edge_counters: OptionalCell<&'a [Option<&'a dyn gpio::EdgeCounter>]>,
 }
 
 impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
     pub fn new(
         pins: &'a [Option<&'a gpio::InterruptValueWrapper<'a, IP>>],
-        grant: Grant<Option<Callback>>,
+        grant: Grant<App>,
     ) -> Self {
         for (i, maybe_pin) in pins.iter().enumerate() {
             if let Some(pin) = maybe_pin {
@@ -73,9 +175,198 @@ impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
         Self {
             pins: pins,
             apps: grant,
+            sample_timer: OptionalCell::empty(),
+            sampling: Cell::new(None),
+            bank_changed: Cell::new(0),
+            bank_window: Cell::new(false),
+            edge_counters: OptionalCell::empty(),
         }
     }
 
+    /// Provide the timer used for sampled reads and bank monitoring. Without
+    /// one, both return `ENOSUPPORT`.
+    pub fn set_sample_timer(&self, timer: &'a dyn OneshotTimer<'a>) {
+        self.sample_timer.set(timer);
+    }
+
+    /// Provide the pins that can count edges, in the same order as the pins
+    /// passed to `new()`, with `None` for those that cannot. Without them,
+    /// edge counting returns `ENOSUPPORT`.
+    pub fn set_edge_counters(&self, counters: &'a [Option<&'a dyn gpio::EdgeCounter>]) {
+        self.edge_counters.set(counters);
+    }
+
+    /// The edge counter of pin `pin_index`, if it has one.
+    fn edge_counter(&self, pin_index: usize) -> Option<&'a dyn gpio::EdgeCounter> {
+        self.edge_counters
+            .map(|counters| counters.get(pin_index).copied().flatten())
+            .flatten()
+    }
+
+    /// Start counting edges on `pin_index` as selected by `edge_config`, or
+    /// stop counting with `3`.
+    fn count_edges(&self, pin_index: usize, edge_config: usize) -> ReturnCode {
+        if self.pins[pin_index].is_none() {
+            return ReturnCode::ENODEVICE;
+        }
+        let counter = match self.edge_counter(pin_index) {
+            Some(counter) => counter,
+            None => return ReturnCode::ENOSUPPORT,
+        };
+        match edge_config {
+            0 => counter.start_counting(gpio::InterruptEdge::EitherEdge),
+            1 => counter.start_counting(gpio::InterruptEdge::RisingEdge),
+            2 => counter.start_counting(gpio::InterruptEdge::FallingEdge),
+            _ => {
+                counter.stop_counting();
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn pin_config(
+        pin: &gpio::InterruptValueWrapper<'a, IP>,
+    ) -> (gpio::Configuration, gpio::FloatingState) {
+        (pin.configuration(), pin.floating_state())
+    }
+
+    /// Start reading `pin_index` `count` times, `spacing_us` apart.
+    fn start_sampling(
+        &self,
+        app: AppId,
+        pin_index: usize,
+        count: usize,
+        spacing_us: u32,
+    ) -> ReturnCode {
+        if count == 0 {
+            return ReturnCode::EINVAL;
+        }
+        let pin = match self.pins[pin_index] {
+            Some(pin) => pin,
+            None => return ReturnCode::ENODEVICE,
+        };
+        match pin.configuration() {
+            gpio::Configuration::Input | gpio::Configuration::InputOutput => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        if self.sampling.get().is_some() || self.bank_window.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.sample_timer.map_or(ReturnCode::ENOSUPPORT, |timer| {
+            self.sampling.set(Some(Sampling {
+                app: app,
+                pin: pin_index,
+                config: Self::pin_config(pin),
+                spacing_us: spacing_us,
+                remaining: count,
+                highs: 0,
+                total: count,
+            }));
+            timer.schedule(spacing_us);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn finish_sampling(&self, sampling: Sampling, result: ReturnCode, value: bool) {
+        self.sampling.set(None);
+        let _ = self.apps.enter(sampling.app, |app, _| {
+            app.sample_callback
+                .map(|mut cb| cb.schedule(usize::from(result), sampling.pin, value as usize));
+        });
+        // Bank changes during the sampled read waited for the timer.
+        if self.bank_changed.get() != 0 {
+            self.open_bank_window();
+        }
+    }
+
+    /// Monitor the pins in `mask` for `app`, coalescing changes for
+    /// `window_us`. A mask of `0` stops monitoring.
+    fn monitor_bank(&self, app: AppId, mask: usize, window_us: u32) -> ReturnCode {
+        if self.sample_timer.is_none() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if !bank_mask_valid(mask, self.pins.len()) {
+            return ReturnCode::EINVAL;
+        }
+        self.apps
+            .enter(app, |app, _| {
+                app.bank_mask = mask as u32;
+                app.bank_window_us = window_us;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Record a change of pin `pin_num`, opening a bank window if an app
+    /// monitors it and none is open.
+    fn bank_pin_changed(&self, pin_num: usize) {
+        if pin_num >= MAX_BANK_PINS {
+            return;
+        }
+        let bit = 1 << pin_num;
+        let monitored = Cell::new(false);
+        self.apps.each(|app| {
+            if app.bank_mask & bit != 0 {
+                monitored.set(true);
+            }
+        });
+        if !monitored.get() {
+            return;
+        }
+        self.bank_changed.set(self.bank_changed.get() | bit);
+        if !self.bank_window.get() && self.sampling.get().is_none() {
+            self.open_bank_window();
+        }
+    }
+
+    /// Start timing the shortest window of the apps monitoring the changed
+    /// pins.
+    fn open_bank_window(&self) {
+        let changed = self.bank_changed.get();
+        let window_us: Cell<Option<u32>> = Cell::new(None);
+        self.apps.each(|app| {
+            if app.bank_mask & changed != 0 {
+                let us = window_us
+                    .get()
+                    .map_or(app.bank_window_us, |us| us.min(app.bank_window_us));
+                window_us.set(Some(us));
+            }
+        });
+        match window_us.get() {
+            Some(us) => {
+                self.sample_timer.map(|timer| {
+                    self.bank_window.set(true);
+                    timer.schedule(us);
+                });
+            }
+            // The apps stopped monitoring the pins in the meantime.
+            None => self.bank_changed.set(0),
+        }
+    }
+
+    /// The bank window ended: send each app monitoring a changed pin the
+    /// current state of its monitored pins.
+    fn close_bank_window(&self) {
+        self.bank_window.set(false);
+        let changed = self.bank_changed.replace(0);
+        let mut state = 0u32;
+        for (i, maybe_pin) in self.pins.iter().take(MAX_BANK_PINS).enumerate() {
+            if let Some(pin) = maybe_pin {
+                if pin.read() {
+                    state |= 1 << i;
+                }
+            }
+        }
+        self.apps.each(|app| {
+            if app.bank_mask & changed != 0 {
+                let mask = app.bank_mask;
+                app.bank_callback.map(|mut cb| {
+                    cb.schedule((state & mask) as usize, (changed & mask) as usize, 0)
+                });
+            }
+        });
+    }
+
     fn configure_input_pin(&self, pin_num: u32, config: usize) -> ReturnCode {
         let maybe_pin = self.pins[pin_num as usize];
         if let Some(pin) = maybe_pin {
@@ -120,6 +411,10 @@ impl<'a, IP: gpio::InterruptPin<'a>> GPIO<'a, IP> {
                     ReturnCode::SUCCESS
                 }
 
+                3 => pin.enable_level_interrupts(gpio::InterruptLevel::High),
+
+                4 => pin.enable_level_interrupts(gpio::InterruptLevel::Low),
+
                 _ => ReturnCode::ENOSUPPORT,
             }
         } else {
@@ -136,9 +431,47 @@ impl<'a, IP: gpio::InterruptPin<'a>> gpio::ClientWithValue for GPIO<'a, IP> {
             let pin_state = pin.read();
 
             // schedule callback with the pin number and value
-            self.apps.each(|callback| {
-                callback.map(|mut cb| cb.schedule(pin_num as usize, pin_state as usize, 0));
+            self.apps.each(|app| {
+                app.callback
+                    .map(|mut cb| cb.schedule(pin_num as usize, pin_state as usize, 0));
             });
+
+            self.bank_pin_changed(pin_num as usize);
+        }
+    }
+}
+
+impl<'a, IP: gpio::InterruptPin<'a>> OneshotTimerClient for GPIO<'a, IP> {
+    fn fired(&self) {
+        if self.bank_window.get() {
+            self.close_bank_window();
+            return;
+        }
+
+        let mut sampling = match self.sampling.get() {
+            Some(sampling) => sampling,
+            None => return,
+        };
+        let pin = match self.pins[sampling.pin] {
+            Some(pin) => pin,
+            None => return,
+        };
+        if Self::pin_config(pin) != sampling.config {
+            self.finish_sampling(sampling, ReturnCode::ECANCEL, false);
+            return;
+        }
+
+        if pin.read() {
+            sampling.highs += 1;
+        }
+        sampling.remaining -= 1;
+        if sampling.remaining == 0 {
+            let value = majority(sampling.highs, sampling.total);
+            self.finish_sampling(sampling, ReturnCode::SUCCESS, value);
+        } else {
+            self.sampling.set(Some(sampling));
+            self.sample_timer
+                .map(|timer| timer.schedule(sampling.spacing_us));
         }
     }
 }
@@ -150,6 +483,11 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
     ///
     /// - `0`: Subscribe to interrupts from all pins with interrupts enabled.
     ///        The callback signature is `fn(pin_num: usize, pin_state: bool)`
+    /// - `1`: Subscribe to the result of sampled reads. The callback signature
+    ///        is `fn(result: ReturnCode, pin_num: usize, pin_state: bool)`
+    /// - `2`: Subscribe to changes of the monitored bank. The callback
+    ///        signature is `fn(state: usize, changed: usize)`, with one bit
+    ///        per monitored pin.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -162,7 +500,25 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
             0 => self
                 .apps
                 .enter(app_id, |app, _| {
-                    **app = callback;
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // subscribe to the result of sampled reads
+            1 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.sample_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // subscribe to bank changes
+            2 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.bank_callback = callback;
                     ReturnCode::SUCCESS
                 })
                 .unwrap_or_else(|err| err.into()),
@@ -189,6 +545,18 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
     ///                   Set to `0` to interrupt on either edge.
     ///                   Set to `1` for rising edge.
     ///                   Set to `2` for falling edge.
+    ///                   Set to `3` for high level.
+    ///                   Set to `4` for low level.
+    ///                   Level interrupts fire once and must then be
+    ///                   re-enabled with command `7`, so a pin held at
+    ///                   the level cannot flood the app with callbacks.
+    ///                   Pins that cannot trigger on levels return
+    ///                   `ENOSUPPORT`.
+    ///   - `edge_config`: Edges to count, in `data2`.
+    ///                   Set to `0` for either edge.
+    ///                   Set to `1` for rising edges.
+    ///                   Set to `2` for falling edges.
+    ///                   Set to `3` to stop counting.
     ///
     /// ### `command_num`
     ///
@@ -202,8 +570,39 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
     /// - `7`: Configure interrupt on `pin` with `irq_config` in 0x00XX00000
     /// - `8`: Disable interrupt on `pin`.
     /// - `9`: Disable `pin`.
-    fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
+    /// - `10`: Enable (`1`) or disable (`0`) input hysteresis on `pin`, passed
+    ///         in `data2`. Returns `ENOSUPPORT` if the pin has no hysteresis
+    ///         control.
+    /// - `11`: Sampled read of `pin`, which must be configured as an input.
+    ///         The low byte of `data2` is the number of samples (at least
+    ///         `1`) and the upper bytes are the spacing between samples in
+    ///         microseconds. The majority value is delivered to the
+    ///         subscribe `1` callback, with ties reading as low. If the pin
+    ///         is reconfigured before sampling completes, the callback
+    ///         reports `ECANCEL`. Only one sampled read can be in progress
+    ///         at a time; returns `EBUSY` otherwise, and `ENOSUPPORT` if the
+    ///         board provides no sample timer.
+    /// - `12`: Monitor the pins whose bits are set in `data1` as a bank,
+    ///         coalescing changes that happen within `data2` microseconds of
+    ///         the first into one subscribe `2` callback. `0` stops
+    ///         monitoring. Only the first 32 pins can be monitored. Returns
+    ///         `EINVAL` if the mask names pins that do not exist, and
+    ///         `ENOSUPPORT` if the board provides no sample timer.
+    /// - `13`: Count edges on `pin` with `edge_config`, starting from `0`.
+    ///         Returns `ENOSUPPORT` if the pin cannot count edges.
+    /// - `14`: Number of edges counted on `pin`. If `data2` is `1`, the count
+    ///         is also reset to `0`. Returns `ENOSUPPORT` if the pin cannot
+    ///         count edges.
+    ///
+    /// Unknown commands, pins that do not exist, hysteresis settings and
+    /// edge count resets other than `0` or `1`, and `edge_config` other than
+    /// `0` to `3` return `EINVAL`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
         let pins = self.pins.as_ref();
+        let checked = COMMANDS.check(command_num, data1, data2, pins.len());
+        if checked != ReturnCode::SUCCESS {
+            return checked;
+        }
         let pin_index = data1;
         match command_num {
             // number of pins
@@ -213,83 +612,59 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
 
             // enable output
             1 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.make_output();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.make_output();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
                 }
             }
 
             // set pin
             2 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.set();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.set();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
                 }
             }
 
             // clear pin
             3 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.clear();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.clear();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
                 }
             }
 
             // toggle pin
             4 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.toggle();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.toggle();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
                 }
             }
 
             // enable and configure input
             5 => {
                 let pin_config = data2;
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
-                } else {
-                    self.configure_input_pin(pin_index as u32, pin_config)
-                }
+                self.configure_input_pin(pin_index as u32, pin_config)
             }
 
             // read input
             6 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
-                } else {
-                    if let Some(pin) = pins[pin_index] {
-                        let pin_state = pin.read();
-                        ReturnCode::SuccessWithValue {
-                            value: pin_state as usize,
-                        }
-                    } else {
-                        ReturnCode::ENODEVICE
+                if let Some(pin) = pins[pin_index] {
+                    let pin_state = pin.read();
+                    ReturnCode::SuccessWithValue {
+                        value: pin_state as usize,
                     }
+                } else {
+                    ReturnCode::ENODEVICE
                 }
             }
 
@@ -297,45 +672,81 @@ impl<'a, IP: gpio::InterruptPin<'a>> Driver for GPIO<'a, IP> {
             // (no affect or reliance on registered callback)
             7 => {
                 let irq_config = data2;
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
-                } else {
-                    self.configure_interrupt(pin_index as u32, irq_config)
-                }
+                self.configure_interrupt(pin_index as u32, irq_config)
             }
 
             // disable interrupts on pin, also disables pin
             // (no affect or reliance on registered callback)
             8 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.disable_interrupts();
+                    pin.deactivate_to_low_power();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.disable_interrupts();
-                        pin.deactivate_to_low_power();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
                 }
             }
 
             // disable pin
             9 => {
-                if pin_index >= pins.len() {
-                    ReturnCode::EINVAL /* impossible pin */
+                if let Some(pin) = pins[pin_index] {
+                    pin.deactivate_to_low_power();
+                    ReturnCode::SUCCESS
                 } else {
-                    if let Some(pin) = pins[pin_index] {
-                        pin.deactivate_to_low_power();
-                        ReturnCode::SUCCESS
-                    } else {
-                        ReturnCode::ENODEVICE
-                    }
+                    ReturnCode::ENODEVICE
+                }
+            }
+
+            // configure input hysteresis
+            10 => {
+                if let Some(pin) = pins[pin_index] {
+                    pin.set_hysteresis(data2 == 1)
+                } else {
+                    ReturnCode::ENODEVICE
                 }
             }
 
+            // sampled read
+            11 => self.start_sampling(appid, pin_index, data2 & 0xff, (data2 >> 8) as u32),
+
+            // monitor a bank of pins
+            12 => self.monitor_bank(appid, data1, data2 as u32),
+
+            // count edges
+            13 => self.count_edges(pin_index, data2),
+
+            // read the edge count
+            14 => match (pins[pin_index], self.edge_counter(pin_index)) {
+                (None, _) => ReturnCode::ENODEVICE,
+                (Some(_), None) => ReturnCode::ENOSUPPORT,
+                (Some(_), Some(counter)) => ReturnCode::SuccessWithValue {
+                    value: counter.edge_count(data2 == 1) as usize,
+                },
+            },
+
             // default
-            _ => ReturnCode::ENOSUPPORT,
+            _ => ReturnCode::EINVAL,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{bank_mask_valid, majority};
+
+    #[test]
+    fn bank_mask_names_existing_pins() {
+        assert!(bank_mask_valid(0b1011, 4));
+        assert!(!bank_mask_valid(0b1_0000, 4));
+        assert!(bank_mask_valid(0, 0));
+        assert!(bank_mask_valid(0xffff_ffff, 40));
+    }
+
+    #[test]
+    fn majority_requires_more_than_half() {
+        assert!(majority(3, 5));
+        assert!(!majority(2, 5));
+        assert!(!majority(2, 4));
+        assert!(majority(1, 1));
+    }
+}
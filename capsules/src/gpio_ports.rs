@@ -0,0 +1,185 @@
+//! Provides userspace with consistent reads of several GPIO ports at once.
+//!
+//! Data on a parallel interface wider than one GPIO port is spread over
+//! several input registers. Reading them with one command per pin or port can
+//! mix values from before and after the inputs changed. This capsule reads
+//! all the ports an app selects in one step, with interrupts disabled, and
+//! writes their values into an allowed buffer.
+//!
+//! The board chooses which pins of each port apps may read. Pins of a port
+//! that are not readable always read as 0, and a read fails unless all
+//! readable pins of the selected ports are configured as inputs, so that an
+//! app does not mistake a pin it drives itself for data.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let gpio_ports = static_init!(
+//!     capsules::gpio_ports::GpioPorts<'static>,
+//!     capsules::gpio_ports::GpioPorts::new(
+//!         &peripherals.gpio_port,
+//!         &[0x0000_ff00, 0x0000_00ff],
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer the port values are written to.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check. Returns the number of ports.
+//! - `1`: Read the ports selected by `data1`, bit `n` for port `n`, and write
+//!   their values into the allowed buffer as consecutive little-endian `u32`s
+//!   in increasing port order. Bit `m` of a value is pin `32 * n + m`. Returns
+//!   the number of ports read. Returns `EINVAL` if `data1` selects no port or
+//!   a port without readable pins, `FAIL` if a readable pin of a selected
+//!   port is not configured as an input, `ERESERVE` if no buffer is allowed
+//!   and `ESIZE` if it cannot hold all selected ports.
+//! - `2`: Return the pins of port `data1` that apps may read, one bit per
+//!   pin, or `EINVAL` if there is no such port.
+
+use kernel::hil::gpio::PortSnapshot;
+use kernel::{AppId, AppSlice, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::GpioPorts as usize;
+
+/// Most ports a single command can read.
+const MAX_PORTS: usize = 4;
+
+#[derive(Default)]
+pub struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct GpioPorts<'a> {
+    ports: &'a dyn PortSnapshot,
+    /// Pins of each port that apps may read, one bit per pin.
+    readable: &'a [u32],
+    apps: Grant<App>,
+}
+
+impl<'a> GpioPorts<'a> {
+    pub fn new(
+        ports: &'a dyn PortSnapshot,
+        readable: &'a [u32],
+        grant: Grant<App>,
+    ) -> GpioPorts<'a> {
+        GpioPorts {
+            ports: ports,
+            readable: readable,
+            apps: grant,
+        }
+    }
+
+    /// The pins of `port` that apps may read.
+    fn readable_pins(&self, port: usize) -> u32 {
+        if port < self.ports.port_count() {
+            self.readable.get(port).copied().unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    /// Read the ports selected by `mask` into `buffer`.
+    fn read(&self, mask: usize, buffer: &mut [u8]) -> ReturnCode {
+        let selected = || (0..MAX_PORTS).filter(move |port| mask & 1 << port != 0);
+        if mask == 0
+            || mask >> MAX_PORTS != 0
+            || selected().any(|port| self.readable_pins(port) == 0)
+        {
+            return ReturnCode::EINVAL;
+        }
+        if selected().any(|port| self.readable_pins(port) & !self.ports.input_pins(port) != 0) {
+            return ReturnCode::FAIL;
+        }
+        let count = mask.count_ones() as usize;
+        if buffer.len() < count * 4 {
+            return ReturnCode::ESIZE;
+        }
+
+        let mut values = [0; MAX_PORTS];
+        match self.ports.read_ports(mask as u32, &mut values) {
+            ReturnCode::SuccessWithValue { .. } => {}
+            err => return err,
+        }
+        for ((chunk, value), port) in buffer
+            .chunks_exact_mut(4)
+            .zip(values.iter())
+            .zip(selected())
+        {
+            chunk.copy_from_slice(&(value & self.readable_pins(port)).to_le_bytes());
+        }
+        ReturnCode::SuccessWithValue { value: count }
+    }
+}
+
+impl Driver for GpioPorts<'_> {
+    /// Set the buffer port values are written to.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Port value buffer.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Read GPIO ports.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check, returns the number of ports.
+    /// - `1`: Read the ports selected by `data1` into the allowed buffer.
+    /// - `2`: Readable pins of port `data1`.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SuccessWithValue {
+                value: self.ports.port_count(),
+            },
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| match app.buffer {
+                    Some(ref mut slice) => self.read(data1, slice.as_mut()),
+                    None => ReturnCode::ERESERVE,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 => {
+                if data1 < self.ports.port_count() {
+                    ReturnCode::SuccessWithValue {
+                        value: self.readable_pins(data1) as usize,
+                    }
+                } else {
+                    ReturnCode::EINVAL
+                }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Saves a snapshot of all processes to nonvolatile storage for hibernation.
+//!
+//! `save()` freezes all processes (see `kernel::hibernate`) and writes their
+//! snapshot, one buffer at a time, to a region of nonvolatile storage. Once it
+//! is written the client is told, and the board can power off. If the
+//! snapshot cannot be taken, does not fit in the region or cannot be written,
+//! the processes are resumed and the client gets the error.
+//!
+//! On the next boot the board reads the region back, usually straight from
+//! memory-mapped flash, and passes it to `Hibernation::restore()` after
+//! loading processes. If that succeeds it should call `invalidate()` so that
+//! the same snapshot is not restored again after a later reset.
+//!
+//! `HibernateDriver` lets the apps the board trusts ask to hibernate, in the
+//! same way as `system_reset`. The snapshot is saved from a deferred call
+//! rather than during the command, so that the requesting app is frozen after
+//! the command has returned, and resumes after it on the next boot.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let hibernation = static_init!(
+//!     kernel::hibernate::Hibernation,
+//!     kernel::hibernate::Hibernation::new(board_kernel, HIBERNATION_VERSION)
+//! );
+//! let hibernate_storage = static_init!(
+//!     capsules::hibernate_storage::HibernateStorage<'static, Capability>,
+//!     capsules::hibernate_storage::HibernateStorage::new(
+//!         hibernation,
+//!         flash_storage,
+//!         HIBERNATION_REGION_START,
+//!         HIBERNATION_REGION_LEN,
+//!         &mut capsules::hibernate_storage::BUFFER,
+//!         Capability
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(flash_storage, hibernate_storage);
+//! hibernate_storage.set_client(power_off);
+//!
+//! let hibernate = static_init!(
+//!     capsules::hibernate_storage::HibernateDriver<'static, Capability>,
+//!     capsules::hibernate_storage::HibernateDriver::new(
+//!         hibernate_storage,
+//!         board_kernel,
+//!         trusted_apps,
+//!         dynamic_deferred_caller
+//!     )
+//! );
+//! hibernate.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(hibernate)
+//!         .expect("no deferred call slot available for hibernation"),
+//! );
+//!
+//! if hibernation.restore(hibernation_region, &process_mgmt_cap) == ReturnCode::SUCCESS {
+//!     hibernate_storage.invalidate();
+//! }
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Hibernate. Returns `EBUSY` if hibernation was already requested.
+//!   On success the board powers off soon after the command returns. If the
+//!   snapshot cannot be saved the processes keep running, and the board is
+//!   told the error.
+//!
+//! All commands return `ENOSUPPORT` to apps not allowed to hibernate.
+
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hibernate::Hibernation;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::{AppId, Driver, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Hibernate as usize;
+
+/// Buffer the snapshot is written through.
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// Told when a snapshot has been written or invalidated.
+pub trait HibernateClient {
+    /// The snapshot was written to storage (`SUCCESS`), or an error occurred
+    /// and the processes are running again.
+    fn snapshot_saved(&self, result: ReturnCode);
+
+    /// The stored snapshot has been invalidated.
+    fn snapshot_invalidated(&self);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    /// Writing the snapshot: bytes written so far and total length.
+    Saving(usize, usize),
+    Invalidating,
+}
+
+pub struct HibernateStorage<'a, C: ProcessManagementCapability> {
+    hibernation: &'a Hibernation,
+    storage: &'a dyn NonvolatileStorage<'a>,
+    /// Storage address the snapshot is written at.
+    start: usize,
+    /// Length of the storage region for the snapshot.
+    len: usize,
+    buffer: TakeCell<'a, [u8]>,
+    operation: Cell<Operation>,
+    client: OptionalCell<&'a dyn HibernateClient>,
+    capability: C,
+}
+
+impl<'a, C: ProcessManagementCapability> HibernateStorage<'a, C> {
+    pub fn new(
+        hibernation: &'a Hibernation,
+        storage: &'a dyn NonvolatileStorage<'a>,
+        start: usize,
+        len: usize,
+        buffer: &'a mut [u8],
+        capability: C,
+    ) -> HibernateStorage<'a, C> {
+        HibernateStorage {
+            hibernation,
+            storage,
+            start,
+            len,
+            buffer: TakeCell::new(buffer),
+            operation: Cell::new(Operation::Idle),
+            client: OptionalCell::empty(),
+            capability,
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn HibernateClient) {
+        self.client.set(client);
+    }
+
+    /// Freeze all processes and start writing their snapshot. Returns the
+    /// error from `Hibernation::freeze()` if the processes cannot be frozen,
+    /// and `ESIZE` if their snapshot is larger than the storage region.
+    pub fn save(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        match self.hibernation.freeze(&self.capability) {
+            Ok(len) if len > self.len => {
+                self.hibernation.thaw(&self.capability);
+                ReturnCode::ESIZE
+            }
+            Ok(len) => {
+                self.operation.set(Operation::Saving(0, len));
+                let result = self.write_from(0);
+                if result != ReturnCode::SUCCESS {
+                    self.save_failed();
+                }
+                result
+            }
+            Err(error) => error,
+        }
+    }
+
+    /// Overwrite the start of the stored snapshot so that it is no longer
+    /// restored.
+    pub fn invalidate(&self) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            for byte in buffer.iter_mut().take(4) {
+                *byte = 0;
+            }
+            self.operation.set(Operation::Invalidating);
+            let result = self.storage.write(buffer, self.start, 4);
+            if result != ReturnCode::SUCCESS {
+                self.operation.set(Operation::Idle);
+            }
+            result
+        })
+    }
+
+    /// Write the next part of the snapshot, starting `offset` bytes in.
+    fn write_from(&self, offset: usize) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let len = self.hibernation.read(offset, buffer, &self.capability);
+            self.storage.write(buffer, self.start + offset, len)
+        })
+    }
+
+    fn save_failed(&self) {
+        self.operation.set(Operation::Idle);
+        self.hibernation.thaw(&self.capability);
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> NonvolatileStorageClient<'a> for HibernateStorage<'a, C> {
+    fn read_done(&self, buffer: &'a mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'a mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        match self.operation.get() {
+            Operation::Saving(written, total) => {
+                let written = written + length;
+                if length == 0 {
+                    self.save_failed();
+                    self.client
+                        .map(|client| client.snapshot_saved(ReturnCode::FAIL));
+                } else if written >= total {
+                    // The processes stay frozen, as the board is about to
+                    // power off.
+                    self.operation.set(Operation::Idle);
+                    self.client
+                        .map(|client| client.snapshot_saved(ReturnCode::SUCCESS));
+                } else {
+                    self.operation.set(Operation::Saving(written, total));
+                    let result = self.write_from(written);
+                    if result != ReturnCode::SUCCESS {
+                        self.save_failed();
+                        self.client.map(|client| client.snapshot_saved(result));
+                    }
+                }
+            }
+            Operation::Invalidating => {
+                self.operation.set(Operation::Idle);
+                self.client.map(|client| client.snapshot_invalidated());
+            }
+            Operation::Idle => {}
+        }
+    }
+}
+
+/// Lets trusted apps hibernate the board.
+pub struct HibernateDriver<'a, C: ProcessManagementCapability> {
+    storage: &'a HibernateStorage<'a, C>,
+    kernel: &'static Kernel,
+    /// Flash addresses of the apps allowed to hibernate.
+    allowed: &'a [usize],
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    /// Whether the snapshot is yet to be saved.
+    pending: Cell<bool>,
+}
+
+impl<'a, C: ProcessManagementCapability> HibernateDriver<'a, C> {
+    pub fn new(
+        storage: &'a HibernateStorage<'a, C>,
+        kernel: &'static Kernel,
+        allowed: &'a [usize],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> HibernateDriver<'a, C> {
+        HibernateDriver {
+            storage: storage,
+            kernel: kernel,
+            allowed: allowed,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            pending: Cell::new(false),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn is_allowed(&self, appid: AppId) -> bool {
+        self.allowed.iter().any(|&address| {
+            self.kernel
+                .lookup_app_by_flash_address(address, &self.storage.capability)
+                == Some(appid)
+        })
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> DynamicDeferredCallClient for HibernateDriver<'a, C> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.pending.set(false);
+        let result = self.storage.save();
+        if result != ReturnCode::SUCCESS {
+            self.storage
+                .client
+                .map(|client| client.snapshot_saved(result));
+        }
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> Driver for HibernateDriver<'a, C> {
+    /// Hibernate the board.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Hibernate once the command has returned.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        if !self.is_allowed(appid) {
+            return ReturnCode::ENOSUPPORT;
+        }
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                if self.pending.get() {
+                    return ReturnCode::EBUSY;
+                }
+                self.pending.set(true);
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
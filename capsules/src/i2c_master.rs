@@ -1,28 +1,454 @@
 //! Driver for an I2C Master interface.
+//!
+//! Besides transfers to a single device, apps can issue I2C general calls,
+//! which every device that supports them listens to. The supported general
+//! call commands are:
+//!
+//! - `0x06`: Software reset. Devices reset and latch the programmable part of
+//!   their address. Command `5` sends this without needing a buffer.
+//! - `0x04`: Latch the programmable part of the address without resetting.
+//!
+//! General calls are write only: reads and write-reads from the 7-bit
+//! address 0, including in scripts, are rejected with `EINVAL`. If no device
+//! acknowledges a transfer, including a general call, the completion callback
+//! reports an address NAK instead of success.
+//!
+//! Sharing the Bus
+//! ---------------
+//!
+//! Transfers issued while the bus is busy with another transfer wait in a
+//! queue, and are started in the order they were issued once the bus is
+//! free. Each app can have one transfer waiting besides the one in progress,
+//! so an app issuing transfers back to back cannot keep others off the bus.
+//! Issuing another while one is waiting returns `ENOMEM`. The bytes a
+//! transfer writes are copied from the app's command buffer when it is
+//! issued, into a copy kept for each app, so the app can reuse its buffer
+//! right away even if the transfer has to wait. A waiting transfer is dropped
+//! if its app faults or exits, and one in progress completes without a
+//! callback. Scripts do not wait: starting one while the bus is busy returns
+//! `EBUSY`.
+//!
+//! Scripts
+//! -------
+//!
+//! To run a fixed sequence of transfers, such as the initialization of a
+//! sensor, without a system call per transfer, apps can allow a script
+//! (allow `2`) and run it with command `6`. A script is a list of steps, each
+//! starting with an opcode byte:
+//!
+//! - `0x01 addr len data[len]`: Write `len` bytes to `addr`.
+//! - `0x02 addr len`: Read `len` bytes from `addr`.
+//! - `0x03 addr wlen rlen data[wlen]`: Write `wlen` bytes to `addr`, then read
+//!   `rlen` bytes.
+//! - `0x04 ms_lo ms_hi`: Wait for a little-endian 16 bit number of
+//!   milliseconds. Delays need the board to provide a timer with
+//!   `set_timer`.
+//!
+//! Transfer lengths are limited to the size of the kernel buffer (64 bytes),
+//! and a script may have at most `MAX_SCRIPT_STEPS` steps. The bytes read by
+//! all steps are stored one after the other in the command buffer (allow
+//! `1`). Scripts stop at the first failing step, and a single callback reports
+//! the result, the failing step and how many bytes were read.
+//!
+//! Long Reads
+//! ----------
+//!
+//! Reads and write-reads can read up to `MAX_READ_LEN` bytes, even though
+//! transfers go through the 64 byte kernel buffer. A longer read is split
+//! into chunks that fit the buffer: the first transfer writes any bytes and
+//! reads the first chunk, and each further chunk is a plain read from the
+//! same device, which suits devices such as EEPROMs that continue where the
+//! previous read stopped. Each chunk is copied into the command buffer at its
+//! offset as soon as it arrives, and a single callback reports the end of
+//! the read. If a chunk fails, the callback reports the error and how many
+//! bytes were read before it. Other transfers wait until all chunks are done.
+//!
+//! Clock Stretching Timeout
+//! ------------------------
+//!
+//! A slave that holds the clock low indefinitely stalls the bus, and the
+//! transfer never completes. With command `7`, an app can set how long a
+//! transfer may go without making progress on the bus before it is aborted
+//! with a clock stretching timeout error. Progress is measured by the I2C
+//! hardware, so this only works on chips that report it and can abort
+//! transfers, such as the Apollo3, and needs the board to provide a timer with
+//! `set_timer`. Elsewhere, command `7` returns `ENOSUPPORT`.
+//!
+//! 10-bit Addresses
+//! ----------------
+//!
+//! Transfers address 7-bit devices unless the app selects 10-bit addresses
+//! with command `8`, which applies to its following write, read and
+//! write-read commands until it selects 7-bit addresses again. Addresses
+//! that do not fit the selected width are rejected with `EINVAL`. On chips
+//! that cannot address 10-bit devices the completion callback reports
+//! "not supported". General calls and scripts always use 7-bit addresses.
+//!
+//! Bus Frequency
+//! -------------
+//!
+//! Commands `9` and `10` return the bus frequency the chip driver aims for
+//! and the one its clock dividers actually produce. The dividers only take
+//! whole numbers, so the actual frequency can be lower, which matters for
+//! sensors with tight timing.
+//!
+//! Power Budget
+//! ------------
+//!
+//! Boards can count the bus against a power budget with
+//! `set_power_consumer()`. The consumer is enabled when a transfer or script
+//! starts and disabled once the driver is idle again. If the budget does not
+//! allow enabling it, the command is rejected with `ERESERVE`.
 
+use crate::oneshot_timer::{OneshotTimer, OneshotTimerClient};
+use core::cell::Cell;
+use core::cmp;
 use enum_primitive::enum_from_primitive;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::hil::i2c;
+use kernel::power_budget::PowerConsumer;
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 /// Syscall driver number.
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::I2cMaster as usize;
 
-#[derive(Default)]
 pub struct App {
     callback: Option<Callback>,
     slice: Option<AppSlice<Shared, u8>>,
+    script_callback: Option<Callback>,
+    script: Option<AppSlice<Shared, u8>>,
+    /// Longest a transfer may stall before it is aborted, `0` for no limit.
+    stretch_timeout_us: u32,
+    /// Whether transfers address devices with 10-bit addresses.
+    ten_bit_addresses: bool,
+    /// Transfer waiting for the bus, and its place in the queue.
+    queued: Option<(Operation, usize)>,
+    /// Bytes the app's latest transfer writes, copied from the command buffer
+    /// when it was issued. A waiting write can be as long as any other, so
+    /// this is as long as the kernel buffer. It lives in the app's grant, so
+    /// the memory is taken from the app that uses the driver rather than
+    /// from the kernel, and only once per app since an app has at most one
+    /// transfer waiting.
+    staged: [u8; BUF_LEN],
 }
 
-pub static mut BUF: [u8; 64] = [0; 64];
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            slice: None,
+            script_callback: None,
+            script: None,
+            stretch_timeout_us: 0,
+            ten_bit_addresses: false,
+            queued: None,
+            staged: [0; BUF_LEN],
+        }
+    }
+}
+
+/// Size of the kernel buffer the transfers are staged in, which is also the
+/// most bytes a transfer can write.
+const BUF_LEN: usize = 64;
+
+/// Most bytes a read can read, in chunks of at most `BUF_LEN` bytes. This is
+/// also the largest command buffer an app may allow.
+pub const MAX_READ_LEN: usize = 512;
+
+pub static mut BUF: [u8; BUF_LEN] = [0; BUF_LEN];
+
+/// The address all devices supporting general calls respond to.
+pub const GENERAL_CALL_ADDR: u8 = 0x00;
+/// General call command to reset and latch the programmable address.
+pub const GENERAL_CALL_RESET: u8 = 0x06;
+/// General call command to latch the programmable address only.
+pub const GENERAL_CALL_LATCH_ADDR: u8 = 0x04;
+
+/// Largest 7-bit device address.
+const MAX_7BIT_ADDR: usize = 0x7f;
+/// Largest 10-bit device address.
+const MAX_10BIT_ADDR: usize = 0x3ff;
+
+/// Check that `addr` fits the selected address width, and, for transfers
+/// that `read`, that it is not the general call address.
+fn check_address(addr: usize, ten_bit: bool, read: bool) -> Option<u16> {
+    let max = if ten_bit {
+        MAX_10BIT_ADDR
+    } else {
+        MAX_7BIT_ADDR
+    };
+    let general_call = !ten_bit && addr == GENERAL_CALL_ADDR as usize;
+    if addr <= max && !(read && general_call) {
+        Some(addr as u16)
+    } else {
+        None
+    }
+}
+
+/// Largest script an app may allow.
+const SCRIPT_LEN: usize = 64;
+/// Largest number of steps in a script.
+pub const MAX_SCRIPT_STEPS: usize = 16;
+
+const OP_WRITE: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+const OP_WRITE_READ: u8 = 0x03;
+const OP_DELAY: u8 = 0x04;
+
+/// Error reported when the app revokes the script or command buffer while a
+/// script is running, or its command buffer while a read is waiting for the
+/// bus.
+const ERR_REVOKED: isize = -6;
+
+/// A transfer of the app's command buffer, or a general call reset.
+#[derive(Clone, Copy)]
+struct Operation {
+    command: Cmd,
+    addr: u16,
+    wlen: u8,
+    rlen: u16,
+}
+
+impl Operation {
+    /// A transfer of `wlen` bytes to write and `rlen` bytes to read, or
+    /// `None` if either is longer than a transfer can be. The lengths are
+    /// checked before they are narrowed, so that a length an app asks for is
+    /// never truncated into a shorter one that fits.
+    fn new(command: Cmd, addr: u16, wlen: usize, rlen: usize) -> Option<Operation> {
+        if wlen > BUF_LEN || rlen > MAX_READ_LEN {
+            return None;
+        }
+        Some(Operation {
+            command: command,
+            addr: addr,
+            wlen: wlen as u8,
+            rlen: rlen as u16,
+        })
+    }
+
+    /// Whether the transfer fits in the app's command buffer, and its bytes
+    /// to write in the kernel buffer.
+    fn fits(&self, app: &App) -> bool {
+        if self.command == Cmd::GeneralCallReset {
+            return true;
+        }
+        let len = cmp::max(self.wlen as usize, self.rlen as usize);
+        app.slice.as_ref().map_or(false, |slice| len <= slice.len())
+            && self.wlen as usize <= BUF_LEN
+            && self.rlen as usize <= MAX_READ_LEN
+    }
+
+    /// Whether the app's command buffer can take the bytes the transfer
+    /// reads. Unlike `fits()`, this holds once the bytes to write are staged
+    /// even if the app has revoked its buffer, as long as nothing is read.
+    fn read_fits(&self, app: &App) -> bool {
+        let len = self.rlen as usize;
+        len == 0 || app.slice.as_ref().map_or(false, |slice| len <= slice.len())
+    }
+
+    /// Copy the bytes the transfer writes from the app's command buffer into
+    /// `staged`. The transfer must fit, see `fits()`.
+    fn stage(&self, command_buffer: Option<&[u8]>, staged: &mut [u8]) {
+        let len = self.wlen as usize;
+        if self.command == Cmd::GeneralCallReset {
+            staged[0] = GENERAL_CALL_RESET;
+        } else if let Some(bytes) = command_buffer {
+            staged[..len].copy_from_slice(&bytes[..len]);
+        }
+    }
+}
+
+/// Returns the entry whose ticket was handed out first, counting back from
+/// `next_ticket`, the ticket the next waiting transfer gets. Tickets wrap
+/// around, so they are compared by their age rather than their value.
+fn oldest<T>(next_ticket: usize, queued: impl Iterator<Item = (T, usize)>) -> Option<T> {
+    let mut oldest: Option<(T, usize)> = None;
+    for (entry, ticket) in queued {
+        let age = next_ticket.wrapping_sub(ticket);
+        if oldest
+            .as_ref()
+            .map_or(true, |&(_, oldest_age)| age > oldest_age)
+        {
+            oldest = Some((entry, age));
+        }
+    }
+    oldest.map(|(entry, _)| entry)
+}
+
+/// Progress of a read that is split into chunks of at most `BUF_LEN` bytes,
+/// read one after the other from the same device.
+#[derive(Clone, Copy)]
+struct ChunkedRead {
+    len: usize,
+    /// Bytes read so far.
+    done: usize,
+}
+
+impl ChunkedRead {
+    fn new(len: usize) -> ChunkedRead {
+        ChunkedRead { len: len, done: 0 }
+    }
 
+    /// Length of the next chunk to read.
+    fn next_len(&self) -> usize {
+        cmp::min(self.len - self.done, BUF_LEN)
+    }
+
+    fn finished(&self) -> bool {
+        self.done >= self.len
+    }
+
+    /// Copy a chunk that was read into its place in `dest`. The app may have
+    /// allowed a smaller buffer since the read started, in which case only
+    /// what fits is copied.
+    fn complete(&mut self, chunk: &[u8], dest: &mut [u8]) {
+        if let Some(dest) = dest.get_mut(self.done..) {
+            for (a, b) in dest.iter_mut().zip(chunk.iter()) {
+                *a = *b;
+            }
+        }
+        self.done += chunk.len();
+    }
+}
+
+/// A transfer being watched for clock stretching.
+#[derive(Clone, Copy)]
+struct StretchGuard {
+    timeout_us: u32,
+    /// Bytes the transfer had left when the timer was last started.
+    remaining: Option<usize>,
+}
+
+/// A decoded script step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    /// Write the `len` bytes at offset `data` of the script to `addr`.
+    Write {
+        addr: u8,
+        data: usize,
+        len: u8,
+    },
+    Read {
+        addr: u8,
+        len: u8,
+    },
+    WriteRead {
+        addr: u8,
+        data: usize,
+        wlen: u8,
+        rlen: u8,
+    },
+    Delay {
+        ms: u16,
+    },
+}
+
+impl Step {
+    /// Decode the step at offset `pos` of `script`. Returns the step and the
+    /// offset of the next one, or `None` if the step is malformed.
+    fn parse(script: &[u8], pos: usize) -> Option<(Step, usize)> {
+        let arg = |i: usize| script.get(pos + i).copied();
+        let fits = |len: u8| len as usize <= BUF_LEN;
+        let (step, next) = match arg(0)? {
+            OP_WRITE => {
+                let len = arg(2)?;
+                let step = Step::Write {
+                    addr: arg(1)?,
+                    data: pos + 3,
+                    len: len,
+                };
+                (step, pos + 3 + len as usize)
+            }
+            OP_READ => {
+                let step = Step::Read {
+                    addr: arg(1)?,
+                    len: arg(2)?,
+                };
+                (step, pos + 3)
+            }
+            OP_WRITE_READ => {
+                let wlen = arg(2)?;
+                let step = Step::WriteRead {
+                    addr: arg(1)?,
+                    data: pos + 4,
+                    wlen: wlen,
+                    rlen: arg(3)?,
+                };
+                (step, pos + 4 + wlen as usize)
+            }
+            OP_DELAY => {
+                let ms = u16::from_le_bytes([arg(1)?, arg(2)?]);
+                (Step::Delay { ms: ms }, pos + 3)
+            }
+            _ => return None,
+        };
+        let valid = match step {
+            Step::Write { len, .. } => fits(len),
+            Step::Read { addr, len } => fits(len) && addr != GENERAL_CALL_ADDR,
+            Step::WriteRead {
+                addr, wlen, rlen, ..
+            } => fits(wlen) && fits(rlen) && addr != GENERAL_CALL_ADDR,
+            Step::Delay { .. } => true,
+        };
+        if valid && next <= script.len() {
+            Some((step, next))
+        } else {
+            None
+        }
+    }
+
+    /// Number of bytes the step reads.
+    fn read_len(&self) -> usize {
+        match *self {
+            Step::Read { len, .. } => len as usize,
+            Step::WriteRead { rlen, .. } => rlen as usize,
+            Step::Write { .. } | Step::Delay { .. } => 0,
+        }
+    }
+}
+
+/// Check that `script` is a well-formed script. Returns the total number of
+/// bytes it reads and whether it has any delays.
+fn check_script(script: &[u8]) -> Option<(usize, bool)> {
+    let (mut pos, mut steps, mut read_len, mut delays) = (0, 0, 0, false);
+    while pos < script.len() {
+        let (step, next) = Step::parse(script, pos)?;
+        steps += 1;
+        read_len += step.read_len();
+        delays |= matches!(step, Step::Delay { .. });
+        pos = next;
+    }
+    if steps > 0 && steps <= MAX_SCRIPT_STEPS {
+        Some((read_len, delays))
+    } else {
+        None
+    }
+}
+
+/// A script being run.
+#[derive(Clone, Copy)]
+struct Script {
+    app_id: AppId,
+    /// Length of the script in the app's script buffer.
+    len: usize,
+    /// Index of the current step.
+    step: usize,
+    /// Offsets of the current and of the next step in the script.
+    pos: usize,
+    next: usize,
+    /// Bytes read by the steps completed so far.
+    read_offset: usize,
+}
+
+#[derive(Clone, Copy)]
 struct Transaction {
-    /// The buffer containing the bytes to transmit as it should be returned to
-    /// the client
     app_id: AppId,
-    /// The total amount to transmit
-    read_len: OptionalCell<usize>,
+    /// The bytes the transfer reads, if any.
+    read: Option<ChunkedRead>,
+    /// The device further chunks of a long read are read from.
+    addr: u16,
+    ten_bit: bool,
 }
 
 pub struct I2CMasterDriver<I: 'static + i2c::I2CMaster> {
@@ -30,6 +456,12 @@ pub struct I2CMasterDriver<I: 'static + i2c::I2CMaster> {
     buf: TakeCell<'static, [u8]>,
     tx: MapCell<Transaction>,
     apps: Grant<App>,
+    script: Cell<Option<Script>>,
+    timer: OptionalCell<&'static dyn OneshotTimer<'static>>,
+    stretch_guard: Cell<Option<StretchGuard>>,
+    power: OptionalCell<&'static PowerConsumer>,
+    /// Place in the queue the next waiting transfer gets.
+    next_ticket: Cell<usize>,
 }
 
 impl<I: 'static + i2c::I2CMaster> I2CMasterDriver<I> {
@@ -39,54 +471,371 @@ impl<I: 'static + i2c::I2CMaster> I2CMasterDriver<I> {
             buf: TakeCell::new(buf),
             tx: MapCell::empty(),
             apps,
+            script: Cell::new(None),
+            timer: OptionalCell::empty(),
+            stretch_guard: Cell::new(None),
+            power: OptionalCell::empty(),
+            next_ticket: Cell::new(0),
         }
     }
 
-    fn operation(
-        &self,
-        app_id: AppId,
-        app: &mut App,
-        command: Cmd,
-        addr: u8,
-        wlen: u8,
-        rlen: u8,
-    ) -> ReturnCode {
+    /// Provide the timer used for delays in scripts and for clock stretching
+    /// timeouts. Without one, scripts with delays and setting a timeout are
+    /// rejected with `ENOSUPPORT`.
+    pub fn set_timer(&self, timer: &'static dyn OneshotTimer<'static>) {
+        self.timer.set(timer);
+    }
+
+    /// Count the bus against a power budget while it is in use.
+    pub fn set_power_consumer(&self, power: &'static PowerConsumer) {
+        self.power.set(power);
+    }
+
+    /// Stop counting the bus against the power budget if no transfer or
+    /// script is running.
+    fn release_power_if_idle(&self) {
+        if self.buf.is_some() && self.script.get().is_none() {
+            self.power.map(|power| power.disable());
+        }
+    }
+
+    /// Start watching the transfer that was just started, aborting it if it
+    /// makes no progress for `timeout_us`.
+    fn start_stretch_guard(&self, timeout_us: u32) {
+        if timeout_us == 0 {
+            return;
+        }
+        self.timer.map(|timer| {
+            self.stretch_guard.set(Some(StretchGuard {
+                timeout_us: timeout_us,
+                remaining: self.i2c.bytes_remaining(),
+            }));
+            timer.schedule(timeout_us);
+        });
+    }
+
+    fn stop_stretch_guard(&self) {
+        if self.stretch_guard.take().is_some() {
+            self.timer.map(|timer| timer.cancel());
+        }
+    }
+
+    /// The stretch guard's timer expired: abort the transfer if it has not
+    /// made progress since the timer was started.
+    fn check_stretch_guard(&self, guard: StretchGuard) {
+        let remaining = self.i2c.bytes_remaining();
+        if remaining.is_some() && remaining != guard.remaining {
+            self.stretch_guard.set(Some(StretchGuard {
+                remaining: remaining,
+                ..guard
+            }));
+            self.timer.map(|timer| timer.schedule(guard.timeout_us));
+        } else {
+            self.stretch_guard.set(None);
+            self.i2c.abort(i2c::Error::ClockStretchTimeout);
+        }
+    }
+
+    fn stretch_timeout(&self, app_id: AppId) -> u32 {
         self.apps
-            .enter(app_id, |_, _| {
-                if let Some(app_buffer) = app.slice.take() {
-                    self.buf.take().map(|buffer| {
-                        for n in 0..wlen as usize {
-                            buffer[n] = app_buffer.as_ref()[n];
-                        }
+            .enter(app_id, |app, _| app.stretch_timeout_us)
+            .unwrap_or(0)
+    }
 
-                        let read_len: OptionalCell<usize>;
-                        if rlen == 0 {
-                            read_len = OptionalCell::empty();
-                        } else {
-                            read_len = OptionalCell::new(rlen as usize);
-                        }
-                        self.tx.put(Transaction { app_id, read_len });
-                        app.slice = Some(app_buffer);
+    /// Check the first `len` bytes of the app's script and start running it.
+    fn start_script(&self, app_id: AppId, len: usize) -> ReturnCode {
+        if self.script.get().is_some() || self.buf.is_none() {
+            return ReturnCode::EBUSY;
+        }
+        let checked = self
+            .apps
+            .enter(app_id, |app, _| {
+                let script = app.script.as_ref().map_or(&[][..], |slice| slice.as_ref());
+                if len > script.len() {
+                    return ReturnCode::EINVAL;
+                }
+                let data_len = app.slice.as_ref().map_or(0, |slice| slice.len());
+                match check_script(&script[..len]) {
+                    Some((read_len, _)) if read_len > data_len => ReturnCode::ESIZE,
+                    Some((_, true)) if self.timer.is_none() => ReturnCode::ENOSUPPORT,
+                    Some(_) => ReturnCode::SUCCESS,
+                    None => ReturnCode::EINVAL,
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if checked == ReturnCode::SUCCESS {
+            self.script.set(Some(Script {
+                app_id: app_id,
+                len: len,
+                step: 0,
+                pos: 0,
+                next: 0,
+                read_offset: 0,
+            }));
+            self.run_step();
+        }
+        checked
+    }
 
-                        match command {
-                            Cmd::Ping => return ReturnCode::EINVAL,
-                            Cmd::Write => self.i2c.write(addr, buffer, wlen),
-                            Cmd::Read => self.i2c.read(addr, buffer, rlen),
-                            Cmd::WriteRead => self.i2c.write_read(addr, buffer, wlen, rlen),
-                        }
-                        ReturnCode::SUCCESS
-                    });
-                    // buffer has not been returned by I2C
-                    // i2c_master.rs should not allow us to get here
-                    ReturnCode::ENOMEM
-                } else {
-                    // AppDriver is attempting operation
-                    // but has not granted memory
-                    ReturnCode::EINVAL
+    /// Start the current step of the running script, or finish the script if
+    /// there are no steps left.
+    fn run_step(&self) {
+        let script = match self.script.get() {
+            Some(script) => script,
+            None => return,
+        };
+        if script.pos >= script.len {
+            self.finish_script(0);
+            return;
+        }
+
+        // Decode the step again, as the app may have changed its buffer since
+        // the script was checked, and stage any bytes to write.
+        let step = self
+            .apps
+            .enter(script.app_id, |app, _| {
+                let bytes = app.script.as_ref()?.as_ref();
+                let (step, next) = Step::parse(bytes.get(..script.len)?, script.pos)?;
+                let write = match step {
+                    Step::Write { data, len, .. } => Some((data, len as usize)),
+                    Step::WriteRead { data, wlen, .. } => Some((data, wlen as usize)),
+                    Step::Read { .. } | Step::Delay { .. } => None,
+                };
+                if let Some((data, len)) = write {
+                    self.buf
+                        .map(|buffer| buffer[..len].copy_from_slice(&bytes[data..data + len]));
                 }
+                Some((step, next))
             })
-            .expect("Appid does not map to app");
-        ReturnCode::ENOSUPPORT
+            .unwrap_or(None);
+
+        let (step, next) = match step {
+            Some(step) => step,
+            None => {
+                self.finish_script(ERR_REVOKED);
+                return;
+            }
+        };
+        self.script.set(Some(Script { next, ..script }));
+        match step {
+            Step::Write { addr, len, .. } => {
+                self.buf
+                    .take()
+                    .map(|buffer| self.i2c.write(addr, buffer, len));
+            }
+            Step::Read { addr, len } => {
+                self.buf
+                    .take()
+                    .map(|buffer| self.i2c.read(addr, buffer, len));
+            }
+            Step::WriteRead {
+                addr, wlen, rlen, ..
+            } => {
+                self.buf
+                    .take()
+                    .map(|buffer| self.i2c.write_read(addr, buffer, wlen, rlen));
+            }
+            Step::Delay { ms } => {
+                self.timer.map(|timer| timer.schedule(ms as u32 * 1000));
+                return;
+            }
+        }
+        self.start_stretch_guard(self.stretch_timeout(script.app_id));
+    }
+
+    /// Move on to the next step of the running script.
+    fn next_step(&self, read_len: usize) {
+        if let Some(script) = self.script.get() {
+            self.script.set(Some(Script {
+                step: script.step + 1,
+                pos: script.next,
+                read_offset: script.read_offset + read_len,
+                ..script
+            }));
+            self.run_step();
+        }
+    }
+
+    /// Stop the running script and report `err`, `0` on success, to the app.
+    fn finish_script(&self, err: isize) {
+        if let Some(script) = self.script.take() {
+            self.release_power_if_idle();
+            let _ = self.apps.enter(script.app_id, |app, _| {
+                app.script_callback.map(|mut cb| {
+                    cb.schedule(err as usize, script.step, script.read_offset);
+                });
+            });
+        }
+    }
+
+    /// Handle the completion of a transfer started by a script.
+    fn script_transfer_complete(&self, script: Script, buffer: &'static mut [u8], err: isize) {
+        // The step only ever reads into the command buffer, which was checked
+        // to be large enough when the script started.
+        let read_len = if err == 0 {
+            self.apps
+                .enter(script.app_id, |app, _| {
+                    let bytes = app.script.as_ref()?.as_ref();
+                    let (step, _) = Step::parse(bytes.get(..script.len)?, script.pos)?;
+                    let read_len = step.read_len();
+                    let dest = app
+                        .slice
+                        .as_mut()?
+                        .as_mut()
+                        .get_mut(script.read_offset..script.read_offset + read_len)?;
+                    dest.copy_from_slice(&buffer[..read_len]);
+                    Some(read_len)
+                })
+                .unwrap_or(None)
+        } else {
+            None
+        };
+        self.buf.put(Some(buffer));
+
+        match read_len {
+            Some(read_len) => self.next_step(read_len),
+            None if err != 0 => self.finish_script(err),
+            None => self.finish_script(ERR_REVOKED),
+        }
+    }
+
+    /// Start a transfer for the app, or queue it if the bus is busy.
+    fn operation(&self, app_id: AppId, app: &mut App, op: Operation) -> ReturnCode {
+        if !op.fits(app) {
+            // AppDriver is attempting operation
+            // but has not granted (enough) memory
+            return ReturnCode::EINVAL;
+        }
+        if self.buf.is_none() && app.queued.is_some() {
+            // The app already has a transfer waiting.
+            return ReturnCode::ENOMEM;
+        }
+        op.stage(
+            app.slice.as_ref().map(|slice| slice.as_ref()),
+            &mut app.staged,
+        );
+        if self.buf.is_none() {
+            let ticket = self.next_ticket.get();
+            self.next_ticket.set(ticket.wrapping_add(1));
+            app.queued = Some((op, ticket));
+            return ReturnCode::SUCCESS;
+        }
+        self.start_operation(app_id, app, op)
+    }
+
+    /// Copy the staged bytes to write into the kernel buffer and start the
+    /// transfer on the bus. Returns `EINVAL` if the app's command buffer no
+    /// longer fits the bytes the transfer reads.
+    fn start_operation(&self, app_id: AppId, app: &mut App, op: Operation) -> ReturnCode {
+        if !op.read_fits(app) {
+            return ReturnCode::EINVAL;
+        }
+        let buffer = match self.buf.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EBUSY,
+        };
+        let wlen = op.wlen;
+        buffer[..wlen as usize].copy_from_slice(&app.staged[..wlen as usize]);
+
+        let addr = op.addr;
+        let ten_bit = app.ten_bit_addresses;
+        let read = if op.rlen == 0 {
+            None
+        } else {
+            Some(ChunkedRead::new(op.rlen as usize))
+        };
+        // Longer reads continue in `read_next_chunk()`.
+        let rlen = read.map_or(0, |read| read.next_len()) as u8;
+        self.tx.put(Transaction {
+            app_id,
+            read,
+            addr,
+            ten_bit,
+        });
+
+        let started = match op.command {
+            Cmd::Write if ten_bit => self.i2c.write_10bit(addr, buffer, wlen),
+            Cmd::Read if ten_bit => self.i2c.read_10bit(addr, buffer, rlen),
+            Cmd::WriteRead if ten_bit => self.i2c.write_read_10bit(addr, buffer, wlen, rlen),
+            Cmd::Write => Ok(self.i2c.write(addr as u8, buffer, wlen)),
+            Cmd::Read => Ok(self.i2c.read(addr as u8, buffer, rlen)),
+            Cmd::WriteRead => Ok(self.i2c.write_read(addr as u8, buffer, wlen, rlen)),
+            Cmd::GeneralCallWrite => Ok(self.i2c.write(GENERAL_CALL_ADDR, buffer, wlen)),
+            Cmd::GeneralCallReset => Ok(self.i2c.write(GENERAL_CALL_ADDR, buffer, 1)),
+            Cmd::Ping
+            | Cmd::Script
+            | Cmd::StretchTimeout
+            | Cmd::AddressWidth
+            | Cmd::RequestedFrequency
+            | Cmd::ActualFrequency => Err((i2c::Error::NotSupported, buffer)),
+        };
+        match started {
+            Ok(()) => self.start_stretch_guard(app.stretch_timeout_us),
+            // Report the transfer as failed, like the hardware would. The
+            // app's grant is already entered, so this does not go through
+            // `command_complete()`.
+            Err((error, buffer)) => {
+                self.tx.take();
+                self.buf.put(Some(buffer));
+                app.callback.map(|mut cb| {
+                    cb.schedule(0, error_code(error) as usize, 0);
+                });
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Read the next chunk of a long read.
+    fn read_next_chunk(&self, tx: Transaction, buffer: &'static mut [u8], stretch_timeout_us: u32) {
+        let len = tx.read.map_or(0, |read| read.next_len()) as u8;
+        let (addr, ten_bit) = (tx.addr, tx.ten_bit);
+        self.tx.put(tx);
+        let started = if ten_bit {
+            self.i2c.read_10bit(addr, buffer, len)
+        } else {
+            Ok(self.i2c.read(addr as u8, buffer, len))
+        };
+        match started {
+            Ok(()) => self.start_stretch_guard(stretch_timeout_us),
+            Err((error, buffer)) => i2c::I2CHwMasterClient::command_complete(self, buffer, error),
+        }
+    }
+
+    /// Start the transfer that has been waiting the longest, if the bus is
+    /// free. Reads whose app revoked its command buffer in the meantime fail
+    /// without using the bus, and the next transfer is tried.
+    fn start_queued(&self) {
+        while self.buf.is_some() && self.script.get().is_none() {
+            let queued = self.apps.iter().filter_map(|cntr| {
+                cntr.enter(|app, _| app.queued.map(|(_, ticket)| (app.appid(), ticket)))
+            });
+            let app_id = match oldest(self.next_ticket.get(), queued) {
+                Some(app_id) => app_id,
+                None => break,
+            };
+            let _ = self.apps.enter(app_id, |app, _| {
+                if let Some((op, _)) = app.queued.take() {
+                    if self.start_operation(app_id, app, op) != ReturnCode::SUCCESS {
+                        app.callback.map(|mut cb| {
+                            cb.schedule(0, ERR_REVOKED as usize, 0);
+                        });
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Map an I2C error to a number we can pass back to the application.
+fn error_code(error: i2c::Error) -> isize {
+    match error {
+        i2c::Error::AddressNak => -1,
+        i2c::Error::DataNak => -2,
+        i2c::Error::ArbitrationLost => -3,
+        i2c::Error::Overrun => -4,
+        i2c::Error::NotSupported => -5,
+        i2c::Error::ClockStretchTimeout => -7,
+        i2c::Error::CommandComplete => 0,
     }
 }
 
@@ -99,6 +848,13 @@ pub enum Cmd {
     Write = 1,
     Read = 2,
     WriteRead = 3,
+    GeneralCallWrite = 4,
+    GeneralCallReset = 5,
+    Script = 6,
+    StretchTimeout = 7,
+    AddressWidth = 8,
+    RequestedFrequency = 9,
+    ActualFrequency = 10,
 }
 }
 
@@ -108,6 +864,11 @@ impl<I: i2c::I2CMaster> Driver for I2CMasterDriver<I> {
     /// ### `allow_num`
     ///
     /// - `1`: buffer for command
+    /// - `2`: script to run with command `6`
+    ///
+    /// Revoking the buffer while a transfer is in progress does not stop the
+    /// transfer, but any data it reads is discarded instead of being copied
+    /// into the revoked buffer.
     fn allow(
         &self,
         appid: AppId,
@@ -122,15 +883,44 @@ impl<I: i2c::I2CMaster> Driver for I2CMasterDriver<I> {
                     ReturnCode::SUCCESS
                 })
                 .unwrap_or_else(|err| err.into()),
+            2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.script = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
             _ => ReturnCode::ENOSUPPORT,
         }
     }
 
+    /// Reads are limited to `MAX_READ_LEN`, so a larger buffer could never be
+    /// used in full.
+    fn allow_max_size(&self, allow_num: usize) -> Option<usize> {
+        match allow_num {
+            1 => Some(MAX_READ_LEN),
+            2 => Some(SCRIPT_LEN),
+            _ => None,
+        }
+    }
+
     /// Setup callbacks.
     ///
     /// ### `subscribe_num`
     ///
-    /// - `1`: Write buffer completed callback
+    /// - `1`: Transfer completed callback. The second argument is `0` on
+    ///        success, or a negative error: `-1` address NAK (no device
+    ///        acknowledged), `-2` data NAK, `-3` arbitration lost, `-4`
+    ///        overrun, `-5` not supported, `-6` the app revoked the buffer
+    ///        a read was waiting to read into, `-7` clock stretching timeout.
+    ///        The third argument is the number of bytes read, which for a
+    ///        long read that failed counts the chunks read before the error.
+    /// - `2`: Script completed callback. The first argument is `0` on
+    ///        success, one of the errors above, or `-6` if the app revoked
+    ///        the script or command buffer while the script was running. The
+    ///        second is the index of the failing step (the number of steps on
+    ///        success), and the third the number of bytes read into the
+    ///        command buffer.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -144,52 +934,194 @@ impl<I: i2c::I2CMaster> Driver for I2CMasterDriver<I> {
                     ReturnCode::SUCCESS
                 }).unwrap_or_else(|err| err.into())
             },
+            2 /* script_done */ => {
+                self.apps.enter(app_id, |app, _| {
+                    app.script_callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into())
+            },
             _ => ReturnCode::ENOSUPPORT,
         }
     }
 
     /// Initiate transfers
+    ///
+    /// ### `cmd_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Write `arg2` bytes of the buffer to address `arg1`.
+    /// - `2`: Read `arg2` bytes from address `arg1` into the buffer.
+    /// - `3`: Write `arg1 >> 8` bytes to address `arg1 & 0xff`, then read
+    ///        `arg2` bytes. With 10-bit addresses, write `arg1 >> 16` bytes
+    ///        to address `arg1 & 0xffff`.
+    /// - `4`: General call: write `arg1` bytes of the buffer to the general
+    ///        call address. The first byte must be a supported general call
+    ///        command (`0x04` or `0x06`), otherwise `EINVAL` is returned.
+    /// - `5`: General call software reset.
+    /// - `6`: Run the first `arg1` bytes of the script buffer as a script.
+    ///        Returns `EINVAL` if the script is malformed, `ESIZE` if the
+    ///        command buffer cannot hold all the bytes it reads, and
+    ///        `ENOSUPPORT` if it has delays but the board provides no timer.
+    /// - `7`: Abort this app's transfers if they make no progress on the bus
+    ///        for `arg1` microseconds, usually because a slave is stretching
+    ///        the clock. `0` removes the limit. Returns `ENOSUPPORT` if the
+    ///        board provides no timer, or the chip cannot abort transfers.
+    /// - `8`: Use `arg1`-bit addresses, `7` or `10`, for this app's following
+    ///        transfers.
+    /// - `9`: Bus frequency in Hz the chip driver aims for.
+    /// - `10`: Bus frequency in Hz the hardware is set up for.
+    ///
+    /// Commands `9` and `10` return `ENOSUPPORT` if the chip driver does not
+    /// report frequencies. Commands `1` to `3` return `EINVAL` if the address does not fit the
+    /// selected width. Commands `1` to `5` return `EINVAL` if the transfer does not fit the
+    /// buffer, and `ENOMEM` if the bus is busy and the app already has a transfer waiting for
+    /// it. While a script is running, all commands other than `0` return `EBUSY`.
     fn command(&self, cmd_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
         if let Some(cmd) = Cmd::from_usize(cmd_num) {
-            match cmd {
+            if cmd != Cmd::Ping && self.script.get().is_some() {
+                return ReturnCode::EBUSY;
+            }
+            let uses_bus = match cmd {
+                Cmd::Write
+                | Cmd::Read
+                | Cmd::WriteRead
+                | Cmd::GeneralCallWrite
+                | Cmd::GeneralCallReset
+                | Cmd::Script => true,
+                _ => false,
+            };
+            if uses_bus {
+                let powered = self
+                    .power
+                    .map_or(ReturnCode::SUCCESS, |power| power.enable());
+                if powered != ReturnCode::SUCCESS {
+                    return powered;
+                }
+            }
+            let result = match cmd {
                 Cmd::Ping => ReturnCode::SUCCESS,
                 Cmd::Write => self
                     .apps
                     .enter(appid, |app, _| {
-                        let addr = arg1 as u8;
-                        let write_len = arg2;
-                        self.operation(appid, app, Cmd::Write, addr, write_len as u8, 0);
-                        ReturnCode::SUCCESS
+                        let addr = match check_address(arg1, app.ten_bit_addresses, false) {
+                            Some(addr) => addr,
+                            None => return ReturnCode::EINVAL,
+                        };
+                        match Operation::new(Cmd::Write, addr, arg2, 0) {
+                            Some(op) => self.operation(appid, app, op),
+                            None => ReturnCode::EINVAL,
+                        }
                     })
                     .unwrap_or_else(|err| err.into()),
                 Cmd::Read => self
                     .apps
                     .enter(appid, |app, _| {
-                        let addr = arg1 as u8;
-                        let read_len = arg2;
-                        self.operation(appid, app, Cmd::Read, addr, 0, read_len as u8);
-                        ReturnCode::SUCCESS
+                        let addr = match check_address(arg1, app.ten_bit_addresses, true) {
+                            Some(addr) => addr,
+                            None => return ReturnCode::EINVAL,
+                        };
+                        match Operation::new(Cmd::Read, addr, 0, arg2) {
+                            Some(op) => self.operation(appid, app, op),
+                            None => ReturnCode::EINVAL,
+                        }
                     })
                     .unwrap_or_else(|err| err.into()),
                 Cmd::WriteRead => {
-                    let addr = arg1 as u8;
-                    let write_len = arg1 >> 8; // can extend to 24 bit write length
                     let read_len = arg2; // can extend to 32 bit read length
                     self.apps
                         .enter(appid, |app, _| {
-                            self.operation(
-                                appid,
-                                app,
-                                Cmd::WriteRead,
-                                addr,
-                                write_len as u8,
-                                read_len as u8,
-                            );
+                            // 10-bit addresses need more than the low byte.
+                            let (addr, write_len) = if app.ten_bit_addresses {
+                                (arg1 & 0xffff, arg1 >> 16)
+                            } else {
+                                (arg1 & 0xff, arg1 >> 8)
+                            };
+                            let addr = match check_address(addr, app.ten_bit_addresses, true) {
+                                Some(addr) => addr,
+                                None => return ReturnCode::EINVAL,
+                            };
+                            match Operation::new(Cmd::WriteRead, addr, write_len, read_len) {
+                                Some(op) => self.operation(appid, app, op),
+                                None => ReturnCode::EINVAL,
+                            }
+                        })
+                        .unwrap_or_else(|err| err.into())
+                }
+                Cmd::GeneralCallWrite => self
+                    .apps
+                    .enter(appid, |app, _| {
+                        let write_len = arg1;
+                        let command = app
+                            .slice
+                            .as_ref()
+                            .filter(|slice| write_len > 0 && write_len <= slice.len())
+                            .map(|slice| slice.as_ref()[0]);
+                        match command {
+                            Some(GENERAL_CALL_RESET) | Some(GENERAL_CALL_LATCH_ADDR) => {
+                                match Operation::new(
+                                    Cmd::GeneralCallWrite,
+                                    GENERAL_CALL_ADDR as u16,
+                                    write_len,
+                                    0,
+                                ) {
+                                    Some(op) => self.operation(appid, app, op),
+                                    None => ReturnCode::EINVAL,
+                                }
+                            }
+                            _ => ReturnCode::EINVAL,
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                Cmd::GeneralCallReset => self
+                    .apps
+                    .enter(appid, |app, _| {
+                        let op = Operation {
+                            command: Cmd::GeneralCallReset,
+                            addr: GENERAL_CALL_ADDR as u16,
+                            wlen: 1,
+                            rlen: 0,
+                        };
+                        self.operation(appid, app, op)
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                Cmd::Script => self.start_script(appid, arg1),
+                Cmd::StretchTimeout => {
+                    if self.timer.is_none() || !self.i2c.can_abort() {
+                        return ReturnCode::ENOSUPPORT;
+                    }
+                    self.apps
+                        .enter(appid, |app, _| {
+                            app.stretch_timeout_us = arg1 as u32;
                             ReturnCode::SUCCESS
                         })
                         .unwrap_or_else(|err| err.into())
                 }
-            }
+                Cmd::AddressWidth => self
+                    .apps
+                    .enter(appid, |app, _| match arg1 {
+                        7 | 10 => {
+                            app.ten_bit_addresses = arg1 == 10;
+                            ReturnCode::SUCCESS
+                        }
+                        _ => ReturnCode::EINVAL,
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                Cmd::RequestedFrequency => self
+                    .i2c
+                    .requested_frequency_hz()
+                    .map_or(ReturnCode::ENOSUPPORT, |hz| ReturnCode::SuccessWithValue {
+                        value: hz as usize,
+                    }),
+                Cmd::ActualFrequency => self
+                    .i2c
+                    .actual_frequency_hz()
+                    .map_or(ReturnCode::ENOSUPPORT, |hz| ReturnCode::SuccessWithValue {
+                        value: hz as usize,
+                    }),
+            };
+            // The command may have been rejected without using the bus.
+            self.release_power_if_idle();
+            result
         } else {
             ReturnCode::ENOSUPPORT
         }
@@ -197,29 +1129,411 @@ impl<I: i2c::I2CMaster> Driver for I2CMasterDriver<I> {
 }
 
 impl<I: i2c::I2CMaster> i2c::I2CHwMasterClient for I2CMasterDriver<I> {
-    fn command_complete(&self, buffer: &'static mut [u8], _error: i2c::Error) {
-        self.tx.take().map(|tx| {
-            self.apps.enter(tx.app_id, |app, _| {
-                if let Some(read_len) = tx.read_len.take() {
-                    if let Some(mut app_buffer) = app.slice.take() {
-                        for n in 0..read_len {
-                            app_buffer.as_mut()[n] = buffer[n];
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        let err = error_code(error);
+
+        self.stop_stretch_guard();
+
+        if let Some(script) = self.script.get() {
+            self.script_transfer_complete(script, buffer, err);
+            return;
+        }
+
+        let next_chunk = self.tx.take().and_then(|mut tx| {
+            self.apps
+                .enter(tx.app_id, |app, _| {
+                    let mut reading = false;
+                    if let Some(read) = tx.read.as_mut() {
+                        match app.slice.as_mut() {
+                            Some(app_buffer) if err == 0 => {
+                                let len = read.next_len();
+                                read.complete(&buffer[..len], app_buffer.as_mut());
+                                reading = !read.finished();
+                            }
+                            // The chunk failed, or the app revoked its buffer
+                            // while the transfer was in progress, so the data
+                            // read is dropped and the read ends.
+                            _ => {}
                         }
-                        app.slice.replace(app_buffer);
-                    } else {
-                        // app has requested read but we have no buffer
-                        // should not arrive here
                     }
-                }
+                    if reading {
+                        return Some((tx, app.stretch_timeout_us));
+                    }
 
-                // signal to driver that tx complete
-                app.callback.map(|mut cb| {
-                    cb.schedule(0, 0, 0);
-                });
-            })
+                    // signal to driver that tx complete
+                    let read_len = tx.read.map_or(0, |read| read.done);
+                    app.callback.map(|mut cb| {
+                        cb.schedule(0, err as usize, read_len);
+                    });
+                    None
+                })
+                .unwrap_or(None)
         });
 
-        //recover buffer
-        self.buf.put(Some(buffer));
+        match next_chunk {
+            Some((tx, stretch_timeout_us)) => self.read_next_chunk(tx, buffer, stretch_timeout_us),
+            None => {
+                //recover buffer
+                self.buf.put(Some(buffer));
+                self.start_queued();
+                self.release_power_if_idle();
+            }
+        }
+    }
+}
+
+impl<I: i2c::I2CMaster> OneshotTimerClient for I2CMasterDriver<I> {
+    fn fired(&self) {
+        // The timer is either watching a transfer or timing a delay in a
+        // script, never both.
+        match self.stretch_guard.get() {
+            Some(guard) => self.check_stretch_guard(guard),
+            None => self.next_step(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_address, check_script, oldest, Cmd, I2CMasterDriver, Operation, Step, BUF_LEN,
+        DRIVER_NUM, ERR_REVOKED,
+    };
+    use crate::oneshot_timer::{OneshotTimer, OneshotTimerClient};
+    use core::cell::Cell;
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::i2c::{self, I2CHwMasterClient};
+    use kernel::procs::ProcessType;
+    use kernel::testing::MockProcess;
+    use kernel::{create_capability, Kernel, ReturnCode};
+    use std::boxed::Box;
+
+    #[test]
+    fn addresses_fit_width() {
+        assert_eq!(check_address(0x7f, false, false), Some(0x7f));
+        assert_eq!(check_address(0x80, false, false), None);
+        assert_eq!(check_address(0x3ff, true, false), Some(0x3ff));
+        assert_eq!(check_address(0x400, true, false), None);
+    }
+
+    #[test]
+    fn general_call_is_write_only() {
+        assert_eq!(check_address(0, false, false), Some(0));
+        assert_eq!(check_address(0, false, true), None);
+        assert_eq!(check_address(0, true, true), Some(0));
+        assert_eq!(Step::parse(&[0x02, 0x00, 1], 0), None);
+        assert_eq!(Step::parse(&[0x03, 0x00, 1, 1, 0xd0], 0), None);
+        assert!(Step::parse(&[0x01, 0x00, 1, 0x06], 0).is_some());
+    }
+
+    #[test]
+    fn parse_script_steps() {
+        let script = [0x03, 0x48, 1, 2, 0xd0, 0x04, 0x0a, 0x00, 0x02, 0x48, 6];
+        assert_eq!(
+            Step::parse(&script, 0),
+            Some((
+                Step::WriteRead {
+                    addr: 0x48,
+                    data: 4,
+                    wlen: 1,
+                    rlen: 2
+                },
+                5
+            ))
+        );
+        assert_eq!(Step::parse(&script, 5), Some((Step::Delay { ms: 10 }, 8)));
+        assert_eq!(check_script(&script), Some((8, true)));
+        // The write runs past the end of the script.
+        assert_eq!(check_script(&[0x01, 0x48, 4, 0]), None);
+        assert_eq!(check_script(&[]), None);
+    }
+
+    #[test]
+    fn queued_transfers_start_in_issue_order() {
+        // Two apps wait with writes to different addresses; the ticket of the
+        // second one has wrapped around.
+        let queued = [("second", 0), ("first", usize::MAX)];
+        assert_eq!(oldest(1, queued.iter().copied()), Some("first"));
+        // Once the first has started, the second follows.
+        assert_eq!(oldest(1, queued[..1].iter().copied()), Some("second"));
+        assert_eq!(oldest(1, queued[..0].iter().copied()), None::<&str>);
+    }
+
+    #[test]
+    fn staged_writes_are_kept_apart() {
+        let write = |addr| Operation {
+            command: Cmd::Write,
+            addr: addr,
+            wlen: 2,
+            rlen: 0,
+        };
+        let (mut first, mut second) = ([0; BUF_LEN], [0; BUF_LEN]);
+        let mut command_buffer = [0x11, 0x22];
+        write(0x48).stage(Some(&command_buffer[..]), &mut first);
+        // The app reuses its buffer before the first transfer has started.
+        command_buffer = [0x33, 0x44];
+        write(0x49).stage(Some(&command_buffer[..]), &mut second);
+        assert_eq!(first[..2], [0x11, 0x22]);
+        assert_eq!(second[..2], [0x33, 0x44]);
+    }
+
+    /// Stands in for the IOM. It holds on to the buffer of the transfer in
+    /// progress until the test completes the transfer.
+    struct MockI2C {
+        buffer: TakeCell<'static, [u8]>,
+        /// Address and read length of the transfer in progress.
+        transfer: Cell<Option<(u8, usize)>>,
+        transfers: Cell<usize>,
+        can_abort: Cell<bool>,
+    }
+
+    impl MockI2C {
+        fn start(&self, addr: u8, buffer: &'static mut [u8], read_len: u8) {
+            self.buffer.replace(buffer);
+            self.transfer.set(Some((addr, read_len as usize)));
+            self.transfers.set(self.transfers.get() + 1);
+        }
+
+        /// Complete the read in progress with the next bytes of `eeprom`, or
+        /// with `error`. Returns the address and length of the read.
+        fn complete_read(
+            &self,
+            driver: &I2CMasterDriver<MockI2C>,
+            eeprom: &[u8],
+            cursor: &mut usize,
+            error: i2c::Error,
+        ) -> (u8, usize) {
+            let (addr, len) = self.transfer.take().expect("no transfer in progress");
+            let buffer = self.buffer.take().unwrap();
+            if error == i2c::Error::CommandComplete {
+                buffer[..len].copy_from_slice(&eeprom[*cursor..*cursor + len]);
+                *cursor += len;
+            }
+            driver.command_complete(buffer, error);
+            (addr, len)
+        }
+    }
+
+    impl i2c::I2CMaster for MockI2C {
+        fn set_master_client(&self, _client: &'static dyn i2c::I2CHwMasterClient) {}
+        fn enable(&self) {}
+        fn disable(&self) {}
+        fn write_read(&self, addr: u8, data: &'static mut [u8], _write_len: u8, read_len: u8) {
+            self.start(addr, data, read_len);
+        }
+        fn write(&self, addr: u8, data: &'static mut [u8], _len: u8) {
+            self.start(addr, data, 0);
+        }
+        fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
+            self.start(addr, buffer, len);
+        }
+        fn can_abort(&self) -> bool {
+            self.can_abort.get()
+        }
+    }
+
+    struct NoTimer;
+
+    impl<'a> OneshotTimer<'a> for NoTimer {
+        fn set_client(&self, _client: &'a dyn OneshotTimerClient) {}
+        fn schedule(&self, _us: u32) {}
+        fn cancel(&self) {}
+    }
+
+    /// A process with a 300 byte command buffer and a transfer callback, and
+    /// the driver it uses.
+    fn setup() -> (
+        &'static MockProcess,
+        &'static MockI2C,
+        &'static I2CMasterDriver<MockI2C>,
+    ) {
+        let (processes, i2c, driver) = setup_apps();
+        (processes[0], i2c, driver)
+    }
+
+    /// Like `setup()`, with two processes using the driver.
+    fn setup_apps() -> (
+        [&'static MockProcess; 2],
+        &'static MockI2C,
+        &'static I2CMasterDriver<MockI2C>,
+    ) {
+        let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+        let apps: [&'static MockProcess; 2] = [
+            Box::leak(Box::new(MockProcess::new())),
+            Box::leak(Box::new(MockProcess::new())),
+        ];
+        let processes = Box::leak(Box::new([
+            Some(apps[0] as &dyn ProcessType),
+            Some(apps[1] as &dyn ProcessType),
+        ]));
+        let kernel = Box::leak(Box::new(Kernel::new(processes)));
+        let i2c: &'static MockI2C = Box::leak(Box::new(MockI2C {
+            buffer: TakeCell::empty(),
+            transfer: Cell::new(None),
+            transfers: Cell::new(0),
+            can_abort: Cell::new(false),
+        }));
+        let driver: &'static I2CMasterDriver<MockI2C> = Box::leak(Box::new(I2CMasterDriver::new(
+            i2c,
+            Box::leak(Box::new([0; BUF_LEN])),
+            kernel.create_grant(&memory_allocation_cap),
+        )));
+
+        for (index, process) in apps.iter().enumerate() {
+            process.attach(kernel, index);
+            let command_buffer = process.app_memory(0, 300);
+            assert_eq!(
+                process.allow_driver(driver, 1, Some(command_buffer)),
+                ReturnCode::SUCCESS
+            );
+            assert_eq!(
+                process.subscribe(driver, DRIVER_NUM, 1),
+                ReturnCode::SUCCESS
+            );
+        }
+        (apps, i2c, driver)
+    }
+
+    #[test]
+    fn writes_from_two_apps_both_complete() {
+        let ([first, second], i2c, driver) = setup_apps();
+        let write = |process: &MockProcess, addr, bytes: &[u8]| {
+            process.app_memory(0, bytes.len()).copy_from_slice(bytes);
+            process.command(driver, 1, addr, bytes.len())
+        };
+
+        assert_eq!(write(first, 0x48, &[0x11, 0x22]), ReturnCode::SUCCESS);
+        assert_eq!(write(second, 0x49, &[0x33, 0x44]), ReturnCode::SUCCESS);
+        // The second app reuses its buffer while its write waits for the bus.
+        second.app_memory(0, 2).copy_from_slice(&[0, 0]);
+        assert_eq!(
+            write(second, 0x49, &[0x55]),
+            ReturnCode::ENOMEM,
+            "only one transfer per app may wait"
+        );
+
+        for &(process, addr, bytes) in &[(first, 0x48, [0x11, 0x22]), (second, 0x49, [0x33, 0x44])]
+        {
+            assert_eq!(i2c.transfer.get(), Some((addr, 0)));
+            assert!(i2c.buffer.map_or(false, |buffer| buffer[..2] == bytes));
+            // A write reads nothing, so it completes like an empty read.
+            let mut cursor = 0;
+            i2c.complete_read(driver, &[], &mut cursor, i2c::Error::CommandComplete);
+            assert_eq!(process.take_callback(), Some((1, 0, 0, 0)));
+        }
+        assert_eq!(i2c.transfers.get(), 2);
+        assert!(i2c.transfer.get().is_none());
+        assert_eq!(first.take_callback(), None);
+        assert_eq!(second.take_callback(), None);
+    }
+
+    #[test]
+    fn long_read_is_assembled_from_chunks() {
+        let (process, i2c, driver) = setup();
+        let mut eeprom = [0; 300];
+        for (i, byte) in eeprom.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+
+        assert_eq!(process.command(driver, 2, 0x50, 300), ReturnCode::SUCCESS);
+        let mut cursor = 0;
+        for &len in &[64, 64, 64, 64] {
+            let read = i2c.complete_read(driver, &eeprom, &mut cursor, i2c::Error::CommandComplete);
+            assert_eq!(read, (0x50, len));
+            // One callback for the whole read.
+            assert_eq!(process.take_callback(), None);
+        }
+        let read = i2c.complete_read(driver, &eeprom, &mut cursor, i2c::Error::CommandComplete);
+        assert_eq!(read, (0x50, 300 - 4 * BUF_LEN));
+
+        assert_eq!(process.take_callback(), Some((1, 0, 0, 300)));
+        assert_eq!(i2c.transfers.get(), 5);
+        assert_eq!(process.app_memory(0, 300), &eeprom[..]);
+    }
+
+    #[test]
+    fn too_long_transfers_are_rejected_not_truncated() {
+        let (process, i2c, driver) = setup();
+        // 65600 would read 64 bytes, and 300 write 44, once narrowed.
+        assert_eq!(process.command(driver, 2, 0x50, 65600), ReturnCode::EINVAL);
+        assert_eq!(process.command(driver, 1, 0x50, 300), ReturnCode::EINVAL);
+        assert_eq!(
+            process.command(driver, 3, 0x50 | (300 << 8), 8),
+            ReturnCode::EINVAL
+        );
+        assert!(i2c.transfer.get().is_none());
+        assert_eq!(i2c.transfers.get(), 0);
+    }
+
+    #[test]
+    fn failed_chunk_reports_bytes_read() {
+        let (process, i2c, driver) = setup();
+        let eeprom = [0xa5; 300];
+
+        assert_eq!(process.command(driver, 2, 0x50, 300), ReturnCode::SUCCESS);
+        let mut cursor = 0;
+        for _ in 0..2 {
+            i2c.complete_read(driver, &eeprom, &mut cursor, i2c::Error::CommandComplete);
+        }
+        i2c.complete_read(driver, &eeprom, &mut cursor, i2c::Error::DataNak);
+
+        assert_eq!(
+            process.take_callback(),
+            Some((1, 0, -2isize as usize, 2 * BUF_LEN))
+        );
+        assert!(i2c.transfer.get().is_none());
+        let command_buffer = process.app_memory(0, 300);
+        assert!(command_buffer[..2 * BUF_LEN]
+            .iter()
+            .all(|byte| *byte == 0xa5));
+        assert!(command_buffer[2 * BUF_LEN..].iter().all(|byte| *byte == 0));
+
+        // The bus is free for the next transfer.
+        assert_eq!(process.command(driver, 2, 0x50, 8), ReturnCode::SUCCESS);
+        assert_eq!(i2c.transfer.get(), Some((0x50, 8)));
+    }
+
+    #[test]
+    fn revoking_buffer_drops_data_read() {
+        let (process, i2c, driver) = setup();
+        assert_eq!(process.command(driver, 2, 0x50, 8), ReturnCode::SUCCESS);
+
+        // A null buffer revokes the one being read into.
+        assert_eq!(process.allow_driver(driver, 1, None), ReturnCode::SUCCESS);
+        let mut cursor = 0;
+        i2c.complete_read(driver, &[0xa5; 8], &mut cursor, i2c::Error::CommandComplete);
+
+        assert_eq!(process.take_callback(), Some((1, 0, 0, 0)));
+        assert_eq!(process.app_memory(0, 8), &[0; 8]);
+    }
+
+    #[test]
+    fn waiting_read_fails_when_buffer_is_revoked() {
+        let ([first, second], i2c, driver) = setup_apps();
+        assert_eq!(first.command(driver, 2, 0x50, 8), ReturnCode::SUCCESS);
+        assert_eq!(second.command(driver, 2, 0x51, 8), ReturnCode::SUCCESS);
+        assert_eq!(second.allow_driver(driver, 1, None), ReturnCode::SUCCESS);
+
+        let mut cursor = 0;
+        i2c.complete_read(driver, &[0xa5; 8], &mut cursor, i2c::Error::CommandComplete);
+        assert_eq!(first.take_callback(), Some((1, 0, 0, 8)));
+        // The second read never reaches the bus.
+        assert_eq!(
+            second.take_callback(),
+            Some((1, 0, ERR_REVOKED as usize, 0))
+        );
+        assert_eq!(i2c.transfers.get(), 1);
+        assert!(i2c.transfer.get().is_none());
+    }
+
+    #[test]
+    fn stretch_timeout_needs_timer_and_abort() {
+        let (process, i2c, driver) = setup();
+        assert_eq!(process.command(driver, 7, 1000, 0), ReturnCode::ENOSUPPORT);
+        driver.set_timer(Box::leak(Box::new(NoTimer)));
+        assert_eq!(process.command(driver, 7, 1000, 0), ReturnCode::ENOSUPPORT);
+        i2c.can_abort.set(true);
+        assert_eq!(process.command(driver, 7, 1000, 0), ReturnCode::SUCCESS);
     }
 }
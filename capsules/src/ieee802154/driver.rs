@@ -574,6 +574,12 @@ impl Driver for RadioDriver<'_> {
         }
     }
 
+    /// The config buffer is used to pass link-layer keys, so it is zeroed
+    /// once the app stops sharing it.
+    fn allow_zero_on_revoke(&self, allow_num: usize) -> bool {
+        allow_num == 2
+    }
+
     /// Setup callbacks.
     ///
     /// ### `subscribe_num`
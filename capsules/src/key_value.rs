@@ -0,0 +1,1027 @@
+//! Persistent key-value store for small application settings.
+//!
+//! This capsule keeps a log of records in a region of internal flash, so that
+//! apps can store small values under 16 bit keys without managing the flash
+//! layout themselves. Keys are shared by all apps on the board.
+//!
+//! Storage layout
+//! --------------
+//!
+//! The region is a ring of flash pages. Every page in use starts with a magic
+//! number and a sequence number, followed by records:
+//!
+//! ```text
+//! +---------+----------+-----------------+-------------------+
+//! | key u16 | len u8   | flag u8         | value, len bytes  | padding to 4 bytes
+//! +---------+----------+-----------------+-------------------+
+//! ```
+//!
+//! Setting or deleting a key appends a record to the newest page, and the
+//! newest record for a key wins. When the newest page is full the next page of
+//! the ring is used. One page is always kept free: when only that page is
+//! left, the records still in use are copied from the oldest page into it and
+//! the oldest page is erased. Pages are therefore erased in turn, which spreads
+//! wear over the whole region.
+//!
+//! The location of the current record of each key is kept in RAM. `mount()`
+//! rebuilds it from flash at boot, and must be called before apps can use the
+//! store. If a board loses power while the oldest page is being compacted,
+//! `mount()` finishes the compaction. A page that was only partially written
+//! when power was lost can lose the records in it.
+//!
+//! At most `MAX_KEYS` keys can be stored, each with a value of at most
+//! `MAX_VALUE_LEN` bytes. The region needs at least two pages, and should be
+//! large enough that all keys at their largest fit in all but one page.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let page_buffer = static_init!(
+//!     <Flash as kernel::hil::flash::Flash>::Page,
+//!     <Flash as kernel::hil::flash::Flash>::Page::default()
+//! );
+//! let key_value = static_init!(
+//!     capsules::key_value::KeyValue<'static, Flash>,
+//!     capsules::key_value::KeyValue::new(
+//!         flash,
+//!         board_kernel.create_grant(&grant_cap),
+//!         page_buffer,
+//!         FIRST_PAGE,
+//!         NUM_PAGES,
+//!     )
+//! );
+//! flash.set_client(key_value);
+//! key_value.mount();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer the value is read from by `set` and written to by `get`.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Callback when an operation completes. The first argument is the
+//!   result as a `ReturnCode`, and for `get` the second argument is the length
+//!   of the stored value. Only as much of the value as fits in the allowed
+//!   buffer is copied.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Get the value stored under key `data1`. Completes with `EINVAL` if
+//!   there is no value for the key.
+//! - `2`: Store the first `data2` bytes of the allowed buffer under key
+//!   `data1`. Completes with `ENOMEM` if `MAX_KEYS` keys are already stored or
+//!   the region is full.
+//! - `3`: Delete the value stored under key `data1`. Completes with `EINVAL` if
+//!   there is no value for the key.
+//!
+//! Keys must be less than `0xffff`. Each app can have one operation
+//! outstanding, and operations of different apps are run in turn. Commands
+//! return `EOFF` if the store could not be mounted.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KeyValue as usize;
+
+/// Maximum number of keys that can be stored at once.
+pub const MAX_KEYS: usize = 32;
+
+/// Maximum length of a value in bytes.
+pub const MAX_VALUE_LEN: usize = 64;
+
+/// "TKKV" in little endian, marks a page that is in use.
+const MAGIC: u32 = 0x564b_4b54;
+/// Length of the magic and sequence number at the start of each page.
+const HEADER_LEN: usize = 8;
+/// Length of the key, length and flag of each record.
+const RECORD_HEADER_LEN: usize = 4;
+/// Key of the first unwritten record slot in a page, as flash erases to 0xff.
+const ERASED_KEY: u16 = 0xffff;
+
+const FLAG_SET: u8 = 0x01;
+const FLAG_DELETE: u8 = 0x02;
+
+/// A record parsed from a page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    key: u16,
+    flag: u8,
+    offset: usize,
+    len: usize,
+}
+
+/// Location of the current value of a key.
+#[derive(Clone, Copy)]
+struct Entry {
+    key: u16,
+    page: usize,
+    offset: usize,
+    len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Get(u16),
+    Set(u16, usize),
+    Delete(u16),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// `mount()` has not been called, or failed.
+    Unmounted,
+    /// Reading every page to find the oldest and the newest.
+    Scanning(usize),
+    /// Reading the pages from the oldest to rebuild the index.
+    Replaying(usize),
+    /// Erasing an oldest page whose compaction was interrupted.
+    EraseStale,
+    Idle,
+    /// Reading the page that holds a value for `get`.
+    Get,
+    /// Reading the newest page to append a record to it.
+    ReadHead,
+    /// Erasing a page before starting to use it.
+    EraseFresh(usize),
+    /// Writing a page with a new record.
+    Write {
+        page: usize,
+        offset: usize,
+        end: usize,
+        fresh: bool,
+    },
+    /// Reading the oldest page to compact it.
+    CompactRead,
+    /// Erasing the free page the compacted records, ending at `end`, go to.
+    CompactEraseSpare(usize),
+    /// Writing the compacted records, ending at `end`.
+    CompactWrite(usize),
+    /// Erasing the oldest page once its records have been copied.
+    CompactEraseTail,
+}
+
+fn round_up(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn record_len(len: usize) -> usize {
+    RECORD_HEADER_LEN + round_up(len)
+}
+
+/// Returns the sequence number of a page, or `None` if it is not in use.
+fn page_sequence(page: &[u8]) -> Option<u32> {
+    if page.len() < HEADER_LEN {
+        return None;
+    }
+    let magic = u32::from_le_bytes([page[0], page[1], page[2], page[3]]);
+    if magic == MAGIC {
+        Some(u32::from_le_bytes([page[4], page[5], page[6], page[7]]))
+    } else {
+        None
+    }
+}
+
+fn write_header(page: &mut [u8], sequence: u32) {
+    page[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    page[4..8].copy_from_slice(&sequence.to_le_bytes());
+}
+
+/// Parses the record at `offset`. Returns `None` at the end of the records of
+/// the page, or if the record is malformed.
+fn parse_record(page: &[u8], offset: usize) -> Option<Record> {
+    if offset + RECORD_HEADER_LEN > page.len() {
+        return None;
+    }
+    let key = u16::from_le_bytes([page[offset], page[offset + 1]]);
+    let len = page[offset + 2] as usize;
+    let flag = page[offset + 3];
+    if key == ERASED_KEY
+        || len > MAX_VALUE_LEN
+        || (flag != FLAG_SET && flag != FLAG_DELETE)
+        || offset + record_len(len) > page.len()
+    {
+        return None;
+    }
+    Some(Record {
+        key: key,
+        flag: flag,
+        offset: offset,
+        len: len,
+    })
+}
+
+/// Writes a record at `offset`. Returns the offset after the record, or `None`
+/// if it does not fit in the page.
+fn append_record(
+    page: &mut [u8],
+    offset: usize,
+    key: u16,
+    flag: u8,
+    value: &[u8],
+) -> Option<usize> {
+    let end = offset + record_len(value.len());
+    if end > page.len() || value.len() > MAX_VALUE_LEN {
+        return None;
+    }
+    page[offset..offset + 2].copy_from_slice(&key.to_le_bytes());
+    page[offset + 2] = value.len() as u8;
+    page[offset + 3] = flag;
+    let start = offset + RECORD_HEADER_LEN;
+    page[start..start + value.len()].copy_from_slice(value);
+    for byte in page[start + value.len()..end].iter_mut() {
+        *byte = 0xff;
+    }
+    Some(end)
+}
+
+/// Iterates over the records of a page. `offset` is the end of the records
+/// once the iterator is exhausted.
+struct Records<'b> {
+    page: &'b [u8],
+    offset: usize,
+}
+
+impl<'b> Records<'b> {
+    fn new(page: &'b [u8]) -> Records<'b> {
+        Records {
+            page: page,
+            offset: HEADER_LEN,
+        }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let record = parse_record(self.page, self.offset)?;
+        self.offset += record_len(record.len);
+        Some(record)
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+    pending: Option<Operation>,
+}
+
+pub struct KeyValue<'a, F: hil::flash::Flash + 'static> {
+    driver: &'a F,
+    apps: Grant<App>,
+    buffer: TakeCell<'static, F::Page>,
+    page_len: usize,
+    /// First flash page of the region.
+    start_page: usize,
+    /// Number of flash pages in the region.
+    num_pages: usize,
+    state: Cell<State>,
+    /// Page of the region whose contents are in `buffer`.
+    cached: OptionalCell<usize>,
+    /// Oldest page in use.
+    tail: Cell<usize>,
+    /// Newest page in use, where records are appended.
+    head: Cell<usize>,
+    /// Offset after the last record of the newest page.
+    head_end: Cell<usize>,
+    /// Number of pages in use.
+    used: Cell<usize>,
+    /// Sequence number of the next page to be written.
+    next_sequence: Cell<u32>,
+    /// Oldest and newest pages found while scanning, with their sequence
+    /// numbers.
+    oldest: Cell<Option<(u32, usize)>>,
+    newest: Cell<Option<(u32, usize)>>,
+    /// Pages compacted for the current operation, to give up when the region
+    /// is full.
+    compactions: Cell<usize>,
+    index: [Cell<Option<Entry>>; MAX_KEYS],
+    current: OptionalCell<(AppId, Operation)>,
+}
+
+impl<'a, F: hil::flash::Flash> KeyValue<'a, F> {
+    pub fn new(
+        driver: &'a F,
+        grant: Grant<App>,
+        buffer: &'static mut F::Page,
+        start_page: usize,
+        num_pages: usize,
+    ) -> KeyValue<'a, F> {
+        let page_len = buffer.as_mut().len();
+        KeyValue {
+            driver: driver,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            page_len: page_len,
+            start_page: start_page,
+            num_pages: num_pages,
+            state: Cell::new(State::Unmounted),
+            cached: OptionalCell::empty(),
+            tail: Cell::new(0),
+            head: Cell::new(0),
+            head_end: Cell::new(HEADER_LEN),
+            used: Cell::new(0),
+            next_sequence: Cell::new(0),
+            oldest: Cell::new(None),
+            newest: Cell::new(None),
+            compactions: Cell::new(0),
+            index: <[Cell<Option<Entry>>; MAX_KEYS]>::default(),
+            current: OptionalCell::empty(),
+        }
+    }
+
+    /// Read the region and find the current value of every key. Operations
+    /// requested by apps in the meantime are run once this completes.
+    pub fn mount(&self) -> ReturnCode {
+        if self.state.get() != State::Unmounted {
+            return ReturnCode::EALREADY;
+        }
+        if self.num_pages < 2 || self.page_len < HEADER_LEN + record_len(MAX_VALUE_LEN) {
+            return ReturnCode::EINVAL;
+        }
+        for entry in self.index.iter() {
+            entry.set(None);
+        }
+        self.oldest.set(None);
+        self.newest.set(None);
+        let result = self.read(0, State::Scanning(0));
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Unmounted);
+        }
+        result
+    }
+
+    fn read(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.cached.clear();
+            self.state.set(state);
+            match self.driver.read_page(self.start_page + page, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.buffer.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    fn write(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.cached.clear();
+            self.state.set(state);
+            match self.driver.write_page(self.start_page + page, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.buffer.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    fn erase(&self, page: usize, state: State) -> ReturnCode {
+        if self.cached.contains(&page) {
+            self.cached.clear();
+        }
+        self.state.set(state);
+        self.driver.erase_page(self.start_page + page)
+    }
+
+    fn next_page(&self, page: usize) -> usize {
+        (page + 1) % self.num_pages
+    }
+
+    fn lookup(&self, key: u16) -> Option<Entry> {
+        self.index
+            .iter()
+            .find_map(|entry| entry.get().filter(|entry| entry.key == key))
+    }
+
+    fn has_room(&self, key: u16) -> bool {
+        self.index
+            .iter()
+            .any(|entry| entry.get().map_or(true, |entry| entry.key == key))
+    }
+
+    fn insert(&self, new: Entry) {
+        let slot = self
+            .index
+            .iter()
+            .find(|entry| entry.get().map_or(false, |entry| entry.key == new.key))
+            .or_else(|| self.index.iter().find(|entry| entry.get().is_none()));
+        slot.map(|entry| entry.set(Some(new)));
+    }
+
+    fn remove(&self, key: u16) {
+        self.index
+            .iter()
+            .filter(|entry| entry.get().map_or(false, |entry| entry.key == key))
+            .for_each(|entry| entry.set(None));
+    }
+
+    /// Apply the records of a page to the index, returning the end of them.
+    fn replay(&self, page: usize, data: &[u8]) -> usize {
+        let mut records = Records::new(data);
+        while let Some(record) = records.next() {
+            if record.flag == FLAG_SET {
+                self.insert(Entry {
+                    key: record.key,
+                    page: page,
+                    offset: record.offset,
+                    len: record.len,
+                });
+            } else {
+                self.remove(record.key);
+            }
+        }
+        records.offset
+    }
+
+    fn mounted(&self) {
+        self.state.set(State::Idle);
+        self.run_next();
+    }
+
+    fn mount_failed(&self) {
+        self.state.set(State::Unmounted);
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                if app.pending.take().is_some() {
+                    app.callback
+                        .map(|mut cb| cb.schedule(usize::from(ReturnCode::EOFF), 0, 0));
+                }
+            });
+        }
+    }
+
+    fn enqueue(&self, appid: AppId, operation: Operation) -> ReturnCode {
+        if self.state.get() == State::Unmounted {
+            return ReturnCode::EOFF;
+        }
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() || self.current.map_or(false, |(id, _)| *id == appid) {
+                    return ReturnCode::EBUSY;
+                }
+                let buffer_len = app.buffer.as_ref().map(|slice| slice.len());
+                match (operation, buffer_len) {
+                    (Operation::Get(_), None) | (Operation::Set(_, _), None) => {
+                        return ReturnCode::ERESERVE;
+                    }
+                    (Operation::Set(_, len), Some(buffer_len)) if len > buffer_len => {
+                        return ReturnCode::EINVAL;
+                    }
+                    _ => {}
+                }
+                app.pending = Some(operation);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS && self.state.get() == State::Idle {
+            self.run_next();
+        }
+        result
+    }
+
+    /// Start the pending operation of the next app, if any.
+    fn run_next(&self) {
+        for cntr in self.apps.iter() {
+            let pending =
+                cntr.enter(|app, _| app.pending.take().map(|operation| (app.appid(), operation)));
+            if let Some((appid, operation)) = pending {
+                self.start(appid, operation);
+                break;
+            }
+        }
+    }
+
+    fn start(&self, appid: AppId, operation: Operation) {
+        self.current.set((appid, operation));
+        self.compactions.set(0);
+        let result = match operation {
+            Operation::Get(key) => self.lookup(key).map_or(ReturnCode::EINVAL, |entry| {
+                self.read(entry.page, State::Get)
+            }),
+            Operation::Set(key, _) => {
+                if self.has_room(key) {
+                    self.append()
+                } else {
+                    ReturnCode::ENOMEM
+                }
+            }
+            Operation::Delete(key) => {
+                if self.lookup(key).is_some() {
+                    self.append()
+                } else {
+                    ReturnCode::EINVAL
+                }
+            }
+        };
+        if result != ReturnCode::SUCCESS {
+            self.finish(result, 0);
+        }
+    }
+
+    /// Complete the current operation and start the next one.
+    fn finish(&self, result: ReturnCode, len: usize) {
+        self.state.set(State::Idle);
+        self.current.take().map(|(appid, _)| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(usize::from(result), len, 0));
+            });
+        });
+        self.run_next();
+    }
+
+    /// Append the record of the current operation, making room for it first
+    /// if needed.
+    fn append(&self) -> ReturnCode {
+        let len = match self.current.map(|(_, operation)| *operation) {
+            Some(Operation::Set(_, len)) => len,
+            Some(Operation::Delete(_)) => 0,
+            _ => return ReturnCode::FAIL,
+        };
+        let head = self.head.get();
+        if self.used.get() > 0 && self.head_end.get() + record_len(len) <= self.page_len {
+            if self.cached.contains(&head) {
+                self.write_record(head, self.head_end.get(), false)
+            } else {
+                self.read(head, State::ReadHead)
+            }
+        } else if self.used.get() + 1 < self.num_pages {
+            let page = if self.used.get() == 0 {
+                self.tail.get()
+            } else {
+                self.next_page(head)
+            };
+            self.erase(page, State::EraseFresh(page))
+        } else if self.compactions.get() >= self.num_pages {
+            ReturnCode::ENOMEM
+        } else {
+            self.compactions.set(self.compactions.get() + 1);
+            self.read(self.tail.get(), State::CompactRead)
+        }
+    }
+
+    /// Add the record of the current operation to `buffer` and write it to
+    /// `page`. A `fresh` page is started from scratch.
+    fn write_record(&self, page: usize, offset: usize, fresh: bool) -> ReturnCode {
+        let (appid, operation) = match self.current.map(|current| *current) {
+            Some(current) => current,
+            None => return ReturnCode::FAIL,
+        };
+        let end = self.buffer.map_or(None, |buffer| {
+            let data = buffer.as_mut();
+            if fresh {
+                for byte in data.iter_mut() {
+                    *byte = 0xff;
+                }
+                write_header(data, self.next_sequence.get());
+            }
+            match operation {
+                Operation::Set(key, len) => self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.buffer.as_ref().and_then(|slice| {
+                            if slice.len() < len {
+                                None
+                            } else {
+                                append_record(data, offset, key, FLAG_SET, &slice.as_ref()[..len])
+                            }
+                        })
+                    })
+                    .unwrap_or(None),
+                Operation::Delete(key) => append_record(data, offset, key, FLAG_DELETE, &[]),
+                Operation::Get(_) => None,
+            }
+        });
+        match end {
+            Some(end) => self.write(
+                page,
+                State::Write {
+                    page: page,
+                    offset: offset,
+                    end: end,
+                    fresh: fresh,
+                },
+            ),
+            None => {
+                self.cached.clear();
+                ReturnCode::ERESERVE
+            }
+        }
+    }
+
+    /// Copy the records of the oldest page that are still current to the
+    /// start of `data`, returning the end of them.
+    fn compact(&self, data: &mut [u8]) -> usize {
+        let tail = self.tail.get();
+        let mut end = HEADER_LEN;
+        let mut offset = HEADER_LEN;
+        while let Some(record) = parse_record(data, offset) {
+            let len = record_len(record.len);
+            let current = record.flag == FLAG_SET
+                && self
+                    .lookup(record.key)
+                    .map_or(false, |entry| entry.page == tail && entry.offset == offset);
+            if current {
+                data.copy_within(offset..offset + len, end);
+                end += len;
+            }
+            offset += len;
+        }
+        for byte in data[end..].iter_mut() {
+            *byte = 0xff;
+        }
+        write_header(data, self.next_sequence.get());
+        end
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for KeyValue<'_, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let failed = error != hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Scanning(page) => {
+                let sequence = page_sequence(buffer.as_mut());
+                self.buffer.replace(buffer);
+                if failed {
+                    self.mount_failed();
+                    return;
+                }
+                if let Some(sequence) = sequence {
+                    if self
+                        .oldest
+                        .get()
+                        .map_or(true, |(oldest, _)| sequence < oldest)
+                    {
+                        self.oldest.set(Some((sequence, page)));
+                    }
+                    if self
+                        .newest
+                        .get()
+                        .map_or(true, |(newest, _)| sequence > newest)
+                    {
+                        self.newest.set(Some((sequence, page)));
+                    }
+                }
+                let result = if page + 1 < self.num_pages {
+                    self.read(page + 1, State::Scanning(page + 1))
+                } else {
+                    match (self.oldest.get(), self.newest.get()) {
+                        (Some((_, tail)), Some((sequence, head))) => {
+                            self.tail.set(tail);
+                            self.head.set(head);
+                            self.used
+                                .set((head + self.num_pages - tail) % self.num_pages + 1);
+                            self.next_sequence.set(sequence.wrapping_add(1));
+                            self.read(tail, State::Replaying(0))
+                        }
+                        _ => {
+                            self.tail.set(0);
+                            self.head.set(0);
+                            self.head_end.set(HEADER_LEN);
+                            self.used.set(0);
+                            self.mounted();
+                            ReturnCode::SUCCESS
+                        }
+                    }
+                };
+                if result != ReturnCode::SUCCESS {
+                    self.mount_failed();
+                }
+            }
+            State::Replaying(count) => {
+                if failed {
+                    self.buffer.replace(buffer);
+                    self.mount_failed();
+                    return;
+                }
+                let page = (self.tail.get() + count) % self.num_pages;
+                let end = self.replay(page, buffer.as_mut());
+                self.buffer.replace(buffer);
+                if page == self.head.get() {
+                    self.head_end.set(end);
+                    self.cached.set(page);
+                }
+                let result = if count + 1 < self.used.get() {
+                    self.read(self.next_page(page), State::Replaying(count + 1))
+                } else if self.used.get() == self.num_pages {
+                    // No page is free, so the oldest page was being compacted
+                    // into the newest one when the board lost power.
+                    self.erase(self.tail.get(), State::EraseStale)
+                } else {
+                    self.mounted();
+                    ReturnCode::SUCCESS
+                };
+                if result != ReturnCode::SUCCESS {
+                    self.mount_failed();
+                }
+            }
+            State::Get => {
+                let operation = self.current.map(|(_, operation)| *operation);
+                let entry = match operation {
+                    Some(Operation::Get(key)) => self.lookup(key),
+                    _ => None,
+                };
+                let result = match entry {
+                    Some(entry) if !failed => {
+                        let start = entry.offset + RECORD_HEADER_LEN;
+                        let value = &buffer.as_mut()[start..start + entry.len];
+                        self.current.map(|(appid, _)| {
+                            let _ = self.apps.enter(*appid, |app, _| {
+                                app.buffer.as_mut().map(|slice| {
+                                    let len = cmp::min(slice.len(), value.len());
+                                    slice.as_mut()[..len].copy_from_slice(&value[..len]);
+                                });
+                            });
+                        });
+                        self.cached.set(entry.page);
+                        (ReturnCode::SUCCESS, entry.len)
+                    }
+                    _ => (ReturnCode::FAIL, 0),
+                };
+                self.buffer.replace(buffer);
+                self.finish(result.0, result.1);
+            }
+            State::ReadHead => {
+                self.buffer.replace(buffer);
+                let result = if failed {
+                    ReturnCode::FAIL
+                } else {
+                    self.cached.set(self.head.get());
+                    self.write_record(self.head.get(), self.head_end.get(), false)
+                };
+                if result != ReturnCode::SUCCESS {
+                    self.finish(result, 0);
+                }
+            }
+            State::CompactRead => {
+                let end = if failed {
+                    None
+                } else {
+                    Some(self.compact(buffer.as_mut()))
+                };
+                self.buffer.replace(buffer);
+                let result = end.map_or(ReturnCode::FAIL, |end| {
+                    let spare = self.next_page(self.head.get());
+                    self.erase(spare, State::CompactEraseSpare(end))
+                });
+                if result != ReturnCode::SUCCESS {
+                    self.finish(result, 0);
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: hil::flash::Error) {
+        let failed = error != hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Write {
+                page,
+                offset,
+                end,
+                fresh,
+            } => {
+                self.buffer.replace(buffer);
+                if failed {
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                if fresh {
+                    if self.used.get() == 0 {
+                        self.tail.set(page);
+                    }
+                    self.head.set(page);
+                    self.used.set(self.used.get() + 1);
+                    self.next_sequence
+                        .set(self.next_sequence.get().wrapping_add(1));
+                }
+                self.head_end.set(end);
+                self.cached.set(page);
+                match self.current.map(|(_, operation)| *operation) {
+                    Some(Operation::Set(key, len)) => self.insert(Entry {
+                        key: key,
+                        page: page,
+                        offset: offset,
+                        len: len,
+                    }),
+                    Some(Operation::Delete(key)) => self.remove(key),
+                    _ => {}
+                }
+                self.finish(ReturnCode::SUCCESS, 0);
+            }
+            State::CompactWrite(end) => {
+                let spare = self.next_page(self.head.get());
+                if !failed {
+                    // Every record of the compacted page is current.
+                    self.replay(spare, buffer.as_mut());
+                }
+                self.buffer.replace(buffer);
+                if failed {
+                    self.finish(ReturnCode::FAIL, 0);
+                    return;
+                }
+                self.head.set(spare);
+                self.head_end.set(end);
+                self.used.set(self.used.get() + 1);
+                self.next_sequence
+                    .set(self.next_sequence.get().wrapping_add(1));
+                self.cached.set(spare);
+                let result = self.erase(self.tail.get(), State::CompactEraseTail);
+                if result != ReturnCode::SUCCESS {
+                    self.finish(result, 0);
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        let failed = error != hil::flash::Error::CommandComplete;
+        let result = match self.state.get() {
+            State::EraseStale => {
+                if failed {
+                    self.mount_failed();
+                } else {
+                    self.tail.set(self.next_page(self.tail.get()));
+                    self.used.set(self.used.get() - 1);
+                    self.mounted();
+                }
+                return;
+            }
+            State::EraseFresh(page) => {
+                if failed {
+                    ReturnCode::FAIL
+                } else {
+                    self.write_record(page, HEADER_LEN, true)
+                }
+            }
+            State::CompactEraseSpare(end) => {
+                if failed {
+                    ReturnCode::FAIL
+                } else {
+                    let spare = self.next_page(self.head.get());
+                    self.write(spare, State::CompactWrite(end))
+                }
+            }
+            State::CompactEraseTail => {
+                if failed {
+                    ReturnCode::FAIL
+                } else {
+                    self.tail.set(self.next_page(self.tail.get()));
+                    self.used.set(self.used.get() - 1);
+                    self.append()
+                }
+            }
+            _ => return,
+        };
+        if result != ReturnCode::SUCCESS {
+            self.finish(result, 0);
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> Driver for KeyValue<'_, F> {
+    /// Setup the value buffer.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer values are read from and written to.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Operation complete callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Get, set and delete values.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the value of key `data1`.
+    /// - `2`: Set the value of key `data1` to the first `data2` bytes of the
+    ///   buffer.
+    /// - `3`: Delete the value of key `data1`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if data1 >= ERASED_KEY as usize {
+            return ReturnCode::EINVAL;
+        }
+        let key = data1 as u16;
+        match command_num {
+            1 => self.enqueue(appid, Operation::Get(key)),
+            2 => {
+                if data2 > MAX_VALUE_LEN {
+                    ReturnCode::ESIZE
+                } else {
+                    self.enqueue(appid, Operation::Set(key, data2))
+                }
+            }
+            3 => self.enqueue(appid, Operation::Delete(key)),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let mut page = [0xffu8; 64];
+        write_header(&mut page, 7);
+        assert_eq!(page_sequence(&page), Some(7));
+
+        let end = append_record(&mut page, HEADER_LEN, 3, FLAG_SET, &[1, 2, 3]).unwrap();
+        assert_eq!(end, HEADER_LEN + 8);
+        let end = append_record(&mut page, end, 3, FLAG_DELETE, &[]).unwrap();
+
+        let mut records = Records::new(&page);
+        assert_eq!(
+            records.next(),
+            Some(Record {
+                key: 3,
+                flag: FLAG_SET,
+                offset: HEADER_LEN,
+                len: 3
+            })
+        );
+        assert_eq!(records.next().map(|record| record.flag), Some(FLAG_DELETE));
+        assert_eq!(records.next(), None);
+        assert_eq!(records.offset, end);
+    }
+
+    #[test]
+    fn record_must_fit() {
+        let mut page = [0xffu8; 16];
+        assert_eq!(page_sequence(&page), None);
+        assert_eq!(
+            append_record(&mut page, HEADER_LEN, 1, FLAG_SET, &[0; 5]),
+            None
+        );
+        assert_eq!(
+            append_record(&mut page, HEADER_LEN, 1, FLAG_SET, &[0; 4]),
+            Some(16)
+        );
+    }
+}
@@ -2,6 +2,10 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+// This is used to run the tests on a host
+#[cfg(test)]
+extern crate std;
+
 pub mod test;
 
 #[macro_use]
@@ -10,11 +14,13 @@ pub mod net;
 pub mod adc;
 pub mod adc_microphone;
 pub mod alarm;
+pub mod alarm_stats;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod bit_bang;
 pub mod ble_advertising_driver;
 pub mod bus;
 pub mod button;
@@ -24,19 +30,24 @@ pub mod crc;
 pub mod ctap;
 pub mod dac;
 pub mod debug_process_restart;
+pub mod debug_rate_limit;
+pub mod device_id;
 pub mod driver;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
+pub mod gpio_ports;
 pub mod hd44780;
+pub mod hibernate_storage;
 pub mod hmac;
 pub mod humidity;
 pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod key_value;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
@@ -49,16 +60,27 @@ pub mod lsm303xx;
 pub mod ltc294x;
 pub mod max17205;
 pub mod mcp230xx;
+pub mod memory_barrier;
 pub mod mlx90614;
+pub mod mpu_limits;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
+pub mod oneshot_timer;
 pub mod panic_button;
 pub mod pca9544a;
+pub mod pin_mux;
+pub mod power_budget;
+pub mod pre_sleep;
 pub mod process_console;
+pub mod process_stats;
+pub mod process_wait_time;
 pub mod proximity;
+pub mod pulse_train;
+pub mod ram_uart;
+pub mod restart_throttle;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
@@ -67,15 +89,21 @@ pub mod sdcard;
 pub mod segger_rtt;
 pub mod sht3x;
 pub mod si7021;
+pub mod sleep_inhibit;
+pub mod sleep_veto;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod st77xx;
+pub mod syscall_benchmark;
+pub mod system_reset;
 pub mod temperature;
 pub mod temperature_stm;
 pub mod text_screen;
+pub mod timeslice_yield;
 pub mod touch;
 pub mod tsl2561;
+pub mod uptime;
 pub mod usb;
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
@@ -89,3 +117,6 @@ pub mod virtual_rng;
 pub mod virtual_spi;
 pub mod virtual_timer;
 pub mod virtual_uart;
+pub mod wake_reason;
+pub mod wakeup_timer;
+pub mod watchdog;
@@ -0,0 +1,87 @@
+//! Lets apps order their writes to shared buffers with memory barriers.
+//!
+//! An app that fills a buffer and then signals a consumer, such as a DMA
+//! engine or a peripheral reading the buffer, may need its writes to have
+//! reached memory before the signal is seen. The processor and bus can
+//! reorder or buffer accesses, so this driver issues the architecture's
+//! barrier instructions on the app's behalf.
+//!
+//! Ordering guarantees
+//! -------------------
+//!
+//! - A data memory barrier (DMB) ensures that all explicit memory accesses
+//!   the app made before the command are observed by other bus masters
+//!   before any explicit memory access it makes after the command. It does
+//!   not wait for the earlier accesses to complete.
+//! - A data synchronization barrier (DSB) additionally waits until all
+//!   explicit memory accesses made before the command have completed, so
+//!   they are visible to every bus master by the time the command returns.
+//!
+//! Neither barrier flushes or invalidates caches. Chips without a data cache,
+//! such as the Apollo3, need nothing more, but on chips with one the buffer
+//! must also be in memory the consumer sees coherently. A DMB is enough when
+//! the consumer is signalled with a later memory access, such as a write to
+//! a shared flag or a peripheral register; use a DSB when the writes must be
+//! complete before the app does anything else, such as another system call.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let memory_barrier = static_init!(
+//!     capsules::memory_barrier::MemoryBarrierDriver<'static>,
+//!     capsules::memory_barrier::MemoryBarrierDriver::new(&cortexm4::support::MemoryBarriers)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Issue a data memory barrier (DMB).
+//! - `2`: Issue a data synchronization barrier (DSB).
+
+use kernel::hil::memory_barrier::MemoryBarrier;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::MemoryBarrier as usize;
+
+pub struct MemoryBarrierDriver<'a> {
+    barriers: &'a dyn MemoryBarrier,
+}
+
+impl<'a> MemoryBarrierDriver<'a> {
+    pub fn new(barriers: &'a dyn MemoryBarrier) -> MemoryBarrierDriver<'a> {
+        MemoryBarrierDriver { barriers: barriers }
+    }
+}
+
+impl<'a> Driver for MemoryBarrierDriver<'a> {
+    /// Issue memory barriers.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Data memory barrier.
+    /// - `2`: Data synchronization barrier.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                self.barriers.data_memory_barrier();
+                ReturnCode::SUCCESS
+            }
+            2 => {
+                self.barriers.data_synchronization_barrier();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
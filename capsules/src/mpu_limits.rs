@@ -0,0 +1,88 @@
+//! Provides userspace with the fixed limits of the chip's MPU.
+//!
+//! Every process needs a few MPU regions, and the sizes and alignment of
+//! those regions are constrained by the hardware. This capsule lets apps and
+//! tools read those constraints, for example so that a deployment tool can
+//! check that an app's memory layout fits the hardware before loading it. The
+//! values describe the hardware, not the regions that are currently in use.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mpu_limits = static_init!(
+//!     capsules::mpu_limits::MpuLimitsDriver,
+//!     capsules::mpu_limits::MpuLimitsDriver::new(chip.mpu().limits(&mpu_info_cap))
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the total number of MPU regions.
+//! - `2`: Return the smallest region size in bytes.
+//! - `3`: Return the alignment of region start addresses in bytes.
+//! - `4`: Return 1 if region sizes have to be powers of two, 0 otherwise.
+//! - `5`: Return the number of subregions per region, 0 if there are none.
+//!
+//! Commands `1` to `5` return `ENOSUPPORT` if the MPU does not report its
+//! limits.
+
+use kernel::mpu::MpuLimits;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::MpuLimits as usize;
+
+pub struct MpuLimitsDriver {
+    limits: Option<MpuLimits>,
+}
+
+impl MpuLimitsDriver {
+    pub fn new(limits: Option<MpuLimits>) -> MpuLimitsDriver {
+        MpuLimitsDriver { limits: limits }
+    }
+}
+
+impl Driver for MpuLimitsDriver {
+    /// Read the MPU limits.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Total number of regions.
+    /// - `2`: Minimum region size in bytes.
+    /// - `3`: Minimum alignment in bytes.
+    /// - `4`: Whether sizes must be powers of two.
+    /// - `5`: Subregions per region.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        self.limits
+            .map_or(ReturnCode::ENOSUPPORT, |limits| match command_num {
+                1 => ReturnCode::SuccessWithValue {
+                    value: limits.total_regions,
+                },
+                2 => ReturnCode::SuccessWithValue {
+                    value: limits.min_region_size,
+                },
+                3 => ReturnCode::SuccessWithValue {
+                    value: limits.min_alignment,
+                },
+                4 => ReturnCode::SuccessWithValue {
+                    value: limits.power_of_two_sizes as usize,
+                },
+                5 => ReturnCode::SuccessWithValue {
+                    value: limits.subregions,
+                },
+                _ => ReturnCode::ENOSUPPORT,
+            })
+    }
+}
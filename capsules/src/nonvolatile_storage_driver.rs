@@ -53,7 +53,31 @@
 //!         &mut capsules::nonvolatile_storage_driver::BUFFER));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
+//!
+//! Write quotas
+//! ------------
+//!
+//! To protect the endurance of flash from an app that writes constantly, the
+//! board can limit how many bytes each app may write per window of time. The
+//! quota counts the bytes of every write the driver accepts from the app,
+//! and writes that would exceed it fail with `EBUSY` until the next window
+//! starts. Apps can read their remaining quota to pace themselves. Writes from
+//! the kernel are not limited.
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let quota_timer = static_init!(
+//!     capsules::oneshot_timer::AlarmOneshotTimer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::oneshot_timer::AlarmOneshotTimer::new(quota_alarm)
+//! );
+//! quota_alarm.set_alarm_client(quota_timer);
+//! quota_timer.set_client(nonvolatile_storage);
+//! // At most 4 KiB per app per hour.
+//! nonvolatile_storage.set_write_quota(quota_timer, 4096, 3600);
+//! ```
 
+use crate::oneshot_timer::{OneshotTimer, OneshotTimerClient};
 use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -66,6 +90,10 @@ pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Longest the write quota timer waits at once. Longer windows are counted
+/// in steps of this length.
+const QUOTA_TIMER_STEP_S: u32 = 60;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -89,6 +117,8 @@ pub struct App {
     length: usize,
     buffer_read: Option<AppSlice<Shared, u8>>,
     buffer_write: Option<AppSlice<Shared, u8>>,
+    // Bytes written in the current write quota window.
+    quota_used: usize,
 }
 
 impl Default for App {
@@ -102,6 +132,7 @@ impl Default for App {
             length: 0,
             buffer_read: None,
             buffer_write: None,
+            quota_used: 0,
         }
     }
 }
@@ -140,6 +171,15 @@ pub struct NonvolatileStorage<'a> {
     kernel_readwrite_length: Cell<usize>,
     // Where to read/write from the kernel request.
     kernel_readwrite_address: Cell<usize>,
+
+    // Optional timer that ends write quota windows.
+    quota_timer: OptionalCell<&'a dyn OneshotTimer<'a>>,
+    // How many bytes each app may write per window, if limited.
+    write_quota: Cell<Option<usize>>,
+    // Length of a write quota window in seconds.
+    quota_window_s: Cell<u32>,
+    // Seconds of the current window not yet scheduled on the timer.
+    quota_window_left_s: Cell<u32>,
 }
 
 impl<'a> NonvolatileStorage<'a> {
@@ -167,7 +207,42 @@ impl<'a> NonvolatileStorage<'a> {
             kernel_buffer: TakeCell::empty(),
             kernel_readwrite_length: Cell::new(0),
             kernel_readwrite_address: Cell::new(0),
+            quota_timer: OptionalCell::empty(),
+            write_quota: Cell::new(None),
+            quota_window_s: Cell::new(0),
+            quota_window_left_s: Cell::new(0),
+        }
+    }
+
+    /// Limit each app to writing `bytes_per_window` bytes every `window_s`
+    /// seconds. `timer` ends the windows and must have this driver as its
+    /// client. The first window starts now.
+    pub fn set_write_quota(
+        &self,
+        timer: &'a dyn OneshotTimer<'a>,
+        bytes_per_window: usize,
+        window_s: u32,
+    ) {
+        self.quota_timer.set(timer);
+        self.write_quota.set(Some(bytes_per_window));
+        self.quota_window_s.set(cmp::max(window_s, 1));
+        self.start_quota_window();
+    }
+
+    fn start_quota_window(&self) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| app.quota_used = 0);
         }
+        self.quota_window_left_s.set(self.quota_window_s.get());
+        self.schedule_quota_timer();
+    }
+
+    fn schedule_quota_timer(&self) {
+        let left_s = self.quota_window_left_s.get();
+        let step_s = cmp::min(left_s, QUOTA_TIMER_STEP_S);
+        self.quota_window_left_s.set(left_s - step_s);
+        self.quota_timer
+            .map(|timer| timer.schedule(step_s * 1_000_000));
     }
 
     // Check so see if we are doing something. If not, go ahead and do this
@@ -232,9 +307,22 @@ impl<'a> NonvolatileStorage<'a> {
                             // put it.
                             let active_len = cmp::min(length, allow_buf_len);
 
+                            // Check that the write fits in the app's quota.
+                            let quota_len = match command {
+                                NonvolatileCommand::UserspaceWrite => active_len,
+                                _ => 0,
+                            };
+                            if self
+                                .write_quota
+                                .get()
+                                .map_or(false, |quota| app.quota_used + quota_len > quota)
+                            {
+                                return ReturnCode::EBUSY;
+                            }
+
                             // First need to determine if we can execute this or must
                             // queue it.
-                            if self.current_user.is_none() {
+                            let result = if self.current_user.is_none() {
                                 // No app is currently using the underlying storage.
                                 // Mark this app as active, and then execute the command.
                                 self.current_user
@@ -274,7 +362,11 @@ impl<'a> NonvolatileStorage<'a> {
                                     app.length = active_len;
                                     ReturnCode::SUCCESS
                                 }
+                            };
+                            if result == ReturnCode::SUCCESS {
+                                app.quota_used += quota_len;
                             }
+                            result
                         })
                         .unwrap_or_else(|err| err.into())
                 })
@@ -449,6 +541,17 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
     }
 }
 
+/// Ends write quota windows.
+impl OneshotTimerClient for NonvolatileStorage<'_> {
+    fn fired(&self) {
+        if self.quota_window_left_s.get() == 0 {
+            self.start_quota_window();
+        } else {
+            self.schedule_quota_timer();
+        }
+    }
+}
+
 /// Provide an interface for the kernel.
 impl hil::nonvolatile_storage::NonvolatileStorage<'static> for NonvolatileStorage<'_> {
     fn set_client(&self, client: &'static dyn hil::nonvolatile_storage::NonvolatileStorageClient) {
@@ -526,6 +629,8 @@ impl Driver for NonvolatileStorage<'_> {
     /// - `1`: Return the number of bytes available to userspace.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `4`: Return how many bytes the app may still write in the current
+    ///   write quota window, or `ENOSUPPORT` if writes are not limited.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         let command_num = arg0 & 0xFF;
 
@@ -565,6 +670,18 @@ impl Driver for NonvolatileStorage<'_> {
                 )
             }
 
+            // How much of its write quota the app has left
+            4 => self
+                .write_quota
+                .get()
+                .map_or(ReturnCode::ENOSUPPORT, |quota| {
+                    self.apps
+                        .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                            value: quota.saturating_sub(app.quota_used),
+                        })
+                        .unwrap_or_else(|err| err.into())
+                }),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -0,0 +1,79 @@
+//! One-shot timer for capsules that only occasionally need to wait.
+//!
+//! Drivers such as `gpio` and `i2c_master` are generic only over their
+//! hardware, and only need a timer for optional features. Rather than adding
+//! an alarm type parameter to them, and to every board that uses them, they
+//! take an optional `&dyn OneshotTimer`. `AlarmOneshotTimer` implements it on
+//! top of any alarm, typically a virtual alarm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let oneshot_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let oneshot_timer = static_init!(
+//!     capsules::oneshot_timer::AlarmOneshotTimer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::oneshot_timer::AlarmOneshotTimer::new(oneshot_alarm)
+//! );
+//! oneshot_alarm.set_alarm_client(oneshot_timer);
+//! oneshot_timer.set_client(gpio);
+//! gpio.set_sample_timer(oneshot_timer);
+//! ```
+
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm};
+
+pub trait OneshotTimer<'a> {
+    fn set_client(&self, client: &'a dyn OneshotTimerClient);
+
+    /// Call the client's `fired()` once, `us` microseconds from now. Replaces
+    /// any earlier request that has not fired yet.
+    fn schedule(&self, us: u32);
+
+    fn cancel(&self);
+}
+
+pub trait OneshotTimerClient {
+    fn fired(&self);
+}
+
+pub struct AlarmOneshotTimer<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn OneshotTimerClient>,
+}
+
+impl<'a, A: Alarm<'a>> AlarmOneshotTimer<'a, A> {
+    pub fn new(alarm: &'a A) -> AlarmOneshotTimer<'a, A> {
+        AlarmOneshotTimer {
+            alarm: alarm,
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> OneshotTimer<'a> for AlarmOneshotTimer<'a, A> {
+    fn set_client(&self, client: &'a dyn OneshotTimerClient) {
+        self.client.set(client);
+    }
+
+    fn schedule(&self, us: u32) {
+        let dt = cmp::max(A::ticks_from_us(us), self.alarm.minimum_dt());
+        self.alarm.set_alarm(self.alarm.now(), dt);
+    }
+
+    fn cancel(&self) {
+        self.alarm.disarm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmOneshotTimer<'a, A> {
+    fn alarm(&self) {
+        self.client.map(|client| client.fired());
+    }
+}
@@ -0,0 +1,106 @@
+//! Lets processes change which peripheral a pad is connected to.
+//!
+//! On chips whose pads can each serve several functions, boards with a
+//! software-configurable pinout can use this to let an app switch a pad
+//! between, for example, GPIO and a UART. The board passes the pads apps may
+//! change; all others are out of reach. Function numbers are chip specific,
+//! on the Apollo3 they are the pad's `FNCSEL` value.
+//!
+//! The chip refuses to reroute pads the board has given to a peripheral, and
+//! refuses to route a signal to a pad while it is routed to another one. It
+//! does not know about capsules, though: a pad that is also exposed by the
+//! GPIO driver will stop working as a GPIO once rerouted, and GPIO commands
+//! on it may switch it back. Restoring a pad's function afterwards is up to
+//! the app that changed it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let pin_mux = static_init!(
+//!     capsules::pin_mux::PinMux<'static, apollo3::gpio::Port<'static>, Capability>,
+//!     capsules::pin_mux::PinMux::new(&peripherals.gpio_port, &[22, 23], pin_mux_cap)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Number of pads that can be changed.
+//! - `1`: Connect pad `data1`, an index into the board's list of pads, to
+//!   function `data2`.
+//! - `2`: Return the function pad `data1` is connected to.
+
+use kernel::capabilities::PinMuxCapability;
+use kernel::hil::gpio::PadMux;
+use kernel::{AppId, CommandArg, CommandSpec, CommandTable, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PinMux as usize;
+
+/// Commands and the arguments they accept, checked before dispatch.
+const COMMANDS: CommandTable = CommandTable::new(&[
+    CommandSpec::new(0, CommandArg::Any, CommandArg::Any),
+    CommandSpec::new(1, CommandArg::Index, CommandArg::Any),
+    CommandSpec::new(2, CommandArg::Index, CommandArg::Any),
+]);
+
+pub struct PinMux<'a, M: PadMux, C: PinMuxCapability> {
+    mux: &'a M,
+    pads: &'a [usize],
+    capability: C,
+}
+
+impl<'a, M: PadMux, C: PinMuxCapability> PinMux<'a, M, C> {
+    pub fn new(mux: &'a M, pads: &'a [usize], capability: C) -> PinMux<'a, M, C> {
+        PinMux {
+            mux: mux,
+            pads: pads,
+            capability: capability,
+        }
+    }
+}
+
+impl<'a, M: PadMux, C: PinMuxCapability> Driver for PinMux<'a, M, C> {
+    /// Change and query pad functions.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Number of pads that can be changed.
+    /// - `1`: Connect pad `data1` to function `data2`. Returns `EINVAL` if
+    ///        the function is not available on the pad, and `EBUSY` if the
+    ///        pad is in use by a peripheral or the function is already
+    ///        connected to another pad.
+    /// - `2`: The function pad `data1` is connected to.
+    ///
+    /// Unknown commands and pads return `EINVAL`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
+        let checked = COMMANDS.check(command_num, data1, data2, self.pads.len());
+        if checked != ReturnCode::SUCCESS {
+            return checked;
+        }
+        match command_num {
+            0 => ReturnCode::SuccessWithValue {
+                value: self.pads.len(),
+            },
+
+            1 => self
+                .mux
+                .set_pad_function(self.pads[data1], data2, &self.capability),
+
+            2 => self
+                .mux
+                .pad_function(self.pads[data1])
+                .map_or(ReturnCode::EINVAL, |function| {
+                    ReturnCode::SuccessWithValue { value: function }
+                }),
+
+            _ => ReturnCode::EINVAL,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Provides userspace with the estimated power draw of the board's
+//! peripherals.
+//!
+//! The kernel keeps a coarse estimate of the power drawn by the peripherals
+//! that are enabled, and can refuse to enable more once a budget is reached
+//! (see `kernel::power_budget`). This capsule lets apps read the estimate and
+//! the budget, for example to log them alongside the charge of a solar
+//! battery. The budget itself can only be set by the board.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let power_budget_driver = static_init!(
+//!     capsules::power_budget::PowerBudgetDriver,
+//!     capsules::power_budget::PowerBudgetDriver::new(power_budget)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the estimated draw of the enabled peripherals, in
+//!   microwatts.
+//! - `2`: Return the budget in microwatts, or `EINVAL` if there is none.
+//! - `3`: Return the highest estimated draw since boot, in microwatts.
+//! - `4`: Return how many times a peripheral was not enabled because of the
+//!   budget.
+
+use kernel::power_budget::PowerBudget;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PowerBudget as usize;
+
+pub struct PowerBudgetDriver {
+    budget: &'static PowerBudget,
+}
+
+impl PowerBudgetDriver {
+    pub fn new(budget: &'static PowerBudget) -> PowerBudgetDriver {
+        PowerBudgetDriver { budget: budget }
+    }
+}
+
+impl Driver for PowerBudgetDriver {
+    /// Read the power estimate and budget.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Estimated draw in microwatts.
+    /// - `2`: Budget in microwatts.
+    /// - `3`: Peak estimated draw in microwatts.
+    /// - `4`: Number of refused peripherals.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue {
+                value: self.budget.draw_uw() as usize,
+            },
+            2 => self
+                .budget
+                .budget_uw()
+                .map_or(ReturnCode::EINVAL, |budget| ReturnCode::SuccessWithValue {
+                    value: budget as usize,
+                }),
+            3 => ReturnCode::SuccessWithValue {
+                value: self.budget.peak_draw_uw() as usize,
+            },
+            4 => ReturnCode::SuccessWithValue {
+                value: self.budget.refused_count(),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
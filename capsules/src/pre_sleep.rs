@@ -0,0 +1,228 @@
+//! Warns processes before the chip enters deep sleep.
+//!
+//! Deep sleep may shut down peripherals an app is using. Apps that subscribe
+//! to this driver get a callback right before the kernel would put the chip
+//! into deep sleep, so they can flush state or release resources first. An
+//! app that cannot let the chip sleep deeply can take a veto through the
+//! sleep veto driver (see `sleep_veto`) from that callback.
+//!
+//! When the kernel is about to sleep and deep sleep is not vetoed, every
+//! subscribed app is notified and sleep is put off. The chip then only sleeps
+//! normally until all notified apps have acknowledged with command `1`, or
+//! until the window the board chose for them has passed, whichever comes
+//! first. The next sleep after that may be deep. The window bounds how long
+//! apps can keep the chip out of deep sleep, even one that never
+//! acknowledges.
+//!
+//! This capsule wraps the board's other deep sleep veto, and must be given to
+//! the chip in its place.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let pre_sleep = static_init!(
+//!     capsules::pre_sleep::PreSleep<'static>,
+//!     capsules::pre_sleep::PreSleep::new(
+//!         sleep_veto,
+//!         pre_sleep_timer,
+//!         10_000,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! pre_sleep_timer.set_client(pre_sleep);
+//! chip.set_deep_sleep_veto(pre_sleep);
+//! board_kernel.set_sleep_notifier(pre_sleep, &main_loop_cap);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Subscribe
+//!
+//! - `0`: Callback before deep sleep. It has no arguments.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Acknowledge a notification, once the app is ready for deep sleep.
+//!   Returns `EALREADY` if the app has no notification to acknowledge.
+//! - `2`: Return the window apps have to react, in microseconds.
+
+use crate::oneshot_timer::{OneshotTimer, OneshotTimerClient};
+use core::cell::Cell;
+use kernel::{AppId, Callback, DeepSleepVeto, Driver, Grant, ReturnCode, SleepNotifier};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PreSleep as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    /// Notified and not yet acknowledged.
+    notified: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// Apps are notified before the next deep sleep.
+    Awake,
+    /// Apps were notified and the window is open.
+    Warning,
+    /// The window has closed, so the chip may sleep deeply.
+    Warned,
+}
+
+pub struct PreSleep<'a> {
+    veto: &'a dyn DeepSleepVeto,
+    timer: &'a dyn OneshotTimer<'a>,
+    window_us: u32,
+    phase: Cell<Phase>,
+    apps: Grant<App>,
+}
+
+impl<'a> PreSleep<'a> {
+    pub fn new(
+        veto: &'a dyn DeepSleepVeto,
+        timer: &'a dyn OneshotTimer<'a>,
+        window_us: u32,
+        grant: Grant<App>,
+    ) -> PreSleep<'a> {
+        PreSleep {
+            veto: veto,
+            timer: timer,
+            window_us: window_us,
+            phase: Cell::new(Phase::Awake),
+            apps: grant,
+        }
+    }
+
+    /// Notify every subscribed app, returning how many were.
+    fn notify_apps(&self) -> usize {
+        let mut notified = 0;
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                app.callback.map(|mut cb| {
+                    app.notified = true;
+                    notified += 1;
+                    cb.schedule(0, 0, 0);
+                });
+            });
+        }
+        notified
+    }
+
+    fn close_window(&self) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| app.notified = false);
+        }
+        self.phase.set(Phase::Warned);
+    }
+}
+
+impl DeepSleepVeto for PreSleep<'_> {
+    fn deep_sleep_vetoed(&self) -> bool {
+        self.phase.get() == Phase::Warning || self.veto.deep_sleep_vetoed()
+    }
+}
+
+impl SleepNotifier for PreSleep<'_> {
+    fn ready_to_sleep(&self) -> bool {
+        match self.phase.get() {
+            Phase::Awake => {
+                if self.veto.deep_sleep_vetoed() || self.notify_apps() == 0 {
+                    return true;
+                }
+                self.phase.set(Phase::Warning);
+                self.timer.schedule(self.window_us);
+                false
+            }
+            // Deep sleep is vetoed until the window closes.
+            Phase::Warning => true,
+            // This is the sleep the apps were warned of, warn them again
+            // before the next one.
+            Phase::Warned => {
+                self.phase.set(Phase::Awake);
+                true
+            }
+        }
+    }
+}
+
+impl OneshotTimerClient for PreSleep<'_> {
+    fn fired(&self) {
+        if self.phase.get() == Phase::Warning {
+            self.close_window();
+        }
+    }
+}
+
+impl Driver for PreSleep<'_> {
+    /// Subscribe to notifications before deep sleep.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Callback before deep sleep.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Acknowledge notifications.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Acknowledge a notification.
+    /// - `2`: Window apps have to react, in microseconds.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => {
+                let result = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.notified {
+                            app.notified = false;
+                            ReturnCode::SUCCESS
+                        } else {
+                            ReturnCode::EALREADY
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+                let waiting = self
+                    .apps
+                    .iter()
+                    .any(|cntr| cntr.enter(|app, _| app.notified));
+                if result == ReturnCode::SUCCESS && !waiting {
+                    self.timer.cancel();
+                    self.phase.set(Phase::Warned);
+                }
+                result
+            }
+
+            2 => ReturnCode::SuccessWithValue {
+                value: self.window_us as usize,
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -102,6 +102,8 @@
 //! Total processes: 2
 //! Active processes: 2
 //! Timeslice expirations: 0
+//! Stale callbacks: 0
+//! Peak running processes: 2
 //! ```
 //!
 //! and you can control processes with the `start` and `stop` commands:
@@ -290,6 +292,14 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                 "Timeslice expirations: {}",
                                 info.timeslice_expirations(&self.capability)
                             );
+                            debug!(
+                                "Stale callbacks: {}",
+                                info.stale_callbacks(&self.capability)
+                            );
+                            debug!(
+                                "Peak running processes: {}",
+                                info.peak_running_processes(&self.capability)
+                            );
                         } else {
                             debug!("Valid commands are: help status list stop start fault");
                         }
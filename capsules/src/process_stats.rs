@@ -0,0 +1,349 @@
+//! Gives userspace a snapshot of process and memory statistics.
+//!
+//! Monitoring apps that poll several drivers for system state see it change
+//! between calls. This capsule instead fills an allowed buffer with the
+//! totals and a brief record for each process in a single command, so all
+//! values are taken at the same point in time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//! let process_stats = static_init!(
+//!     capsules::process_stats::ProcessStats<ProcessMgmtCap>,
+//!     capsules::process_stats::ProcessStats::new(
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer the snapshot is written to.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Write a snapshot into the allowed buffer and return the number of
+//!   processes. Returns `ERESERVE` if no buffer is allowed and `ESIZE` if it
+//!   cannot hold the header.
+//! - `2`: Return how many times memory for a grant could not be allocated in
+//!   the process with identifier `data1`, because its grant region was full.
+//!   Capsules often ignore these failures and just do not work for that
+//!   process, so a nonzero count means the process needs more memory.
+//!   Returns `EINVAL` if there is no such process.
+//! - `3`: Return the most processes that have been in the `Running` state at
+//!   the same time since boot or the last reset of the peak. A peak well
+//!   below the number of process slots means the board could do with fewer.
+//! - `4`: Reset that peak to the number of processes running now.
+//! - `5`: Return why the process with identifier `data1` is blocked: `0` it
+//!   is not, `1` it waits for a callback it subscribed to, `2` it yielded
+//!   with nothing subscribed, `3` it is stopped, `4` it faulted and `5` it
+//!   has not started. An app that stays waiting for a callback may be
+//!   deadlocked, while an idle one has simply finished.
+//! - `6`: Return the number of callbacks queued for the process with
+//!   identifier `data1`.
+//! - `7`: Return the driver number the process with identifier `data1` last
+//!   subscribed a callback to. Returns `FAIL` if it has not subscribed to
+//!   any since it started.
+//!
+//! Commands `5` to `7` return `EINVAL` if there is no such process.
+//!
+//! Snapshot Layout
+//! ---------------
+//!
+//! The snapshot is a sequence of little-endian `u32` values. It starts with
+//! a header:
+//!
+//! | Offset | Value                                              |
+//! |--------|----------------------------------------------------|
+//! | 0      | Number of processes                                |
+//! | 4      | Processes in the `Running` state                   |
+//! | 8      | Processes yielded and waiting for events           |
+//! | 12     | Bytes of kernel-owned memory used by all processes |
+//! | 16     | Number of process records that follow              |
+//!
+//! followed by one 24 byte record per process, as many as fit in the buffer:
+//!
+//! | Offset | Value                                                 |
+//! |--------|-------------------------------------------------------|
+//! | 0      | Process identifier                                    |
+//! | 4      | State, see below                                      |
+//! | 8      | Bytes of RAM allocated to the process                 |
+//! | 12     | Bytes of that RAM owned by the kernel (grants)        |
+//! | 16     | System calls made                                     |
+//! | 20     | Times the process was restarted                       |
+//!
+//! States are numbered `0` Unstarted, `1` Running, `2` Yielded,
+//! `3` StoppedRunning, `4` StoppedYielded, `5` StoppedFaulted and `6` Fault.
+//!
+//! If the record count is smaller than the number of processes the buffer was
+//! too small to hold them all; allow a buffer of at least
+//! `20 + 24 * processes` bytes and try again.
+
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::TakeCell;
+use kernel::introspection::{BlockedReason, BlockedStatus, KernelInfo};
+use kernel::procs::{ProcessType, State};
+use kernel::{AppId, AppSlice, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessStats as usize;
+
+/// Size of the snapshot header in bytes.
+pub const HEADER_LEN: usize = 20;
+
+/// Size of a per-process record in bytes.
+pub const RECORD_LEN: usize = 24;
+
+#[derive(Default)]
+pub struct App {
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct ProcessStats<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+}
+
+impl<C: ProcessManagementCapability> ProcessStats<C> {
+    pub fn new(kernel: &'static Kernel, capability: C, grant: Grant<App>) -> ProcessStats<C> {
+        ProcessStats {
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+        }
+    }
+
+    /// Write the snapshot into `buffer`, returning the number of processes.
+    fn snapshot(&self, buffer: &mut [u8]) -> usize {
+        let total = Cell::new(0);
+        let running = Cell::new(0);
+        let blocked = Cell::new(0);
+        let kernel_bytes = Cell::new(0);
+        let records = Cell::new(0);
+        let max_records = (buffer.len() - HEADER_LEN) / RECORD_LEN;
+        let body = TakeCell::new(&mut buffer[HEADER_LEN..]);
+
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let grant_bytes =
+                    process.mem_end() as usize - process.kernel_memory_break() as usize;
+                total.set(total.get() + 1);
+                kernel_bytes.set(kernel_bytes.get() + grant_bytes);
+                match process.get_state() {
+                    State::Running => running.set(running.get() + 1),
+                    State::Yielded => blocked.set(blocked.get() + 1),
+                    _ => {}
+                }
+
+                if records.get() < max_records {
+                    body.map(|body| {
+                        let offset = records.get() * RECORD_LEN;
+                        write_record(&mut body[offset..offset + RECORD_LEN], process, grant_bytes);
+                    });
+                    records.set(records.get() + 1);
+                }
+            });
+
+        let header = [
+            total.get(),
+            running.get(),
+            blocked.get(),
+            kernel_bytes.get(),
+            records.get(),
+        ];
+        write_words(&mut buffer[..HEADER_LEN], &header);
+        total.get()
+    }
+
+    /// Failed grant allocations of the process with identifier `id`, if it
+    /// exists.
+    fn grant_alloc_failures(&self, id: usize) -> Option<usize> {
+        let failures = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.appid().id() == id {
+                    failures.set(Some(process.debug_grant_alloc_failure_count()));
+                }
+            });
+        failures.get()
+    }
+
+    /// Blocked status of the process with identifier `id`, if it exists.
+    fn blocked_status(&self, id: usize) -> Option<BlockedStatus> {
+        let status = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.appid().id() == id {
+                    status.set(
+                        KernelInfo::new(self.kernel)
+                            .blocked_status(process.appid(), &self.capability),
+                    );
+                }
+            });
+        status.get()
+    }
+}
+
+/// Number reported for each blocked reason.
+fn blocked_reason_code(reason: BlockedReason) -> usize {
+    match reason {
+        BlockedReason::Ready => 0,
+        BlockedReason::WaitingForCallback => 1,
+        BlockedReason::Idle => 2,
+        BlockedReason::Stopped => 3,
+        BlockedReason::Faulted => 4,
+        BlockedReason::Unstarted => 5,
+    }
+}
+
+/// Number reported for each process state.
+fn state_code(state: State) -> usize {
+    match state {
+        State::Unstarted => 0,
+        State::Running => 1,
+        State::Yielded => 2,
+        State::StoppedRunning => 3,
+        State::StoppedYielded => 4,
+        State::StoppedFaulted => 5,
+        State::Fault => 6,
+    }
+}
+
+fn write_record(record: &mut [u8], process: &dyn ProcessType, grant_bytes: usize) {
+    let words = [
+        process.appid().id(),
+        state_code(process.get_state()),
+        process.mem_end() as usize - process.mem_start() as usize,
+        grant_bytes,
+        process.debug_syscall_count(),
+        process.get_restart_count(),
+    ];
+    write_words(record, &words);
+}
+
+/// Store `words` in `buffer` as consecutive little-endian `u32`s.
+fn write_words(buffer: &mut [u8], words: &[usize]) {
+    for (chunk, word) in buffer.chunks_exact_mut(4).zip(words.iter()) {
+        chunk.copy_from_slice(&(*word as u32).to_le_bytes());
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for ProcessStats<C> {
+    /// Set the buffer the snapshot is written to.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Snapshot buffer.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Take a snapshot.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Write a snapshot into the allowed buffer. Returns the number of
+    ///   processes.
+    /// - `2`: Failed grant allocations of the process with identifier `data1`.
+    /// - `3`: Peak number of running processes.
+    /// - `4`: Reset the peak number of running processes.
+    /// - `5`: Why the process with identifier `data1` is blocked.
+    /// - `6`: Callbacks queued for the process with identifier `data1`.
+    /// - `7`: Driver the process with identifier `data1` last subscribed to.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| match app.buffer {
+                    Some(ref mut slice) if slice.len() < HEADER_LEN => ReturnCode::ESIZE,
+                    Some(ref mut slice) => ReturnCode::SuccessWithValue {
+                        value: self.snapshot(slice.as_mut()),
+                    },
+                    None => ReturnCode::ERESERVE,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 => self
+                .grant_alloc_failures(data1)
+                .map_or(ReturnCode::EINVAL, |failures| {
+                    ReturnCode::SuccessWithValue { value: failures }
+                }),
+
+            3 => ReturnCode::SuccessWithValue {
+                value: KernelInfo::new(self.kernel).peak_running_processes(&self.capability),
+            },
+
+            4 => {
+                KernelInfo::new(self.kernel).reset_peak_running_processes(&self.capability);
+                ReturnCode::SUCCESS
+            }
+
+            5 => self
+                .blocked_status(data1)
+                .map_or(ReturnCode::EINVAL, |status| ReturnCode::SuccessWithValue {
+                    value: blocked_reason_code(status.reason),
+                }),
+
+            6 => self
+                .blocked_status(data1)
+                .map_or(ReturnCode::EINVAL, |status| ReturnCode::SuccessWithValue {
+                    value: status.pending_tasks,
+                }),
+
+            7 => self
+                .blocked_status(data1)
+                .map_or(ReturnCode::EINVAL, |status| {
+                    status
+                        .last_subscribed_driver
+                        .map_or(ReturnCode::FAIL, |driver| ReturnCode::SuccessWithValue {
+                            value: driver,
+                        })
+                }),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_words;
+
+    #[test]
+    fn words_are_little_endian() {
+        let mut buffer = [0xff; 10];
+        write_words(&mut buffer, &[0x0403_0201, 5, 6]);
+        assert_eq!(buffer, [1, 2, 3, 4, 5, 0, 0, 0, 0xff, 0xff]);
+    }
+}
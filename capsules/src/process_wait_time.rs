@@ -0,0 +1,161 @@
+//! Lets userspace see how long processes wait to be scheduled.
+//!
+//! A process that is ready to run but keeps being passed over by the
+//! scheduler is starved, and its wait time grows without bound. This capsule
+//! reports the wait times the kernel tracks with a clock set with
+//! `Kernel::set_wait_time_clock()`, to tune scheduler parameters. Wait times
+//! are only updated at scheduling decisions, so they are as precise as the
+//! gaps between those.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let clock = static_init!(
+//!     capsules::process_wait_time::CounterClock<'static, apollo3::stimer::STimer<'static>>,
+//!     capsules::process_wait_time::CounterClock::new(&peripherals.stimer)
+//! );
+//! board_kernel.set_wait_time_clock(clock, &process_mgmt_cap);
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//! let process_wait_time = static_init!(
+//!     capsules::process_wait_time::ProcessWaitTime<ProcessMgmtCap>,
+//!     capsules::process_wait_time::ProcessWaitTime::new(board_kernel, ProcessMgmtCap)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! All commands return `ENOSUPPORT` if the board set no wait time clock.
+//!
+//! - `0`: Driver check.
+//! - `1`: Microseconds the process with identifier `data1` has been ready
+//!   without running, 0 if it is not waiting. Returns `EINVAL` if there is no
+//!   such process.
+//! - `2`: The longest time any process has been waiting, in microseconds.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::hil::time::{Counter, Frequency, Ticks};
+use kernel::{AppId, Driver, Kernel, ReturnCode, WaitTimeClock};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessWaitTime as usize;
+
+/// Implements the kernel's `WaitTimeClock` with a counter.
+///
+/// Counter ticks are converted to microseconds as they pass, so the clock
+/// stays consistent when the counter wraps, as long as it is read at least
+/// once per counter period.
+pub struct CounterClock<'a, C: Counter<'a>> {
+    counter: &'a C,
+    /// Counter value up to which time has been added to `now_us`.
+    last: Cell<u32>,
+    now_us: Cell<u32>,
+}
+
+impl<'a, C: Counter<'a>> CounterClock<'a, C> {
+    pub fn new(counter: &'a C) -> CounterClock<'a, C> {
+        CounterClock {
+            counter: counter,
+            last: Cell::new(counter.now().into_u32()),
+            now_us: Cell::new(0),
+        }
+    }
+}
+
+impl<'a, C: Counter<'a>> WaitTimeClock for CounterClock<'a, C> {
+    fn now_us(&self) -> u32 {
+        let frequency = <C::Frequency>::frequency() as u64;
+        let ticks = self.counter.now().into_u32().wrapping_sub(self.last.get()) as u64;
+        let us = ticks * 1_000_000 / frequency;
+        // Only consume the ticks that made up whole microseconds, so that
+        // fractions are not lost between readings.
+        let used_ticks = us * frequency / 1_000_000;
+        self.last
+            .set(self.last.get().wrapping_add(used_ticks as u32));
+        self.now_us.set(self.now_us.get().wrapping_add(us as u32));
+        self.now_us.get()
+    }
+}
+
+pub struct ProcessWaitTime<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<C: ProcessManagementCapability> ProcessWaitTime<C> {
+    pub fn new(kernel: &'static Kernel, capability: C) -> ProcessWaitTime<C> {
+        ProcessWaitTime {
+            kernel: kernel,
+            capability: capability,
+        }
+    }
+
+    /// Wait time of the process with identifier `id`, if it exists.
+    fn wait_time(&self, id: usize) -> Option<u32> {
+        let wait_time = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.appid().id() == id {
+                    wait_time.set(
+                        self.kernel
+                            .process_wait_time_us(process.appid(), &self.capability),
+                    );
+                }
+            });
+        wait_time.get()
+    }
+
+    /// The longest wait time of all processes.
+    fn longest_wait_time(&self) -> u32 {
+        let longest = Cell::new(0);
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let wait_time = self
+                    .kernel
+                    .process_wait_time_us(process.appid(), &self.capability);
+                longest.set(cmp::max(longest.get(), wait_time.unwrap_or(0)));
+            });
+        longest.get()
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for ProcessWaitTime<C> {
+    /// Read process wait times.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Wait time of the process with identifier `data1`.
+    /// - `2`: Longest wait time of any process.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        // Any valid process has a wait time once a clock is set, the calling
+        // one included.
+        if self
+            .kernel
+            .process_wait_time_us(appid, &self.capability)
+            .is_none()
+        {
+            return ReturnCode::ENOSUPPORT;
+        }
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.wait_time(data1).map_or(ReturnCode::EINVAL, |us| {
+                ReturnCode::SuccessWithValue { value: us as usize }
+            }),
+            2 => ReturnCode::SuccessWithValue {
+                value: self.longest_wait_time() as usize,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Generates precisely timed pulse trains on a GPIO pin.
+//!
+//! Stepper motors and IR remotes need a pin held high and low for exact
+//! intervals, which apps cannot do reliably as they are only scheduled from
+//! time to time. With this capsule an app allows a buffer of interval
+//! lengths and the capsule toggles the pin at the end of each one, from the
+//! alarm interrupt, then calls back the app.
+//!
+//! The first interval holds the pin at the initial level the app chooses,
+//! and every following interval at the opposite level of the one before.
+//! Before and after a sequence, and after it is aborted, the pin rests at the
+//! opposite of the initial level.
+//!
+//! Edges are timed from the start of the sequence rather than from the
+//! previous edge, so rounding to alarm ticks does not add up over a long
+//! sequence. Intervals shorter than the alarm can time, `minimum_dt()` ticks,
+//! are rejected; with the 16 kHz Apollo3 STimer that is 123 microseconds.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let pulse_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let pulse_train = static_init!(
+//!     capsules::pulse_train::PulseTrain<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::pulse_train::PulseTrain::new(
+//!         &peripherals.gpio_port[18],
+//!         pulse_alarm,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! pulse_alarm.set_alarm_client(pulse_train);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: The interval lengths, as little-endian `u32` microseconds.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Called with the number of intervals generated once a sequence
+//!   completes.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start the sequence in the allowed buffer, with the pin high for the
+//!   first interval if `data1` is nonzero and low otherwise. Returns `EBUSY`
+//!   if a sequence is running, and `EINVAL` if the buffer is empty, not a
+//!   whole number of intervals, or has an interval shorter than the minimum.
+//! - `2`: Abort the running sequence of the app. Returns the number of
+//!   intervals that completed, or `EINVAL` if none is running.
+//! - `3`: The shortest interval supported, in microseconds.
+
+use core::cell::Cell;
+use core::convert::TryInto;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::gpio;
+use kernel::hil::time::{Alarm, AlarmClient, Frequency, Ticks};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PulseTrain as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    intervals: Option<AppSlice<Shared, u8>>,
+}
+
+/// Alarm ticks from the start of a sequence to `us` microseconds into it.
+fn ticks_at(us: u64, frequency: u32) -> u32 {
+    (us * frequency as u64 / 1_000_000) as u32
+}
+
+/// The shortest interval in microseconds that spans at least `minimum_dt`
+/// ticks, and at least one.
+fn min_interval_us(minimum_dt: u32, frequency: u32) -> u32 {
+    let ticks = core::cmp::max(minimum_dt, 1) as u64;
+    ((ticks * 1_000_000 + frequency as u64 - 1) / frequency as u64) as u32
+}
+
+/// The length of interval `index` in the buffer, if it has one.
+fn interval_us(buffer: &[u8], index: usize) -> Option<u32> {
+    buffer
+        .chunks_exact(4)
+        .nth(index)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub struct PulseTrain<'a, A: Alarm<'a>> {
+    pin: &'a dyn gpio::Pin,
+    alarm: &'a A,
+    apps: Grant<App>,
+    /// The app whose sequence is running.
+    active_app: OptionalCell<AppId>,
+    initial_high: Cell<bool>,
+    /// Intervals completed in the running sequence.
+    completed: Cell<usize>,
+    /// Alarm ticks at the start of the running sequence.
+    start: Cell<u32>,
+    /// Microseconds from the start of the sequence to the next edge.
+    next_edge_us: Cell<u64>,
+}
+
+impl<'a, A: Alarm<'a>> PulseTrain<'a, A> {
+    pub fn new(pin: &'a dyn gpio::Pin, alarm: &'a A, grant: Grant<App>) -> PulseTrain<'a, A> {
+        PulseTrain {
+            pin: pin,
+            alarm: alarm,
+            apps: grant,
+            active_app: OptionalCell::empty(),
+            initial_high: Cell::new(false),
+            completed: Cell::new(0),
+            start: Cell::new(0),
+            next_edge_us: Cell::new(0),
+        }
+    }
+
+    fn min_interval_us(&self) -> u32 {
+        min_interval_us(
+            self.alarm.minimum_dt().into_u32(),
+            <A::Frequency>::frequency(),
+        )
+    }
+
+    fn set_level(&self, high: bool) {
+        if high {
+            self.pin.set();
+        } else {
+            self.pin.clear();
+        }
+    }
+
+    /// Set the alarm for the edge `edge_us` into the sequence, counting from
+    /// the edge at `from_us`.
+    fn set_edge(&self, from_us: u64, edge_us: u64) {
+        let frequency = <A::Frequency>::frequency();
+        let from = self.start.get().wrapping_add(ticks_at(from_us, frequency));
+        let dt = ticks_at(edge_us, frequency).wrapping_sub(ticks_at(from_us, frequency));
+        self.alarm
+            .set_alarm(A::Ticks::from(from), A::Ticks::from(dt));
+        self.next_edge_us.set(edge_us);
+    }
+
+    fn start(&self, appid: AppId, initial_high: bool) -> ReturnCode {
+        if self.active_app.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let min_interval_us = self.min_interval_us();
+        self.apps
+            .enter(appid, |app, _| {
+                let buffer = match app.intervals {
+                    Some(ref slice) => slice.as_ref(),
+                    None => return ReturnCode::EINVAL,
+                };
+                let valid = !buffer.is_empty()
+                    && buffer.len() % 4 == 0
+                    && buffer.chunks_exact(4).all(|bytes| {
+                        u32::from_le_bytes(bytes.try_into().unwrap()) >= min_interval_us
+                    });
+                if !valid {
+                    return ReturnCode::EINVAL;
+                }
+
+                self.active_app.set(appid);
+                self.initial_high.set(initial_high);
+                self.completed.set(0);
+                self.pin.make_output();
+                self.set_level(initial_high);
+                self.start.set(self.alarm.now().into_u32());
+                self.set_edge(0, interval_us(buffer, 0).unwrap_or(0) as u64);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// End the running sequence and leave the pin at its idle level.
+    fn stop(&self) {
+        self.set_level(!self.initial_high.get());
+        self.active_app.clear();
+    }
+
+    /// An interval of the running sequence of `appid` ended.
+    fn edge(&self, appid: AppId) {
+        let completed = self.completed.get() + 1;
+        self.completed.set(completed);
+        let edge_us = self.next_edge_us.get();
+
+        let res = self.apps.enter(appid, |app, _| {
+            let next = app
+                .intervals
+                .as_ref()
+                .and_then(|slice| interval_us(slice.as_ref(), completed));
+            match next {
+                Some(next_us) => {
+                    // Odd intervals are at the opposite of the initial level.
+                    self.set_level(self.initial_high.get() == (completed % 2 == 0));
+                    self.set_edge(edge_us, edge_us + next_us as u64);
+                }
+                None => {
+                    self.stop();
+                    app.callback.map(|mut cb| cb.schedule(completed, 0, 0));
+                }
+            }
+        });
+        if res.is_err() {
+            // The app is gone.
+            self.stop();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for PulseTrain<'a, A> {
+    fn alarm(&self) {
+        self.active_app.map(|appid| self.edge(*appid));
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for PulseTrain<'a, A> {
+    /// Setup the buffer of interval lengths.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Interval lengths in microseconds. Cannot change while a
+    ///   sequence of the app is running.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => {
+                if self.active_app.contains(&appid) {
+                    return ReturnCode::EBUSY;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.intervals = slice;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup the completion callback.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when a sequence completes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Start and abort pulse trains.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Start the sequence, high first if `data1` is nonzero.
+    /// - `2`: Abort the sequence and return the intervals completed.
+    /// - `3`: Shortest interval in microseconds.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.start(appid, data1 != 0),
+            2 => {
+                if !self.active_app.contains(&appid) {
+                    return ReturnCode::EINVAL;
+                }
+                self.alarm.disarm();
+                self.stop();
+                ReturnCode::SuccessWithValue {
+                    value: self.completed.get(),
+                }
+            }
+            3 => ReturnCode::SuccessWithValue {
+                value: self.min_interval_us() as usize,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interval_us, min_interval_us, ticks_at};
+
+    #[test]
+    fn stimer_minimum() {
+        assert_eq!(min_interval_us(2, 16384), 123);
+        assert_eq!(min_interval_us(0, 1_000_000), 1);
+    }
+
+    #[test]
+    fn edges_do_not_accumulate_rounding() {
+        // 100 us is 1.6384 ticks at 16 kHz, rounded down per edge it would
+        // lose 0.6384 ticks each time.
+        assert_eq!(ticks_at(100 * 1000, 16384), 1638);
+    }
+
+    #[test]
+    fn reads_intervals() {
+        let buffer = [0xe8, 0x03, 0, 0, 0x10, 0, 0, 0, 0xff];
+        assert_eq!(interval_us(&buffer, 0), Some(1000));
+        assert_eq!(interval_us(&buffer, 1), Some(16));
+        assert_eq!(interval_us(&buffer, 2), None);
+    }
+}
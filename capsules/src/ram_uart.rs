@@ -0,0 +1,111 @@
+//! UART transmitter that keeps what is sent in RAM.
+//!
+//! Boards can use this as the sink of the kernel debug writer when their
+//! console UART is not working, so that `debug!()` output is kept rather than
+//! lost or, worse, the board hanging on a UART that never completes a
+//! transmission. The most recent bytes sent are kept in a ring buffer, older
+//! ones are overwritten, and can be read out with a debugger.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ram_uart = static_init!(
+//!     capsules::ram_uart::RamUart<'static>,
+//!     capsules::ram_uart::RamUart::new(ring_buffer, dynamic_deferred_caller)
+//! );
+//! ram_uart.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(ram_uart).unwrap()
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::ring_buffer::RingBuffer;
+use kernel::common::Queue;
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+pub struct RamUart<'a> {
+    ring: TakeCell<'static, RingBuffer<'static, u8>>,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    /// The buffer being "transmitted" and its length, returned to the client
+    /// from a deferred call.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> RamUart<'a> {
+    pub fn new(
+        ring: &'static mut RingBuffer<'static, u8>,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> RamUart<'a> {
+        RamUart {
+            ring: TakeCell::new(ring),
+            tx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+}
+
+impl<'a> uart::Transmit<'a> for RamUart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_buffer.is_some() {
+            return (ReturnCode::EBUSY, Some(tx_buffer));
+        }
+        if tx_len > tx_buffer.len() || self.handle.is_none() {
+            return (ReturnCode::EINVAL, Some(tx_buffer));
+        }
+        self.ring.map(|ring| {
+            for &byte in &tx_buffer[..tx_len] {
+                ring.push(byte);
+            }
+        });
+        // The client expects transmissions to complete later, not from
+        // within this call.
+        self.tx_buffer.replace(tx_buffer);
+        self.tx_len.set(tx_len);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn transmit_word(&self, _word: u32) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+
+    fn transmit_abort(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for RamUart<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.tx_buffer.take().map(|buffer| {
+            self.tx_client.map(move |client| {
+                client.transmitted_buffer(buffer, self.tx_len.get(), ReturnCode::SUCCESS);
+            });
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! Delays the restart of processes that keep faulting at the same instruction.
+//!
+//! The kernel detects a process that faults repeatedly at the same program
+//! counter, which is most likely a deterministic bug, and leaves it stopped
+//! instead of restarting it in a tight loop. This capsule implements the
+//! kernel's `RestartThrottleTimer` with an alarm, restarting each such process
+//! once its delay has passed. Up to `MAX_THROTTLED` processes can wait at the
+//! same time; any further process is restarted right away.
+//!
+//! Only processes whose `FaultResponse` is `Restart` are affected.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let throttle_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let restart_throttle = static_init!(
+//!     capsules::restart_throttle::RestartThrottle<
+//!         'static,
+//!         VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!         Capability,
+//!     >,
+//!     capsules::restart_throttle::RestartThrottle::new(
+//!         throttle_alarm,
+//!         board_kernel,
+//!         process_mgmt_cap
+//!     )
+//! );
+//! throttle_alarm.set_alarm_client(restart_throttle);
+//! board_kernel.set_restart_throttle_timer(restart_throttle, &process_mgmt_cap);
+//! ```
+
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::procs::RestartThrottleTimer;
+use kernel::AppId;
+
+/// Number of processes that can wait to be restarted at the same time.
+pub const MAX_THROTTLED: usize = 4;
+
+pub struct RestartThrottle<'a, A: Alarm<'a>, C: ProcessManagementCapability> {
+    alarm: &'a A,
+    kernel: &'static kernel::Kernel,
+    /// Processes waiting to be restarted, with the reference and delay of
+    /// their restart.
+    throttled: [Cell<Option<(AppId, A::Ticks, A::Ticks)>>; MAX_THROTTLED],
+    capability: C,
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> RestartThrottle<'a, A, C> {
+    pub fn new(
+        alarm: &'a A,
+        kernel: &'static kernel::Kernel,
+        capability: C,
+    ) -> RestartThrottle<'a, A, C> {
+        RestartThrottle {
+            alarm: alarm,
+            kernel: kernel,
+            throttled: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            capability: capability,
+        }
+    }
+
+    /// Set the alarm for the earliest pending restart, if any.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let next = self
+            .throttled
+            .iter()
+            .filter_map(|slot| slot.get())
+            .min_by_key(|(_, reference, dt)| reference.wrapping_add(*dt).wrapping_sub(now));
+        match next {
+            Some((_, reference, dt)) => self.alarm.set_alarm(reference, dt),
+            None => {
+                self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> RestartThrottleTimer
+    for RestartThrottle<'a, A, C>
+{
+    fn delay_restart(&self, appid: AppId, delay_ms: u32) {
+        match self.throttled.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some((appid, self.alarm.now(), A::ticks_from_ms(delay_ms))));
+                self.rearm();
+            }
+            None => self
+                .kernel
+                .finish_throttled_restart(appid, &self.capability),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> time::AlarmClient
+    for RestartThrottle<'a, A, C>
+{
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        for slot in self.throttled.iter() {
+            if let Some((appid, reference, dt)) = slot.get() {
+                if !now.within_range(reference, reference.wrapping_add(dt)) {
+                    slot.set(None);
+                    self.kernel
+                        .finish_throttled_restart(appid, &self.capability);
+                }
+            }
+        }
+        self.rearm();
+    }
+}
@@ -0,0 +1,87 @@
+//! Lets processes keep the chip from ever sleeping.
+//!
+//! This is a development aid for debugging power issues: while sleep is
+//! inhibited the kernel loop spins instead of putting the chip to sleep, so
+//! a debugger stays attached and behavior can be observed without sleep
+//! interfering. Unlike the per-app vetoes of `sleep_veto`, which only rule
+//! out deep sleep, this rules out sleep altogether, and is a single global
+//! switch that any app can flip.
+//!
+//! The chip then draws its full active current at all times, typically orders
+//! of magnitude more than when asleep, so this capsule should only be
+//! included in development builds of a board. Sleep is allowed at boot.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sleep_inhibit = static_init!(
+//!     capsules::sleep_inhibit::SleepInhibit<Capability>,
+//!     capsules::sleep_inhibit::SleepInhibit::new(board_kernel, main_loop_cap)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Inhibit sleep.
+//! - `2`: Allow sleep again.
+//! - `3`: Return `1` if sleep is inhibited, `0` otherwise.
+
+use kernel::capabilities::MainLoopCapability;
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SleepInhibit as usize;
+
+pub struct SleepInhibit<C: MainLoopCapability> {
+    kernel: &'static kernel::Kernel,
+    capability: C,
+}
+
+impl<C: MainLoopCapability> SleepInhibit<C> {
+    pub fn new(kernel: &'static kernel::Kernel, capability: C) -> SleepInhibit<C> {
+        SleepInhibit {
+            kernel: kernel,
+            capability: capability,
+        }
+    }
+}
+
+impl<C: MainLoopCapability> Driver for SleepInhibit<C> {
+    /// Inhibit and allow sleep.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Inhibit sleep.
+    /// - `2`: Allow sleep.
+    /// - `3`: Whether sleep is inhibited.
+    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => {
+                self.kernel.set_sleep_inhibited(true, &self.capability);
+                ReturnCode::SUCCESS
+            }
+
+            2 => {
+                self.kernel.set_sleep_inhibited(false, &self.capability);
+                ReturnCode::SUCCESS
+            }
+
+            3 => ReturnCode::SuccessWithValue {
+                value: self.kernel.sleep_inhibited() as usize,
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Lets processes prevent the chip from entering deep sleep.
+//!
+//! Deep sleep may shut down peripherals, which can break an operation an app
+//! has in progress. While any app holds a veto the chip uses normal sleep
+//! instead. Vetoes are counted per app, so an app must release each veto it
+//! takes. An app's vetoes are stored in its grant, and so are dropped
+//! automatically if the app faults, is restarted, or exits.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sleep_veto = static_init!(
+//!     capsules::sleep_veto::SleepVeto,
+//!     capsules::sleep_veto::SleepVeto::new(board_kernel.create_grant(&grant_cap))
+//! );
+//! chip.set_deep_sleep_veto(sleep_veto);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Take a veto against deep sleep.
+//! - `2`: Release a veto. Returns `EALREADY` if the app holds none.
+//! - `3`: Return the number of vetoes held by the app.
+
+use kernel::{AppId, DeepSleepVeto, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SleepVeto as usize;
+
+#[derive(Default)]
+pub struct App {
+    vetoes: usize,
+}
+
+pub struct SleepVeto {
+    apps: Grant<App>,
+}
+
+impl SleepVeto {
+    pub fn new(grant: Grant<App>) -> SleepVeto {
+        SleepVeto { apps: grant }
+    }
+}
+
+impl DeepSleepVeto for SleepVeto {
+    fn deep_sleep_vetoed(&self) -> bool {
+        self.apps
+            .iter()
+            .any(|cntr| cntr.enter(|app, _| app.vetoes > 0))
+    }
+}
+
+impl Driver for SleepVeto {
+    /// Take and release vetoes against deep sleep.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Take a veto.
+    /// - `2`: Release a veto.
+    /// - `3`: Number of vetoes held by this app.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.vetoes += 1;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if app.vetoes == 0 {
+                        ReturnCode::EALREADY
+                    } else {
+                        app.vetoes -= 1;
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            3 => self
+                .apps
+                .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                    value: app.vetoes,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Lets userspace measure the overhead of system calls.
+//!
+//! A capsule cannot see the moment a process traps into the kernel or the
+//! moment it resumes, so a full round trip is measured between two commands:
+//! command `1` records the time, and command `2`, issued right after it,
+//! returns the time elapsed since. This covers returning from the first
+//! command to the process, the process issuing the second command, and the
+//! kernel dispatching it, which is one complete round trip through the system
+//! call path. Command `3` instead measures only kernel-side work, a grant
+//! lookup, bracketed by two reads of the counter.
+//!
+//! Times are measured with a `Counter` and converted to microseconds,
+//! rounding down, so they are only as precise as one counter tick. Command
+//! `4` returns the length of a tick in nanoseconds; measurements shorter than
+//! that read as 0 and should be repeated and averaged instead.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let syscall_benchmark = static_init!(
+//!     capsules::syscall_benchmark::SyscallBenchmark<'static, apollo3::stimer::STimer<'static>>,
+//!     capsules::syscall_benchmark::SyscallBenchmark::new(
+//!         &peripherals.stimer,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Record the current time for the calling process.
+//! - `2`: Microseconds since the process last issued command `1`. Returns
+//!   `EINVAL` if it never did.
+//! - `3`: Microseconds taken by a trivial kernel operation.
+//! - `4`: Length of a counter tick, in nanoseconds.
+
+use kernel::hil::time::{Counter, Frequency, Ticks};
+use kernel::{AppId, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SyscallBenchmark as usize;
+
+#[derive(Default)]
+pub struct App {
+    mark: Option<u32>,
+}
+
+pub struct SyscallBenchmark<'a, C: Counter<'a>> {
+    counter: &'a C,
+    apps: Grant<App>,
+}
+
+impl<'a, C: Counter<'a>> SyscallBenchmark<'a, C> {
+    pub fn new(counter: &'a C, grant: Grant<App>) -> SyscallBenchmark<'a, C> {
+        SyscallBenchmark {
+            counter: counter,
+            apps: grant,
+        }
+    }
+
+    /// Microseconds between two counter values, accounting for the counter
+    /// wrapping once in between.
+    fn elapsed_us(start: C::Ticks, end: C::Ticks) -> usize {
+        let ticks = end.wrapping_sub(start).into_u32() as u64;
+        (ticks * 1_000_000 / <C::Frequency>::frequency() as u64) as usize
+    }
+}
+
+impl<'a, C: Counter<'a>> Driver for SyscallBenchmark<'a, C> {
+    /// Measure system call overhead.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Record the current time.
+    /// - `2`: Microseconds since the last command `1`.
+    /// - `3`: Microseconds taken by a trivial kernel operation.
+    /// - `4`: Nanoseconds per counter tick.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.mark = Some(self.counter.now().into_u32());
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 => {
+                // Read the counter first so that the grant lookup is not
+                // included in the measurement.
+                let now = self.counter.now();
+                self.apps
+                    .enter(appid, |app, _| match app.mark {
+                        Some(mark) => ReturnCode::SuccessWithValue {
+                            value: Self::elapsed_us(C::Ticks::from(mark), now),
+                        },
+                        None => ReturnCode::EINVAL,
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            3 => {
+                let start = self.counter.now();
+                let entered = self.apps.enter(appid, |_, _| ());
+                let end = self.counter.now();
+                match entered {
+                    Ok(()) => ReturnCode::SuccessWithValue {
+                        value: Self::elapsed_us(start, end),
+                    },
+                    Err(err) => err.into(),
+                }
+            }
+
+            4 => ReturnCode::SuccessWithValue {
+                value: (1_000_000_000 / <C::Frequency>::frequency()) as usize,
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Lets a trusted app reset the whole system.
+//!
+//! A management app may need to reboot the system, for example to apply a
+//! firmware update or to recover remotely from a degraded state. Resetting
+//! ends every process, so only the apps the board trusts can request it; the
+//! command fails for all others. The board names trusted apps by the flash
+//! address of their TBF header, see `Kernel::lookup_app_by_flash_address()`,
+//! as package names are chosen by the apps themselves and so can be spoofed.
+//!
+//! The reset goes through the chip's `SystemReset`, which records the reason
+//! passed by the app so that after the reboot the board can tell it apart
+//! from a crash or a watchdog reset.
+//!
+//! Console output and flash writes are done in the background by interrupts,
+//! so resetting right away can cut them short. If the app asks for it, the
+//! reset is delayed by `FLUSH_DELAY_MS` to give them time to finish.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ResetCap;
+//! unsafe impl capabilities::SystemResetCapability for ResetCap {}
+//! unsafe impl capabilities::ProcessManagementCapability for ResetCap {}
+//!
+//! let reset_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! // The manager app is the first app in flash.
+//! let trusted_apps = static_init!([usize; 1], [&_sapps as *const u8 as usize]);
+//! let system_reset = static_init!(
+//!     capsules::system_reset::SystemResetDriver<
+//!         'static,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!         ResetCap,
+//!     >,
+//!     capsules::system_reset::SystemResetDriver::new(
+//!         mcu_ctrl,
+//!         reset_alarm,
+//!         board_kernel,
+//!         trusted_apps,
+//!         ResetCap
+//!     )
+//! );
+//! reset_alarm.set_alarm_client(system_reset);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Reset the system with the reason `data1`. If bit 0 of `data2` is
+//!   set, pending console output and flash writes are given time to finish
+//!   first. Returns `EBUSY` if a reset is already pending. Does not return
+//!   otherwise, unless the reset is delayed.
+//!
+//! All commands return `ENOSUPPORT` to apps not allowed to reset the system.
+
+use kernel::capabilities::{ProcessManagementCapability, SystemResetCapability};
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{Alarm, AlarmClient};
+use kernel::{AppId, Driver, Kernel, ReturnCode, SystemReset};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SystemReset as usize;
+
+/// How long a delayed reset waits for pending output and writes.
+pub const FLUSH_DELAY_MS: u32 = 100;
+
+pub struct SystemResetDriver<
+    'a,
+    A: Alarm<'a>,
+    C: SystemResetCapability + ProcessManagementCapability,
+> {
+    reset: &'a dyn SystemReset,
+    alarm: &'a A,
+    kernel: &'static Kernel,
+    /// Flash addresses of the apps allowed to reset the system.
+    allowed: &'a [usize],
+    capability: C,
+    /// Reason of a delayed reset.
+    pending: OptionalCell<u32>,
+}
+
+impl<'a, A: Alarm<'a>, C: SystemResetCapability + ProcessManagementCapability>
+    SystemResetDriver<'a, A, C>
+{
+    pub fn new(
+        reset: &'a dyn SystemReset,
+        alarm: &'a A,
+        kernel: &'static Kernel,
+        allowed: &'a [usize],
+        capability: C,
+    ) -> SystemResetDriver<'a, A, C> {
+        SystemResetDriver {
+            reset: reset,
+            alarm: alarm,
+            kernel: kernel,
+            allowed: allowed,
+            capability: capability,
+            pending: OptionalCell::empty(),
+        }
+    }
+
+    fn is_allowed(&self, appid: AppId) -> bool {
+        self.allowed.iter().any(|&address| {
+            self.kernel
+                .lookup_app_by_flash_address(address, &self.capability)
+                == Some(appid)
+        })
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: SystemResetCapability + ProcessManagementCapability> AlarmClient
+    for SystemResetDriver<'a, A, C>
+{
+    fn alarm(&self) {
+        self.pending
+            .take()
+            .map(|reason| self.reset.reset(reason, &self.capability));
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: SystemResetCapability + ProcessManagementCapability> Driver
+    for SystemResetDriver<'a, A, C>
+{
+    /// Reset the system.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Reset with reason `data1`, after a grace period if bit 0 of
+    ///   `data2` is set.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if !self.is_allowed(appid) {
+            return ReturnCode::ENOSUPPORT;
+        }
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                if self.pending.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                if data2 & 1 == 0 {
+                    self.reset.reset(data1 as u32, &self.capability);
+                }
+                self.pending.set(data1 as u32);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(FLUSH_DELAY_MS));
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
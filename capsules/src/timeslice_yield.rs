@@ -0,0 +1,81 @@
+//! Lets compute-bound apps give up their timeslice when it is nearly over.
+//!
+//! An app running a long computation is preempted wherever its timeslice
+//! happens to expire. If it instead calls this driver at natural boundaries,
+//! such as between loop iterations, it gives up the rest of its timeslice
+//! there once less than a threshold is left, and otherwise continues right
+//! away. It remains ready to run and is scheduled again like a preempted
+//! process, but not treated by schedulers as having used up its timeslice.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let timeslice_yield = static_init!(
+//!     capsules::timeslice_yield::TimesliceYield,
+//!     capsules::timeslice_yield::TimesliceYield::new(board_kernel)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Give up the rest of the timeslice if less than `data1` microseconds
+//!   of it are left. Returns `1` once the app runs again if it gave up its
+//!   timeslice, and `0` right away otherwise. Apps run without a timeslice
+//!   are never preempted and always get `0`.
+//! - `2`: Return the microseconds left of the timeslice. Returns `ENOSUPPORT`
+//!   if the app runs without one.
+
+use kernel::{AppId, Driver, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::TimesliceYield as usize;
+
+pub struct TimesliceYield {
+    kernel: &'static Kernel,
+}
+
+impl TimesliceYield {
+    pub fn new(kernel: &'static Kernel) -> TimesliceYield {
+        TimesliceYield { kernel: kernel }
+    }
+}
+
+impl Driver for TimesliceYield {
+    /// Give up the timeslice.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Give up the timeslice if less than `data1` microseconds are left.
+    /// - `2`: Microseconds left of the timeslice.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                let nearly_over = self
+                    .kernel
+                    .remaining_timeslice_us(appid)
+                    .map_or(false, |remaining_us| (remaining_us as usize) < data1);
+                let yielded = nearly_over && self.kernel.end_timeslice(appid);
+                ReturnCode::SuccessWithValue {
+                    value: yielded as usize,
+                }
+            }
+            2 => self.kernel.remaining_timeslice_us(appid).map_or(
+                ReturnCode::ENOSUPPORT,
+                |remaining_us| ReturnCode::SuccessWithValue {
+                    value: remaining_us as usize,
+                },
+            ),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
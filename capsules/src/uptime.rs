@@ -0,0 +1,97 @@
+//! Provides userspace with the time elapsed since boot.
+//!
+//! The time is read from the kernel's uptime clock, a 64-bit count that does
+//! not wrap, see `kernel::uptime`. The board sets that clock with
+//! `Kernel::set_uptime_clock()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let uptime = static_init!(
+//!     capsules::uptime::Uptime,
+//!     capsules::uptime::Uptime::new(
+//!         board_kernel,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! Commands `1` and `2` return `ENOSUPPORT` if the board set no uptime clock.
+//!
+//! - `0`: Driver check.
+//! - `1`: Read the number of microseconds since boot. Returns the low 32 bits
+//!   of the value; the high 32 bits are kept for the calling process and can
+//!   be read with command `2`.
+//! - `2`: Return the high 32 bits of the value last read by this process with
+//!   command `1`.
+
+use kernel::{AppId, Driver, Grant, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Uptime as usize;
+
+#[derive(Default)]
+pub struct App {
+    high: u32,
+}
+
+pub struct Uptime {
+    kernel: &'static Kernel,
+    apps: Grant<App>,
+}
+
+impl Uptime {
+    pub fn new(kernel: &'static Kernel, grant: Grant<App>) -> Uptime {
+        Uptime {
+            kernel: kernel,
+            apps: grant,
+        }
+    }
+}
+
+impl Driver for Uptime {
+    /// Read the time since boot.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Microseconds since boot, low 32 bits.
+    /// - `2`: High 32 bits of the last value read with command `1`.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| match self.kernel.uptime_us() {
+                    Some(us) => {
+                        app.high = (us >> 32) as u32;
+                        ReturnCode::SuccessWithValue {
+                            value: us as u32 as usize,
+                        }
+                    }
+                    None => ReturnCode::ENOSUPPORT,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 if self.kernel.uptime_us().is_none() => ReturnCode::ENOSUPPORT,
+            2 => self
+                .apps
+                .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                    value: app.high as usize,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
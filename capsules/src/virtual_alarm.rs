@@ -89,8 +89,28 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
         self.armed.get()
     }
 
+    /// If the mux has a limit on armed alarms and it has been reached, the
+    /// alarm is not armed: this panics in debug builds, and is counted in
+    /// `MuxAlarmStats::refused` otherwise. Clients of a mux with a limit must
+    /// use `try_set_alarm` and handle `EBUSY` instead.
     fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+        let result = self.try_set_alarm(reference, dt);
+        debug_assert!(
+            result == ReturnCode::SUCCESS,
+            "set_alarm() on a MuxAlarm with all of its alarms armed"
+        );
+        if result != ReturnCode::SUCCESS {
+            self.mux
+                .refused
+                .set(self.mux.refused.get().saturating_add(1));
+        }
+    }
+
+    fn try_set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) -> ReturnCode {
         let enabled = self.mux.enabled.get();
+        if !self.armed.get() && self.mux.max_armed.map_or(false, |max| enabled >= max) {
+            return ReturnCode::EBUSY;
+        }
         self.reference.set(reference);
         self.dt.set(dt);
 
@@ -131,6 +151,7 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
                 // current alarm will fire earlier, keep it
             }
         }
+        ReturnCode::SUCCESS
     }
 
     fn get_alarm(&self) -> Self::Ticks {
@@ -154,26 +175,90 @@ pub struct MuxAlarm<'a, A: Alarm<'a>> {
     virtual_alarms: List<'a, VirtualMuxAlarm<'a, A>>,
     /// Number of virtual alarms that are currently enabled.
     enabled: Cell<usize>,
+    /// Maximum number of virtual alarms that may be enabled at once, if any.
+    max_armed: Option<usize>,
     /// Underlying alarm, over which the virtual alarms are multiplexed.
     alarm: &'a A,
     /// Whether we are firing; used to delay restarted alarms
     firing: Cell<bool>,
     /// Reference to next alarm
     next_tick_vals: Cell<Option<(A::Ticks, A::Ticks)>>,
+    /// Number of times the underlying alarm has been set, saturating.
+    rearms: Cell<u32>,
+    /// Number of virtual alarms that fired late, saturating.
+    missed_deadlines: Cell<u32>,
+    /// Number of `set_alarm` calls refused by the limit, saturating.
+    refused: Cell<u32>,
 }
 
+/// A snapshot of the usage counters of a `MuxAlarm`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MuxAlarmStats {
+    /// Number of virtual alarms that are currently armed.
+    pub active: usize,
+    /// Number of times the underlying alarm has been set.
+    pub rearms: u32,
+    /// Number of virtual alarms that fired more than `MISSED_DEADLINE_MS`
+    /// after their expiration.
+    pub missed_deadlines: u32,
+    /// Number of `set_alarm` calls that did not arm their alarm because
+    /// `max_armed` alarms were already armed.
+    pub refused: u32,
+}
+
+/// How late, in milliseconds, a virtual alarm may fire before it is counted
+/// as a missed deadline.
+pub const MISSED_DEADLINE_MS: u32 = 1;
+
 impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
     pub const fn new(alarm: &'a A) -> MuxAlarm<'a, A> {
+        MuxAlarm::create(alarm, None)
+    }
+
+    /// Creates a mux that arms at most `max_armed` virtual alarms at once.
+    /// Arming another virtual alarm beyond that fails with `EBUSY` until one
+    /// of the armed alarms fires or is disarmed.
+    ///
+    /// Only clients that arm their alarm with `try_set_alarm` find out about
+    /// the limit, so every client of such a mux must use it.
+    pub const fn new_with_limit(alarm: &'a A, max_armed: usize) -> MuxAlarm<'a, A> {
+        MuxAlarm::create(alarm, Some(max_armed))
+    }
+
+    const fn create(alarm: &'a A, max_armed: Option<usize>) -> MuxAlarm<'a, A> {
         MuxAlarm {
             virtual_alarms: List::new(),
             enabled: Cell::new(0),
+            max_armed: max_armed,
             alarm: alarm,
             firing: Cell::new(false),
             next_tick_vals: Cell::new(None),
+            rearms: Cell::new(0),
+            missed_deadlines: Cell::new(0),
+            refused: Cell::new(0),
         }
     }
 
+    /// Returns the current usage counters. The counters saturate rather than
+    /// wrap, and can be cleared with `reset_stats()`.
+    pub fn stats(&self) -> MuxAlarmStats {
+        MuxAlarmStats {
+            active: self.enabled.get(),
+            rearms: self.rearms.get(),
+            missed_deadlines: self.missed_deadlines.get(),
+            refused: self.refused.get(),
+        }
+    }
+
+    /// Clears the re-arm, missed deadline and refused counters.
+    pub fn reset_stats(&self) {
+        self.rearms.set(0);
+        self.missed_deadlines.set(0);
+        self.refused.set(0);
+    }
+
     pub fn set_alarm(&self, reference: A::Ticks, dt: A::Ticks) {
+        self.rearms.set(self.rearms.get().saturating_add(1));
         self.next_tick_vals.set(Some((reference, dt)));
         self.alarm.set_alarm(reference, dt);
     }
@@ -189,6 +274,7 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
     /// alarms that should now fire.
     fn alarm(&self) {
         let now = self.alarm.now();
+        let late = A::ticks_from_ms(MISSED_DEADLINE_MS);
         // Check whether to fire each alarm. At this level, alarms are one-shot,
         // so a repeating client will set it again in the alarm() callback.
         self.firing.set(true);
@@ -204,6 +290,11 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
             .for_each(|cur| {
                 cur.armed.set(false);
                 self.enabled.set(self.enabled.get() - 1);
+                let expiration = cur.reference.get().wrapping_add(cur.dt.get());
+                if now.wrapping_sub(expiration) > late {
+                    self.missed_deadlines
+                        .set(self.missed_deadlines.get().saturating_add(1));
+                }
                 //debug!("  Virtualizer: {:?} outside {:?}-{:?}, fire!", now, cur.reference.get(), cur.reference.get().wrapping_add(cur.dt.get()));
                 cur.alarm();
             });
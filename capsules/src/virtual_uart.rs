@@ -7,6 +7,11 @@
 //! Clients can choose if they want to receive. Incoming messages will be sent
 //! to all clients that have enabled receiving.
 //!
+//! The baud rate of the shared bus can be changed at runtime with
+//! `set_baud_rate()` and returned to the rate the mux was created with using
+//! `restore_baud_rate()`. The change is applied between transmissions, so a
+//! transmission from any client is always sent entirely at one rate.
+//!
 //! `MuxUart` provides shared access to a single UART bus for multiple users.
 //! `UartDevice` provides access for a single client.
 //!
@@ -34,6 +39,7 @@
 //!         console_uart,
 //!         &mut capsules::console::WRITE_BUF,
 //!         &mut capsules::console::READ_BUF,
+//!         board_kernel.create_grant(&grant_cap),
 //!         board_kernel.create_grant(&grant_cap)
 //!     )
 //! );
@@ -58,6 +64,7 @@ pub static mut RX_BUF: [u8; RX_BUF_LEN] = [0; RX_BUF_LEN];
 pub struct MuxUart<'a> {
     uart: &'a dyn uart::Uart<'a>,
     speed: u32,
+    pending_speed: OptionalCell<u32>,
     devices: List<'a, UartDevice<'a>>,
     inflight: OptionalCell<&'a UartDevice<'a>>,
     buffer: TakeCell<'static, [u8]>,
@@ -194,6 +201,7 @@ impl<'a> MuxUart<'a> {
         MuxUart {
             uart: uart,
             speed: speed,
+            pending_speed: OptionalCell::empty(),
             devices: List::new(),
             inflight: OptionalCell::empty(),
             buffer: TakeCell::new(buffer),
@@ -204,13 +212,45 @@ impl<'a> MuxUart<'a> {
     }
 
     pub fn initialize(&self) {
+        self.configure(self.speed);
+    }
+
+    fn configure(&self, baud_rate: u32) -> ReturnCode {
         self.uart.configure(uart::Parameters {
-            baud_rate: self.speed,
+            baud_rate: baud_rate,
             width: uart::Width::Eight,
             stop_bits: uart::StopBits::One,
             parity: uart::Parity::None,
             hw_flow_control: false,
-        });
+        })
+    }
+
+    /// Change the baud rate of the underlying UART.
+    ///
+    /// If a transmission is in progress the change is deferred until it
+    /// completes, and is applied before any transmission queued by another
+    /// client starts. Returns `EINVAL` for a baud rate of zero.
+    pub fn set_baud_rate(&self, baud_rate: u32) -> ReturnCode {
+        if baud_rate == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.pending_speed.set(baud_rate);
+        if self.inflight.is_none() {
+            self.apply_baud_rate()
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Return the underlying UART to the baud rate the mux was created with.
+    pub fn restore_baud_rate(&self) -> ReturnCode {
+        self.set_baud_rate(self.speed)
+    }
+
+    fn apply_baud_rate(&self) -> ReturnCode {
+        self.pending_speed
+            .take()
+            .map_or(ReturnCode::SUCCESS, |baud_rate| self.configure(baud_rate))
     }
 
     pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
@@ -219,6 +259,7 @@ impl<'a> MuxUart<'a> {
 
     fn do_next_op(&self) {
         if self.inflight.is_none() {
+            self.apply_baud_rate();
             let mnode = self.devices.iter().find(|node| node.operation.is_some());
             mnode.map(|node| {
                 node.tx_buffer.take().map(|buf| {
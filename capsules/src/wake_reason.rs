@@ -0,0 +1,75 @@
+//! Provides userspace with what woke the chip from sleep.
+//!
+//! Each time the chip wakes up, the chip records which interrupt woke it and
+//! keeps a count per interrupt. Frequent wakeups from an unexpected source
+//! drain the battery, so this capsule exposes those records for power
+//! debugging. Interrupt numbers are the chip's NVIC numbers.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let wake_reason = static_init!(
+//!     capsules::wake_reason::WakeReason,
+//!     capsules::wake_reason::WakeReason::new(chip)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the interrupt that last woke the chip. Returns `FAIL` if the
+//!        chip has not slept yet or woke without a pending interrupt.
+//! - `2`: Return how many times the interrupt in `data1` has woken the chip.
+//! - `3`: Reset the counts returned by command `2`.
+
+use kernel::{AppId, Driver, ReturnCode, WakeSource};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::WakeReason as usize;
+
+pub struct WakeReason {
+    source: &'static dyn WakeSource,
+}
+
+impl WakeReason {
+    pub fn new(source: &'static dyn WakeSource) -> WakeReason {
+        WakeReason { source: source }
+    }
+}
+
+impl Driver for WakeReason {
+    /// Read and reset the wake records.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Interrupt that last woke the chip.
+    /// - `2`: Number of wakeups caused by interrupt `data1`.
+    /// - `3`: Reset the wake counts.
+    fn command(&self, command_num: usize, data1: usize, _: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self
+                .source
+                .last_wake_source()
+                .map_or(ReturnCode::FAIL, |interrupt| ReturnCode::SuccessWithValue {
+                    value: interrupt as usize,
+                }),
+            2 => ReturnCode::SuccessWithValue {
+                value: self.source.wake_count(data1 as u32) as usize,
+            },
+            3 => {
+                self.source.reset_wake_counts();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
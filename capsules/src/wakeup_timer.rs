@@ -0,0 +1,49 @@
+//! Wakes the chip from sleep using an alarm.
+//!
+//! Implements the kernel's `WakeupTimer` interface on top of any alarm,
+//! typically a virtual alarm, so that the kernel can sleep for a bounded time.
+//! The alarm callback does nothing: the alarm interrupt waking the chip is all
+//! that is needed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let wakeup_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, apollo3::stimer::STimer>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let wakeup_timer = static_init!(
+//!     capsules::wakeup_timer::AlarmWakeupTimer<'static, VirtualMuxAlarm<'static, apollo3::stimer::STimer>>,
+//!     capsules::wakeup_timer::AlarmWakeupTimer::new(wakeup_alarm)
+//! );
+//! wakeup_alarm.set_alarm_client(wakeup_timer);
+//! board_kernel.set_min_loop_period(1000, wakeup_timer, &main_loop_cap);
+//! ```
+
+use core::cmp;
+use kernel::hil::time::{self, Alarm};
+use kernel::WakeupTimer;
+
+pub struct AlarmWakeupTimer<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+}
+
+impl<'a, A: Alarm<'a>> AlarmWakeupTimer<'a, A> {
+    pub fn new(alarm: &'a A) -> AlarmWakeupTimer<'a, A> {
+        AlarmWakeupTimer { alarm: alarm }
+    }
+}
+
+impl<'a, A: Alarm<'a>> WakeupTimer for AlarmWakeupTimer<'a, A> {
+    fn set_wakeup(&self, us: u32) {
+        let dt = cmp::max(A::ticks_from_us(us), self.alarm.minimum_dt());
+        self.alarm.set_alarm(self.alarm.now(), dt);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AlarmWakeupTimer<'a, A> {
+    fn alarm(&self) {}
+}
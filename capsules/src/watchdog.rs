@@ -0,0 +1,159 @@
+//! Lets a trusted app read and change the watchdog timeout.
+//!
+//! The kernel loop restarts the hardware watchdog each time around, and the
+//! watchdog resets the system if that does not happen within its timeout.
+//! How long a hang to tolerate depends on the deployment, so a management app
+//! can tune the timeout at runtime rather than the kernel being rebuilt. Only
+//! the apps the board names can use this driver, as a short timeout can keep
+//! the system resetting.
+//!
+//! Timeouts are limited to what the hardware can time, and to at least
+//! `MIN_TIMEOUT_MS`, which leaves the kernel loop ample time to come around
+//! while processes run their timeslices. The hardware rounds timeouts down,
+//! so a timeout it would round below `MIN_TIMEOUT_MS` is rejected too. The
+//! countdown restarts with each new timeout, so changing it does not reset
+//! the system.
+//!
+//! Only the apps the board trusts can use the driver. The board names them
+//! by the flash address of their TBF header, see
+//! `Kernel::lookup_app_by_flash_address()`, as package names are chosen by
+//! the apps themselves and so can be spoofed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct WatchdogCap;
+//! unsafe impl capabilities::ProcessManagementCapability for WatchdogCap {}
+//!
+//! // The manager app is the first app in flash.
+//! let trusted_apps = static_init!([usize; 1], [&_sapps as *const u8 as usize]);
+//! let watchdog = static_init!(
+//!     capsules::watchdog::WatchdogDriver<WatchdogCap>,
+//!     capsules::watchdog::WatchdogDriver::new(
+//!         chip.watchdog(),
+//!         board_kernel,
+//!         trusted_apps,
+//!         WatchdogCap
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the timeout in milliseconds.
+//! - `2`: Set the timeout to `data1` milliseconds, rounded down to what the
+//!   hardware can time. Returns `EINVAL` if it is out of range, or would be
+//!   rounded below `MIN_TIMEOUT_MS`.
+//! - `3`: Return the shortest timeout that can be set, in milliseconds.
+//! - `4`: Return the longest timeout that can be set, in milliseconds.
+//!
+//! All commands return `ENOSUPPORT` to apps not allowed to use the watchdog.
+
+use core::cmp;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::watchdog::WatchDogTimeout;
+use kernel::{AppId, Driver, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Watchdog as usize;
+
+/// Shortest timeout apps can set, in milliseconds.
+pub const MIN_TIMEOUT_MS: u32 = 100;
+
+pub struct WatchdogDriver<'a, C: ProcessManagementCapability> {
+    watchdog: &'a dyn WatchDogTimeout,
+    kernel: &'static Kernel,
+    /// Flash addresses of the apps allowed to use the watchdog.
+    allowed: &'a [usize],
+    capability: C,
+}
+
+impl<'a, C: ProcessManagementCapability> WatchdogDriver<'a, C> {
+    pub fn new(
+        watchdog: &'a dyn WatchDogTimeout,
+        kernel: &'static Kernel,
+        allowed: &'a [usize],
+        capability: C,
+    ) -> WatchdogDriver<'a, C> {
+        WatchdogDriver {
+            watchdog: watchdog,
+            kernel: kernel,
+            allowed: allowed,
+            capability: capability,
+        }
+    }
+
+    fn is_allowed(&self, appid: AppId) -> bool {
+        self.allowed.iter().any(|&address| {
+            self.kernel
+                .lookup_app_by_flash_address(address, &self.capability)
+                == Some(appid)
+        })
+    }
+
+    /// Whether apps may set a timeout of `timeout_ms`: the hardware must
+    /// support it without rounding it below `MIN_TIMEOUT_MS`.
+    fn is_valid(&self, timeout_ms: u32) -> bool {
+        self.watchdog
+            .supported_timeout_ms(timeout_ms)
+            .map_or(false, |timeout_ms| timeout_ms >= MIN_TIMEOUT_MS)
+    }
+
+    /// The shortest and longest timeout apps can set. The shortest is the
+    /// first one past `MIN_TIMEOUT_MS` that is not rounded below it, which is
+    /// found within one step of the hardware's resolution.
+    fn range_ms(&self) -> (u32, u32) {
+        let (min, max) = self.watchdog.timeout_range_ms();
+        let floor = cmp::max(min, MIN_TIMEOUT_MS);
+        let min = (floor..=max)
+            .find(|&timeout_ms| self.is_valid(timeout_ms))
+            .unwrap_or(max);
+        (min, max)
+    }
+}
+
+impl<'a, C: ProcessManagementCapability> Driver for WatchdogDriver<'a, C> {
+    /// Read and change the watchdog timeout.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Timeout in milliseconds.
+    /// - `2`: Set the timeout to `data1` milliseconds.
+    /// - `3`: Shortest timeout in milliseconds.
+    /// - `4`: Longest timeout in milliseconds.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        if !self.is_allowed(appid) {
+            return ReturnCode::ENOSUPPORT;
+        }
+        let (min, max) = self.range_ms();
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue {
+                value: self.watchdog.timeout_ms() as usize,
+            },
+            2 => {
+                if data1 < min as usize || data1 > max as usize || !self.is_valid(data1 as u32) {
+                    return ReturnCode::EINVAL;
+                }
+                self.watchdog.set_timeout_ms(data1 as u32)
+            }
+            3 => ReturnCode::SuccessWithValue {
+                value: min as usize,
+            },
+            4 => ReturnCode::SuccessWithValue {
+                value: max as usize,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -1,4 +1,13 @@
 //! BLE driver.
+//!
+//! Advertisements are transferred to the BLE core to be sent. The core runs
+//! the link layer itself and is told to scan over HCI: a receive sends LE Set
+//! Scan Parameters for a passive scan and then LE Set Scan Enable, waiting for
+//! each command to complete. The core raises `BLECIRQ` when it has an event to
+//! read out of the FIFO, and each LE Advertising Report is rebuilt into the
+//! advertising channel PDU it was received as and handed to the receive
+//! client, with the RSSI from the report. Once the client stops asking for
+//! advertisements scanning is disabled again.
 
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
@@ -253,6 +262,121 @@ register_bitfields![u32,
 
 static mut PAYLOAD: [u8; 40] = [0x00; 40];
 
+/// HCI commands sent to the BLE core.
+static mut COMMAND: [u8; 16] = [0x00; 16];
+
+/// HCI events read from the BLE core. An LE Advertising Report with the
+/// largest advertising data is 46 bytes.
+static mut EVENT: [u8; 64] = [0x00; 64];
+
+/// HCI packet indicators, the first byte of a packet on the HCI transport.
+const HCI_COMMAND_PACKET: u8 = 0x01;
+const HCI_EVENT_PACKET: u8 = 0x04;
+
+/// HCI event codes.
+const HCI_COMMAND_COMPLETE: u8 = 0x0e;
+const HCI_LE_META: u8 = 0x3e;
+
+/// LE meta event subevent code.
+const HCI_LE_ADVERTISING_REPORT: u8 = 0x02;
+
+/// HCI LE controller command opcodes.
+const HCI_LE_SET_SCAN_PARAMETERS: u16 = 0x200b;
+const HCI_LE_SET_SCAN_ENABLE: u16 = 0x200c;
+
+/// Passive scan with equal 10 ms interval and window, so the core listens
+/// continuously, from the public address and without a filter list.
+const SCAN_PARAMETERS: [u8; 7] = [0x00, 0x10, 0x00, 0x10, 0x00, 0x00, 0x00];
+
+/// Enable scanning without filtering duplicates, and disable it.
+const SCAN_ENABLE: [u8; 2] = [0x01, 0x00];
+const SCAN_DISABLE: [u8; 2] = [0x00, 0x00];
+
+/// Largest advertising data in an advertising channel PDU.
+const ADV_DATA_MAX: usize = 31;
+
+/// Largest RSSI a BLE radio reports, in dBm.
+const RSSI_MAX: i8 = 20;
+
+/// Write an HCI command packet for `opcode` with `params` into `buf`,
+/// returning its length.
+fn hci_command(opcode: u16, params: &[u8], buf: &mut [u8]) -> usize {
+    let len = 4 + params.len();
+    buf[0] = HCI_COMMAND_PACKET;
+    buf[1..3].copy_from_slice(&opcode.to_le_bytes());
+    buf[3] = params.len() as u8;
+    buf[4..len].copy_from_slice(params);
+    len
+}
+
+/// Parameters of a well formed HCI event packet with the `code` given.
+fn hci_event(event: &[u8], code: u8) -> Option<&[u8]> {
+    if event.len() < 3 || event[0] != HCI_EVENT_PACKET || event[1] != code {
+        return None;
+    }
+    event.get(3..3 + event[2] as usize)
+}
+
+/// Opcode and status of the command an HCI Command Complete event is for.
+fn command_complete(event: &[u8]) -> Option<(u16, u8)> {
+    let params = hci_event(event, HCI_COMMAND_COMPLETE)?;
+    if params.len() < 4 {
+        return None;
+    }
+    Some((u16::from_le_bytes([params[1], params[2]]), params[3]))
+}
+
+/// Rebuild the advertising channel PDU of an HCI LE Advertising Report into
+/// `pdu`, returning the PDU length and the RSSI of the report. Only reports
+/// of a single advertisement are handled, which is all the core sends while
+/// scanning with one advertisement per event. RSSI values above `RSSI_MAX`,
+/// including the 127 a controller sends when it has no measurement, are
+/// reported as unavailable.
+fn advertising_report(event: &[u8], pdu: &mut [u8]) -> Option<(usize, i8)> {
+    let params = hci_event(event, HCI_LE_META)?;
+    if params.len() < 12 || params[0] != HCI_LE_ADVERTISING_REPORT || params[1] != 1 {
+        return None;
+    }
+    // The report's event type names the PDU type it was received as.
+    let pdu_type = match params[2] {
+        0x00 => 0x00, // ADV_IND
+        0x01 => 0x01, // ADV_DIRECT_IND
+        0x02 => 0x06, // ADV_SCAN_IND
+        0x03 => 0x02, // ADV_NONCONN_IND
+        0x04 => 0x04, // SCAN_RSP
+        _ => return None,
+    };
+    let data_len = params[10] as usize;
+    if data_len > ADV_DATA_MAX || params.len() < 12 + data_len {
+        return None;
+    }
+    let len = 8 + data_len;
+    // TxAdd is set for a random advertiser address.
+    pdu[0] = pdu_type | (params[3] & 0x01) << 6;
+    pdu[1] = (6 + data_len) as u8;
+    pdu[2..8].copy_from_slice(&params[4..10]);
+    pdu[8..len].copy_from_slice(&params[11..11 + data_len]);
+    let rssi = match params[11 + data_len] as i8 {
+        rssi if rssi > RSSI_MAX => ble_advertising::RSSI_UNAVAILABLE,
+        rssi => rssi,
+    };
+    Some((len, rssi))
+}
+
+/// Where the BLE core is in scanning, which it is started and stopped in with
+/// HCI commands.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Scan {
+    Off,
+    /// LE Set Scan Parameters is being sent or waited on.
+    SettingParameters,
+    /// LE Set Scan Enable is starting the scan.
+    Enabling,
+    On,
+    /// LE Set Scan Enable is stopping the scan.
+    Disabling,
+}
+
 pub struct Ble<'a> {
     registers: StaticRef<BleRegisters>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
@@ -263,6 +387,15 @@ pub struct Ble<'a> {
 
     read_len: Cell<usize>,
     read_index: Cell<usize>,
+
+    /// Length of the HCI command in `COMMAND` still to be written, or 0.
+    command_len: Cell<usize>,
+    /// A DMA write to the core is under way.
+    writing: Cell<bool>,
+    scan: Cell<Scan>,
+
+    /// Listening for an advertisement.
+    receiving: Cell<bool>,
 }
 
 impl<'a> Ble<'a> {
@@ -275,9 +408,23 @@ impl<'a> Ble<'a> {
             write_len: Cell::new(0),
             read_len: Cell::new(0),
             read_index: Cell::new(0),
+            command_len: Cell::new(0),
+            writing: Cell::new(false),
+            scan: Cell::new(Scan::Off),
+            receiving: Cell::new(false),
         }
     }
 
+    /// Whether an advertisement is being transferred to the BLE core or the
+    /// core is scanning or being told to, which keeps the chip out of deep
+    /// sleep until it completes.
+    pub fn is_busy(&self) -> bool {
+        self.buffer.is_some()
+            || self.receiving.get()
+            || self.command_len.get() > 0
+            || self.scan.get() != Scan::Off
+    }
+
     pub fn setup_clocks(&self) {
         self.registers.clkcfg.write(CLKCFG::CLK32KEN::SET);
         self.registers.bledbg.write(BLEDBG::DBGDATA.val(1 << 14));
@@ -328,11 +475,21 @@ impl<'a> Ble<'a> {
         // Disable the FIFO
         //self.registers.fifothr.write(FIFOTHR::FIFORTHR::CLEAR + FIFOTHR::FIFOWTHR::CLEAR);
 
+        // A pending HCI command goes ahead of an advertisement
+        let len = match self.command_len.get() {
+            0 => self.write_len.get(),
+            len => len,
+        };
+
         // Setup the DMA
         unsafe {
-            self.registers.dmatargaddr.set(PAYLOAD.as_ptr() as u32);
+            if self.command_len.get() > 0 {
+                self.registers.dmatargaddr.set(COMMAND.as_ptr() as u32);
+            } else {
+                self.registers.dmatargaddr.set(PAYLOAD.as_ptr() as u32);
+            }
         }
-        self.registers.dmatocount.set(self.write_len.get() as u32);
+        self.registers.dmatocount.set(len as u32);
         self.registers.dmatrigen.write(DMATRIGEN::DTHREN::SET);
         self.registers
             .dmacfg
@@ -341,15 +498,57 @@ impl<'a> Ble<'a> {
         // Setup the operation
         self.registers
             .cmd
-            .modify(CMD::TSIZE.val(self.write_len.get() as u32) + CMD::CMD::WRITE);
+            .modify(CMD::TSIZE.val(len as u32) + CMD::CMD::WRITE);
 
         // Enable DMA
+        self.writing.set(true);
         self.registers.dmacfg.modify(DMACFG::DMAEN::SET);
 
         // Set the wake low
         self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
     }
 
+    /// Whether there is an HCI command or an advertisement to write to the
+    /// core. Advertisements wait for the core to stop scanning.
+    fn write_pending(&self) -> bool {
+        !self.writing.get()
+            && (self.command_len.get() > 0
+                || (self.buffer.is_some() && self.scan.get() == Scan::Off))
+    }
+
+    /// Wake the core to write to it, and write straight away if it is ready
+    /// and has nothing for us to read first.
+    fn start_write(&self) {
+        // Enable interrupts
+        self.enable_interrupts();
+
+        // Wakeup BLE
+        self.registers.blecfg.modify(BLECFG::WAKEUPCTL::ON);
+
+        // See if we can send the data
+        if self.registers.bstatus.is_set(BSTATUS::SPISTATUS)
+            && !self.registers.bstatus.is_set(BSTATUS::BLEIRQ)
+        {
+            self.send_data();
+        }
+    }
+
+    fn send_command(&self, opcode: u16, params: &[u8]) {
+        let len = unsafe { hci_command(opcode, params, &mut COMMAND) };
+        self.command_len.set(len);
+        self.start_write();
+    }
+
+    fn start_scan(&self) {
+        self.scan.set(Scan::SettingParameters);
+        self.send_command(HCI_LE_SET_SCAN_PARAMETERS, &SCAN_PARAMETERS);
+    }
+
+    fn stop_scan(&self) {
+        self.scan.set(Scan::Disabling);
+        self.send_command(HCI_LE_SET_SCAN_ENABLE, &SCAN_DISABLE);
+    }
+
     pub fn handle_interrupt(&self) {
         let irqs = self.registers.intstat.extract();
 
@@ -360,16 +559,13 @@ impl<'a> Ble<'a> {
             // Enable interrupts
             self.enable_interrupts();
 
-            if self.registers.bstatus.is_set(BSTATUS::BLEIRQ) {
-                panic!("Read requested while trying to write");
-            }
-
             if !self.registers.bstatus.is_set(BSTATUS::SPISTATUS) {
                 panic!("SPI not ready");
             }
 
-            // If we have data, send it
-            if self.buffer.is_some() {
+            // If we have data, send it. When the core has an event for us it
+            // is read first, and the write started again after.
+            if self.write_pending() && !self.registers.bstatus.is_set(BSTATUS::BLEIRQ) {
                 // Send the data
                 self.send_data();
             }
@@ -378,6 +574,7 @@ impl<'a> Ble<'a> {
         if irqs.is_set(INT::DCMP) {
             // Disable and clear DMA
             self.registers.dmacfg.set(0x00000000);
+            self.writing.set(false);
 
             // Disable the wake controller
             self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
@@ -385,41 +582,111 @@ impl<'a> Ble<'a> {
             // Reset FIFOs
             self.reset_fifo();
 
-            if self.buffer.is_some() {
+            self.enable_interrupts();
+
+            if self.command_len.get() > 0 {
+                // The command is written, and its Command Complete event
+                // follows.
+                self.command_len.set(0);
+            } else if self.buffer.is_some() {
                 self.tx_client.map(|client| {
                     client.transmit_event(self.buffer.take().unwrap(), kernel::ReturnCode::SUCCESS);
                 });
             }
-
-            self.enable_interrupts();
         }
 
         if irqs.is_set(INT::BLECIRQ) {
-            self.rx_client.map(|client| {
-                self.registers
-                    .cmd
-                    .modify(CMD::TSIZE.val(0) + CMD::CMD::READ);
+            let read = self.read_packet();
 
-                unsafe {
-                    let mut i = 0;
-
-                    while self.registers.fifoptr.read(FIFOPTR::FIFO1SIZ) > 0 && i < 40 {
-                        let temp = self.registers.fifopop.get().to_ne_bytes();
+            // Let the BLE core go back to sleep until the next transfer
+            self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
+            self.reset_fifo();
+            self.enable_interrupts();
 
-                        PAYLOAD[i + 0] = temp[0];
-                        PAYLOAD[i + 1] = temp[1];
-                        PAYLOAD[i + 2] = temp[2];
-                        PAYLOAD[i + 3] = temp[3];
+            let pending = self.write_pending();
+            self.handle_event(unsafe { &EVENT[..read] });
+            if pending && self.write_pending() {
+                self.start_write();
+            }
+        }
+    }
 
-                        i = i + 4;
+    /// Move the scan along on an HCI event from the core, and deliver the
+    /// advertisements it reports.
+    fn handle_event(&self, event: &[u8]) {
+        if let Some((opcode, status)) = command_complete(event) {
+            match (self.scan.get(), opcode) {
+                (Scan::SettingParameters, HCI_LE_SET_SCAN_PARAMETERS)
+                | (Scan::Enabling, HCI_LE_SET_SCAN_ENABLE)
+                    if status != 0 =>
+                {
+                    // The core refused to scan
+                    self.scan.set(Scan::Off);
+                    self.receiving.set(false);
+                    self.rx_client.map(|client| unsafe {
+                        client.receive_event(
+                            &mut PAYLOAD,
+                            0,
+                            ble_advertising::RSSI_UNAVAILABLE,
+                            kernel::ReturnCode::FAIL,
+                        )
+                    });
+                }
+                (Scan::SettingParameters, HCI_LE_SET_SCAN_PARAMETERS) => {
+                    self.scan.set(Scan::Enabling);
+                    self.send_command(HCI_LE_SET_SCAN_ENABLE, &SCAN_ENABLE);
+                }
+                (Scan::Enabling, HCI_LE_SET_SCAN_ENABLE) => self.scan.set(Scan::On),
+                (Scan::Disabling, HCI_LE_SET_SCAN_ENABLE) => {
+                    self.scan.set(Scan::Off);
+                    if self.receiving.get() {
+                        // Asked to receive again while the scan was stopping
+                        self.start_scan();
+                    } else if self.write_pending() {
+                        // An advertisement waited for the scan to stop
+                        self.start_write();
                     }
-
-                    client.receive_event(&mut PAYLOAD, 10, kernel::ReturnCode::SUCCESS);
                 }
+                _ => {}
+            }
+            return;
+        }
+
+        if self.scan.get() != Scan::On || !self.receiving.get() {
+            return;
+        }
+        if let Some((len, rssi)) = unsafe { advertising_report(event, &mut PAYLOAD) } {
+            self.receiving.set(false);
+            self.rx_client.map(|client| unsafe {
+                client.receive_event(&mut PAYLOAD, len as u8, rssi, kernel::ReturnCode::SUCCESS)
             });
+            // The client receives again from its callback to keep scanning
+            if !self.receiving.get() {
+                self.stop_scan();
+            }
         }
     }
 
+    /// Read an HCI event out of the FIFO into `EVENT`, returning the number
+    /// of bytes read.
+    fn read_packet(&self) -> usize {
+        self.registers
+            .cmd
+            .modify(CMD::TSIZE.val(0) + CMD::CMD::READ);
+
+        let mut i = 0;
+        unsafe {
+            while self.registers.fifoptr.read(FIFOPTR::FIFO1SIZ) > 0 && i < EVENT.len() {
+                let temp = self.registers.fifopop.get().to_ne_bytes();
+
+                EVENT[i..i + 4].copy_from_slice(&temp);
+
+                i = i + 4;
+            }
+        }
+        i
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.inten.set(0x18381);
     }
@@ -450,20 +717,20 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
         self.read_len.set(0);
         self.read_index.set(0);
 
-        // Enable interrupts
-        self.enable_interrupts();
-
-        // Wakeup BLE
-        self.registers.blecfg.modify(BLECFG::WAKEUPCTL::ON);
-
-        // See if we can send the data
-        if self.registers.bstatus.is_set(BSTATUS::SPISTATUS) {
-            self.send_data();
+        // A scan still being stopped sends the advertisement once it has
+        if self.write_pending() {
+            self.start_write();
         }
     }
 
+    // The BLE core hops between the advertising channels itself, so the
+    // channel is not passed on. Once scanning, the core keeps reporting
+    // advertisements for as long as the client keeps receiving.
     fn receive_advertisement(&self, _channel: RadioChannel) {
-        unimplemented!();
+        self.receiving.set(true);
+        if self.scan.get() == Scan::Off {
+            self.start_scan();
+        }
     }
 
     fn set_receive_client(&self, client: &'a dyn ble_advertising::RxClient) {
@@ -473,6 +740,36 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     fn set_transmit_client(&self, client: &'a dyn ble_advertising::TxClient) {
         self.tx_client.set(client);
     }
+
+    fn power_down(&self) -> kernel::ReturnCode {
+        if self.is_busy() {
+            return kernel::ReturnCode::EBUSY;
+        }
+
+        // Let the BLE core go back to sleep
+        self.disable_interrupts();
+        self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
+        kernel::ReturnCode::SUCCESS
+    }
+
+    fn abort(&self) -> Option<&'static mut [u8]> {
+        self.disable_interrupts();
+
+        // Stop the DMA mid transfer and drop anything left in the FIFOs
+        self.registers.dmacfg.set(0x00000000);
+        self.reset_fifo();
+
+        self.registers.blecfg.modify(BLECFG::WAKEUPCTL::OFF);
+        self.receiving.set(false);
+        self.writing.set(false);
+        self.command_len.set(0);
+
+        // Leave the core not scanning
+        if self.scan.get() != Scan::Off {
+            self.stop_scan();
+        }
+        self.buffer.take()
+    }
 }
 
 impl ble_advertising::BleConfig for Ble<'_> {
@@ -480,3 +777,104 @@ impl ble_advertising::BleConfig for Ble<'_> {
         kernel::ReturnCode::SUCCESS
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{advertising_report, command_complete, hci_command};
+    use super::{HCI_LE_SET_SCAN_ENABLE, HCI_LE_SET_SCAN_PARAMETERS, SCAN_PARAMETERS};
+    use kernel::hil::ble_advertising::RSSI_UNAVAILABLE;
+
+    /// An LE Advertising Report event for one advertisement from `addr`.
+    fn report(event_type: u8, addr_type: u8, data: &[u8], rssi: i8, event: &mut [u8]) -> usize {
+        let params = 12 + data.len();
+        event[..3].copy_from_slice(&[0x04, 0x3e, params as u8]);
+        event[3..7].copy_from_slice(&[0x02, 1, event_type, addr_type]);
+        event[7..13].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+        event[13] = data.len() as u8;
+        event[14..14 + data.len()].copy_from_slice(data);
+        event[14 + data.len()] = rssi as u8;
+        3 + params
+    }
+
+    #[test]
+    fn scan_commands() {
+        let mut buf = [0; 16];
+        assert_eq!(
+            hci_command(HCI_LE_SET_SCAN_PARAMETERS, &SCAN_PARAMETERS, &mut buf),
+            11
+        );
+        assert_eq!(buf[..4], [0x01, 0x0b, 0x20, 7]);
+        assert_eq!(hci_command(HCI_LE_SET_SCAN_ENABLE, &[1, 0], &mut buf), 6);
+        assert_eq!(buf[..6], [0x01, 0x0c, 0x20, 2, 1, 0]);
+    }
+
+    #[test]
+    fn command_complete_opcode_and_status() {
+        let event = [0x04, 0x0e, 4, 1, 0x0c, 0x20, 0x00, 0xaa, 0xaa];
+        assert_eq!(command_complete(&event), Some((HCI_LE_SET_SCAN_ENABLE, 0)));
+        let event = [0x04, 0x0e, 4, 1, 0x0b, 0x20, 0x0c];
+        assert_eq!(
+            command_complete(&event),
+            Some((HCI_LE_SET_SCAN_PARAMETERS, 0x0c))
+        );
+        // Command Status is not a completion, and a short event is dropped.
+        assert_eq!(command_complete(&[0x04, 0x0f, 4, 0, 1, 0x0c, 0x20]), None);
+        assert_eq!(command_complete(&[0x04, 0x0e, 4, 1, 0x0c]), None);
+    }
+
+    #[test]
+    fn report_is_rebuilt_into_pdu() {
+        let mut event = [0; 64];
+        let mut pdu = [0; 40];
+        // ADV_NONCONN_IND from a random address with 3 bytes of data.
+        let read = report(0x03, 0x01, &[2, 1, 6], -67, &mut event);
+        assert_eq!(
+            advertising_report(&event[..read], &mut pdu),
+            Some((11, -67))
+        );
+        assert_eq!(pdu[..11], [0x42, 9, 1, 2, 3, 4, 5, 6, 2, 1, 6]);
+        // ADV_SCAN_IND from a public address, read with the FIFO padding.
+        let read = report(0x02, 0x00, &[], 4, &mut event);
+        assert_eq!(
+            advertising_report(&event[..read + 3], &mut pdu),
+            Some((8, 4))
+        );
+        assert_eq!(pdu[..2], [0x06, 6]);
+    }
+
+    #[test]
+    fn rssi_is_taken_from_report() {
+        let mut event = [0; 64];
+        let mut pdu = [0; 40];
+        let read = report(0x00, 0x00, &[0xff], 127, &mut event);
+        assert_eq!(
+            advertising_report(&event[..read], &mut pdu),
+            Some((9, RSSI_UNAVAILABLE))
+        );
+        let read = report(0x00, 0x00, &[0xff], -127, &mut event);
+        assert_eq!(
+            advertising_report(&event[..read], &mut pdu),
+            Some((9, -127))
+        );
+    }
+
+    #[test]
+    fn malformed_report_is_rejected() {
+        let mut event = [0; 64];
+        let mut pdu = [0; 40];
+        let read = report(0x00, 0x00, &[1, 2, 3], -40, &mut event);
+        // Truncated before the RSSI.
+        assert_eq!(advertising_report(&event[..read - 1], &mut pdu), None);
+        // Unknown event type.
+        event[5] = 0x05;
+        assert_eq!(advertising_report(&event[..read], &mut pdu), None);
+        // More than one report.
+        event[5] = 0x00;
+        event[4] = 2;
+        assert_eq!(advertising_report(&event[..read], &mut pdu), None);
+        // Not an advertising report.
+        event[4] = 1;
+        event[3] = 0x01;
+        assert_eq!(advertising_report(&event[..read], &mut pdu), None);
+    }
+}
@@ -1,15 +1,77 @@
 //! Chip trait setup.
+//!
+//! ### Sleep depth
+//!
+//! `sleep()` chooses between normal sleep, where the HFRC and peripheral
+//! clocks keep running, and deep sleep, where the power controller stops the
+//! HFRC and gates the clocks of all peripherals. Only the crystal and LFRC
+//! keep running in deep sleep. Deep sleep is only selected when the deep
+//! sleep veto allows it and no peripheral reports that it needs its clock:
+//!
+//! - STimer: when it counts from the HFRC. Counting from the crystal, as it
+//!   does once started, does not block deep sleep.
+//! - IOM: while an I2C transfer is in progress.
+//! - UART: while a transmission is in progress.
+//! - BLE: while an advertisement is being transferred to the BLE core, or
+//!   while the core is listening for one.
+//!
+//! GPIO interrupts wake the chip from deep sleep and do not block it.
 
+use core::cell::Cell;
 use core::fmt::Write;
 use cortexm4;
+use kernel::common::cells::OptionalCell;
 use kernel::Chip;
+use kernel::DeepSleepVeto;
 use kernel::InterruptService;
+use kernel::WakeSource;
+
+/// Peripherals whose clocks must keep running while the chip sleeps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClocksInUse {
+    pub stimer: bool,
+    pub iom: bool,
+    pub uart: bool,
+    pub ble: bool,
+}
+
+impl ClocksInUse {
+    pub fn any(&self) -> bool {
+        self.stimer || self.iom || self.uart || self.ble
+    }
+}
+
+/// Reports which peripherals currently need their clocks.
+pub trait ClockUsers {
+    fn clocks_in_use(&self) -> ClocksInUse;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SleepDepth {
+    Normal,
+    Deep,
+}
+
+/// The sleep depth to select, given whether deep sleep is vetoed (`None` if
+/// there is no veto source) and which peripherals need their clocks.
+fn sleep_depth(vetoed: Option<bool>, clocks: ClocksInUse) -> SleepDepth {
+    match vetoed {
+        Some(false) if !clocks.any() => SleepDepth::Deep,
+        _ => SleepDepth::Normal,
+    }
+}
 
 pub struct Apollo3<I: InterruptService<()> + 'static> {
     mpu: cortexm4::mpu::MPU,
     userspace_kernel_boundary: cortexm4::syscall::SysCall,
     scheduler_timer: cortexm4::systick::SysTick,
+    watchdog: crate::wdt::WatchDog,
     interrupt_service: &'static I,
+    interrupt_priority: Cell<&'static [u32]>,
+    deep_sleep_veto: OptionalCell<&'static dyn DeepSleepVeto>,
+    clock_users: OptionalCell<&'static dyn ClockUsers>,
+    last_wake: Cell<Option<u32>>,
+    wake_counts: [Cell<u32>; 32],
 }
 
 impl<I: InterruptService<()> + 'static> Apollo3<I> {
@@ -18,9 +80,87 @@ impl<I: InterruptService<()> + 'static> Apollo3<I> {
             mpu: cortexm4::mpu::MPU::new(),
             userspace_kernel_boundary: cortexm4::syscall::SysCall::new(),
             scheduler_timer: cortexm4::systick::SysTick::new_with_calibration(48_000_000),
+            watchdog: crate::wdt::WatchDog::new(),
             interrupt_service,
+            interrupt_priority: Cell::new(&[]),
+            deep_sleep_veto: OptionalCell::empty(),
+            clock_users: OptionalCell::empty(),
+            last_wake: Cell::new(None),
+            wake_counts: Default::default(),
         }
     }
+
+    /// Start the hardware watchdog when the kernel loop starts, with
+    /// `wdt::DEFAULT_TIMEOUT_MS` unless its timeout is changed through
+    /// `watchdog()`.
+    pub fn enable_watchdog(&self) {
+        self.watchdog.enable();
+    }
+
+    /// Set the order in which pending interrupts are serviced.
+    ///
+    /// By default `service_pending_interrupts()` handles pending interrupts
+    /// starting with the lowest interrupt number. A board can pass a list of
+    /// NVIC interrupt numbers (see `apollo3::nvic`) here, most important
+    /// first, and whenever any of those are pending they are serviced before
+    /// all other interrupts. Interrupts not in the list keep the default
+    /// ordering. This does not change the hardware NVIC priorities, only the
+    /// order bottom halves run in within a single pass of the kernel loop.
+    ///
+    /// ```rust,ignore
+    /// static INTERRUPT_PRIORITY: [u32; 2] = [apollo3::nvic::GPIO, apollo3::nvic::UART0];
+    /// chip.set_interrupt_priority(&INTERRUPT_PRIORITY);
+    /// ```
+    pub fn set_interrupt_priority(&self, interrupts: &'static [u32]) {
+        self.interrupt_priority.set(interrupts);
+    }
+
+    /// Register the source of vetoes against deep sleep.
+    ///
+    /// The chip only selects deep sleep once a veto source has been
+    /// registered, so that there is always a way to keep it in normal sleep,
+    /// and then only while the source does not veto it.
+    pub fn set_deep_sleep_veto(&self, veto: &'static dyn DeepSleepVeto) {
+        self.deep_sleep_veto.set(veto);
+    }
+
+    /// Register the peripherals whose activity blocks deep sleep, usually
+    /// `Apollo3DefaultPeripherals`. Until they are registered only the deep
+    /// sleep veto is consulted, so a board that allows deep sleep must
+    /// register them to keep transfers in flight from losing their clock.
+    pub fn set_clock_users(&self, users: &'static dyn ClockUsers) {
+        self.clock_users.set(users);
+    }
+
+    /// The depth the next `sleep()` would select.
+    pub fn sleep_depth(&self) -> SleepDepth {
+        let vetoed = self.deep_sleep_veto.map(|veto| veto.deep_sleep_vetoed());
+        let clocks = self
+            .clock_users
+            .map_or(ClocksInUse::default(), |users| users.clocks_in_use());
+        sleep_depth(vetoed, clocks)
+    }
+
+    /// Note which interrupt woke the chip. The kernel sleeps with interrupts
+    /// disabled, so the interrupt that woke it is still pending here. Only
+    /// the lowest numbered one is recorded if several are pending.
+    unsafe fn record_wake(&self) {
+        let source = cortexm4::nvic::next_pending();
+        self.last_wake.set(source);
+        if let Some(count) = source.and_then(|interrupt| self.wake_counts.get(interrupt as usize)) {
+            count.set(count.get().saturating_add(1));
+        }
+    }
+
+    /// Returns the highest priority pending interrupt, if any.
+    unsafe fn next_pending(&self) -> Option<u32> {
+        self.interrupt_priority
+            .get()
+            .iter()
+            .find(|&&interrupt| cortexm4::nvic::Nvic::new(interrupt).is_pending())
+            .copied()
+            .or_else(|| cortexm4::nvic::next_pending())
+    }
 }
 
 /// This struct, when initialized, instantiates all peripheral drivers for the apollo3.
@@ -59,6 +199,20 @@ impl Apollo3DefaultPeripherals {
     }
 }
 
+impl ClockUsers for Apollo3DefaultPeripherals {
+    fn clocks_in_use(&self) -> ClocksInUse {
+        let ioms = [
+            &self.iom0, &self.iom1, &self.iom2, &self.iom3, &self.iom4, &self.iom5,
+        ];
+        ClocksInUse {
+            stimer: self.stimer.needs_high_frequency_clock(),
+            iom: ioms.iter().any(|iom| iom.is_busy()),
+            uart: self.uart0.is_busy() || self.uart1.is_busy(),
+            ble: self.ble.is_busy(),
+        }
+    }
+}
+
 impl kernel::InterruptService<()> for Apollo3DefaultPeripherals {
     unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
         use crate::nvic;
@@ -87,12 +241,12 @@ impl<I: InterruptService<()> + 'static> Chip for Apollo3<I> {
     type MPU = cortexm4::mpu::MPU;
     type UserspaceKernelBoundary = cortexm4::syscall::SysCall;
     type SchedulerTimer = cortexm4::systick::SysTick;
-    type WatchDog = ();
+    type WatchDog = crate::wdt::WatchDog;
 
     fn service_pending_interrupts(&self) {
         unsafe {
             loop {
-                if let Some(interrupt) = cortexm4::nvic::next_pending() {
+                if let Some(interrupt) = self.next_pending() {
                     if !self.interrupt_service.service_interrupt(interrupt) {
                         panic!("unhandled interrupt, {}", interrupt);
                     }
@@ -120,7 +274,7 @@ impl<I: InterruptService<()> + 'static> Chip for Apollo3<I> {
     }
 
     fn watchdog(&self) -> &Self::WatchDog {
-        &()
+        &self.watchdog
     }
 
     fn userspace_kernel_boundary(&self) -> &cortexm4::syscall::SysCall {
@@ -129,8 +283,12 @@ impl<I: InterruptService<()> + 'static> Chip for Apollo3<I> {
 
     fn sleep(&self) {
         unsafe {
-            cortexm4::scb::unset_sleepdeep();
+            match self.sleep_depth() {
+                SleepDepth::Deep => cortexm4::scb::set_sleepdeep(),
+                SleepDepth::Normal => cortexm4::scb::unset_sleepdeep(),
+            }
             cortexm4::support::wfi();
+            self.record_wake();
         }
     }
 
@@ -145,3 +303,68 @@ impl<I: InterruptService<()> + 'static> Chip for Apollo3<I> {
         cortexm4::print_cortexm4_state(write);
     }
 }
+
+impl<I: InterruptService<()> + 'static> WakeSource for Apollo3<I> {
+    fn last_wake_source(&self) -> Option<u32> {
+        self.last_wake.get()
+    }
+
+    fn wake_count(&self, interrupt: u32) -> u32 {
+        self.wake_counts
+            .get(interrupt as usize)
+            .map_or(0, |count| count.get())
+    }
+
+    fn reset_wake_counts(&self) {
+        for count in self.wake_counts.iter() {
+            count.set(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_sleep_needs_veto_source() {
+        assert_eq!(
+            sleep_depth(None, ClocksInUse::default()),
+            SleepDepth::Normal
+        );
+        assert_eq!(
+            sleep_depth(Some(true), ClocksInUse::default()),
+            SleepDepth::Normal
+        );
+        assert_eq!(
+            sleep_depth(Some(false), ClocksInUse::default()),
+            SleepDepth::Deep
+        );
+    }
+
+    #[test]
+    fn busy_peripheral_blocks_deep_sleep() {
+        let busy = [
+            ClocksInUse {
+                stimer: true,
+                ..Default::default()
+            },
+            ClocksInUse {
+                iom: true,
+                ..Default::default()
+            },
+            ClocksInUse {
+                uart: true,
+                ..Default::default()
+            },
+            ClocksInUse {
+                ble: true,
+                ..Default::default()
+            },
+        ];
+        for clocks in busy.iter() {
+            assert_eq!(sleep_depth(Some(false), *clocks), SleepDepth::Normal);
+            assert_eq!(sleep_depth(Some(true), *clocks), SleepDepth::Normal);
+        }
+    }
+}
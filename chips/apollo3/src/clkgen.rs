@@ -1,4 +1,15 @@
 //! Power Reset Clock Interrupt controller driver.
+//!
+//! ### Clock sources
+//!
+//! The core and peripheral clocks always come from the 48 MHz internal RC
+//! oscillator (HFRC). On its own the HFRC is only factory trimmed, and its
+//! frequency drifts with temperature and supply voltage by enough (on the
+//! order of a percent) to upset high UART baud rates or BLE timing. Selecting
+//! `ClockSource::Crystal` starts the external 32.768 kHz crystal, which is
+//! accurate to tens of ppm, and lets the hardware periodically adjust the
+//! HFRC against it (HFADJ). The adjusted HFRC tracks the crystal on average,
+//! with small steps at each adjustment.
 
 use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
 use kernel::common::StaticRef;
@@ -11,12 +22,12 @@ register_structs! {
         (0x00 => calxt: ReadWrite<u32>),
         (0x04 => calrc: ReadWrite<u32>),
         (0x08 => acalctr: ReadWrite<u32>),
-        (0x0c => octrl: ReadWrite<u32>),
+        (0x0c => octrl: ReadWrite<u32, OCTRL::Register>),
         (0x10 => clkout: ReadWrite<u32>),
         (0x14 => clkkey: ReadWrite<u32>),
         (0x18 => cctrl: ReadWrite<u32>),
-        (0x1c => status: ReadWrite<u32>),
-        (0x20 => hfadj: ReadWrite<u32>),
+        (0x1c => status: ReadWrite<u32, STATUS::Register>),
+        (0x20 => hfadj: ReadWrite<u32, HFADJ::Register>),
         (0x24 => _reserved0),
         (0x28 => clockenstat: ReadWrite<u32>),
         (0x2c => clocken2stat: ReadWrite<u32>),
@@ -34,6 +45,33 @@ register_structs! {
 }
 
 register_bitfields![u32,
+    OCTRL [
+        STOPXT OFFSET(0) NUMBITS(1) [],
+        STOPRC OFFSET(1) NUMBITS(1) [],
+        FOS OFFSET(6) NUMBITS(1) [],
+        OSEL OFFSET(7) NUMBITS(1) [
+            XT = 0x0,
+            LFRC = 0x1
+        ],
+        ACAL OFFSET(8) NUMBITS(3) []
+    ],
+    STATUS [
+        OSCF OFFSET(0) NUMBITS(1) [],
+        OMODE OFFSET(1) NUMBITS(1) []
+    ],
+    HFADJ [
+        HFADJEN OFFSET(0) NUMBITS(1) [],
+        HFADJCK OFFSET(1) NUMBITS(3) [
+            Sec4 = 0x0
+        ],
+        HFXTADJ OFFSET(8) NUMBITS(12) [],
+        HFWARMUP OFFSET(20) NUMBITS(1) [
+            Sec1 = 0x0
+        ],
+        HFADJGAIN OFFSET(21) NUMBITS(3) [
+            GainOneHalf = 0x1
+        ]
+    ],
     BLEBUCKTONADJ [
         TONLOWTHRESHOLD OFFSET(0) NUMBITS(10) [],
         TONHIGHTHRESHOLD OFFSET(10) NUMBITS(10) [],
@@ -51,6 +89,20 @@ pub enum ClockFrequency {
     Freq48MHz,
 }
 
+/// Reference the HFRC is calibrated against. See the module documentation
+/// for the accuracy of each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The internal RC oscillator alone.
+    InternalRc,
+    /// The internal RC oscillator, adjusted against the external 32.768 kHz
+    /// crystal.
+    Crystal,
+}
+
+/// HFRC cycles per crystal cycle, the target of the HFRC adjustment.
+const HFXTADJ_48MHZ: u32 = 48_000_000 / 32_768;
+
 pub struct ClkGen {
     registers: StaticRef<ClkGenRegisters>,
 }
@@ -75,6 +127,55 @@ impl ClkGen {
         };
     }
 
+    /// Select the reference for the core clock.
+    ///
+    /// Selecting the crystal starts it and returns right away. A 32.768 kHz
+    /// crystal can take up to about a second to stabilize, so the hardware
+    /// waits that long before it first adjusts the HFRC, which runs
+    /// unadjusted until then. If the crystal does not start, the HFRC stays
+    /// unadjusted, and `clock_source()` reads `ClockSource::InternalRc` once
+    /// the failure is detected.
+    pub fn set_clock_source(&self, source: ClockSource) {
+        let regs = self.registers;
+
+        match source {
+            ClockSource::InternalRc => {
+                regs.hfadj.modify(HFADJ::HFADJEN::CLEAR);
+            }
+            ClockSource::Crystal => {
+                // Run the crystal, and have the RTC fail over to the LFRC if it
+                // stops so that a failure shows up in `STATUS`.
+                regs.octrl
+                    .modify(OCTRL::STOPXT::CLEAR + OCTRL::OSEL::XT + OCTRL::FOS::SET);
+
+                // The first adjustment waits out the crystal's warm-up.
+                regs.hfadj.write(
+                    HFADJ::HFADJGAIN::GainOneHalf
+                        + HFADJ::HFWARMUP::Sec1
+                        + HFADJ::HFXTADJ.val(HFXTADJ_48MHZ)
+                        + HFADJ::HFADJCK::Sec4
+                        + HFADJ::HFADJEN::SET,
+                );
+            }
+        }
+    }
+
+    /// The reference the core clock is currently calibrated against. This
+    /// reads `InternalRc` if the crystal was selected but has since failed.
+    pub fn clock_source(&self) -> ClockSource {
+        if self.registers.hfadj.is_set(HFADJ::HFADJEN) && self.crystal_running() {
+            ClockSource::Crystal
+        } else {
+            ClockSource::InternalRc
+        }
+    }
+
+    fn crystal_running(&self) -> bool {
+        let regs = self.registers;
+
+        !regs.status.is_set(STATUS::OSCF) && !regs.status.is_set(STATUS::OMODE)
+    }
+
     pub fn enable_ble(&self) {
         let regs = self.registers;
 
@@ -0,0 +1,327 @@
+//! Internal flash programming.
+//!
+//! The Apollo3 programs its flash through helper functions in the bootrom
+//! rather than through registers. The helpers run from the bootrom, block
+//! until the operation is done and do not use interrupts, so `erase_page()`
+//! and `write_words()` also work from a panic handler. The caller is stalled
+//! for the whole operation, a page erase takes tens of milliseconds.
+//!
+//! `FlashCtrl` is a `hil::flash` driver on top of the same helpers, for
+//! capsules that keep data in flash. It only operates on the pages of the
+//! region the board gives it, so that a capsule cannot erase the kernel or
+//! apps. Each operation is done right away, and the client is called from a
+//! deferred call. Several `FlashCtrl` can be used for different regions.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let flash = static_init!(
+//!     apollo3::flashctrl::FlashCtrl,
+//!     apollo3::flashctrl::FlashCtrl::new(REGION_START, REGION_LEN, dynamic_deferred_caller)
+//! );
+//! flash.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(flash)
+//!         .expect("no deferred call slot available for the flash"),
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil;
+use kernel::ReturnCode;
+
+/// Size of a flash page, the unit of erasure.
+pub const PAGE_SIZE: usize = 8192;
+
+/// End of the internal flash.
+pub const FLASH_END: usize = 0x0010_0000;
+
+/// Pages per flash instance. The flash is split into two instances of
+/// 512 KiB, each addressed by its own page numbers.
+const PAGES_PER_INSTANCE: usize = 64;
+
+/// Key the helpers require to modify the flash.
+const PROGRAM_KEY: u32 = 0x1234_4321;
+
+/// Bootrom entry of `flash_page_erase(key, instance, page)`.
+const ROM_PAGE_ERASE: usize = 0x0800_0051;
+
+/// Bootrom entry of `flash_program_main(key, src, dst, words)`.
+const ROM_PROGRAM_MAIN: usize = 0x0800_0055;
+
+type PageErase = unsafe extern "C" fn(u32, u32, u32) -> i32;
+type ProgramMain = unsafe extern "C" fn(u32, *const u32, *mut u32, u32) -> i32;
+
+/// Erase the flash page starting at `address`.
+///
+/// Returns `EINVAL` if `address` is not the start of a page.
+///
+/// # Safety
+///
+/// The page must not hold code or data in use, including the kernel.
+pub unsafe fn erase_page(address: usize) -> ReturnCode {
+    if address % PAGE_SIZE != 0 || address >= FLASH_END {
+        return ReturnCode::EINVAL;
+    }
+    let page = address / PAGE_SIZE;
+    let erase: PageErase = core::mem::transmute(ROM_PAGE_ERASE);
+    let result = erase(
+        PROGRAM_KEY,
+        (page / PAGES_PER_INSTANCE) as u32,
+        (page % PAGES_PER_INSTANCE) as u32,
+    );
+    if result == 0 {
+        ReturnCode::SUCCESS
+    } else {
+        ReturnCode::FAIL
+    }
+}
+
+/// Program `words` into erased flash starting at `address`.
+///
+/// Returns `EINVAL` if `address` is not word aligned or the words do not fit
+/// in the flash, and `FAIL` if the flash does not read back what was written.
+///
+/// # Safety
+///
+/// The range must not hold code or data in use, including the kernel.
+pub unsafe fn write_words(address: usize, words: &[u32]) -> ReturnCode {
+    if address % 4 != 0 || address + words.len() * 4 > FLASH_END {
+        return ReturnCode::EINVAL;
+    }
+    let program: ProgramMain = core::mem::transmute(ROM_PROGRAM_MAIN);
+    let result = program(
+        PROGRAM_KEY,
+        words.as_ptr(),
+        address as *mut u32,
+        words.len() as u32,
+    );
+    if result != 0 {
+        return ReturnCode::FAIL;
+    }
+    let written = core::slice::from_raw_parts(address as *const u32, words.len());
+    if written == words {
+        ReturnCode::SUCCESS
+    } else {
+        ReturnCode::FAIL
+    }
+}
+
+/// A page of flash, as read and written by `FlashCtrl`.
+pub struct Apollo3Page(pub [u8; PAGE_SIZE]);
+
+impl Default for Apollo3Page {
+    fn default() -> Self {
+        Self { 0: [0; PAGE_SIZE] }
+    }
+}
+
+impl AsMut<[u8]> for Apollo3Page {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Whether `page` is one of the `num_pages` pages from `first_page`, and in
+/// the flash.
+fn page_in_region(page: usize, first_page: usize, num_pages: usize) -> bool {
+    page >= first_page && page - first_page < num_pages && page * PAGE_SIZE < FLASH_END
+}
+
+/// The operation whose client callback is due.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Idle,
+    Read,
+    Write,
+    Erase,
+}
+
+/// `hil::flash` driver for a region of the internal flash.
+pub struct FlashCtrl {
+    /// Page number of the first page of the region, and number of pages.
+    first_page: usize,
+    num_pages: usize,
+    client: OptionalCell<&'static dyn hil::flash::Client<FlashCtrl>>,
+    buffer: TakeCell<'static, Apollo3Page>,
+    operation: Cell<Operation>,
+    result: Cell<hil::flash::Error>,
+    deferred_caller: &'static DynamicDeferredCall,
+    deferred_handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl FlashCtrl {
+    /// A driver for the `len` bytes of flash from `start`. Page numbers are
+    /// those of the whole flash, so the first page of the region is
+    /// `start / PAGE_SIZE`. Both must be multiples of `PAGE_SIZE`.
+    ///
+    /// # Safety
+    ///
+    /// The region must not hold code or data in use, including the kernel
+    /// and apps.
+    pub unsafe fn new(
+        start: usize,
+        len: usize,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> FlashCtrl {
+        FlashCtrl {
+            first_page: start / PAGE_SIZE,
+            num_pages: len / PAGE_SIZE,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            result: Cell::new(hil::flash::Error::CommandComplete),
+            deferred_caller: deferred_caller,
+            deferred_handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.deferred_handle.set(handle);
+    }
+
+    /// Whether an operation on `page_number` can start now.
+    fn check(&self, page_number: usize) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            ReturnCode::EBUSY
+        } else if !page_in_region(page_number, self.first_page, self.num_pages) {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Call the client for `operation` once the kernel gets to it.
+    fn complete(&self, operation: Operation, result: ReturnCode) {
+        self.operation.set(operation);
+        self.result.set(if result == ReturnCode::SUCCESS {
+            hil::flash::Error::CommandComplete
+        } else {
+            hil::flash::Error::FlashError
+        });
+        self.deferred_handle
+            .map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    /// Erase the page and program it with the contents of `page`.
+    unsafe fn program_page(address: usize, page: &Apollo3Page) -> ReturnCode {
+        let erased = erase_page(address);
+        if erased != ReturnCode::SUCCESS {
+            return erased;
+        }
+        // The helper takes words, so program the page a chunk at a time.
+        let mut words = [0u32; 64];
+        for (i, chunk) in page.0.chunks(words.len() * 4).enumerate() {
+            for (word, bytes) in words.iter_mut().zip(chunk.chunks(4)) {
+                *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            let written = write_words(address + i * words.len() * 4, &words);
+            if written != ReturnCode::SUCCESS {
+                return written;
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+impl DynamicDeferredCallClient for FlashCtrl {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let operation = self.operation.replace(Operation::Idle);
+        let result = self.result.get();
+        self.client.map(|client| match operation {
+            Operation::Read => {
+                self.buffer
+                    .take()
+                    .map(|buffer| client.read_complete(buffer, result));
+            }
+            Operation::Write => {
+                self.buffer
+                    .take()
+                    .map(|buffer| client.write_complete(buffer, result));
+            }
+            Operation::Erase => client.erase_complete(result),
+            Operation::Idle => {}
+        });
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FlashCtrl {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for FlashCtrl {
+    type Page = Apollo3Page;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        let result = self.check(page_number);
+        if result != ReturnCode::SUCCESS {
+            return Err((result, buf));
+        }
+        // The flash is memory mapped.
+        let page = unsafe {
+            core::slice::from_raw_parts((page_number * PAGE_SIZE) as *const u8, PAGE_SIZE)
+        };
+        buf.0.copy_from_slice(page);
+        self.buffer.replace(buf);
+        self.complete(Operation::Read, ReturnCode::SUCCESS);
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        let result = self.check(page_number);
+        if result != ReturnCode::SUCCESS {
+            return Err((result, buf));
+        }
+        // The page is in the region the board set aside for this driver.
+        let result = unsafe { FlashCtrl::program_page(page_number * PAGE_SIZE, buf) };
+        self.buffer.replace(buf);
+        self.complete(Operation::Write, result);
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let result = self.check(page_number);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        // The page is in the region the board set aside for this driver.
+        let result = unsafe { erase_page(page_number * PAGE_SIZE) };
+        self.complete(Operation::Erase, result);
+        ReturnCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pages_of_the_region_are_used() {
+        // Pages 120 and 121.
+        assert!(page_in_region(120, 120, 2));
+        assert!(page_in_region(121, 120, 2));
+        assert!(!page_in_region(119, 120, 2));
+        assert!(!page_in_region(122, 120, 2));
+        assert!(!page_in_region(0, 120, 0));
+        // A region running past the end of the flash.
+        assert!(page_in_region(127, 126, 4));
+        assert!(!page_in_region(128, 126, 4));
+    }
+}
@@ -1,18 +1,76 @@
 //! General Purpose Input/Output driver.
 
+use core::cell::Cell;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicU32, Ordering};
 use enum_primitive::cast::FromPrimitive;
 use enum_primitive::enum_from_primitive;
+use kernel::capabilities::PinMuxCapability;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil::gpio;
+use kernel::ReturnCode;
 
 pub const GPIO_BASE_RAW: usize = 0x4001_0000; //safe to export outside crate
 
 const GPIO_BASE: StaticRef<GpioRegisters> =
     unsafe { StaticRef::new(GPIO_BASE_RAW as *const GpioRegisters) };
 
+/// `FNCSEL` value that makes a pad a GPIO. It is the same on every pad.
+const GPIO_FUNCTION: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Signal {
+    Uart0Tx,
+    Uart0Rx,
+    Iom2Sda,
+    Iom2Scl,
+    Iom4Sda,
+    Iom4Scl,
+}
+
+/// A peripheral signal that can be routed to a pad.
+struct Route {
+    pin: Pin,
+    function: u8,
+    signal: Signal,
+    input: bool,
+    open_drain: bool,
+}
+
+const fn route(pin: Pin, function: u8, signal: Signal, input: bool, open_drain: bool) -> Route {
+    Route {
+        pin,
+        function,
+        signal,
+        input,
+        open_drain,
+    }
+}
+
+/// The functions other than GPIO that pads can be switched to at runtime.
+/// Pads serve more functions than these, see the pad function tables in the
+/// Apollo3 datasheet. Listed are the UART0 and IOM2 routings this driver sets
+/// up in `enable_uart()` and `enable_i2c()`, which boards normally claim, and
+/// the other pads those signals and the IOM4 I2C signals can be moved to.
+const ROUTES: [Route; 8] = [
+    route(Pin::Pin48, 0, Signal::Uart0Tx, false, false),
+    route(Pin::Pin22, 0, Signal::Uart0Tx, false, false),
+    route(Pin::Pin49, 0, Signal::Uart0Rx, true, false),
+    route(Pin::Pin23, 0, Signal::Uart0Rx, true, false),
+    route(Pin::Pin25, 4, Signal::Iom2Sda, true, true),
+    route(Pin::Pin27, 4, Signal::Iom2Scl, true, true),
+    route(Pin::Pin40, 4, Signal::Iom4Sda, true, true),
+    route(Pin::Pin39, 4, Signal::Iom4Scl, true, true),
+];
+
+fn find_route(pin: Pin, function: u8) -> Option<&'static Route> {
+    ROUTES
+        .iter()
+        .find(|route| route.pin == pin && route.function == function)
+}
+
 pub struct Port<'a> {
     pins: [GpioPin<'a>; 50],
 }
@@ -109,9 +167,9 @@ impl Port<'_> {
         regs.int1clr.set(irqs);
 
         let mut count = 0;
-        while irqs != 0 && count < self.pins.len() {
+        while irqs != 0 && count + 32 < self.pins.len() {
             if (irqs & 0b1) != 0 {
-                self.pins[count].handle_interrupt();
+                self.pins[count + 32].handle_interrupt();
             }
             count += 1;
             irqs >>= 1;
@@ -120,6 +178,8 @@ impl Port<'_> {
 
     pub fn enable_uart(&self, tx_pin: &GpioPin, rx_pin: &GpioPin) {
         let regs = GPIO_BASE;
+        tx_pin.claimed.set(true);
+        rx_pin.claimed.set(true);
 
         match tx_pin.pin as usize {
             48 => {
@@ -152,6 +212,8 @@ impl Port<'_> {
 
     pub fn enable_i2c(&self, sda: &GpioPin, scl: &GpioPin) {
         let regs = GPIO_BASE;
+        sda.claimed.set(true);
+        scl.claimed.set(true);
 
         match sda.pin as usize {
             25 => {
@@ -193,6 +255,86 @@ impl Port<'_> {
     }
 }
 
+impl gpio::PadMux for Port<'_> {
+    fn set_pad_function(
+        &self,
+        pad: usize,
+        function: usize,
+        capability: &dyn PinMuxCapability,
+    ) -> ReturnCode {
+        if pad >= self.pins.len() || function > 7 {
+            return ReturnCode::EINVAL;
+        }
+        // A signal can only be on one pad at a time.
+        if let Some(route) = find_route(self.pins[pad].pin, function as u8) {
+            let elsewhere = ROUTES.iter().any(|other| {
+                other.signal == route.signal
+                    && other.pin != route.pin
+                    && self.pins[other.pin as usize].function() == other.function
+            });
+            if elsewhere {
+                return ReturnCode::EBUSY;
+            }
+        }
+        self.pins[pad].set_function(function as u8, capability)
+    }
+
+    fn pad_function(&self, pad: usize) -> Option<usize> {
+        self.pins.get(pad).map(|pin| pin.function() as usize)
+    }
+}
+
+/// Number of ports, read through `RDA` and `RDB`.
+const NUM_PORTS: usize = 2;
+
+/// The bits of `RDB` that belong to pins, 32 to 49.
+const PORT_B_PINS: u32 = (1 << 18) - 1;
+
+impl gpio::PortSnapshot for Port<'_> {
+    fn port_count(&self) -> usize {
+        NUM_PORTS
+    }
+
+    fn input_pins(&self, port: usize) -> u32 {
+        self.pins
+            .iter()
+            .skip(port * 32)
+            .take(32)
+            .enumerate()
+            .filter(|(_, pin)| pin.is_input())
+            .fold(0, |mask, (bit, _)| mask | 1 << bit)
+    }
+
+    fn read_ports(&self, mask: u32, values: &mut [u32]) -> ReturnCode {
+        if mask == 0 || mask >> NUM_PORTS != 0 {
+            return ReturnCode::EINVAL;
+        }
+        let count = mask.count_ones() as usize;
+        if values.len() < count {
+            return ReturnCode::ESIZE;
+        }
+
+        let regs = GPIO_BASE;
+        let snapshot = unsafe {
+            cortexm4::support::atomic(|| {
+                [
+                    if mask & 0b01 != 0 { regs.rda.get() } else { 0 },
+                    if mask & 0b10 != 0 {
+                        regs.rdb.get() & PORT_B_PINS
+                    } else {
+                        0
+                    },
+                ]
+            })
+        };
+        let selected = (0..NUM_PORTS).filter(|port| mask & 1 << port != 0);
+        for (value, port) in values.iter_mut().zip(selected) {
+            *value = snapshot[port];
+        }
+        ReturnCode::SuccessWithValue { value: count }
+    }
+}
+
 enum_from_primitive! {
     #[derive(Copy, Clone, Debug, PartialEq)]
     pub enum Pin {
@@ -478,10 +620,96 @@ register_bitfields![u32,
     ]
 ];
 
+const NO_EDGES: AtomicU32 = AtomicU32::new(0);
+
+/// Edges counted on the pins, see `gpio::EdgeCounter`. Edges are counted by
+/// `count_edges()` in the interrupt handler itself, as each one happens: by
+/// the time the kernel services the GPIO interrupt, several edges on a pin
+/// would show as a single `INTxSTAT` bit.
+struct EdgeCounts {
+    /// Pins counting edges, one bit per pin in each of the two interrupt
+    /// register banks.
+    counting: [AtomicU32; 2],
+    counts: [AtomicU32; 64],
+}
+
+static EDGES: EdgeCounts = EdgeCounts::new();
+
+impl EdgeCounts {
+    const fn new() -> EdgeCounts {
+        EdgeCounts {
+            counting: [NO_EDGES; 2],
+            counts: [NO_EDGES; 64],
+        }
+    }
+
+    fn start(&self, pin: usize) {
+        self.counts[pin].store(0, Ordering::Relaxed);
+        self.counting[pin / 32].fetch_or(1 << (pin % 32), Ordering::Release);
+    }
+
+    fn stop(&self, pin: usize) {
+        self.counting[pin / 32].fetch_and(!(1 << (pin % 32)), Ordering::Release);
+    }
+
+    /// Count the edges in `pending`, the interrupt status of register bank
+    /// `bank`, on pins that are counting them. Returns the bits counted.
+    ///
+    /// Only called from the GPIO interrupt, which does not preempt itself, so
+    /// the load and store of a count cannot race with another increment.
+    fn record(&self, bank: usize, pending: u32) -> u32 {
+        let counted = pending & self.counting[bank].load(Ordering::Acquire);
+        let mut bits = counted;
+        while bits != 0 {
+            let bit = bits.trailing_zeros() as usize;
+            let count = &self.counts[bank * 32 + bit];
+            count.store(
+                count.load(Ordering::Relaxed).saturating_add(1),
+                Ordering::Relaxed,
+            );
+            bits &= bits - 1;
+        }
+        counted
+    }
+
+    fn read(&self, pin: usize, reset: bool) -> u32 {
+        if reset {
+            self.counts[pin].swap(0, Ordering::Relaxed)
+        } else {
+            self.counts[pin].load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Count edges on pins that are counting them, and clear their interrupts.
+/// Called by the GPIO interrupt handler before anything else. Returns
+/// non-zero if other pin interrupts are left for `Port::handle_interrupt()`.
+pub extern "C" fn count_edges() -> u32 {
+    let regs = GPIO_BASE;
+
+    let irqs = regs.int0stat.get();
+    let counted = EDGES.record(0, irqs);
+    regs.int0clr.set(counted);
+    let mut left = irqs & !counted;
+
+    let irqs = regs.int1stat.get();
+    let counted = EDGES.record(1, irqs);
+    regs.int1clr.set(counted);
+    left |= irqs & !counted;
+
+    left
+}
+
 pub struct GpioPin<'a> {
     registers: StaticRef<GpioRegisters>,
     pin: Pin,
     client: OptionalCell<&'a dyn gpio::Client>,
+    /// Set while a level-triggered interrupt is armed. The hardware only
+    /// detects edges, so levels are emulated with the matching edge.
+    level: OptionalCell<gpio::InterruptLevel>,
+    /// Set once the board has given the pad to a peripheral, after which its
+    /// function cannot be changed at runtime.
+    claimed: Cell<bool>,
 }
 
 impl<'a> GpioPin<'a> {
@@ -490,11 +718,89 @@ impl<'a> GpioPin<'a> {
             registers: base,
             pin,
             client: OptionalCell::empty(),
+            level: OptionalCell::empty(),
+            claimed: Cell::new(false),
         }
     }
 
+    /// The function the pad is connected to, as its `FNCSEL` value.
+    pub fn function(&self) -> u8 {
+        let shift = (self.pin as usize % 4) * 8 + 3;
+        ((self.registers.padreg[self.pin as usize / 4].get() >> shift) & 0x7) as u8
+    }
+
+    /// Connect the pad to `function`, its `FNCSEL` value. Switching to GPIO
+    /// only changes the function; switching to a peripheral also sets up the
+    /// input enable and output mode that peripheral needs.
+    ///
+    /// Returns `EBUSY` if the pad was given to a peripheral by
+    /// `Port::enable_uart()` or `Port::enable_i2c()`, and `EINVAL` if the
+    /// function is not a known routing for this pad. This does not check
+    /// whether the function's signal is already on another pad;
+    /// `Port::set_pad_function()` does.
+    pub fn set_function(&self, function: u8, _capability: &dyn PinMuxCapability) -> ReturnCode {
+        if self.claimed.get() {
+            return ReturnCode::EBUSY;
+        }
+        let route = find_route(self.pin, function);
+        if function != GPIO_FUNCTION && route.is_none() {
+            return ReturnCode::EINVAL;
+        }
+
+        let regs = self.registers;
+        regs.padkey.set(115);
+
+        let padreg = &regs.padreg[self.pin as usize / 4];
+        let pad_shift = (self.pin as usize % 4) * 8;
+        let fncsel_mask = 0b111 << (pad_shift + 3);
+        padreg.set((padreg.get() & !fncsel_mask) | ((function as u32) << (pad_shift + 3)));
+
+        route.map(|route| {
+            let inpen = 1 << (pad_shift + 1);
+            if route.input {
+                padreg.set(padreg.get() | inpen);
+            } else {
+                padreg.set(padreg.get() & !inpen);
+            }
+
+            // Disable the GPIO interrupt and select push-pull or open-drain
+            // output.
+            let cfg = &regs.cfg[self.pin as usize / 8];
+            let cfg_shift = (self.pin as usize % 8) * 4;
+            let outcfg = if route.open_drain { 0x2 } else { 0x0 };
+            cfg.set((cfg.get() & !(0b1110 << cfg_shift)) | (outcfg << (cfg_shift + 1)));
+        });
+
+        regs.padkey.set(0x00);
+        ReturnCode::SUCCESS
+    }
+
+    /// Whether the pad is connected to GPIO with its input enabled and its
+    /// output disabled. Pins switched to output keep their input enabled, so
+    /// the input enable alone does not tell.
+    fn is_input(&self) -> bool {
+        let inpen_shift = (self.pin as usize % 4) * 8 + 1;
+        let inpen = (self.registers.padreg[self.pin as usize / 4].get() >> inpen_shift) & 1;
+        let outcfg_shift = (self.pin as usize % 8) * 4 + 1;
+        let outcfg = (self.registers.cfg[self.pin as usize / 8].get() >> outcfg_shift) & 0b11;
+        self.function() == GPIO_FUNCTION && inpen != 0 && outcfg == 0
+    }
+
     pub fn handle_interrupt(&self) {
-        unimplemented!();
+        // Counted edges are not passed on to the client. They are normally
+        // counted and cleared by `count_edges()`, but counting can start
+        // after the interrupt was taken.
+        let pin = self.pin as usize;
+        if EDGES.record(pin / 32, 1 << (pin % 32)) != 0 {
+            return;
+        }
+        // Level interrupts are one-shot: mask the pin until the client
+        // re-arms it, otherwise a pin held at the level would keep
+        // firing.
+        if self.level.is_some() {
+            gpio::Interrupt::disable_interrupts(self);
+        }
+        self.client.map(|client| client.fired());
     }
 }
 
@@ -503,12 +809,29 @@ impl<'a> gpio::Configure for GpioPin<'a> {
         unimplemented!();
     }
 
-    fn set_floating_state(&self, _mode: gpio::FloatingState) {
-        unimplemented!();
+    fn set_floating_state(&self, mode: gpio::FloatingState) {
+        let regs = self.registers;
+        let padreg = &regs.padreg[self.pin as usize / 4];
+        let pull = 1 << ((self.pin as usize % 4) * 8);
+
+        regs.padkey.set(115);
+        match mode {
+            gpio::FloatingState::PullUp => padreg.set(padreg.get() | pull),
+            // The pads have no pull-down, so this only disables the pull-up.
+            gpio::FloatingState::PullDown | gpio::FloatingState::PullNone => {
+                padreg.set(padreg.get() & !pull)
+            }
+        }
+        regs.padkey.set(0x00);
     }
 
     fn floating_state(&self) -> gpio::FloatingState {
-        unimplemented!();
+        let pull_shift = (self.pin as usize % 4) * 8;
+        if (self.registers.padreg[self.pin as usize / 4].get() >> pull_shift) & 1 != 0 {
+            gpio::FloatingState::PullUp
+        } else {
+            gpio::FloatingState::PullNone
+        }
     }
 
     fn deactivate_to_low_power(&self) {
@@ -559,7 +882,26 @@ impl<'a> gpio::Configure for GpioPin<'a> {
     }
 
     fn make_input(&self) -> gpio::Configuration {
-        unimplemented!();
+        let regs = self.registers;
+        regs.padkey.set(115);
+
+        // Configure the pin as GPIO and enable its input
+        let padreg = &regs.padreg[self.pin as usize / 4];
+        let pad_shift = (self.pin as usize % 4) * 8;
+        let fncsel_mask = 0b111 << (pad_shift + 3);
+        padreg.set(
+            (padreg.get() & !fncsel_mask)
+                | ((GPIO_FUNCTION as u32) << (pad_shift + 3))
+                | (1 << (pad_shift + 1)),
+        );
+
+        // Disable the output
+        let cfg = &regs.cfg[self.pin as usize / 8];
+        let outcfg_mask = 0b11 << ((self.pin as usize % 8) * 4 + 1);
+        cfg.set(cfg.get() & !outcfg_mask);
+
+        regs.padkey.set(0x00);
+        gpio::Configuration::Input
     }
 
     fn disable_input(&self) -> gpio::Configuration {
@@ -631,6 +973,8 @@ impl<'a> gpio::Interrupt<'a> for GpioPin<'a> {
 
     fn enable_interrupts(&self, mode: gpio::InterruptEdge) {
         let regs = self.registers;
+        self.level.clear();
+        EDGES.stop(self.pin as usize);
 
         // Set the key
         regs.padkey.set(115);
@@ -641,7 +985,7 @@ impl<'a> gpio::Interrupt<'a> for GpioPin<'a> {
             0 => PADREG::PAD0FNCSEL.val(0x3),
             1 => PADREG::PAD1FNCSEL.val(0x3),
             2 => PADREG::PAD2FNCSEL.val(0x3),
-            3 => PADREG::PAD2FNCSEL.val(0x3),
+            3 => PADREG::PAD3FNCSEL.val(0x3),
             _ => unreachable!(),
         };
         regs.padreg[pagreg_offset].modify(pagreg_value);
@@ -705,8 +1049,36 @@ impl<'a> gpio::Interrupt<'a> for GpioPin<'a> {
         regs.padkey.set(0x00);
     }
 
+    fn enable_level_interrupts(&self, level: gpio::InterruptLevel) -> ReturnCode {
+        let regs = self.registers;
+
+        match level {
+            gpio::InterruptLevel::High => self.enable_interrupts(gpio::InterruptEdge::RisingEdge),
+            gpio::InterruptLevel::Low => self.enable_interrupts(gpio::InterruptEdge::FallingEdge),
+        }
+        self.level.set(level);
+
+        // The edge has already passed if the pin is at the level, so
+        // pend the interrupt by hand.
+        let at_level = match level {
+            gpio::InterruptLevel::High => gpio::Input::read(self),
+            gpio::InterruptLevel::Low => !gpio::Input::read(self),
+        };
+        if at_level {
+            if (self.pin as usize) < 32 {
+                regs.int0set.set(1 << self.pin as usize);
+            } else {
+                regs.int1set.set(1 << (self.pin as usize - 32));
+            }
+        }
+
+        ReturnCode::SUCCESS
+    }
+
     fn disable_interrupts(&self) {
         let regs = self.registers;
+        self.level.clear();
+        EDGES.stop(self.pin as usize);
 
         // Disable interrupt
         if (self.pin as usize) < 32 {
@@ -714,7 +1086,7 @@ impl<'a> gpio::Interrupt<'a> for GpioPin<'a> {
                 .set(!(1 << self.pin as usize) & regs.int0en.get());
         } else {
             regs.int1en
-                .set(!(1 << (self.pin as usize - 32)) & regs.int0en.get());
+                .set(!(1 << (self.pin as usize - 32)) & regs.int1en.get());
         }
 
         // Clear interrupt
@@ -732,5 +1104,106 @@ impl<'a> gpio::Interrupt<'a> for GpioPin<'a> {
     }
 }
 
+impl gpio::EdgeCounter for GpioPin<'_> {
+    fn start_counting(&self, edge: gpio::InterruptEdge) -> ReturnCode {
+        gpio::Interrupt::enable_interrupts(self, edge);
+        EDGES.start(self.pin as usize);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_counting(&self) {
+        gpio::Interrupt::disable_interrupts(self);
+    }
+
+    fn edge_count(&self, reset: bool) -> u32 {
+        EDGES.read(self.pin as usize, reset)
+    }
+}
+
 impl<'a> gpio::Pin for GpioPin<'a> {}
 impl<'a> gpio::InterruptPin<'a> for GpioPin<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_route, EdgeCounts, Pin, Signal, ROUTES};
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    fn routes_match_pad_functions() {
+        let signal = |pin, function| find_route(pin, function).map(|route| route.signal);
+        assert_eq!(signal(Pin::Pin25, 4), Some(Signal::Iom2Sda));
+        assert_eq!(signal(Pin::Pin27, 4), Some(Signal::Iom2Scl));
+        assert_eq!(signal(Pin::Pin40, 4), Some(Signal::Iom4Sda));
+        assert_eq!(signal(Pin::Pin39, 4), Some(Signal::Iom4Scl));
+        assert_eq!(signal(Pin::Pin22, 0), Some(Signal::Uart0Tx));
+        assert_eq!(signal(Pin::Pin39, 0), None);
+    }
+
+    #[test]
+    fn signals_can_move_off_claimed_pads() {
+        // Pads `enable_uart()` and `enable_i2c()` claim.
+        let claimed = [Pin::Pin48, Pin::Pin49, Pin::Pin25, Pin::Pin27];
+        let signals = [
+            Signal::Uart0Tx,
+            Signal::Uart0Rx,
+            Signal::Iom4Sda,
+            Signal::Iom4Scl,
+        ];
+        for signal in signals.iter() {
+            assert!(ROUTES
+                .iter()
+                .any(|route| route.signal == *signal && !claimed.contains(&route.pin)));
+        }
+        // Each pad serves one signal.
+        for route in ROUTES.iter() {
+            assert_eq!(
+                ROUTES.iter().filter(|other| other.pin == route.pin).count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn edges_counted_only_while_counting() {
+        let edges = EdgeCounts::new();
+        assert_eq!(edges.record(0, 1 << 5), 0);
+        edges.start(5);
+        for _ in 0..3 {
+            assert_eq!(edges.record(0, 1 << 5), 1 << 5);
+        }
+        assert_eq!(edges.read(5, false), 3);
+        assert_eq!(edges.read(5, true), 3);
+        assert_eq!(edges.record(0, 1 << 5), 1 << 5);
+        assert_eq!(edges.read(5, false), 1);
+        // Starting again counts from zero.
+        edges.start(5);
+        assert_eq!(edges.read(5, false), 0);
+        edges.stop(5);
+        assert_eq!(edges.record(0, 1 << 5), 0);
+    }
+
+    #[test]
+    fn only_counting_pins_are_counted() {
+        let edges = EdgeCounts::new();
+        edges.start(33);
+        edges.start(40);
+        let pending = (1 << 1) | (1 << 2) | (1 << 8);
+        assert_eq!(edges.record(1, pending), (1 << 1) | (1 << 8));
+        assert_eq!(edges.record(0, pending), 0);
+        assert_eq!(edges.read(33, false), 1);
+        assert_eq!(edges.read(34, false), 0);
+        assert_eq!(edges.read(40, false), 1);
+        assert_eq!(edges.read(1, false), 0);
+    }
+
+    #[test]
+    fn edge_count_saturates() {
+        let edges = EdgeCounts::new();
+        edges.start(0);
+        edges.counts[0].store(u32::MAX - 1, Ordering::Relaxed);
+        edges.record(0, 1);
+        edges.record(0, 1);
+        assert_eq!(edges.read(0, true), u32::MAX);
+        assert_eq!(edges.read(0, false), 0);
+    }
+}
@@ -1,12 +1,47 @@
 //! IO Master Driver (I2C)
+//!
+//! Transfers go through the IOM FIFO, and the CPU is interrupted whenever
+//! enough data (for reads) or free space (for writes) is available, as set by
+//! the FIFO threshold. By default the threshold is half of the remaining
+//! transfer. `set_fifo_threshold()` fixes the threshold instead: a higher
+//! threshold means fewer interrupts and wakeups during large transfers, but
+//! the bus may stall waiting on the CPU for longer, and a lower threshold
+//! gives lower latency at the cost of more interrupts.
+//!
+//! Devices with 10-bit addresses are supported, the IOM sends the two byte
+//! address sequence itself. Writes to the I2C general call address (0x00)
+//! are sent like any other write. Reads from it are rejected with `NotSupported`, and a transfer that
+//! nothing acknowledges completes with `AddressNak`.
 
 use core::cell::Cell;
+use core::cmp;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::common::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::hil::i2c;
+use kernel::ReturnCode;
+
+/// Size in bytes of each of the read and write FIFOs, which is the largest
+/// usable FIFO threshold.
+pub const FIFO_DEPTH: u8 = 32;
+
+/// The I2C general call address.
+const GENERAL_CALL_ADDR: u8 = 0x00;
+
+/// Bus frequency of I2C transfers.
+const I2C_FREQ_HZ: u32 = 400_000;
+/// Bus frequency of SMBus transfers.
+const SMBUS_FREQ_HZ: u32 = 100_000;
+
+/// Frequency of the HFRC, the clock `CLKCFG::FSEL` divides.
+const HFRC_FREQ_HZ: u32 = 48_000_000;
+
+/// Number of times the interrupt status is polled before `probe()` gives up
+/// on a device that neither acknowledges nor refuses its address. Addressing
+/// a device at 400 kHz takes about 25 microseconds.
+const PROBE_POLLS: u32 = 100_000;
 
 const IOM0_BASE: StaticRef<IomRegisters> =
     unsafe { StaticRef::new(0x5000_4000 as *const IomRegisters) };
@@ -271,6 +306,8 @@ pub struct Iom<'a> {
     read_index: Cell<usize>,
 
     smbus: Cell<bool>,
+
+    fifo_threshold: OptionalCell<u8>,
 }
 
 impl<'a> Iom<'_> {
@@ -284,6 +321,7 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
         }
     }
     pub const fn new1() -> Iom<'a> {
@@ -296,6 +334,7 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
         }
     }
     pub const fn new2() -> Iom<'a> {
@@ -308,6 +347,7 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
         }
     }
     pub const fn new3() -> Iom<'a> {
@@ -320,6 +360,7 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
         }
     }
     pub const fn new4() -> Iom<'a> {
@@ -332,6 +373,7 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
         }
     }
     pub const fn new5() -> Iom<'a> {
@@ -344,7 +386,142 @@ impl<'a> Iom<'_> {
             read_len: Cell::new(0),
             read_index: Cell::new(0),
             smbus: Cell::new(false),
+            fifo_threshold: OptionalCell::empty(),
+        }
+    }
+
+    /// Whether a transfer is in progress. The IOM needs its clock until it
+    /// completes, so the chip does not sleep deeply meanwhile.
+    pub fn is_busy(&self) -> bool {
+        self.buffer.is_some()
+    }
+
+    /// Set the FIFO threshold, in bytes, used for subsequent transfers, or
+    /// `None` to use half of the remaining transfer.
+    ///
+    /// Returns `EINVAL` if the threshold is zero or larger than `FIFO_DEPTH`,
+    /// and `EBUSY` if a transfer is in progress.
+    pub fn set_fifo_threshold(&self, threshold: Option<u8>) -> ReturnCode {
+        if self.buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        match threshold {
+            Some(t) if t == 0 || t > FIFO_DEPTH => ReturnCode::EINVAL,
+            Some(t) => {
+                self.fifo_threshold.set(t);
+                ReturnCode::SUCCESS
+            }
+            None => {
+                self.fifo_threshold.clear();
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// Check whether a device acknowledges address `addr`, by sending it an
+    /// empty write and busy-waiting for the result. Unlike a read, this does
+    /// not depend on the device having data ready.
+    ///
+    /// This is meant for boards to detect optional peripherals while they are
+    /// set up, before the kernel loop starts and before any client uses the
+    /// bus. The IOM must be enabled. The transfer is polled with the IOM
+    /// interrupts disabled, and if it does not finish within `PROBE_POLLS`
+    /// polls, for example because a device holds the clock low, the command
+    /// is aborted and the device is reported absent rather than blocking boot.
+    pub fn probe(&self, addr: u8) -> bool {
+        let regs = self.registers;
+
+        if addr == GENERAL_CALL_ADDR || self.buffer.is_some() {
+            return false;
+        }
+
+        regs.inten.set(0);
+        regs.dmacfg.write(DMACFG::DMAEN::CLEAR);
+        self.set_address(addr as u16, false);
+        regs.dcx.set(0);
+        regs.fifothr
+            .write(FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(0));
+        self.reset_fifo();
+        regs.intclr.set(0xFFFF_FFFF);
+
+        regs.cmd
+            .write(CMD::TSIZE.val(0) + CMD::CMD::WRITE + CMD::CONT::CLEAR);
+
+        let mut done = false;
+        let mut present = false;
+        for _ in 0..PROBE_POLLS {
+            let irqs = regs.intstat.extract();
+            if irqs.is_set(INT::NAK) {
+                done = true;
+                break;
+            }
+            if irqs.is_set(INT::CMDCMP) {
+                done = true;
+                present = true;
+                break;
+            }
+        }
+
+        if done {
+            // Leave no interrupt pending for the driver.
+            regs.intclr.set(0xFFFF_FFFF);
+        } else {
+            // The command is still running, so stop it before the bus is
+            // used for anything else.
+            self.stop_command();
+        }
+        present
+    }
+
+    /// Address the next transfer to `addr`, a 10-bit address if `ten_bit` is
+    /// set and a 7-bit one otherwise.
+    fn set_address(&self, addr: u16, ten_bit: bool) {
+        let regs = self.registers;
+        if ten_bit {
+            regs.mi2ccfg.modify(MI2CCFG::ADDRSZ::SET);
+        } else {
+            regs.mi2ccfg.modify(MI2CCFG::ADDRSZ::CLEAR);
         }
+        regs.devcfg.write(DEVCFG::DEVADDR.val(addr as u32));
+    }
+
+    /// The SCL frequency the clock is currently set up for.
+    fn scl_frequency_hz(&self) -> u32 {
+        let clkcfg = self.registers.clkcfg.extract();
+        scl_frequency_hz(
+            clkcfg.read(CLKCFG::FSEL),
+            clkcfg.is_set(CLKCFG::DIV3),
+            clkcfg.is_set(CLKCFG::DIVEN),
+            clkcfg.read(CLKCFG::TOTPER),
+        )
+    }
+
+    /// The FIFO threshold to use with `remaining` bytes left to transfer.
+    fn fifo_threshold(&self, remaining: usize) -> u32 {
+        let threshold = self
+            .fifo_threshold
+            .map_or(if remaining > 4 { remaining / 2 } else { 1 }, |threshold| {
+                *threshold as usize
+            });
+
+        // Never wait on more than is left, and never beyond the FIFO.
+        cmp::max(
+            cmp::min(cmp::min(threshold, remaining), FIFO_DEPTH as usize),
+            1,
+        ) as u32
+    }
+
+    /// Stop the running command by turning the I2C submodule off and on
+    /// again, which releases the bus, and drop whatever is left in the FIFO.
+    fn stop_command(&self) {
+        let regs = self.registers;
+
+        regs.inten.set(0);
+        regs.submodctrl.write(SUBMODCTRL::SMOD1EN::CLEAR);
+        self.reset_fifo();
+        regs.intclr.set(0xFFFF_FFFF);
+        regs.submodctrl.write(SUBMODCTRL::SMOD1EN::SET);
     }
 
     fn reset_fifo(&self) {
@@ -474,32 +651,39 @@ impl<'a> Iom<'_> {
             if regs.fifothr.read(FIFOTHR::FIFOWTHR) > 0 {
                 let remaining = self.write_len.get() - self.write_index.get();
 
-                if remaining > 4 {
-                    regs.fifothr.write(
-                        FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(remaining as u32 / 2),
-                    );
-                } else {
-                    regs.fifothr
-                        .write(FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(1));
-                }
+                regs.fifothr.write(
+                    FIFOTHR::FIFORTHR.val(0)
+                        + FIFOTHR::FIFOWTHR.val(self.fifo_threshold(remaining)),
+                );
 
                 self.write_data();
             } else if regs.fifothr.read(FIFOTHR::FIFORTHR) > 0 {
                 let remaining = self.read_len.get() - self.read_index.get();
 
-                if remaining > 4 {
-                    regs.fifothr.write(
-                        FIFOTHR::FIFORTHR.val(remaining as u32 / 2) + FIFOTHR::FIFOWTHR.val(0),
-                    );
-                } else {
-                    regs.fifothr
-                        .write(FIFOTHR::FIFORTHR.val(1) + FIFOTHR::FIFOWTHR.val(0));
-                }
+                regs.fifothr.write(
+                    FIFOTHR::FIFORTHR.val(self.fifo_threshold(remaining))
+                        + FIFOTHR::FIFOWTHR.val(0),
+                );
 
                 self.read_data();
             }
         }
 
+        if irqs.is_set(INT::NAK) {
+            // Nothing acknowledged the address, for example because no device
+            // is present or none listens to a general call. The IOM aborts
+            // the command, so report it now rather than waiting for the
+            // transfer to complete.
+            self.reset_fifo();
+            if let Some(buffer) = self.buffer.take() {
+                self.master_client.map(move |client| {
+                    client.command_complete(buffer, hil::i2c::Error::AddressNak);
+                });
+            }
+            self.finish_smbus();
+            return;
+        }
+
         if irqs.is_set(INT::CMDCMP) {
             if (self.read_len.get() > 0 && self.read_index.get() == self.read_len.get())
                 || (self.write_len.get() > 0 && self.write_index.get() == self.write_len.get())
@@ -511,47 +695,60 @@ impl<'a> Iom<'_> {
                     );
                 });
 
-                // Finished with SMBus
-                if self.smbus.get() {
-                    // Setup 400kHz
-                    regs.clkcfg.write(
-                        CLKCFG::TOTPER.val(0x1D)
-                            + CLKCFG::LOWPER.val(0xE)
-                            + CLKCFG::DIVEN.val(1)
-                            + CLKCFG::DIV3.val(0)
-                            + CLKCFG::FSEL.val(2)
-                            + CLKCFG::IOCLKEN::SET,
-                    );
-
-                    self.smbus.set(false);
-
-                    return;
-                }
+                self.finish_smbus();
             }
         }
     }
 
-    fn tx_rx(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
+    /// Switch back to 400kHz after an SMBus transfer.
+    fn finish_smbus(&self) {
+        if self.smbus.get() {
+            self.registers.clkcfg.write(
+                CLKCFG::TOTPER.val(0x1D)
+                    + CLKCFG::LOWPER.val(0xE)
+                    + CLKCFG::DIVEN.val(1)
+                    + CLKCFG::DIV3.val(0)
+                    + CLKCFG::FSEL.val(2)
+                    + CLKCFG::IOCLKEN::SET,
+            );
+
+            self.smbus.set(false);
+        }
+    }
+
+    fn tx_rx(
+        &self,
+        addr: u16,
+        ten_bit: bool,
+        data: &'static mut [u8],
+        write_len: u8,
+        read_len: u8,
+    ) {
         let regs = self.registers;
         let mut offsetlo = 0;
 
+        if !ten_bit && addr == GENERAL_CALL_ADDR as u16 {
+            // General calls are write only
+            self.master_client.map(move |client| {
+                client.command_complete(data, hil::i2c::Error::NotSupported);
+            });
+            return;
+        }
+
         // Disable DMA as we don't support it
         regs.dmacfg.write(DMACFG::DMAEN::CLEAR);
 
         // Set the address
-        regs.devcfg.write(DEVCFG::DEVADDR.val(addr as u32));
+        self.set_address(addr, ten_bit);
 
         // Set the DCX
         regs.dcx.set(0);
 
         // Set the read FIFO threashold and disable the write
-        if read_len > 4 {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(read_len as u32 / 2) + FIFOTHR::FIFOWTHR.val(0));
-        } else {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(1) + FIFOTHR::FIFOWTHR.val(0));
-        }
+        regs.fifothr.write(
+            FIFOTHR::FIFORTHR.val(self.fifo_threshold(read_len as usize))
+                + FIFOTHR::FIFOWTHR.val(0),
+        );
 
         self.reset_fifo();
 
@@ -595,26 +792,22 @@ impl<'a> Iom<'_> {
         self.read_data();
     }
 
-    fn tx(&self, addr: u8, data: &'static mut [u8], len: u8) {
+    fn tx(&self, addr: u16, ten_bit: bool, data: &'static mut [u8], len: u8) {
         let regs = self.registers;
 
         // Disable DMA as we don't support it
         regs.dmacfg.write(DMACFG::DMAEN::CLEAR);
 
         // Set the address
-        regs.devcfg.write(DEVCFG::DEVADDR.val(addr as u32));
+        self.set_address(addr, ten_bit);
 
         // Set the DCX
         regs.dcx.set(0);
 
         // Set the write FIFO threashold and disable the read
-        if len > 4 {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(len as u32 / 2));
-        } else {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(1));
-        }
+        regs.fifothr.write(
+            FIFOTHR::FIFORTHR.val(0) + FIFOTHR::FIFOWTHR.val(self.fifo_threshold(len as usize)),
+        );
 
         self.reset_fifo();
 
@@ -635,26 +828,30 @@ impl<'a> Iom<'_> {
             .write(CMD::TSIZE.val(len as u32) + CMD::CMD::WRITE + CMD::CONT::CLEAR);
     }
 
-    fn rx(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
+    fn rx(&self, addr: u16, ten_bit: bool, buffer: &'static mut [u8], len: u8) {
         let regs = self.registers;
 
+        if !ten_bit && addr == GENERAL_CALL_ADDR as u16 {
+            // General calls are write only
+            self.master_client.map(move |client| {
+                client.command_complete(buffer, hil::i2c::Error::NotSupported);
+            });
+            return;
+        }
+
         // Disable DMA as we don't support it
         regs.dmacfg.modify(DMACFG::DMAEN::CLEAR);
 
         // Set the address
-        regs.devcfg.write(DEVCFG::DEVADDR.val(addr as u32));
+        self.set_address(addr, ten_bit);
 
         // Set the DCX
         regs.dcx.set(0);
 
         // Set the read FIFO threashold and disable the write
-        if len > 4 {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(len as u32 / 2) + FIFOTHR::FIFOWTHR.val(0));
-        } else {
-            regs.fifothr
-                .write(FIFOTHR::FIFORTHR.val(1) + FIFOTHR::FIFOWTHR.val(0));
-        }
+        regs.fifothr.write(
+            FIFOTHR::FIFORTHR.val(self.fifo_threshold(len as usize)) + FIFOTHR::FIFOWTHR.val(0),
+        );
 
         self.reset_fifo();
 
@@ -676,6 +873,26 @@ impl<'a> Iom<'_> {
     }
 }
 
+/// The SCL frequency for the `CLKCFG` fields `fsel`, `div3`, `diven` and
+/// `totper`. `FSEL` selects the HFRC divided by a power of two, `DIV3`
+/// divides that by three, and with `DIVEN` the divided clock has a period of
+/// `TOTPER + 1` cycles. The I2C submodule takes two cycles of that clock per
+/// SCL cycle.
+fn scl_frequency_hz(fsel: u32, div3: bool, diven: bool, totper: u32) -> u32 {
+    // FSEL 0 selects the minimum power clock, which stops the bus.
+    if fsel == 0 {
+        return 0;
+    }
+    let mut clock = HFRC_FREQ_HZ >> (fsel - 1);
+    if div3 {
+        clock /= 3;
+    }
+    if diven {
+        clock /= totper + 1;
+    }
+    clock / 2
+}
+
 impl<'a> hil::i2c::I2CMaster for Iom<'a> {
     fn set_master_client(&self, master_client: &'a dyn i2c::I2CHwMasterClient) {
         self.master_client.set(master_client);
@@ -720,15 +937,88 @@ impl<'a> hil::i2c::I2CMaster for Iom<'a> {
     }
 
     fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8) {
-        self.tx_rx(addr, data, write_len, read_len);
+        self.tx_rx(addr as u16, false, data, write_len, read_len);
     }
 
     fn write(&self, addr: u8, data: &'static mut [u8], len: u8) {
-        self.tx(addr, data, len);
+        self.tx(addr as u16, false, data, len);
     }
 
     fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8) {
-        self.rx(addr, buffer, len);
+        self.rx(addr as u16, false, buffer, len);
+    }
+
+    fn bytes_remaining(&self) -> Option<usize> {
+        if self.buffer.is_none() {
+            return None;
+        }
+        Some(self.registers.cmdstat.read(CMDSTAT::CTSIZE) as usize)
+    }
+
+    /// The IOM has no clock stretching timeout of its own, so stalled
+    /// transfers are aborted by turning the I2C submodule off and on again,
+    /// which releases the bus.
+    fn abort(&self, error: i2c::Error) -> ReturnCode {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EINVAL,
+        };
+
+        self.stop_command();
+        self.finish_smbus();
+
+        self.master_client.map(move |client| {
+            client.command_complete(buffer, error);
+        });
+        ReturnCode::SUCCESS
+    }
+
+    fn can_abort(&self) -> bool {
+        true
+    }
+
+    /// SMBus transfers run at 100 kHz, all others at 400 kHz.
+    fn requested_frequency_hz(&self) -> Option<u32> {
+        if self.smbus.get() {
+            Some(SMBUS_FREQ_HZ)
+        } else {
+            Some(I2C_FREQ_HZ)
+        }
+    }
+
+    fn actual_frequency_hz(&self) -> Option<u32> {
+        Some(self.scl_frequency_hz())
+    }
+
+    fn write_read_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        write_len: u8,
+        read_len: u8,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.tx_rx(addr, true, data, write_len, read_len);
+        Ok(())
+    }
+
+    fn write_10bit(
+        &self,
+        addr: u16,
+        data: &'static mut [u8],
+        len: u8,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.tx(addr, true, data, len);
+        Ok(())
+    }
+
+    fn read_10bit(
+        &self,
+        addr: u16,
+        buffer: &'static mut [u8],
+        len: u8,
+    ) -> Result<(), (i2c::Error, &'static mut [u8])> {
+        self.rx(addr, true, buffer, len);
+        Ok(())
     }
 }
 
@@ -754,7 +1044,7 @@ impl<'a> hil::i2c::SMBusMaster for Iom<'a> {
 
         self.smbus.set(true);
 
-        self.tx_rx(addr, data, write_len, read_len);
+        self.tx_rx(addr as u16, false, data, write_len, read_len);
         Ok(())
     }
 
@@ -778,7 +1068,7 @@ impl<'a> hil::i2c::SMBusMaster for Iom<'a> {
 
         self.smbus.set(true);
 
-        self.tx(addr, data, len);
+        self.tx(addr as u16, false, data, len);
         Ok(())
     }
 
@@ -802,7 +1092,23 @@ impl<'a> hil::i2c::SMBusMaster for Iom<'a> {
 
         self.smbus.set(true);
 
-        self.rx(addr, buffer, len);
+        self.rx(addr as u16, false, buffer, len);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::scl_frequency_hz;
+
+    #[test]
+    fn scl_frequency() {
+        // The settings for 400 kHz and 100 kHz, from the 24 MHz clock.
+        assert_eq!(scl_frequency_hz(2, false, true, 0x1D), 400_000);
+        assert_eq!(scl_frequency_hz(2, false, true, 0x77), 100_000);
+        // Periods that do not divide the clock round the frequency down.
+        assert_eq!(scl_frequency_hz(2, false, true, 0x1F), 375_000);
+        assert_eq!(scl_frequency_hz(1, true, false, 0), 8_000_000);
+        assert_eq!(scl_frequency_hz(0, false, true, 0x1D), 0);
+    }
+}
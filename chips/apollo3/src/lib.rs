@@ -3,6 +3,7 @@
 #![crate_name = "apollo3"]
 #![crate_type = "rlib"]
 #![feature(asm, const_fn)]
+#![feature(naked_functions)]
 #![no_std]
 
 // Peripherals
@@ -10,6 +11,7 @@ pub mod ble;
 pub mod cachectrl;
 pub mod chip;
 pub mod clkgen;
+pub mod flashctrl;
 pub mod gpio;
 pub mod iom;
 pub mod mcuctrl;
@@ -17,6 +19,7 @@ pub mod nvic;
 pub mod pwrctrl;
 pub mod stimer;
 pub mod uart;
+pub mod wdt;
 
 use cortexm4::{
     generic_isr, hard_fault_handler, scb, svc_handler, systick_handler, unhandled_interrupt,
@@ -62,7 +65,69 @@ pub static BASE_VECTORS: [unsafe extern "C" fn(); 16] = [
 )]
 // used Ensures that the symbol is kept until the final binary
 #[cfg_attr(all(target_arch = "arm", target_os = "none"), used)]
-pub static IRQS: [unsafe extern "C" fn(); 32] = [generic_isr; 32];
+pub static IRQS: [unsafe extern "C" fn(); 32] = [
+    generic_isr, // BROWNOUT (0)
+    generic_isr, // WDT (1)
+    generic_isr, // RTC (2)
+    generic_isr, // VCOMP (3)
+    generic_isr, // IOSLAVE (4)
+    generic_isr, // IOSLAVEACC (5)
+    generic_isr, // IOMSTR0 (6)
+    generic_isr, // IOMSTR1 (7)
+    generic_isr, // IOMSTR2 (8)
+    generic_isr, // IOMSTR3 (9)
+    generic_isr, // IOMSTR4 (10)
+    generic_isr, // IOMSTR5 (11)
+    generic_isr, // BLE (12)
+    gpio_isr,    // GPIO (13)
+    generic_isr, // CTIMER (14)
+    generic_isr, // UART0 (15)
+    generic_isr, // UART1 (16)
+    generic_isr, // SCARD (17)
+    generic_isr, // ADC (18)
+    generic_isr, // PDM (19)
+    generic_isr, // MSPI0 (20)
+    generic_isr, // (21)
+    generic_isr, // STIMER (22)
+    generic_isr, // STIMER_CMPR0 (23)
+    generic_isr, // STIMER_CMPR1 (24)
+    generic_isr, // STIMER_CMPR2 (25)
+    generic_isr, // STIMER_CMPR3 (26)
+    generic_isr, // STIMER_CMPR4 (27)
+    generic_isr, // STIMER_CMPR5 (28)
+    generic_isr, // STIMER_CMPR6 (29)
+    generic_isr, // STIMER_CMPR7 (30)
+    generic_isr, // CLKGEN (31)
+];
+
+/// The GPIO interrupt handler. Edges on pins counting them are counted here,
+/// as they happen, and the interrupt only goes to the kernel, through
+/// `generic_isr`, if other pins interrupted.
+#[cfg(all(target_arch = "arm", target_os = "none"))]
+#[naked]
+unsafe extern "C" fn gpio_isr() {
+    asm!(
+        "
+    push {{r0, lr}}
+    bl {count_edges}
+    mov r1, r0
+    pop {{r0, lr}}
+
+    // Pins other than counting ones interrupted: let the kernel handle them.
+    cmp r1, #0
+    bne {generic_isr}
+    bx lr
+    ",
+        count_edges = sym gpio::count_edges,
+        generic_isr = sym generic_isr,
+        options(noreturn)
+    );
+}
+
+#[cfg(not(any(target_arch = "arm", target_os = "none")))]
+unsafe extern "C" fn gpio_isr() {
+    unimplemented!()
+}
 
 // The Patch table.
 //
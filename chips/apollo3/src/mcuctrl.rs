@@ -1,8 +1,12 @@
 //! MCU Control driver.
 
+use kernel::capabilities::SystemResetCapability;
 use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
 use kernel::common::StaticRef;
 use kernel::debug;
+use kernel::hil::device_id::DeviceId;
+use kernel::ReturnCode;
+use kernel::SystemReset;
 
 const MCUCTRL_BASE: StaticRef<McuCtrlRegisters> =
     unsafe { StaticRef::new(0x4002_0000 as *const McuCtrlRegisters) };
@@ -100,6 +104,14 @@ register_bitfields![u32,
     ]
 ];
 
+/// Stored in `SCRATCH0` when a reset was requested with `SystemReset`, whose
+/// reason is then in `SCRATCH1`. The scratch registers keep their value
+/// across all resets except power on.
+const RESET_REASON_MAGIC: u32 = 0x5253_5452;
+
+/// Length of the unique chip identifier in bytes.
+pub const UNIQUE_ID_LEN: usize = 8;
+
 pub struct McuCtrl {
     registers: StaticRef<McuCtrlRegisters>,
 }
@@ -128,6 +140,27 @@ impl McuCtrl {
         }
     }
 
+    /// The unique identifier the factory programmed into this chip, or `None`
+    /// if it was never programmed and reads as all ones.
+    ///
+    /// The identifier is the 64 bit value of the `CHIPID0` and `CHIPID1`
+    /// registers, which are loaded from the info space at reset. It is
+    /// returned as the little-endian bytes of `CHIPID0` followed by those of
+    /// `CHIPID1`.
+    pub fn unique_id(&self) -> Option<[u8; UNIQUE_ID_LEN]> {
+        let regs = self.registers;
+
+        let low = regs.chipid0.get();
+        let high = regs.chipid1.get();
+        if low == 0xffff_ffff && high == 0xffff_ffff {
+            return None;
+        }
+        let mut id = [0; UNIQUE_ID_LEN];
+        id[..4].copy_from_slice(&low.to_le_bytes());
+        id[4..].copy_from_slice(&high.to_le_bytes());
+        Some(id)
+    }
+
     pub fn enable_ble(&self) {
         let regs = self.registers;
 
@@ -147,4 +180,52 @@ impl McuCtrl {
 
         regs.miscctrl.modify(MISCCTRL::BLE_RESETN::SET);
     }
+
+    /// The reason passed to `SystemReset::reset()` if that caused the last
+    /// reset, or `None` if the chip reset for another reason, such as power
+    /// on, a crash or the watchdog. The record is cleared, so boards should
+    /// call this once at boot.
+    pub fn take_reset_reason(&self) -> Option<u32> {
+        let regs = self.registers;
+
+        let requested = regs.scratch0.get() == RESET_REASON_MAGIC;
+        let reason = regs.scratch1.get();
+        regs.scratch0.set(0);
+        regs.scratch1.set(0);
+        if requested {
+            Some(reason)
+        } else {
+            None
+        }
+    }
+}
+
+impl SystemReset for McuCtrl {
+    fn reset(&self, reason: u32, _capability: &dyn SystemResetCapability) -> ! {
+        let regs = self.registers;
+
+        regs.scratch0.set(RESET_REASON_MAGIC);
+        regs.scratch1.set(reason);
+        unsafe {
+            cortexm4::scb::reset();
+        }
+        // The reset takes effect after a few cycles.
+        loop {}
+    }
+}
+
+impl DeviceId for McuCtrl {
+    fn id_len(&self) -> usize {
+        UNIQUE_ID_LEN
+    }
+
+    fn read_id(&self, buf: &mut [u8]) -> ReturnCode {
+        if buf.len() < UNIQUE_ID_LEN {
+            return ReturnCode::ESIZE;
+        }
+        self.unique_id().map_or(ReturnCode::ENODEVICE, |id| {
+            buf[..UNIQUE_ID_LEN].copy_from_slice(&id);
+            ReturnCode::SUCCESS
+        })
+    }
 }
@@ -6,6 +6,10 @@ use kernel::common::StaticRef;
 const PWRCTRL_BASE: StaticRef<PwrCtrlRegisters> =
     unsafe { StaticRef::new(0x4002_1000 as *const PwrCtrlRegisters) };
 
+/// Number of times the power status is polled before giving up on a domain
+/// that does not come up. Domains normally power up within a few microseconds.
+const POWER_UP_POLLS: u32 = 10_000;
+
 register_structs! {
     pub PwrCtrlRegisters {
         (0x000 => supplysrc: ReadWrite<u32, SUPPLYSRC::Register>),
@@ -76,10 +80,14 @@ impl PwrCtrl {
         }
     }
 
-    pub fn enable_uart0(&self) {
+    /// Power up UART0. Returns `false` if its power domain (HCPA) did not
+    /// report being powered.
+    pub fn enable_uart0(&self) -> bool {
         let regs = self.registers;
 
         regs.devpwren.modify(DEVPWREN::PWRUART0::SET);
+
+        (0..POWER_UP_POLLS).any(|_| regs.devpwrstatus.is_set(DEVPWRSTATUS::HCPA))
     }
 
     pub fn enable_iom2(&self) {
@@ -95,6 +95,7 @@ register_bitfields![u32,
 pub struct STimer<'a> {
     registers: StaticRef<STimerRegisters>,
     client: OptionalCell<&'a dyn AlarmClient>,
+    overflow_client: OptionalCell<&'a dyn OverflowClient>,
 }
 
 impl<'a> STimer<'_> {
@@ -103,22 +104,41 @@ impl<'a> STimer<'_> {
         STimer {
             registers: STIMER_BASE,
             client: OptionalCell::empty(),
+            overflow_client: OptionalCell::empty(),
         }
     }
 
     pub fn handle_interrupt(&self) {
         let regs = self.registers;
+        let status = regs.stmintstat.extract();
 
-        // Disable timer
-        regs.stcfg.modify(STCFG::COMPARE_A_EN::CLEAR);
+        if status.is_set(STMINT::OVERFLOW) {
+            // Clear interrupt
+            regs.stmintclr.write(STMINT::OVERFLOW::SET);
 
-        // Disable interrupt
-        regs.stminten.modify(STMINT::COMPAREA::CLEAR);
+            self.overflow_client.map(|client| client.overflow());
+        }
+
+        if status.is_set(STMINT::COMPAREA) {
+            // Disable timer
+            regs.stcfg.modify(STCFG::COMPARE_A_EN::CLEAR);
+
+            // Disable interrupt
+            regs.stminten.modify(STMINT::COMPAREA::CLEAR);
+
+            // Clear interrupt
+            regs.stmintclr.write(STMINT::COMPAREA::SET);
 
-        // Clear interrupt
-        regs.stmintclr.modify(STMINT::COMPAREA::SET);
+            self.client.map(|client| client.alarm());
+        }
+    }
 
-        self.client.map(|client| client.alarm());
+    /// Whether the timer counts from the high frequency RC oscillator, which
+    /// stops in deep sleep. The crystal clocks keep running.
+    pub fn needs_high_frequency_clock(&self) -> bool {
+        let regs = self.registers;
+        regs.stcfg.matches_all(STCFG::CLKSEL::HRFC_DIV16)
+            || regs.stcfg.matches_all(STCFG::CLKSEL::HRFC_DIV256)
     }
 }
 
@@ -132,13 +152,17 @@ impl Time for STimer<'_> {
 }
 
 impl<'a> Counter<'a> for STimer<'a> {
-    fn set_overflow_client(&'a self, _client: &'a dyn OverflowClient) {
-        //self.overflow_client.set(client);
+    fn set_overflow_client(&'a self, client: &'a dyn OverflowClient) {
+        self.overflow_client.set(client);
     }
 
     fn start(&self) -> ReturnCode {
         // Set the clock source
         self.registers.stcfg.write(STCFG::CLKSEL::XTAL_DIV2);
+
+        // The overflow interrupt is a wakeup source, so a wrap that happens
+        // while the core is asleep is still delivered to the overflow client.
+        self.registers.stminten.modify(STMINT::OVERFLOW::SET);
         ReturnCode::SUCCESS
     }
 
@@ -9,6 +9,11 @@ use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::ReturnCode;
 
+/// Number of times the status is polled while waiting for the transmit FIFO
+/// to drain before reconfiguring. At 9600 baud a full FIFO drains in about
+/// 30 ms.
+const DRAIN_POLLS: u32 = 2_000_000;
+
 const UART0_BASE: StaticRef<UartRegisters> =
     unsafe { StaticRef::new(0x4001_C000 as *const UartRegisters) };
 
@@ -281,6 +286,12 @@ impl Uart<'_> {
         }
     }
 
+    /// Whether a transmission is in progress. The UART needs its clock until
+    /// it completes, so the chip does not sleep deeply meanwhile.
+    pub fn is_busy(&self) -> bool {
+        self.tx_buffer.is_some()
+    }
+
     pub fn transmit_sync(&self, bytes: &[u8]) {
         let regs = self.registers;
         for b in bytes.iter() {
@@ -297,6 +308,18 @@ impl hil::uart::Configure for Uart<'_> {
     fn configure(&self, params: hil::uart::Parameters) -> ReturnCode {
         let regs = self.registers;
 
+        if self.tx_buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+
+        // Let the FIFO drain so no queued byte is sent at the new rate. A
+        // UART that never drains is stuck.
+        let drained =
+            (0..DRAIN_POLLS).any(|_| !(regs.cr.is_set(CR::UARTEN) && regs.fr.is_set(FR::BUSY)));
+        if !drained {
+            return ReturnCode::FAIL;
+        }
+
         // Disable UART
         regs.cr
             .write(CR::UARTEN::CLEAR + CR::RXE::CLEAR + CR::TXE::CLEAR);
@@ -318,7 +341,12 @@ impl hil::uart::Configure for Uart<'_> {
         regs.cr
             .modify(CR::UARTEN::SET + CR::RXE::SET + CR::TXE::SET);
 
-        ReturnCode::SUCCESS
+        // An unpowered UART ignores writes, so check that it took effect.
+        if regs.cr.is_set(CR::UARTEN) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        }
     }
 }
 
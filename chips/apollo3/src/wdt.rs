@@ -0,0 +1,239 @@
+//! Watchdog Timer (WDT) driver.
+//!
+//! The watchdog counts ticks of one of the clocks derived from the low
+//! frequency RC oscillator and resets the chip once it has counted to the
+//! reset value, unless the kernel loop restarts it first. The 8 bit reset
+//! value and the choice of clock bound the timeout to between 8 ms at 128 Hz
+//! and 4080 s at 1/16 Hz. The fastest clock that can time a given timeout is
+//! used, for the best resolution.
+//!
+//! The watchdog is disabled unless the board calls `Apollo3::enable_watchdog()`.
+
+use core::cell::Cell;
+use kernel::common::registers::{register_bitfields, register_structs, ReadOnly, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::watchdog::WatchDogTimeout;
+use kernel::ReturnCode;
+
+const WDT_BASE: StaticRef<WdtRegisters> =
+    unsafe { StaticRef::new(0x4002_4000 as *const WdtRegisters) };
+
+const RSTGEN_BASE: StaticRef<RstGenRegisters> =
+    unsafe { StaticRef::new(0x4000_0000 as *const RstGenRegisters) };
+
+/// Key to write to `RSTRT` to restart the watchdog.
+const RESTART_KEY: u32 = 0xB2;
+
+/// Timeout of the watchdog until the board changes it.
+pub const DEFAULT_TIMEOUT_MS: u32 = 1000;
+
+/// Shortest timeout, one tick of the 128 Hz clock.
+pub const MIN_TIMEOUT_MS: u32 = 8;
+
+/// Longest timeout, 255 ticks of the 1/16 Hz clock.
+pub const MAX_TIMEOUT_MS: u32 = 4_080_000;
+
+register_structs! {
+    pub WdtRegisters {
+        (0x000 => cfg: ReadWrite<u32, CFG::Register>),
+        (0x004 => rstrt: ReadWrite<u32>),
+        (0x008 => lock: ReadWrite<u32>),
+        (0x00c => count: ReadOnly<u32>),
+        (0x010 => @END),
+    },
+    pub RstGenRegisters {
+        (0x000 => cfg: ReadWrite<u32, RSTGEN_CFG::Register>),
+        (0x004 => @END),
+    }
+}
+
+register_bitfields![u32,
+    CFG [
+        WDTEN OFFSET(0) NUMBITS(1) [],
+        INTEN OFFSET(1) NUMBITS(1) [],
+        RESEN OFFSET(2) NUMBITS(1) [],
+        RESVAL OFFSET(8) NUMBITS(8) [],
+        INTVAL OFFSET(16) NUMBITS(8) [],
+        CLKSEL OFFSET(24) NUMBITS(3) [
+            OFF = 0,
+            HZ128 = 1,
+            HZ16 = 2,
+            HZ1 = 3,
+            HZ1_16 = 4
+        ]
+    ],
+    RSTGEN_CFG [
+        BODHREN OFFSET(0) NUMBITS(1) [],
+        WDREN OFFSET(1) NUMBITS(1) []
+    ]
+];
+
+/// The `CLKSEL` values of the watchdog clocks, fastest first, with their
+/// frequencies in sixteenths of a hertz.
+const CLOCKS: [(u32, u64); 4] = [(1, 2048), (2, 256), (3, 16), (4, 1)];
+
+/// The clock and number of its ticks that time at most `timeout_ms`, as
+/// closely as possible.
+fn timeout_config(timeout_ms: u32) -> Option<(u32, u32)> {
+    CLOCKS.iter().find_map(|&(clksel, freq)| {
+        let ticks = timeout_ms as u64 * freq / 16_000;
+        if (1..=0xff).contains(&ticks) {
+            Some((clksel, ticks as u32))
+        } else {
+            None
+        }
+    })
+}
+
+/// The timeout in milliseconds of `ticks` ticks of clock `clksel`.
+fn timeout_of(clksel: u32, ticks: u32) -> u32 {
+    CLOCKS
+        .iter()
+        .find(|&&(sel, _)| sel == clksel)
+        .map_or(0, |&(_, freq)| (ticks as u64 * 16_000 / freq) as u32)
+}
+
+pub struct WatchDog {
+    registers: StaticRef<WdtRegisters>,
+    enabled: Cell<bool>,
+    /// `CLKSEL` value and ticks of the current timeout.
+    timeout: Cell<(u32, u32)>,
+}
+
+impl WatchDog {
+    pub const fn new() -> WatchDog {
+        WatchDog {
+            registers: WDT_BASE,
+            enabled: Cell::new(false),
+            // One second, 128 ticks of the 128 Hz clock.
+            timeout: Cell::new((1, 128)),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.set(true);
+    }
+
+    fn restart(&self) {
+        self.registers.rstrt.set(RESTART_KEY);
+    }
+
+    /// Configure the watchdog with the current timeout and start counting
+    /// from zero.
+    fn start(&self) {
+        let regs = self.registers;
+        let (clksel, ticks) = self.timeout.get();
+
+        // The clock and reset value can only be changed safely while the
+        // watchdog is stopped.
+        regs.cfg.modify(CFG::WDTEN::CLEAR);
+        regs.cfg.write(
+            CFG::CLKSEL.val(clksel) + CFG::RESVAL.val(ticks) + CFG::RESEN::SET + CFG::INTEN::CLEAR,
+        );
+        self.restart();
+        regs.cfg.modify(CFG::WDTEN::SET);
+    }
+
+    fn stop(&self) {
+        self.registers.cfg.modify(CFG::WDTEN::CLEAR);
+    }
+}
+
+impl kernel::watchdog::WatchDog for WatchDog {
+    fn setup(&self) {
+        if self.enabled.get() {
+            // Let the watchdog reset the chip.
+            RSTGEN_BASE.cfg.modify(RSTGEN_CFG::WDREN::SET);
+            self.start();
+        }
+    }
+
+    fn tickle(&self) {
+        if self.enabled.get() {
+            self.restart();
+        }
+    }
+
+    fn suspend(&self) {
+        if self.enabled.get() {
+            self.stop();
+        }
+    }
+
+    fn resume(&self) {
+        if self.enabled.get() {
+            self.restart();
+            self.registers.cfg.modify(CFG::WDTEN::SET);
+        }
+    }
+}
+
+impl WatchDogTimeout for WatchDog {
+    fn timeout_range_ms(&self) -> (u32, u32) {
+        (MIN_TIMEOUT_MS, MAX_TIMEOUT_MS)
+    }
+
+    fn timeout_ms(&self) -> u32 {
+        let (clksel, ticks) = self.timeout.get();
+        timeout_of(clksel, ticks)
+    }
+
+    fn supported_timeout_ms(&self, timeout_ms: u32) -> Option<u32> {
+        if timeout_ms > MAX_TIMEOUT_MS {
+            return None;
+        }
+        timeout_config(timeout_ms).map(|(clksel, ticks)| timeout_of(clksel, ticks))
+    }
+
+    fn set_timeout_ms(&self, timeout_ms: u32) -> ReturnCode {
+        if timeout_ms > MAX_TIMEOUT_MS {
+            return ReturnCode::EINVAL;
+        }
+        match timeout_config(timeout_ms) {
+            Some(timeout) => {
+                self.timeout.set(timeout);
+                // Only reconfigure a running watchdog, a suspended one picks
+                // up the new timeout when it is started again.
+                if self.enabled.get() && self.registers.cfg.is_set(CFG::WDTEN) {
+                    self.start();
+                }
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        timeout_config, timeout_of, WatchDog, DEFAULT_TIMEOUT_MS, MAX_TIMEOUT_MS, MIN_TIMEOUT_MS,
+    };
+    use kernel::watchdog::WatchDogTimeout;
+
+    #[test]
+    fn bounds() {
+        assert_eq!(timeout_config(MIN_TIMEOUT_MS - 1), None);
+        assert_eq!(timeout_config(MIN_TIMEOUT_MS), Some((1, 1)));
+        assert_eq!(timeout_config(MAX_TIMEOUT_MS), Some((4, 255)));
+    }
+
+    #[test]
+    fn fastest_clock_that_fits() {
+        assert_eq!(timeout_config(DEFAULT_TIMEOUT_MS), Some((1, 128)));
+        // Too long for 255 ticks at 128 Hz.
+        assert_eq!(timeout_config(2000), Some((2, 32)));
+        assert_eq!(timeout_of(2, 32), 2000);
+        // Rounded down to whole ticks.
+        assert_eq!(timeout_of(1, timeout_config(100).unwrap().1), 93);
+    }
+
+    #[test]
+    fn supported_timeouts() {
+        let wdt = WatchDog::new();
+        assert_eq!(wdt.supported_timeout_ms(100), Some(93));
+        assert_eq!(wdt.supported_timeout_ms(DEFAULT_TIMEOUT_MS), Some(1000));
+        assert_eq!(wdt.supported_timeout_ms(MIN_TIMEOUT_MS - 1), None);
+        assert_eq!(wdt.supported_timeout_ms(MAX_TIMEOUT_MS + 1), None);
+    }
+}
@@ -559,6 +559,9 @@ impl<'a> Radio<'a> {
 
     fn rx(&self) {
         self.registers.event_ready.write(Event::READY::CLEAR);
+        // Sample the RSSI once the access address has been received
+        self.registers.event_rssiend.write(Event::READY::CLEAR);
+        self.registers.shorts.write(Shortcut::ADDRESS_RSSISTART::SET);
         self.registers.task_rxen.write(Task::ENABLE::SET);
     }
 
@@ -634,13 +637,25 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_RXIDLE
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
+                    // RSSISAMPLE is the magnitude of the RSSI in dBm, read
+                    // before the radio is powered off
+                    let rssi = if self.registers.event_rssiend.is_set(Event::READY) {
+                        -(self.registers.rssisample.read(RssiSample::RSSISAMPLE) as i8)
+                    } else {
+                        ble_advertising::RSSI_UNAVAILABLE
+                    };
                     self.radio_off();
                     unsafe {
                         self.rx_client.map(|client| {
                             // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
                             // And because the length field is directly read from the packet
                             // We need to add 2 to length to get the total length
-                            client.receive_event(&mut PAYLOAD, PAYLOAD[1] + 2, result)
+                            client.receive_event(
+                                &mut PAYLOAD,
+                                PAYLOAD[1] + 2,
+                                rssi,
+                                result,
+                            )
                         });
                     }
                 }
@@ -808,6 +823,23 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     fn set_transmit_client(&self, client: &'a dyn ble_advertising::TxClient) {
         self.tx_client.set(client);
     }
+
+    fn power_down(&self) -> ReturnCode {
+        if self.buffer.is_some() {
+            ReturnCode::EBUSY
+        } else {
+            self.radio_off();
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn abort(&self) -> Option<&'static mut [u8]> {
+        self.disable_all_interrupts();
+        self.registers.task_disable.write(Task::ENABLE::SET);
+        self.registers.event_end.write(Event::READY::CLEAR);
+        self.radio_off();
+        self.buffer.take()
+    }
 }
 
 impl ble_advertising::BleConfig for Radio<'_> {
@@ -0,0 +1,325 @@
+//! A pool of memory for large per-process buffers, outside of grant regions.
+//!
+//! Grant regions come out of each process's own RAM, which is usually only a
+//! few kilobytes, so a capsule that needs a large buffer for every process it
+//! serves, such as audio samples, can exhaust it. Boards can instead give the
+//! kernel a static region as an `AppPool`, from which capsules allocate
+//! buffers owned by a process.
+//!
+//! Like grants, allocations are tied to the lifetime of the process: when the
+//! process exits, faults or is restarted, all of its allocations are freed.
+//! Capsules therefore never hold a reference to pool memory. They hold an
+//! `AppPoolAllocation` handle and access the memory with `enter()`, which
+//! fails once the allocation was freed.
+//!
+//! Allocations are placed first fit and word aligned relative to the start of
+//! the pool, so boards should give a word aligned region.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use core::cell::Cell;
+//! # use kernel::capabilities::MemoryAllocationCapability;
+//! # use kernel::{create_capability, static_init};
+//! # static mut PROCESSES: [Option<&'static dyn kernel::procs::ProcessType>; 1] = [None];
+//! # unsafe {
+//! # let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+//! # let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+//! static mut POOL_MEMORY: [u8; 16384] = [0; 16384];
+//! let blocks = static_init!([Cell<Option<kernel::app_pool::Block>>; 8], Default::default());
+//! let pool = static_init!(
+//!     kernel::app_pool::AppPool,
+//!     kernel::app_pool::AppPool::new(&mut POOL_MEMORY, blocks)
+//! );
+//! board_kernel.set_app_pool(pool, &memory_allocation_cap);
+//! # }
+//! ```
+
+use core::cell::Cell;
+
+use crate::callback::AppId;
+use crate::returncode::ReturnCode;
+
+/// Alignment of all allocations, in bytes, relative to the start of the pool.
+const ALIGNMENT: usize = 4;
+
+/// Errors returned by the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppPoolError {
+    /// There is no free range large enough for the allocation.
+    OutOfMemory,
+    /// All allocation slots are in use.
+    NoFreeSlot,
+    /// The allocation was freed, for example because its process ended.
+    Freed,
+    /// Another allocation is being accessed with `enter()`.
+    Busy,
+    /// The process does not exist, or the allocation is empty.
+    Invalid,
+}
+
+impl From<AppPoolError> for ReturnCode {
+    fn from(err: AppPoolError) -> ReturnCode {
+        match err {
+            AppPoolError::OutOfMemory => ReturnCode::ENOMEM,
+            AppPoolError::NoFreeSlot => ReturnCode::ENOMEM,
+            AppPoolError::Freed => ReturnCode::EINVAL,
+            AppPoolError::Busy => ReturnCode::EBUSY,
+            AppPoolError::Invalid => ReturnCode::EINVAL,
+        }
+    }
+}
+
+/// Bookkeeping for one allocation. Boards only provide storage for these.
+#[derive(Clone, Copy)]
+pub struct Block {
+    owner: AppId,
+    generation: usize,
+    start: usize,
+    len: usize,
+}
+
+/// Handle to an allocation, to pass to `AppPool::enter()`.
+#[derive(Clone, Copy)]
+pub struct AppPoolAllocation {
+    slot: usize,
+    owner: AppId,
+    /// Tells the allocation apart from later ones in the same slot, which
+    /// may have the same owner.
+    generation: usize,
+}
+
+impl AppPoolAllocation {
+    /// The process owning the allocation.
+    pub fn owner(&self) -> AppId {
+        self.owner
+    }
+}
+
+/// Round `offset` up to the alignment of allocations, or `None` if that
+/// overflows.
+fn align(offset: usize) -> Option<usize> {
+    offset
+        .checked_add(ALIGNMENT - 1)
+        .map(|offset| offset / ALIGNMENT * ALIGNMENT)
+}
+
+/// Returns the start of the first range of `len` bytes within `total` that
+/// does not overlap with any of the `used` ranges.
+fn first_fit(
+    used: impl Iterator<Item = (usize, usize)> + Clone,
+    total: usize,
+    len: usize,
+) -> Option<usize> {
+    let candidates = core::iter::once(0).chain(
+        used.clone()
+            .filter_map(|(start, len)| start.checked_add(len).and_then(align)),
+    );
+    candidates
+        .filter(|&start| start.checked_add(len).map_or(false, |end| end <= total))
+        .filter(|&start| {
+            used.clone().all(|(used_start, used_len)| {
+                start + len <= used_start || used_start + used_len <= start
+            })
+        })
+        .min()
+}
+
+/// A region of memory for per-process allocations.
+pub struct AppPool {
+    memory: *mut u8,
+    len: usize,
+    blocks: &'static [Cell<Option<Block>>],
+    /// Generation of the next allocation.
+    next_generation: Cell<usize>,
+    /// Slot of the allocation currently accessed through `enter()`.
+    entered: Cell<Option<usize>>,
+}
+
+impl AppPool {
+    /// Create a pool over `memory`. It can hold as many allocations at once
+    /// as `blocks` has entries.
+    pub fn new(memory: &'static mut [u8], blocks: &'static [Cell<Option<Block>>]) -> AppPool {
+        AppPool {
+            memory: memory.as_mut_ptr(),
+            len: memory.len(),
+            blocks,
+            next_generation: Cell::new(0),
+            entered: Cell::new(None),
+        }
+    }
+
+    /// Allocate `len` zeroed bytes owned by the process `appid`.
+    ///
+    /// Returns `OutOfMemory` if no free range is large enough, `NoFreeSlot` if
+    /// the pool holds as many allocations as it can track, and `Invalid` if
+    /// `len` is 0 or the process does not exist.
+    pub fn allocate(&self, appid: AppId, len: usize) -> Result<AppPoolAllocation, AppPoolError> {
+        if len == 0 || appid.index().is_none() {
+            return Err(AppPoolError::Invalid);
+        }
+        let slot = self
+            .blocks
+            .iter()
+            .position(|block| block.get().is_none())
+            .ok_or(AppPoolError::NoFreeSlot)?;
+        let used = self
+            .blocks
+            .iter()
+            .filter_map(|block| block.get().map(|block| (block.start, block.len)));
+        let start = first_fit(used, self.len, len).ok_or(AppPoolError::OutOfMemory)?;
+
+        unsafe {
+            core::ptr::write_bytes(self.memory.add(start), 0, len);
+        }
+        let generation = self.next_generation.get();
+        self.next_generation.set(generation.wrapping_add(1));
+        self.blocks[slot].set(Some(Block {
+            owner: appid,
+            generation,
+            start,
+            len,
+        }));
+        Ok(AppPoolAllocation {
+            slot,
+            owner: appid,
+            generation,
+        })
+    }
+
+    /// Run `fun` on the memory of `allocation`.
+    ///
+    /// Returns `Freed` if the allocation was freed, and `Busy` if called from
+    /// within `fun` of another `enter()`.
+    pub fn enter<F, R>(&self, allocation: AppPoolAllocation, fun: F) -> Result<R, AppPoolError>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let block = self.block(allocation).ok_or(AppPoolError::Freed)?;
+        if self.entered.get().is_some() {
+            return Err(AppPoolError::Busy);
+        }
+        self.entered.set(Some(allocation.slot));
+        let memory =
+            unsafe { core::slice::from_raw_parts_mut(self.memory.add(block.start), block.len) };
+        let res = fun(memory);
+        self.entered.set(None);
+        Ok(res)
+    }
+
+    /// Free `allocation`. Does nothing if it was already freed, or if it is
+    /// being accessed with `enter()`.
+    pub fn free(&self, allocation: AppPoolAllocation) {
+        if self.block(allocation).is_some() && self.entered.get() != Some(allocation.slot) {
+            self.blocks[allocation.slot].set(None);
+        }
+    }
+
+    /// Bytes in the largest range that can currently be allocated.
+    pub fn largest_free(&self) -> usize {
+        let used = self
+            .blocks
+            .iter()
+            .filter_map(|block| block.get().map(|block| (block.start, block.len)));
+        // Every free range starts at 0 or after an allocation, and ends at the
+        // next allocation or the end of the pool.
+        core::iter::once(0)
+            .chain(
+                used.clone()
+                    .filter_map(|(start, len)| start.checked_add(len).and_then(align)),
+            )
+            .map(|start| {
+                let end = used
+                    .clone()
+                    .map(|(used_start, _)| used_start)
+                    .filter(|&used_start| used_start >= start)
+                    .min()
+                    .unwrap_or(self.len);
+                end.saturating_sub(start)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Free all allocations of the process `appid`.
+    pub(crate) fn release(&self, appid: AppId) {
+        for block in self.blocks.iter() {
+            if block.get().map_or(false, |block| block.owner == appid) {
+                block.set(None);
+            }
+        }
+    }
+
+    fn block(&self, allocation: AppPoolAllocation) -> Option<Block> {
+        self.blocks
+            .get(allocation.slot)
+            .and_then(|block| block.get())
+            .filter(|block| {
+                block.owner == allocation.owner && block.generation == allocation.generation
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::{first_fit, AppPool, AppPoolError, Block};
+    use crate::process::ProcessType;
+    use crate::sched::Kernel;
+    use crate::static_init;
+    use crate::testing::MockProcess;
+
+    #[test]
+    fn empty_pool_allocates_at_start() {
+        assert_eq!(first_fit(core::iter::empty(), 100, 10), Some(0));
+    }
+
+    #[test]
+    fn fills_gap_between_allocations() {
+        let used = [(0, 10), (40, 10)];
+        assert_eq!(first_fit(used.iter().copied(), 100, 20), Some(12));
+        assert_eq!(first_fit(used.iter().copied(), 100, 30), Some(52));
+    }
+
+    #[test]
+    fn exhausted() {
+        let used = [(0, 60)];
+        assert_eq!(first_fit(used.iter().copied(), 100, 41), None);
+        assert_eq!(first_fit(used.iter().copied(), 100, 40), Some(60));
+    }
+
+    #[test]
+    fn lengths_do_not_overflow() {
+        let used = [(0, 10)];
+        assert_eq!(first_fit(used.iter().copied(), 100, usize::MAX), None);
+        assert_eq!(first_fit(used.iter().copied(), 100, usize::MAX - 8), None);
+    }
+
+    #[test]
+    fn freed_handle_does_not_reach_later_allocation() {
+        unsafe {
+            let process: &'static MockProcess = static_init!(MockProcess, MockProcess::new());
+            let processes: &'static [Option<&'static dyn ProcessType>] = static_init!(
+                [Option<&'static dyn ProcessType>; 1],
+                [Some(process as &dyn ProcessType)]
+            );
+            let kernel: &'static Kernel = static_init!(Kernel, Kernel::new(processes));
+            process.attach(kernel, 0);
+
+            static mut MEMORY: [u8; 64] = [0; 64];
+            let blocks = static_init!([Cell<Option<Block>>; 1], Default::default());
+            let pool = AppPool::new(&mut MEMORY, blocks);
+
+            // The same process gets the same slot again.
+            let first = pool.allocate(process.appid(), 16).unwrap();
+            pool.free(first);
+            let second = pool.allocate(process.appid(), 16).unwrap();
+
+            assert_eq!(pool.enter(first, |_| ()), Err(AppPoolError::Freed));
+            pool.free(first);
+            assert_eq!(pool.enter(second, |memory| memory.len()), Ok(16));
+        }
+    }
+}
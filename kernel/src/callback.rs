@@ -151,6 +151,27 @@ impl AppId {
             (start, end)
         })
     }
+
+    /// Checks that the `size` bytes starting at `buf_start_addr` lie within
+    /// the RAM the app can currently access (excluding its grant region).
+    /// Returns `false` if the app no longer exists.
+    ///
+    /// Capsules must use this, or `in_app_flash_memory()`, to validate any
+    /// address an app passes them other than through `allow` (for example as
+    /// a command argument) before accessing it.
+    pub fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        self.kernel.process_map_or(false, *self, |process| {
+            process.in_app_owned_memory(buf_start_addr, size)
+        })
+    }
+
+    /// Checks that the `size` bytes starting at `buf_start_addr` lie within
+    /// the app's flash region. Returns `false` if the app no longer exists.
+    pub fn in_app_flash_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        self.kernel.process_map_or(false, *self, |process| {
+            process.in_app_flash_memory(buf_start_addr, size)
+        })
+    }
 }
 
 /// Type to uniquely identify a callback subscription across all drivers.
@@ -194,22 +215,35 @@ impl Callback {
     /// `false` if the queue for the process is full and the callback could not
     /// be scheduled.
     ///
+    /// A callback is stale if the process has since unsubscribed it or
+    /// subscribed another function in its place, or has restarted or ended.
+    /// Stale callbacks are dropped and counted by the kernel, as delivering
+    /// them would call a function the process no longer expects.
+    ///
     /// The arguments (`r0-r2`) are the values passed back to the process and
     /// are specific to the individual `Driver` interfaces.
     pub fn schedule(&mut self, r0: usize, r1: usize, r2: usize) -> bool {
-        let res = self
-            .app_id
-            .kernel
-            .process_map_or(false, self.app_id, |process| {
+        let kernel = self.app_id.kernel;
+        let fn_ptr = self.fn_ptr.as_ptr() as usize;
+        let res = kernel.process_map_or(None, self.app_id, |process| {
+            if !process.is_subscribed(self.callback_id, fn_ptr, self.appdata) {
+                return None;
+            }
+            Some(
                 process.enqueue_task(process::Task::FunctionCall(process::FunctionCall {
                     source: process::FunctionCallSource::Driver(self.callback_id),
                     argument0: r0,
                     argument1: r1,
                     argument2: r2,
                     argument3: self.appdata,
-                    pc: self.fn_ptr.as_ptr() as usize,
-                }))
-            });
+                    pc: fn_ptr,
+                })),
+            )
+        });
+        let res = res.unwrap_or_else(|| {
+            kernel.stale_callback_dropped();
+            false
+        });
         if config::CONFIG.trace_syscalls {
             debug!(
                 "[{:?}] schedule[{:#x}:{}] @{:#x}({:#x}, {:#x}, {:#x}, {:#x}) = {}",
@@ -226,4 +260,15 @@ impl Callback {
         }
         res
     }
+
+    /// Returns whether a callback scheduled with `schedule()` is still waiting
+    /// to be delivered to the process. Callbacks scheduled through other
+    /// copies of this `Callback` count too.
+    pub fn is_pending(&self) -> bool {
+        self.app_id
+            .kernel
+            .process_map_or(false, self.app_id, |process| {
+                process.has_pending_callback(self.callback_id)
+            })
+    }
 }
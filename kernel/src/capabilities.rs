@@ -85,8 +85,43 @@ pub unsafe trait UdpDriverCapability {}
 /// kernel
 pub unsafe trait CreatePortTableCapability {}
 
+/// The `MpuInfoCapability` allows the holder to read the fixed limits of the
+/// MPU hardware, such as how many regions it has and how they must be aligned.
+/// This does not change any protection, but only the board should decide
+/// which capsules expose it to processes.
+pub unsafe trait MpuInfoCapability {}
+
 /// The `NetworkCapabilityCreationCapability` allows the holder to instantiate
 /// `NetworkCapability`S and visibility capabilities for the IP and UDP layers
 /// of the networking stack. A capsule would never hold this capability although
 /// it may hold capabilities created via this capability.
 pub unsafe trait NetworkCapabilityCreationCapability {}
+
+/// The `PeripheralMappingCapability` allows the holder to give a process
+/// direct access to memory-mapped peripheral registers. This bypasses the
+/// isolation the kernel normally provides, so it should only be held by the
+/// board's main file.
+pub unsafe trait PeripheralMappingCapability {}
+
+/// The `PinMuxCapability` allows the holder to change, at runtime, which
+/// peripheral a pad is connected to. Rerouting a pad that a driver is using
+/// breaks that driver, so this should only be given to capsules the board
+/// trusts to change its pinout.
+pub unsafe trait PinMuxCapability {}
+
+/// The `PowerBudgetCapability` allows the holder to set the power budget and
+/// to create the consumers that count against it. A driver given a consumer
+/// with a low estimate could exceed the budget, so this should only be held
+/// by the board's main file.
+pub unsafe trait PowerBudgetCapability {}
+
+/// The `PreemptionControlCapability` allows the holder to briefly stop the
+/// kernel from preempting processes. This holds off interrupt handling and
+/// other processes, so it should only be given to board code that must not be
+/// interrupted by a context switch.
+pub unsafe trait PreemptionControlCapability {}
+
+/// The `SystemResetCapability` allows the holder to reset the whole system.
+/// Everything running is lost, so this should only be given to capsules that
+/// restrict which processes may request a reset.
+pub unsafe trait SystemResetCapability {}
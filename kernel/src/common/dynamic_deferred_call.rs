@@ -64,6 +64,21 @@
 //!     dynamic_deferred_call.register(some_capsule).expect("no deferred call slot available")
 //! );
 //! ```
+//!
+//! Ordering
+//! --------
+//!
+//! Pending deferred calls run in the order they were scheduled. Clients
+//! registered with `register_with_priority(client, DeferredCallPriority::Urgent)`
+//! run before all pending normal clients, in the order they were scheduled
+//! among themselves. So that urgent clients cannot starve the others, a pending
+//! normal call runs after at most `MAX_URGENT_BURST` urgent calls in a row.
+//!
+//! `call_global_instance_round_robin_while` instead runs pending calls in
+//! turn by client, ignoring priorities and the order they were scheduled in.
+//! Each call starts with the client after the one that ran last, so when the
+//! predicate stops a call early, the clients that were skipped run first the
+//! next time.
 
 use crate::common::cells::OptionalCell;
 use core::cell::Cell;
@@ -74,20 +89,44 @@ use core::cell::Cell;
 /// through `unsafe` static functions on the `DynamicDeferredCall` struct
 static mut DYNAMIC_DEFERRED_CALL: Option<&'static DynamicDeferredCall> = None;
 
+/// Number of urgent deferred calls that may run in a row while a normal
+/// deferred call is pending.
+pub const MAX_URGENT_BURST: usize = 4;
+
+/// Priority of a client of the [DynamicDeferredCall]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeferredCallPriority {
+    /// Called in the order it was scheduled.
+    Normal,
+    /// Called before pending normal clients.
+    Urgent,
+}
+
 /// Internal per-client state tracking for the [DynamicDeferredCall]
 pub struct DynamicDeferredCallClientState {
     scheduled: Cell<bool>,
+    urgent: Cell<bool>,
+    /// Position of the call in the order calls were scheduled in.
+    order: Cell<usize>,
     client: OptionalCell<&'static dyn DynamicDeferredCallClient>,
 }
 impl Default for DynamicDeferredCallClientState {
     fn default() -> DynamicDeferredCallClientState {
         DynamicDeferredCallClientState {
             scheduled: Cell::new(false),
+            urgent: Cell::new(false),
+            order: Cell::new(0),
             client: OptionalCell::empty(),
         }
     }
 }
 
+/// Whether `order` was assigned before `other`, allowing for the counter
+/// wrapping around.
+fn scheduled_before(order: usize, other: usize) -> bool {
+    (order.wrapping_sub(other) as isize) < 0
+}
+
 /// Dynamic deferred call
 ///
 /// This struct manages and calls dynamically (at runtime) registered
@@ -99,6 +138,12 @@ pub struct DynamicDeferredCall {
     client_states: &'static [DynamicDeferredCallClientState],
     handle_counter: Cell<usize>,
     call_pending: Cell<bool>,
+    /// Order assigned to the next scheduled call.
+    order_counter: Cell<usize>,
+    /// Number of urgent calls run in a row while a normal call was pending.
+    urgent_burst: Cell<usize>,
+    /// Client the next round-robin call starts from.
+    next_client: Cell<usize>,
 }
 
 impl DynamicDeferredCall {
@@ -115,6 +160,9 @@ impl DynamicDeferredCall {
             client_states,
             handle_counter: Cell::new(0),
             call_pending: Cell::new(false),
+            order_counter: Cell::new(0),
+            urgent_burst: Cell::new(0),
+            next_client: Cell::new(0),
         }
     }
 
@@ -147,6 +195,16 @@ impl DynamicDeferredCall {
             .is_some()
     }
 
+    /// Call the globally registered instance while the supplied predicate
+    /// returns `true`, running clients in turn.
+    ///
+    /// Returns `true` if a global instance was registered and has been called.
+    pub unsafe fn call_global_instance_round_robin_while<F: Fn() -> bool>(f: F) -> bool {
+        DYNAMIC_DEFERRED_CALL
+            .map(move |ddc| ddc.call_round_robin_while(f))
+            .is_some()
+    }
+
     /// Check if one or more dynamic deferred calls are pending in the
     /// globally registered instance
     ///
@@ -173,6 +231,9 @@ impl DynamicDeferredCall {
                 Some(false)
             } else {
                 call_set.set(true);
+                client_state.order.set(self.order_counter.get());
+                self.order_counter
+                    .set(self.order_counter.get().wrapping_add(1));
                 self.call_pending.set(true);
                 Some(true)
             }
@@ -188,12 +249,28 @@ impl DynamicDeferredCall {
     pub fn register(
         &self,
         ddc_client: &'static dyn DynamicDeferredCallClient,
+    ) -> Option<DeferredCallHandle> {
+        self.register_with_priority(ddc_client, DeferredCallPriority::Normal)
+    }
+
+    /// Register a new client with the given priority
+    ///
+    /// Deferred calls of `Urgent` clients are run before those of `Normal`
+    /// clients. Only latency-sensitive clients should be registered as
+    /// `Urgent`.
+    pub fn register_with_priority(
+        &self,
+        ddc_client: &'static dyn DynamicDeferredCallClient,
+        priority: DeferredCallPriority,
     ) -> Option<DeferredCallHandle> {
         let current_counter = self.handle_counter.get();
 
         if current_counter < self.client_states.len() {
             let client_state = &self.client_states[current_counter];
             client_state.scheduled.set(false);
+            client_state
+                .urgent
+                .set(priority == DeferredCallPriority::Urgent);
             client_state.client.set(ddc_client);
 
             self.handle_counter.set(current_counter + 1);
@@ -224,29 +301,110 @@ impl DynamicDeferredCall {
     ///
     /// It may be called without holding the `DynamicDeferredCall` reference through
     /// `call_global_instance_while`.
+    ///
+    /// Only calls that were scheduled before this function was called are
+    /// run, so a client that schedules itself again runs on the next call.
     pub(self) fn call_while<F: Fn() -> bool>(&self, f: F) {
         if self.call_pending.get() {
-            for (i, client_state) in self.client_states.iter().enumerate() {
-                if !f() {
-                    break;
+            let pass = self.order_counter.get();
+            while f() {
+                match self.next_call(pass) {
+                    Some(i) => self.call_client(i),
+                    None => break,
                 }
-                if client_state.scheduled.get() {
-                    client_state.client.map(|client| {
-                        client_state.scheduled.set(false);
-                        client.call(DeferredCallHandle(i));
-                    });
+            }
+            self.update_pending();
+        }
+    }
+
+    /// Call all registered and to-be-scheduled deferred calls while the supplied
+    /// predicate returns `true`, in turn by client.
+    ///
+    /// It may be called without holding the `DynamicDeferredCall` reference through
+    /// `call_global_instance_round_robin_while`.
+    ///
+    /// As with `call_while`, only calls that were scheduled before this function
+    /// was called are run.
+    pub(self) fn call_round_robin_while<F: Fn() -> bool>(&self, f: F) {
+        if self.call_pending.get() {
+            let pass = self.order_counter.get();
+            while f() {
+                match self.next_round_robin_call(pass) {
+                    Some(i) => {
+                        self.next_client.set((i + 1) % self.client_states.len());
+                        self.call_client(i);
+                    }
+                    None => break,
                 }
             }
+            self.update_pending();
+        }
+    }
 
-            // Recompute call_pending here, as some deferred calls may have been skipped due to the
-            // `f` predicate becoming false.
-            self.call_pending.set(
-                self.client_states
-                    .iter()
-                    .any(|client_state| client_state.scheduled.get()),
-            );
+    fn call_client(&self, i: usize) {
+        let client_state = &self.client_states[i];
+        client_state.client.map(|client| {
+            client_state.scheduled.set(false);
+            client.call(DeferredCallHandle(i));
+        });
+    }
+
+    // Recompute call_pending after calling clients, as some deferred calls may have been
+    // skipped due to the `f` predicate becoming false.
+    fn update_pending(&self) {
+        self.call_pending.set(
+            self.client_states
+                .iter()
+                .any(|client_state| client_state.scheduled.get()),
+        );
+    }
+
+    /// Whether the client at `i` has a call scheduled before `pass`.
+    fn runnable(&self, i: usize, pass: usize) -> bool {
+        let client_state = &self.client_states[i];
+        client_state.scheduled.get()
+            && client_state.client.is_some()
+            && scheduled_before(client_state.order.get(), pass)
+    }
+
+    /// Returns the oldest call of the given urgency scheduled before `pass`.
+    fn oldest_call(&self, urgent: bool, pass: usize) -> Option<usize> {
+        self.client_states
+            .iter()
+            .enumerate()
+            .filter(|&(i, client_state)| {
+                client_state.urgent.get() == urgent && self.runnable(i, pass)
+            })
+            .min_by_key(|(_, client_state)| client_state.order.get().wrapping_sub(pass) as isize)
+            .map(|(i, _)| i)
+    }
+
+    /// Picks the next call to run among those scheduled before `pass`: urgent
+    /// calls first, unless `MAX_URGENT_BURST` of them ran in a row while a
+    /// normal call was waiting.
+    fn next_call(&self, pass: usize) -> Option<usize> {
+        match (self.oldest_call(true, pass), self.oldest_call(false, pass)) {
+            (Some(urgent), None) => Some(urgent),
+            (Some(urgent), Some(_)) if self.urgent_burst.get() < MAX_URGENT_BURST => {
+                self.urgent_burst.set(self.urgent_burst.get() + 1);
+                Some(urgent)
+            }
+            (_, Some(normal)) => {
+                self.urgent_burst.set(0);
+                Some(normal)
+            }
+            (None, None) => None,
         }
     }
+
+    /// Returns the first client with a call scheduled before `pass`, starting
+    /// from the client after the one that ran last.
+    fn next_round_robin_call(&self, pass: usize) -> Option<usize> {
+        let clients = self.client_states.len();
+        (0..clients)
+            .map(|offset| (self.next_client.get() + offset) % clients)
+            .find(|&i| self.runnable(i, pass))
+    }
 }
 
 /// Client for the
@@ -262,3 +420,80 @@ pub trait DynamicDeferredCallClient {
 /// [DynamicDeferredCall](crate::common::dynamic_deferred_call::DynamicDeferredCall)
 #[derive(Copy, Clone, Debug)]
 pub struct DeferredCallHandle(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_init;
+
+    /// Records which clients were called, in order.
+    struct CallLog {
+        calls: [Cell<usize>; 6],
+        len: Cell<usize>,
+    }
+
+    /// Schedules itself again every time it is called.
+    struct Chatty {
+        id: usize,
+        ddc: &'static DynamicDeferredCall,
+        log: &'static CallLog,
+    }
+
+    impl DynamicDeferredCallClient for Chatty {
+        fn call(&self, handle: DeferredCallHandle) {
+            let len = self.log.len.get();
+            self.log.calls[len].set(self.id);
+            self.log.len.set(len + 1);
+            self.ddc.set(handle);
+        }
+    }
+
+    #[test]
+    fn chatty_clients_are_called_in_turn() {
+        let (ddc, log, clients) = unsafe {
+            let client_states =
+                static_init!([DynamicDeferredCallClientState; 3], Default::default());
+            let ddc = static_init!(DynamicDeferredCall, DynamicDeferredCall::new(client_states));
+            let log = static_init!(
+                CallLog,
+                CallLog {
+                    calls: Default::default(),
+                    len: Cell::new(0),
+                }
+            );
+            let clients = static_init!(
+                [Chatty; 3],
+                [
+                    Chatty { id: 0, ddc, log },
+                    Chatty { id: 1, ddc, log },
+                    Chatty { id: 2, ddc, log },
+                ]
+            );
+            (&*ddc, &*log, &*clients)
+        };
+        let mut handles = [None; 3];
+        for (handle, client) in handles.iter_mut().zip(clients.iter()) {
+            *handle = ddc.register(client);
+        }
+        // Scheduled in a different order than the clients were registered.
+        for &i in &[2, 0, 1] {
+            ddc.set(handles[i].unwrap());
+        }
+
+        // Each call only has room for two clients.
+        for _ in 0..3 {
+            let budget = Cell::new(2usize);
+            ddc.call_round_robin_while(|| {
+                let left = budget.get();
+                budget.set(left.saturating_sub(1));
+                left > 0
+            });
+        }
+
+        assert_eq!(log.len.get(), 6);
+        for (call, &id) in log.calls.iter().zip([0, 1, 2, 0, 1, 2].iter()) {
+            assert_eq!(call.get(), id);
+        }
+        assert!(ddc.has_pending());
+    }
+}
@@ -55,7 +55,22 @@ macro_rules! static_buf {
     }};
 }
 
-use core::mem::MaybeUninit;
+use core::mem::{self, MaybeUninit};
+
+/// Number of static buffers initialized so far, and their total size in bytes.
+static mut STATIC_ALLOCATIONS: (usize, usize) = (0, 0);
+
+/// Returns how many buffers created with `static_init!` or `static_buf!` have
+/// been initialized so far, and how many bytes of memory they use in total.
+///
+/// Static buffers are placed in the kernel's BSS, which shrinks the RAM left
+/// for processes. Boards can report this after initialization to help size
+/// their memory layout.
+pub fn static_allocations() -> (usize, usize) {
+    // Safety: the kernel is single threaded and this is only written while a
+    // static buffer is initialized.
+    unsafe { STATIC_ALLOCATIONS }
+}
 
 /// The `UninitializedBuffer` type is designed to be statically allocated
 /// as a global buffer to hold data structures in Tock. As a static, global
@@ -123,6 +138,8 @@ impl<T> StaticUninitializedBuffer<T> {
     /// allows for runtime initialization of `static` values that do not have a
     /// `const` constructor.
     pub unsafe fn initialize(self, value: T) -> &'static mut T {
+        STATIC_ALLOCATIONS.0 += 1;
+        STATIC_ALLOCATIONS.1 += mem::size_of::<T>();
         self.buf.0.as_mut_ptr().write(value);
         // TODO: use MaybeUninit::get_mut() once that is stabilized (see
         // https://github.com/rust-lang/rust/issues/63568).
@@ -33,6 +33,13 @@ pub(crate) struct Config {
     /// If enabled, the kernel will print a message in the debug output for each system call and
     /// callback, with details including the application ID, and system call or callback parameters.
     pub(crate) trace_syscalls: bool,
+    /// Whether the kernel should trace buffers that processes allow to drivers.
+    ///
+    /// If enabled, the kernel will print a message in the debug output each time a process allows
+    /// a buffer to a driver or revokes one, and keeps track of the buffers currently allowed so
+    /// that `Kernel::dump_allowed_buffers` can list them. This helps find buffers that stay
+    /// allowed longer than intended. When disabled, no buffers are tracked.
+    pub(crate) trace_allows: bool,
 
     /// Whether the kernel should show debugging output when loading processes.
     ///
@@ -40,11 +47,23 @@ pub(crate) struct Config {
     /// into which SRAM addresses. This can be useful to debug whether the kernel could
     /// successfully load processes, and whether the allocated SRAM is as expected.
     pub(crate) debug_load_processes: bool,
+
+    /// Whether the kernel loop should check the consistency of the scheduler state.
+    ///
+    /// If enabled, the kernel checks before each scheduling decision that its count of outstanding
+    /// process work matches the processes' states and queued tasks, that no process is left in the
+    /// transient `Fault` state, and that each process is stored at the index its `AppId` refers
+    /// to. A violation panics with a description of it. This catches bugs in the kernel's
+    /// bookkeeping close to where they happen, at the cost of a walk over all processes in every
+    /// loop iteration, so it is meant for development builds only.
+    pub(crate) check_scheduler_invariants: bool,
 }
 
 /// A unique instance of `Config` where compile-time configuration options are defined. These
 /// options are available in the kernel crate to be used for relevant configuration.
 pub(crate) const CONFIG: Config = Config {
     trace_syscalls: false,
+    trace_allows: false,
     debug_load_processes: false,
+    check_scheduler_invariants: false,
 };
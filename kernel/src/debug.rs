@@ -24,6 +24,41 @@
 //! components::debug_queue::DebugQueueComponent::new(buf).finalize(());
 //! ```
 //!
+//! A board can also register a hook that runs at the very start of a panic,
+//! before the panic report is printed, to capture context elsewhere (for
+//! example log to flash or notify over a radio):
+//!
+//! ```ignore
+//! fn board_panic_hook(info: &core::panic::PanicInfo) {
+//!     // ...
+//! }
+//!
+//! kernel::debug::set_panic_hook(board_panic_hook);
+//! ```
+//!
+//! Several messages can be kept together, so that other output such as a
+//! process's console traffic is not interleaved with them, by printing them
+//! in a group:
+//!
+//! ```ignore
+//! kernel::debug::group(|| {
+//!     debug!("fault in {}", name);
+//!     debug!("  pc: {:#x}", pc);
+//! });
+//! ```
+//!
+//! A capsule stuck in a loop can flood the console with `debug!()` output. A
+//! board can cap how many bytes of it are printed per time window, with a
+//! timer that calls `end_rate_limit_window()` at the end of each window, such
+//! as `capsules::debug_rate_limit`. Once a window's budget is used up, further
+//! messages in it are dropped, and a count of them is printed when the window
+//! ends. Output is not limited unless a budget is set, and panic output is
+//! never limited.
+//!
+//! ```ignore
+//! kernel::debug::set_rate_limit(Some(1024));
+//! ```
+//!
 //! Example
 //! -------
 //!
@@ -88,6 +123,21 @@ pub trait IoWrite {
 ///////////////////////////////////////////////////////////////////
 // panic! support routines
 
+/// Board function run at the start of a panic. See `set_panic_hook()`.
+pub type PanicHook = fn(&PanicInfo);
+
+static mut PANIC_HOOK: Option<PanicHook> = None;
+
+/// Register a function to be called at the start of the panic routine.
+///
+/// The hook runs before anything is printed, and is passed the panic
+/// information. It is best-effort: it must not loop or wait on interrupts,
+/// since none will be serviced. A hook runs at most once, so a panic raised
+/// from within the hook skips it and proceeds with the normal panic routine.
+pub unsafe fn set_panic_hook(hook: PanicHook) {
+    PANIC_HOOK = Some(hook);
+}
+
 /// Tock default panic routine.
 ///
 /// **NOTE:** The supplied `writer` must be synchronous.
@@ -99,6 +149,7 @@ pub unsafe fn panic<L: hil::led::Led, W: Write + IoWrite, C: Chip>(
     processes: &'static [Option<&'static dyn ProcessType>],
     chip: &'static Option<&'static C>,
 ) -> ! {
+    panic_hook(panic_info);
     panic_begin(nop);
     panic_banner(writer, panic_info);
     // Flush debug buffer if needed
@@ -108,6 +159,16 @@ pub unsafe fn panic<L: hil::led::Led, W: Write + IoWrite, C: Chip>(
     panic_blink_forever(leds)
 }
 
+/// Run the board's panic hook, if one is registered.
+///
+/// Boards that do not use `panic()` should call this first in their panic
+/// handler.
+pub unsafe fn panic_hook(panic_info: &PanicInfo) {
+    // Remove the hook before calling it, so that a panic inside the hook does
+    // not run it again.
+    PANIC_HOOK.take().map(|hook| hook(panic_info));
+}
+
 /// Generic panic entry.
 ///
 /// This opaque method should always be called at the beginning of a board's
@@ -337,6 +398,16 @@ pub struct DebugWriter {
     internal_buffer: TakeCell<'static, RingBuffer<'static, u8>>,
     // Number of debug!() calls.
     count: Cell<usize>,
+    // How many groups are open; output is held back while this is non-zero.
+    group_depth: Cell<usize>,
+    // Length of the output buffer, the most that can be sent at once.
+    output_len: usize,
+    // Bytes of output allowed per rate limit window, if limited.
+    rate_limit: Cell<Option<usize>>,
+    // Bytes written in the current rate limit window.
+    window_bytes: Cell<usize>,
+    // Messages dropped in the current rate limit window.
+    suppressed: Cell<usize>,
 }
 
 /// Static variable that holds the kernel's reference to the debug tool. This is
@@ -372,9 +443,14 @@ impl DebugWriter {
     ) -> DebugWriter {
         DebugWriter {
             uart: uart,
+            output_len: out_buffer.len(),
             output_buffer: TakeCell::new(out_buffer),
             internal_buffer: TakeCell::new(internal_buffer),
             count: Cell::new(0), // how many debug! calls
+            group_depth: Cell::new(0),
+            rate_limit: Cell::new(None),
+            window_bytes: Cell::new(0),
+            suppressed: Cell::new(0),
         }
     }
 
@@ -392,6 +468,12 @@ impl DebugWriter {
         // Can only publish if we have the output_buffer. If we don't that is
         // fine, we will do it when the transmit done callback happens.
         self.internal_buffer.map(|ring_buffer| {
+            // While a group is open, only send once a full output buffer's
+            // worth of it has built up, so that it goes out in as few
+            // transmissions as possible.
+            if self.group_depth.get() > 0 && ring_buffer.len() < self.output_len {
+                return;
+            }
             if let Some(out_buffer) = self.output_buffer.take() {
                 let mut count = 0;
 
@@ -419,6 +501,41 @@ impl DebugWriter {
     fn extract(&self) -> Option<&mut RingBuffer<'static, u8>> {
         self.internal_buffer.take()
     }
+
+    fn begin_group(&self) {
+        self.group_depth.increment();
+    }
+
+    fn end_group(&self) {
+        if self.group_depth.get() > 0 {
+            self.group_depth.decrement();
+        }
+        self.publish_bytes();
+    }
+
+    fn set_rate_limit(&self, bytes_per_window: Option<usize>) {
+        self.rate_limit.set(bytes_per_window);
+    }
+
+    /// Whether a new message may be printed in the current window. Counts
+    /// the message as suppressed if not. A message that starts within the
+    /// budget is printed in full, even if it goes over.
+    fn admit(&self) -> bool {
+        match self.rate_limit.get() {
+            Some(limit) if self.window_bytes.get() >= limit => {
+                self.suppressed.increment();
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Start a new rate limit window and return how many messages were
+    /// suppressed in the one that ended.
+    fn end_window(&self) -> usize {
+        self.window_bytes.set(0);
+        self.suppressed.replace(0)
+    }
 }
 
 impl hil::uart::TransmitClient for DebugWriter {
@@ -455,12 +572,33 @@ impl DebugWriterWrapper {
     fn extract(&self) -> Option<&mut RingBuffer<'static, u8>> {
         self.dw.map_or(None, |dw| dw.extract())
     }
+
+    fn begin_group(&self) {
+        self.dw.map(|dw| dw.begin_group());
+    }
+
+    fn end_group(&self) {
+        self.dw.map(|dw| dw.end_group());
+    }
+
+    fn set_rate_limit(&self, bytes_per_window: Option<usize>) {
+        self.dw.map(|dw| dw.set_rate_limit(bytes_per_window));
+    }
+
+    fn admit(&self) -> bool {
+        self.dw.map_or(true, |dw| dw.admit())
+    }
+
+    fn end_window(&self) -> usize {
+        self.dw.map_or(0, |dw| dw.end_window())
+    }
 }
 
 impl IoWrite for DebugWriterWrapper {
     fn write(&mut self, bytes: &[u8]) {
         const FULL_MSG: &[u8] = b"\n*** DEBUG BUFFER FULL ***\n";
         self.dw.map(|dw| {
+            dw.window_bytes.add(bytes.len());
             dw.internal_buffer.map(|ring_buffer| {
                 let available_len_for_msg =
                     ring_buffer.available_len().saturating_sub(FULL_MSG.len());
@@ -493,6 +631,9 @@ impl Write for DebugWriterWrapper {
 
 pub fn begin_debug_fmt(args: Arguments) {
     let writer = unsafe { get_debug_writer() };
+    if !writer.admit() {
+        return;
+    }
 
     let _ = write(writer, args);
     let _ = writer.write_str("\r\n");
@@ -501,6 +642,9 @@ pub fn begin_debug_fmt(args: Arguments) {
 
 pub fn begin_debug_verbose_fmt(args: Arguments, file_line: &(&'static str, u32)) {
     let writer = unsafe { get_debug_writer() };
+    if !writer.admit() {
+        return;
+    }
 
     writer.increment_count();
     let count = writer.get_count();
@@ -512,6 +656,55 @@ pub fn begin_debug_verbose_fmt(args: Arguments, file_line: &(&'static str, u32))
     writer.publish_bytes();
 }
 
+/// Start holding back `debug!()` output until the matching `end_group()`,
+/// then send it together. If more is printed than fits in the output buffer,
+/// it is sent early, one full buffer at a time. Groups can be nested; output
+/// is sent when the outermost one ends.
+pub fn begin_group() {
+    if let Some(writer) = unsafe { try_get_debug_writer() } {
+        writer.begin_group();
+    }
+}
+
+/// End a group started with `begin_group()`.
+pub fn end_group() {
+    if let Some(writer) = unsafe { try_get_debug_writer() } {
+        writer.end_group();
+    }
+}
+
+/// Run `f`, sending any `debug!()` output it prints as a group.
+pub fn group<R, F: FnOnce() -> R>(f: F) -> R {
+    begin_group();
+    let result = f();
+    end_group();
+    result
+}
+
+/// Limit `debug!()` output to `bytes_per_window` bytes per rate limit
+/// window, or remove the limit with `None`, the default. Windows are ended by
+/// calling `end_rate_limit_window()`.
+pub fn set_rate_limit(bytes_per_window: Option<usize>) {
+    if let Some(writer) = unsafe { try_get_debug_writer() } {
+        writer.set_rate_limit(bytes_per_window);
+    }
+}
+
+/// End the current rate limit window, printing how many messages were
+/// dropped in it, if any.
+pub fn end_rate_limit_window() {
+    if let Some(writer) = unsafe { try_get_debug_writer() } {
+        let suppressed = writer.end_window();
+        if suppressed > 0 {
+            let _ = writer.write_fmt(format_args!(
+                "*** {} debug messages suppressed ***\r\n",
+                suppressed
+            ));
+            writer.publish_bytes();
+        }
+    }
+}
+
 /// In-kernel `println()` debugging.
 #[macro_export]
 macro_rules! debug {
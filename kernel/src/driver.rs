@@ -39,7 +39,9 @@
 //! understand its function and how it interacts with `subscribe`.
 
 use crate::callback::{AppId, Callback};
+use crate::debug;
 use crate::mem::{AppSlice, Shared};
+use crate::platform::Platform;
 use crate::returncode::ReturnCode;
 
 /// `Driver`s implement the three driver-specific system calls: `subscribe`,
@@ -102,6 +104,13 @@ pub trait Driver {
     /// The buffer is __shared__ between the application and driver, meaning the
     /// driver should not rely on the contents of the buffer to remain
     /// unchanged.
+    ///
+    /// An application revokes a buffer by allowing a null or zero-length
+    /// buffer with the same `minor_num`, in which case `slice` is `None`. The
+    /// driver must then drop the buffer it holds for that `minor_num` and
+    /// stop any operation still using it, as the application may reuse the
+    /// memory as soon as the call returns. Revoking when no buffer is held is
+    /// not an error.
     #[allow(unused_variables)]
     fn allow(
         &self,
@@ -111,4 +120,204 @@ pub trait Driver {
     ) -> ReturnCode {
         ReturnCode::ENOSUPPORT
     }
+
+    /// `allow_max_size` returns the largest buffer, in bytes, the driver is
+    /// willing to accept for the given allow `minor_num`, or `None` if there
+    /// is no limit.
+    ///
+    /// The kernel checks this before calling `allow`, and rejects larger
+    /// buffers with `ESIZE` without calling into the driver.
+    #[allow(unused_variables)]
+    fn allow_max_size(&self, minor_num: usize) -> Option<usize> {
+        None
+    }
+
+    /// `allow_zero_on_revoke` returns whether buffers allowed with the given
+    /// `minor_num` should be zeroed once the driver drops them, normally
+    /// because the app revoked the allow by allowing a null buffer or
+    /// replaced it with another buffer. Drivers that receive secrets, such as
+    /// keys, should return `true` for those buffers so that the secrets do
+    /// not linger in process memory.
+    ///
+    /// Zeroing covers exactly the allowed buffer, and does not happen if the
+    /// process has died or restarted since.
+    #[allow(unused_variables)]
+    fn allow_zero_on_revoke(&self, minor_num: usize) -> bool {
+        false
+    }
+
+    /// `self_test` checks that the hardware behind the driver works, such as
+    /// a timer counting or a bus not being stuck, and returns `SUCCESS` if it
+    /// does. Boards run self-tests during initialization with
+    /// `run_self_tests()`, so they must finish without waiting for
+    /// interrupts.
+    ///
+    /// Drivers without a self-test return `ENOSUPPORT`.
+    fn self_test(&self) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+}
+
+/// Results of the self-tests run by `run_self_tests()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelfTestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    /// Drivers that have no self-test or that the platform does not provide.
+    pub skipped: usize,
+}
+
+impl SelfTestSummary {
+    pub fn all_passed(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Run the self-tests of the drivers with numbers `driver_nums` that
+/// `platform` provides, printing the result of each and a summary with
+/// `debug!()`.
+///
+/// Failed self-tests are only reported, so that the board can decide whether
+/// to continue booting with faulty hardware.
+pub fn run_self_tests<P: Platform>(platform: &P, driver_nums: &[usize]) -> SelfTestSummary {
+    let mut summary = SelfTestSummary::default();
+    for &driver_num in driver_nums {
+        let result = platform.with_driver(driver_num, |driver| {
+            driver.map_or(ReturnCode::ENOSUPPORT, |driver| driver.self_test())
+        });
+        match result {
+            ReturnCode::SUCCESS => summary.passed += 1,
+            ReturnCode::ENOSUPPORT => summary.skipped += 1,
+            err => {
+                debug!("Self-test of driver {:#x} failed: {:?}", driver_num, err);
+                summary.failed += 1;
+            }
+        }
+    }
+    debug!(
+        "Self-tests: {} passed, {} failed, {} skipped",
+        summary.passed, summary.failed, summary.skipped
+    );
+    summary
+}
+
+/// What a `command` argument must be for a command to be accepted by a
+/// `CommandTable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandArg {
+    /// Any value.
+    Any,
+    /// The argument is unused and must be 0.
+    Zero,
+    /// A value less than the given bound.
+    Below(usize),
+    /// An index into something the driver manages, such as its pins. The
+    /// driver passes the number of items to `CommandTable::check()`.
+    Index,
+}
+
+impl CommandArg {
+    fn accepts(self, value: usize, index_bound: usize) -> bool {
+        match self {
+            CommandArg::Any => true,
+            CommandArg::Zero => value == 0,
+            CommandArg::Below(bound) => value < bound,
+            CommandArg::Index => value < index_bound,
+        }
+    }
+}
+
+/// A command a driver supports, and what its two arguments must be.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandSpec {
+    pub num: usize,
+    pub arg1: CommandArg,
+    pub arg2: CommandArg,
+}
+
+impl CommandSpec {
+    pub const fn new(num: usize, arg1: CommandArg, arg2: CommandArg) -> CommandSpec {
+        CommandSpec {
+            num: num,
+            arg1: arg1,
+            arg2: arg2,
+        }
+    }
+}
+
+/// The commands a driver supports, used to validate `command` calls before
+/// dispatching them.
+///
+/// Rather than each driver checking command numbers and arguments in every
+/// arm of its `command` match, and returning whichever error it chose, a
+/// driver can declare its commands once and call `check()` first:
+///
+/// ```rust,ignore
+/// const COMMANDS: CommandTable = CommandTable::new(&[
+///     CommandSpec::new(0, CommandArg::Any, CommandArg::Any),
+///     CommandSpec::new(1, CommandArg::Index, CommandArg::Below(2)),
+/// ]);
+///
+/// fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
+///     let checked = COMMANDS.check(command_num, data1, data2, self.items.len());
+///     if checked != ReturnCode::SUCCESS {
+///         return checked;
+///     }
+///     ...
+/// }
+/// ```
+///
+/// Unknown commands and out of range arguments both return `EINVAL`. The
+/// table is only consulted by drivers that call `check()`, so drivers that do
+/// not use it pay nothing.
+pub struct CommandTable {
+    commands: &'static [CommandSpec],
+}
+
+impl CommandTable {
+    pub const fn new(commands: &'static [CommandSpec]) -> CommandTable {
+        CommandTable { commands: commands }
+    }
+
+    /// Check that `command_num` is a supported command and that its
+    /// arguments are acceptable. `index_bound` is the bound of
+    /// `CommandArg::Index` arguments. Returns `SUCCESS` or `EINVAL`.
+    pub fn check(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        index_bound: usize,
+    ) -> ReturnCode {
+        let valid = self.commands.iter().any(|spec| {
+            spec.num == command_num
+                && spec.arg1.accepts(arg1, index_bound)
+                && spec.arg2.accepts(arg2, index_bound)
+        });
+        if valid {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::EINVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandArg, CommandSpec, CommandTable};
+    use crate::returncode::ReturnCode;
+
+    #[test]
+    fn check_commands() {
+        const COMMANDS: CommandTable = CommandTable::new(&[
+            CommandSpec::new(0, CommandArg::Any, CommandArg::Zero),
+            CommandSpec::new(1, CommandArg::Index, CommandArg::Below(2)),
+        ]);
+        assert_eq!(COMMANDS.check(0, 7, 0, 4), ReturnCode::SUCCESS);
+        assert_eq!(COMMANDS.check(0, 7, 1, 4), ReturnCode::EINVAL);
+        assert_eq!(COMMANDS.check(1, 3, 1, 4), ReturnCode::SUCCESS);
+        assert_eq!(COMMANDS.check(1, 4, 1, 4), ReturnCode::EINVAL);
+        assert_eq!(COMMANDS.check(1, 3, 2, 4), ReturnCode::EINVAL);
+        assert_eq!(COMMANDS.check(2, 0, 0, 4), ReturnCode::EINVAL);
+    }
 }
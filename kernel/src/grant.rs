@@ -202,14 +202,18 @@ impl Allocator {
         self.appid
             .kernel
             .process_map_or(Err(Error::NoSuchApp), self.appid, |process| {
-                process
-                    .alloc(alloc_size, align_of::<T>())
-                    .map_or(Err(Error::OutOfMemory), |buf| {
+                process.alloc(alloc_size, align_of::<T>()).map_or_else(
+                    || {
+                        process.debug_grant_alloc_failed();
+                        Err(Error::OutOfMemory)
+                    },
+                    |buf| {
                         // Convert untyped `*mut u8` allocation to allocated type
                         let ptr = NonNull::cast::<T>(buf);
 
                         Ok(ptr)
-                    })
+                    },
+                )
             })
     }
 }
@@ -245,6 +249,26 @@ impl<'a, T: 'a + ?Sized> DerefMut for Borrowed<'a, T> {
     }
 }
 
+/// Allocate grant `grant_num`, holding a `T`, in the memory of `process` if
+/// it is not allocated yet. Returns `false` if it does not fit.
+pub(crate) fn allocate_grant<T: Default>(process: &dyn ProcessType, grant_num: usize) -> bool {
+    match process.get_grant_ptr(grant_num) {
+        Some(grant_ptr) if !grant_ptr.is_null() => true,
+        Some(_) => match process.alloc(size_of::<T>(), align_of::<T>()) {
+            Some(region) => unsafe {
+                write(region.as_ptr() as *mut T, T::default());
+                process.set_grant_ptr(grant_num, region.as_ptr());
+                true
+            },
+            None => {
+                process.debug_grant_alloc_failed();
+                false
+            }
+        },
+        None => false,
+    }
+}
+
 impl<T: Default> Grant<T> {
     pub(crate) fn new(kernel: &'static Kernel, grant_index: usize) -> Grant<T> {
         Grant {
@@ -254,6 +278,10 @@ impl<T: Default> Grant<T> {
         }
     }
 
+    pub(crate) fn grant_num(&self) -> usize {
+        self.grant_num
+    }
+
     pub fn grant(&self, appid: AppId) -> Option<AppliedGrant<T>> {
         appid.kernel.process_map_or(None, appid, |process| {
             if let Some(grant_ptr) = process.get_grant_ptr(self.grant_num) {
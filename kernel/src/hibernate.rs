@@ -0,0 +1,413 @@
+//! Snapshot and restore all processes, for hibernating to nonvolatile storage.
+//!
+//! A board that wants to power off without losing process state freezes all
+//! processes with `Hibernation::freeze()`, writes the snapshot returned by
+//! `Hibernation::read()` to storage, and powers off. On the next boot it loads
+//! processes as usual and then passes the stored snapshot to
+//! `Hibernation::restore()`, which resumes every process where it was frozen.
+//! If the snapshot is corrupted, was taken by a different kernel or board
+//! version (the `version` passed to `Hibernation::new()`), or does not match
+//! the loaded processes, `restore()` changes nothing and the processes simply
+//! cold start.
+//!
+//! A snapshot holds, for each process, its saved registers, its stack and
+//! heap, its grant regions and its grant pointers. It does not hold any state
+//! the kernel or capsules keep outside of grants, such as an operation a
+//! capsule has in progress with hardware, a pending alarm, or IPC sharing.
+//! Boards should therefore only hibernate while no process is waiting on such
+//! an operation. Processes must not have callbacks queued when they are
+//! frozen, and callbacks queued for them while frozen are lost. Restoring
+//! requires that the architecture implements
+//! `UserspaceKernelBoundary::store_context()`.
+//!
+//! After a successful restore, boards should invalidate the stored snapshot so
+//! that a later reset does not restore the same state again.
+//!
+//! Snapshot format
+//! ---------------
+//!
+//! All fields are little-endian 32-bit words.
+//!
+//! - Header: magic, format version, board version, number of processes.
+//! - For each process, in the order of the processes array: a record (see
+//!   `ProcessRecord`) followed by the contents of the process memory segments
+//!   it describes.
+//! - A CRC-32 of everything before it.
+
+use core::slice;
+
+use crate::capabilities::ProcessManagementCapability;
+use crate::common::cells::OptionalCell;
+use crate::process::State;
+use crate::returncode::ReturnCode;
+use crate::sched::Kernel;
+
+const MAGIC: u32 = 0x4842_4b54; // "TKBH"
+const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 4 * 4;
+const CRC_LEN: usize = 4;
+
+/// Largest architecture stored state a snapshot can hold, in bytes.
+pub const STORED_STATE_MAX: usize = 64;
+
+/// Length of a serialized `ProcessRecord`, in bytes.
+pub const RECORD_LEN: usize = 13 * 4 + STORED_STATE_MAX;
+
+/// What a snapshot records about one process. Memory locations are offsets
+/// from the start of the process's memory.
+#[derive(Clone, Copy)]
+pub struct ProcessRecord {
+    pub flash_start: usize,
+    pub mem_start: usize,
+    pub mem_len: usize,
+    pub identifier: usize,
+    pub state: State,
+    pub restart_count: usize,
+    pub allow_high_water_mark: usize,
+    /// The process-accessible memory `0..app_break` is saved.
+    pub app_break: usize,
+    /// The grant memory `kernel_memory_break..kernel_memory_break + grants_len`
+    /// is saved.
+    pub kernel_memory_break: usize,
+    pub grants_len: usize,
+    /// The grant pointers `grant_ptrs..grant_ptrs + grant_ptrs_len` are saved.
+    pub grant_ptrs: usize,
+    pub grant_ptrs_len: usize,
+    pub stored_state_len: usize,
+    pub stored_state: [u8; STORED_STATE_MAX],
+    /// Number of tasks queued for the process. Not part of the snapshot.
+    pub pending_tasks: usize,
+}
+
+impl ProcessRecord {
+    /// The saved memory segments as `(offset, length)` pairs, in the order
+    /// their contents follow the record.
+    pub fn segments(&self) -> [(usize, usize); 3] {
+        [
+            (0, self.app_break),
+            (self.kernel_memory_break, self.grants_len),
+            (self.grant_ptrs, self.grant_ptrs_len),
+        ]
+    }
+
+    /// Total length of the saved memory segments.
+    pub fn memory_len(&self) -> usize {
+        self.segments().iter().map(|&(_, len)| len).sum()
+    }
+
+    fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let words = [
+            self.flash_start,
+            self.mem_start,
+            self.mem_len,
+            self.identifier,
+            state_to_word(self.state) as usize,
+            self.restart_count,
+            self.allow_high_water_mark,
+            self.app_break,
+            self.kernel_memory_break,
+            self.grants_len,
+            self.grant_ptrs,
+            self.grant_ptrs_len,
+            self.stored_state_len,
+        ];
+        let mut bytes = [0; RECORD_LEN];
+        for (word, chunk) in words.iter().zip(bytes.chunks_exact_mut(4)) {
+            chunk.copy_from_slice(&(*word as u32).to_le_bytes());
+        }
+        bytes[RECORD_LEN - STORED_STATE_MAX..].copy_from_slice(&self.stored_state);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<ProcessRecord> {
+        if bytes.len() != RECORD_LEN {
+            return None;
+        }
+        let word = |index: usize| read_word(bytes, index * 4) as usize;
+        let mut stored_state = [0; STORED_STATE_MAX];
+        stored_state.copy_from_slice(&bytes[RECORD_LEN - STORED_STATE_MAX..]);
+        let record = ProcessRecord {
+            flash_start: word(0),
+            mem_start: word(1),
+            mem_len: word(2),
+            identifier: word(3),
+            state: word_to_state(word(4) as u32)?,
+            restart_count: word(5),
+            allow_high_water_mark: word(6),
+            app_break: word(7),
+            kernel_memory_break: word(8),
+            grants_len: word(9),
+            grant_ptrs: word(10),
+            grant_ptrs_len: word(11),
+            stored_state_len: word(12),
+            stored_state,
+            pending_tasks: 0,
+        };
+        if record.stored_state_len > STORED_STATE_MAX {
+            None
+        } else {
+            Some(record)
+        }
+    }
+}
+
+/// Frozen processes are stopped, so these are the only states a snapshot
+/// holds.
+fn state_to_word(state: State) -> u32 {
+    match state {
+        State::Unstarted => 0,
+        State::StoppedRunning => 1,
+        State::StoppedYielded => 2,
+        _ => 3,
+    }
+}
+
+fn word_to_state(word: u32) -> Option<State> {
+    match word {
+        0 => Some(State::Unstarted),
+        1 => Some(State::StoppedRunning),
+        2 => Some(State::StoppedYielded),
+        3 => Some(State::StoppedFaulted),
+        _ => None,
+    }
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+/// Running CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Copy the part of `chunk`, which starts at `chunk_start` in the snapshot,
+/// that falls into the window of `buf.len()` bytes starting at `offset`.
+fn copy_window(chunk: &[u8], chunk_start: usize, offset: usize, buf: &mut [u8]) {
+    let start = core::cmp::max(chunk_start, offset);
+    let end = core::cmp::min(chunk_start + chunk.len(), offset + buf.len());
+    if start < end {
+        buf[start - offset..end - offset]
+            .copy_from_slice(&chunk[start - chunk_start..end - chunk_start]);
+    }
+}
+
+/// Creates and restores snapshots of all processes.
+pub struct Hibernation {
+    kernel: &'static Kernel,
+    version: u32,
+    /// CRC and total length of the snapshot while processes are frozen.
+    frozen: OptionalCell<(u32, usize)>,
+}
+
+impl Hibernation {
+    /// `version` identifies the kernel and board build. Snapshots are only
+    /// restored by a `Hibernation` with the same version.
+    pub fn new(kernel: &'static Kernel, version: u32) -> Hibernation {
+        Hibernation {
+            kernel,
+            version,
+            frozen: OptionalCell::empty(),
+        }
+    }
+
+    /// Stop all processes and prepare a snapshot of them. Returns the length
+    /// of the snapshot in bytes.
+    ///
+    /// Returns `EBUSY` if a process that has started has callbacks queued,
+    /// `ENOSUPPORT` if the architecture cannot save process state, and
+    /// `EALREADY` if processes are already frozen.
+    pub fn freeze(
+        &self,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Result<usize, ReturnCode> {
+        if self.frozen.is_some() {
+            return Err(ReturnCode::EALREADY);
+        }
+        for process in self.kernel.get_process_iter() {
+            let record = process.hibernation_record().ok_or(ReturnCode::ENOSUPPORT)?;
+            if record.state != State::Unstarted && record.pending_tasks > 0 {
+                return Err(ReturnCode::EBUSY);
+            }
+        }
+        for process in self.kernel.get_process_iter() {
+            process.stop();
+        }
+
+        let mut crc = !0;
+        let mut len = 0;
+        let walked = self.walk(|chunk| {
+            crc = crc32_update(crc, chunk);
+            len += chunk.len();
+        });
+        if !walked {
+            self.resume_all();
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+        self.frozen.set((!crc, len + CRC_LEN));
+        Ok(len + CRC_LEN)
+    }
+
+    /// Copy the snapshot, starting `offset` bytes in, into `buf`. Returns the
+    /// number of bytes copied, which is 0 past the end of the snapshot or if
+    /// processes are not frozen.
+    pub fn read(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        _capability: &dyn ProcessManagementCapability,
+    ) -> usize {
+        self.frozen.map_or(0, |&mut (crc, len)| {
+            let copied = core::cmp::min(buf.len(), len.saturating_sub(offset));
+            let buf = &mut buf[..copied];
+            let mut position = 0;
+            self.walk(|chunk| {
+                copy_window(chunk, position, offset, buf);
+                position += chunk.len();
+            });
+            copy_window(&crc.to_le_bytes(), position, offset, buf);
+            copied
+        })
+    }
+
+    /// Resume the frozen processes, for example because saving the snapshot
+    /// failed.
+    pub fn thaw(&self, _capability: &dyn ProcessManagementCapability) {
+        if self.frozen.is_some() {
+            self.frozen.clear();
+            self.resume_all();
+        }
+    }
+
+    /// Resume processes from a snapshot. This must be called after processes
+    /// are loaded and before the kernel loop starts.
+    ///
+    /// Returns `EINVAL` without changing any process if the snapshot is
+    /// invalid, corrupted, taken with a different version or taken with
+    /// different processes. Returns `FAIL` if a process that matched the
+    /// snapshot could still not be restored; that process is stopped and the
+    /// others are restored.
+    pub fn restore(
+        &self,
+        snapshot: &[u8],
+        _capability: &dyn ProcessManagementCapability,
+    ) -> ReturnCode {
+        if snapshot.len() < HEADER_LEN
+            || read_word(snapshot, 0) != MAGIC
+            || read_word(snapshot, 4) != FORMAT_VERSION
+            || read_word(snapshot, 8) != self.version
+            || read_word(snapshot, 12) as usize != self.kernel.get_process_iter().count()
+        {
+            return ReturnCode::EINVAL;
+        }
+
+        // Check every record before changing anything, so that a bad snapshot
+        // leaves all processes to cold start.
+        let mut position = HEADER_LEN;
+        for process in self.kernel.get_process_iter() {
+            let record = match snapshot
+                .get(position..position + RECORD_LEN)
+                .and_then(ProcessRecord::from_bytes)
+            {
+                Some(record) => record,
+                None => return ReturnCode::EINVAL,
+            };
+            if !process.hibernation_check(&record) {
+                return ReturnCode::EINVAL;
+            }
+            position += RECORD_LEN + record.memory_len();
+        }
+        match snapshot.get(position..position + CRC_LEN) {
+            Some(crc) if read_word(crc, 0) == crc32(&snapshot[..position]) => {}
+            _ => return ReturnCode::EINVAL,
+        }
+
+        let mut result = ReturnCode::SUCCESS;
+        let mut position = HEADER_LEN;
+        for process in self.kernel.get_process_iter() {
+            let record = ProcessRecord::from_bytes(&snapshot[position..position + RECORD_LEN]);
+            position += RECORD_LEN;
+            if let Some(record) = record {
+                let memory = &snapshot[position..position + record.memory_len()];
+                position += record.memory_len();
+                self.kernel.reserve_process_identifier(record.identifier);
+                if unsafe { process.hibernation_restore(&record, memory) }.is_err() {
+                    result = ReturnCode::FAIL;
+                }
+            }
+        }
+        result
+    }
+
+    fn resume_all(&self) {
+        for process in self.kernel.get_process_iter() {
+            process.resume();
+        }
+    }
+
+    /// Call `f` with the snapshot contents in order, except for the final
+    /// CRC. Returns `false` if a process cannot be recorded.
+    fn walk<F: FnMut(&[u8])>(&self, mut f: F) -> bool {
+        let mut header = [0; HEADER_LEN];
+        let count = self.kernel.get_process_iter().count() as u32;
+        for (word, chunk) in [MAGIC, FORMAT_VERSION, self.version, count]
+            .iter()
+            .zip(header.chunks_exact_mut(4))
+        {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        f(&header);
+
+        for process in self.kernel.get_process_iter() {
+            let record = match process.hibernation_record() {
+                Some(record) => record,
+                None => return false,
+            };
+            f(&record.to_bytes());
+            for &(offset, len) in record.segments().iter() {
+                // The processes are frozen and the segments lie within their
+                // memory, so nothing else changes them while they are read.
+                let segment =
+                    unsafe { slice::from_raw_parts(process.mem_start().wrapping_add(offset), len) };
+                f(segment);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_window, crc32};
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn copy_window_overlaps() {
+        let mut buf = [0; 4];
+        // Chunk covering bytes 2..6 of the snapshot, window at 4..8.
+        copy_window(&[1, 2, 3, 4], 2, 4, &mut buf);
+        assert_eq!(buf, [3, 4, 0, 0]);
+        copy_window(&[5, 6], 6, 4, &mut buf);
+        assert_eq!(buf, [3, 4, 5, 6]);
+        // Chunk entirely outside the window.
+        copy_window(&[9], 8, 4, &mut buf);
+        assert_eq!(buf, [3, 4, 5, 6]);
+    }
+}
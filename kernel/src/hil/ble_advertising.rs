@@ -54,14 +54,29 @@ pub trait BleAdvertisementDriver<'a> {
     fn receive_advertisement(&self, channel: RadioChannel);
     fn set_receive_client(&self, client: &'a dyn RxClient);
     fn set_transmit_client(&self, client: &'a dyn TxClient);
+
+    /// Power the radio down between operations. Returns `EBUSY` if a
+    /// transmission or reception is in progress.
+    fn power_down(&self) -> ReturnCode;
+
+    /// Stop any transmission or reception in progress immediately and power
+    /// the radio down. Returns the buffer of an aborted transmission, for
+    /// which no `transmit_event` is signalled.
+    fn abort(&self) -> Option<&'static mut [u8]>;
 }
 
 pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
 }
 
+/// RSSI reported for a received packet when the radio has no measurement
+/// for it. It is above the largest RSSI a BLE radio can report (+20 dBm).
+pub const RSSI_UNAVAILABLE: i8 = 127;
+
 pub trait RxClient {
-    fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode);
+    /// A packet of `len` bytes was received into `buf`, with signal strength
+    /// `rssi` in dBm or `RSSI_UNAVAILABLE`.
+    fn receive_event(&self, buf: &'static mut [u8], len: u8, rssi: i8, result: ReturnCode);
 }
 
 pub trait TxClient {
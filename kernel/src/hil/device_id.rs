@@ -0,0 +1,16 @@
+//! Interface for reading an identifier unique to each chip.
+
+use crate::returncode::ReturnCode;
+
+/// A factory-programmed identifier that differs between chips, unlike the
+/// part number or revision.
+pub trait DeviceId {
+    /// Length of the identifier in bytes.
+    fn id_len(&self) -> usize;
+
+    /// Copy the identifier into the start of `buf`.
+    ///
+    /// Returns `ESIZE` if `buf` is shorter than `id_len()`, and `ENODEVICE`
+    /// if the chip was never programmed with an identifier.
+    fn read_id(&self, buf: &mut [u8]) -> ReturnCode;
+}
@@ -1,10 +1,11 @@
+use crate::capabilities;
 use crate::common::cells::OptionalCell;
 use crate::ReturnCode;
 
 use core::cell::Cell;
 
 /// Enum for configuring any pull-up or pull-down resistors on the GPIO pin.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FloatingState {
     PullUp,
     PullDown,
@@ -19,11 +20,18 @@ pub enum InterruptEdge {
     EitherEdge,
 }
 
+/// Enum for selecting which level a level-triggered interrupt fires on.
+#[derive(Clone, Copy, Debug)]
+pub enum InterruptLevel {
+    High,
+    Low,
+}
+
 /// Enum for which state the pin is in. Some MCUs can support Input/Output pins,
 /// so this is a valid option. `Function` means the pin has been configured to
 /// a special function. Determining which function it outside the scope of the HIL,
 /// and should instead use a chip-specific API.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Configuration {
     /// Cannot be read or written or used; effectively inactive.
     LowPower,
@@ -101,6 +109,12 @@ pub trait Configure {
     /// Return the current floating state of the pin.
     fn floating_state(&self) -> FloatingState;
 
+    /// Enable or disable input hysteresis (a Schmitt trigger) on the pin.
+    /// Returns ENOSUPPORT if the pin's input buffer cannot be configured.
+    fn set_hysteresis(&self, _enable: bool) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
     /// Return whether the pin is an input (reading from
     /// the Input trait will return valid results). Returns
     /// true if the pin is in Configuration::Input or
@@ -193,6 +207,16 @@ pub trait Interrupt<'a>: Input {
     /// should be separately configured as an input, etc.
     fn enable_interrupts(&self, mode: InterruptEdge);
 
+    /// Enable a level-triggered interrupt on the GPIO pin. The
+    /// interrupt fires once while the pin is at `level`, including
+    /// immediately if it already is, and is then disabled until it
+    /// is enabled again. This keeps a pin held at the level from
+    /// causing an interrupt storm. Returns ENOSUPPORT if the pin
+    /// cannot trigger on levels.
+    fn enable_level_interrupts(&self, _level: InterruptLevel) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
     /// Disable interrupts for the GPIO pin.
     fn disable_interrupts(&self);
 
@@ -229,6 +253,12 @@ pub trait InterruptWithValue<'a>: Input {
     ///              the struct is not yet fully initialized.
     fn enable_interrupts(&self, mode: InterruptEdge) -> ReturnCode;
 
+    /// Enable a level-triggered interrupt on the GPIO pin, with the
+    /// same one-shot semantics as `Interrupt::enable_level_interrupts`.
+    fn enable_level_interrupts(&self, _level: InterruptLevel) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
     /// Disable interrupts for the GPIO pin.
     fn disable_interrupts(&self);
 
@@ -251,6 +281,75 @@ pub trait ClientWithValue {
     fn fired(&self, value: u32);
 }
 
+/// Runtime selection of the function a pad is connected to, for chips whose
+/// pads can each serve several peripherals. Function numbers are chip
+/// specific.
+///
+/// Implementations must refuse to reroute a pad that is claimed by another
+/// subsystem, and must refuse to route a peripheral signal to a pad when it is
+/// already routed to another one. Neither the previous function nor any
+/// electrical configuration is restored by the implementation; that is up to
+/// the caller.
+pub trait PadMux {
+    /// Connect `pad` to `function`. Returns `EINVAL` if there is no such pad
+    /// or the function is not available on it, and `EBUSY` if the pad is
+    /// claimed or the function's signal is already routed to another pad.
+    fn set_pad_function(
+        &self,
+        pad: usize,
+        function: usize,
+        capability: &dyn capabilities::PinMuxCapability,
+    ) -> ReturnCode;
+
+    /// The function `pad` is currently connected to, or `None` if there is
+    /// no such pad.
+    fn pad_function(&self, pad: usize) -> Option<usize>;
+}
+
+/// Reading several GPIO ports at the same time, for chips whose pins are read
+/// through more than one input register. Pin `n` is bit `n % 32` of port
+/// `n / 32`.
+///
+/// Reading ports one after the other can tear when inputs change in between,
+/// for example on a parallel bus wider than one port. `read_ports()` reads
+/// all the selected ports back to back with interrupts disabled, so the
+/// values belong together.
+pub trait PortSnapshot {
+    /// The number of ports.
+    fn port_count(&self) -> usize;
+
+    /// The pins of `port` that are configured as GPIO inputs, one bit per
+    /// pin, or 0 if there is no such port.
+    fn input_pins(&self, port: usize) -> u32;
+
+    /// Read the ports selected by `mask`, bit `n` for port `n`, into
+    /// `values` in increasing port order. Returns the number of ports read,
+    /// or `EINVAL` if `mask` selects no port or one that does not exist, and
+    /// `ESIZE` if `values` cannot hold all selected ports.
+    fn read_ports(&self, mask: u32, values: &mut [u32]) -> ReturnCode;
+}
+
+/// Counting the edges on an input pin as they interrupt, without telling the
+/// pin's client of each one, for signals such as tachometer or encoder pulses
+/// that are too frequent to handle one at a time.
+///
+/// Counting uses the pin's interrupt: enabling or disabling its interrupts
+/// through `Interrupt` stops counting.
+pub trait EdgeCounter {
+    /// Start counting `edge`s on the pin, from 0. Returns `ENOSUPPORT` if the
+    /// pin cannot count edges.
+    fn start_counting(&self, edge: InterruptEdge) -> ReturnCode;
+
+    /// Stop counting and disable the pin's interrupt. The count is kept
+    /// until counting starts again.
+    fn stop_counting(&self);
+
+    /// The number of edges counted, saturating at `u32::MAX`. With `reset`,
+    /// the count is set back to 0 in the same step, so no edge is lost
+    /// between reading and resetting.
+    fn edge_count(&self, reset: bool) -> u32;
+}
+
 /// Standard implementation of InterruptWithValue: handles an
 /// `gpio::Client::fired` and passes it up as a
 /// `gpio::ClientWithValue::fired`.
@@ -297,6 +396,10 @@ impl<'a, IP: InterruptPin<'a>> InterruptWithValue<'a> for InterruptValueWrapper<
         ReturnCode::SUCCESS
     }
 
+    fn enable_level_interrupts(&self, level: InterruptLevel) -> ReturnCode {
+        self.source.enable_level_interrupts(level)
+    }
+
     fn disable_interrupts(&self) {
         self.source.disable_interrupts();
     }
@@ -341,6 +444,10 @@ impl<'a, IP: InterruptPin<'a>> Configure for InterruptValueWrapper<'a, IP> {
         self.source.floating_state()
     }
 
+    fn set_hysteresis(&self, enable: bool) -> ReturnCode {
+        self.source.set_hysteresis(enable)
+    }
+
     fn is_input(&self) -> bool {
         self.source.is_input()
     }
@@ -3,6 +3,8 @@
 use core::fmt;
 use core::fmt::{Display, Formatter};
 
+use crate::returncode::ReturnCode;
+
 /// The type of error encoutered during I2C communication.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Error {
@@ -25,6 +27,10 @@ pub enum Error {
     /// The requested operation wasn't supported.
     NotSupported,
 
+    /// The slave held the clock low for longer than allowed, and the transfer
+    /// was aborted.
+    ClockStretchTimeout,
+
     /// No error occured and the command completed successfully.
     CommandComplete,
 }
@@ -37,6 +43,7 @@ impl Display for Error {
             Error::ArbitrationLost => "I2C Bus Arbitration Lost",
             Error::Overrun => "I2C receive overrun",
             Error::NotSupported => "I2C/SMBus command not supported",
+            Error::ClockStretchTimeout => "I2C clock stretched for too long",
             Error::CommandComplete => "I2C Command Completed",
         };
         write!(fmt, "{}", display_str)
@@ -58,6 +65,76 @@ pub trait I2CMaster {
     fn write_read(&self, addr: u8, data: &'static mut [u8], write_len: u8, read_len: u8);
     fn write(&self, addr: u8, data: &'static mut [u8], len: u8);
     fn read(&self, addr: u8, buffer: &'static mut [u8], len: u8);
+
+    /// How many bytes the transfer in progress still has to move on the bus,
+    /// or `None` if the hardware cannot tell. A value that stops changing
+    /// while a transfer is in progress means the bus is stalled, typically
+    /// because the slave is stretching the clock.
+    fn bytes_remaining(&self) -> Option<usize> {
+        None
+    }
+
+    /// Stop the transfer in progress and complete it with `error`. Returns
+    /// `ENOSUPPORT` if the hardware cannot abort transfers.
+    fn abort(&self, _error: Error) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    /// Whether `abort()` can stop transfers on this hardware.
+    fn can_abort(&self) -> bool {
+        false
+    }
+
+    /// The bus frequency in Hz the driver aims for, or `None` if it does not
+    /// know.
+    fn requested_frequency_hz(&self) -> Option<u32> {
+        None
+    }
+
+    /// The bus frequency in Hz the hardware is set up for, computed from its
+    /// clock and dividers, or `None` if the driver does not know. This can
+    /// be lower than the requested frequency, as dividers only take whole
+    /// numbers.
+    fn actual_frequency_hz(&self) -> Option<u32> {
+        None
+    }
+
+    /// Like `write_read()`, but to a device with the 10-bit address `addr`.
+    /// Returns the buffer with `NotSupported` if the hardware cannot address
+    /// such devices.
+    fn write_read_10bit(
+        &self,
+        _addr: u16,
+        data: &'static mut [u8],
+        _write_len: u8,
+        _read_len: u8,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        Err((Error::NotSupported, data))
+    }
+
+    /// Like `write()`, but to a device with the 10-bit address `addr`.
+    /// Returns the buffer with `NotSupported` if the hardware cannot address
+    /// such devices.
+    fn write_10bit(
+        &self,
+        _addr: u16,
+        data: &'static mut [u8],
+        _len: u8,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        Err((Error::NotSupported, data))
+    }
+
+    /// Like `read()`, but from a device with the 10-bit address `addr`.
+    /// Returns the buffer with `NotSupported` if the hardware cannot address
+    /// such devices.
+    fn read_10bit(
+        &self,
+        _addr: u16,
+        buffer: &'static mut [u8],
+        _len: u8,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        Err((Error::NotSupported, buffer))
+    }
 }
 
 /// Interface for an SMBus Master hardware driver.
@@ -0,0 +1,13 @@
+//! Interface for ordering memory accesses with barrier instructions.
+
+/// Barriers that order the memory accesses of the processor as seen by other
+/// bus masters, such as DMA engines or peripherals reading shared buffers.
+pub trait MemoryBarrier {
+    /// All explicit memory accesses before the barrier are observed before
+    /// any explicit memory access after it.
+    fn data_memory_barrier(&self);
+
+    /// All explicit memory accesses before the barrier complete before the
+    /// barrier does, and no instruction after it executes until then.
+    fn data_synchronization_barrier(&self);
+}
@@ -6,6 +6,7 @@ pub mod ble_advertising;
 pub mod bus8080;
 pub mod crc;
 pub mod dac;
+pub mod device_id;
 pub mod digest;
 pub mod eic;
 pub mod entropy;
@@ -16,6 +17,7 @@ pub mod i2c;
 pub mod kv_system;
 pub mod led;
 pub mod log;
+pub mod memory_barrier;
 pub mod nonvolatile_storage;
 pub mod pwm;
 pub mod radio;
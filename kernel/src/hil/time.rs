@@ -185,6 +185,18 @@ pub trait Alarm<'a>: Time {
     /// passed and those in the far far future (see #1651).
     fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks);
 
+    /// Like [`set_alarm`](#tymethod.set_alarm), but reports whether the
+    /// alarm was armed. Implementations that can only arm a limited number
+    /// of alarms at once use this to refuse new ones. Valid `ReturnCode`
+    /// codes are:
+    ///   - `ReturnCode::SUCCESS` the alarm has been set
+    ///   - `ReturnCode::EBUSY` the alarm was not armed and too many
+    ///   alarms are already armed; the previous alarm state is unchanged
+    fn try_set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) -> ReturnCode {
+        self.set_alarm(reference, dt);
+        ReturnCode::SUCCESS
+    }
+
     /// Return the current alarm value. This is undefined at boot and
     /// otherwise returns `now + dt` from the last call to `set_alarm`.
     fn get_alarm(&self) -> Self::Ticks;
@@ -18,6 +18,61 @@ use crate::common::cells::NumericCellExt;
 use crate::process;
 use crate::sched::Kernel;
 
+/// Why a process is not running, as reported by `KernelInfo::blocked_status()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockedReason {
+    /// The process is not blocked: it is running, or it yielded and has
+    /// tasks queued that it will run when next scheduled.
+    Ready,
+
+    /// The process yielded with no tasks queued and has a callback
+    /// subscribed, so it is waiting for a driver to schedule one.
+    WaitingForCallback,
+
+    /// The process yielded with no tasks queued and nothing subscribed, so
+    /// nothing will wake it up. This is how an app that has finished ends.
+    Idle,
+
+    /// The kernel stopped the process. It runs again once resumed.
+    Stopped,
+
+    /// The process faulted and was not restarted.
+    Faulted,
+
+    /// The process has not started yet.
+    Unstarted,
+}
+
+/// Detailed status of a blocked process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockedStatus {
+    /// Why the process is blocked.
+    pub reason: BlockedReason,
+
+    /// Number of callbacks and other tasks queued for the process.
+    pub pending_tasks: usize,
+
+    /// The driver the process most recently subscribed a callback to since
+    /// it started, if any.
+    pub last_subscribed_driver: Option<usize>,
+}
+
+/// Calls `closure` with whether each of the `number_of_grants` grants has
+/// memory allocated, looking its pointer up in a process's grant pointer
+/// table with `grant_ptr`.
+fn each_grant_allocated<F: FnMut(usize, bool)>(
+    number_of_grants: usize,
+    grant_ptr: impl Fn(usize) -> Option<*mut u8>,
+    mut closure: F,
+) {
+    for i in 0..number_of_grants {
+        // If the pointer at that location is not NULL then the grant memory
+        // has been allocated and the grant is being used.
+        let allocated = grant_ptr(i).map_or(false, |ptr| !ptr.is_null());
+        closure(i, allocated);
+    }
+}
+
 /// This struct provides the inspection functions.
 pub struct KernelInfo {
     kernel: &'static Kernel,
@@ -81,6 +136,38 @@ impl KernelInfo {
             .process_map_or("unknown", app, |process| process.get_process_name())
     }
 
+    /// Returns why the process is blocked, with the number of tasks queued
+    /// for it and the driver it last subscribed to. This tells an app that
+    /// is idle apart from one waiting for a callback that never comes.
+    /// Returns `None` if `app` is no longer valid.
+    pub fn blocked_status(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Option<BlockedStatus> {
+        self.kernel.process_map_or(None, app, |process| {
+            let pending_tasks = process.pending_tasks();
+            let reason = match process.get_state() {
+                process::State::Running => BlockedReason::Ready,
+                process::State::Yielded if pending_tasks > 0 => BlockedReason::Ready,
+                process::State::Yielded if process.has_subscriptions() => {
+                    BlockedReason::WaitingForCallback
+                }
+                process::State::Yielded => BlockedReason::Idle,
+                process::State::StoppedRunning | process::State::StoppedYielded => {
+                    BlockedReason::Stopped
+                }
+                process::State::StoppedFaulted | process::State::Fault => BlockedReason::Faulted,
+                process::State::Unstarted => BlockedReason::Unstarted,
+            };
+            Some(BlockedStatus {
+                reason: reason,
+                pending_tasks: pending_tasks,
+                last_subscribed_driver: process.debug_last_subscribed_driver(),
+            })
+        })
+    }
+
     /// Returns the number of syscalls the app has called.
     pub fn number_app_syscalls(
         &self,
@@ -123,33 +210,55 @@ impl KernelInfo {
             .process_map_or(0, app, |process| process.debug_timeslice_expiration_count())
     }
 
+    /// Returns the number of times memory for a grant could not be allocated
+    /// in this app, because its grant region was full.
+    pub fn number_app_grant_alloc_failures(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> usize {
+        self.kernel
+            .process_map_or(0, app, |process| process.debug_grant_alloc_failure_count())
+    }
+
     /// Returns a tuple of the (the number of grants in the grant region this
     /// app has allocated, total number of grants that exist in the system).
     pub fn number_app_grant_uses(
         &self,
         app: AppId,
-        _capability: &dyn ProcessManagementCapability,
+        capability: &dyn ProcessManagementCapability,
     ) -> (usize, usize) {
         // Just need to get the number, this has already been finalized, but it
         // doesn't hurt to call this again.
         let number_of_grants = self.kernel.get_grant_count_and_finalize();
         let mut used = 0;
-        self.kernel.process_map_or((), app, |process| {
-            for i in 0..number_of_grants {
-                if let Some(grant_ptr) = process.get_grant_ptr(i) {
-                    // If the pointer at that location is not NULL then the
-                    // grant memory has been allocated and the grant is
-                    // being used.
-                    if !(grant_ptr.is_null()) {
-                        used += 1;
-                    }
-                }
+        self.app_grant_usage(app, capability, |_, allocated| {
+            if allocated {
+                used += 1;
             }
         });
 
         (used, number_of_grants)
     }
 
+    /// Calls `closure` with the number of each grant in the system, in order,
+    /// and whether this app has allocated memory for it in its grant region.
+    /// Memory for a grant is allocated the first time a capsule enters the
+    /// grant for the app, so this shows which capsules are using the app's
+    /// memory. No grants are allocated in an app that is not running, and
+    /// nothing is reported if there is no such app.
+    pub fn app_grant_usage<F: FnMut(usize, bool)>(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+        closure: F,
+    ) {
+        let number_of_grants = self.kernel.get_grant_count_and_finalize();
+        self.kernel.process_map_or((), app, |process| {
+            each_grant_allocated(number_of_grants, |i| process.get_grant_ptr(i), closure)
+        });
+    }
+
     /// Returns the total number of times all processes have exceeded
     /// their timeslices.
     pub fn timeslice_expirations(&self, _capability: &dyn ProcessManagementCapability) -> usize {
@@ -159,4 +268,77 @@ impl KernelInfo {
         });
         count.get()
     }
+
+    /// Returns how many callbacks were dropped because the process they were
+    /// scheduled for had since unsubscribed, restarted or ended.
+    pub fn stale_callbacks(&self, _capability: &dyn ProcessManagementCapability) -> usize {
+        self.kernel.stale_callback_count()
+    }
+
+    /// Returns the most processes that have been in the `Running` state at
+    /// the same time, since boot or since `reset_peak_running_processes()`.
+    /// A peak well below the number of process slots means the board could
+    /// do with fewer.
+    pub fn peak_running_processes(&self, _capability: &dyn ProcessManagementCapability) -> usize {
+        self.kernel.peak_running_processes()
+    }
+
+    /// Start tracking the peak number of running processes anew, from the
+    /// number running now.
+    pub fn reset_peak_running_processes(&self, _capability: &dyn ProcessManagementCapability) {
+        self.kernel.reset_peak_running_processes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{each_grant_allocated, KernelInfo};
+    use crate::capabilities::{MemoryAllocationCapability, ProcessManagementCapability};
+    use crate::create_capability;
+    use crate::grant::Grant;
+    use crate::process::ProcessType;
+    use crate::sched::Kernel;
+    use crate::testing::MockProcess;
+    use std::boxed::Box;
+
+    #[test]
+    fn only_entered_grants_are_allocated() {
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+        let kernel: &'static Kernel = Box::leak(Box::new(Kernel::new(processes)));
+        process.attach(kernel, 0);
+        let memory_cap = create_capability!(MemoryAllocationCapability);
+        let process_cap = create_capability!(ProcessManagementCapability);
+        let entered: Grant<u32> = kernel.create_grant(&memory_cap);
+        let _untouched: Grant<u32> = kernel.create_grant(&memory_cap);
+
+        assert!(entered
+            .enter(process.appid(), |value, _| **value = 1)
+            .is_ok());
+
+        let info = KernelInfo::new(kernel);
+        let mut allocated = [None; 2];
+        info.app_grant_usage(process.appid(), &process_cap, |i, used| {
+            allocated[i] = Some(used)
+        });
+        assert_eq!(allocated, [Some(true), Some(false)]);
+        assert_eq!(
+            info.number_app_grant_uses(process.appid(), &process_cap),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn inactive_process_has_no_grants() {
+        let mut count = 0;
+        each_grant_allocated(
+            3,
+            |_| None,
+            |_, used| {
+                assert!(!used);
+                count += 1;
+            },
+        );
+        assert_eq!(count, 3);
+    }
 }
@@ -89,14 +89,25 @@
 #![warn(unreachable_pub)]
 #![no_std]
 
+// This is used to run the tests on a host
+#[cfg(test)]
+extern crate std;
+
+pub mod app_pool;
 pub mod capabilities;
 pub mod common;
 pub mod component;
 pub mod debug;
+pub mod hibernate;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod power_budget;
+pub mod swap;
 pub mod syscall;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod uptime;
 
 mod callback;
 mod config;
@@ -110,19 +121,29 @@ mod returncode;
 mod sched;
 
 pub use crate::callback::{AppId, Callback};
-pub use crate::driver::Driver;
+pub use crate::driver::{
+    run_self_tests, CommandArg, CommandSpec, CommandTable, Driver, SelfTestSummary,
+};
 pub use crate::grant::{DynamicGrant, Grant};
 pub use crate::mem::{AppSlice, Private, Shared};
 pub use crate::platform::scheduler_timer::{SchedulerTimer, VirtualSchedulerTimer};
 pub use crate::platform::watchdog;
-pub use crate::platform::{mpu, Chip, InterruptService, Platform};
+pub use crate::platform::{
+    mpu, Chip, DeepSleepVeto, InterruptService, Platform, SleepNotifier, SystemReset, UptimeClock,
+    WaitTimeClock, WakeSource, WakeupTimer,
+};
 pub use crate::platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
 pub use crate::returncode::ReturnCode;
 pub use crate::sched::cooperative::{CoopProcessNode, CooperativeSched};
+pub use crate::sched::edf::{EDFProcessNode, EDFSched, ProcessDeadline};
 pub use crate::sched::mlfq::{MLFQProcessNode, MLFQSched};
 pub use crate::sched::priority::PrioritySched;
+pub use crate::sched::proportional::{ProcessWeight, ProportionalProcessNode, ProportionalSched};
 pub use crate::sched::round_robin::{RoundRobinProcessNode, RoundRobinSched};
-pub use crate::sched::{Kernel, Scheduler};
+pub use crate::sched::two_tier::{TwoTierProcessNode, TwoTierSched};
+pub use crate::sched::{
+    FaultAction, InitProcessFaultPolicy, Kernel, Scheduler, SchedulingParameter,
+};
 
 // Export only select items from the process module. To remove the name conflict
 // this cannot be called `process`, so we use a shortened version. These
@@ -131,8 +152,9 @@ pub use crate::sched::{Kernel, Scheduler};
 /// Publicly available process-related objects.
 pub mod procs {
     pub use crate::process::{
-        load_processes, AlwaysRestart, Error, FaultResponse, FunctionCall, FunctionCallSource,
-        Process, ProcessLoadError, ProcessRestartPolicy, ProcessType, State, Task,
-        ThresholdRestart, ThresholdRestartThenPanic,
+        load_processes, load_processes_checking_drivers, AlwaysRestart, Error, FaultResponse,
+        FunctionCall, FunctionCallSource, Process, ProcessLoadError, ProcessRestartPolicy,
+        ProcessType, RestartThrottleTimer, State, Task, ThresholdRestart,
+        ThresholdRestartThenPanic,
     };
 }
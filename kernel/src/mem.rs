@@ -1,7 +1,7 @@
 //! Data structure for passing application memory to the kernel.
 
 use core::marker::PhantomData;
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
 use core::slice;
 
 use crate::callback::AppId;
@@ -37,9 +37,14 @@ impl<L, T> AppPtr<L, T> {
 /// Buffer of memory shared from an app to the kernel.
 ///
 /// This is the type created after an app calls the `allow` syscall.
+///
+/// If the driver asked for it with `Driver::allow_zero_on_revoke`, the buffer
+/// is zeroed when the `AppSlice` is dropped, which is when the driver lets go
+/// of it because the app revoked or replaced the allow.
 pub struct AppSlice<L, T> {
     ptr: AppPtr<L, T>,
     len: usize,
+    zero_on_drop: bool,
 }
 
 impl<L, T> AppSlice<L, T> {
@@ -49,6 +54,7 @@ impl<L, T> AppSlice<L, T> {
         AppSlice {
             ptr: AppPtr::new(ptr, appid),
             len: len,
+            zero_on_drop: false,
         }
     }
     /// Safety: Trusts that `ptr` + `len` is a buffer in the memory region owned
@@ -65,9 +71,15 @@ impl<L, T> AppSlice<L, T> {
         AppSlice {
             ptr: AppPtr::new(ptr, appid),
             len: len,
+            zero_on_drop: false,
         }
     }
 
+    /// Zero the buffer when this `AppSlice` is dropped.
+    pub(crate) fn set_zero_on_drop(&mut self) {
+        self.zero_on_drop = true;
+    }
+
     /// Number of bytes in the `AppSlice`.
     ///
     /// If the app died, has restarted, or its AppId identifier
@@ -200,3 +212,20 @@ impl<L, T> AsMut<[T]> for AppSlice<L, T> {
             })
     }
 }
+
+impl<L, T> Drop for AppSlice<L, T> {
+    fn drop(&mut self) {
+        if self.zero_on_drop {
+            // Only zero the buffer if the process it belongs to still owns
+            // it. After the process dies or restarts the memory may belong to
+            // its next execution.
+            let (ptr, len) = (self.ptr.ptr.as_ptr(), self.len);
+            self.ptr
+                .process
+                .kernel
+                .process_map_or((), self.ptr.process, |_| unsafe {
+                    ptr::write_bytes(ptr, 0, len);
+                });
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! Interface for chips and boards.
 
+use crate::capabilities;
 use crate::driver::Driver;
 use crate::process;
 use crate::returncode;
@@ -184,6 +185,77 @@ pub trait InterruptService<T> {
     unsafe fn service_deferred_call(&self, task: T) -> bool;
 }
 
+/// Interface for something that can prevent the chip from entering its
+/// deepest sleep state, for example because a process relies on peripherals
+/// that deep sleep would shut down. Chips that select between sleep depths
+/// consult this before sleeping.
+pub trait DeepSleepVeto {
+    /// Returns `true` if the chip must not enter deep sleep.
+    fn deep_sleep_vetoed(&self) -> bool;
+}
+
+/// Interface for warning processes before the chip sleeps, so they can save
+/// state or release peripherals that deep sleep would shut down. The kernel
+/// loop asks it right before putting the chip to sleep, see
+/// `Kernel::set_sleep_notifier()`.
+pub trait SleepNotifier {
+    /// Called with interrupts disabled when the chip is about to sleep.
+    /// Returns `false` to put off sleeping, because processes were just
+    /// notified and need to run first.
+    fn ready_to_sleep(&self) -> bool;
+}
+
+/// Interface to reset the whole system in a controlled way, as opposed to a
+/// reset caused by a crash or the watchdog.
+pub trait SystemReset {
+    /// Reset the chip. Chips record `reason` somewhere that survives the
+    /// reset, so that after booting the board can tell that the reset was
+    /// requested and why.
+    fn reset(&self, reason: u32, capability: &dyn capabilities::SystemResetCapability) -> !;
+}
+
+/// Interface for finding out what woke the chip from sleep, to track down
+/// wakeups that waste power. Chips record the interrupt pending right after
+/// `Chip::sleep()` returns.
+pub trait WakeSource {
+    /// Returns the interrupt that woke the chip the last time it slept, or
+    /// `None` if it has not slept yet or woke without a pending interrupt.
+    fn last_wake_source(&self) -> Option<u32>;
+
+    /// Returns how many times `interrupt` has woken the chip since the counts
+    /// were last reset. Counts saturate instead of wrapping.
+    fn wake_count(&self, interrupt: u32) -> u32;
+
+    /// Reset all wake counts to zero.
+    fn reset_wake_counts(&self);
+}
+
+/// Interface for a timer that can wake the chip from sleep after a delay. The
+/// kernel uses it to sleep for a bounded time while processes are still
+/// ready, see `Kernel::set_min_loop_period()`, and to wake up in time for the
+/// scheduler's next decision, see `Kernel::set_decision_wakeup_timer()`.
+pub trait WakeupTimer {
+    /// Make sure the chip is woken up, by an interrupt, no later than `us`
+    /// microseconds from now.
+    fn set_wakeup(&self, us: u32);
+}
+
+/// Interface for a free-running microsecond clock. The kernel uses it to time
+/// how long processes wait to run, see `Kernel::set_wait_time_clock()`.
+pub trait WaitTimeClock {
+    /// The current time in microseconds. The value wraps around, only
+    /// differences between two readings are meaningful.
+    fn now_us(&self) -> u32;
+}
+
+/// Interface for a microsecond clock that counts from boot and does not wrap
+/// in the lifetime of a device. The kernel reports it as the time since boot,
+/// see `Kernel::set_uptime_clock()`.
+pub trait UptimeClock {
+    /// Microseconds since boot.
+    fn uptime_us(&self) -> u64;
+}
+
 /// Generic operations that clock-like things are expected to support.
 pub trait ClockInterface {
     fn is_enabled(&self) -> bool;
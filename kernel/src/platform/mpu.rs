@@ -1,6 +1,7 @@
 //! Interface for configuring the Memory Protection Unit.
 
 use crate::callback::AppId;
+use crate::capabilities::MpuInfoCapability;
 use core::cmp;
 use core::fmt::{self, Display};
 
@@ -50,6 +51,26 @@ impl Region {
     }
 }
 
+/// A range of peripheral registers that a board allows to be mapped directly
+/// into the address space of one process. See
+/// `Kernel::set_peripheral_regions()`.
+#[derive(Copy, Clone)]
+pub struct PeripheralRegion {
+    /// Address of the first register in the range.
+    pub start_address: usize,
+
+    /// Length of the range in bytes. The range must be one the MPU can cover
+    /// exactly, which usually means a power of two aligned to its size.
+    pub size: usize,
+
+    /// Access given to the process.
+    pub permissions: Permissions,
+
+    /// Flash address of the TBF header of the only process the range may be
+    /// mapped into, see `Kernel::lookup_app_by_flash_address()`.
+    pub app_flash_address: usize,
+}
+
 /// Null type for the default type of the `MpuConfig` type in an implementation
 /// of the `MPU` trait. We need this to workaround a bug in the Rust compiler.
 ///
@@ -65,6 +86,27 @@ impl Display for MpuConfigDefault {
     }
 }
 
+/// Fixed limits of the MPU hardware that every region has to satisfy.
+///
+/// These describe the hardware, not the current configuration, so they can be
+/// compared against the regions a process needs before it is loaded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MpuLimits {
+    /// The number of regions the MPU supports, including the ones the kernel
+    /// uses for each process' flash and RAM.
+    pub total_regions: usize,
+    /// The smallest size of a region in bytes.
+    pub min_region_size: usize,
+    /// The alignment of region start addresses in bytes.
+    pub min_alignment: usize,
+    /// Whether region sizes have to be powers of two, with each region aligned
+    /// to its size.
+    pub power_of_two_sizes: bool,
+    /// The number of equally sized subregions a region can be split into, or 0
+    /// if the MPU has no subregions.
+    pub subregions: usize,
+}
+
 /// The generic trait that particular memory protection unit implementations
 /// need to implement.
 ///
@@ -122,6 +164,16 @@ pub trait MPU {
         0
     }
 
+    /// Returns the fixed limits of the MPU hardware, or `None` if the
+    /// implementation does not describe them.
+    ///
+    /// This reveals how the hardware constrains process memory, so it is
+    /// guarded by a capability and only the board decides who may learn it.
+    #[allow(unused_variables)]
+    fn limits(&self, capability: &dyn MpuInfoCapability) -> Option<MpuLimits> {
+        None
+    }
+
     /// Allocates a new MPU region.
     ///
     /// An implementation must allocate an MPU region at least `min_region_size`
@@ -158,6 +210,22 @@ pub trait MPU {
         }
     }
 
+    /// Removes an MPU region previously allocated with `allocate_region()`.
+    ///
+    /// # Arguments
+    ///
+    /// - `region`: region returned by `allocate_region()`
+    /// - `config`: MPU region configuration
+    ///
+    /// # Return Value
+    ///
+    /// Returns an error if `region` is not in `config`, or if the
+    /// implementation does not support removing regions.
+    #[allow(unused_variables)]
+    fn remove_region(&self, region: Region, config: &mut Self::MpuConfig) -> Result<(), ()> {
+        Err(())
+    }
+
     /// Chooses the location for a process's memory, and allocates an MPU region
     /// covering the app-owned part.
     ///
@@ -1,5 +1,7 @@
 //! Interface for configuring a watchdog
 
+use crate::returncode::ReturnCode;
+
 /// A trait for implementing a watchdog in the kernel.
 /// This trait is called from the `kernel_loop()` code to setup
 /// and maintain the watchdog timer.
@@ -33,3 +35,25 @@ pub trait WatchDog {
 
 /// Implement default WatchDog trait for unit.
 impl WatchDog for () {}
+
+/// A watchdog whose timeout can be read and changed while the kernel runs.
+pub trait WatchDogTimeout {
+    /// The shortest and the longest timeout the hardware supports, in
+    /// milliseconds.
+    fn timeout_range_ms(&self) -> (u32, u32);
+
+    /// The current timeout in milliseconds.
+    fn timeout_ms(&self) -> u32;
+
+    /// The timeout, in milliseconds, that `set_timeout_ms(timeout_ms)` would
+    /// set, or `None` if it would return `EINVAL`.
+    fn supported_timeout_ms(&self, timeout_ms: u32) -> Option<u32>;
+
+    /// Change the timeout to `timeout_ms` milliseconds, or the closest shorter
+    /// timeout the hardware supports.
+    ///
+    /// The countdown restarts with the new timeout, so changing it cannot
+    /// make a running watchdog expire. Returns `EINVAL` if the timeout is
+    /// outside of `timeout_range_ms()`.
+    fn set_timeout_ms(&self, timeout_ms: u32) -> ReturnCode;
+}
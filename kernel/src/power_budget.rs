@@ -0,0 +1,188 @@
+//! Coarse accounting of the power drawn by peripherals, to stay within a
+//! budget.
+//!
+//! A device powered by a small solar cell can only draw as much power as it
+//! harvests on average. A board can create a `PowerBudget` and give each
+//! peripheral driver that draws significant power, such as a radio or a bus,
+//! a `PowerConsumer` with the estimated power the peripheral draws while it
+//! is enabled. Drivers call `PowerConsumer::enable()` before they enable the
+//! peripheral and `PowerConsumer::disable()` once they disable it again. The
+//! budget adds up the estimates of all enabled consumers, and once a budget
+//! is set, refuses to enable a consumer that would take the total over it.
+//!
+//! The estimates are fixed numbers chosen by the board, so the total is only
+//! an approximation of the actual draw. It does not include the processor
+//! itself or peripherals without a consumer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::capabilities::PowerBudgetCapability;
+//! # use kernel::{create_capability, static_init};
+//! # unsafe {
+//! # let power_budget_cap = create_capability!(PowerBudgetCapability);
+//!
+//! let power_budget = static_init!(
+//!     kernel::power_budget::PowerBudget,
+//!     kernel::power_budget::PowerBudget::new()
+//! );
+//! power_budget.set_budget_uw(Some(20_000), &power_budget_cap);
+//! let radio_power = static_init!(
+//!     kernel::power_budget::PowerConsumer,
+//!     kernel::power_budget::PowerConsumer::new(power_budget, 10_000, &power_budget_cap)
+//! );
+//! # }
+//! ```
+
+use core::cell::Cell;
+
+use crate::capabilities::PowerBudgetCapability;
+use crate::returncode::ReturnCode;
+
+/// The budget and the estimated draw of all enabled consumers.
+pub struct PowerBudget {
+    budget_uw: Cell<Option<u32>>,
+    draw_uw: Cell<u32>,
+    peak_draw_uw: Cell<u32>,
+    refused: Cell<usize>,
+}
+
+impl PowerBudget {
+    /// Create a budget without a limit, see `set_budget_uw()`.
+    pub const fn new() -> PowerBudget {
+        PowerBudget {
+            budget_uw: Cell::new(None),
+            draw_uw: Cell::new(0),
+            peak_draw_uw: Cell::new(0),
+            refused: Cell::new(0),
+        }
+    }
+
+    /// Set the most power, in microwatts, that enabled consumers may draw
+    /// together, or remove the limit with `None`. Consumers that are already
+    /// enabled stay enabled even if they exceed a lower budget.
+    pub fn set_budget_uw(&self, budget_uw: Option<u32>, _capability: &dyn PowerBudgetCapability) {
+        self.budget_uw.set(budget_uw);
+    }
+
+    /// The budget in microwatts, or `None` if there is no limit.
+    pub fn budget_uw(&self) -> Option<u32> {
+        self.budget_uw.get()
+    }
+
+    /// The estimated draw of all enabled consumers, in microwatts.
+    pub fn draw_uw(&self) -> u32 {
+        self.draw_uw.get()
+    }
+
+    /// The highest estimated draw since boot, in microwatts.
+    pub fn peak_draw_uw(&self) -> u32 {
+        self.peak_draw_uw.get()
+    }
+
+    /// How many times enabling a consumer was refused. Saturates instead of
+    /// wrapping.
+    pub fn refused_count(&self) -> usize {
+        self.refused.get()
+    }
+
+    fn add(&self, cost_uw: u32) -> ReturnCode {
+        let draw_uw = self.draw_uw.get().saturating_add(cost_uw);
+        if self
+            .budget_uw
+            .get()
+            .map_or(false, |budget| draw_uw > budget)
+        {
+            self.refused.set(self.refused.get().saturating_add(1));
+            return ReturnCode::ERESERVE;
+        }
+        self.draw_uw.set(draw_uw);
+        if draw_uw > self.peak_draw_uw.get() {
+            self.peak_draw_uw.set(draw_uw);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn remove(&self, cost_uw: u32) {
+        self.draw_uw.set(self.draw_uw.get().saturating_sub(cost_uw));
+    }
+}
+
+/// A peripheral whose estimated draw counts against a `PowerBudget` while it
+/// is enabled.
+pub struct PowerConsumer {
+    budget: &'static PowerBudget,
+    cost_uw: u32,
+    enabled: Cell<bool>,
+}
+
+impl PowerConsumer {
+    /// Create a consumer that draws `cost_uw` microwatts while enabled.
+    pub fn new(
+        budget: &'static PowerBudget,
+        cost_uw: u32,
+        _capability: &dyn PowerBudgetCapability,
+    ) -> PowerConsumer {
+        PowerConsumer {
+            budget,
+            cost_uw,
+            enabled: Cell::new(false),
+        }
+    }
+
+    /// Count the consumer's draw against the budget before its peripheral is
+    /// enabled. Returns `ERESERVE`, and the peripheral must stay disabled, if
+    /// this would exceed the budget. Enabling a consumer that is already
+    /// enabled succeeds without counting it twice.
+    pub fn enable(&self) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::SUCCESS;
+        }
+        let result = self.budget.add(self.cost_uw);
+        if result == ReturnCode::SUCCESS {
+            self.enabled.set(true);
+        }
+        result
+    }
+
+    /// Stop counting the consumer's draw once its peripheral is disabled.
+    /// Does nothing if the consumer is not enabled.
+    pub fn disable(&self) {
+        if self.enabled.replace(false) {
+            self.budget.remove(self.cost_uw);
+        }
+    }
+
+    /// Whether the consumer's draw is counted against the budget.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PowerBudget;
+    use crate::returncode::ReturnCode;
+
+    #[test]
+    fn refuses_beyond_budget() {
+        let budget = PowerBudget::new();
+        budget.budget_uw.set(Some(100));
+        assert_eq!(budget.add(60), ReturnCode::SUCCESS);
+        assert_eq!(budget.add(60), ReturnCode::ERESERVE);
+        assert_eq!(budget.add(40), ReturnCode::SUCCESS);
+        assert_eq!(budget.draw_uw(), 100);
+        assert_eq!(budget.refused_count(), 1);
+    }
+
+    #[test]
+    fn tracks_peak() {
+        let budget = PowerBudget::new();
+        assert_eq!(budget.add(70), ReturnCode::SUCCESS);
+        budget.remove(70);
+        assert_eq!(budget.add(20), ReturnCode::SUCCESS);
+        assert_eq!(budget.draw_uw(), 20);
+        assert_eq!(budget.peak_draw_uw(), 70);
+    }
+}
@@ -14,10 +14,11 @@ use crate::common::cells::{MapCell, NumericCellExt};
 use crate::common::{Queue, RingBuffer};
 use crate::config;
 use crate::debug;
+use crate::hibernate;
 use crate::ipc;
 use crate::mem::{AppSlice, Shared};
 use crate::platform::mpu::{self, MPU};
-use crate::platform::Chip;
+use crate::platform::{Chip, Platform};
 use crate::returncode::ReturnCode;
 use crate::sched::Kernel;
 use crate::syscall::{self, Syscall, UserspaceKernelBoundary};
@@ -139,6 +140,53 @@ pub fn load_processes<C: Chip>(
     procs: &'static mut [Option<&'static dyn ProcessType>],
     fault_response: FaultResponse,
     _capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_response,
+        &|_| true,
+    )
+}
+
+/// Like `load_processes()`, but does not start processes that require, in
+/// their TBF header, a driver that `platform` does not provide. The missing
+/// driver is printed with `debug!()`, and loading continues with the next
+/// process.
+pub fn load_processes_checking_drivers<C: Chip, P: Platform>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    platform: &P,
+    app_flash: &'static [u8],
+    app_memory: &'static mut [u8],
+    procs: &'static mut [Option<&'static dyn ProcessType>],
+    fault_response: FaultResponse,
+    _capability: &dyn ProcessManagementCapability,
+) -> Result<(), ProcessLoadError> {
+    load_processes_inner(
+        kernel,
+        chip,
+        app_flash,
+        app_memory,
+        procs,
+        fault_response,
+        &|driver_num| platform.with_driver(driver_num, |driver| driver.is_some()),
+    )
+}
+
+/// Shared implementation of the process loaders. `has_driver` tells whether
+/// the board provides a given driver.
+fn load_processes_inner<C: Chip>(
+    kernel: &'static Kernel,
+    chip: &'static C,
+    app_flash: &'static [u8],
+    app_memory: &'static mut [u8],
+    procs: &'static mut [Option<&'static dyn ProcessType>],
+    fault_response: FaultResponse,
+    has_driver: &dyn Fn(usize) -> bool,
 ) -> Result<(), ProcessLoadError> {
     if config::CONFIG.debug_load_processes {
         debug!(
@@ -225,6 +273,7 @@ pub fn load_processes<C: Chip>(
                     remaining_memory,
                     fault_response,
                     i,
+                    has_driver,
                 )?
             };
             process_option.map(|process| {
@@ -281,10 +330,51 @@ pub trait ProcessType {
     /// `None`.
     fn dequeue_task(&self) -> Option<Task>;
 
+    /// Returns how many `Task`s are queued for the process.
+    fn pending_tasks(&self) -> usize;
+
     /// Remove all scheduled callbacks for a given callback id from the task
     /// queue.
     fn remove_pending_callbacks(&self, callback_id: CallbackId);
 
+    /// Returns whether a callback for `callback_id` is scheduled and has not
+    /// been delivered yet.
+    fn has_pending_callback(&self, callback_id: CallbackId) -> bool;
+
+    /// Record that the process subscribed the function at `fn_ptr` with
+    /// `appdata` to `callback_id`. A `fn_ptr` of 0 records an unsubscribe.
+    fn set_subscription(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize);
+
+    /// Returns whether a callback to the function at `fn_ptr` with `appdata`
+    /// matches the current subscription to `callback_id`. Callbacks that do
+    /// not are stale and must not be delivered.
+    ///
+    /// Processes only track a limited number of subscriptions, and callbacks
+    /// for untracked ones are assumed to be current.
+    fn is_subscribed(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize) -> bool;
+
+    /// Returns whether the process has a function subscribed to any of its
+    /// tracked subscriptions.
+    fn has_subscriptions(&self) -> bool;
+
+    /// Record that the process allowed the buffer at `address` of `size`
+    /// bytes to `subdriver_number` of driver `driver_number`. A null address
+    /// or a size of 0 records a revoke.
+    ///
+    /// Allowed buffers are only tracked if `trace_allows` is enabled in the
+    /// kernel configuration, and only a limited number of them.
+    fn trace_allow(
+        &self,
+        driver_number: usize,
+        subdriver_number: usize,
+        address: usize,
+        size: usize,
+    );
+
+    /// Call `closure` with the driver number, subdriver number, address and
+    /// size of each tracked buffer the process currently allows.
+    fn traced_allows_each(&self, closure: &mut dyn FnMut(usize, usize, usize, usize));
+
     /// Returns the current state the process is in. Common states are "running"
     /// or "yielded".
     fn get_state(&self) -> State;
@@ -311,9 +401,45 @@ pub trait ProcessType {
     /// `FaultResponse` for this process to occur.
     fn set_fault_state(&self);
 
+    /// Keep this yielded process from running, even if tasks are queued for
+    /// it, until `release_hold()` is called. While it is held its queued tasks
+    /// do not count as work for the kernel. `since_us` and `for_us` are kept
+    /// for whoever releases the hold, see `hold_time()`.
+    ///
+    /// Returns `false`, doing nothing, if the process is not yielded.
+    fn hold(&self, since_us: u32, for_us: u32) -> bool;
+
+    /// When the hold on this process started and how long it lasts, in
+    /// microseconds, or `None` if the process is not held.
+    fn hold_time(&self) -> Option<(u32, u32)>;
+
+    /// Let a held process run again. Does nothing if it is not held.
+    fn release_hold(&self);
+
     /// Returns how many times this process has been restarted.
     fn get_restart_count(&self) -> usize;
 
+    /// Restart this process if its restart was delayed because it kept
+    /// faulting at the same instruction (see `RestartThrottleTimer`). Does
+    /// nothing otherwise.
+    fn finish_throttled_restart(&self);
+
+    /// Discard all state of this process and start it again from its image
+    /// in flash: its RAM is zeroed, its grants and pending tasks are freed,
+    /// and it gets a new identifier, so any `AppId` for the old process is
+    /// no longer valid. This happens regardless of the process's
+    /// `FaultResponse`.
+    ///
+    /// Returns `false`, leaving the process stopped and faulted, if it could
+    /// not be started again.
+    fn reload(&self) -> bool;
+
+    /// Stop this process for good, as if it faulted with
+    /// `FaultResponse::Stop`: its grants, pending tasks and other kernel
+    /// resources are freed and it is left `StoppedFaulted`. Used to swap a
+    /// process out of RAM (see `swap::SwapManager`).
+    fn stop_and_free(&self);
+
     /// Get the name of the process. Used for IPC.
     fn get_process_name(&self) -> &'static str;
 
@@ -351,6 +477,20 @@ pub trait ProcessType {
     /// The lowest address of the grant region for the process.
     fn kernel_memory_break(&self) -> *const u8;
 
+    /// Checks if the buffer starting at `buf_start_addr` of `size` bytes lies
+    /// entirely within the RAM currently accessible to the process, that is
+    /// from the start of its memory up to its app break. A buffer that passes
+    /// this check does not overlap the grant region or kernel memory.
+    ///
+    /// Capsules that receive addresses from a process other than through
+    /// `allow`, for example as command arguments, must validate them with this
+    /// (or `in_app_flash_memory()`) before accessing them.
+    fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool;
+
+    /// Checks if the buffer starting at `buf_start_addr` of `size` bytes lies
+    /// entirely within the flash region of the process.
+    fn in_app_flash_memory(&self, buf_start_addr: *const u8, size: usize) -> bool;
+
     /// How many writeable flash regions defined in the TBF header for this
     /// process.
     fn number_writeable_flash_regions(&self) -> usize;
@@ -372,7 +512,7 @@ pub trait ProcessType {
 
     /// Creates an `AppSlice` from the given offset and size in process memory.
     ///
-    /// If `buf_start_addr` is NULL this will have no effect and the return
+    /// If `buf_start_addr` is NULL or `size` is zero this will have no effect and the return
     /// value will be `None` to signal the capsule to drop the buffer.
     ///
     /// If the process is not active then this will return an error as it is not
@@ -382,8 +522,9 @@ pub trait ProcessType {
     ///
     /// ## Returns
     ///
-    /// If the buffer is null (a zero-valued offset) this returns `None`,
-    /// signaling the capsule to delete the entry. If the buffer is within the
+    /// If the buffer is null (a zero-valued offset) or has a length of zero
+    /// this returns `None`, signaling the capsule to revoke any buffer
+    /// previously allowed with the same number. If the buffer is within the
     /// process's accessible memory, returns an `AppSlice` wrapping that buffer.
     /// Otherwise, returns an error `ReturnCode`.
     fn allow(
@@ -419,6 +560,26 @@ pub trait ProcessType {
         min_region_size: usize,
     ) -> Option<mpu::Region>;
 
+    /// Give the process direct access to a range of peripheral registers by
+    /// adding an MPU region covering exactly that range. A process can have
+    /// at most one peripheral range mapped. The mapping is removed when the
+    /// process is terminated or restarted.
+    ///
+    /// Returns `FAIL` if the process is inactive, `EBUSY` if it already has a
+    /// peripheral range mapped, and `EINVAL` if the MPU cannot add a region
+    /// covering exactly the range.
+    fn add_peripheral_mpu_region(
+        &self,
+        start: *const u8,
+        size: usize,
+        permissions: mpu::Permissions,
+    ) -> Result<(), ReturnCode>;
+
+    /// The peripheral range currently mapped into the process with
+    /// `add_peripheral_mpu_region()`, as the MPU region covering it and the
+    /// access given, or `None` if there is none.
+    fn peripheral_mpu_region(&self) -> Option<(mpu::Region, mpu::Permissions)>;
+
     // grants
 
     /// Create new memory in the grant region, and check that the MPU region
@@ -445,6 +606,27 @@ pub trait ProcessType {
     /// grant region in the process memory.
     unsafe fn set_grant_ptr(&self, grant_num: usize, grant_ptr: *mut u8);
 
+    // hibernation
+
+    /// Describe the state needed to resume this process after a reboot, see
+    /// `kernel::hibernate`. Returns `None` if the architecture cannot save the
+    /// process's stored state.
+    fn hibernation_record(&self) -> Option<hibernate::ProcessRecord>;
+
+    /// Check that `record` describes this process with the same memory layout
+    /// as it has now, so that it can be restored from it.
+    fn hibernation_check(&self, record: &hibernate::ProcessRecord) -> bool;
+
+    /// Resume the process from `record`, with `memory` holding the contents
+    /// of the record's memory segments back to back. Only valid after
+    /// `hibernation_check()` accepted the record, and before the process has
+    /// run. If restoring fails the process is left stopped and faulted.
+    unsafe fn hibernation_restore(
+        &self,
+        record: &hibernate::ProcessRecord,
+        memory: &[u8],
+    ) -> Result<(), ()>;
+
     // functions for processes that are architecture specific
 
     /// Set the return value the process should see when it begins executing
@@ -500,9 +682,38 @@ pub trait ProcessType {
     /// Increment the number of times the process has exceeded its timeslice.
     fn debug_timeslice_expired(&self);
 
+    /// Returns how many times memory for a grant could not be allocated in
+    /// this process because its grant region was full.
+    fn debug_grant_alloc_failure_count(&self) -> usize;
+
+    /// Increment the number of failed grant allocations.
+    fn debug_grant_alloc_failed(&self);
+
     /// Increment the number of times the process called a syscall and record
     /// the last syscall that was called.
     fn debug_syscall_called(&self, last_syscall: Syscall);
+
+    /// Returns the driver the process most recently subscribed a function
+    /// to, if it subscribed to any since it started.
+    fn debug_last_subscribed_driver(&self) -> Option<usize>;
+
+    /// Returns when, in microseconds of the kernel's wait time clock, the
+    /// process was first seen ready but not scheduled, or `None` if it was
+    /// not waiting at the last scheduling decision.
+    fn debug_waiting_since(&self) -> Option<u32>;
+
+    /// Record when the process started waiting to run, or that it is not
+    /// waiting.
+    fn debug_set_waiting_since(&self, since_us: Option<u32>);
+
+    /// Returns the time, in microseconds, the process has run with a
+    /// timeslice and the time it has run cooperatively since it started, as
+    /// last recorded by the kernel.
+    fn debug_cpu_time_us(&self) -> (u64, u64);
+
+    /// Record the time the process has run with a timeslice and
+    /// cooperatively since it started.
+    fn debug_set_cpu_time_us(&self, charged_us: u64, uncharged_us: u64);
 }
 
 /// Generic trait for implementing process restart policies.
@@ -574,6 +785,73 @@ impl ProcessRestartPolicy for AlwaysRestart {
     }
 }
 
+/// Number of faults in a row at the same instruction after which the restarts
+/// of a process are delayed.
+const REPEATED_FAULT_THRESHOLD: usize = 3;
+
+/// Restart delay in milliseconds at `REPEATED_FAULT_THRESHOLD` faults. The
+/// delay doubles with each further fault at the same instruction, up to
+/// `MAX_RESTART_DELAY_MS`.
+const RESTART_DELAY_MS: u32 = 100;
+
+/// Longest delay in milliseconds before restarting a faulting process.
+const MAX_RESTART_DELAY_MS: u32 = 60_000;
+
+/// Number of times a restarted process has to yield before its run counts as
+/// clean, and earlier faults no longer count as repeated.
+const CLEAN_RUN_YIELDS: usize = 8;
+
+/// Number of subscriptions per process that are tracked to detect stale
+/// callbacks.
+const MAX_TRACKED_SUBSCRIPTIONS: usize = 8;
+
+/// The function a process subscribed to a callback, 0 if it unsubscribed.
+#[derive(Clone, Copy)]
+struct Subscription {
+    callback_id: CallbackId,
+    fn_ptr: usize,
+    appdata: usize,
+}
+
+/// Number of allowed buffers per process that are tracked when allows are
+/// traced. None are tracked otherwise, so the table takes no memory.
+const MAX_TRACED_ALLOWS: usize = if config::CONFIG.trace_allows { 8 } else { 0 };
+
+/// A buffer a process allowed to a driver.
+#[derive(Clone, Copy)]
+struct TracedAllow {
+    driver_number: usize,
+    subdriver_number: usize,
+    address: usize,
+    size: usize,
+}
+
+/// Returns how long to delay a restart after `faults` faults in a row at the
+/// same instruction, if at all.
+fn restart_delay_ms(faults: usize) -> Option<u32> {
+    if faults < REPEATED_FAULT_THRESHOLD {
+        return None;
+    }
+    let doublings = cmp::min(faults - REPEATED_FAULT_THRESHOLD, 16) as u32;
+    Some(cmp::min(
+        RESTART_DELAY_MS << doublings,
+        MAX_RESTART_DELAY_MS,
+    ))
+}
+
+/// Timer used to delay the restart of a process that keeps faulting at the
+/// same instruction, which is most likely a deterministic bug. Rather than
+/// restarting such a process in a tight loop, the kernel leaves it
+/// `StoppedFaulted` and asks this timer to restart it later.
+///
+/// Boards enable this with `Kernel::set_restart_throttle_timer()`. Without a
+/// timer, repeated faults are only logged.
+pub trait RestartThrottleTimer {
+    /// Call `Kernel::finish_throttled_restart()` for `appid` in `delay_ms`
+    /// milliseconds.
+    fn delay_restart(&self, appid: AppId, delay_ms: u32);
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Error {
     NoSuchApp,
@@ -667,8 +945,10 @@ impl<'a> ProcessStateCell<'a> {
 
         if old_state == State::Running && new_state != State::Running {
             self.kernel.decrement_work();
+            self.kernel.process_stopped_running();
         } else if new_state == State::Running && old_state != State::Running {
-            self.kernel.increment_work()
+            self.kernel.increment_work();
+            self.kernel.process_started_running();
         }
         self.state.set(new_state);
     }
@@ -775,6 +1055,9 @@ struct ProcessDebug {
     /// What was the most recent syscall.
     last_syscall: Option<Syscall>,
 
+    /// The driver the most recent subscribe with a function was to.
+    last_subscribed_driver: Option<usize>,
+
     /// How many callbacks were dropped because the queue was insufficiently
     /// long.
     dropped_callback_count: usize,
@@ -782,6 +1065,18 @@ struct ProcessDebug {
     /// How many times this process has been paused because it exceeded its
     /// timeslice.
     timeslice_expiration_count: usize,
+
+    /// When the process became ready without being scheduled.
+    waiting_since_us: Option<u32>,
+
+    /// Time the process has run with a timeslice, and cooperatively, since
+    /// it started.
+    charged_us: u64,
+    uncharged_us: u64,
+
+    /// How many grant allocations failed because the process was out of
+    /// memory.
+    grant_alloc_failure_count: usize,
 }
 
 /// A type for userspace processes in Tock.
@@ -872,15 +1167,44 @@ pub struct Process<'a, C: 'static + Chip> {
     /// MPU regions are saved as a pointer-size pair.
     mpu_regions: [Cell<Option<mpu::Region>>; 6],
 
+    /// MPU region giving the process access to peripheral registers, if any,
+    /// and the access it gives.
+    peripheral_region: Cell<Option<(mpu::Region, mpu::Permissions)>>,
+
     /// Essentially a list of callbacks that want to call functions in the
     /// process.
     tasks: MapCell<RingBuffer<'a, Task>>,
 
+    /// The current subscriptions of the process, to drop callbacks for
+    /// functions it no longer subscribes.
+    subscriptions: [Cell<Option<Subscription>>; MAX_TRACKED_SUBSCRIPTIONS],
+
+    /// The buffers the process currently allows, if allows are traced.
+    traced_allows: [Cell<Option<TracedAllow>>; MAX_TRACED_ALLOWS],
+
     /// Count of how many times this process has entered the fault condition and
     /// been restarted. This is used by some `ProcessRestartPolicy`s to
     /// determine if the process should be restarted or not.
     restart_count: Cell<usize>,
 
+    /// Address of the instruction the process last faulted at, if known.
+    last_fault_pc: Cell<Option<usize>>,
+
+    /// Number of faults in a row at `last_fault_pc`.
+    repeated_faults: Cell<usize>,
+
+    /// Number of times the process yielded since it was last restarted, up to
+    /// `CLEAN_RUN_YIELDS`.
+    yields_since_restart: Cell<usize>,
+
+    /// Whether the process is waiting for a `RestartThrottleTimer` to restart
+    /// it.
+    restart_throttled: Cell<bool>,
+
+    /// When the process was held after yielding and for how long, see
+    /// `ProcessType::hold()`.
+    held: Cell<Option<(u32, u32)>>,
+
     /// Name of the app.
     process_name: &'static str,
 
@@ -908,7 +1232,7 @@ impl<C: Chip> ProcessType for Process<'_, C> {
             self.debug.map(|debug| {
                 debug.dropped_callback_count += 1;
             });
-        } else {
+        } else if self.held.get().is_none() {
             self.kernel.increment_work();
         }
 
@@ -916,10 +1240,86 @@ impl<C: Chip> ProcessType for Process<'_, C> {
     }
 
     fn ready(&self) -> bool {
+        if self.held.get().is_some() {
+            return false;
+        }
         self.tasks.map_or(false, |ring_buf| ring_buf.has_elements())
             || self.state.get() == State::Running
     }
 
+    fn set_subscription(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize) {
+        let slot = self
+            .subscriptions
+            .iter()
+            .find(|slot| {
+                slot.get()
+                    .map_or(false, |sub| sub.callback_id == callback_id)
+            })
+            .or_else(|| self.subscriptions.iter().find(|slot| slot.get().is_none()));
+        // Once all slots are in use, further subscriptions are not tracked.
+        slot.map(|slot| {
+            slot.set(Some(Subscription {
+                callback_id,
+                fn_ptr,
+                appdata,
+            }))
+        });
+    }
+
+    fn is_subscribed(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize) -> bool {
+        self.subscriptions
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|sub| sub.callback_id == callback_id)
+            .map_or(true, |sub| sub.fn_ptr == fn_ptr && sub.appdata == appdata)
+    }
+
+    fn has_subscriptions(&self) -> bool {
+        self.subscriptions
+            .iter()
+            .filter_map(|slot| slot.get())
+            .any(|sub| sub.fn_ptr != 0)
+    }
+
+    fn trace_allow(
+        &self,
+        driver_number: usize,
+        subdriver_number: usize,
+        address: usize,
+        size: usize,
+    ) {
+        let slot = self.traced_allows.iter().find(|slot| {
+            slot.get().map_or(false, |allow| {
+                allow.driver_number == driver_number && allow.subdriver_number == subdriver_number
+            })
+        });
+        if address == 0 || size == 0 {
+            slot.map(|slot| slot.set(None));
+            return;
+        }
+        // Once all slots are in use, further buffers are not tracked.
+        slot.or_else(|| self.traced_allows.iter().find(|slot| slot.get().is_none()))
+            .map(|slot| {
+                slot.set(Some(TracedAllow {
+                    driver_number,
+                    subdriver_number,
+                    address,
+                    size,
+                }))
+            });
+    }
+
+    fn traced_allows_each(&self, closure: &mut dyn FnMut(usize, usize, usize, usize)) {
+        for allow in self.traced_allows.iter().filter_map(|slot| slot.get()) {
+            closure(
+                allow.driver_number,
+                allow.subdriver_number,
+                allow.address,
+                allow.size,
+            );
+        }
+    }
+
     fn remove_pending_callbacks(&self, callback_id: CallbackId) {
         self.tasks.map(|tasks| {
             let count_before = tasks.len();
@@ -932,7 +1332,9 @@ impl<C: Chip> ProcessType for Process<'_, C> {
                         if id != callback_id {
                             true
                         } else {
-                            self.kernel.decrement_work();
+                            if self.held.get().is_none() {
+                                self.kernel.decrement_work();
+                            }
                             false
                         }
                     }
@@ -952,6 +1354,24 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         });
     }
 
+    fn has_pending_callback(&self, callback_id: CallbackId) -> bool {
+        self.tasks.map_or(false, |tasks| {
+            let mut pending = false;
+            // Keeps every task, only looking at them.
+            tasks.retain(|task| {
+                if let Task::FunctionCall(FunctionCall {
+                    source: FunctionCallSource::Driver(id),
+                    ..
+                }) = task
+                {
+                    pending |= *id == callback_id;
+                }
+                true
+            });
+            pending
+        })
+    }
+
     fn get_state(&self) -> State {
         self.state.get()
     }
@@ -959,6 +1379,17 @@ impl<C: Chip> ProcessType for Process<'_, C> {
     fn set_yielded_state(&self) {
         if self.state.get() == State::Running {
             self.state.update(State::Yielded);
+
+            // A process that keeps yielding after a restart is working, so
+            // earlier faults are not part of a crash loop.
+            if self.yields_since_restart.get() < CLEAN_RUN_YIELDS {
+                self.yields_since_restart
+                    .set(self.yields_since_restart.get() + 1);
+                if self.yields_since_restart.get() == CLEAN_RUN_YIELDS {
+                    self.last_fault_pc.set(None);
+                    self.repeated_faults.set(0);
+                }
+            }
         }
     }
 
@@ -987,7 +1418,20 @@ impl<C: Chip> ProcessType for Process<'_, C> {
                 panic!("Process {} had a fault", self.process_name);
             }
             FaultResponse::Restart(_) => {
-                self.restart(State::StoppedFaulted);
+                let throttle = self.record_fault().and_then(|delay_ms| {
+                    self.kernel
+                        .restart_throttle_timer()
+                        .map(|timer| (timer, delay_ms))
+                });
+                match throttle {
+                    Some((timer, delay_ms)) => {
+                        self.terminate();
+                        self.state.update(State::StoppedFaulted);
+                        self.restart_throttled.set(true);
+                        timer.delay_restart(self.appid(), delay_ms);
+                    }
+                    None => self.restart(State::StoppedFaulted),
+                }
             }
             FaultResponse::Stop => {
                 // This looks a lot like restart, except we just leave the app
@@ -1000,10 +1444,61 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         }
     }
 
+    fn hold(&self, since_us: u32, for_us: u32) -> bool {
+        if self.state.get() != State::Yielded {
+            return false;
+        }
+        if self.held.replace(Some((since_us, for_us))).is_none() {
+            for _ in 0..self.pending_tasks() {
+                self.kernel.decrement_work();
+            }
+        }
+        true
+    }
+
+    fn hold_time(&self) -> Option<(u32, u32)> {
+        self.held.get()
+    }
+
+    fn release_hold(&self) {
+        if self.held.take().is_some() {
+            for _ in 0..self.pending_tasks() {
+                self.kernel.increment_work();
+            }
+        }
+    }
+
     fn get_restart_count(&self) -> usize {
         self.restart_count.get()
     }
 
+    fn finish_throttled_restart(&self) {
+        if self.restart_throttled.get() && self.state.get() == State::StoppedFaulted {
+            self.restart_throttled.set(false);
+            self.restart(State::StoppedFaulted);
+        }
+    }
+
+    fn reload(&self) -> bool {
+        self.terminate();
+        self.restart_throttled.set(false);
+
+        // The top of the process's memory holds the grant pointers, the
+        // callback queue and this struct itself, none of which belong to
+        // the process. Everything below them is zeroed.
+        let clear_len = self.memory.len() - self.initial_kernel_memory_size();
+        unsafe {
+            ptr::write_bytes(self.memory.as_ptr() as *mut u8, 0, clear_len);
+        }
+
+        self.start_fresh()
+    }
+
+    fn stop_and_free(&self) {
+        self.terminate();
+        self.restart_throttled.set(false);
+    }
+
     fn dequeue_task(&self) -> Option<Task> {
         self.tasks.map_or(None, |tasks| {
             tasks.dequeue().map(|cb| {
@@ -1013,6 +1508,10 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         })
     }
 
+    fn pending_tasks(&self) -> usize {
+        self.tasks.map_or(0, |tasks| tasks.len())
+    }
+
     fn mem_start(&self) -> *const u8 {
         self.memory.as_ptr()
     }
@@ -1037,6 +1536,22 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         self.kernel_memory_break.get()
     }
 
+    fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        let buf_end_addr = buf_start_addr.wrapping_add(size);
+
+        buf_end_addr >= buf_start_addr
+            && buf_start_addr >= self.mem_start()
+            && buf_end_addr <= self.app_break.get()
+    }
+
+    fn in_app_flash_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        let buf_end_addr = buf_start_addr.wrapping_add(size);
+
+        buf_end_addr >= buf_start_addr
+            && buf_start_addr >= self.flash_start()
+            && buf_end_addr <= self.flash_end()
+    }
+
     fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -1102,6 +1617,37 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         })
     }
 
+    fn add_peripheral_mpu_region(
+        &self,
+        start: *const u8,
+        size: usize,
+        permissions: mpu::Permissions,
+    ) -> Result<(), ReturnCode> {
+        if !self.is_active() {
+            // Do not modify an inactive process.
+            return Err(ReturnCode::FAIL);
+        }
+        if self.peripheral_region.get().is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+
+        self.mpu_config.map_or(Err(ReturnCode::FAIL), |config| {
+            // Offering the MPU only the requested range ensures the region
+            // does not expose any neighbouring registers.
+            let region = self
+                .chip
+                .mpu()
+                .allocate_region(start, size, size, permissions, config)
+                .ok_or(ReturnCode::EINVAL)?;
+            self.peripheral_region.set(Some((region, permissions)));
+            Ok(())
+        })
+    }
+
+    fn peripheral_mpu_region(&self) -> Option<(mpu::Region, mpu::Permissions)> {
+        self.peripheral_region.get()
+    }
+
     fn sbrk(&self, increment: isize) -> Result<*const u8, Error> {
         // Do not modify an inactive process.
         if !self.is_active() {
@@ -1150,6 +1696,12 @@ impl<C: Chip> ProcessType for Process<'_, C> {
             return Err(ReturnCode::FAIL);
         }
 
+        if size == 0 {
+            // An empty buffer revokes the previous allow, just like a null
+            // buffer does.
+            return Ok(None);
+        }
+
         match NonNull::new(buf_start_addr as *mut u8) {
             None => {
                 // A null buffer means pass in `None` to the capsule
@@ -1261,6 +1813,128 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         *grant_pointer_pointer = grant_ptr;
     }
 
+    fn hibernation_record(&self) -> Option<hibernate::ProcessRecord> {
+        let mem_start = self.mem_start() as usize;
+        let state = self.state.get();
+        let mut record = hibernate::ProcessRecord {
+            flash_start: self.flash_start() as usize,
+            mem_start: mem_start,
+            mem_len: self.memory.len(),
+            identifier: self.app_id.get().id(),
+            state: state,
+            restart_count: self.restart_count.get(),
+            allow_high_water_mark: 0,
+            app_break: 0,
+            kernel_memory_break: 0,
+            grants_len: 0,
+            grant_ptrs: 0,
+            grant_ptrs_len: 0,
+            stored_state_len: 0,
+            stored_state: [0; hibernate::STORED_STATE_MAX],
+            pending_tasks: self.tasks.map_or(0, |tasks| tasks.len()),
+        };
+
+        // Processes that have not started or have faulted are started from
+        // scratch, so nothing but their identity needs to be saved.
+        match state {
+            State::Unstarted | State::StoppedFaulted | State::Fault => return Some(record),
+            _ => {}
+        }
+
+        record.stored_state_len = self
+            .stored_state
+            .map_or(Err(()), |stored_state| {
+                self.chip
+                    .userspace_kernel_boundary()
+                    .store_context(stored_state, &mut record.stored_state)
+            })
+            .ok()?;
+        let (grant_ptrs, grant_ptrs_len) = self.grant_ptrs_range();
+        record.allow_high_water_mark = self.allow_high_water_mark.get() as usize - mem_start;
+        record.app_break = self.app_break.get() as usize - mem_start;
+        record.kernel_memory_break = self.kernel_memory_break.get() as usize - mem_start;
+        record.grants_len = self.struct_offset() - record.kernel_memory_break;
+        record.grant_ptrs = grant_ptrs;
+        record.grant_ptrs_len = grant_ptrs_len;
+        Some(record)
+    }
+
+    fn hibernation_check(&self, record: &hibernate::ProcessRecord) -> bool {
+        if record.flash_start != self.flash_start() as usize
+            || record.mem_start != self.mem_start() as usize
+            || record.mem_len != self.memory.len()
+        {
+            return false;
+        }
+        match record.state {
+            State::StoppedRunning | State::StoppedYielded => {}
+            _ => return record.memory_len() == 0,
+        }
+        let (grant_ptrs, grant_ptrs_len) = self.grant_ptrs_range();
+        record.allow_high_water_mark <= record.app_break
+            && record.app_break <= record.kernel_memory_break
+            && record.kernel_memory_break + record.grants_len == self.struct_offset()
+            && record.grant_ptrs == grant_ptrs
+            && record.grant_ptrs_len == grant_ptrs_len
+    }
+
+    unsafe fn hibernation_restore(
+        &self,
+        record: &hibernate::ProcessRecord,
+        memory: &[u8],
+    ) -> Result<(), ()> {
+        // Callbacks saved in grants refer to the process by its identifier,
+        // so it must get the same one back.
+        let index = self.app_id.get().index;
+        self.app_id
+            .set(AppId::new(self.kernel, record.identifier, index));
+        self.restart_count.set(record.restart_count);
+
+        match record.state {
+            State::StoppedRunning | State::StoppedYielded => {}
+            State::StoppedFaulted => {
+                self.terminate();
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+
+        // The process continues where it was frozen instead of running its
+        // init function.
+        self.clear_tasks();
+
+        let mem_start = self.memory.as_ptr() as *mut u8;
+        let mut copied = 0;
+        for &(offset, len) in record.segments().iter() {
+            ptr::copy_nonoverlapping(memory[copied..].as_ptr(), mem_start.add(offset), len);
+            copied += len;
+        }
+
+        self.kernel_memory_break
+            .set(mem_start.add(record.kernel_memory_break));
+        self.allow_high_water_mark.set(mem_start);
+        let restored = self.brk(mem_start.add(record.app_break)).is_ok()
+            && self.stored_state.map_or(false, |stored_state| {
+                self.chip
+                    .userspace_kernel_boundary()
+                    .restore_context(
+                        stored_state,
+                        &record.stored_state[..record.stored_state_len],
+                    )
+                    .is_ok()
+            });
+        if !restored {
+            self.terminate();
+            return Err(());
+        }
+        self.allow_high_water_mark
+            .set(mem_start.add(record.allow_high_water_mark));
+
+        self.state.update(record.state);
+        self.resume();
+        Ok(())
+    }
+
     fn get_process_name(&self) -> &'static str {
         self.process_name
     }
@@ -1388,10 +2062,54 @@ impl<C: Chip> ProcessType for Process<'_, C> {
             .map(|debug| debug.timeslice_expiration_count += 1);
     }
 
+    fn debug_grant_alloc_failure_count(&self) -> usize {
+        self.debug
+            .map_or(0, |debug| debug.grant_alloc_failure_count)
+    }
+
+    fn debug_grant_alloc_failed(&self) {
+        self.debug.map(|debug| debug.grant_alloc_failure_count += 1);
+    }
+
     fn debug_syscall_called(&self, last_syscall: Syscall) {
         self.debug.map(|debug| {
             debug.syscall_count += 1;
             debug.last_syscall = Some(last_syscall);
+            if let Syscall::SUBSCRIBE {
+                driver_number,
+                callback_ptr,
+                ..
+            } = last_syscall
+            {
+                if !callback_ptr.is_null() {
+                    debug.last_subscribed_driver = Some(driver_number);
+                }
+            }
+        });
+    }
+
+    fn debug_last_subscribed_driver(&self) -> Option<usize> {
+        self.debug
+            .map_or(None, |debug| debug.last_subscribed_driver)
+    }
+
+    fn debug_waiting_since(&self) -> Option<u32> {
+        self.debug.map_or(None, |debug| debug.waiting_since_us)
+    }
+
+    fn debug_set_waiting_since(&self, since_us: Option<u32>) {
+        self.debug.map(|debug| debug.waiting_since_us = since_us);
+    }
+
+    fn debug_cpu_time_us(&self) -> (u64, u64) {
+        self.debug
+            .map_or((0, 0), |debug| (debug.charged_us, debug.uncharged_us))
+    }
+
+    fn debug_set_cpu_time_us(&self, charged_us: u64, uncharged_us: u64) {
+        self.debug.map(|debug| {
+            debug.charged_us = charged_us;
+            debug.uncharged_us = uncharged_us;
         });
     }
 
@@ -1629,6 +2347,7 @@ impl<C: 'static + Chip> Process<'_, C> {
         remaining_memory: &'static mut [u8],
         fault_response: FaultResponse,
         index: usize,
+        has_driver: &dyn Fn(usize) -> bool,
     ) -> Result<(Option<&'static dyn ProcessType>, &'static mut [u8]), ProcessLoadError> {
         // Get a slice for just the app header.
         let header_flash = app_flash
@@ -1682,6 +2401,20 @@ impl<C: 'static + Chip> Process<'_, C> {
             return Ok((None, remaining_memory));
         }
 
+        // Do not start a process that would fail at runtime because a driver
+        // it needs is missing from the board.
+        let missing_driver = (0..tbf_header.number_required_drivers())
+            .filter_map(|i| tbf_header.get_required_driver(i))
+            .find(|&driver_num| !has_driver(driver_num as usize));
+        if let Some(driver_num) = missing_driver {
+            debug!(
+                "Not starting process {:?}: it requires driver {:#x}, which this board does not provide",
+                process_name.unwrap_or(""),
+                driver_num
+            );
+            return Ok((None, remaining_memory));
+        }
+
         // Otherwise, actually load the app.
         let process_ram_requested_size = tbf_header.get_minimum_app_ram_size() as usize;
         let init_fn = app_flash
@@ -1943,6 +2676,11 @@ impl<C: 'static + Chip> Process<'_, C> {
         process.state = ProcessStateCell::new(process.kernel);
         process.fault_response = fault_response;
         process.restart_count = Cell::new(0);
+        process.last_fault_pc = Cell::new(None);
+        process.repeated_faults = Cell::new(0);
+        process.yields_since_restart = Cell::new(0);
+        process.restart_throttled = Cell::new(false);
+        process.held = Cell::new(None);
 
         process.mpu_config = MapCell::new(mpu_config);
         process.mpu_regions = [
@@ -1953,7 +2691,10 @@ impl<C: 'static + Chip> Process<'_, C> {
             Cell::new(None),
             Cell::new(None),
         ];
+        process.peripheral_region = Cell::new(None);
         process.tasks = MapCell::new(tasks);
+        process.subscriptions = Default::default();
+        process.traced_allows = Default::default();
         process.process_name = process_name.unwrap_or("");
 
         process.debug = MapCell::new(ProcessDebug {
@@ -1964,8 +2705,13 @@ impl<C: 'static + Chip> Process<'_, C> {
             app_stack_min_pointer: None,
             syscall_count: 0,
             last_syscall: None,
+            last_subscribed_driver: None,
             dropped_callback_count: 0,
             timeslice_expiration_count: 0,
+            waiting_since_us: None,
+            charged_us: 0,
+            uncharged_us: 0,
+            grant_alloc_failure_count: 0,
         });
 
         let flash_protected_size = process.header.get_protected_size() as usize;
@@ -2013,10 +2759,55 @@ impl<C: 'static + Chip> Process<'_, C> {
 
         kernel.increment_work();
 
+        // A process whose eager grants do not fit in its memory is loaded
+        // but never started, like one that faulted, so that the other
+        // processes still load.
+        if !kernel.allocate_eager_grants(process) {
+            debug!(
+                "Not starting process {:?}: the grants the kernel requires do not fit in its memory",
+                process_name.unwrap_or("")
+            );
+            process.clear_tasks();
+            process.state.update(State::StoppedFaulted);
+        }
+
         // Return the process object and a remaining memory for processes slice.
         Ok((Some(process), unused_memory))
     }
 
+    /// Note that the process faulted, and check whether it keeps faulting at
+    /// the same instruction. Returns how long to delay restarting it, if it
+    /// should be throttled.
+    fn record_fault(&self) -> Option<u32> {
+        let pc = self.stored_state.map_or(None, |stored_state| unsafe {
+            self.chip.userspace_kernel_boundary().get_fault_pc(
+                self.memory.as_ptr(),
+                self.app_break.get(),
+                stored_state,
+            )
+        });
+        match pc {
+            Some(pc) if self.last_fault_pc.get() == Some(pc) => {
+                self.repeated_faults
+                    .set(self.repeated_faults.get().saturating_add(1));
+            }
+            _ => self.repeated_faults.set(1),
+        }
+        self.last_fault_pc.set(pc);
+
+        let delay_ms = pc.and_then(|_| restart_delay_ms(self.repeated_faults.get()));
+        if let (Some(pc), Some(_)) = (pc, delay_ms) {
+            debug!(
+                "[{:?}] {} faulted {} times in a row at {:#010x}",
+                self.appid(),
+                self.process_name,
+                self.repeated_faults.get(),
+                pc
+            );
+        }
+        delay_ms
+    }
+
     /// Attempt to restart the process.
     ///
     /// This function can be called when the process is in any state and
@@ -2068,6 +2859,23 @@ impl<C: 'static + Chip> Process<'_, C> {
             }
         }
 
+        self.start_fresh();
+    }
+
+    /// Size of the memory the kernel reserves at the top of a process's RAM
+    /// before the process allocates any grants.
+    fn initial_kernel_memory_size(&self) -> usize {
+        let grant_ptr_size = mem::size_of::<*const usize>();
+        let grant_ptrs_num = self.kernel.get_grant_count_and_finalize();
+        let grant_ptrs_offset = grant_ptrs_num * grant_ptr_size;
+
+        grant_ptrs_offset + Self::CALLBACKS_OFFSET + Self::PROCESS_STRUCT_OFFSET
+    }
+
+    /// Reset a terminated process to its initial state and queue its
+    /// `_start` function. Returns `false`, leaving the process in its current
+    /// state, if the process could not be set up to run again.
+    fn start_fresh(&self) -> bool {
         // We need a new process identifier for this process since the restarted
         // version is in effect a new process. This is also necessary to
         // invalidate any stored `AppId`s that point to the old version of the
@@ -2082,10 +2890,24 @@ impl<C: 'static + Chip> Process<'_, C> {
         self.debug.map(|debug| {
             debug.syscall_count = 0;
             debug.last_syscall = None;
+            debug.last_subscribed_driver = None;
             debug.dropped_callback_count = 0;
             debug.timeslice_expiration_count = 0;
+            debug.waiting_since_us = None;
+            debug.charged_us = 0;
+            debug.uncharged_us = 0;
+            debug.grant_alloc_failure_count = 0;
         });
 
+        // The restarted process has not subscribed to anything yet.
+        for slot in self.subscriptions.iter() {
+            slot.set(None);
+        }
+        // Nor allowed any buffers.
+        for slot in self.traced_allows.iter() {
+            slot.set(None);
+        }
+
         // FLASH
 
         // We are going to start this process over again, so need the init_fn
@@ -2113,7 +2935,7 @@ impl<C: 'static + Chip> Process<'_, C> {
             // unexpected since we previously ran this process. However, we
             // return now and leave the process faulted and it will not be
             // scheduled.
-            return;
+            return false;
         }
 
         // RAM
@@ -2126,12 +2948,7 @@ impl<C: 'static + Chip> Process<'_, C> {
             .initial_process_app_brk_size();
 
         // Recalculate initial_kernel_memory_size as was done in create()
-        let grant_ptr_size = mem::size_of::<*const usize>();
-        let grant_ptrs_num = self.kernel.get_grant_count_and_finalize();
-        let grant_ptrs_offset = grant_ptrs_num * grant_ptr_size;
-
-        let initial_kernel_memory_size =
-            grant_ptrs_offset + Self::CALLBACKS_OFFSET + Self::PROCESS_STRUCT_OFFSET;
+        let initial_kernel_memory_size = self.initial_kernel_memory_size();
 
         let app_mpu_mem = self.chip.mpu().allocate_app_memory_region(
             self.memory.as_ptr() as *const u8,
@@ -2149,7 +2966,7 @@ impl<C: 'static + Chip> Process<'_, C> {
                 // happen since we were able to start the process before, but at
                 // this point it is better to leave the app faulted and not
                 // schedule it.
-                return;
+                return false;
             }
         };
 
@@ -2191,7 +3008,7 @@ impl<C: 'static + Chip> Process<'_, C> {
                 // point the app is no longer valid. The best thing we
                 // can do now is leave the app as still faulted and not
                 // schedule it.
-                return;
+                return false;
             }
         };
 
@@ -2202,8 +3019,20 @@ impl<C: 'static + Chip> Process<'_, C> {
         // Mark the state as `Unstarted` for the scheduler.
         self.state.update(State::Unstarted);
 
+        // The grants were freed when the process was terminated, so the eager
+        // ones must be allocated again.
+        if !self.kernel.allocate_eager_grants(self) {
+            self.state.update(State::StoppedFaulted);
+            return false;
+        }
+
+        // The new MPU configuration has no peripheral mapping, so restore any
+        // the board designated for this process.
+        self.kernel.map_peripheral_regions(self);
+
         // Mark that we restarted this process.
         self.restart_count.increment();
+        self.yields_since_restart.set(0);
 
         // Enqueue the initial function.
         self.tasks.map(|tasks| {
@@ -2219,6 +3048,7 @@ impl<C: 'static + Chip> Process<'_, C> {
 
         // Mark that the process is ready to run.
         self.kernel.increment_work();
+        true
     }
 
     /// Stop and clear a process's state.
@@ -2228,38 +3058,58 @@ impl<C: 'static + Chip> Process<'_, C> {
     /// queued tasks for this process, but leaves the debug information about
     /// the process and other state intact.
     fn terminate(&self) {
-        // Remove the tasks that were scheduled for the app from the
-        // amount of work queue.
-        let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
-        for _ in 0..tasks_len {
-            self.kernel.decrement_work();
-        }
-
-        // And remove those tasks
-        self.tasks.map(|tasks| {
-            tasks.empty();
-        });
+        self.clear_tasks();
+        self.held.set(None);
 
         // Clear any grant regions this app has setup with any capsules.
         unsafe {
             self.grant_ptrs_reset();
         }
 
+        // Free memory the app was given outside of its grant regions.
+        self.kernel.release_app_pool(self.appid());
+
+        // Remove direct access to peripheral registers. If the MPU cannot
+        // remove the region the process still cannot use it, as it will not
+        // run again until it is restarted with a fresh MPU configuration.
+        self.peripheral_region.take().map(|(region, _)| {
+            self.mpu_config.map(|config| {
+                let _ = self.chip.mpu().remove_region(region, config);
+            });
+        });
+
         // Mark the app as stopped so the scheduler won't try to run it.
         self.state.update(State::StoppedFaulted);
     }
 
-    /// Checks if the buffer represented by the passed in base pointer and size
-    /// are within the memory bounds currently exposed to the processes (i.e.
-    /// ending at `app_break`. If this method returns true, the buffer
-    /// is guaranteed to be accessible to the process and to not overlap with
-    /// the grant region.
-    fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
-        let buf_end_addr = buf_start_addr.wrapping_add(size);
+    /// Remove all queued tasks.
+    fn clear_tasks(&self) {
+        // Remove the tasks that were scheduled for the app from the
+        // amount of work queue. Those of a held process are not counted.
+        let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
+        if self.held.get().is_none() {
+            for _ in 0..tasks_len {
+                self.kernel.decrement_work();
+            }
+        }
 
-        buf_end_addr >= buf_start_addr
-            && buf_start_addr >= self.mem_start()
-            && buf_end_addr <= self.app_break.get()
+        // And remove those tasks
+        self.tasks.map(|tasks| {
+            tasks.empty();
+        });
+    }
+
+    /// Offset of this `Process` struct from the start of process memory. The
+    /// struct lies directly above the grant allocations.
+    fn struct_offset(&self) -> usize {
+        self as *const Self as usize - self.mem_start() as usize
+    }
+
+    /// The grant pointers at the top of process memory, as an offset from
+    /// the start of process memory and a length.
+    fn grant_ptrs_range(&self) -> (usize, usize) {
+        let len = self.kernel.get_grant_count_and_finalize() * mem::size_of::<*const usize>();
+        (self.memory.len() - len, len)
     }
 
     /// Reset all `grant_ptr`s to NULL.
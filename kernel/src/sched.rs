@@ -4,26 +4,31 @@
 //! different scheduler implementations.
 
 pub(crate) mod cooperative;
+pub(crate) mod edf;
 pub(crate) mod mlfq;
 pub(crate) mod priority;
+pub(crate) mod proportional;
 pub(crate) mod round_robin;
+pub(crate) mod two_tier;
 
 use core::cell::Cell;
+use core::cmp;
 use core::ptr::NonNull;
 
+use crate::app_pool::AppPool;
 use crate::callback::{AppId, Callback, CallbackId};
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
-use crate::grant::Grant;
+use crate::grant::{self, Grant};
 use crate::ipc;
 use crate::memop;
-use crate::platform::mpu::MPU;
+use crate::platform::mpu::{self, MPU};
 use crate::platform::scheduler_timer::SchedulerTimer;
 use crate::platform::watchdog::WatchDog;
-use crate::platform::{Chip, Platform};
+use crate::platform::{Chip, Platform, SleepNotifier, UptimeClock, WaitTimeClock, WakeupTimer};
 use crate::process::{self, Task};
 use crate::returncode::ReturnCode;
 use crate::syscall::{ContextSwitchReason, Syscall};
@@ -33,6 +38,11 @@ use crate::syscall::{ContextSwitchReason, Syscall};
 /// is less than this threshold.
 pub(crate) const MIN_QUANTA_THRESHOLD_US: u32 = 500;
 
+/// Timeslice in microseconds given to a process that is being single-stepped.
+/// This leaves the process only a short window of execution past
+/// `MIN_QUANTA_THRESHOLD_US` before it is preempted.
+const SINGLE_STEP_TIMESLICE_US: u32 = MIN_QUANTA_THRESHOLD_US + 100;
+
 /// Trait which any scheduler must implement.
 pub trait Scheduler<C: Chip> {
     /// Decide which process to run next.
@@ -101,6 +111,88 @@ pub trait Scheduler<C: Chip> {
         !(chip.has_pending_interrupts()
             || DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false))
     }
+
+    /// Microseconds from now until the scheduler next needs to make a
+    /// decision even if no interrupt occurs, for example because a process
+    /// that is waiting for its next period becomes ready then. Returns `None`
+    /// if only interrupts can change what the scheduler decides, which this
+    /// default implementation always does.
+    ///
+    /// The kernel asks before it puts the chip to sleep, and programs the
+    /// timer set with `Kernel::set_decision_wakeup_timer()` to wake the chip
+    /// in time. A value of 0 keeps the chip from sleeping.
+    fn next_decision_time_us(&self) -> Option<u32> {
+        None
+    }
+
+    /// Longest time, in microseconds, the kernel may keep running a process
+    /// and handling its syscalls in one go before it preempts the process with
+    /// `StoppedExecutingReason::KernelPreemption`. This keeps a process that
+    /// makes syscalls continuously from starving the kernel loop, and with it
+    /// the watchdog, which is tickled between processes. Returns `None` for
+    /// no bound, which this default implementation always does.
+    ///
+    /// The bound complements the scheduler timer. The scheduler timer ends
+    /// the timeslice while the process executes, whereas the bound is only
+    /// checked when the process returns to the kernel, so it cannot stop a
+    /// process that computes without making syscalls. It also applies to
+    /// processes run cooperatively, and while preemption is disabled. Time is
+    /// counted from when the process is switched to, with the clock set with
+    /// `Kernel::set_wait_time_clock()`. Without that clock the scheduler timer
+    /// measures it, so only processes with a timeslice are bounded.
+    fn max_syscall_handling_us(&self) -> Option<u32> {
+        None
+    }
+
+    /// Change a parameter of how the scheduler treats process `id`, such as
+    /// its timeslice or weight. Schedulers return `ENOSUPPORT` for parameters
+    /// they do not use, which this default implementation does for all of
+    /// them.
+    ///
+    /// The kernel only calls this between scheduling decisions, see
+    /// `Kernel::set_scheduling_parameter()`.
+    fn set_parameter(
+        &self,
+        _id: AppId,
+        _parameter: SchedulingParameter,
+        _value: u32,
+    ) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    /// Called once each time the system becomes idle, that is when no process
+    /// is ready to run and none has a callback pending. It is not called again
+    /// until some process has had work in between. This default
+    /// implementation does nothing.
+    ///
+    /// This is independent of whether the chip then sleeps, so a scheduler
+    /// can use it to prepare for deep sleep, or a test harness to notice that
+    /// all processes have finished their work.
+    fn on_system_idle(&self) {}
+
+    /// Decide what the kernel does with `process`, which has just faulted.
+    /// The process is still in the state it faulted in, so the scheduler can
+    /// inspect it, for example to count its faults or to lower the priority
+    /// of a process that keeps faulting. This default implementation returns
+    /// `FaultAction::Default`, which applies the process's `FaultResponse`.
+    fn on_process_fault(&self, _process: &dyn process::ProcessType) -> FaultAction {
+        FaultAction::Default
+    }
+}
+
+/// Parameters of how a scheduler treats a process that can be changed while
+/// it runs. Each scheduler only supports the parameters it uses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SchedulingParameter {
+    /// Length of a fresh timeslice, in microseconds.
+    Timeslice,
+
+    /// Share of the processor relative to other processes.
+    Weight,
+
+    /// Time within which each job of the process has to finish, in
+    /// microseconds.
+    Deadline,
 }
 
 /// Enum representing the actions the scheduler can request in each call to
@@ -118,6 +210,151 @@ pub enum SchedulingDecision {
     TrySleep,
 }
 
+/// What the kernel does with a process that faulted, as decided by
+/// `Scheduler::on_process_fault()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Apply the process's `FaultResponse`, as if the scheduler had not been
+    /// asked.
+    Default,
+
+    /// Restart the process from its image in flash, regardless of its
+    /// `FaultResponse`. See `ProcessType::reload()`.
+    Restart,
+
+    /// Stop the process for good and free its resources, as
+    /// `FaultResponse::Stop` does.
+    Stop,
+
+    /// Leave the process as it is and stop running it for now. It continues
+    /// at the faulting instruction the next time it runs, and will most
+    /// likely fault again, so this is only for schedulers that deal with the
+    /// process themselves.
+    Ignore,
+}
+
+/// Handle a fault of `process` with `action`. Returns `false` if the process
+/// was left as it is.
+fn apply_fault_action(process: &dyn process::ProcessType, action: FaultAction) -> bool {
+    match action {
+        FaultAction::Default => process.set_fault_state(),
+        FaultAction::Restart => {
+            process.reload();
+        }
+        FaultAction::Stop => process.stop_and_free(),
+        FaultAction::Ignore => return false,
+    }
+    true
+}
+
+/// The state `Kernel::stop_process()` moves a process in `state` to, or why
+/// it cannot.
+fn stopped_state(state: process::State) -> Result<process::State, ReturnCode> {
+    match state {
+        process::State::Running => Ok(process::State::StoppedRunning),
+        process::State::Yielded => Ok(process::State::StoppedYielded),
+        process::State::StoppedRunning | process::State::StoppedYielded => {
+            Err(ReturnCode::EALREADY)
+        }
+        process::State::StoppedFaulted | process::State::Fault | process::State::Unstarted => {
+            Err(ReturnCode::FAIL)
+        }
+    }
+}
+
+/// The state `Kernel::resume_process()` moves a process in `state` to, or
+/// why it cannot.
+fn resumed_state(state: process::State) -> Result<process::State, ReturnCode> {
+    match state {
+        process::State::StoppedRunning => Ok(process::State::Running),
+        process::State::StoppedYielded => Ok(process::State::Yielded),
+        process::State::Running | process::State::Yielded => Err(ReturnCode::EALREADY),
+        process::State::StoppedFaulted | process::State::Fault | process::State::Unstarted => {
+            Err(ReturnCode::FAIL)
+        }
+    }
+}
+
+/// Why `do_process()` returns without running a process in a stopped
+/// `state`.
+fn stopped_reason(state: process::State) -> StoppedExecutingReason {
+    match state {
+        process::State::StoppedFaulted => StoppedExecutingReason::StoppedFaulted,
+        _ => StoppedExecutingReason::Stopped,
+    }
+}
+
+/// Whether the kernel has spent `max_us` running a process and handling its
+/// syscalls, `elapsed_us` after it was switched to. Without a bound or a
+/// measurement the process is never stopped.
+fn syscall_handling_exceeded(max_us: Option<u32>, elapsed_us: Option<u32>) -> bool {
+    match (max_us, elapsed_us) {
+        (Some(max), Some(elapsed)) => elapsed >= max,
+        _ => false,
+    }
+}
+
+/// The entry of an occupied slot of the process array.
+fn keep_some<T: Copy>(&slot: &Option<T>) -> Option<T> {
+    slot
+}
+
+/// Largest number of grants that can be created with
+/// `Kernel::create_eager_grant()`.
+const MAX_EAGER_GRANTS: usize = 4;
+
+/// Time a process has spent running since it started, in microseconds.
+#[derive(Clone, Copy, Default)]
+struct CpuTime {
+    /// Time it ran with a timeslice, as measured by the scheduler timer.
+    charged_us: u64,
+    /// Time it ran cooperatively, as far as the wait time clock measured it.
+    uncharged_us: u64,
+}
+
+impl CpuTime {
+    /// The time recorded in `process`.
+    fn of(process: &dyn process::ProcessType) -> CpuTime {
+        let (charged_us, uncharged_us) = process.debug_cpu_time_us();
+        CpuTime {
+            charged_us,
+            uncharged_us,
+        }
+    }
+
+    /// Add a run that took `time_executed_us` of a timeslice, or, for a
+    /// cooperative run, `measured_us` if it was measured. Saturates instead of
+    /// wrapping.
+    fn add(&mut self, time_executed_us: Option<u32>, measured_us: Option<u32>) {
+        match time_executed_us {
+            Some(us) => self.charged_us = self.charged_us.saturating_add(us as u64),
+            None => {
+                let us = measured_us.unwrap_or(0);
+                self.uncharged_us = self.uncharged_us.saturating_add(us as u64);
+            }
+        }
+    }
+}
+
+/// A grant allocated in every process when it starts.
+#[derive(Clone, Copy)]
+struct EagerGrant {
+    grant_num: usize,
+    /// Allocates the grant for a process, see `grant::allocate_grant()`.
+    allocate: fn(&dyn process::ProcessType, usize) -> bool,
+}
+
+/// Returns the first identifier from `next` on, wrapping around, that is not
+/// `in_use`. There are far fewer processes than identifiers, so one is always
+/// found.
+fn next_free_identifier<F: Fn(usize) -> bool>(next: usize, in_use: F) -> usize {
+    let mut identifier = next;
+    while in_use(identifier) {
+        identifier = identifier.wrapping_add(1);
+    }
+    identifier
+}
+
 /// Main object for the kernel. Each board will need to create one.
 pub struct Kernel {
     /// How many "to-do" items exist at any given time. These include
@@ -141,6 +378,88 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// Grants allocated when each process starts rather than when a capsule
+    /// first enters them.
+    eager_grants: [Cell<Option<EagerGrant>>; MAX_EAGER_GRANTS],
+
+    /// The process, if any, that is being single-stepped. Each time this
+    /// process is scheduled it only runs until it next returns to the kernel.
+    single_step: OptionalCell<AppId>,
+
+    /// Peripheral register ranges the board allows to be mapped into the
+    /// processes they designate.
+    peripheral_regions: Cell<&'static [mpu::PeripheralRegion]>,
+
+    /// Minimum loop period in microseconds, and the timer used to wake the
+    /// chip when sleeping out the rest of a period.
+    loop_throttle: OptionalCell<(u32, &'static dyn WakeupTimer)>,
+
+    /// Timer used to wake the chip in time for the scheduler's next decision,
+    /// see `Scheduler::next_decision_time_us()`.
+    decision_wakeup: OptionalCell<&'static dyn WakeupTimer>,
+
+    /// Timer used to delay restarting processes that keep faulting at the
+    /// same instruction.
+    restart_throttle: OptionalCell<&'static dyn process::RestartThrottleTimer>,
+
+    /// When set, the main loop never puts the chip to sleep.
+    sleep_inhibited: Cell<bool>,
+
+    /// Asked right before the chip sleeps, so processes can be warned.
+    sleep_notifier: OptionalCell<&'static dyn SleepNotifier>,
+
+    /// Clock that times `YIELD_FOR` holds, and timer that wakes the chip when
+    /// one ends.
+    yield_for_timer: OptionalCell<(&'static dyn WaitTimeClock, &'static dyn WakeupTimer)>,
+
+    /// Longest a process may run before the kernel loop gets to service its
+    /// own work, in microseconds.
+    kernel_service_period_us: OptionalCell<u32>,
+
+    /// Set when a process was stopped because the kernel service period
+    /// elapsed, so the kernel loop services kernel work next.
+    kernel_service_due: Cell<bool>,
+
+    /// Microseconds left during which processes are not preempted.
+    no_preemption_us: Cell<u32>,
+
+    /// Clock used to time how long ready processes wait to run.
+    wait_time_clock: OptionalCell<&'static dyn WaitTimeClock>,
+
+    /// Clock that counts the time since boot.
+    uptime_clock: OptionalCell<&'static dyn UptimeClock>,
+
+    /// Memory for large per-process allocations outside of grant regions.
+    app_pool: OptionalCell<&'static AppPool>,
+
+    /// How many callbacks were dropped because the process they were for had
+    /// since unsubscribed, restarted or ended.
+    stale_callbacks: Cell<usize>,
+
+    /// How many processes are in the `Running` state, and the most that have
+    /// been at once since boot or since the peak was last reset.
+    running_processes: Cell<usize>,
+    peak_running_processes: Cell<usize>,
+
+    /// The process whose system call is being handled, and how much of its
+    /// timeslice was left when it made the call, if it has one.
+    syscall_caller: Cell<Option<(AppId, Option<u32>)>>,
+
+    /// Set when the process whose system call is being handled gives up the
+    /// rest of its timeslice.
+    timeslice_yielded: Cell<bool>,
+
+    /// A scheduling parameter change waiting for the next scheduling
+    /// decision.
+    pending_scheduling_parameter: Cell<Option<(AppId, SchedulingParameter, u32)>>,
+
+    /// What the scheduler returned for the last scheduling parameter change.
+    scheduling_parameter_result: Cell<Option<ReturnCode>>,
+
+    /// Whether no process had work the last time the kernel loop checked, so
+    /// that `Scheduler::on_system_idle()` is only called when this changes.
+    system_idle: Cell<bool>,
 }
 
 /// Enum used to inform scheduler why a process stopped executing (aka why
@@ -166,6 +485,26 @@ pub enum StoppedExecutingReason {
     /// interrupt), or because the scheduler no longer wants to execute that
     /// process.
     KernelPreemption,
+
+    /// The process is being single-stepped and returned to the kernel after
+    /// executing one step.
+    SingleStep,
+
+    /// The process gave up the rest of its timeslice, at a point where being
+    /// preempted suits it.
+    TimesliceYielded,
+}
+
+/// What the kernel does if the init process faults before it finishes. See
+/// `Kernel::run_init_process()`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum InitProcessFaultPolicy {
+    /// Panic, aborting the boot.
+    Panic,
+
+    /// Continue booting. The process is left however its own fault response
+    /// handled the fault.
+    Continue,
 }
 
 impl Kernel {
@@ -176,6 +515,34 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            eager_grants: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            single_step: OptionalCell::empty(),
+            peripheral_regions: Cell::new(&[]),
+            loop_throttle: OptionalCell::empty(),
+            decision_wakeup: OptionalCell::empty(),
+            restart_throttle: OptionalCell::empty(),
+            sleep_inhibited: Cell::new(false),
+            sleep_notifier: OptionalCell::empty(),
+            yield_for_timer: OptionalCell::empty(),
+            kernel_service_period_us: OptionalCell::empty(),
+            kernel_service_due: Cell::new(false),
+            no_preemption_us: Cell::new(0),
+            wait_time_clock: OptionalCell::empty(),
+            uptime_clock: OptionalCell::empty(),
+            app_pool: OptionalCell::empty(),
+            stale_callbacks: Cell::new(0),
+            running_processes: Cell::new(0),
+            peak_running_processes: Cell::new(0),
+            syscall_caller: Cell::new(None),
+            timeslice_yielded: Cell::new(false),
+            pending_scheduling_parameter: Cell::new(None),
+            scheduling_parameter_result: Cell::new(None),
+            system_idle: Cell::new(false),
         }
     }
 
@@ -225,6 +592,67 @@ impl Kernel {
         self.work.get() == 0
     }
 
+    /// Panic if the scheduler's bookkeeping disagrees with the processes. See
+    /// `Config::check_scheduler_invariants`.
+    fn check_invariants(&self) {
+        let mut running = 0;
+        let mut tasks = 0;
+        for (index, process) in self.processes.iter().enumerate() {
+            let process = match process {
+                Some(process) => process,
+                None => continue,
+            };
+            let appid = process.appid();
+            if appid.index != index {
+                panic!(
+                    "Process {} at index {} has an AppId for index {}",
+                    process.get_process_name(),
+                    index,
+                    appid.index
+                );
+            }
+            let duplicate = self.processes[..index]
+                .iter()
+                .flatten()
+                .any(|other| other.appid().id() == appid.id());
+            if duplicate {
+                panic!(
+                    "Process {} shares its identifier {} with another process",
+                    process.get_process_name(),
+                    appid.id()
+                );
+            }
+            match process.get_state() {
+                process::State::Running => running += 1,
+                process::State::Fault => panic!(
+                    "Process {} was left in the Fault state",
+                    process.get_process_name()
+                ),
+                _ => {}
+            }
+            // The tasks of a held process do not count as work.
+            if process.hold_time().is_none() {
+                tasks += process.pending_tasks();
+            }
+        }
+
+        if self.running_processes.get() != running {
+            panic!(
+                "Kernel counts {} running processes, but {} are running",
+                self.running_processes.get(),
+                running
+            );
+        }
+        if self.work.get() != running + tasks {
+            panic!(
+                "Kernel counts {} units of work, but {} processes are running and {} tasks are queued",
+                self.work.get(),
+                running,
+                tasks
+            );
+        }
+    }
+
     /// Run a closure on a specific process if it exists. If the process with a
     /// matching `AppId` does not exist at the index specified within the
     /// `AppId`, then `default` will be returned.
@@ -288,14 +716,31 @@ impl Kernel {
         core::slice::Iter<Option<&dyn process::ProcessType>>,
         fn(&Option<&'static dyn process::ProcessType>) -> Option<&'static dyn process::ProcessType>,
     > {
-        fn keep_some(
-            &x: &Option<&'static dyn process::ProcessType>,
-        ) -> Option<&'static dyn process::ProcessType> {
-            x
-        }
         self.processes.iter().filter_map(keep_some)
     }
 
+    /// Number of processes loaded into the process array.
+    pub fn loaded_processes(&self) -> usize {
+        self.processes.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Number of slots in the process array, whether a process is loaded
+    /// into them or not.
+    pub fn process_slots(&self) -> usize {
+        self.processes.len()
+    }
+
+    /// Index of `entry` in the processes array, or `None` if `entry` is not
+    /// one of its entries.
+    pub(crate) fn process_entry_index(
+        &self,
+        entry: *const Option<&'static dyn process::ProcessType>,
+    ) -> Option<usize> {
+        self.processes
+            .iter()
+            .position(|process_entry| core::ptr::eq(process_entry, entry))
+    }
+
     /// Run a closure on every valid process. This will iterate the array of
     /// processes and call the closure on every process that exists.
     ///
@@ -357,6 +802,195 @@ impl Kernel {
         })
     }
 
+    /// Retrieve the `AppId` of the process with the given package name, as set
+    /// in its TBF header. This lets tools and supervisor apps that only know a
+    /// process by name act on it with the `AppId`-based APIs.
+    ///
+    /// Package names are not required to be unique. If more than one process
+    /// has the name, the first one in the processes array is returned.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function, as the returned `AppId` can be used to control the process.
+    pub fn lookup_app_by_name(
+        &self,
+        name: &str,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<AppId> {
+        self.processes
+            .iter()
+            .filter_map(|p| *p)
+            .find(|p| p.get_process_name() == name)
+            .map(|p| p.appid())
+    }
+
+    /// Find the process whose image, starting with its TBF header, is at
+    /// `flash_address`.
+    ///
+    /// Unlike the package name, which an app chooses in its own TBF header,
+    /// where an app is placed in flash is decided by whoever flashes the
+    /// board, so boards can use this to identify apps they trust, e.g. the
+    /// first app in flash.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function, as the returned `AppId` can be used to control the process.
+    pub fn lookup_app_by_flash_address(
+        &self,
+        flash_address: usize,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<AppId> {
+        self.processes
+            .iter()
+            .filter_map(|p| *p)
+            .find(|p| p.flash_start() as usize == flash_address)
+            .map(|p| p.appid())
+    }
+
+    /// Give the kernel a pool that capsules can allocate large per-process
+    /// buffers from. The kernel frees the allocations of a process when it
+    /// exits, faults or is restarted.
+    pub fn set_app_pool(
+        &self,
+        pool: &'static AppPool,
+        _capability: &dyn capabilities::MemoryAllocationCapability,
+    ) {
+        self.app_pool.set(pool);
+    }
+
+    /// Free all pool allocations owned by a process that is ending.
+    pub(crate) fn release_app_pool(&self, appid: AppId) {
+        self.app_pool.map(|pool| pool.release(appid));
+    }
+
+    /// A callback was dropped because it no longer matched a subscription of
+    /// a running process.
+    pub(crate) fn stale_callback_dropped(&self) {
+        self.stale_callbacks.increment();
+    }
+
+    /// How many callbacks were dropped because they were stale.
+    pub(crate) fn stale_callback_count(&self) -> usize {
+        self.stale_callbacks.get()
+    }
+
+    /// A process entered the `Running` state.
+    pub(crate) fn process_started_running(&self) {
+        self.running_processes.increment();
+        if self.running_processes.get() > self.peak_running_processes.get() {
+            self.peak_running_processes
+                .set(self.running_processes.get());
+        }
+    }
+
+    /// A process left the `Running` state.
+    pub(crate) fn process_stopped_running(&self) {
+        self.running_processes.decrement();
+    }
+
+    /// The most processes that have been in the `Running` state at once.
+    pub(crate) fn peak_running_processes(&self) -> usize {
+        self.peak_running_processes.get()
+    }
+
+    /// Restart tracking the peak from the processes running now.
+    pub(crate) fn reset_peak_running_processes(&self) {
+        self.peak_running_processes
+            .set(self.running_processes.get());
+    }
+
+    /// Microseconds that were left of the timeslice of `appid` when it made
+    /// the system call being handled. Returns `None` if `appid` is not making
+    /// a system call, or runs without a timeslice and is never preempted.
+    pub fn remaining_timeslice_us(&self, appid: AppId) -> Option<u32> {
+        match self.syscall_caller.get() {
+            Some((caller, remaining_us)) if caller == appid => remaining_us,
+            _ => None,
+        }
+    }
+
+    /// Let `appid` give up the rest of its timeslice, so that a compute-bound
+    /// process can let others run at a point of its choosing rather than be
+    /// preempted anywhere. The process stops running once the system call it
+    /// is making returns, and stays ready to run. Schedulers see it stop with
+    /// `StoppedExecutingReason::TimesliceYielded`.
+    ///
+    /// Returns `false` if `appid` is not making a system call.
+    pub fn end_timeslice(&self, appid: AppId) -> bool {
+        let is_caller = self.is_syscall_caller(appid);
+        if is_caller {
+            self.timeslice_yielded.set(true);
+        }
+        is_caller
+    }
+
+    /// Whether `appid` is making the system call being handled.
+    pub(crate) fn is_syscall_caller(&self, appid: AppId) -> bool {
+        self.syscall_caller
+            .get()
+            .map_or(false, |(caller, _)| caller == appid)
+    }
+
+    /// Delay the restarts of processes that keep faulting at the same
+    /// instruction, using `timer` to restart them later.
+    ///
+    /// After three faults in a row at the same program counter, a process that
+    /// would be restarted is instead left `StoppedFaulted`, and the fault
+    /// address is logged. The timer restarts it after 100 ms, a delay that
+    /// doubles with each further fault at that address up to one minute. A
+    /// fault elsewhere, or a run in which the process yields a few times,
+    /// resets this. The board's `ProcessRestartPolicy` still applies when the
+    /// process is finally restarted.
+    pub fn set_restart_throttle_timer(
+        &self,
+        timer: &'static dyn process::RestartThrottleTimer,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.restart_throttle.set(timer);
+    }
+
+    pub(crate) fn restart_throttle_timer(
+        &self,
+    ) -> Option<&'static dyn process::RestartThrottleTimer> {
+        self.restart_throttle.map(|timer| *timer)
+    }
+
+    /// Restart a process whose restart was delayed with a
+    /// `RestartThrottleTimer`. Does nothing if `appid` is no longer valid, or
+    /// the process was not waiting to be restarted.
+    pub fn finish_throttled_restart(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.process_map_or((), appid, |process| {
+            process.finish_throttled_restart();
+        });
+    }
+
+    /// Restart a process from a pristine state, for example because its RAM
+    /// may be corrupted. Unlike the restart that follows a fault, all of the
+    /// process's RAM is zeroed and its grants are freed before it runs its
+    /// image from flash again, and the process's restart policy is not
+    /// consulted.
+    ///
+    /// The reloaded process has a new identifier: `appid` and any other
+    /// `AppId` for the old process are no longer valid. On success, returns
+    /// the `AppId` of the reloaded process. Returns `EINVAL` if `appid` is not
+    /// valid, and `FAIL` if the process could not be started again, in which
+    /// case it is left stopped and faulted.
+    pub fn reload_process(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Result<AppId, ReturnCode> {
+        self.process_map_or(Err(ReturnCode::EINVAL), appid, |process| {
+            if process.reload() {
+                Ok(process.appid())
+            } else {
+                Err(ReturnCode::FAIL)
+            }
+        })
+    }
+
     /// Checks if the provided `AppId` is still valid given the processes stored
     /// in the processes array. Returns `true` if the AppId still refers to
     /// a valid process, and `false` if not.
@@ -369,87 +1003,821 @@ impl Kernel {
         })
     }
 
-    /// Create a new grant. This is used in board initialization to setup grants
-    /// that capsules use to interact with processes.
+    /// Create a new grant. This is used in board initialization to setup grants
+    /// that capsules use to interact with processes.
+    ///
+    /// Grants **must** only be created _before_ processes are initialized.
+    /// Processes use the number of grants that have been allocated to correctly
+    /// initialize the process's memory with a pointer for each grant. If a
+    /// grant is created after processes are initialized this will panic.
+    ///
+    /// Calling this function is restricted to only certain users, and to
+    /// enforce this calling this function requires the
+    /// `MemoryAllocationCapability` capability.
+    pub fn create_grant<T: Default>(
+        &'static self,
+        _capability: &dyn capabilities::MemoryAllocationCapability,
+    ) -> Grant<T> {
+        if self.grants_finalized.get() {
+            panic!("Grants finalized. Cannot create a new grant.");
+        }
+
+        // Create and return a new grant.
+        let grant_index = self.grant_counter.get();
+        self.grant_counter.increment();
+        Grant::new(self, grant_index)
+    }
+
+    /// Create a new grant that is allocated in each process as soon as the
+    /// process is loaded or restarted, instead of the first time a capsule
+    /// enters it.
+    ///
+    /// Entering a grant for the first time fails if the process's grant
+    /// region has no room left for it, which some capsules, such as a
+    /// watchdog, cannot tolerate. Eager grants are guaranteed to be
+    /// allocated: a process they do not fit in is loaded but not started,
+    /// like a faulted process, and the other processes load as usual. The
+    /// same happens when such a process is restarted. The tradeoff is that
+    /// every process pays for the memory of an eager grant, even if it never
+    /// uses the capsule.
+    ///
+    /// At most `MAX_EAGER_GRANTS` (4) grants can be eager.
+    pub fn create_eager_grant<T: Default>(
+        &'static self,
+        capability: &dyn capabilities::MemoryAllocationCapability,
+    ) -> Grant<T> {
+        let grant = self.create_grant::<T>(capability);
+        let eager_grant = EagerGrant {
+            grant_num: grant.grant_num(),
+            allocate: grant::allocate_grant::<T>,
+        };
+        match self.eager_grants.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => slot.set(Some(eager_grant)),
+            None => panic!("Too many eager grants."),
+        }
+        grant
+    }
+
+    /// Allocate the eager grants for `process`. Returns `false` if they do not
+    /// all fit in its grant region.
+    pub(crate) fn allocate_eager_grants(&self, process: &dyn process::ProcessType) -> bool {
+        self.eager_grants
+            .iter()
+            .filter_map(|slot| slot.get())
+            .all(|eager_grant| (eager_grant.allocate)(process, eager_grant.grant_num))
+    }
+
+    /// Returns the number of grants that have been setup in the system and
+    /// marks the grants as "finalized". This means that no more grants can
+    /// be created because data structures have been setup based on the number
+    /// of grants when this function is called.
+    ///
+    /// In practice, this is called when processes are created, and the process
+    /// memory is setup based on the number of current grants.
+    pub(crate) fn get_grant_count_and_finalize(&self) -> usize {
+        self.grants_finalized.set(true);
+        self.grant_counter.get()
+    }
+
+    /// Returns the number of grants that have been setup in the system and
+    /// marks the grants as "finalized". This means that no more grants can
+    /// be created because data structures have been setup based on the number
+    /// of grants when this function is called.
+    ///
+    /// In practice, this is called when processes are created, and the process
+    /// memory is setup based on the number of current grants.
+    ///
+    /// This is exposed publicly, but restricted with a capability. The intent
+    /// is that external implementations of `ProcessType` need to be able to
+    /// retrieve the final number of grants.
+    pub fn get_grant_count_and_finalize_external(
+        &self,
+        _capability: &dyn capabilities::ExternalProcessCapability,
+    ) -> usize {
+        self.get_grant_count_and_finalize()
+    }
+
+    /// Create a new unique identifier for a process and return the identifier.
+    ///
+    /// Typically we just choose a larger number than we have used for any process
+    /// before which ensures that the identifier is unique. Once the counter
+    /// wraps around, identifiers of live processes are skipped so that no two
+    /// processes share one.
+    pub(crate) fn create_process_identifier(&self) -> usize {
+        let identifier = next_free_identifier(self.process_identifier_max.get(), |identifier| {
+            self.processes
+                .iter()
+                .filter_map(|p| *p)
+                .any(|process| process.appid().id() == identifier)
+        });
+        self.process_identifier_max.set(identifier.wrapping_add(1));
+        identifier
+    }
+
+    /// Make sure identifiers created from now on are larger than
+    /// `identifier`, which a process has been given back after hibernation.
+    pub(crate) fn reserve_process_identifier(&self, identifier: usize) {
+        if self.process_identifier_max.get() <= identifier {
+            self.process_identifier_max.set(identifier.wrapping_add(1));
+        }
+    }
+
+    /// Cause all apps to fault.
+    ///
+    /// This will call `set_fault_state()` on each app, causing the app to enter
+    /// the state as if it had crashed (for example with an MPU violation). If
+    /// the process is configured to be restarted it will be.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function. This restricts general capsules from being able to call this
+    /// function, since capsules should not be able to arbitrarily restart all
+    /// apps.
+    pub fn hardfault_all_apps<C: capabilities::ProcessManagementCapability>(&self, _c: &C) {
+        for p in self.processes.iter() {
+            p.map(|process| {
+                process.set_fault_state();
+            });
+        }
+    }
+
+    /// Stop process `appid`, so that it is not run until it is resumed with
+    /// `resume_process()`. Callbacks for it are still queued while it is
+    /// stopped.
+    ///
+    /// Returns `EINVAL` if there is no such process, `EALREADY` if it is
+    /// already stopped, and `FAIL` if it has faulted or has not started yet.
+    pub fn stop_process(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid, |process| {
+            match stopped_state(process.get_state()) {
+                Ok(_) => {
+                    process.stop();
+                    ReturnCode::SUCCESS
+                }
+                Err(err) => err,
+            }
+        })
+    }
+
+    /// Let process `appid`, stopped with `stop_process()`, run again from
+    /// where it was stopped.
+    ///
+    /// Returns `EINVAL` if there is no such process, `EALREADY` if it is not
+    /// stopped, and `FAIL` if it has faulted or has not started yet.
+    pub fn resume_process(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid, |process| {
+            match resumed_state(process.get_state()) {
+                Ok(_) => {
+                    process.resume();
+                    ReturnCode::SUCCESS
+                }
+                Err(err) => err,
+            }
+        })
+    }
+
+    /// Put a process into single-step mode.
+    ///
+    /// Each time the process is subsequently scheduled, `do_process()` returns
+    /// to the scheduler with `StoppedExecutingReason::SingleStep` as soon as
+    /// the process traps back into the kernel, either with a system call or
+    /// because it was preempted. There is no generic hardware instruction-step
+    /// support, so a step is approximated by running the process with a very
+    /// short timeslice. Only one process can be single-stepped at a time;
+    /// selecting a new process replaces the previous one.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function, as it allows pausing arbitrary processes.
+    pub fn set_single_step(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.single_step.set(appid);
+    }
+
+    /// Return the process that is being single-stepped to normal execution.
+    pub fn clear_single_step(&self, _capability: &dyn capabilities::ProcessManagementCapability) {
+        self.single_step.clear();
+    }
+
+    /// Give processes direct access to peripheral registers.
+    ///
+    /// Each entry of `regions` is mapped into the MPU configuration of the
+    /// process flashed at its `app_flash_address`, letting that process
+    /// access the registers without going through a system call. As this
+    /// bypasses the isolation between processes and hardware, only the ranges
+    /// listed here can ever be mapped, and only into the process each one
+    /// names. Processes are named by where they are flashed rather than by
+    /// their package name, which any process can claim in its TBF header.
+    ///
+    /// Boards should call this after `load_processes()`. A mapping is removed
+    /// when its process faults and is restored if the process is restarted.
+    ///
+    /// Returns `FAIL` if any range could not be mapped into its process, in
+    /// which case the other ranges are still mapped.
+    pub fn set_peripheral_regions(
+        &self,
+        regions: &'static [mpu::PeripheralRegion],
+        _capability: &dyn capabilities::PeripheralMappingCapability,
+    ) -> ReturnCode {
+        self.peripheral_regions.set(regions);
+
+        let mut result = ReturnCode::SUCCESS;
+        for process in self.processes.iter().filter_map(|p| *p) {
+            if self.map_peripheral_regions(process) != ReturnCode::SUCCESS {
+                result = ReturnCode::FAIL;
+            }
+        }
+        result
+    }
+
+    /// Run `closure` on every peripheral range currently mapped into a
+    /// process, with the process, the start address and length of the MPU
+    /// region covering the range, and the access the process has.
+    ///
+    /// This is meant for auditing which processes have direct access to
+    /// hardware. Mappings are removed when a process faults, exits or is
+    /// restarted, so only live mappings are reported; the region may be larger
+    /// than the range the board designated if the MPU had to round it up.
+    pub fn peripheral_mappings_each<F>(
+        &self,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+        mut closure: F,
+    ) where
+        F: FnMut(AppId, usize, usize, mpu::Permissions),
+    {
+        for process in self.processes.iter().filter_map(|p| *p) {
+            if let Some((region, permissions)) = process.peripheral_mpu_region() {
+                closure(
+                    process.appid(),
+                    region.start_address() as usize,
+                    region.size(),
+                    permissions,
+                );
+            }
+        }
+    }
+
+    /// Change a parameter of how the scheduler treats process `appid`, without
+    /// restarting it.
+    ///
+    /// The change is handed to the scheduler right before its next scheduling
+    /// decision, so it never sees a change in the middle of one. A process
+    /// that is partway through a timeslice keeps it, the change applies from
+    /// its next one. Returns `EBUSY` if an earlier change has not been handed
+    /// over yet; what the scheduler made of a change is reported by
+    /// `scheduling_parameter_result()` afterwards.
+    pub fn set_scheduling_parameter(
+        &self,
+        appid: AppId,
+        parameter: SchedulingParameter,
+        value: u32,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> ReturnCode {
+        if self.pending_scheduling_parameter.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.scheduling_parameter_result.set(None);
+        self.pending_scheduling_parameter
+            .set(Some((appid, parameter, value)));
+        ReturnCode::SUCCESS
+    }
+
+    /// What the scheduler returned for the last change passed to
+    /// `set_scheduling_parameter()`, or `None` if it has not been handed over
+    /// yet. `ENOSUPPORT` means the scheduler does not use that parameter.
+    pub fn scheduling_parameter_result(
+        &self,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<ReturnCode> {
+        self.scheduling_parameter_result.get()
+    }
+
+    /// Print the buffers that processes currently allow to drivers to the
+    /// debug output, with the driver and subdriver numbers, address and size
+    /// of each.
+    ///
+    /// Buffers are only tracked if `trace_allows` is enabled in the kernel
+    /// configuration, otherwise nothing is printed. A driver may have let go
+    /// of a buffer that is still listed, as only allows and revokes by the
+    /// process are seen.
+    pub fn dump_allowed_buffers(
+        &self,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        for process in self.processes.iter().filter_map(|p| *p) {
+            let appid = process.appid();
+            process.traced_allows_each(&mut |driver_number, subdriver_number, address, size| {
+                debug!(
+                    "[{:?}] allowed {:#x}:{} @{:#x}, {} bytes",
+                    appid, driver_number, subdriver_number, address, size
+                );
+            });
+        }
+    }
+
+    /// Map the peripheral ranges designated for `process` into it.
+    pub(crate) fn map_peripheral_regions(&self, process: &dyn process::ProcessType) -> ReturnCode {
+        let mut result = ReturnCode::SUCCESS;
+        for region in self
+            .peripheral_regions
+            .get()
+            .iter()
+            .filter(|region| region.app_flash_address == process.flash_start() as usize)
+        {
+            if let Err(err) = process.add_peripheral_mpu_region(
+                region.start_address as *const u8,
+                region.size,
+                region.permissions,
+            ) {
+                result = err;
+            }
+        }
+        result
+    }
+
+    /// Run the init process to completion before any other process runs.
+    ///
+    /// Boards may call this after `load_processes()` and before
+    /// `kernel_loop()` to designate the process named `name` as an init
+    /// process, e.g. one that sets up hardware or persistent state that other
+    /// processes rely on. Only that process is executed, cooperatively and
+    /// without a timeslice, while kernel work (interrupts and deferred calls)
+    /// is serviced as usual. Other processes are not run until this function
+    /// returns.
+    ///
+    /// Tock has no exit system call, so the init process is considered
+    /// finished once it yields with no callbacks queued and the kernel has no
+    /// pending interrupts or deferred calls left to service. An init process
+    /// that waits on an operation that will only complete later (e.g. an
+    /// alarm) must therefore not be used with this function.
+    ///
+    /// If the process faults, `policy` decides whether to panic or to return
+    /// and let the boot continue. A process that is restarted by its fault
+    /// response counts as having faulted.
+    ///
+    /// Returns `SUCCESS` if the process finished, `EINVAL` if no process is
+    /// named `name`, and `FAIL` if it faulted and `policy` is `Continue`.
+    pub fn run_init_process<P: Platform, C: Chip, SC: Scheduler<C>, const NUM_PROCS: usize>(
+        &self,
+        name: &'static str,
+        policy: InitProcessFaultPolicy,
+        platform: &P,
+        chip: &C,
+        ipc: Option<&ipc::IPC<NUM_PROCS>>,
+        scheduler: &SC,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) -> ReturnCode {
+        let process = match self
+            .processes
+            .iter()
+            .filter_map(|p| *p)
+            .find(|p| p.get_process_name() == name)
+        {
+            Some(process) => process,
+            None => return ReturnCode::EINVAL,
+        };
+        let restart_count = process.get_restart_count();
+
+        loop {
+            chip.watchdog().tickle();
+
+            let faulted = match process.get_state() {
+                process::State::Fault | process::State::StoppedFaulted => true,
+                _ => process.get_restart_count() != restart_count,
+            };
+            if faulted {
+                match policy {
+                    InitProcessFaultPolicy::Panic => {
+                        panic!("Init process {} faulted", name);
+                    }
+                    InitProcessFaultPolicy::Continue => return ReturnCode::FAIL,
+                }
+            }
+
+            unsafe {
+                if scheduler.do_kernel_work_now(chip) {
+                    scheduler.execute_kernel_work(chip);
+                } else if process.ready() {
+                    self.do_process(platform, chip, scheduler, process, ipc, None);
+                } else {
+                    // Nothing is left for the process or the kernel to do.
+                    return ReturnCode::SUCCESS;
+                }
+            }
+        }
+    }
+
+    /// Cap how often the main loop runs processes that have little to do.
+    ///
+    /// Without a cap, processes that are ready but only do a small amount of
+    /// work each time they run (e.g. they yield and are immediately woken by
+    /// a callback) keep the kernel loop spinning without ever sleeping. With
+    /// a minimum period set, whenever a process yields after running for
+    /// less than `period_us` and no process is ready to run, the kernel
+    /// sleeps for the rest of the period before scheduling the next process,
+    /// using `timer` to wake up again.
+    ///
+    /// This never delays other work beyond the period:
+    ///
+    /// - The kernel only sleeps when no interrupts or deferred calls are
+    ///   pending, and any interrupt wakes it early, so kernel work is not
+    ///   delayed.
+    /// - A process that exhausts its timeslice, or is preempted by the
+    ///   kernel, is not throttled. Only processes that yield are.
+    /// - The kernel does not sleep while any process is ready, so the
+    ///   throttle only slows down a process that is woken up again after
+    ///   yielding, and never other processes waiting for their turn.
+    ///
+    /// The run time of a process is only known when the scheduler gives it a
+    /// timeslice, so the cap has no effect with cooperative scheduling.
+    /// Passing a `period_us` of 0 removes the cap.
+    pub fn set_min_loop_period(
+        &self,
+        period_us: u32,
+        timer: &'static dyn WakeupTimer,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        if period_us == 0 {
+            self.loop_throttle.clear();
+        } else {
+            self.loop_throttle.set((period_us, timer));
+        }
+    }
+
+    /// Use `timer` to wake the chip from sleep in time for the scheduler's
+    /// next decision, see `Scheduler::next_decision_time_us()`. Without a
+    /// timer, the chip sleeps until the next interrupt even if the scheduler
+    /// needs to decide earlier. The timer may be the same as the one passed
+    /// to `set_min_loop_period()`, as the kernel only sleeps for one reason
+    /// at a time.
+    pub fn set_decision_wakeup_timer(
+        &self,
+        timer: &'static dyn WakeupTimer,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        self.decision_wakeup.set(timer);
+    }
+
+    /// Keep the chip from ever sleeping, or allow it to sleep again.
+    ///
+    /// This is a development aid, for example to keep a debugger attached or
+    /// to rule out sleep as the cause of a problem. While sleep is inhibited
+    /// the main loop spins instead of sleeping when there is nothing to do,
+    /// so the chip draws its full active current all the time, typically
+    /// orders of magnitude more than when asleep. It also overrides the
+    /// minimum loop period set with `set_min_loop_period()`. Sleep is allowed
+    /// by default.
+    pub fn set_sleep_inhibited(
+        &self,
+        inhibited: bool,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        self.sleep_inhibited.set(inhibited);
+    }
+
+    /// Ask `notifier` before every sleep, so that it can warn processes and
+    /// have them run first, see `SleepNotifier`. A notifier that keeps
+    /// putting sleep off keeps the chip awake, so it must bound how long it
+    /// waits for processes.
+    pub fn set_sleep_notifier(
+        &self,
+        notifier: &'static dyn SleepNotifier,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        self.sleep_notifier.set(notifier);
+    }
+
+    /// Use `clock` and `timer` to hold processes that yield with `YIELD_FOR`
+    /// for the time they ask for.
+    ///
+    /// A held process is yielded, and is not run even if callbacks arrive
+    /// for it; they are queued and delivered once the hold ends. The kernel
+    /// checks `clock` every time around the main loop and ends the holds
+    /// that have passed, and before sleeping sets `timer` to wake the chip
+    /// when the next one ends. Holds only affect the held process, and are
+    /// independent of the scheduler timer, which only times timeslices.
+    /// A process may be held longer than it asked for if other processes
+    /// keep running, as it becomes ready again only when the kernel loop
+    /// next checks.
+    ///
+    /// Without a clock and timer there is no way to end a hold in time, so
+    /// `YIELD_FOR` is treated as a plain `YIELD`. Both may be the ones
+    /// passed to `set_wait_time_clock()` and `set_decision_wakeup_timer()`.
+    pub fn set_yield_for_timer(
+        &self,
+        clock: &'static dyn WaitTimeClock,
+        timer: &'static dyn WakeupTimer,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        self.yield_for_timer.set((clock, timer));
+    }
+
+    /// Hold `process`, which just yielded, for at least `min_us`, if holds
+    /// can be timed.
+    fn hold_yielded(&self, process: &dyn process::ProcessType, min_us: usize) {
+        self.yield_for_timer.map(|(clock, _)| {
+            // Limit holds to half the clock's range, so that a hold that
+            // has passed is never mistaken for one that just started.
+            let min_us = cmp::min(min_us, (u32::MAX / 2) as usize) as u32;
+            if min_us > 0 {
+                process.hold(clock.now_us(), min_us);
+            }
+        });
+    }
+
+    /// Microseconds until the next hold of a process ends, 0 if one already
+    /// has, or `None` if no process is held.
+    fn next_hold_end_us(&self) -> Option<u32> {
+        let (clock, _) = self.yield_for_timer.map(|timers| *timers)?;
+        let now = clock.now_us();
+        self.processes
+            .iter()
+            .flatten()
+            .filter_map(|process| process.hold_time())
+            .map(|(since_us, for_us)| for_us.saturating_sub(now.wrapping_sub(since_us)))
+            .min()
+    }
+
+    /// Let processes whose hold has passed run again.
+    fn end_passed_holds(&self) {
+        self.yield_for_timer.map(|(clock, _)| {
+            let now = clock.now_us();
+            self.process_each(|process| {
+                if let Some((since_us, for_us)) = process.hold_time() {
+                    if now.wrapping_sub(since_us) >= for_us {
+                        process.release_hold();
+                    }
+                }
+            });
+        });
+    }
+
+    /// Make sure the chip wakes up when the next hold ends, before it sleeps.
+    /// Returns `false` if a hold has already ended, so the chip should not
+    /// sleep at all.
+    fn arm_hold_wakeup(&self) -> bool {
+        match self.next_hold_end_us() {
+            Some(0) => false,
+            Some(us) => {
+                self.yield_for_timer.map(|(_, timer)| timer.set_wakeup(us));
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Guarantee that the kernel services interrupts and deferred calls at
+    /// least every `period_us` microseconds, even when processes are always
+    /// ready.
+    ///
+    /// A process normally runs until it yields, exhausts the timeslice the
+    /// scheduler gave it, or the scheduler stops it for kernel work. Some
+    /// schedulers, such as MLFQ, never stop a process for kernel work, and
+    /// cooperative scheduling gives no timeslice at all, so a compute-bound
+    /// process set can hold off capsules for a long time. With a period set,
+    /// every process runs for at most `period_us` at a time. When that cuts a
+    /// process short, the scheduler sees it as preempted by the kernel, and
+    /// the kernel loop services any pending kernel work before the next
+    /// scheduling decision.
+    ///
+    /// This needs the chip to provide a scheduler timer, also for cooperative
+    /// scheduling. Shorter periods mean more context switches. Returns
+    /// `EINVAL` if `period_us` is not above `MIN_QUANTA_THRESHOLD_US`, as
+    /// processes would then never run. Passing `None` removes the guarantee.
+    pub fn set_kernel_service_period(
+        &self,
+        period_us: Option<u32>,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) -> ReturnCode {
+        match period_us {
+            Some(period) if period <= MIN_QUANTA_THRESHOLD_US => ReturnCode::EINVAL,
+            Some(period) => {
+                self.kernel_service_period_us.set(period);
+                ReturnCode::SUCCESS
+            }
+            None => {
+                self.kernel_service_period_us.clear();
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// Longest time, in microseconds, preemption can be disabled for with
+    /// `disable_preemption()`.
+    pub const MAX_NO_PREEMPTION_US: u32 = 20_000;
+
+    /// Stop the kernel from preempting processes for the next `duration_us`
+    /// microseconds of process execution.
     ///
-    /// Grants **must** only be created _before_ processes are initialized.
-    /// Processes use the number of grants that have been allocated to correctly
-    /// initialize the process's memory with a pointer for each grant. If a
-    /// grant is created after processes are initialized this will panic.
+    /// This is a privileged tool for short board-level operations that must
+    /// not be interrupted by a context switch, not for general use. While it
+    /// is in effect, the running process keeps running past the end of its
+    /// timeslice and the kernel does not stop it to service interrupts or
+    /// deferred calls, so everything else waits. The scheduler timer bounds
+    /// the window: once processes have run for `duration_us`, preemption is
+    /// enabled again on its own. Call `enable_preemption()` as soon as the
+    /// operation is done.
     ///
-    /// Calling this function is restricted to only certain users, and to
-    /// enforce this calling this function requires the
-    /// `MemoryAllocationCapability` capability.
-    pub fn create_grant<T: Default>(
-        &'static self,
-        _capability: &dyn capabilities::MemoryAllocationCapability,
-    ) -> Grant<T> {
-        if self.grants_finalized.get() {
-            panic!("Grants finalized. Cannot create a new grant.");
+    /// Cooperatively scheduled processes are never preempted, and a process
+    /// being single-stepped is not affected. Returns `EINVAL` if
+    /// `duration_us` is 0 or above `MAX_NO_PREEMPTION_US`.
+    pub fn disable_preemption(
+        &self,
+        duration_us: u32,
+        _capability: &dyn capabilities::PreemptionControlCapability,
+    ) -> ReturnCode {
+        if duration_us == 0 || duration_us > Self::MAX_NO_PREEMPTION_US {
+            return ReturnCode::EINVAL;
         }
+        self.no_preemption_us.set(duration_us);
+        ReturnCode::SUCCESS
+    }
 
-        // Create and return a new grant.
-        let grant_index = self.grant_counter.get();
-        self.grant_counter.increment();
-        Grant::new(self, grant_index)
+    /// End a window started with `disable_preemption()` early.
+    pub fn enable_preemption(&self, _capability: &dyn capabilities::PreemptionControlCapability) {
+        self.no_preemption_us.set(0);
     }
 
-    /// Returns the number of grants that have been setup in the system and
-    /// marks the grants as "finalized". This means that no more grants can
-    /// be created because data structures have been setup based on the number
-    /// of grants when this function is called.
+    /// Start timing how long ready processes wait to be scheduled, using
+    /// `clock`.
     ///
-    /// In practice, this is called when processes are created, and the process
-    /// memory is setup based on the number of current grants.
-    pub(crate) fn get_grant_count_and_finalize(&self) -> usize {
-        self.grants_finalized.set(true);
-        self.grant_counter.get()
+    /// To keep this cheap, waits are only updated when the scheduler makes a
+    /// decision: a process that is ready but not chosen is marked as waiting
+    /// from then on, unless it already was, and the chosen process and
+    /// processes that are not ready stop waiting. A process whose wait time
+    /// keeps growing is being starved.
+    pub fn set_wait_time_clock(
+        &self,
+        clock: &'static dyn WaitTimeClock,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) {
+        self.wait_time_clock.set(clock);
     }
 
-    /// Returns the number of grants that have been setup in the system and
-    /// marks the grants as "finalized". This means that no more grants can
-    /// be created because data structures have been setup based on the number
-    /// of grants when this function is called.
-    ///
-    /// In practice, this is called when processes are created, and the process
-    /// memory is setup based on the number of current grants.
-    ///
-    /// This is exposed publicly, but restricted with a capability. The intent
-    /// is that external implementations of `ProcessType` need to be able to
-    /// retrieve the final number of grants.
-    pub fn get_grant_count_and_finalize_external(
+    /// Keep the time since boot with `clock`, see `kernel::uptime`. The same
+    /// clock may also be passed to `set_wait_time_clock()`.
+    pub fn set_uptime_clock(
         &self,
-        _capability: &dyn capabilities::ExternalProcessCapability,
-    ) -> usize {
-        self.get_grant_count_and_finalize()
+        clock: &'static dyn UptimeClock,
+        _capability: &dyn capabilities::MainLoopCapability,
+    ) {
+        self.uptime_clock.set(clock);
     }
 
-    /// Create a new unique identifier for a process and return the identifier.
-    ///
-    /// Typically we just choose a larger number than we have used for any process
-    /// before which ensures that the identifier is unique.
-    pub(crate) fn create_process_identifier(&self) -> usize {
-        self.process_identifier_max.get_and_increment()
+    /// Microseconds since boot, or `None` if the board set no clock with
+    /// `set_uptime_clock()`. The value does not wrap.
+    pub fn uptime_us(&self) -> Option<u64> {
+        self.uptime_clock.map(|clock| clock.uptime_us())
     }
 
-    /// Cause all apps to fault.
-    ///
-    /// This will call `set_fault_state()` on each app, causing the app to enter
-    /// the state as if it had crashed (for example with an MPU violation). If
-    /// the process is configured to be restarted it will be.
-    ///
-    /// Only callers with the `ProcessManagementCapability` can call this
-    /// function. This restricts general capsules from being able to call this
-    /// function, since capsules should not be able to arbitrarily restart all
-    /// apps.
-    pub fn hardfault_all_apps<C: capabilities::ProcessManagementCapability>(&self, _c: &C) {
-        for p in self.processes.iter() {
-            p.map(|process| {
-                process.set_fault_state();
+    /// How long, in microseconds, the process has been ready without being
+    /// scheduled. This is 0 if it was not waiting at the last scheduling
+    /// decision. Returns `None` if `appid` is not valid or no clock was set
+    /// with `set_wait_time_clock()`.
+    pub fn process_wait_time_us(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<u32> {
+        let now = self.wait_time_clock.map(|clock| clock.now_us())?;
+        self.process_map_or(None, appid, |process| {
+            Some(
+                process
+                    .debug_waiting_since()
+                    .map_or(0, |since| now.wrapping_sub(since)),
+            )
+        })
+    }
+
+    /// Total time, in microseconds, that the process has run with a timeslice
+    /// since it started. Saturates instead of wrapping. Returns `None` if
+    /// `appid` is not valid.
+    pub fn process_cpu_time_us(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<u64> {
+        self.cpu_time(appid).map(|time| time.charged_us)
+    }
+
+    /// Total time, in microseconds, that the process has run cooperatively,
+    /// without a timeslice, since it started. The kernel can only measure
+    /// this with the clock set with `set_wait_time_clock()`; without one it
+    /// stays 0. Returns `None` in the same cases as `process_cpu_time_us()`.
+    pub fn process_uncharged_time_us(
+        &self,
+        appid: AppId,
+        _capability: &dyn capabilities::ProcessManagementCapability,
+    ) -> Option<u64> {
+        self.cpu_time(appid).map(|time| time.uncharged_us)
+    }
+
+    fn cpu_time(&self, appid: AppId) -> Option<CpuTime> {
+        self.process_map_or(None, appid, |process| Some(CpuTime::of(process)))
+    }
+
+    /// Add a run of the process `appid` to its CPU time. `started_us` is when
+    /// the run started according to the wait time clock, if there is one.
+    fn account_cpu_time(
+        &self,
+        appid: AppId,
+        time_executed_us: Option<u32>,
+        started_us: Option<u32>,
+    ) {
+        let measured_us = started_us.and_then(|started| {
+            self.wait_time_clock
+                .map(|clock| clock.now_us().wrapping_sub(started))
+        });
+        self.process_map_or((), appid, |process| {
+            let mut time = CpuTime::of(process);
+            time.add(time_executed_us, measured_us);
+            process.debug_set_cpu_time_us(time.charged_us, time.uncharged_us);
+        });
+    }
+
+    /// Update which processes are waiting to run after the scheduler chose
+    /// `scheduled`, or no process.
+    fn update_wait_times(&self, scheduled: Option<AppId>) {
+        self.wait_time_clock.map(|clock| {
+            let now = clock.now_us();
+            self.process_each(|process| {
+                if Some(process.appid()) == scheduled || !process.ready() {
+                    process.debug_set_waiting_since(None);
+                } else if process.debug_waiting_since().is_none() {
+                    process.debug_set_waiting_since(Some(now));
+                }
             });
+        });
+    }
+
+    /// Whether sleep is inhibited with `set_sleep_inhibited()`.
+    pub fn sleep_inhibited(&self) -> bool {
+        self.sleep_inhibited.get()
+    }
+
+    /// Make sure the chip wakes up for the scheduler's next decision,
+    /// `decision_us` from now, before it sleeps. Returns `false` if the
+    /// decision is due now, so the chip should not sleep at all.
+    fn arm_decision_wakeup(&self, decision_us: Option<u32>) -> bool {
+        match decision_us {
+            Some(0) => false,
+            Some(us) => {
+                self.decision_wakeup.map(|timer| timer.set_wakeup(us));
+                true
+            }
+            None => true,
         }
     }
 
+    /// Sleep out the rest of the minimum loop period after a process yielded
+    /// having run for `time_executed_us`, waking up earlier for a scheduling
+    /// decision due in `decision_us`. Does not sleep if any process is ready.
+    unsafe fn throttle_loop<C: Chip>(
+        &self,
+        chip: &C,
+        time_executed_us: u32,
+        decision_us: Option<u32>,
+    ) {
+        self.loop_throttle.map(|&mut (period_us, timer)| {
+            if time_executed_us >= period_us || self.sleep_inhibited.get() {
+                return;
+            }
+            chip.atomic(|| {
+                // As when idle, do not sleep with kernel work pending, and
+                // only throttle when there is no other process to run.
+                if !chip.has_pending_interrupts()
+                    && !DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false)
+                    && !self
+                        .processes
+                        .iter()
+                        .flatten()
+                        .any(|process| process.ready())
+                {
+                    let sleep_us = cmp::min(
+                        period_us - time_executed_us,
+                        decision_us.unwrap_or(u32::MAX),
+                    );
+                    if sleep_us == 0 {
+                        return;
+                    }
+                    timer.set_wakeup(sleep_us);
+                    chip.watchdog().suspend();
+                    chip.sleep();
+                    chip.watchdog().resume();
+                }
+            });
+        });
+    }
+
     /// Main loop of the OS.
     ///
     /// Most of the behavior of this loop is controlled by the `Scheduler`
@@ -465,6 +1833,15 @@ impl Kernel {
         chip.watchdog().setup();
         loop {
             chip.watchdog().tickle();
+            if config::CONFIG.check_scheduler_invariants {
+                self.check_invariants();
+            }
+            self.end_passed_holds();
+            let idle = self.processes_blocked();
+            if idle && !self.system_idle.get() {
+                scheduler.on_system_idle();
+            }
+            self.system_idle.set(idle);
             unsafe {
                 // Ask the scheduler if we should do tasks inside of the kernel,
                 // such as handle interrupts. A scheduler may want to prioritize
@@ -478,21 +1855,37 @@ impl Kernel {
                     }
                     false => {
                         // No kernel work ready, so ask scheduler for a process.
-                        match scheduler.next(self) {
+                        if let Some((appid, parameter, value)) =
+                            self.pending_scheduling_parameter.take()
+                        {
+                            self.scheduling_parameter_result
+                                .set(Some(scheduler.set_parameter(appid, parameter, value)));
+                        }
+                        let decision = scheduler.next(self);
+                        self.update_wait_times(match decision {
+                            SchedulingDecision::RunProcess((appid, _)) => Some(appid),
+                            SchedulingDecision::TrySleep => None,
+                        });
+                        match decision {
                             SchedulingDecision::RunProcess((appid, timeslice_us)) => {
-                                self.process_map_or((), appid, |process| {
-                                    let (reason, time_executed) = self.do_process(
-                                        platform,
-                                        chip,
-                                        scheduler,
-                                        process,
-                                        ipc,
-                                        timeslice_us,
-                                    );
-                                    scheduler.result(reason, time_executed);
+                                let yielded_after = self.run_process(
+                                    platform,
+                                    chip,
+                                    scheduler,
+                                    ipc,
+                                    appid,
+                                    timeslice_us,
+                                );
+                                yielded_after.map(|us| {
+                                    let decision_us = scheduler.next_decision_time_us();
+                                    self.throttle_loop(chip, us, decision_us)
                                 });
+                                if self.kernel_service_due.replace(false) {
+                                    scheduler.execute_kernel_work(chip);
+                                }
                             }
                             SchedulingDecision::TrySleep => {
+                                let decision_us = scheduler.next_decision_time_us();
                                 chip.atomic(|| {
                                     // Cannot sleep if interrupts are pending,
                                     // as on most platforms unhandled interrupts
@@ -506,6 +1899,12 @@ impl Kernel {
                                     if !chip.has_pending_interrupts()
                                         && !DynamicDeferredCall::global_instance_calls_pending()
                                             .unwrap_or(false)
+                                        && !self.sleep_inhibited.get()
+                                        && self.arm_decision_wakeup(decision_us)
+                                        && self.arm_hold_wakeup()
+                                        && self
+                                            .sleep_notifier
+                                            .map_or(true, |notifier| notifier.ready_to_sleep())
                                     {
                                         chip.watchdog().suspend();
                                         chip.sleep();
@@ -520,6 +1919,30 @@ impl Kernel {
         }
     }
 
+    /// Run the process the scheduler chose with `do_process()`, add the run to
+    /// its CPU time and report the result to the scheduler. Returns how long
+    /// the process ran if it stopped because it had no work left and was run
+    /// with a timeslice.
+    unsafe fn run_process<P: Platform, C: Chip, S: Scheduler<C>, const NUM_PROCS: usize>(
+        &self,
+        platform: &P,
+        chip: &C,
+        scheduler: &S,
+        ipc: Option<&ipc::IPC<NUM_PROCS>>,
+        appid: AppId,
+        timeslice_us: Option<u32>,
+    ) -> Option<u32> {
+        self.process_map_or(None, appid, |process| {
+            let started_us = self.wait_time_clock.map(|clock| clock.now_us());
+            let (reason, time_executed) =
+                self.do_process(platform, chip, scheduler, process, ipc, timeslice_us);
+            self.account_cpu_time(appid, time_executed, started_us);
+            let yielded = reason == StoppedExecutingReason::NoWorkLeft;
+            scheduler.result(reason, time_executed);
+            time_executed.filter(|_| yielded)
+        })
+    }
+
     /// Transfer control from the kernel to a userspace process.
     ///
     /// This function is called by the main kernel loop to run userspace code.
@@ -560,6 +1983,38 @@ impl Kernel {
         ipc: Option<&crate::ipc::IPC<NUM_PROCS>>,
         timeslice_us: Option<u32>,
     ) -> (StoppedExecutingReason, Option<u32>) {
+        // A process being single-stepped always runs with a minimal timeslice,
+        // regardless of what the scheduler asked for.
+        let single_step = self.single_step.contains(&process.appid());
+        let timeslice_us = if single_step {
+            Some(SINGLE_STEP_TIMESLICE_US)
+        } else {
+            timeslice_us
+        };
+        // Whether the process has been switched to during this call.
+        let mut stepped = false;
+
+        // Never let the process hold off kernel work for longer than the
+        // kernel service period.
+        let requested_us = timeslice_us;
+        let timeslice_us = match self.kernel_service_period_us.map(|period| *period) {
+            Some(period) if !single_step && timeslice_us.map_or(true, |t| t > period) => {
+                Some(period)
+            }
+            _ => timeslice_us,
+        };
+        let service_capped = timeslice_us != requested_us;
+
+        // If preemption is disabled, run the process through the rest of that
+        // window even if its timeslice is shorter. The scheduler timer then
+        // ends the window.
+        let no_preemption_us = if single_step {
+            0
+        } else {
+            self.no_preemption_us.get()
+        };
+        let timeslice_us = timeslice_us.map(|timeslice| cmp::max(timeslice, no_preemption_us));
+
         // We must use a dummy scheduler timer if the process should be executed
         // without any timeslice restrictions. Note, a chip may not provide a
         // real scheduler timer implementation even if a timeslice is requested.
@@ -576,6 +2031,24 @@ impl Kernel {
         scheduler_timer.reset();
         timeslice_us.map(|timeslice| scheduler_timer.start(timeslice));
 
+        // Bound the time spent handling the process's syscalls.
+        let max_syscall_us = if single_step {
+            None
+        } else {
+            scheduler.max_syscall_handling_us()
+        };
+        let started_us = self.wait_time_clock.map(|clock| clock.now_us());
+        let elapsed_us = || match started_us {
+            Some(start) => self
+                .wait_time_clock
+                .map(|clock| clock.now_us().wrapping_sub(start)),
+            None => timeslice_us.and_then(|timeslice| {
+                scheduler_timer
+                    .get_remaining_us()
+                    .map(|remaining| timeslice.saturating_sub(remaining))
+            }),
+        };
+
         // Need to track why the process is no longer executing so that we can
         // inform the scheduler.
         let mut return_reason = StoppedExecutingReason::NoWorkLeft;
@@ -587,6 +2060,18 @@ impl Kernel {
         // no longer wants to execute this process or if it exceeds its
         // timeslice.
         loop {
+            self.syscall_caller.set(None);
+            if self.timeslice_yielded.replace(false) {
+                return_reason = StoppedExecutingReason::TimesliceYielded;
+                break;
+            }
+
+            if single_step && stepped {
+                // The process has completed its step.
+                return_reason = StoppedExecutingReason::SingleStep;
+                break;
+            }
+
             let stop_running = match scheduler_timer.get_remaining_us() {
                 Some(us) => us <= MIN_QUANTA_THRESHOLD_US,
                 None => true,
@@ -598,8 +2083,21 @@ impl Kernel {
                 break;
             }
 
-            // Check if the scheduler wishes to continue running this process.
-            if !scheduler.continue_process(process.appid(), chip) {
+            if stepped && syscall_handling_exceeded(max_syscall_us, elapsed_us()) {
+                return_reason = StoppedExecutingReason::KernelPreemption;
+                break;
+            }
+
+            // Check if the scheduler wishes to continue running this process,
+            // unless preemption is disabled. It may have been enabled again
+            // since the process started running.
+            let preemptible = match (timeslice_us, scheduler_timer.get_remaining_us()) {
+                (Some(timeslice), Some(remaining)) if no_preemption_us > 0 => {
+                    timeslice.saturating_sub(remaining) >= self.no_preemption_us.get()
+                }
+                _ => true,
+            };
+            if preemptible && !scheduler.continue_process(process.appid(), chip) {
                 return_reason = StoppedExecutingReason::KernelPreemption;
                 break;
             }
@@ -618,16 +2116,26 @@ impl Kernel {
                     let context_switch_reason = process.switch_to();
                     scheduler_timer.disarm();
                     chip.mpu().disable_app_mpu();
+                    stepped = true;
 
                     // Now the process has returned back to the kernel. Check
                     // why and handle the process as appropriate.
                     match context_switch_reason {
                         Some(ContextSwitchReason::Fault) => {
-                            // Let process deal with it as appropriate.
-                            process.set_fault_state();
+                            // Let the scheduler decide what to do. By default
+                            // the process deals with it as appropriate.
+                            let action = scheduler.on_process_fault(process);
+                            if !apply_fault_action(process, action) {
+                                return_reason = StoppedExecutingReason::StoppedFaulted;
+                                break;
+                            }
                         }
                         Some(ContextSwitchReason::SyscallFired { syscall }) => {
                             process.debug_syscall_called(syscall);
+                            let remaining_us = timeslice_us
+                                .map(|_| scheduler_timer.get_remaining_us().unwrap_or(0));
+                            self.syscall_caller
+                                .set(Some((process.appid(), remaining_us)));
 
                             // Enforce platform-specific syscall filtering here.
                             //
@@ -643,7 +2151,11 @@ impl Kernel {
                             // immediately (assuming the process has not already
                             // exhausted its timeslice) allowing the process to
                             // decide how to handle the error.
-                            if syscall != Syscall::YIELD {
+                            let yielding = match syscall {
+                                Syscall::YIELD | Syscall::YIELD_FOR { .. } => true,
+                                _ => false,
+                            };
+                            if !yielding {
                                 if let Err(response) = platform.filter_syscall(process, &syscall) {
                                     process.set_syscall_return_value(response.into());
                                     continue;
@@ -666,11 +2178,14 @@ impl Kernel {
                                     }
                                     process.set_syscall_return_value(res.into());
                                 }
-                                Syscall::YIELD => {
+                                Syscall::YIELD | Syscall::YIELD_FOR { .. } => {
                                     if config::CONFIG.trace_syscalls {
-                                        debug!("[{:?}] yield", process.appid());
+                                        debug!("[{:?}] {:?}", process.appid(), syscall);
                                     }
                                     process.set_yielded_state();
+                                    if let Syscall::YIELD_FOR { min_us } = syscall {
+                                        self.hold_yielded(process, min_us);
+                                    }
 
                                     // There might be already enqueued callbacks
                                     continue;
@@ -716,6 +2231,15 @@ impl Kernel {
                                                 None => ReturnCode::ENODEVICE,
                                             },
                                         );
+                                    // Callbacks the driver still holds for an
+                                    // earlier subscription are stale from now on.
+                                    if res == ReturnCode::SUCCESS {
+                                        process.set_subscription(
+                                            callback_id,
+                                            callback_ptr as usize,
+                                            appdata,
+                                        );
+                                    }
                                     if config::CONFIG.trace_syscalls {
                                         debug!(
                                             "[{:?}] subscribe({:#x}, {}, @{:#x}, {:#x}) = {:#x} = {:?}",
@@ -772,18 +2296,52 @@ impl Kernel {
                                     let res = platform.with_driver(driver_number, |driver| {
                                         match driver {
                                             Some(d) => {
-                                                match process.allow(allow_address, allow_size) {
-                                                    Ok(oslice) => d.allow(
-                                                        process.appid(),
-                                                        subdriver_number,
-                                                        oslice,
-                                                    ),
-                                                    Err(err) => err, /* memory not valid */
+                                                if d.allow_max_size(subdriver_number)
+                                                    .map_or(false, |max| allow_size > max)
+                                                {
+                                                    // Larger than the driver accepts.
+                                                    ReturnCode::ESIZE
+                                                } else {
+                                                    match process.allow(allow_address, allow_size) {
+                                                        Ok(mut oslice) => {
+                                                            if d.allow_zero_on_revoke(
+                                                                subdriver_number,
+                                                            ) {
+                                                                oslice.as_mut().map(|slice| {
+                                                                    slice.set_zero_on_drop()
+                                                                });
+                                                            }
+                                                            d.allow(
+                                                                process.appid(),
+                                                                subdriver_number,
+                                                                oslice,
+                                                            )
+                                                        }
+                                                        Err(err) => err, /* memory not valid */
+                                                    }
                                                 }
                                             }
                                             None => ReturnCode::ENODEVICE,
                                         }
                                     });
+                                    if config::CONFIG.trace_allows && res == ReturnCode::SUCCESS {
+                                        let revoke = allow_address.is_null() || allow_size == 0;
+                                        debug!(
+                                            "[{:?}] {} {:#x}:{} @{:#x}, {} bytes",
+                                            process.appid(),
+                                            if revoke { "revoke" } else { "allow" },
+                                            driver_number,
+                                            subdriver_number,
+                                            allow_address as usize,
+                                            allow_size,
+                                        );
+                                        process.trace_allow(
+                                            driver_number,
+                                            subdriver_number,
+                                            allow_address as usize,
+                                            allow_size,
+                                        );
+                                    }
                                     if config::CONFIG.trace_syscalls {
                                         debug!(
                                             "[{:?}] allow({:#x}, {}, @{:#x}, {:#x}) = {:#x} = {:?}",
@@ -822,6 +2380,10 @@ impl Kernel {
                     }
                 }
                 process::State::Yielded | process::State::Unstarted => {
+                    // A held process must not run until its hold ends.
+                    if process.hold_time().is_some() {
+                        break;
+                    }
                     // If the process is yielded or hasn't been started it is
                     // waiting for a callback. If there is a task scheduled for
                     // this process go ahead and set the process to execute it.
@@ -862,16 +2424,11 @@ impl Kernel {
                     // We should never be scheduling a process in fault.
                     panic!("Attempted to schedule a faulty process");
                 }
-                process::State::StoppedRunning => {
-                    return_reason = StoppedExecutingReason::Stopped;
-                    break;
-                }
-                process::State::StoppedYielded => {
-                    return_reason = StoppedExecutingReason::Stopped;
-                    break;
-                }
-                process::State::StoppedFaulted => {
-                    return_reason = StoppedExecutingReason::StoppedFaulted;
+                state @ process::State::StoppedRunning
+                | state @ process::State::StoppedYielded
+                | state @ process::State::StoppedFaulted => {
+                    // Stopped processes are not run until they are resumed.
+                    return_reason = stopped_reason(state);
                     break;
                 }
             }
@@ -893,11 +2450,341 @@ impl Kernel {
             }
         });
 
+        // The time the process ran counts against the window without
+        // preemption, which ends once it is used up.
+        if no_preemption_us > 0 {
+            let used_us = time_executed_us.map_or(0, |us| cmp::min(us, no_preemption_us));
+            self.no_preemption_us
+                .set(self.no_preemption_us.get().saturating_sub(used_us));
+        }
+
         // Reset the scheduler timer in case it unconditionally triggers
         // interrupts upon expiration. We do not want it to expire while the
         // chip is sleeping, for example.
         scheduler_timer.reset();
 
+        // Expiring the shortened timeslice is how a step normally ends.
+        if single_step && stepped && return_reason == StoppedExecutingReason::TimesliceExpired {
+            return_reason = StoppedExecutingReason::SingleStep;
+        }
+
+        // The process did not use up the timeslice it was given, the kernel
+        // cut it short, and a cooperatively scheduled process is still run
+        // without a timeslice as far as the scheduler knows.
+        if service_capped && return_reason == StoppedExecutingReason::TimesliceExpired {
+            return_reason = StoppedExecutingReason::KernelPreemption;
+            self.kernel_service_due.set(true);
+        }
+        // A process kept running while preemption was disabled may have run
+        // past its timeslice; schedulers only expect up to what they gave.
+        let time_executed_us = requested_us
+            .and_then(|requested| time_executed_us.map(|executed| cmp::min(executed, requested)));
+
         (return_reason, time_executed_us)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        next_free_identifier, resumed_state, stopped_reason, stopped_state,
+        syscall_handling_exceeded, CpuTime,
+    };
+    use super::{Kernel, Scheduler, SchedulingDecision};
+    use crate::callback::AppId;
+    use crate::capabilities::ProcessManagementCapability;
+    use crate::create_capability;
+    use crate::driver::Driver;
+    use crate::ipc::IPC;
+    use crate::platform::{Chip, Platform};
+    use crate::process::{FunctionCall, FunctionCallSource, ProcessType, State, Task};
+    use crate::returncode::ReturnCode;
+    use crate::sched::StoppedExecutingReason;
+    use crate::syscall::{ContextSwitchReason, Syscall};
+    use crate::testing::{MockChip, MockProcess};
+    use core::cell::Cell;
+    use std::boxed::Box;
+
+    /// A board without any drivers.
+    struct NoDrivers;
+
+    impl Platform for NoDrivers {
+        fn with_driver<F, R>(&self, _driver_num: usize, f: F) -> R
+        where
+            F: FnOnce(Option<&dyn Driver>) -> R,
+        {
+            f(None)
+        }
+    }
+
+    /// Keeps running a process as long as it has work, and records why it
+    /// stopped.
+    struct RunToCompletion {
+        result: Cell<Option<StoppedExecutingReason>>,
+        max_syscall_handling_us: Option<u32>,
+    }
+
+    impl RunToCompletion {
+        fn new(max_syscall_handling_us: Option<u32>) -> RunToCompletion {
+            RunToCompletion {
+                result: Cell::new(None),
+                max_syscall_handling_us,
+            }
+        }
+    }
+
+    impl Scheduler<MockChip> for RunToCompletion {
+        fn next(&self, _kernel: &Kernel) -> SchedulingDecision {
+            SchedulingDecision::TrySleep
+        }
+
+        fn result(&self, result: StoppedExecutingReason, _execution_time_us: Option<u32>) {
+            self.result.set(Some(result));
+        }
+
+        unsafe fn continue_process(&self, _id: AppId, _chip: &MockChip) -> bool {
+            true
+        }
+
+        fn max_syscall_handling_us(&self) -> Option<u32> {
+            self.max_syscall_handling_us
+        }
+    }
+
+    /// A kernel with a single mock process, which is running, and a chip on
+    /// which each switch to the process takes `switch_us`.
+    fn setup(switch_us: u32) -> (&'static Kernel, &'static MockProcess, &'static MockChip) {
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+        let kernel: &'static Kernel = Box::leak(Box::new(Kernel::new(processes)));
+        process.attach(kernel, 0);
+        let chip: &'static MockChip = Box::leak(Box::new(MockChip::new()));
+        chip.scheduler_timer().set_switch_us(switch_us);
+        (kernel, process, chip)
+    }
+
+    /// Run the process as the kernel loop does after the scheduler chose it.
+    fn run(
+        kernel: &Kernel,
+        chip: &MockChip,
+        scheduler: &RunToCompletion,
+        appid: AppId,
+        timeslice_us: Option<u32>,
+    ) -> Option<StoppedExecutingReason> {
+        unsafe {
+            kernel.run_process(
+                &NoDrivers,
+                chip,
+                scheduler,
+                None::<&IPC<1>>,
+                appid,
+                timeslice_us,
+            );
+        }
+        scheduler.result.take()
+    }
+
+    /// A callback for the process to run.
+    fn callback() -> Task {
+        Task::FunctionCall(FunctionCall {
+            source: FunctionCallSource::Kernel,
+            argument0: 0,
+            argument1: 0,
+            argument2: 0,
+            argument3: 0,
+            pc: 0x1000,
+        })
+    }
+
+    #[test]
+    fn identifiers_wrap_around_live_processes() {
+        let live = [usize::MAX, 0, 2];
+        let in_use = |identifier| live.contains(&identifier);
+
+        assert_eq!(next_free_identifier(usize::MAX - 1, in_use), usize::MAX - 1);
+        // Past the end of the range, skipping live identifiers on both sides.
+        assert_eq!(next_free_identifier(usize::MAX, in_use), 1);
+        assert_eq!(next_free_identifier(2, in_use), 3);
+    }
+
+    #[test]
+    fn cpu_time_accumulates_across_runs() {
+        let mut time = CpuTime::default();
+        time.add(Some(1_000), None);
+        time.add(Some(250), Some(9_999));
+        // Cooperative runs are kept apart, and count nothing if unmeasured.
+        time.add(None, Some(400));
+        time.add(None, None);
+        assert_eq!(time.charged_us, 1_250);
+        assert_eq!(time.uncharged_us, 400);
+    }
+
+    #[test]
+    fn cpu_time_saturates() {
+        let mut time = CpuTime {
+            charged_us: u64::MAX - 10,
+            uncharged_us: u64::MAX,
+        };
+        time.add(Some(100), None);
+        time.add(None, Some(100));
+        assert_eq!(time.charged_us, u64::MAX);
+        assert_eq!(time.uncharged_us, u64::MAX);
+    }
+
+    #[test]
+    fn cpu_time_accumulates_over_runs_of_the_process() {
+        let (kernel, process, chip) = setup(300);
+        let scheduler = RunToCompletion::new(None);
+        let appid = process.appid();
+        let cap = create_capability!(ProcessManagementCapability);
+
+        // The process yields each time it runs, and then runs again for the
+        // next callback.
+        for _ in 0..3 {
+            assert!(
+                run(kernel, chip, &scheduler, appid, Some(10_000))
+                    == Some(StoppedExecutingReason::NoWorkLeft)
+            );
+            assert!(process.enqueue_task(callback()));
+        }
+        assert_eq!(process.switches(), 3);
+        assert_eq!(kernel.process_cpu_time_us(appid, &cap), Some(900));
+        assert_eq!(kernel.process_uncharged_time_us(appid, &cap), Some(0));
+
+        // Run cooperatively, which without a clock cannot be measured.
+        run(kernel, chip, &scheduler, appid, None);
+        assert_eq!(process.switches(), 4);
+        assert_eq!(kernel.process_cpu_time_us(appid, &cap), Some(900));
+        assert_eq!(kernel.process_uncharged_time_us(appid, &cap), Some(0));
+    }
+
+    #[test]
+    fn cpu_time_is_accounted_in_every_slot() {
+        let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let mut processes = [None; 12];
+        processes[11] = Some(process as &dyn ProcessType);
+        let kernel: &'static Kernel =
+            Box::leak(Box::new(Kernel::new(Box::leak(Box::new(processes)))));
+        process.attach(kernel, 11);
+        let chip: &'static MockChip = Box::leak(Box::new(MockChip::new()));
+        chip.scheduler_timer().set_switch_us(300);
+        let cap = create_capability!(ProcessManagementCapability);
+
+        run(
+            kernel,
+            chip,
+            &RunToCompletion::new(None),
+            process.appid(),
+            Some(10_000),
+        );
+        assert_eq!(kernel.process_cpu_time_us(process.appid(), &cap), Some(300));
+    }
+
+    #[test]
+    fn stop_and_resume_restore_the_state() {
+        for &state in [State::Running, State::Yielded].iter() {
+            let stopped = stopped_state(state).unwrap();
+            assert_eq!(stopped_state(stopped), Err(ReturnCode::EALREADY));
+            assert_eq!(resumed_state(stopped), Ok(state));
+            assert_eq!(resumed_state(state), Err(ReturnCode::EALREADY));
+        }
+    }
+
+    #[test]
+    fn faulted_and_unstarted_processes_are_left_alone() {
+        for &state in [State::Fault, State::StoppedFaulted, State::Unstarted].iter() {
+            assert_eq!(stopped_state(state), Err(ReturnCode::FAIL));
+            assert_eq!(resumed_state(state), Err(ReturnCode::FAIL));
+        }
+    }
+
+    #[test]
+    fn stopped_process_is_not_run() {
+        for &state in [State::Running, State::Yielded].iter() {
+            let stopped = stopped_state(state).unwrap();
+            assert!(stopped_reason(stopped) == StoppedExecutingReason::Stopped);
+        }
+        assert!(stopped_reason(State::StoppedFaulted) == StoppedExecutingReason::StoppedFaulted);
+    }
+
+    #[test]
+    fn stopped_process_is_skipped_until_resumed() {
+        let (kernel, process, chip) = setup(100);
+        let scheduler = RunToCompletion::new(None);
+        let appid = process.appid();
+        let cap = create_capability!(ProcessManagementCapability);
+
+        assert_eq!(kernel.stop_process(appid, &cap), ReturnCode::SUCCESS);
+        assert!(
+            run(kernel, chip, &scheduler, appid, Some(10_000))
+                == Some(StoppedExecutingReason::Stopped)
+        );
+        assert_eq!(process.switches(), 0);
+
+        assert_eq!(kernel.resume_process(appid, &cap), ReturnCode::SUCCESS);
+        assert!(
+            run(kernel, chip, &scheduler, appid, Some(10_000))
+                == Some(StoppedExecutingReason::NoWorkLeft)
+        );
+        assert_eq!(process.switches(), 1);
+    }
+
+    #[test]
+    fn chatty_process_is_preempted_within_bound() {
+        // Each syscall takes 100us, counting both the time the process runs
+        // up to it and the time the kernel spends handling it.
+        let (max_us, syscall_us) = (1_000, 100);
+        let mut elapsed = 0;
+        let mut syscalls = 0;
+        while !syscall_handling_exceeded(Some(max_us), Some(elapsed)) {
+            elapsed += syscall_us;
+            syscalls += 1;
+        }
+        assert_eq!(syscalls, 10);
+        assert!(elapsed < max_us + syscall_us);
+
+        // Without a bound or a way to measure time nothing changes.
+        assert!(!syscall_handling_exceeded(None, Some(u32::MAX)));
+        assert!(!syscall_handling_exceeded(Some(max_us), None));
+    }
+
+    #[test]
+    fn process_making_syscalls_is_preempted() {
+        let (kernel, process, chip) = setup(100);
+        let scheduler = RunToCompletion::new(Some(1_000));
+        // A command to a driver the board does not have, over and over.
+        process.on_switch(|_, _| {
+            Some(ContextSwitchReason::SyscallFired {
+                syscall: Syscall::COMMAND {
+                    driver_number: 0x9999,
+                    subdriver_number: 0,
+                    arg0: 0,
+                    arg1: 0,
+                },
+            })
+        });
+        assert!(
+            run(kernel, chip, &scheduler, process.appid(), Some(10_000))
+                == Some(StoppedExecutingReason::KernelPreemption)
+        );
+        // Stopped once the 1000us bound was reached, long before the end of
+        // the timeslice.
+        assert_eq!(process.switches(), 10);
+        assert_eq!(process.return_value(), Some(ReturnCode::ENODEVICE.into()));
+    }
+
+    #[test]
+    fn empty_slots_are_not_counted_as_loaded() {
+        let p0: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let p1: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+        let processes = Box::leak(Box::new([
+            Some(p0 as &dyn ProcessType),
+            None,
+            Some(p1 as &dyn ProcessType),
+            None,
+        ]));
+        let kernel = Kernel::new(processes);
+        assert_eq!(kernel.loaded_processes(), 2);
+        assert_eq!(kernel.process_slots(), 4);
+    }
+}
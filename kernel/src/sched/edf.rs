@@ -0,0 +1,396 @@
+//! Earliest-Deadline-First Scheduler for Tock
+//!
+//! This scheduler runs the ready process whose current job has the nearest
+//! absolute deadline. Each process has a relative deadline, which is also the
+//! period of its jobs: a job starts when the process becomes ready, and has to
+//! finish, by yielding with no callbacks left to run, within one relative
+//! deadline. Once a job finishes the deadline of the next one is armed one
+//! period after the deadline of the finished one, so a periodic process keeps
+//! the deadlines of its period. A process that stays blocked past that
+//! deadline starts its next job with a fresh deadline instead.
+//!
+//! Relative deadlines are kept in a grant, so each process has its own. They
+//! start at `DEFAULT_DEADLINE_US` and can be changed with `set_deadline()` or
+//! `SchedulingParameter::Deadline`; a new relative deadline applies from the
+//! next job of the process on.
+//!
+//! Timeslices end no later than the deadline of the running job, so that the
+//! scheduler gets to decide again once it has passed. Processes whose jobs
+//! have the same deadline run in the order of the `processes` list.
+//!
+//! Deadlines are measured with the clock given to `EDFSched::new()`, and
+//! wrap around with it. Deadlines more than half the clock's range away
+//! cannot be ordered correctly, so relative deadlines are limited to
+//! `MAX_DEADLINE_US`.
+
+use crate::common::cells::OptionalCell;
+use crate::common::list::{List, ListLink, ListNode};
+use crate::grant::Grant;
+use crate::platform::{Chip, WaitTimeClock};
+use crate::process::ProcessType;
+use crate::returncode::ReturnCode;
+use crate::sched::{
+    Kernel, Scheduler, SchedulingDecision, SchedulingParameter, StoppedExecutingReason,
+    MIN_QUANTA_THRESHOLD_US,
+};
+use crate::AppId;
+use core::cell::Cell;
+use core::cmp;
+
+/// Relative deadline of a process that was not given one.
+pub const DEFAULT_DEADLINE_US: u32 = 100_000;
+
+/// Longest relative deadline a process can be given.
+pub const MAX_DEADLINE_US: u32 = u32::MAX / 4;
+
+/// The relative deadline of a process, stored in its grant region.
+pub struct ProcessDeadline {
+    relative_us: u32,
+}
+
+impl Default for ProcessDeadline {
+    fn default() -> ProcessDeadline {
+        ProcessDeadline {
+            relative_us: DEFAULT_DEADLINE_US,
+        }
+    }
+}
+
+/// A node in the linked list the scheduler uses to track processes
+pub struct EDFProcessNode<'a> {
+    proc: &'static Option<&'static dyn ProcessType>,
+    /// Absolute deadline of the current job if `in_job`, otherwise of the
+    /// next one. `None` until the process starts its first job.
+    deadline: Cell<Option<u32>>,
+    /// Whether the process has started a job it has not finished.
+    in_job: Cell<bool>,
+    next: ListLink<'a, EDFProcessNode<'a>>,
+}
+
+impl<'a> EDFProcessNode<'a> {
+    pub fn new(proc: &'static Option<&'static dyn ProcessType>) -> EDFProcessNode<'a> {
+        EDFProcessNode {
+            proc,
+            deadline: Cell::new(None),
+            in_job: Cell::new(false),
+            next: ListLink::empty(),
+        }
+    }
+}
+
+impl<'a> ListNode<'a, EDFProcessNode<'a>> for EDFProcessNode<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, EDFProcessNode> {
+        &self.next
+    }
+}
+
+/// Whether time `a` is before time `b`. Times wrap around, so they are
+/// compared by their distance rather than their value.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// The deadline of a job that starts at `now`, given the deadline armed when
+/// the previous job finished, if there was one.
+fn start_job(now: u32, armed: Option<u32>, relative_us: u32) -> u32 {
+    match armed {
+        Some(deadline) if !before(deadline, now) => deadline,
+        _ => now.wrapping_add(relative_us),
+    }
+}
+
+/// The deadline of the job after one with `deadline`.
+fn next_deadline(deadline: u32, relative_us: u32) -> u32 {
+    deadline.wrapping_add(relative_us)
+}
+
+/// Returns the entry with the nearest deadline, the first one on a tie.
+fn select<T>(ready: impl Iterator<Item = (T, u32)>) -> Option<T> {
+    let mut selected: Option<(T, u32)> = None;
+    for (entry, deadline) in ready {
+        let nearest = selected.as_ref().map_or(true, |&(_, selected_deadline)| {
+            before(deadline, selected_deadline)
+        });
+        if nearest {
+            selected = Some((entry, deadline));
+        }
+    }
+    selected.map(|(entry, _)| entry)
+}
+
+/// Timeslice for a job with `deadline`, ending at the deadline but no longer
+/// than `max_us`. A job that is about to miss its deadline, or already has,
+/// still runs for a useful time.
+fn timeslice(now: u32, deadline: u32, max_us: u32) -> u32 {
+    let until_deadline = if before(now, deadline) {
+        deadline.wrapping_sub(now)
+    } else {
+        0
+    };
+    cmp::max(
+        cmp::min(until_deadline, max_us),
+        2 * MIN_QUANTA_THRESHOLD_US,
+    )
+}
+
+/// Earliest-Deadline-First Scheduler
+pub struct EDFSched<'a> {
+    pub processes: List<'a, EDFProcessNode<'a>>,
+    deadlines: Grant<ProcessDeadline>,
+    clock: &'a dyn WaitTimeClock,
+    running: OptionalCell<&'a EDFProcessNode<'a>>,
+}
+
+impl<'a> EDFSched<'a> {
+    /// Longest a process can run before being pre-empted, even if its
+    /// deadline is further away.
+    const MAX_TIMESLICE_US: u32 = 10000;
+
+    pub fn new(deadlines: Grant<ProcessDeadline>, clock: &'a dyn WaitTimeClock) -> EDFSched<'a> {
+        EDFSched {
+            processes: List::new(),
+            deadlines,
+            clock,
+            running: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the relative deadline of a process, which applies from its next
+    /// job on. Returns `EINVAL` if `deadline_us` is 0 or greater than
+    /// `MAX_DEADLINE_US`.
+    pub fn set_deadline(&self, appid: AppId, deadline_us: u32) -> ReturnCode {
+        if deadline_us == 0 || deadline_us > MAX_DEADLINE_US {
+            return ReturnCode::EINVAL;
+        }
+        self.deadlines
+            .enter(appid, |process_deadline, _| {
+                process_deadline.relative_us = deadline_us;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// The relative deadline of a process, or the default deadline if its
+    /// grant region cannot be allocated.
+    pub fn deadline(&self, appid: AppId) -> u32 {
+        self.deadlines
+            .enter(appid, |process_deadline, _| process_deadline.relative_us)
+            .unwrap_or(DEFAULT_DEADLINE_US)
+    }
+}
+
+impl<'a, C: Chip> Scheduler<C> for EDFSched<'a> {
+    fn next(&self, kernel: &Kernel) -> SchedulingDecision {
+        if kernel.processes_blocked() {
+            // No processes ready
+            return SchedulingDecision::TrySleep;
+        }
+        let now = self.clock.now_us();
+        // Every ready process starts its job now, not only the one selected.
+        for node in self.processes.iter() {
+            if let Some(proc) = node.proc.filter(|proc| proc.ready()) {
+                if !node.in_job.get() {
+                    let relative_us = self.deadline(proc.appid());
+                    node.deadline
+                        .set(Some(start_job(now, node.deadline.get(), relative_us)));
+                    node.in_job.set(true);
+                }
+            }
+        }
+        let ready = self.processes.iter().filter_map(|node| {
+            node.proc
+                .filter(|proc| proc.ready())
+                .and(node.deadline.get())
+                .map(|deadline| (node, deadline))
+        });
+        let node = match select(ready) {
+            Some(node) => node,
+            None => return SchedulingDecision::TrySleep,
+        };
+        let deadline = node.deadline.get().unwrap_or(now); // Set for ready nodes.
+        self.running.set(node);
+
+        let next = node.proc.unwrap().appid(); // Selected nodes hold a process.
+        SchedulingDecision::RunProcess((
+            next,
+            Some(timeslice(now, deadline, Self::MAX_TIMESLICE_US)),
+        ))
+    }
+
+    fn result(&self, result: StoppedExecutingReason, _: Option<u32>) {
+        self.running.take().map(|node| {
+            // The job is done once the process has nothing left to do, arm
+            // the deadline of its next one.
+            if result == StoppedExecutingReason::NoWorkLeft {
+                if let (Some(proc), Some(deadline)) = (*node.proc, node.deadline.get()) {
+                    let relative_us = self.deadline(proc.appid());
+                    node.deadline
+                        .set(Some(next_deadline(deadline, relative_us)));
+                }
+                node.in_job.set(false);
+            }
+        });
+    }
+
+    fn set_parameter(&self, id: AppId, parameter: SchedulingParameter, value: u32) -> ReturnCode {
+        match parameter {
+            SchedulingParameter::Deadline => self.set_deadline(id, value),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{before, next_deadline, select, start_job, timeslice};
+
+    /// A process in a deterministic simulation of the scheduler, running jobs
+    /// of `work_us` that become ready every `period_us`.
+    struct Job {
+        relative_us: u32,
+        period_us: u32,
+        work_us: u32,
+        armed: Option<u32>,
+        deadline: Option<u32>,
+        left_us: u32,
+        released_at: u32,
+    }
+
+    impl Job {
+        fn new(relative_us: u32, period_us: u32, work_us: u32) -> Job {
+            Job {
+                relative_us,
+                period_us,
+                work_us,
+                armed: None,
+                deadline: None,
+                left_us: 0,
+                released_at: 0,
+            }
+        }
+    }
+
+    /// Runs `jobs` until `end_us`, with timeslices of at most `slice_us`.
+    /// Like the kernel, it decides again whenever a job becomes ready.
+    /// Returns the indices of the first jobs in the order they ran, and how
+    /// many deadlines were missed.
+    fn simulate(jobs: &mut [Job], slice_us: u32, end_us: u32) -> ([usize; 4], usize) {
+        let mut now = 0;
+        let mut order = [usize::MAX; 4];
+        let mut switches = 0;
+        let mut last = None;
+        let mut missed = 0;
+        while now < end_us {
+            for job in jobs.iter_mut() {
+                if job.deadline.is_none() && now >= job.released_at {
+                    job.left_us = job.work_us;
+                    job.deadline = Some(start_job(now, job.armed, job.relative_us));
+                }
+            }
+            let next_release_us = jobs
+                .iter()
+                .filter(|job| job.deadline.is_none())
+                .map(|job| job.released_at - now)
+                .min()
+                .unwrap_or(u32::MAX);
+            let ready = jobs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, job)| job.deadline.map(|deadline| (i, deadline)));
+            let i = match select(ready) {
+                Some(i) => i,
+                None => {
+                    now += next_release_us;
+                    continue;
+                }
+            };
+            if last != Some(i) {
+                if switches < order.len() {
+                    order[switches] = i;
+                }
+                switches += 1;
+                last = Some(i);
+            }
+            let job = &mut jobs[i];
+            let run_us = job
+                .left_us
+                .min(timeslice(now, job.deadline.unwrap(), slice_us))
+                .min(next_release_us);
+            now += run_us;
+            job.left_us -= run_us;
+            if job.left_us == 0 {
+                let deadline = job.deadline.take().unwrap();
+                if before(deadline, now) {
+                    missed += 1;
+                }
+                job.armed = Some(next_deadline(deadline, job.relative_us));
+                job.released_at += job.period_us;
+            }
+        }
+        (order, missed)
+    }
+
+    #[test]
+    fn nearest_deadline_runs_first() {
+        let ready = [(1, 300), (2, 100), (3, 200)];
+        assert_eq!(select(ready.iter().copied()), Some(2));
+    }
+
+    #[test]
+    fn ties_go_to_the_first_process() {
+        let ready = [(1, 300), (2, 100), (3, 100)];
+        assert_eq!(select(ready.iter().copied()), Some(2));
+        assert_eq!(select(core::iter::empty::<(u32, u32)>()), None);
+    }
+
+    #[test]
+    fn deadlines_compare_across_wraparound() {
+        let near_max = u32::MAX - 10;
+        let wrapped = near_max.wrapping_add(20);
+        assert!(before(near_max, wrapped));
+        let ready = [(1, wrapped), (2, near_max)];
+        assert_eq!(select(ready.iter().copied()), Some(2));
+    }
+
+    #[test]
+    fn timeslice_ends_at_deadline() {
+        assert_eq!(timeslice(1000, 5000, 10000), 4000);
+        assert_eq!(timeslice(1000, 50000, 10000), 10000);
+        // Jobs at or past their deadline still get a useful timeslice.
+        assert_eq!(timeslice(5000, 5000, 10000), 1000);
+        assert_eq!(timeslice(6000, 5000, 10000), 1000);
+    }
+
+    #[test]
+    fn finished_job_arms_next_period() {
+        // A periodic process keeps the deadlines of its period.
+        let deadline = start_job(0, None, 1000);
+        assert_eq!(deadline, 1000);
+        let armed = next_deadline(deadline, 1000);
+        assert_eq!(start_job(1200, Some(armed), 1000), 2000);
+        // One blocked past the armed deadline gets a fresh one.
+        assert_eq!(start_job(2500, Some(armed), 1000), 3500);
+    }
+
+    #[test]
+    fn feasible_set_meets_all_deadlines() {
+        // Utilization 1/4 + 2/5 + 3/10 = 0.95, which EDF can schedule.
+        let mut jobs = [
+            Job::new(4000, 4000, 1000),
+            Job::new(5000, 5000, 2000),
+            Job::new(10000, 10000, 3000),
+        ];
+        let (order, missed) = simulate(&mut jobs, 10000, 200_000);
+        assert_eq!(missed, 0);
+        assert_eq!(&order[..3], &[0, 1, 2]);
+    }
+
+    #[test]
+    fn ready_job_with_nearer_deadline_runs_next() {
+        let mut jobs = [Job::new(50000, 50000, 20000), Job::new(2000, 5000, 500)];
+        jobs[1].released_at = 1000;
+        let (order, missed) = simulate(&mut jobs, 10000, 50000);
+        assert_eq!(missed, 0);
+        assert_eq!(&order[..3], &[0, 1, 0]);
+    }
+}
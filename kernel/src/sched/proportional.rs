@@ -0,0 +1,333 @@
+//! Proportional-Share Scheduler for Tock
+//!
+//! This scheduler gives each process a share of the CPU proportional to its
+//! weight, using stride scheduling. Every process has a pass value and the
+//! ready process with the lowest pass runs next. When a process stops, its
+//! pass advances by the time it ran divided by its weight, so a process with
+//! twice the weight of another runs twice as long before its pass catches up.
+//! Unlike with `PrioritySched`, processes with a low weight are not starved,
+//! they only run less.
+//!
+//! Weights are kept in a grant, so each process has its own. They start at 100
+//! and can be changed at any time with `set_weight()`; a new weight applies
+//! to the time the process runs after the change. A process with weight 0 is
+//! never scheduled. The scheduler also caches each weight in its list of
+//! processes, so choosing the next process does not enter any grants.
+//!
+//! A process does not build up credit while it is blocked: when it is next
+//! selected, its pass is first raised to the pass of the process scheduled
+//! before it.
+
+use crate::common::cells::OptionalCell;
+use crate::common::list::{List, ListLink, ListNode};
+use crate::grant::Grant;
+use crate::platform::Chip;
+use crate::process::ProcessType;
+use crate::returncode::ReturnCode;
+use crate::sched::{
+    Kernel, Scheduler, SchedulingDecision, SchedulingParameter, StoppedExecutingReason,
+};
+use crate::AppId;
+use core::cell::Cell;
+
+/// Weight of a process that was not given one.
+const DEFAULT_WEIGHT: u32 = 100;
+
+/// Pass units a process with weight 1 accrues per microsecond of execution.
+const STRIDE_SCALE: u64 = 1 << 16;
+
+/// The scheduling weight of a process, stored in its grant region.
+pub struct ProcessWeight {
+    weight: u32,
+}
+
+impl Default for ProcessWeight {
+    fn default() -> ProcessWeight {
+        ProcessWeight {
+            weight: DEFAULT_WEIGHT,
+        }
+    }
+}
+
+/// A node in the linked list the scheduler uses to track processes
+pub struct ProportionalProcessNode<'a> {
+    proc: &'static Option<&'static dyn ProcessType>,
+    pass: Cell<u64>,
+    /// Weight last set for the process, and the process it was set for. A
+    /// restarted process gets a new `AppId` and a fresh grant, so it is back
+    /// at the default weight.
+    weight: Cell<Option<(AppId, u32)>>,
+    next: ListLink<'a, ProportionalProcessNode<'a>>,
+}
+
+impl<'a> ProportionalProcessNode<'a> {
+    pub fn new(proc: &'static Option<&'static dyn ProcessType>) -> ProportionalProcessNode<'a> {
+        ProportionalProcessNode {
+            proc,
+            pass: Cell::new(0),
+            weight: Cell::new(None),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// The weight of the process in this node, as cached by `set_weight()`.
+    fn weight(&self, appid: AppId) -> u32 {
+        match self.weight.get() {
+            Some((id, weight)) if id == appid => weight,
+            _ => DEFAULT_WEIGHT,
+        }
+    }
+}
+
+impl<'a> ListNode<'a, ProportionalProcessNode<'a>> for ProportionalProcessNode<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, ProportionalProcessNode> {
+        &self.next
+    }
+}
+
+/// Whether pass `a` is behind pass `b`. Passes wrap around, so they are
+/// compared by their distance rather than their value.
+fn behind(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) < 0
+}
+
+/// The pass of a process after it ran for `executed_us` with `weight`.
+fn advance(pass: u64, weight: u32, executed_us: u32) -> u64 {
+    pass.wrapping_add(executed_us as u64 * STRIDE_SCALE / weight as u64)
+}
+
+/// Returns the entry with the lowest pass among those with a nonzero weight,
+/// the first one on a tie.
+fn select<T>(ready: impl Iterator<Item = (T, u64, u32)>) -> Option<T> {
+    let mut selected: Option<(T, u64)> = None;
+    for (entry, pass, weight) in ready {
+        if weight == 0 {
+            continue;
+        }
+        let lowest = selected
+            .as_ref()
+            .map_or(true, |&(_, selected_pass)| behind(pass, selected_pass));
+        if lowest {
+            selected = Some((entry, pass));
+        }
+    }
+    selected.map(|(entry, _)| entry)
+}
+
+/// Proportional-Share Scheduler
+pub struct ProportionalSched<'a> {
+    pub processes: List<'a, ProportionalProcessNode<'a>>,
+    weights: Grant<ProcessWeight>,
+    /// Pass of the most recently scheduled process.
+    global_pass: Cell<u64>,
+    running: OptionalCell<&'a ProportionalProcessNode<'a>>,
+}
+
+impl<'a> ProportionalSched<'a> {
+    /// How long a process can run before being pre-empted
+    const DEFAULT_TIMESLICE_US: u32 = 10000;
+
+    pub fn new(weights: Grant<ProcessWeight>) -> ProportionalSched<'a> {
+        ProportionalSched {
+            processes: List::new(),
+            weights,
+            global_pass: Cell::new(0),
+            running: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the weight of a process. A weight of 0 stops the process from
+    /// being scheduled until it is given a nonzero weight.
+    pub fn set_weight(&self, appid: AppId, weight: u32) -> ReturnCode {
+        self.weights
+            .enter(appid, |process_weight, _| {
+                process_weight.weight = weight;
+                self.processes
+                    .iter()
+                    .find(|node| node.proc.map_or(false, |proc| proc.appid() == appid))
+                    .map(|node| node.weight.set(Some((appid, weight))));
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// The weight of a process, or the default weight if its grant region
+    /// cannot be allocated.
+    pub fn weight(&self, appid: AppId) -> u32 {
+        self.weights
+            .enter(appid, |process_weight, _| process_weight.weight)
+            .unwrap_or(DEFAULT_WEIGHT)
+    }
+}
+
+impl<'a, C: Chip> Scheduler<C> for ProportionalSched<'a> {
+    fn next(&self, kernel: &Kernel) -> SchedulingDecision {
+        if kernel.processes_blocked() {
+            // No processes ready
+            return SchedulingDecision::TrySleep;
+        }
+        let ready = self.processes.iter().filter_map(|node| {
+            node.proc
+                .filter(|proc| proc.ready())
+                .map(|proc| (node, node.pass.get(), node.weight(proc.appid())))
+        });
+        let node = match select(ready) {
+            Some(node) => node,
+            // Only processes with weight 0 are ready.
+            None => return SchedulingDecision::TrySleep,
+        };
+
+        if behind(node.pass.get(), self.global_pass.get()) {
+            node.pass.set(self.global_pass.get());
+        }
+        self.global_pass.set(node.pass.get());
+        self.running.set(node);
+
+        let next = node.proc.unwrap().appid(); // Selected nodes hold a process.
+        SchedulingDecision::RunProcess((next, Some(Self::DEFAULT_TIMESLICE_US)))
+    }
+
+    fn result(&self, _: StoppedExecutingReason, execution_time_us: Option<u32>) {
+        let execution_time_us = execution_time_us.unwrap(); // should never fail
+        self.running.take().map(|node| {
+            if let Some(proc) = *node.proc {
+                // A process whose weight was set to 0 while it ran is not
+                // charged, it will not run again until it gets a weight.
+                let weight = node.weight(proc.appid());
+                if weight != 0 {
+                    node.pass
+                        .set(advance(node.pass.get(), weight, execution_time_us));
+                }
+            }
+        });
+    }
+
+    fn set_parameter(&self, id: AppId, parameter: SchedulingParameter, value: u32) -> ReturnCode {
+        match parameter {
+            SchedulingParameter::Weight => self.set_weight(id, value),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{advance, behind, select, ProportionalProcessNode, ProportionalSched};
+    use crate::capabilities::MemoryAllocationCapability;
+    use crate::create_capability;
+    use crate::process::{FunctionCall, FunctionCallSource, ProcessType, Task};
+    use crate::returncode::ReturnCode;
+    use crate::sched::{Kernel, Scheduler, SchedulingDecision, StoppedExecutingReason};
+    use crate::testing::{MockChip, MockProcess};
+    use crate::AppId;
+    use std::boxed::Box;
+
+    #[test]
+    fn long_run_split_follows_weights() {
+        let weights = [1, 2, 3, 0];
+        let mut passes = [0u64; 4];
+        let mut runs = [0u32; 4];
+        for _ in 0..600 {
+            let ready = (0..weights.len()).map(|i| (i, passes[i], weights[i]));
+            let next = select(ready).unwrap();
+            passes[next] = advance(passes[next], weights[next], 10000);
+            runs[next] += 1;
+        }
+        assert_eq!(runs, [100, 200, 300, 0]);
+    }
+
+    #[test]
+    fn weight_change_applies_from_then_on() {
+        let mut weights = [1, 1];
+        let mut passes = [0u64; 2];
+        let mut runs = [0u32; 2];
+        for round in 0..400 {
+            if round == 200 {
+                weights[1] = 3;
+                runs = [0; 2];
+            }
+            let ready = (0..weights.len()).map(|i| (i, passes[i], weights[i]));
+            let next = select(ready).unwrap();
+            passes[next] = advance(passes[next], weights[next], 10000);
+            runs[next] += 1;
+        }
+        assert_eq!(runs, [50, 150]);
+    }
+
+    #[test]
+    fn only_weight_zero_ready() {
+        let ready = [(1, 0, 0), (2, 5, 0)];
+        assert_eq!(select(ready.iter().copied()), None::<u32>);
+    }
+
+    #[test]
+    fn passes_compare_across_wraparound() {
+        let near_max = u64::MAX - 10;
+        let wrapped = advance(near_max, 1, 1);
+        assert!(wrapped < near_max);
+        assert!(behind(near_max, wrapped));
+        assert!(!behind(wrapped, near_max));
+        let ready = [(1, wrapped, 1), (2, near_max, 1)];
+        assert_eq!(select(ready.iter().copied()), Some(2));
+    }
+
+    /// The process `next()` chooses, after the previous one ran a timeslice.
+    fn next(sched: &ProportionalSched<'static>, kernel: &Kernel) -> Option<AppId> {
+        let decision = Scheduler::<MockChip>::next(sched, kernel);
+        Scheduler::<MockChip>::result(sched, StoppedExecutingReason::TimesliceExpired, Some(10000));
+        match decision {
+            SchedulingDecision::RunProcess((appid, _)) => Some(appid),
+            SchedulingDecision::TrySleep => None,
+        }
+    }
+
+    #[test]
+    fn weight_set_through_grant_is_used_by_next() {
+        let memory_allocation_cap = create_capability!(MemoryAllocationCapability);
+        let apps: [&'static MockProcess; 2] = [
+            Box::leak(Box::new(MockProcess::new())),
+            Box::leak(Box::new(MockProcess::new())),
+        ];
+        let processes: &'static [Option<&'static dyn ProcessType>] = Box::leak(Box::new([
+            Some(apps[0] as &dyn ProcessType),
+            Some(apps[1] as &dyn ProcessType),
+        ]));
+        let kernel: &'static Kernel = Box::leak(Box::new(Kernel::new(processes)));
+        let sched: &'static ProportionalSched<'static> = Box::leak(Box::new(
+            ProportionalSched::new(kernel.create_grant(&memory_allocation_cap)),
+        ));
+        for (index, app) in apps.iter().enumerate() {
+            app.attach(kernel, index);
+            app.enqueue_task(Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Kernel,
+                argument0: 0,
+                argument1: 0,
+                argument2: 0,
+                argument3: 0,
+                pc: 0x1000,
+            }));
+            let node = Box::leak(Box::new(ProportionalProcessNode::new(&processes[index])));
+            sched.processes.push_tail(node);
+        }
+        let (first, second) = (apps[0].appid(), apps[1].appid());
+
+        assert_eq!(sched.set_weight(first, 0), ReturnCode::SUCCESS);
+        assert_eq!(sched.weight(first), 0);
+        for _ in 0..3 {
+            assert_eq!(next(sched, kernel), Some(second));
+        }
+
+        // Back from weight 0, the first process starts from the pass of the
+        // last process scheduled rather than making up for the three turns it
+        // missed. It is one turn behind the second, and wins ties as it comes
+        // first in the list.
+        assert_eq!(sched.set_weight(first, 100), ReturnCode::SUCCESS);
+        let turns = [
+            next(sched, kernel),
+            next(sched, kernel),
+            next(sched, kernel),
+            next(sched, kernel),
+        ];
+        assert_eq!(turns, [Some(first), Some(first), Some(second), Some(first)]);
+    }
+}
@@ -13,11 +13,26 @@
 //! userspace processes are interrupted the scheduler timer is paused, and the
 //! same process is resumed with the same scheduler timer value from when it was
 //! interrupted.
+//!
+//! Optionally, with `RoundRobinSched::new_resume_preempted()`, a process that
+//! was interrupted is instead resumed with a fresh timeslice, so that the
+//! process that was doing work when an interrupt arrived gets to finish it
+//! before others run. To keep a process that is interrupted often from
+//! starving the others, it is only resumed this way a limited number of times
+//! in a row before the scheduler moves on to the next process.
+//!
+//! Each process gets timeslices of `DEFAULT_TIMESLICE_US` unless its
+//! timeslice is changed with `SchedulingParameter::Timeslice`.
 
+use crate::callback::AppId;
 use crate::common::list::{List, ListLink, ListNode};
 use crate::platform::Chip;
 use crate::procs::ProcessType;
-use crate::sched::{Kernel, Scheduler, SchedulingDecision, StoppedExecutingReason};
+use crate::returncode::ReturnCode;
+use crate::sched::{
+    Kernel, Scheduler, SchedulingDecision, SchedulingParameter, StoppedExecutingReason,
+    MIN_QUANTA_THRESHOLD_US,
+};
 use core::cell::Cell;
 
 /// A node in the linked list the scheduler uses to track processes
@@ -25,6 +40,8 @@ use core::cell::Cell;
 pub struct RoundRobinProcessNode<'a> {
     proc: &'static Option<&'static dyn ProcessType>,
     next: ListLink<'a, RoundRobinProcessNode<'a>>,
+    /// Length of a fresh timeslice for the process in this slot.
+    timeslice_us: Cell<u32>,
 }
 
 impl<'a> RoundRobinProcessNode<'a> {
@@ -32,6 +49,7 @@ impl<'a> RoundRobinProcessNode<'a> {
         RoundRobinProcessNode {
             proc,
             next: ListLink::empty(),
+            timeslice_us: Cell::new(RoundRobinSched::DEFAULT_TIMESLICE_US),
         }
     }
 }
@@ -47,18 +65,41 @@ pub struct RoundRobinSched<'a> {
     time_remaining: Cell<u32>,
     pub processes: List<'a, RoundRobinProcessNode<'a>>,
     last_rescheduled: Cell<bool>,
+    /// If set, resume interrupted processes with a fresh timeslice, at most
+    /// this many times in a row.
+    max_preempted_resumes: Option<usize>,
+    preempted_resumes: Cell<usize>,
 }
 
 impl<'a> RoundRobinSched<'a> {
     /// How long a process can run before being pre-empted
     const DEFAULT_TIMESLICE_US: u32 = 10000;
     pub const fn new() -> RoundRobinSched<'a> {
+        Self::create(None)
+    }
+
+    /// Create a scheduler that resumes a process interrupted by the kernel
+    /// with a fresh timeslice, up to `max_resumes` times in a row.
+    pub const fn new_resume_preempted(max_resumes: usize) -> RoundRobinSched<'a> {
+        Self::create(Some(max_resumes))
+    }
+
+    const fn create(max_preempted_resumes: Option<usize>) -> RoundRobinSched<'a> {
         RoundRobinSched {
             time_remaining: Cell::new(Self::DEFAULT_TIMESLICE_US),
             processes: List::new(),
             last_rescheduled: Cell::new(false),
+            max_preempted_resumes: max_preempted_resumes,
+            preempted_resumes: Cell::new(0),
         }
     }
+
+    /// Length of a fresh timeslice for the process at the head of the queue.
+    fn head_timeslice_us(&self) -> u32 {
+        self.processes
+            .head()
+            .map_or(Self::DEFAULT_TIMESLICE_US, |node| node.timeslice_us.get())
+    }
 }
 
 impl<'a, C: Chip> Scheduler<C> for RoundRobinSched<'a> {
@@ -90,8 +131,9 @@ impl<'a, C: Chip> Scheduler<C> for RoundRobinSched<'a> {
                 self.time_remaining.get()
             } else {
                 // grant a fresh timeslice
-                self.time_remaining.set(Self::DEFAULT_TIMESLICE_US);
-                Self::DEFAULT_TIMESLICE_US
+                let timeslice = self.head_timeslice_us();
+                self.time_remaining.set(timeslice);
+                timeslice
             };
             assert!(timeslice != 0);
 
@@ -101,8 +143,17 @@ impl<'a, C: Chip> Scheduler<C> for RoundRobinSched<'a> {
 
     fn result(&self, result: StoppedExecutingReason, execution_time_us: Option<u32>) {
         let execution_time_us = execution_time_us.unwrap(); // should never fail
-        let reschedule = match result {
-            StoppedExecutingReason::KernelPreemption => {
+        let reschedule = match (result, self.max_preempted_resumes) {
+            (StoppedExecutingReason::KernelPreemption, Some(max_resumes)) => {
+                if self.preempted_resumes.get() < max_resumes {
+                    self.preempted_resumes.set(self.preempted_resumes.get() + 1);
+                    self.time_remaining.set(self.head_timeslice_us());
+                    true
+                } else {
+                    false
+                }
+            }
+            (StoppedExecutingReason::KernelPreemption, None) => {
                 if self.time_remaining.get() > execution_time_us {
                     self.time_remaining
                         .set(self.time_remaining.get() - execution_time_us);
@@ -115,7 +166,27 @@ impl<'a, C: Chip> Scheduler<C> for RoundRobinSched<'a> {
         };
         self.last_rescheduled.set(reschedule);
         if !reschedule {
+            self.preempted_resumes.set(0);
             self.processes.push_tail(self.processes.pop_head().unwrap());
         }
     }
+
+    fn set_parameter(&self, id: AppId, parameter: SchedulingParameter, value: u32) -> ReturnCode {
+        match parameter {
+            SchedulingParameter::Timeslice => {
+                // Shorter timeslices would expire before the process runs.
+                if value <= MIN_QUANTA_THRESHOLD_US {
+                    return ReturnCode::EINVAL;
+                }
+                self.processes
+                    .iter()
+                    .find(|node| node.proc.map_or(false, |proc| proc.appid() == id))
+                    .map_or(ReturnCode::EINVAL, |node| {
+                        node.timeslice_us.set(value);
+                        ReturnCode::SUCCESS
+                    })
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
 }
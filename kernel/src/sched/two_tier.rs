@@ -0,0 +1,231 @@
+//! Two-Tier Scheduler for Tock
+//!
+//! This scheduler splits processes into a foreground and a background tier.
+//! Background processes only run when no foreground process is ready, which
+//! covers the common case of work that should only happen while the system is
+//! otherwise idle, without the complexity of full priority levels. Within a
+//! tier processes are scheduled round robin with the same timeslice as
+//! `RoundRobinSched`.
+//!
+//! Processes are placed in the background tier by name with
+//! `set_background_processes()`; all other processes are in the foreground.
+//! With no background processes this behaves like `RoundRobinSched`.
+//!
+//! A running background process is preempted as soon as a foreground process
+//! becomes ready, for example because an interrupt queued a callback for it.
+
+use crate::common::dynamic_deferred_call::DynamicDeferredCall;
+use crate::common::list::{List, ListLink, ListNode};
+use crate::platform::Chip;
+use crate::process::ProcessType;
+use crate::sched::{Kernel, Scheduler, SchedulingDecision, StoppedExecutingReason};
+use crate::AppId;
+use core::cell::Cell;
+
+/// A node in the linked list the scheduler uses to track processes
+pub struct TwoTierProcessNode<'a> {
+    proc: &'static Option<&'static dyn ProcessType>,
+    next: ListLink<'a, TwoTierProcessNode<'a>>,
+}
+
+impl<'a> TwoTierProcessNode<'a> {
+    pub fn new(proc: &'static Option<&'static dyn ProcessType>) -> TwoTierProcessNode<'a> {
+        TwoTierProcessNode {
+            proc,
+            next: ListLink::empty(),
+        }
+    }
+}
+
+impl<'a> ListNode<'a, TwoTierProcessNode<'a>> for TwoTierProcessNode<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, TwoTierProcessNode> {
+        &self.next
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Tier {
+    Foreground,
+    Background,
+}
+
+/// Returns the first foreground entry, or if there is none the first
+/// background entry.
+fn select<T>(ready: impl Iterator<Item = (T, Tier)>) -> Option<T> {
+    let mut background = None;
+    for (entry, tier) in ready {
+        match tier {
+            Tier::Foreground => return Some(entry),
+            Tier::Background => {
+                if background.is_none() {
+                    background = Some(entry);
+                }
+            }
+        }
+    }
+    background
+}
+
+/// Two-Tier Scheduler
+pub struct TwoTierSched<'a> {
+    time_remaining: Cell<u32>,
+    pub processes: List<'a, TwoTierProcessNode<'a>>,
+    last_rescheduled: Cell<bool>,
+    background: Cell<&'static [&'static str]>,
+}
+
+impl<'a> TwoTierSched<'a> {
+    /// How long a process can run before being pre-empted
+    const DEFAULT_TIMESLICE_US: u32 = 10000;
+    pub const fn new() -> TwoTierSched<'a> {
+        TwoTierSched {
+            time_remaining: Cell::new(Self::DEFAULT_TIMESLICE_US),
+            processes: List::new(),
+            last_rescheduled: Cell::new(false),
+            background: Cell::new(&[]),
+        }
+    }
+
+    /// Place the processes with these names in the background tier.
+    pub fn set_background_processes(&self, names: &'static [&'static str]) {
+        self.background.set(names);
+    }
+
+    fn tier(&self, proc: &dyn ProcessType) -> Tier {
+        let name = proc.get_process_name();
+        if self.background.get().iter().any(|&bg| bg == name) {
+            Tier::Background
+        } else {
+            Tier::Foreground
+        }
+    }
+
+    /// The process to run next: the first ready foreground process in queue
+    /// order, or if none is ready the first ready background process.
+    fn next_ready(&self) -> Option<AppId> {
+        select(self.processes.iter().filter_map(|node| {
+            node.proc
+                .filter(|proc| proc.ready())
+                .map(|proc| (proc.appid(), self.tier(proc)))
+        }))
+    }
+
+    fn foreground_ready(&self) -> bool {
+        self.processes
+            .iter()
+            .filter_map(|node| *node.proc)
+            .any(|proc| proc.ready() && self.tier(proc) == Tier::Foreground)
+    }
+
+    fn head_is(&self, id: AppId) -> bool {
+        self.processes
+            .head()
+            .and_then(|node| *node.proc)
+            .map_or(false, |proc| proc.appid() == id)
+    }
+}
+
+impl<'a, C: Chip> Scheduler<C> for TwoTierSched<'a> {
+    fn next(&self, kernel: &Kernel) -> SchedulingDecision {
+        if kernel.processes_blocked() {
+            // No processes ready
+            return SchedulingDecision::TrySleep;
+        }
+        let next = match self.next_ready() {
+            Some(next) => next,
+            None => return SchedulingDecision::TrySleep,
+        };
+
+        // Only resume the interrupted timeslice if the same process is picked
+        // again; a foreground process may have become ready in the meantime.
+        let resume = self.last_rescheduled.get() && self.head_is(next);
+
+        // Move every process in front of the selected one to the back of the
+        // queue, as `RoundRobinSched` does.
+        while !self.head_is(next) {
+            self.processes.push_tail(self.processes.pop_head().unwrap());
+        }
+
+        let timeslice = if resume {
+            self.time_remaining.get()
+        } else {
+            // grant a fresh timeslice
+            self.time_remaining.set(Self::DEFAULT_TIMESLICE_US);
+            Self::DEFAULT_TIMESLICE_US
+        };
+        assert!(timeslice != 0);
+
+        SchedulingDecision::RunProcess((next, Some(timeslice)))
+    }
+
+    fn result(&self, result: StoppedExecutingReason, execution_time_us: Option<u32>) {
+        let execution_time_us = execution_time_us.unwrap(); // should never fail
+        let reschedule = match result {
+            StoppedExecutingReason::KernelPreemption => {
+                if self.time_remaining.get() > execution_time_us {
+                    self.time_remaining
+                        .set(self.time_remaining.get() - execution_time_us);
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+        self.last_rescheduled.set(reschedule);
+        if !reschedule {
+            self.processes.push_tail(self.processes.pop_head().unwrap());
+        }
+    }
+
+    unsafe fn continue_process(&self, id: AppId, chip: &C) -> bool {
+        if chip.has_pending_interrupts()
+            || DynamicDeferredCall::global_instance_calls_pending().unwrap_or(false)
+        {
+            return false;
+        }
+        // Stop a background process as soon as a foreground process is ready.
+        let background_running = self
+            .processes
+            .head()
+            .and_then(|node| *node.proc)
+            .map_or(false, |proc| {
+                proc.appid() == id && self.tier(proc) == Tier::Background
+            });
+        !(background_running && self.foreground_ready())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{select, Tier};
+
+    #[test]
+    fn foreground_only_is_round_robin_order() {
+        let ready = [(1, Tier::Foreground), (2, Tier::Foreground)];
+        assert_eq!(select(ready.iter().copied()), Some(1));
+    }
+
+    #[test]
+    fn foreground_preferred_over_earlier_background() {
+        let ready = [
+            (1, Tier::Background),
+            (2, Tier::Background),
+            (3, Tier::Foreground),
+            (4, Tier::Foreground),
+        ];
+        assert_eq!(select(ready.iter().copied()), Some(3));
+    }
+
+    #[test]
+    fn background_runs_when_no_foreground_ready() {
+        let ready = [(1, Tier::Background), (2, Tier::Background)];
+        assert_eq!(select(ready.iter().copied()), Some(1));
+    }
+
+    #[test]
+    fn nothing_ready() {
+        let ready: [(u32, Tier); 0] = [];
+        assert_eq!(select(ready.iter().copied()), None);
+    }
+}
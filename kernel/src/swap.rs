@@ -0,0 +1,395 @@
+//! Swap processes in and out of RAM, to run more apps than fit in RAM at once.
+//!
+//! A board keeps a set of swappable apps in a region of flash, separate from
+//! the apps it loads at boot with `load_processes()`, and sets aside a few
+//! swap slots. Each slot is an entry of the processes array together with a
+//! region of RAM large enough for any of the swappable apps. At most one app
+//! is resident in each slot. `SwapManager::swap_in()` starts a swappable app
+//! in a free slot, and `SwapManager::swap_out()` stops a resident app and
+//! frees its slot for another app.
+//!
+//! Swapping out discards all RAM state of the app: its stack, heap and grant
+//! regions are freed and the slot's RAM is zeroed. Nothing is written back to
+//! flash. The next time the app is swapped in it starts again from its image
+//! in flash with a new `AppId`, just like after a reload (see
+//! `Kernel::reload_process()`). Apps that are swapped should therefore keep
+//! any state they need across swaps in nonvolatile storage.
+//!
+//! Swappable apps are numbered in the order they appear in their flash region,
+//! counting only TBF entries that are apps, so padding does not take a number.
+//! Like `load_processes_checking_drivers()`, swapping in an app that requires
+//! a driver the board does not provide fails.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::static_init;
+//!
+//! // PROCESSES[0..4] hold the apps loaded at boot, PROCESSES[4..6] are swap
+//! // slots.
+//! static mut SWAP_MEMORY: [[u8; 16384]; 2] = [[0; 16384]; 2];
+//! let (boot_processes, swap_processes) = PROCESSES.split_at_mut(4);
+//! kernel::procs::load_processes_checking_drivers(
+//!     board_kernel,
+//!     chip,
+//!     platform,
+//!     app_flash,
+//!     &mut APP_MEMORY,
+//!     boot_processes,
+//!     FAULT_RESPONSE,
+//!     &process_management_capability,
+//! );
+//! let (entry0, entry1) = swap_processes.split_at_mut(1);
+//! let [memory0, memory1] = &mut SWAP_MEMORY;
+//! let slots = static_init!(
+//!     [kernel::swap::SwapSlot; 2],
+//!     [
+//!         kernel::swap::SwapSlot::new(&mut entry0[0], memory0),
+//!         kernel::swap::SwapSlot::new(&mut entry1[0], memory1),
+//!     ]
+//! );
+//! let swap = static_init!(
+//!     kernel::swap::SwapManager<Apollo3, Platform>,
+//!     kernel::swap::SwapManager::new(
+//!         board_kernel,
+//!         chip,
+//!         platform,
+//!         swap_app_flash,
+//!         slots,
+//!         FAULT_RESPONSE,
+//!     )
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::slice;
+
+use crate::callback::AppId;
+use crate::capabilities::ProcessManagementCapability;
+use crate::common::cells::TakeCell;
+use crate::config;
+use crate::debug;
+use crate::platform::{Chip, Platform};
+use crate::process::{FaultResponse, Process, ProcessLoadError, ProcessType};
+use crate::returncode::ReturnCode;
+use crate::sched::Kernel;
+
+/// An entry of the processes array and the RAM a swapped in app runs in.
+pub struct SwapSlot {
+    /// The slot's entry of the processes array. Only the slot writes it.
+    entry: TakeCell<'static, Option<&'static dyn ProcessType>>,
+    /// Index of `entry` in the processes array, set by `SwapManager::new()`.
+    index: Cell<usize>,
+    memory: *mut u8,
+    memory_len: usize,
+    /// Number of the swappable app resident in the slot.
+    resident: Cell<Option<usize>>,
+}
+
+impl SwapSlot {
+    /// Create a slot that runs apps in `memory`, in `entry` of the processes
+    /// array. That entry must be empty and must not be given to any other
+    /// process loader.
+    pub fn new(
+        entry: &'static mut Option<&'static dyn ProcessType>,
+        memory: &'static mut [u8],
+    ) -> SwapSlot {
+        SwapSlot {
+            entry: TakeCell::new(entry),
+            index: Cell::new(0),
+            memory: memory.as_mut_ptr(),
+            memory_len: memory.len(),
+            resident: Cell::new(None),
+        }
+    }
+}
+
+/// Tracks which swappable apps are resident, and swaps them in and out.
+pub struct SwapManager<C: 'static + Chip, P: 'static + Platform> {
+    kernel: &'static Kernel,
+    chip: &'static C,
+    platform: &'static P,
+    app_flash: &'static [u8],
+    slots: &'static [SwapSlot],
+    fault_response: FaultResponse,
+}
+
+impl<C: 'static + Chip, P: 'static + Platform> SwapManager<C, P> {
+    /// Create a manager for the swappable apps in `app_flash`, which run in
+    /// `slots`. Apps are checked against the drivers `platform` provides.
+    ///
+    /// Panics if the entry of a slot is not in the processes array given to
+    /// `Kernel::new()`.
+    pub fn new(
+        kernel: &'static Kernel,
+        chip: &'static C,
+        platform: &'static P,
+        app_flash: &'static [u8],
+        slots: &'static [SwapSlot],
+        fault_response: FaultResponse,
+    ) -> SwapManager<C, P> {
+        for slot in slots.iter() {
+            let index = slot
+                .entry
+                .map_or(None, |entry| kernel.process_entry_index(entry))
+                .expect("Swap slot is not an entry of the processes array");
+            slot.index.set(index);
+        }
+        SwapManager {
+            kernel,
+            chip,
+            platform,
+            app_flash,
+            slots,
+            fault_response,
+        }
+    }
+
+    /// Number of swappable apps.
+    pub fn app_count(&self) -> usize {
+        let mut count = 0;
+        while find_app(self.app_flash, count).is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Package name of swappable app `app`, if it has one.
+    pub fn app_name(&self, app: usize) -> Option<&'static str> {
+        find_app(self.app_flash, app).and_then(|(entry_flash, header_length, version)| {
+            tock_tbf::parse::parse_tbf_header(&entry_flash[..header_length], version)
+                .ok()
+                .and_then(|header| header.get_package_name())
+        })
+    }
+
+    /// The `AppId` of swappable app `app` if it is resident, or `None` if it
+    /// is swapped out.
+    pub fn resident_appid(&self, app: usize) -> Option<AppId> {
+        self.slots
+            .iter()
+            .find(|slot| slot.resident.get() == Some(app))
+            .and_then(|slot| self.slot_process(slot))
+            .map(|process| process.appid())
+    }
+
+    /// Start swappable app `app` in a free slot. Its RAM state starts from
+    /// scratch, see the module documentation.
+    ///
+    /// On success, returns the `AppId` of the started process. Returns
+    /// `EINVAL` if there is no app `app`, `EALREADY` if it is already
+    /// resident, `ENOMEM` if all slots are in use or the app does not fit in
+    /// a slot, and `FAIL` if the app is disabled, requires a driver the board
+    /// does not provide, or could not be loaded for another reason.
+    pub fn swap_in(
+        &self,
+        app: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Result<AppId, ReturnCode> {
+        let (entry_flash, header_length, version) =
+            find_app(self.app_flash, app).ok_or(ReturnCode::EINVAL)?;
+        if self.resident_appid(app).is_some() {
+            return Err(ReturnCode::EALREADY);
+        }
+        let slot = self
+            .slots
+            .iter()
+            .find(|slot| slot.resident.get().is_none())
+            .ok_or(ReturnCode::ENOMEM)?;
+
+        // The slot is free, so no process refers to its memory.
+        let memory = unsafe { slice::from_raw_parts_mut(slot.memory, slot.memory_len) };
+        let created = unsafe {
+            Process::create(
+                self.kernel,
+                self.chip,
+                entry_flash,
+                header_length,
+                version,
+                memory,
+                self.fault_response,
+                slot.index.get(),
+                &|driver_num| {
+                    self.platform
+                        .with_driver(driver_num, |driver| driver.is_some())
+                },
+            )
+        };
+        match created {
+            Ok((Some(process), _unused_memory)) => {
+                slot.entry.map(|entry| *entry = Some(process));
+                slot.resident.set(Some(app));
+                Ok(process.appid())
+            }
+            Ok((None, _)) => Err(ReturnCode::FAIL),
+            Err(ProcessLoadError::NotEnoughMemory) => Err(ReturnCode::ENOMEM),
+            Err(err) => {
+                if config::CONFIG.debug_load_processes {
+                    debug!("Could not swap in app {}: {:?}", app, err);
+                }
+                Err(ReturnCode::FAIL)
+            }
+        }
+    }
+
+    /// Stop the resident process `appid` and free its slot. All of its RAM
+    /// state is lost, see the module documentation.
+    ///
+    /// Returns `EINVAL` if `appid` is not a resident swappable app, and
+    /// `EBUSY` if `appid` is the process whose system call is being handled,
+    /// as the kernel still uses its memory until the call returns.
+    pub fn swap_out(
+        &self,
+        appid: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> ReturnCode {
+        let slot = match self.slots.iter().find(|slot| {
+            self.slot_process(slot)
+                .map_or(false, |process| process.appid() == appid)
+        }) {
+            Some(slot) => slot,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.kernel.is_syscall_caller(appid) {
+            return ReturnCode::EBUSY;
+        }
+
+        self.slot_process(slot)
+            .map(|process| process.stop_and_free());
+        slot.entry.map(|entry| *entry = None);
+        slot.resident.set(None);
+
+        // The `Process` struct itself lives in this memory, so it is only
+        // cleared once no longer reachable from the processes array.
+        unsafe {
+            core::ptr::write_bytes(slot.memory, 0, slot.memory_len);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn slot_process(&self, slot: &SwapSlot) -> Option<&'static dyn ProcessType> {
+        if slot.resident.get().is_none() {
+            return None;
+        }
+        slot.entry.map_or(None, |entry| *entry)
+    }
+}
+
+/// Flash entry, header length and TBF version of swappable app `app`.
+fn find_app(app_flash: &'static [u8], app: usize) -> Option<(&'static [u8], usize, u16)> {
+    let mut remaining_flash = app_flash;
+    let mut count = 0;
+    loop {
+        let test_header_slice = remaining_flash.get(0..8)?;
+        let (version, header_length, entry_length) =
+            match tock_tbf::parse::parse_tbf_header_lengths(test_header_slice.try_into().ok()?) {
+                Ok((v, hl, el)) => (v, hl as usize, el as usize),
+                Err(tock_tbf::types::InitialTbfParseError::InvalidHeader(entry_length)) => {
+                    (0, 0, entry_length as usize)
+                }
+                Err(tock_tbf::types::InitialTbfParseError::UnableToParse) => return None,
+            };
+        if entry_length == 0 {
+            return None;
+        }
+        let entry_flash = remaining_flash.get(0..entry_length)?;
+        remaining_flash = remaining_flash.get(entry_length..)?;
+
+        let is_app = header_length > 0
+            && entry_flash
+                .get(0..header_length)
+                .and_then(|header| tock_tbf::parse::parse_tbf_header(header, version).ok())
+                .map_or(false, |header| header.is_app());
+        if is_app {
+            if count == app {
+                return Some((entry_flash, header_length, version));
+            }
+            count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_app;
+
+    /// Write a TBF entry of `total_size` bytes at the start of `flash`, an app
+    /// named `name` or padding if `name` is `None`. Returns `total_size`.
+    fn write_entry(flash: &mut [u8], name: Option<&str>, total_size: usize) -> usize {
+        let mut header = [0u8; 64];
+        let mut header_size = 16;
+        if let Some(name) = name {
+            // Main TLV.
+            header[16..20].copy_from_slice(&[1, 0, 12, 0]);
+            header_size += 16;
+            // Package name TLV, padded to a whole word.
+            header[32..34].copy_from_slice(&3u16.to_le_bytes());
+            header[34..36].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            header[36..36 + name.len()].copy_from_slice(name.as_bytes());
+            header_size += 4 + (name.len() + 3) / 4 * 4;
+        }
+        let flags: u32 = if name.is_some() { 1 } else { 0 };
+        header[0..2].copy_from_slice(&2u16.to_le_bytes());
+        header[2..4].copy_from_slice(&(header_size as u16).to_le_bytes());
+        header[4..8].copy_from_slice(&(total_size as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&flags.to_le_bytes());
+        let checksum = header[..header_size]
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|&(i, _)| i != 3)
+            .fold(0, |checksum, (_, word)| {
+                checksum ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+            });
+        header[12..16].copy_from_slice(&checksum.to_le_bytes());
+
+        flash[..header_size].copy_from_slice(&header[..header_size]);
+        total_size
+    }
+
+    fn name(app_flash: &'static [u8], app: usize) -> Option<&'static str> {
+        find_app(app_flash, app).and_then(|(entry_flash, header_length, version)| {
+            tock_tbf::parse::parse_tbf_header(&entry_flash[..header_length], version)
+                .ok()
+                .and_then(|header| header.get_package_name())
+        })
+    }
+
+    #[test]
+    fn padding_does_not_take_a_number() {
+        static mut FLASH: [u8; 512] = [0xff; 512];
+        let flash = unsafe { &mut FLASH };
+        let mut offset = write_entry(flash, Some("first"), 128);
+        offset += write_entry(&mut flash[offset..], None, 64);
+        write_entry(&mut flash[offset..], Some("second"), 128);
+        let flash: &'static [u8] = flash;
+
+        assert_eq!(
+            find_app(flash, 0).map(|(entry, _, _)| entry.len()),
+            Some(128)
+        );
+        assert_eq!(name(flash, 0), Some("first"));
+        assert_eq!(name(flash, 1), Some("second"));
+        assert!(find_app(flash, 2).is_none());
+    }
+
+    #[test]
+    fn erased_flash_has_no_apps() {
+        static FLASH: [u8; 64] = [0xff; 64];
+        assert!(find_app(&FLASH, 0).is_none());
+    }
+
+    #[test]
+    fn empty_entry_ends_the_apps() {
+        static mut FLASH: [u8; 256] = [0xff; 256];
+        let flash = unsafe { &mut FLASH };
+        let offset = write_entry(flash, Some("first"), 128);
+        // A header too short to be valid, with a total size of zero.
+        flash[offset..offset + 8].copy_from_slice(&[2, 0, 8, 0, 0, 0, 0, 0]);
+        let flash: &'static [u8] = flash;
+
+        assert_eq!(name(flash, 0), Some("first"));
+        assert!(find_app(flash, 1).is_none());
+    }
+}
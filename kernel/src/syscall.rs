@@ -47,6 +47,14 @@ pub enum Syscall {
     ///
     /// SVC_NUM = 4
     MEMOP { operand: usize, arg0: usize },
+
+    /// Like `YIELD`, but the process is not run again for at least `min_us`
+    /// microseconds, even if callbacks arrive in the meantime. The kernel
+    /// treats this as a plain `YIELD` if it cannot time the wait, see
+    /// `Kernel::set_yield_for_timer()`.
+    ///
+    /// SVC_NUM = 5
+    YIELD_FOR { min_us: usize },
 }
 
 impl Syscall {
@@ -93,6 +101,7 @@ impl Syscall {
                 operand: r0,
                 arg0: r1,
             }),
+            5 => Some(Syscall::YIELD_FOR { min_us: r0 }),
             _ => None,
         }
     }
@@ -249,4 +258,33 @@ pub trait UserspaceKernelBoundary {
         state: &Self::StoredState,
         writer: &mut dyn Write,
     );
+
+    /// Return the address of the instruction a process was executing when it
+    /// last trapped into the kernel, for example because it faulted. Returns
+    /// `None` if the stored state does not point into process memory, or the
+    /// architecture does not support this, which is the default.
+    unsafe fn get_fault_pc(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &Self::StoredState,
+    ) -> Option<usize> {
+        None
+    }
+
+    /// Serialize a process's stored state into `out`, so that the process can
+    /// be resumed after the chip reboots (see `kernel::hibernate`). Returns
+    /// the number of bytes written, or `Err(())` if `out` is too small or the
+    /// architecture does not support this, which is the default.
+    fn store_context(&self, _state: &Self::StoredState, _out: &mut [u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    /// Restore a process's stored state from bytes written by
+    /// `store_context()`. Returns `Err(())` if `data` is not valid stored
+    /// state, or the architecture does not support this, which is the
+    /// default.
+    fn restore_context(&self, _state: &mut Self::StoredState, _data: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
 }
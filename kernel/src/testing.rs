@@ -0,0 +1,932 @@
+//! Stand-ins for a process and an alarm, for exercising the kernel and
+//! capsules in host unit tests.
+//!
+//! A `MockProcess` is a `ProcessType` without an architecture or an MPU
+//! behind it. It owns a small block of memory, the lower part of which the
+//! process "owns" and can share with `allow`, and the upper part of which holds
+//! its grant regions. Callbacks scheduled for it are queued as for a real
+//! process, and tests take them off the queue to check what a capsule
+//! delivered. Switching to it runs a function the test sets, which returns the
+//! reason the process stopped executing, typically a syscall. Capsules are
+//! tested by having the mock make subscribe, allow and command calls to them
+//! the way the kernel would.
+//!
+//! A `MockAlarm` is a 1 kHz alarm whose time only moves when the test sets
+//! it, and which fires when the test says so.
+//!
+//! A `MockChip` lets the kernel run mock processes the way its main loop does.
+//! Its scheduler timer counts a fixed time for every switch to a process, so
+//! a test decides how long processes appear to run.
+//!
+//! Tests place the mock process in the processes array given to
+//! `Kernel::new()`, and then attach it to that kernel:
+//!
+//! ```ignore
+//! let process: &'static MockProcess = Box::leak(Box::new(MockProcess::new()));
+//! let processes = Box::leak(Box::new([Some(process as &dyn ProcessType)]));
+//! let kernel = Box::leak(Box::new(Kernel::new(processes)));
+//! process.attach(kernel, 0);
+//! ```
+//!
+//! This module is only built for the kernel's own tests and with the kernel's
+//! `testing` feature, which crates enable for their tests as a
+//! dev-dependency. It is never part of a board's kernel.
+
+use core::cell::{Cell, UnsafeCell};
+use core::fmt::Write;
+use core::ptr::NonNull;
+
+use crate::callback::{AppId, Callback, CallbackId};
+use crate::common::cells::OptionalCell;
+use crate::driver::Driver;
+use crate::hibernate;
+use crate::hil::time::{self, Alarm, AlarmClient, Freq1KHz, Ticks, Ticks32};
+use crate::mem::{AppSlice, Shared};
+use crate::platform::scheduler_timer::SchedulerTimer;
+use crate::platform::{mpu, Chip};
+use crate::process::{Error, FunctionCall, FunctionCallSource, ProcessType, State, Task};
+use crate::returncode::ReturnCode;
+use crate::sched::Kernel;
+use crate::syscall::{ContextSwitchReason, Syscall, UserspaceKernelBoundary};
+
+/// Size of the memory of a `MockProcess`, in bytes.
+pub const MEMORY_LEN: usize = 2048;
+
+/// Size of the part of that memory the process owns, in bytes. The rest is
+/// grant memory.
+pub const APP_MEMORY_LEN: usize = 1024;
+
+const MAX_TASKS: usize = 10;
+const MAX_SUBSCRIPTIONS: usize = 8;
+const MAX_GRANTS: usize = 16;
+
+/// Where the callback functions `subscribe()` passes start. Nothing is ever
+/// called there.
+const CALLBACK_FUNCTION: usize = 0x1000;
+
+/// What a `MockProcess` does each time the kernel switches to it. The
+/// argument is the number of earlier switches.
+pub type Switch = fn(&MockProcess, usize) -> Option<ContextSwitchReason>;
+
+fn yield_on_switch(_process: &MockProcess, _switches: usize) -> Option<ContextSwitchReason> {
+    Some(ContextSwitchReason::SyscallFired {
+        syscall: Syscall::YIELD,
+    })
+}
+
+pub struct MockProcess {
+    kernel: Cell<Option<&'static Kernel>>,
+    app_id: Cell<Option<AppId>>,
+    state: Cell<State>,
+    held: Cell<Option<(u32, u32)>>,
+
+    tasks: [Cell<Option<Task>>; MAX_TASKS],
+    subscriptions: [Cell<Option<(CallbackId, usize, usize)>>; MAX_SUBSCRIPTIONS],
+
+    /// Word aligned, so that grants of any type can be placed in it.
+    memory: UnsafeCell<[u64; MEMORY_LEN / 8]>,
+    /// Offset of the lowest grant region allocated in `memory`.
+    kernel_memory_break: Cell<usize>,
+    grant_ptrs: [Cell<*mut u8>; MAX_GRANTS],
+
+    on_switch: Cell<Switch>,
+    switches: Cell<usize>,
+    return_value: Cell<Option<isize>>,
+    process_function: Cell<Option<FunctionCall>>,
+
+    syscall_count: Cell<usize>,
+    last_syscall: Cell<Option<Syscall>>,
+    dropped_callback_count: Cell<usize>,
+    timeslice_expiration_count: Cell<usize>,
+    grant_alloc_failure_count: Cell<usize>,
+    waiting_since: Cell<Option<u32>>,
+    cpu_time_us: Cell<(u64, u64)>,
+}
+
+impl MockProcess {
+    /// Create a process that is running and that yields whenever it is
+    /// switched to.
+    pub fn new() -> MockProcess {
+        MockProcess {
+            kernel: Cell::new(None),
+            app_id: Cell::new(None),
+            state: Cell::new(State::Running),
+            held: Cell::new(None),
+            tasks: Default::default(),
+            subscriptions: Default::default(),
+            memory: UnsafeCell::new([0; MEMORY_LEN / 8]),
+            kernel_memory_break: Cell::new(MEMORY_LEN),
+            grant_ptrs: [
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+                Cell::new(0 as *mut u8),
+            ],
+            on_switch: Cell::new(yield_on_switch),
+            switches: Cell::new(0),
+            return_value: Cell::new(None),
+            process_function: Cell::new(None),
+            syscall_count: Cell::new(0),
+            last_syscall: Cell::new(None),
+            dropped_callback_count: Cell::new(0),
+            timeslice_expiration_count: Cell::new(0),
+            grant_alloc_failure_count: Cell::new(0),
+            waiting_since: Cell::new(None),
+            cpu_time_us: Cell::new((0, 0)),
+        }
+    }
+
+    /// Attach the process to the kernel it was placed at `index` in the
+    /// processes array of. Its identifier is its index.
+    pub fn attach(&self, kernel: &'static Kernel, index: usize) {
+        self.kernel.set(Some(kernel));
+        self.app_id.set(Some(AppId::new(kernel, index, index)));
+    }
+
+    fn kernel(&self) -> &'static Kernel {
+        self.kernel
+            .get()
+            .expect("MockProcess is not attached to a kernel")
+    }
+
+    /// Set what the process does when the kernel switches to it.
+    pub fn on_switch(&self, switch: Switch) {
+        self.on_switch.set(switch);
+    }
+
+    /// Number of times the kernel has switched to the process.
+    pub fn switches(&self) -> usize {
+        self.switches.get()
+    }
+
+    /// The return value of the last syscall the process made.
+    pub fn return_value(&self) -> Option<isize> {
+        self.return_value.get()
+    }
+
+    /// The callback or entry point the kernel last set up for the process to
+    /// run.
+    pub fn process_function(&self) -> Option<FunctionCall> {
+        self.process_function.get()
+    }
+
+    /// The last syscall the process made.
+    pub fn last_syscall(&self) -> Option<Syscall> {
+        self.last_syscall.get()
+    }
+
+    /// Subscribe to `driver` as the kernel does for the subscribe syscall,
+    /// with a callback function that stands for `subscribe_num`.
+    pub fn subscribe(
+        &self,
+        driver: &dyn Driver,
+        driver_num: usize,
+        subscribe_num: usize,
+    ) -> ReturnCode {
+        let callback_id = CallbackId {
+            driver_num,
+            subscribe_num,
+        };
+        let fn_ptr = CALLBACK_FUNCTION + subscribe_num;
+        self.remove_pending_callbacks(callback_id);
+        let callback = NonNull::new(fn_ptr as *mut ())
+            .map(|ptr| Callback::new(self.appid(), callback_id, 0, ptr.cast()));
+        let res = driver.subscribe(subscribe_num, callback, self.appid());
+        if res == ReturnCode::SUCCESS {
+            self.set_subscription(callback_id, fn_ptr, 0);
+        }
+        res
+    }
+
+    /// Allow `driver` to access `buf`, which must be in the memory returned
+    /// by `app_memory()`, as the kernel does for the allow syscall. `None`
+    /// revokes the allow.
+    pub fn allow_driver(
+        &self,
+        driver: &dyn Driver,
+        allow_num: usize,
+        buf: Option<&mut [u8]>,
+    ) -> ReturnCode {
+        let (addr, len) = buf.map_or((0 as *const u8, 0), |buf| (buf.as_ptr(), buf.len()));
+        if driver
+            .allow_max_size(allow_num)
+            .map_or(false, |max| len > max)
+        {
+            return ReturnCode::ESIZE;
+        }
+        match self.allow(addr, len) {
+            Ok(mut slice) => {
+                if driver.allow_zero_on_revoke(allow_num) {
+                    slice.as_mut().map(|slice| slice.set_zero_on_drop());
+                }
+                driver.allow(self.appid(), allow_num, slice)
+            }
+            Err(err) => err,
+        }
+    }
+
+    /// Make a command syscall to `driver`.
+    pub fn command(
+        &self,
+        driver: &dyn Driver,
+        command_num: usize,
+        arg0: usize,
+        arg1: usize,
+    ) -> ReturnCode {
+        driver.command(command_num, arg0, arg1, self.appid())
+    }
+
+    /// Arguments of the next callback a driver scheduled for the process,
+    /// taken off its queue, after the subscribe number it was scheduled
+    /// through.
+    pub fn take_callback(&self) -> Option<(usize, usize, usize, usize)> {
+        match self.dequeue_task() {
+            Some(Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(id),
+                argument0,
+                argument1,
+                argument2,
+                ..
+            })) => Some((id.subscribe_num, argument0, argument1, argument2)),
+            _ => None,
+        }
+    }
+
+    /// Bytes `offset..offset + len` of the memory the process owns, for
+    /// passing to `allow()`.
+    pub fn app_memory(&self, offset: usize, len: usize) -> &'static mut [u8] {
+        assert!(offset + len <= APP_MEMORY_LEN);
+        unsafe {
+            let start = (self.memory.get() as *mut u8).add(offset);
+            core::slice::from_raw_parts_mut(start, len)
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        let state = self.state.get();
+        state != State::StoppedFaulted && state != State::Fault
+    }
+
+    fn app_break(&self) -> *const u8 {
+        unsafe { self.mem_start().add(APP_MEMORY_LEN) }
+    }
+}
+
+impl ProcessType for MockProcess {
+    fn appid(&self) -> AppId {
+        self.app_id
+            .get()
+            .expect("MockProcess is not attached to a kernel")
+    }
+
+    fn enqueue_task(&self, task: Task) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+        match self.tasks.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some(task));
+                if self.held.get().is_none() {
+                    self.kernel().increment_work();
+                }
+                true
+            }
+            None => {
+                self.dropped_callback_count
+                    .set(self.dropped_callback_count.get() + 1);
+                false
+            }
+        }
+    }
+
+    fn ready(&self) -> bool {
+        if self.held.get().is_some() {
+            return false;
+        }
+        self.pending_tasks() > 0 || self.state.get() == State::Running
+    }
+
+    fn dequeue_task(&self) -> Option<Task> {
+        let task = self.tasks[0].take()?;
+        // Keep the queue in order, with the oldest task first.
+        for i in 1..MAX_TASKS {
+            self.tasks[i - 1].set(self.tasks[i].take());
+        }
+        self.kernel().decrement_work();
+        Some(task)
+    }
+
+    fn pending_tasks(&self) -> usize {
+        self.tasks
+            .iter()
+            .filter(|slot| slot.get().is_some())
+            .count()
+    }
+
+    fn has_pending_callback(&self, callback_id: CallbackId) -> bool {
+        self.tasks.iter().any(|slot| match slot.get() {
+            Some(Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(id),
+                ..
+            })) => id == callback_id,
+            _ => false,
+        })
+    }
+
+    fn remove_pending_callbacks(&self, callback_id: CallbackId) {
+        for _ in 0..self.pending_tasks() {
+            let task = self.tasks[0].take();
+            for i in 1..MAX_TASKS {
+                self.tasks[i - 1].set(self.tasks[i].take());
+            }
+            match task {
+                Some(Task::FunctionCall(FunctionCall {
+                    source: FunctionCallSource::Driver(id),
+                    ..
+                })) if id == callback_id => {
+                    if self.held.get().is_none() {
+                        self.kernel().decrement_work();
+                    }
+                }
+                task => {
+                    // Back at the end of the queue.
+                    self.tasks
+                        .iter()
+                        .find(|slot| slot.get().is_none())
+                        .map(|slot| slot.set(task));
+                }
+            }
+        }
+    }
+
+    fn set_subscription(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize) {
+        self.subscriptions
+            .iter()
+            .find(|slot| slot.get().map_or(false, |(id, _, _)| id == callback_id))
+            .or_else(|| self.subscriptions.iter().find(|slot| slot.get().is_none()))
+            .map(|slot| slot.set(Some((callback_id, fn_ptr, appdata))));
+    }
+
+    fn is_subscribed(&self, callback_id: CallbackId, fn_ptr: usize, appdata: usize) -> bool {
+        self.subscriptions
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|(id, _, _)| *id == callback_id)
+            .map_or(true, |(_, ptr, data)| ptr == fn_ptr && data == appdata)
+    }
+
+    fn has_subscriptions(&self) -> bool {
+        self.subscriptions
+            .iter()
+            .filter_map(|slot| slot.get())
+            .any(|(_, fn_ptr, _)| fn_ptr != 0)
+    }
+
+    fn trace_allow(
+        &self,
+        _driver_number: usize,
+        _subdriver_number: usize,
+        _address: usize,
+        _size: usize,
+    ) {
+    }
+
+    fn traced_allows_each(&self, _closure: &mut dyn FnMut(usize, usize, usize, usize)) {}
+
+    fn get_state(&self) -> State {
+        self.state.get()
+    }
+
+    fn set_yielded_state(&self) {
+        if self.state.get() == State::Running {
+            self.state.set(State::Yielded);
+        }
+    }
+
+    fn stop(&self) {
+        match self.state.get() {
+            State::Running => self.state.set(State::StoppedRunning),
+            State::Yielded => self.state.set(State::StoppedYielded),
+            _ => {}
+        }
+    }
+
+    fn resume(&self) {
+        match self.state.get() {
+            State::StoppedRunning => self.state.set(State::Running),
+            State::StoppedYielded => self.state.set(State::Yielded),
+            _ => {}
+        }
+    }
+
+    fn set_fault_state(&self) {
+        // Faulted mock processes are left stopped.
+        self.stop_and_free();
+        self.state.set(State::StoppedFaulted);
+    }
+
+    fn hold(&self, since_us: u32, for_us: u32) -> bool {
+        if self.state.get() != State::Yielded {
+            return false;
+        }
+        if self.held.replace(Some((since_us, for_us))).is_none() {
+            for _ in 0..self.pending_tasks() {
+                self.kernel().decrement_work();
+            }
+        }
+        true
+    }
+
+    fn hold_time(&self) -> Option<(u32, u32)> {
+        self.held.get()
+    }
+
+    fn release_hold(&self) {
+        if self.held.take().is_some() {
+            for _ in 0..self.pending_tasks() {
+                self.kernel().increment_work();
+            }
+        }
+    }
+
+    fn get_restart_count(&self) -> usize {
+        0
+    }
+
+    fn finish_throttled_restart(&self) {}
+
+    fn reload(&self) -> bool {
+        false
+    }
+
+    fn stop_and_free(&self) {
+        while self.dequeue_task().is_some() {}
+        self.held.set(None);
+        for grant_ptr in self.grant_ptrs.iter() {
+            grant_ptr.set(0 as *mut u8);
+        }
+    }
+
+    fn get_process_name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn brk(&self, _new_break: *const u8) -> Result<*const u8, Error> {
+        Err(Error::OutOfMemory)
+    }
+
+    fn sbrk(&self, _increment: isize) -> Result<*const u8, Error> {
+        Err(Error::OutOfMemory)
+    }
+
+    fn mem_start(&self) -> *const u8 {
+        self.memory.get() as *const u8
+    }
+
+    fn mem_end(&self) -> *const u8 {
+        unsafe { self.mem_start().add(MEMORY_LEN) }
+    }
+
+    fn flash_start(&self) -> *const u8 {
+        0 as *const u8
+    }
+
+    fn flash_end(&self) -> *const u8 {
+        0 as *const u8
+    }
+
+    fn kernel_memory_break(&self) -> *const u8 {
+        unsafe { self.mem_start().add(self.kernel_memory_break.get()) }
+    }
+
+    fn in_app_owned_memory(&self, buf_start_addr: *const u8, size: usize) -> bool {
+        let buf_end_addr = buf_start_addr.wrapping_add(size);
+        buf_end_addr >= buf_start_addr
+            && buf_start_addr >= self.mem_start()
+            && buf_end_addr <= self.app_break()
+    }
+
+    fn in_app_flash_memory(&self, _buf_start_addr: *const u8, _size: usize) -> bool {
+        false
+    }
+
+    fn number_writeable_flash_regions(&self) -> usize {
+        0
+    }
+
+    fn get_writeable_flash_region(&self, _region_index: usize) -> (u32, u32) {
+        (0, 0)
+    }
+
+    fn update_stack_start_pointer(&self, _stack_pointer: *const u8) {}
+
+    fn update_heap_start_pointer(&self, _heap_pointer: *const u8) {}
+
+    fn allow(
+        &self,
+        buf_start_addr: *const u8,
+        size: usize,
+    ) -> Result<Option<AppSlice<Shared, u8>>, ReturnCode> {
+        if !self.is_active() {
+            return Err(ReturnCode::FAIL);
+        }
+        match NonNull::new(buf_start_addr as *mut u8) {
+            Some(buf) if size > 0 => {
+                if self.in_app_owned_memory(buf_start_addr, size) {
+                    Ok(Some(unsafe { AppSlice::new(buf, size, self.appid()) }))
+                } else {
+                    Err(ReturnCode::EINVAL)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn flash_non_protected_start(&self) -> *const u8 {
+        0 as *const u8
+    }
+
+    fn setup_mpu(&self) {}
+
+    fn add_mpu_region(
+        &self,
+        _unallocated_memory_start: *const u8,
+        _unallocated_memory_size: usize,
+        _min_region_size: usize,
+    ) -> Option<mpu::Region> {
+        None
+    }
+
+    fn add_peripheral_mpu_region(
+        &self,
+        _start: *const u8,
+        _size: usize,
+        _permissions: mpu::Permissions,
+    ) -> Result<(), ReturnCode> {
+        Err(ReturnCode::ENOSUPPORT)
+    }
+
+    fn peripheral_mpu_region(&self) -> Option<(mpu::Region, mpu::Permissions)> {
+        None
+    }
+
+    fn alloc(&self, size: usize, align: usize) -> Option<NonNull<u8>> {
+        if !self.is_active() {
+            return None;
+        }
+        // Grant regions are placed downwards from the end of the memory.
+        let top = self.kernel_memory_break.get().checked_sub(size)?;
+        let start = top - top % align;
+        if start < APP_MEMORY_LEN {
+            return None;
+        }
+        self.kernel_memory_break.set(start);
+        NonNull::new(unsafe { (self.memory.get() as *mut u8).add(start) })
+    }
+
+    unsafe fn free(&self, _: *mut u8) {}
+
+    fn get_grant_ptr(&self, grant_num: usize) -> Option<*mut u8> {
+        if !self.is_active() {
+            return None;
+        }
+        self.grant_ptrs
+            .get(grant_num)
+            .map(|grant_ptr| grant_ptr.get())
+    }
+
+    unsafe fn set_grant_ptr(&self, grant_num: usize, grant_ptr: *mut u8) {
+        self.grant_ptrs[grant_num].set(grant_ptr);
+    }
+
+    fn hibernation_record(&self) -> Option<hibernate::ProcessRecord> {
+        None
+    }
+
+    fn hibernation_check(&self, _record: &hibernate::ProcessRecord) -> bool {
+        false
+    }
+
+    unsafe fn hibernation_restore(
+        &self,
+        _record: &hibernate::ProcessRecord,
+        _memory: &[u8],
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    unsafe fn set_syscall_return_value(&self, return_value: isize) {
+        self.return_value.set(Some(return_value));
+    }
+
+    unsafe fn set_process_function(&self, callback: FunctionCall) {
+        self.process_function.set(Some(callback));
+        self.state.set(State::Running);
+    }
+
+    unsafe fn switch_to(&self) -> Option<ContextSwitchReason> {
+        let switches = self.switches.get();
+        self.switches.set(switches + 1);
+        (self.on_switch.get())(self, switches)
+    }
+
+    unsafe fn print_memory_map(&self, _writer: &mut dyn Write) {}
+
+    unsafe fn print_full_process(&self, _writer: &mut dyn Write) {}
+
+    fn debug_syscall_count(&self) -> usize {
+        self.syscall_count.get()
+    }
+
+    fn debug_dropped_callback_count(&self) -> usize {
+        self.dropped_callback_count.get()
+    }
+
+    fn debug_timeslice_expiration_count(&self) -> usize {
+        self.timeslice_expiration_count.get()
+    }
+
+    fn debug_timeslice_expired(&self) {
+        self.timeslice_expiration_count
+            .set(self.timeslice_expiration_count.get() + 1);
+    }
+
+    fn debug_grant_alloc_failure_count(&self) -> usize {
+        self.grant_alloc_failure_count.get()
+    }
+
+    fn debug_grant_alloc_failed(&self) {
+        self.grant_alloc_failure_count
+            .set(self.grant_alloc_failure_count.get() + 1);
+    }
+
+    fn debug_syscall_called(&self, last_syscall: Syscall) {
+        self.syscall_count.set(self.syscall_count.get() + 1);
+        self.last_syscall.set(Some(last_syscall));
+    }
+
+    fn debug_last_subscribed_driver(&self) -> Option<usize> {
+        None
+    }
+
+    fn debug_waiting_since(&self) -> Option<u32> {
+        self.waiting_since.get()
+    }
+
+    fn debug_set_waiting_since(&self, since_us: Option<u32>) {
+        self.waiting_since.set(since_us);
+    }
+
+    fn debug_cpu_time_us(&self) -> (u64, u64) {
+        self.cpu_time_us.get()
+    }
+
+    fn debug_set_cpu_time_us(&self, charged_us: u64, uncharged_us: u64) {
+        self.cpu_time_us.set((charged_us, uncharged_us));
+    }
+}
+
+/// A 1 kHz alarm for tests, which never fires by itself.
+pub struct MockAlarm<'a> {
+    now: Cell<u32>,
+    alarm: Cell<Option<(u32, u32)>>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+}
+
+impl<'a> MockAlarm<'a> {
+    pub const fn new() -> MockAlarm<'a> {
+        MockAlarm {
+            now: Cell::new(0),
+            alarm: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Move the time to `now` ticks.
+    pub fn set_now(&self, now: u32) {
+        self.now.set(now);
+    }
+
+    /// The reference and interval of the armed alarm.
+    pub fn armed(&self) -> Option<(u32, u32)> {
+        self.alarm.get()
+    }
+
+    /// Move the time to when the alarm is armed for, disarm it and call the
+    /// client. Returns `false` if the alarm is not armed.
+    pub fn fire(&self) -> bool {
+        match self.alarm.take() {
+            Some((reference, dt)) => {
+                self.now.set(reference.wrapping_add(dt));
+                self.client.map(|client| client.alarm());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl time::Time for MockAlarm<'_> {
+    type Frequency = Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.now.get())
+    }
+}
+
+impl<'a> Alarm<'a> for MockAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.alarm.set(Some((reference.into_u32(), dt.into_u32())));
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        let (reference, dt) = self.alarm.get().unwrap_or((0, 0));
+        Ticks32::from(reference.wrapping_add(dt))
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.alarm.set(None);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.alarm.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        Ticks32::from(1)
+    }
+}
+
+/// A scheduler timer whose time only passes while a process runs. Each switch
+/// to a process takes the same time.
+pub struct MockSchedulerTimer {
+    remaining: Cell<Option<u32>>,
+    switch_us: Cell<u32>,
+}
+
+impl MockSchedulerTimer {
+    /// Make each switch to a process take `us` microseconds.
+    pub fn set_switch_us(&self, us: u32) {
+        self.switch_us.set(us);
+    }
+}
+
+impl SchedulerTimer for MockSchedulerTimer {
+    fn start(&self, us: u32) {
+        self.remaining.set(Some(us));
+    }
+
+    fn reset(&self) {
+        self.remaining.set(None);
+    }
+
+    /// Called right before switching to a process, so this is when the time
+    /// of the switch passes.
+    fn arm(&self) {
+        let remaining = self.remaining.get().and_then(|remaining| {
+            Some(remaining.saturating_sub(self.switch_us.get())).filter(|&left| left > 0)
+        });
+        self.remaining.set(remaining);
+    }
+
+    fn disarm(&self) {}
+
+    fn get_remaining_us(&self) -> Option<u32> {
+        self.remaining.get()
+    }
+}
+
+/// Boundary for a `MockChip`. Mock processes do not go through it.
+pub struct MockUserspaceKernelBoundary;
+
+impl UserspaceKernelBoundary for MockUserspaceKernelBoundary {
+    type StoredState = ();
+
+    fn initial_process_app_brk_size(&self) -> usize {
+        0
+    }
+
+    unsafe fn initialize_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    unsafe fn set_syscall_return_value(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _return_value: isize,
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    unsafe fn set_process_function(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+        _callback: FunctionCall,
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    unsafe fn switch_to_process(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &mut (),
+    ) -> (ContextSwitchReason, Option<*const u8>) {
+        (ContextSwitchReason::Fault, None)
+    }
+
+    unsafe fn print_context(
+        &self,
+        _accessible_memory_start: *const u8,
+        _app_brk: *const u8,
+        _state: &(),
+        _writer: &mut dyn Write,
+    ) {
+    }
+}
+
+/// A chip without interrupts, an MPU or a watchdog, with a
+/// `MockSchedulerTimer`.
+pub struct MockChip {
+    scheduler_timer: MockSchedulerTimer,
+    userspace_kernel_boundary: MockUserspaceKernelBoundary,
+}
+
+impl MockChip {
+    pub const fn new() -> MockChip {
+        MockChip {
+            scheduler_timer: MockSchedulerTimer {
+                remaining: Cell::new(None),
+                switch_us: Cell::new(0),
+            },
+            userspace_kernel_boundary: MockUserspaceKernelBoundary,
+        }
+    }
+}
+
+impl Chip for MockChip {
+    type MPU = ();
+    type UserspaceKernelBoundary = MockUserspaceKernelBoundary;
+    type SchedulerTimer = MockSchedulerTimer;
+    type WatchDog = ();
+
+    fn service_pending_interrupts(&self) {}
+
+    fn has_pending_interrupts(&self) -> bool {
+        false
+    }
+
+    fn mpu(&self) -> &() {
+        &()
+    }
+
+    fn scheduler_timer(&self) -> &MockSchedulerTimer {
+        &self.scheduler_timer
+    }
+
+    fn watchdog(&self) -> &() {
+        &()
+    }
+
+    fn userspace_kernel_boundary(&self) -> &MockUserspaceKernelBoundary {
+        &self.userspace_kernel_boundary
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn atomic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        f()
+    }
+
+    unsafe fn print_state(&self, _writer: &mut dyn Write) {}
+}
@@ -0,0 +1,120 @@
+//! Time since boot, as a 64-bit count that does not wrap.
+//!
+//! The hardware counters used for timekeeping are typically only 32 bits (or
+//! less) wide and wrap within days, which is too short to serve as a
+//! monotonic "time since boot". `Uptime` extends such a counter to 64 bits by
+//! keeping the high word in software. A board sets it as the kernel's uptime
+//! clock with `Kernel::set_uptime_clock()`, and the kernel and capsules then
+//! read the time since boot with `Kernel::uptime_us()`. It is also a
+//! `WaitTimeClock`, so the kernel can time waits with the same source.
+//!
+//! The high word is advanced whenever a read of the counter observes a value
+//! smaller than the previous read. The counter's overflow interrupt also
+//! triggers a read, so at least one observation happens per wrap even when
+//! nothing else reads the counter, including when the wrap happens while the
+//! chip is asleep. A wrap that has already been counted by an earlier read is
+//! not counted again by the overflow callback, so the two paths never double
+//! count.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::static_init;
+//!
+//! let uptime = static_init!(
+//!     kernel::uptime::Uptime<'static, apollo3::stimer::STimer<'static>>,
+//!     kernel::uptime::Uptime::new(&peripherals.stimer)
+//! );
+//! peripherals.stimer.set_overflow_client(uptime);
+//! board_kernel.set_uptime_clock(uptime, &main_loop_cap);
+//! ```
+
+use core::cell::Cell;
+
+use crate::hil::time::{Frequency, OverflowClient, Ticks, Time};
+use crate::platform::{UptimeClock, WaitTimeClock};
+
+/// A 64-bit count of the ticks of a counter since boot.
+pub struct Uptime<'a, T: Time> {
+    counter: &'a T,
+    high: Cell<u32>,
+    last: Cell<u32>,
+}
+
+impl<'a, T: Time> Uptime<'a, T> {
+    pub fn new(counter: &'a T) -> Uptime<'a, T> {
+        Uptime {
+            counter: counter,
+            high: Cell::new(0),
+            last: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of counter ticks since boot, extended to 64 bits.
+    pub fn ticks(&self) -> u64 {
+        let now = self.counter.now().into_u32();
+        if now < self.last.get() {
+            self.high.set(self.high.get().wrapping_add(1));
+        }
+        self.last.set(now);
+
+        let width = T::Ticks::max_value().into_u32() as u64 + 1;
+        self.high.get() as u64 * width + now as u64
+    }
+}
+
+impl<'a, T: Time> UptimeClock for Uptime<'a, T> {
+    fn uptime_us(&self) -> u64 {
+        let ticks = self.ticks();
+        let freq = <T::Frequency>::frequency() as u64;
+
+        // Split the conversion so that the multiplication cannot overflow.
+        (ticks / freq) * 1_000_000 + (ticks % freq) * 1_000_000 / freq
+    }
+}
+
+impl<'a, T: Time> WaitTimeClock for Uptime<'a, T> {
+    fn now_us(&self) -> u32 {
+        self.uptime_us() as u32
+    }
+}
+
+impl<'a, T: Time> OverflowClient for Uptime<'a, T> {
+    fn overflow(&self) {
+        // Reading the counter is enough to account for the wrap.
+        self.ticks();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Uptime;
+    use crate::hil::time::OverflowClient;
+    use crate::platform::UptimeClock;
+    use crate::testing::MockAlarm;
+
+    #[test]
+    fn wrap_is_counted_once() {
+        let counter = MockAlarm::new();
+        let uptime = Uptime::new(&counter);
+        counter.set_now(u32::MAX);
+        assert_eq!(uptime.ticks(), u32::MAX as u64);
+
+        // A read sees the wrap before the overflow interrupt is handled.
+        counter.set_now(5);
+        assert_eq!(uptime.ticks(), (1 << 32) + 5);
+        uptime.overflow();
+        assert_eq!(uptime.ticks(), (1 << 32) + 5);
+
+        // Only the overflow interrupt sees the next wrap.
+        counter.set_now(u32::MAX);
+        uptime.ticks();
+        counter.set_now(10);
+        uptime.overflow();
+        assert_eq!(uptime.ticks(), (2 << 32) + 10);
+
+        // 1 kHz ticks are milliseconds.
+        assert_eq!(uptime.uptime_us(), ((2 << 32) + 10) * 1_000);
+    }
+}
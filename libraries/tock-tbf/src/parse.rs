@@ -130,6 +130,7 @@ pub fn parse_tbf_header(
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<types::TbfHeaderV2FixedAddresses> = None;
+                let mut required_drivers: Option<&'static [u8]> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -219,6 +220,21 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderRequiredDrivers => {
+                            // A list of 4 byte driver numbers.
+                            if tlv_header.length as usize % 4 == 0 {
+                                required_drivers = Some(
+                                    remaining
+                                        .get(0..tlv_header.length as usize)
+                                        .ok_or(types::TbfParseError::NotEnoughFlash)?,
+                                );
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -236,6 +252,7 @@ pub fn parse_tbf_header(
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    required_drivers: required_drivers,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
@@ -244,3 +261,50 @@ pub fn parse_tbf_header(
         _ => Err(types::TbfParseError::UnsupportedVersion(version)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_tbf_header;
+    use crate::types::TbfParseError;
+
+    /// Header of an enabled app that requires the I2C master (`0x20003`) and
+    /// LED (`2`) drivers.
+    static REQUIRED_DRIVERS: [u8; 28] = [
+        0x02, 0x00, 0x1c, 0x00, // version 2, header size
+        0x00, 0x01, 0x00, 0x00, // total size
+        0x01, 0x00, 0x00, 0x00, // flags: enabled
+        0x04, 0x01, 0x16, 0x00, // checksum
+        0x06, 0x00, 0x08, 0x00, // required drivers, 8 bytes
+        0x03, 0x00, 0x02, 0x00, // 0x20003
+        0x02, 0x00, 0x00, 0x00, // 2
+    ];
+
+    /// Like `REQUIRED_DRIVERS`, with a length that is not a multiple of a
+    /// driver number.
+    static BAD_REQUIRED_DRIVERS: [u8; 28] = [
+        0x02, 0x00, 0x1c, 0x00, // version 2, header size
+        0x00, 0x01, 0x00, 0x00, // total size
+        0x01, 0x00, 0x00, 0x00, // flags: enabled
+        0x06, 0x01, 0x18, 0x00, // checksum
+        0x06, 0x00, 0x06, 0x00, // required drivers, 6 bytes
+        0x03, 0x00, 0x02, 0x00, // 0x20003
+        0x00, 0x00, 0x00, 0x00, // half a driver number, then padding
+    ];
+
+    #[test]
+    fn parses_required_drivers() {
+        let header = parse_tbf_header(&REQUIRED_DRIVERS, 2).unwrap();
+        assert_eq!(header.number_required_drivers(), 2);
+        assert_eq!(header.get_required_driver(0), Some(0x20003));
+        assert_eq!(header.get_required_driver(1), Some(2));
+        assert_eq!(header.get_required_driver(2), None);
+    }
+
+    #[test]
+    fn rejects_partial_driver_number() {
+        match parse_tbf_header(&BAD_REQUIRED_DRIVERS, 2) {
+            Err(TbfParseError::BadTlvEntry(6)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -105,6 +105,7 @@ pub enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderRequiredDrivers = 6,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -209,6 +210,7 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderRequiredDrivers),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -308,6 +310,8 @@ pub struct TbfHeaderV2 {
     pub(crate) package_name: Option<&'static str>,
     pub(crate) writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     pub(crate) fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    /// Driver numbers the app cannot run without, as little-endian `u32`s.
+    pub(crate) required_drivers: Option<&'static [u8]>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -409,6 +413,27 @@ impl TbfHeader {
         }
     }
 
+    /// Get the number of drivers this app requires the board to provide.
+    pub fn number_required_drivers(&self) -> usize {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.required_drivers.map_or(0, |drivers| drivers.len() / 4)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Get the driver number of a given required driver.
+    pub fn get_required_driver(&self, index: usize) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .required_drivers?
+                .get(index * 4..(index + 1) * 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            _ => None,
+        }
+    }
+
     /// Get the address in RAM this process was specifically compiled for. If
     /// the process is position independent, return `None`.
     pub fn get_fixed_address_ram(&self) -> Option<u32> {